@@ -0,0 +1,1047 @@
+//! Disk-resident B+tree storage engine.
+//!
+//! The tree lives in a single file of fixed-size pages accessed through the
+//! [`BufferPool`]. Page 0 holds the metadata; every other page is a node, or a
+//! free page linked into the free list. Leaves are chained left to right.
+//!
+//! An operation decodes the nodes on the path from the root to the leaf it
+//! touches, so only that path needs to be resident; the rest of the tree is
+//! left to the buffer pool to cache or evict. Nodes that outgrow a page are
+//! split and nodes that fall below a quarter of a page are merged with, or
+//! rebalanced against, a sibling. An entry takes at most a quarter of a page:
+//! a larger value is moved to a chain of overflow pages the entry points to,
+//! so only keys are limited in size.
+//!
+//! Recovery follows ARIES. An operation stages the pages it changes and logs
+//! them before they reach the buffer pool: each mutation is an update record
+//! holding the page changes that redo it and the mutation that undoes it, and
+//! a batch of mutations is a transaction ended by a commit record. The first
+//! change to a page after a checkpoint logs its whole body and later ones only
+//! the bytes that differ, so a page torn by a crash can always be rebuilt from
+//! the log. Opening the tree redoes the records past the last checkpoint that
+//! the pages do not reflect yet, then rolls back the transactions that never
+//! committed, logging compensation records so that a crash during recovery
+//! never undoes anything twice.
+
+mod node;
+mod page;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use self::node::{
+    decode_overflow, encode_overflow, overflow_capacity, separator, Internal, Leaf, LeafValue,
+    Node, OVERFLOW_REF,
+};
+use self::page::{is_intact, page_lsn, stamp, LoggedStore, PAGE_HEADER};
+use crate::buffer::{BufferPool, FilePageStore, PageId, PageStore};
+use crate::coding::{put_u32, put_u64, u32_at, u64_at};
+use crate::engine::Mutation;
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reservation};
+use crate::merge::Operators;
+use crate::options::Options;
+use crate::range::KeyRange;
+use crate::wal::{Durability, LogRecord, Lsn, PageChange, Wal};
+
+const MAGIC: u64 = 0x6565_7274_6267_6964;
+const META_PAGE: PageId = 0;
+const FILE_NAME: &str = "btree.db";
+
+/// Approximate bytes of bookkeeping per undo entry kept during recovery.
+const UNDO_OVERHEAD: usize = 64;
+
+/// Bytes an entry may take on top of its key and value once encoded.
+const ENTRY_OVERHEAD: usize = 20;
+
+/// Key-value pairs copied out of a leaf.
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Allocation state stored in page 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Meta {
+    root: PageId,
+    page_count: u64,
+    free_head: PageId,
+    /// Log position up to which every change has reached the file.
+    checkpoint_lsn: Lsn,
+}
+
+impl Meta {
+    /// Decodes the metadata page body, or `None` if it was never written.
+    fn decode(body: &[u8], page_size: usize) -> Result<Option<Meta>> {
+        match u64_at(body, 0) {
+            0 => return Ok(None),
+            MAGIC => {}
+            _ => return Err(Error::corruption("not a B+tree file")),
+        }
+        if u32_at(body, 8) as usize != page_size {
+            return Err(Error::invalid(format!(
+                "file was created with page size {}",
+                u32_at(body, 8)
+            )));
+        }
+        Ok(Some(Meta {
+            root: u64_at(body, 12),
+            page_count: u64_at(body, 20),
+            free_head: u64_at(body, 28),
+            checkpoint_lsn: u64_at(body, 36),
+        }))
+    }
+
+    fn encode(&self, page_size: usize, body: &mut [u8]) {
+        let mut buf = Vec::with_capacity(44);
+        put_u64(&mut buf, MAGIC);
+        put_u32(&mut buf, page_size as u32);
+        put_u64(&mut buf, self.root);
+        put_u64(&mut buf, self.page_count);
+        put_u64(&mut buf, self.free_head);
+        put_u64(&mut buf, self.checkpoint_lsn);
+        body[..buf.len()].copy_from_slice(&buf);
+    }
+}
+
+/// A node read from disk together with its page id and, for internal nodes,
+/// the index of the child followed by the current operation.
+struct PathEntry {
+    id: PageId,
+    node: Node,
+    child: usize,
+}
+
+/// Storage engine based on an in-place B+tree.
+pub struct BTreeEngine {
+    pool: BufferPool,
+    wal: Arc<Wal>,
+    budget: MemoryBudget,
+    page_size: usize,
+    /// Bytes of a page available to a node.
+    node_size: usize,
+    /// Bytes a leaf entry may occupy; larger values go to overflow pages.
+    max_entry: usize,
+    durability: Durability,
+    operators: Operators,
+    /// Log growth past the last checkpoint that triggers a new one.
+    checkpoint_interval: u64,
+    meta: RwLock<Meta>,
+}
+
+impl BTreeEngine {
+    /// Opens the tree stored in directory `dir`, creating it if needed, and
+    /// recovers it from the log.
+    pub fn open(dir: impl AsRef<Path>, options: &Options, budget: &MemoryBudget) -> Result<Self> {
+        options.validate()?;
+        let dir = dir.as_ref();
+        let vfs = &*options.vfs;
+        vfs.create_dir_all(dir)?;
+        let page_size = options.page_size;
+        let store = FilePageStore::open(vfs, dir.join(FILE_NAME), page_size)?;
+        vfs.sync_dir(dir)?;
+        let start = Self::recovery_start(&store, page_size, budget)?;
+        let wal = Arc::new(Wal::open(dir.join("wal"), options, budget, start)?);
+        let store = LoggedStore::new(store, Arc::clone(&wal));
+        let pool = BufferPool::new(Box::new(store), budget, options)?;
+
+        let node_size = page_size - PAGE_HEADER;
+        let engine = BTreeEngine {
+            pool,
+            wal,
+            budget: budget.clone(),
+            page_size,
+            node_size,
+            // Leave room for at least four entries per page so splits and
+            // merges always have somewhere to put them.
+            max_entry: (node_size - 64) / 4,
+            durability: options.durability,
+            operators: Operators::new(&options.merge_operators),
+            checkpoint_interval: options.wal_segment_size as u64,
+            meta: RwLock::new(Meta::default()),
+        };
+        engine.recover(start)?;
+        Ok(engine)
+    }
+
+    /// Returns the value stored under `key`.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let meta = self.meta.read().unwrap();
+        let path = descend(meta.root, key, |id| self.read_node(id))?;
+        let Node::Leaf(mut leaf) = path.into_iter().last().unwrap().node else {
+            unreachable!("descend ends at a leaf");
+        };
+        match leaf
+            .entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+        {
+            Ok(i) => self.load(leaf.entries.swap_remove(i).1).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Stores `value` under `key` with the durability of
+    /// [`Options::durability`].
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mutation = Mutation::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        self.write(&[mutation], self.durability)
+    }
+
+    /// Removes `key` with the durability of [`Options::durability`].
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mutation = Mutation::Delete { key: key.to_vec() };
+        self.write(&[mutation], self.durability)
+    }
+
+    /// Folds `operand` into the value of `key` with the merge operator named
+    /// `operator`, with the durability of [`Options::durability`].
+    pub fn merge(&self, key: &[u8], operator: &str, operand: &[u8]) -> Result<()> {
+        let mutation = Mutation::Merge {
+            key: key.to_vec(),
+            operator: operator.to_string(),
+            operand: operand.to_vec(),
+        };
+        self.write(&[mutation], self.durability)
+    }
+
+    /// Applies `mutations` atomically, returning once they are as durable as
+    /// `durability` requires.
+    ///
+    /// Keys longer than [`max_key_len`](Self::max_key_len), merges naming no
+    /// registered operator and operands the operator rejects fail the whole
+    /// batch.
+    pub fn write(&self, mutations: &[Mutation], durability: Durability) -> Result<()> {
+        for mutation in mutations {
+            match mutation {
+                Mutation::Put { key, .. } => self.check_key(key)?,
+                Mutation::Merge { key, operator, .. } => {
+                    self.operators.check(operator)?;
+                    self.check_key(key)?;
+                }
+                Mutation::Delete { .. } => {}
+            }
+        }
+        let (lsn, checkpoint) = {
+            let mut meta = self.meta.write().unwrap();
+            let txn = self.wal.next_lsn();
+            let mut undo = Vec::new();
+            let lsn = match self.run(&mut meta, txn, mutations, &mut undo) {
+                Ok(Some(lsn)) => lsn,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    self.roll_back(&mut meta, txn, &undo)?;
+                    return Err(e);
+                }
+            };
+            (lsn, lsn - meta.checkpoint_lsn > self.checkpoint_interval)
+        };
+        self.wal.commit(lsn, durability)?;
+        if checkpoint {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Iterates in key order over the pairs in `range`.
+    ///
+    /// The iterator holds no lock between calls: it reads one leaf at a time
+    /// and finds its place again from the last key it returned.
+    pub fn scan(&self, range: impl Into<KeyRange>) -> BTreeScan<'_> {
+        BTreeScan {
+            engine: self,
+            range: range.into(),
+            last: None,
+            buffer: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Takes a checkpoint: writes every dirty page to disk and drops the log
+    /// records they cover.
+    pub fn flush(&self) -> Result<()> {
+        let mut meta = self.meta.write().unwrap();
+        let lsn = self.wal.next_lsn();
+        if lsn == meta.checkpoint_lsn {
+            return Ok(());
+        }
+        self.pool.flush_all()?;
+        // The new metadata is logged whole, so that recovery can rebuild it
+        // from the log if its write tears, then written after the pages it
+        // vouches for.
+        let mut new = *meta;
+        new.checkpoint_lsn = lsn;
+        let page = self.pool.fetch(META_PAGE)?;
+        let mut data = page.write();
+        new.encode(self.page_size, &mut data[PAGE_HEADER..]);
+        let image = PageChange::Image {
+            page: META_PAGE,
+            body: data[PAGE_HEADER..].to_vec(),
+        };
+        let end = self.wal.append(&LogRecord::Pages(vec![image]).encode())?;
+        new.checkpoint_lsn = end;
+        new.encode(self.page_size, &mut data[PAGE_HEADER..]);
+        stamp(&mut data, end);
+        drop(data);
+        drop(page);
+        self.pool.flush_all()?;
+        *meta = new;
+        self.wal.truncate_before(lsn)
+    }
+
+    /// Longest key the tree stores, about a quarter of a page: the entry of
+    /// a key must fit in a leaf even when its value is moved to overflow
+    /// pages.
+    pub fn max_key_len(&self) -> usize {
+        self.max_entry - ENTRY_OVERHEAD - OVERFLOW_REF
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<()> {
+        if key.len() > self.max_key_len() {
+            return Err(Error::invalid(format!(
+                "key exceeds the {} bytes a key may occupy",
+                self.max_key_len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether an entry of `key` and `value` is stored whole in its leaf.
+    fn fits_inline(&self, key: &[u8], value: &[u8]) -> bool {
+        key.len() + value.len() + ENTRY_OVERHEAD <= self.max_entry
+    }
+
+    /// Bytes of `value`, read from its overflow pages if it has any.
+    fn load(&self, value: LeafValue) -> Result<Vec<u8>> {
+        read_value(value, |id| self.read_overflow(id))
+    }
+
+    /// Link and data of overflow page `id`.
+    fn read_overflow(&self, id: PageId) -> Result<(PageId, Vec<u8>)> {
+        let page = self.pool.fetch(id)?;
+        let data = page.read();
+        let (next, chunk) = decode_overflow(&data[PAGE_HEADER..])?;
+        Ok((next, chunk.to_vec()))
+    }
+
+    /// Applies `mutations` as transaction `txn`, recording in `undo` how to
+    /// roll back each update made, and logs the commit. Returns the LSN of
+    /// the commit record, or `None` if nothing changed.
+    fn run(
+        &self,
+        meta: &mut Meta,
+        txn: u64,
+        mutations: &[Mutation],
+        undo: &mut Vec<(Lsn, Mutation)>,
+    ) -> Result<Option<Lsn>> {
+        for mutation in mutations {
+            let record = |undo, pages| LogRecord::Update { txn, undo, pages };
+            if let Some(entry) = self.apply(meta, mutation, record)? {
+                undo.push(entry);
+            }
+        }
+        if undo.is_empty() {
+            return Ok(None);
+        }
+        let lsn = self.wal.append(&LogRecord::Commit { txn }.encode())?;
+        Ok(Some(lsn))
+    }
+
+    /// Applies `mutation` as one logged operation whose record `record`
+    /// builds from the undoing mutation and the page changes. Returns the LSN
+    /// of the record and the undoing mutation, or `None` if nothing changed.
+    fn apply(
+        &self,
+        meta: &mut Meta,
+        mutation: &Mutation,
+        record: impl FnOnce(Mutation, Vec<PageChange>) -> LogRecord,
+    ) -> Result<Option<(Lsn, Mutation)>> {
+        let mut op = Staged::new(self, *meta);
+        let key = mutation.key();
+        let mut path = descend(meta.root, key, |id| op.read_node(id))?;
+        let Node::Leaf(leaf) = &mut path.last_mut().unwrap().node else {
+            unreachable!("descend ends at a leaf");
+        };
+        let found = leaf
+            .entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key));
+        let value = match mutation {
+            Mutation::Put { value, .. } => Some(value.clone()),
+            Mutation::Delete { .. } => None,
+            Mutation::Merge {
+                operator, operand, ..
+            } => {
+                let existing = match found {
+                    Ok(i) => Some(op.read_value(leaf.entries[i].1.clone())?),
+                    Err(_) => None,
+                };
+                let operator = self.operators.get(operator.as_bytes())?;
+                Some(operator.full_merge(key, existing.as_deref(), &[operand])?)
+            }
+        };
+        let undo = match (value, found) {
+            (Some(value), Ok(i)) => {
+                let value = op.store_value(key, value)?;
+                let old = std::mem::replace(&mut leaf.entries[i].1, value);
+                Mutation::Put {
+                    key: key.to_vec(),
+                    value: op.take_value(old)?,
+                }
+            }
+            (Some(value), Err(i)) => {
+                let value = op.store_value(key, value)?;
+                leaf.entries.insert(i, (key.to_vec(), value));
+                Mutation::Delete { key: key.to_vec() }
+            }
+            (None, Ok(i)) => Mutation::Put {
+                key: key.to_vec(),
+                value: op.take_value(leaf.entries.remove(i).1)?,
+            },
+            (None, Err(_)) => return Ok(None),
+        };
+        op.rebalance(path)?;
+        let lsn = op.commit(meta, |pages| record(undo.clone(), pages))?;
+        Ok(lsn.map(|lsn| (lsn, undo)))
+    }
+
+    /// Undoes the updates of `txn` listed in `undo`, newest first, then logs
+    /// that the transaction aborted.
+    fn roll_back(&self, meta: &mut Meta, txn: u64, undo: &[(Lsn, Mutation)]) -> Result<()> {
+        for (i, (_, mutation)) in undo.iter().enumerate().rev() {
+            let undo_next = if i == 0 { 0 } else { undo[i - 1].0 };
+            self.apply(meta, mutation, |_, pages| LogRecord::Compensation {
+                txn,
+                undo_next,
+                pages,
+            })?;
+        }
+        self.wal.append(&LogRecord::Abort { txn }.encode())?;
+        Ok(())
+    }
+
+    /// Log position recovery starts from: the checkpoint recorded in the
+    /// metadata page, or the oldest record if that page is torn.
+    fn recovery_start(
+        store: &FilePageStore,
+        page_size: usize,
+        budget: &MemoryBudget,
+    ) -> Result<Lsn> {
+        let _reservation = budget.reserve(page_size)?;
+        let mut page = vec![0; page_size];
+        store.read_page(META_PAGE, &mut page)?;
+        if !is_intact(&page) {
+            return Ok(0);
+        }
+        let meta = Meta::decode(&page[PAGE_HEADER..], page_size)?;
+        Ok(meta.map_or(0, |meta| meta.checkpoint_lsn))
+    }
+
+    /// Brings the pages up to date with the log and rolls back the
+    /// transactions that did not commit.
+    fn recover(&self, start: Lsn) -> Result<()> {
+        let mut meta = self.meta.write().unwrap();
+        // Analysis and redo share a single pass over the log.
+        let mut losers: BTreeMap<u64, Vec<(Lsn, Mutation)>> = BTreeMap::new();
+        let mut reservation = self.budget.reservation();
+        let mut torn = BTreeSet::new();
+        let mut replayed = false;
+        let charge = |undo: &[(Lsn, Mutation)]| -> usize {
+            undo.iter().map(|(_, m)| m.size() + UNDO_OVERHEAD).sum()
+        };
+        for record in self.wal.replay(start)? {
+            let (lsn, payload) = record?;
+            replayed = true;
+            match LogRecord::decode(&payload)? {
+                LogRecord::Update { txn, undo, pages } => {
+                    self.redo(lsn, &pages, &mut torn)?;
+                    reservation.grow(undo.size() + UNDO_OVERHEAD)?;
+                    losers.entry(txn).or_default().push((lsn, undo));
+                }
+                LogRecord::Compensation {
+                    txn,
+                    undo_next,
+                    pages,
+                } => {
+                    self.redo(lsn, &pages, &mut torn)?;
+                    if let Some(undo) = losers.get_mut(&txn) {
+                        let keep = undo.partition_point(|(l, _)| *l <= undo_next);
+                        reservation.shrink(charge(&undo[keep..]));
+                        undo.truncate(keep);
+                    }
+                }
+                LogRecord::Pages(pages) => self.redo(lsn, &pages, &mut torn)?,
+                LogRecord::Commit { txn } | LogRecord::Abort { txn } => {
+                    if let Some(undo) = losers.remove(&txn) {
+                        reservation.shrink(charge(&undo));
+                    }
+                }
+                LogRecord::Batch(_) => {
+                    return Err(Error::corruption("batch record in a B+tree log"));
+                }
+            }
+        }
+        if let Some(page) = torn.first() {
+            return Err(Error::corruption(format!(
+                "page {page} is torn and the log holds no image of it"
+            )));
+        }
+
+        let stored = {
+            let page = self.pool.fetch(META_PAGE)?;
+            let data = page.read();
+            Meta::decode(&data[PAGE_HEADER..], self.page_size)?
+        };
+        match stored {
+            Some(stored) => *meta = stored,
+            None => {
+                // A new file: an empty leaf as root.
+                let mut op = Staged::new(self, Meta::default());
+                op.meta = Meta {
+                    root: 1,
+                    page_count: 2,
+                    free_head: 0,
+                    checkpoint_lsn: 0,
+                };
+                op.write_node(1, &Node::Leaf(Leaf::default()), true)?;
+                op.commit(&mut meta, LogRecord::Pages)?;
+                replayed = true;
+            }
+        }
+        for (txn, undo) in &losers {
+            self.roll_back(&mut meta, *txn, undo)?;
+        }
+        drop(meta);
+        if replayed {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the page changes of the record at `lsn` that the pages do not
+    /// reflect yet. Pages found torn are skipped until an image of them
+    /// comes by, and collected in `torn` meanwhile.
+    fn redo(&self, lsn: Lsn, changes: &[PageChange], torn: &mut BTreeSet<PageId>) -> Result<()> {
+        for change in changes {
+            match change {
+                PageChange::Image { page, body } => {
+                    if body.len() != self.node_size {
+                        return Err(Error::corruption(format!("bad image of page {page}")));
+                    }
+                    // An image is installed even over a newer page: the
+                    // records that follow it bring the page up to date.
+                    let frame = self.pool.create(*page)?;
+                    let mut data = frame.write();
+                    data[PAGE_HEADER..].copy_from_slice(body);
+                    stamp(&mut data, lsn);
+                    torn.remove(page);
+                }
+                PageChange::Delta {
+                    page,
+                    offset,
+                    bytes,
+                } => {
+                    if torn.contains(page) {
+                        continue;
+                    }
+                    if offset + bytes.len() > self.node_size {
+                        return Err(Error::corruption(format!("bad change to page {page}")));
+                    }
+                    let frame = match self.pool.fetch(*page) {
+                        Ok(frame) => frame,
+                        Err(Error::Corruption(_)) => {
+                            torn.insert(*page);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    // Checked under a read lock: a page that already
+                    // reflects the record must not be marked dirty.
+                    if page_lsn(&frame.read()) >= lsn {
+                        continue;
+                    }
+                    let mut data = frame.write();
+                    let at = PAGE_HEADER + offset;
+                    data[at..at + bytes.len()].copy_from_slice(bytes);
+                    stamp(&mut data, lsn);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries from the first leaf holding keys of `range` past `after`.
+    fn scan_batch(&self, range: &KeyRange, after: Option<&[u8]>) -> Result<(Pairs, bool)> {
+        let meta = self.meta.read().unwrap();
+        let seek = match (after, range.as_bounds().0) {
+            (Some(after), _) => after,
+            (None, std::ops::Bound::Included(k) | std::ops::Bound::Excluded(k)) => k,
+            (None, std::ops::Bound::Unbounded) => &[],
+        };
+        let path = descend(meta.root, seek, |id| self.read_node(id))?;
+        let Node::Leaf(mut leaf) = path.into_iter().last().unwrap().node else {
+            unreachable!("descend ends at a leaf");
+        };
+        loop {
+            let mut batch = Vec::new();
+            for (key, value) in leaf.entries {
+                if after.is_some_and(|after| key.as_slice() <= after) || range.is_before(&key) {
+                    continue;
+                }
+                if range.is_after(&key) {
+                    return Ok((batch, true));
+                }
+                batch.push((key, self.load(value)?));
+            }
+            if !batch.is_empty() || leaf.next == 0 {
+                return Ok((batch, leaf.next == 0));
+            }
+            match self.read_node(leaf.next)? {
+                Node::Leaf(next) => leaf = next,
+                Node::Internal(_) => return Err(Error::corruption("leaf links to internal node")),
+            }
+        }
+    }
+
+    fn read_node(&self, id: PageId) -> Result<Node> {
+        let page = self.pool.fetch(id)?;
+        let data = page.read();
+        Node::decode(&data[PAGE_HEADER..])
+    }
+}
+
+impl Drop for BTreeEngine {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Reads the nodes from `root` down to the leaf that may contain `key`.
+fn descend(
+    root: PageId,
+    key: &[u8],
+    read_node: impl Fn(PageId) -> Result<Node>,
+) -> Result<Vec<PathEntry>> {
+    let mut path = Vec::new();
+    let mut id = root;
+    loop {
+        let node = read_node(id)?;
+        let (child, next) = match &node {
+            Node::Internal(internal) => {
+                let child = internal.child_index(key);
+                (child, internal.children[child])
+            }
+            Node::Leaf(_) => {
+                path.push(PathEntry { id, node, child: 0 });
+                return Ok(path);
+            }
+        };
+        path.push(PathEntry { id, node, child });
+        id = next;
+    }
+}
+
+/// Reads the bytes of `value`, following its chain of overflow pages with
+/// `read_overflow` if it has one.
+fn read_value(
+    value: LeafValue,
+    read_overflow: impl Fn(PageId) -> Result<(PageId, Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    let (mut id, len) = match value {
+        LeafValue::Inline(value) => return Ok(value),
+        LeafValue::Overflow { first, len } => (first, len as usize),
+    };
+    let mut bytes = Vec::new();
+    while bytes.len() < len && id != 0 {
+        let (next, chunk) = read_overflow(id)?;
+        if chunk.is_empty() {
+            break;
+        }
+        bytes.extend_from_slice(&chunk);
+        id = next;
+    }
+    if bytes.len() != len || id != 0 {
+        return Err(Error::corruption("overflow chain does not match its value"));
+    }
+    Ok(bytes)
+}
+
+/// Pages changed by one operation, held back from the buffer pool until the
+/// operation has been logged.
+struct Staged<'a> {
+    engine: &'a BTreeEngine,
+    meta: Meta,
+    original: Meta,
+    pages: BTreeMap<PageId, Box<[u8]>>,
+    fresh: BTreeSet<PageId>,
+    reservation: Reservation,
+}
+
+impl<'a> Staged<'a> {
+    fn new(engine: &'a BTreeEngine, meta: Meta) -> Self {
+        Staged {
+            engine,
+            meta,
+            original: meta,
+            pages: BTreeMap::new(),
+            fresh: BTreeSet::new(),
+            reservation: engine.budget.reservation(),
+        }
+    }
+
+    fn read_node(&self, id: PageId) -> Result<Node> {
+        match self.pages.get(&id) {
+            Some(page) => Node::decode(&page[PAGE_HEADER..]),
+            None => self.engine.read_node(id),
+        }
+    }
+
+    /// Body of page `id` staged for writing; zeroed if `fresh`.
+    fn body_mut(&mut self, id: PageId, fresh: bool) -> Result<&mut [u8]> {
+        if !self.pages.contains_key(&id) {
+            self.reservation.grow(self.engine.page_size)?;
+            let page = if fresh {
+                self.fresh.insert(id);
+                vec![0; self.engine.page_size].into_boxed_slice()
+            } else {
+                self.engine.pool.fetch(id)?.read().clone()
+            };
+            self.pages.insert(id, page);
+        }
+        let body = &mut self.pages.get_mut(&id).unwrap()[PAGE_HEADER..];
+        if fresh {
+            body.fill(0);
+        }
+        Ok(body)
+    }
+
+    fn write_node(&mut self, id: PageId, node: &Node, fresh: bool) -> Result<()> {
+        node.encode(self.body_mut(id, fresh)?);
+        Ok(())
+    }
+
+    fn read_overflow(&self, id: PageId) -> Result<(PageId, Vec<u8>)> {
+        match self.pages.get(&id) {
+            Some(page) => {
+                let (next, chunk) = decode_overflow(&page[PAGE_HEADER..])?;
+                Ok((next, chunk.to_vec()))
+            }
+            None => self.engine.read_overflow(id),
+        }
+    }
+
+    fn read_value(&self, value: LeafValue) -> Result<Vec<u8>> {
+        read_value(value, |id| self.read_overflow(id))
+    }
+
+    /// Leaf value storing `value` under `key`, written to a chain of
+    /// overflow pages if the entry would not fit in its leaf.
+    fn store_value(&mut self, key: &[u8], value: Vec<u8>) -> Result<LeafValue> {
+        self.engine.check_key(key)?;
+        if self.engine.fits_inline(key, &value) {
+            return Ok(LeafValue::Inline(value));
+        }
+        let chunks: Vec<&[u8]> = value
+            .chunks(overflow_capacity(self.engine.node_size))
+            .collect();
+        let ids = (0..chunks.len())
+            .map(|_| self.allocate())
+            .collect::<Result<Vec<_>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = ids.get(i + 1).copied().unwrap_or(0);
+            encode_overflow(next, chunk, self.body_mut(ids[i], true)?);
+        }
+        Ok(LeafValue::Overflow {
+            first: ids[0],
+            len: value.len() as u64,
+        })
+    }
+
+    /// Reads `value` and frees its overflow pages, if any.
+    fn take_value(&mut self, value: LeafValue) -> Result<Vec<u8>> {
+        let mut id = match value {
+            LeafValue::Inline(value) => return Ok(value),
+            LeafValue::Overflow { first, .. } => first,
+        };
+        let bytes = self.read_value(value)?;
+        while id != 0 {
+            let (next, _) = self.read_overflow(id)?;
+            self.free(id)?;
+            id = next;
+        }
+        Ok(bytes)
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        if self.meta.free_head != 0 {
+            let id = self.meta.free_head;
+            self.meta.free_head = match self.pages.get(&id) {
+                Some(page) => u64_at(&page[PAGE_HEADER..], 1),
+                None => u64_at(&self.engine.pool.fetch(id)?.read()[PAGE_HEADER..], 1),
+            };
+            return Ok(id);
+        }
+        let id = self.meta.page_count;
+        self.meta.page_count += 1;
+        Ok(id)
+    }
+
+    fn free(&mut self, id: PageId) -> Result<()> {
+        let next = self.meta.free_head;
+        self.body_mut(id, true)?[1..9].copy_from_slice(&next.to_le_bytes());
+        self.meta.free_head = id;
+        Ok(())
+    }
+
+    /// Stages the nodes of `path` modified by the current operation,
+    /// splitting overflowing nodes and merging underflowing ones bottom-up.
+    fn rebalance(&mut self, mut path: Vec<PathEntry>) -> Result<()> {
+        let node_size = self.engine.node_size;
+        let mut level = path.len() - 1;
+        loop {
+            let size = path[level].node.encoded_size();
+            if size > node_size {
+                let (right, sep) = split(&mut path[level].node);
+                let right_id = self.allocate()?;
+                let left_id = path[level].id;
+                if let Node::Leaf(left) = &mut path[level].node {
+                    left.next = right_id;
+                }
+                self.write_node(left_id, &path[level].node, false)?;
+                self.write_node(right_id, &right, true)?;
+                if level == 0 {
+                    let root_id = self.allocate()?;
+                    let root = Node::Internal(Internal {
+                        keys: vec![sep],
+                        children: vec![left_id, right_id],
+                    });
+                    self.write_node(root_id, &root, true)?;
+                    self.meta.root = root_id;
+                    break;
+                }
+                let parent = &mut path[level - 1];
+                let Node::Internal(internal) = &mut parent.node else {
+                    unreachable!("parents are internal nodes");
+                };
+                internal.keys.insert(parent.child, sep);
+                internal.children.insert(parent.child + 1, right_id);
+                level -= 1;
+                continue;
+            }
+            if level == 0 {
+                if let Node::Internal(root) = &path[0].node {
+                    if root.keys.is_empty() {
+                        self.meta.root = root.children[0];
+                        self.free(path[0].id)?;
+                        break;
+                    }
+                }
+            } else if size < node_size / 4 {
+                let node = path.pop().unwrap();
+                self.merge(path.last_mut().unwrap(), node)?;
+                level -= 1;
+                continue;
+            }
+            self.write_node(path[level].id, &path[level].node, false)?;
+            break;
+        }
+        Ok(())
+    }
+
+    /// Merges `node` with a sibling under `parent`, or redistributes their
+    /// entries if together they do not fit a page.
+    fn merge(&mut self, parent: &mut PathEntry, node: PathEntry) -> Result<()> {
+        let Node::Internal(internal) = &mut parent.node else {
+            unreachable!("parents are internal nodes");
+        };
+        let i = parent.child;
+        let (left_idx, (left_id, left), (right_id, right)) = if i > 0 {
+            let left_id = internal.children[i - 1];
+            let left = self.read_node(left_id)?;
+            (i - 1, (left_id, left), (node.id, node.node))
+        } else {
+            let right_id = internal.children[i + 1];
+            let right = self.read_node(right_id)?;
+            (i, (node.id, node.node), (right_id, right))
+        };
+        let sep = internal.keys[left_idx].clone();
+        let mut combined = match (left, right) {
+            (Node::Leaf(mut l), Node::Leaf(r)) => {
+                l.entries.extend(r.entries);
+                l.next = r.next;
+                Node::Leaf(l)
+            }
+            (Node::Internal(mut l), Node::Internal(r)) => {
+                l.keys.push(sep);
+                l.keys.extend(r.keys);
+                l.children.extend(r.children);
+                Node::Internal(l)
+            }
+            _ => return Err(Error::corruption("siblings at different depths")),
+        };
+        if combined.encoded_size() <= self.engine.node_size {
+            self.write_node(left_id, &combined, false)?;
+            self.free(right_id)?;
+            internal.keys.remove(left_idx);
+            internal.children.remove(left_idx + 1);
+        } else {
+            let (right, sep) = split(&mut combined);
+            if let Node::Leaf(left) = &mut combined {
+                left.next = right_id;
+            }
+            self.write_node(left_id, &combined, false)?;
+            self.write_node(right_id, &right, false)?;
+            internal.keys[left_idx] = sep;
+        }
+        Ok(())
+    }
+
+    /// Logs the staged pages with the record `record` builds from their
+    /// changes, then installs them in the buffer pool stamped with the
+    /// record's LSN and updates `meta`. Returns `None` if nothing changed.
+    fn commit(
+        mut self,
+        meta: &mut Meta,
+        record: impl FnOnce(Vec<PageChange>) -> LogRecord,
+    ) -> Result<Option<Lsn>> {
+        if self.meta != self.original {
+            let (page_size, new) = (self.engine.page_size, self.meta);
+            new.encode(page_size, self.body_mut(META_PAGE, false)?);
+        }
+        let pool = &self.engine.pool;
+        let mut changes = Vec::with_capacity(self.pages.len());
+        for (&id, page) in &self.pages {
+            let body = &page[PAGE_HEADER..];
+            let current = match self.fresh.contains(&id) {
+                true => None,
+                false => Some(pool.fetch(id)?),
+            };
+            let change = match current {
+                // The first change since the checkpoint logs the whole page,
+                // so that a torn write of it can be repaired.
+                Some(current) if page_lsn(&current.read()) > self.original.checkpoint_lsn => {
+                    let current = current.read();
+                    match diff(&current[PAGE_HEADER..], body) {
+                        Some((start, end)) => PageChange::Delta {
+                            page: id,
+                            offset: start,
+                            bytes: body[start..end].to_vec(),
+                        },
+                        None => continue,
+                    }
+                }
+                _ => PageChange::Image {
+                    page: id,
+                    body: body.to_vec(),
+                },
+            };
+            self.reservation.grow(match &change {
+                PageChange::Image { body, .. } => body.len(),
+                PageChange::Delta { bytes, .. } => bytes.len(),
+            })?;
+            changes.push(change);
+        }
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        let payload = record(changes).encode();
+        self.reservation.grow(payload.len())?;
+        let lsn = self.engine.wal.append(&payload)?;
+        for (id, page) in &self.pages {
+            let frame = match self.fresh.contains(id) {
+                true => pool.create(*id)?,
+                false => pool.fetch(*id)?,
+            };
+            let mut data = frame.write();
+            data.copy_from_slice(page);
+            stamp(&mut data, lsn);
+        }
+        *meta = self.meta;
+        Ok(Some(lsn))
+    }
+}
+
+/// Smallest range of bytes outside of which `old` and `new` agree.
+fn diff(old: &[u8], new: &[u8]) -> Option<(usize, usize)> {
+    let start = old.iter().zip(new).position(|(a, b)| a != b)?;
+    let end = new.len()
+        - old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .position(|(a, b)| a != b)?;
+    Some((start, end))
+}
+
+/// Splits `node` roughly in half by encoded size, leaving the lower half in
+/// place. Returns the upper half and the key separating the two.
+fn split(node: &mut Node) -> (Node, Vec<u8>) {
+    match node {
+        Node::Leaf(leaf) => {
+            let sizes: Vec<usize> = leaf
+                .entries
+                .iter()
+                .map(|(k, v)| k.len() + v.encoded_size())
+                .collect();
+            let at = split_point(&sizes).clamp(1, leaf.entries.len() - 1);
+            let entries = leaf.entries.split_off(at);
+            let sep = separator(&leaf.entries[at - 1].0, &entries[0].0);
+            let right = Leaf {
+                entries,
+                next: leaf.next,
+            };
+            (Node::Leaf(right), sep)
+        }
+        Node::Internal(internal) => {
+            let sizes: Vec<usize> = internal.keys.iter().map(|k| k.len() + 8).collect();
+            let at = split_point(&sizes).clamp(1, internal.keys.len() - 2);
+            let keys = internal.keys.split_off(at + 1);
+            let sep = internal.keys.pop().unwrap();
+            let children = internal.children.split_off(at + 1);
+            (Node::Internal(Internal { keys, children }), sep)
+        }
+    }
+}
+
+/// Index at which the cumulative size first reaches half of the total.
+fn split_point(sizes: &[usize]) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut acc = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        acc += size;
+        if acc >= half {
+            return i + 1;
+        }
+    }
+    sizes.len()
+}
+
+/// Iterator over a range of a [`BTreeEngine`].
+pub struct BTreeScan<'a> {
+    engine: &'a BTreeEngine,
+    range: KeyRange,
+    last: Option<Vec<u8>>,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl Iterator for BTreeScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            match self.engine.scan_batch(&self.range, self.last.as_deref()) {
+                Ok((batch, done)) => {
+                    self.buffer.extend(batch);
+                    self.done = done;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        let (key, value) = self.buffer.pop_front()?;
+        self.last = Some(key.clone());
+        Some(Ok((key, value)))
+    }
+}
@@ -0,0 +1,265 @@
+//! On-page representation of B+tree nodes.
+//!
+//! Nodes are decoded into owned structures, modified and encoded back. Keys
+//! of a node share a common prefix that is stored once:
+//!
+//! ```text
+//! kind:u8 count:u16 link:u64 prefix:bytes entry*
+//! leaf entry:     suffix:bytes 0:u8 value:bytes
+//!               | suffix:bytes 1:u8 first:u64 len:u64
+//! internal entry: suffix:bytes child:u64
+//! ```
+//!
+//! `link` is the next leaf for leaves and the leftmost child for internal
+//! nodes. A value too large for a leaf is stored in a chain of overflow pages
+//! starting at `first`, each holding part of it:
+//!
+//! ```text
+//! kind:u8 next:u64 len:u32 data
+//! ```
+
+use crate::buffer::PageId;
+use crate::coding::{put_bytes, put_u32, put_u64, u32_at, u64_at, Reader};
+use crate::error::{Error, Result};
+
+const KIND_LEAF: u8 = 1;
+const KIND_INTERNAL: u8 = 2;
+const KIND_OVERFLOW: u8 = 3;
+
+const VALUE_INLINE: u8 = 0;
+const VALUE_OVERFLOW: u8 = 1;
+
+/// Bytes of the fixed part of the header, before the prefix.
+const HEADER: usize = 1 + 2 + 8;
+
+/// Bytes of the header of an overflow page, before its data.
+const OVERFLOW_HEADER: usize = 1 + 8 + 4;
+
+/// Bytes a leaf entry spends on a value stored in overflow pages.
+pub(crate) const OVERFLOW_REF: usize = 1 + 8 + 8;
+
+#[derive(Debug, Clone)]
+pub(crate) enum Node {
+    Leaf(Leaf),
+    Internal(Internal),
+}
+
+/// Sorted key-value pairs plus the id of the next leaf, `0` for none.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Leaf {
+    pub(crate) entries: Vec<(Vec<u8>, LeafValue)>,
+    pub(crate) next: PageId,
+}
+
+/// Value of a leaf entry.
+#[derive(Debug, Clone)]
+pub(crate) enum LeafValue {
+    /// Stored in the leaf itself.
+    Inline(Vec<u8>),
+    /// `len` bytes stored in the chain of overflow pages starting at `first`.
+    Overflow { first: PageId, len: u64 },
+}
+
+impl LeafValue {
+    /// Bytes the value occupies in the leaf once encoded.
+    pub(crate) fn encoded_size(&self) -> usize {
+        match self {
+            LeafValue::Inline(value) => 1 + bytes_len(value.len()),
+            LeafValue::Overflow { .. } => OVERFLOW_REF,
+        }
+    }
+}
+
+/// Separator keys and children; `children[i]` holds the keys `k` with
+/// `keys[i - 1] <= k < keys[i]`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Internal {
+    pub(crate) keys: Vec<Vec<u8>>,
+    pub(crate) children: Vec<PageId>,
+}
+
+impl Internal {
+    /// Index of the child whose subtree may contain `key`.
+    pub(crate) fn child_index(&self, key: &[u8]) -> usize {
+        self.keys.partition_point(|k| k.as_slice() <= key)
+    }
+}
+
+fn varint_len(v: usize) -> usize {
+    let bits = usize::BITS - (v | 1).leading_zeros();
+    bits.div_ceil(7) as usize
+}
+
+fn bytes_len(len: usize) -> usize {
+    varint_len(len) + len
+}
+
+fn common_prefix<'a>(mut keys: impl Iterator<Item = &'a [u8]>) -> usize {
+    let Some(first) = keys.next() else {
+        return 0;
+    };
+    // Keys are sorted, so the common prefix of all of them is the common
+    // prefix of the first and the last.
+    let last = keys.last().unwrap_or(first);
+    first.iter().zip(last).take_while(|(a, b)| a == b).count()
+}
+
+impl Node {
+    fn prefix_len(&self) -> usize {
+        match self {
+            Node::Leaf(leaf) => common_prefix(leaf.entries.iter().map(|(k, _)| k.as_slice())),
+            Node::Internal(node) => common_prefix(node.keys.iter().map(Vec::as_slice)),
+        }
+    }
+
+    /// Bytes the node occupies once encoded.
+    pub(crate) fn encoded_size(&self) -> usize {
+        let prefix = self.prefix_len();
+        let entries: usize = match self {
+            Node::Leaf(leaf) => leaf
+                .entries
+                .iter()
+                .map(|(k, v)| bytes_len(k.len() - prefix) + v.encoded_size())
+                .sum(),
+            Node::Internal(node) => node
+                .keys
+                .iter()
+                .map(|k| bytes_len(k.len() - prefix) + 8)
+                .sum(),
+        };
+        HEADER + bytes_len(prefix) + entries
+    }
+
+    /// Encodes the node into `page`, which must be large enough.
+    pub(crate) fn encode(&self, page: &mut [u8]) {
+        let prefix_len = self.prefix_len();
+        let mut buf = Vec::with_capacity(page.len());
+        match self {
+            Node::Leaf(leaf) => {
+                buf.push(KIND_LEAF);
+                buf.extend_from_slice(&(leaf.entries.len() as u16).to_le_bytes());
+                put_u64(&mut buf, leaf.next);
+                let prefix = leaf
+                    .entries
+                    .first()
+                    .map_or(&[][..], |(k, _)| &k[..prefix_len]);
+                put_bytes(&mut buf, prefix);
+                for (key, value) in &leaf.entries {
+                    put_bytes(&mut buf, &key[prefix_len..]);
+                    match value {
+                        LeafValue::Inline(value) => {
+                            buf.push(VALUE_INLINE);
+                            put_bytes(&mut buf, value);
+                        }
+                        LeafValue::Overflow { first, len } => {
+                            buf.push(VALUE_OVERFLOW);
+                            put_u64(&mut buf, *first);
+                            put_u64(&mut buf, *len);
+                        }
+                    }
+                }
+            }
+            Node::Internal(node) => {
+                buf.push(KIND_INTERNAL);
+                buf.extend_from_slice(&(node.keys.len() as u16).to_le_bytes());
+                put_u64(&mut buf, node.children[0]);
+                let prefix = node.keys.first().map_or(&[][..], |k| &k[..prefix_len]);
+                put_bytes(&mut buf, prefix);
+                for (key, child) in node.keys.iter().zip(&node.children[1..]) {
+                    put_bytes(&mut buf, &key[prefix_len..]);
+                    put_u64(&mut buf, *child);
+                }
+            }
+        }
+        debug_assert!(buf.len() <= page.len());
+        page[..buf.len()].copy_from_slice(&buf);
+        page[buf.len()..].fill(0);
+    }
+
+    /// Decodes the node stored in `page`.
+    pub(crate) fn decode(page: &[u8]) -> Result<Node> {
+        let mut reader = Reader::new(page);
+        let kind = reader.u8()?;
+        let count = u16::from_le_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+        let link = reader.u64()?;
+        let prefix = reader.bytes()?;
+        let full_key = |suffix: &[u8]| {
+            let mut key = Vec::with_capacity(prefix.len() + suffix.len());
+            key.extend_from_slice(prefix);
+            key.extend_from_slice(suffix);
+            key
+        };
+        match kind {
+            KIND_LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = full_key(reader.bytes()?);
+                    let value = match reader.u8()? {
+                        VALUE_INLINE => LeafValue::Inline(reader.bytes()?.to_vec()),
+                        VALUE_OVERFLOW => LeafValue::Overflow {
+                            first: reader.u64()?,
+                            len: reader.u64()?,
+                        },
+                        tag => return Err(Error::corruption(format!("unknown value tag {tag}"))),
+                    };
+                    entries.push((key, value));
+                }
+                Ok(Node::Leaf(Leaf {
+                    entries,
+                    next: link,
+                }))
+            }
+            KIND_INTERNAL => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(link);
+                for _ in 0..count {
+                    keys.push(full_key(reader.bytes()?));
+                    children.push(reader.u64()?);
+                }
+                Ok(Node::Internal(Internal { keys, children }))
+            }
+            _ => Err(Error::corruption(format!("unknown node kind {kind}"))),
+        }
+    }
+}
+
+/// Bytes of value an overflow page of a node-sized body holds.
+pub(crate) fn overflow_capacity(node_size: usize) -> usize {
+    node_size - OVERFLOW_HEADER
+}
+
+/// Encodes into `page` an overflow page holding `data` and linking to the
+/// page `next`, `0` for none.
+pub(crate) fn encode_overflow(next: PageId, data: &[u8], page: &mut [u8]) {
+    let mut buf = Vec::with_capacity(OVERFLOW_HEADER);
+    buf.push(KIND_OVERFLOW);
+    put_u64(&mut buf, next);
+    put_u32(&mut buf, data.len() as u32);
+    page[..OVERFLOW_HEADER].copy_from_slice(&buf);
+    page[OVERFLOW_HEADER..OVERFLOW_HEADER + data.len()].copy_from_slice(data);
+    page[OVERFLOW_HEADER + data.len()..].fill(0);
+}
+
+/// Decodes the overflow page stored in `page` into the page it links to and
+/// the data it holds.
+pub(crate) fn decode_overflow(page: &[u8]) -> Result<(PageId, &[u8])> {
+    if page[0] != KIND_OVERFLOW {
+        return Err(Error::corruption("expected an overflow page"));
+    }
+    let len = u32_at(page, 9) as usize;
+    if len > overflow_capacity(page.len()) {
+        return Err(Error::corruption("malformed overflow page"));
+    }
+    Ok((
+        u64_at(page, 1),
+        &page[OVERFLOW_HEADER..OVERFLOW_HEADER + len],
+    ))
+}
+
+/// Shortest key `s` with `left < s <= right`, used as separator between two
+/// siblings so that internal nodes hold as little as possible.
+pub(crate) fn separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let shared = left.iter().zip(right).take_while(|(a, b)| a == b).count();
+    right[..(shared + 1).min(right.len())].to_vec()
+}
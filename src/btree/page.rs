@@ -0,0 +1,69 @@
+//! Header shared by every page of the B+tree file.
+//!
+//! ```text
+//! crc:u32 page_lsn:u64 body
+//! ```
+//!
+//! The checksum covers the rest of the page and reveals pages torn by a crash
+//! in the middle of their write. `page_lsn` is the log position of the last
+//! record applied to the page; a page is never written before the log is
+//! durable up to it.
+
+use std::sync::Arc;
+
+use crate::buffer::{FilePageStore, PageId, PageStore};
+use crate::checksum::crc32c;
+use crate::coding::{u32_at, u64_at};
+use crate::error::{Error, Result};
+use crate::wal::{Durability, Lsn, Wal};
+
+/// Bytes of the header in front of the body.
+pub(crate) const PAGE_HEADER: usize = 12;
+
+pub(crate) fn page_lsn(page: &[u8]) -> Lsn {
+    u64_at(page, 4)
+}
+
+/// Records that `page` reflects the log up to `lsn` and checksums it.
+pub(crate) fn stamp(page: &mut [u8], lsn: Lsn) {
+    page[4..PAGE_HEADER].copy_from_slice(&lsn.to_le_bytes());
+    let crc = crc32c(&page[4..]);
+    page[..4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Whether `page` was written whole. Pages never written read as zeroes and
+/// count as intact.
+pub(crate) fn is_intact(page: &[u8]) -> bool {
+    u32_at(page, 0) == crc32c(&page[4..]) || page.iter().all(|&b| b == 0)
+}
+
+/// [`PageStore`] enforcing the write-ahead rule and checking page checksums.
+pub(crate) struct LoggedStore {
+    inner: FilePageStore,
+    wal: Arc<Wal>,
+}
+
+impl LoggedStore {
+    pub(crate) fn new(inner: FilePageStore, wal: Arc<Wal>) -> Self {
+        LoggedStore { inner, wal }
+    }
+}
+
+impl PageStore for LoggedStore {
+    fn read_page(&self, id: PageId, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_page(id, buf)?;
+        if !is_intact(buf) {
+            return Err(Error::corruption(format!("page {id} is torn")));
+        }
+        Ok(())
+    }
+
+    fn write_page(&self, id: PageId, buf: &[u8]) -> Result<()> {
+        self.wal.commit(page_lsn(buf), Durability::Sync)?;
+        self.inner.write_page(id, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
@@ -0,0 +1,463 @@
+//! Victim selection for the buffer pool.
+//!
+//! The pool tells an [`EvictionPolicy`] about every access and about which
+//! frames are currently unpinned; the policy only decides which unpinned frame
+//! to give up when a new page has to be brought in.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use super::{FrameId, PageId};
+
+/// Strategy used by the buffer pool to choose which frame to evict.
+pub trait EvictionPolicy: Send {
+    /// Records that `frame` now holds `page` and has just been accessed.
+    fn record_access(&mut self, frame: FrameId, page: PageId);
+
+    /// Marks whether `frame` may be chosen as a victim. Pinned frames are never
+    /// evictable.
+    fn set_evictable(&mut self, frame: FrameId, evictable: bool);
+
+    /// Chooses an evictable frame, forgets it and returns it.
+    fn evict(&mut self) -> Option<FrameId>;
+
+    /// Forgets `frame`, whose page was dropped from the pool.
+    fn remove(&mut self, frame: FrameId);
+}
+
+/// Built-in eviction policies selectable through [`Options`](crate::Options).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eviction {
+    /// Least recently used.
+    #[default]
+    Lru,
+    /// Second-chance clock; cheapest bookkeeping, suited to tiny devices.
+    Clock,
+    /// 2Q: pages seen once are kept apart from frequently used pages.
+    TwoQ,
+    /// LRU-K: evicts the frame with the largest backward K-distance, which
+    /// protects the working set against sequential scans.
+    LruK {
+        /// Number of past accesses considered; must be at least 1.
+        k: usize,
+    },
+}
+
+impl Eviction {
+    /// Instantiates the policy for a pool of `frames` frames.
+    pub fn build(self, frames: usize) -> Box<dyn EvictionPolicy> {
+        match self {
+            Eviction::Lru => Box::new(Lru::new(frames)),
+            Eviction::Clock => Box::new(Clock::new(frames)),
+            Eviction::TwoQ => Box::new(TwoQ::new(frames)),
+            Eviction::LruK { k } => Box::new(LruK::new(frames, k)),
+        }
+    }
+}
+
+/// Least-recently-used eviction.
+#[derive(Debug)]
+pub struct Lru {
+    clock: u64,
+    stamps: Vec<u64>,
+    evictable: Vec<bool>,
+    queue: BTreeSet<(u64, FrameId)>,
+}
+
+impl Lru {
+    /// Creates the policy for `frames` frames.
+    pub fn new(frames: usize) -> Self {
+        Lru {
+            clock: 0,
+            stamps: vec![0; frames],
+            evictable: vec![false; frames],
+            queue: BTreeSet::new(),
+        }
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn record_access(&mut self, frame: FrameId, _page: PageId) {
+        self.clock += 1;
+        if self.evictable[frame] {
+            self.queue.remove(&(self.stamps[frame], frame));
+            self.queue.insert((self.clock, frame));
+        }
+        self.stamps[frame] = self.clock;
+    }
+
+    fn set_evictable(&mut self, frame: FrameId, evictable: bool) {
+        if self.evictable[frame] == evictable {
+            return;
+        }
+        self.evictable[frame] = evictable;
+        if evictable {
+            self.queue.insert((self.stamps[frame], frame));
+        } else {
+            self.queue.remove(&(self.stamps[frame], frame));
+        }
+    }
+
+    fn evict(&mut self) -> Option<FrameId> {
+        let (_, frame) = self.queue.pop_first()?;
+        self.evictable[frame] = false;
+        Some(frame)
+    }
+
+    fn remove(&mut self, frame: FrameId) {
+        self.set_evictable(frame, false);
+    }
+}
+
+/// Second-chance clock eviction.
+#[derive(Debug)]
+pub struct Clock {
+    hand: usize,
+    referenced: Vec<bool>,
+    evictable: Vec<bool>,
+    candidates: usize,
+}
+
+impl Clock {
+    /// Creates the policy for `frames` frames.
+    pub fn new(frames: usize) -> Self {
+        Clock {
+            hand: 0,
+            referenced: vec![false; frames],
+            evictable: vec![false; frames],
+            candidates: 0,
+        }
+    }
+}
+
+impl EvictionPolicy for Clock {
+    fn record_access(&mut self, frame: FrameId, _page: PageId) {
+        self.referenced[frame] = true;
+    }
+
+    fn set_evictable(&mut self, frame: FrameId, evictable: bool) {
+        if self.evictable[frame] != evictable {
+            self.evictable[frame] = evictable;
+            if evictable {
+                self.candidates += 1;
+            } else {
+                self.candidates -= 1;
+            }
+        }
+    }
+
+    fn evict(&mut self) -> Option<FrameId> {
+        if self.candidates == 0 {
+            return None;
+        }
+        let frames = self.evictable.len();
+        // Two sweeps are enough: the first clears every reference bit.
+        for _ in 0..2 * frames {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % frames;
+            if !self.evictable[frame] {
+                continue;
+            }
+            if self.referenced[frame] {
+                self.referenced[frame] = false;
+                continue;
+            }
+            self.set_evictable(frame, false);
+            return Some(frame);
+        }
+        None
+    }
+
+    fn remove(&mut self, frame: FrameId) {
+        self.set_evictable(frame, false);
+        self.referenced[frame] = false;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queue {
+    None,
+    /// Pages accessed once since they were brought in (FIFO).
+    Recent,
+    /// Pages accessed again, or recently evicted from `Recent` (LRU).
+    Frequent,
+}
+
+/// 2Q eviction with a FIFO for first-time pages, an LRU for hot pages and a
+/// ghost list remembering pages recently evicted from the FIFO.
+#[derive(Debug)]
+pub struct TwoQ {
+    clock: u64,
+    queue: Vec<Queue>,
+    stamps: Vec<u64>,
+    evictable: Vec<bool>,
+    recent: BTreeSet<(u64, FrameId)>,
+    frequent: BTreeSet<(u64, FrameId)>,
+    recent_len: usize,
+    recent_target: usize,
+    pages: Vec<PageId>,
+    ghosts: VecDeque<PageId>,
+    ghost_set: HashSet<PageId>,
+    ghost_capacity: usize,
+}
+
+impl TwoQ {
+    /// Creates the policy for `frames` frames.
+    ///
+    /// A quarter of the pool is reserved for first-time pages and the ghost
+    /// list remembers half a pool worth of page ids, as recommended by the
+    /// original 2Q paper.
+    pub fn new(frames: usize) -> Self {
+        TwoQ {
+            clock: 0,
+            queue: vec![Queue::None; frames],
+            stamps: vec![0; frames],
+            evictable: vec![false; frames],
+            recent: BTreeSet::new(),
+            frequent: BTreeSet::new(),
+            recent_len: 0,
+            recent_target: (frames / 4).max(1),
+            pages: vec![0; frames],
+            ghosts: VecDeque::new(),
+            ghost_set: HashSet::new(),
+            ghost_capacity: (frames / 2).max(1),
+        }
+    }
+
+    fn set_of(&mut self, queue: Queue) -> &mut BTreeSet<(u64, FrameId)> {
+        match queue {
+            Queue::Recent => &mut self.recent,
+            _ => &mut self.frequent,
+        }
+    }
+
+    fn detach(&mut self, frame: FrameId) {
+        let queue = self.queue[frame];
+        if queue == Queue::None {
+            return;
+        }
+        if self.evictable[frame] {
+            let key = (self.stamps[frame], frame);
+            self.set_of(queue).remove(&key);
+        }
+        if queue == Queue::Recent {
+            self.recent_len -= 1;
+        }
+        self.queue[frame] = Queue::None;
+    }
+
+    fn attach(&mut self, frame: FrameId, queue: Queue) {
+        self.clock += 1;
+        self.stamps[frame] = self.clock;
+        self.queue[frame] = queue;
+        if queue == Queue::Recent {
+            self.recent_len += 1;
+        }
+        if self.evictable[frame] {
+            let key = (self.clock, frame);
+            self.set_of(queue).insert(key);
+        }
+    }
+
+    fn remember(&mut self, page: PageId) {
+        if self.ghost_set.insert(page) {
+            self.ghosts.push_back(page);
+            if self.ghosts.len() > self.ghost_capacity {
+                if let Some(old) = self.ghosts.pop_front() {
+                    self.ghost_set.remove(&old);
+                }
+            }
+        }
+    }
+}
+
+impl EvictionPolicy for TwoQ {
+    fn record_access(&mut self, frame: FrameId, page: PageId) {
+        match self.queue[frame] {
+            // Correlated re-references inside the FIFO do not promote a page.
+            Queue::Recent if self.pages[frame] == page => {}
+            Queue::Frequent if self.pages[frame] == page => {
+                self.detach(frame);
+                self.attach(frame, Queue::Frequent);
+            }
+            _ => {
+                self.detach(frame);
+                self.pages[frame] = page;
+                let queue = if self.ghost_set.remove(&page) {
+                    Queue::Frequent
+                } else {
+                    Queue::Recent
+                };
+                self.attach(frame, queue);
+            }
+        }
+    }
+
+    fn set_evictable(&mut self, frame: FrameId, evictable: bool) {
+        if self.evictable[frame] == evictable {
+            return;
+        }
+        self.evictable[frame] = evictable;
+        let queue = self.queue[frame];
+        if queue == Queue::None {
+            return;
+        }
+        let key = (self.stamps[frame], frame);
+        if evictable {
+            self.set_of(queue).insert(key);
+        } else {
+            self.set_of(queue).remove(&key);
+        }
+    }
+
+    fn evict(&mut self) -> Option<FrameId> {
+        let prefer_recent = self.recent_len > self.recent_target || self.frequent.is_empty();
+        let (_, frame) = if prefer_recent {
+            self.recent
+                .first()
+                .or_else(|| self.frequent.first())
+                .copied()?
+        } else {
+            self.frequent
+                .first()
+                .or_else(|| self.recent.first())
+                .copied()?
+        };
+        if self.queue[frame] == Queue::Recent {
+            self.remember(self.pages[frame]);
+        }
+        self.detach(frame);
+        self.evictable[frame] = false;
+        Some(frame)
+    }
+
+    fn remove(&mut self, frame: FrameId) {
+        self.detach(frame);
+        self.evictable[frame] = false;
+    }
+}
+
+/// LRU-K eviction.
+///
+/// Pages with fewer than `k` recorded accesses have an infinite backward
+/// K-distance and are evicted first, oldest first access first; among the
+/// others the page whose K-th most recent access is oldest goes.
+///
+/// Accesses are recorded per page rather than per frame, and the history of
+/// an evicted page is retained for as many evictions as the pool has frames,
+/// so a page read again soon after its eviction keeps its standing instead of
+/// competing with pages touched once by a scan.
+#[derive(Debug)]
+pub struct LruK {
+    k: usize,
+    clock: u64,
+    /// Page held by each frame.
+    pages: Vec<Option<PageId>>,
+    history: HashMap<PageId, History>,
+    /// Evicted pages whose history is retained, by eviction number.
+    retained: BTreeMap<u64, PageId>,
+    evictions: u64,
+    evictable: Vec<bool>,
+    queue: BTreeSet<(bool, u64, FrameId)>,
+}
+
+/// Most recent accesses of a page, oldest first.
+#[derive(Debug, Default)]
+struct History {
+    accesses: VecDeque<u64>,
+    /// Eviction number under which the page is retained, if evicted.
+    evicted: Option<u64>,
+}
+
+impl LruK {
+    /// Creates the policy for `frames` frames; `k` is clamped to at least 1.
+    pub fn new(frames: usize, k: usize) -> Self {
+        LruK {
+            k: k.max(1),
+            clock: 0,
+            pages: vec![None; frames],
+            history: HashMap::new(),
+            retained: BTreeMap::new(),
+            evictions: 0,
+            evictable: vec![false; frames],
+            queue: BTreeSet::new(),
+        }
+    }
+
+    fn key(&self, frame: FrameId) -> (bool, u64, FrameId) {
+        let accesses = self.pages[frame]
+            .and_then(|page| self.history.get(&page))
+            .map(|history| &history.accesses);
+        let full = accesses.is_some_and(|a| a.len() >= self.k);
+        let first = accesses.and_then(|a| a.front().copied()).unwrap_or(0);
+        (full, first, frame)
+    }
+
+    /// Takes the page out of `frame`, retaining its history.
+    fn release(&mut self, frame: FrameId) {
+        let Some(page) = self.pages[frame].take() else {
+            return;
+        };
+        let Some(history) = self.history.get_mut(&page) else {
+            return;
+        };
+        self.evictions += 1;
+        history.evicted = Some(self.evictions);
+        self.retained.insert(self.evictions, page);
+        if self.retained.len() > self.pages.len() {
+            if let Some((_, old)) = self.retained.pop_first() {
+                self.history.remove(&old);
+            }
+        }
+    }
+}
+
+impl EvictionPolicy for LruK {
+    fn record_access(&mut self, frame: FrameId, page: PageId) {
+        if self.evictable[frame] {
+            self.queue.remove(&self.key(frame));
+        }
+        if self.pages[frame] != Some(page) {
+            self.release(frame);
+            self.pages[frame] = Some(page);
+        }
+        self.clock += 1;
+        let history = self.history.entry(page).or_default();
+        if let Some(evicted) = history.evicted.take() {
+            self.retained.remove(&evicted);
+        }
+        history.accesses.push_back(self.clock);
+        if history.accesses.len() > self.k {
+            history.accesses.pop_front();
+        }
+        if self.evictable[frame] {
+            self.queue.insert(self.key(frame));
+        }
+    }
+
+    fn set_evictable(&mut self, frame: FrameId, evictable: bool) {
+        if self.evictable[frame] == evictable {
+            return;
+        }
+        self.evictable[frame] = evictable;
+        if evictable {
+            self.queue.insert(self.key(frame));
+        } else {
+            self.queue.remove(&self.key(frame));
+        }
+    }
+
+    fn evict(&mut self) -> Option<FrameId> {
+        let (_, _, frame) = self.queue.pop_first()?;
+        self.evictable[frame] = false;
+        self.release(frame);
+        Some(frame)
+    }
+
+    fn remove(&mut self, frame: FrameId) {
+        self.set_evictable(frame, false);
+        // The page was freed, so its history is of no further use.
+        if let Some(page) = self.pages[frame].take() {
+            self.history.remove(&page);
+        }
+    }
+}
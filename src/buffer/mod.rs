@@ -0,0 +1,396 @@
+//! Fixed-size page cache.
+//!
+//! A [`BufferPool`] owns a fixed number of page-sized frames whose memory is
+//! reserved from the [`MemoryBudget`] once, when the pool is created. Pages are
+//! read from a [`PageStore`] on demand and stay pinned while a [`PageRef`] to
+//! them is alive; unpinned pages are candidates for eviction according to the
+//! configured [`EvictionPolicy`].
+
+mod eviction;
+
+pub use eviction::{Clock, Eviction, EvictionPolicy, Lru, LruK, TwoQ};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reservation};
+use crate::options::Options;
+use crate::vfs::{Vfs, VfsFile};
+
+/// Identifier of a page within a [`PageStore`].
+pub type PageId = u64;
+
+/// Index of a frame within a [`BufferPool`].
+pub type FrameId = usize;
+
+/// Bookkeeping bytes charged per frame on top of the page itself.
+const FRAME_OVERHEAD: usize = 64;
+
+/// Backing storage the buffer pool reads pages from and writes them back to.
+pub trait PageStore: Send + Sync {
+    /// Fills `buf` with the contents of page `id`. Pages that were never
+    /// written read as zeroes.
+    fn read_page(&self, id: PageId, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes `buf` as the new contents of page `id`.
+    fn write_page(&self, id: PageId, buf: &[u8]) -> Result<()>;
+
+    /// Makes every completed write durable.
+    fn sync(&self) -> Result<()>;
+}
+
+/// [`PageStore`] over a single file, page `n` living at offset `n * page_size`.
+pub struct FilePageStore {
+    file: Box<dyn VfsFile>,
+    page_size: usize,
+}
+
+impl FilePageStore {
+    /// Opens or creates the file at `path` in `vfs`.
+    pub fn open(vfs: &dyn Vfs, path: impl AsRef<Path>, page_size: usize) -> Result<Self> {
+        let file = vfs.open(path.as_ref(), true)?;
+        Ok(FilePageStore { file, page_size })
+    }
+
+    fn offset(&self, id: PageId) -> u64 {
+        id * self.page_size as u64
+    }
+}
+
+impl PageStore for FilePageStore {
+    fn read_page(&self, id: PageId, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self
+                .file
+                .read_at(&mut buf[filled..], self.offset(id) + filled as u64)?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        buf[filled..].fill(0);
+        Ok(())
+    }
+
+    fn write_page(&self, id: PageId, buf: &[u8]) -> Result<()> {
+        self.file.write_at(buf, self.offset(id))?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()?;
+        Ok(())
+    }
+}
+
+/// Counters describing how well the pool is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Fetches served from a resident frame.
+    pub hits: u64,
+    /// Fetches that had to read the page from the store.
+    pub misses: u64,
+    /// Pages dropped to make room for another page.
+    pub evictions: u64,
+    /// Dirty pages written back to the store.
+    pub writebacks: u64,
+}
+
+impl BufferPoolStats {
+    /// Fraction of fetches served without I/O, or `0.0` before any fetch.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    writebacks: AtomicU64,
+}
+
+struct Frame {
+    data: RwLock<Box<[u8]>>,
+    dirty: AtomicBool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct FrameMeta {
+    page: Option<PageId>,
+    pins: usize,
+}
+
+struct State {
+    page_table: HashMap<PageId, FrameId>,
+    meta: Vec<FrameMeta>,
+    free: Vec<FrameId>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+/// Fixed-size cache of pages with pin/unpin semantics.
+pub struct BufferPool {
+    store: Box<dyn PageStore>,
+    page_size: usize,
+    frames: Vec<Frame>,
+    state: Mutex<State>,
+    counters: Counters,
+    _reservation: Reservation,
+}
+
+impl BufferPool {
+    /// Creates a pool sized and configured from `options`.
+    pub fn new(
+        store: Box<dyn PageStore>,
+        budget: &MemoryBudget,
+        options: &Options,
+    ) -> Result<Self> {
+        let frames = options.buffer_pool_bytes() / (options.page_size + FRAME_OVERHEAD);
+        let policy = options.eviction.build(frames);
+        Self::with_policy(store, budget, options.page_size, frames, policy)
+    }
+
+    /// Creates a pool of `frames` frames of `page_size` bytes using a custom
+    /// eviction policy.
+    pub fn with_policy(
+        store: Box<dyn PageStore>,
+        budget: &MemoryBudget,
+        page_size: usize,
+        frames: usize,
+        policy: Box<dyn EvictionPolicy>,
+    ) -> Result<Self> {
+        if frames == 0 || page_size == 0 {
+            return Err(Error::invalid("buffer pool needs at least one frame"));
+        }
+        let reservation = budget.reserve(frames * (page_size + FRAME_OVERHEAD))?;
+        let frames_vec = (0..frames)
+            .map(|_| Frame {
+                data: RwLock::new(vec![0; page_size].into_boxed_slice()),
+                dirty: AtomicBool::new(false),
+            })
+            .collect();
+        Ok(BufferPool {
+            store,
+            page_size,
+            frames: frames_vec,
+            state: Mutex::new(State {
+                page_table: HashMap::with_capacity(frames),
+                meta: vec![FrameMeta::default(); frames],
+                free: (0..frames).rev().collect(),
+                policy,
+            }),
+            counters: Counters::default(),
+            _reservation: reservation,
+        })
+    }
+
+    /// Size of every page in bytes.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Number of frames in the pool.
+    pub fn capacity(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Snapshot of the pool's counters.
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            writebacks: self.counters.writebacks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Pins page `id`, reading it from the store if it is not resident.
+    pub fn fetch(&self, id: PageId) -> Result<PageRef<'_>> {
+        self.pin(id, true)
+    }
+
+    /// Pins page `id` without reading it, zeroing the frame instead. Used for
+    /// freshly allocated pages whose previous contents do not matter.
+    pub fn create(&self, id: PageId) -> Result<PageRef<'_>> {
+        let page = self.pin(id, false)?;
+        page.write().fill(0);
+        Ok(page)
+    }
+
+    /// Writes page `id` back to the store if it is resident and dirty.
+    pub fn flush(&self, id: PageId) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        if let Some(&frame) = state.page_table.get(&id) {
+            self.write_back(frame, id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty page back and syncs the store.
+    pub fn flush_all(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        for (&id, &frame) in &state.page_table {
+            self.write_back(frame, id)?;
+        }
+        self.store.sync()
+    }
+
+    /// Drops page `id` from the pool without writing it back, for pages that
+    /// have been freed by their owner.
+    pub fn discard(&self, id: PageId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(&frame) = state.page_table.get(&id) else {
+            return Ok(());
+        };
+        if state.meta[frame].pins > 0 {
+            return Err(Error::invalid(format!("page {id} is pinned")));
+        }
+        state.page_table.remove(&id);
+        state.meta[frame].page = None;
+        state.policy.remove(frame);
+        state.free.push(frame);
+        self.frames[frame].dirty.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    fn pin(&self, id: PageId, read: bool) -> Result<PageRef<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&frame) = state.page_table.get(&id) {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            let State { meta, policy, .. } = &mut *state;
+            meta[frame].pins += 1;
+            policy.set_evictable(frame, false);
+            policy.record_access(frame, id);
+            return Ok(PageRef {
+                pool: self,
+                frame,
+                id,
+            });
+        }
+
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let frame = match state.free.pop() {
+            Some(frame) => frame,
+            None => {
+                let frame = state.policy.evict().ok_or(Error::BufferPoolFull {
+                    frames: self.frames.len(),
+                })?;
+                let old = state.meta[frame].page.expect("evicted frame holds a page");
+                if let Err(e) = self.write_back(frame, old) {
+                    state.policy.record_access(frame, old);
+                    state.policy.set_evictable(frame, true);
+                    return Err(e);
+                }
+                state.page_table.remove(&old);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+                frame
+            }
+        };
+
+        if read {
+            let mut data = self.frames[frame].data.write().unwrap();
+            if let Err(e) = self.store.read_page(id, &mut data) {
+                state.meta[frame].page = None;
+                state.free.push(frame);
+                return Err(e);
+            }
+        }
+        state.page_table.insert(id, frame);
+        state.meta[frame] = FrameMeta {
+            page: Some(id),
+            pins: 1,
+        };
+        state.policy.record_access(frame, id);
+        Ok(PageRef {
+            pool: self,
+            frame,
+            id,
+        })
+    }
+
+    fn unpin(&self, frame: FrameId) {
+        let mut state = self.state.lock().unwrap();
+        let meta = &mut state.meta[frame];
+        meta.pins -= 1;
+        if meta.pins == 0 {
+            state.policy.set_evictable(frame, true);
+        }
+    }
+
+    /// Writes `frame` back if dirty. Callers hold the state lock so the frame
+    /// cannot be reassigned meanwhile.
+    fn write_back(&self, frame: FrameId, id: PageId) -> Result<()> {
+        let slot = &self.frames[frame];
+        if !slot.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let data = slot.data.read().unwrap();
+        if let Err(e) = self.store.write_page(id, &data) {
+            slot.dirty.store(true, Ordering::Release);
+            return Err(e);
+        }
+        self.counters.writebacks.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("page_size", &self.page_size)
+            .field("frames", &self.frames.len())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// A pinned page. The page stays resident until every `PageRef` to it has
+/// been dropped.
+pub struct PageRef<'a> {
+    pool: &'a BufferPool,
+    frame: FrameId,
+    id: PageId,
+}
+
+impl PageRef<'_> {
+    /// Identifier of the pinned page.
+    pub fn id(&self) -> PageId {
+        self.id
+    }
+
+    /// Locks the page for reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, Box<[u8]>> {
+        self.pool.frames[self.frame].data.read().unwrap()
+    }
+
+    /// Locks the page for writing and marks it dirty.
+    pub fn write(&self) -> RwLockWriteGuard<'_, Box<[u8]>> {
+        let slot = &self.pool.frames[self.frame];
+        let guard = slot.data.write().unwrap();
+        slot.dirty.store(true, Ordering::Release);
+        guard
+    }
+}
+
+impl Drop for PageRef<'_> {
+    fn drop(&mut self) {
+        self.pool.unpin(self.frame);
+    }
+}
+
+impl fmt::Debug for PageRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageRef").field("id", &self.id).finish()
+    }
+}
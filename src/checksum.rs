@@ -0,0 +1,37 @@
+//! CRC-32C (Castagnoli) used to detect torn and corrupted data on disk.
+
+const POLY: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC-32C of `data`.
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    extend(0, data)
+}
+
+/// Continues a CRC-32C computation over `data`.
+pub(crate) fn extend(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,97 @@
+//! Dictionary training.
+//!
+//! Training follows the idea of Zstd's COVER algorithm. Every 8-byte
+//! substring of the samples is counted (into a small table of hashed
+//! counters), the samples are cut into as many epochs as the dictionary has
+//! segments, and each epoch contributes its segment whose substrings are the
+//! most frequent overall. The counters of a chosen segment are cleared so
+//! that later epochs pick different content. Segments are laid out with the
+//! most valuable last, where back references to them are the shortest.
+
+use std::cmp::Reverse;
+
+/// Length of the substrings whose frequency is counted.
+const GRAM: usize = 8;
+/// Length of the segments a dictionary is made of.
+const SEGMENT: usize = 128;
+const COUNTER_BITS: u32 = 15;
+
+/// Bytes [`train`] allocates besides the dictionary itself.
+pub(crate) const TRAINING_MEMORY: usize = (1 << COUNTER_BITS) * 2;
+
+fn gram(data: &[u8], at: usize) -> usize {
+    let word = u64::from_le_bytes(data[at..at + GRAM].try_into().unwrap());
+    (word.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - COUNTER_BITS)) as usize
+}
+
+/// Builds a dictionary of at most `size` bytes from `samples`; it is empty if
+/// the samples are too small to repeat anything.
+pub(crate) fn train(samples: &[&[u8]], size: usize) -> Vec<u8> {
+    let mut counts = vec![0u16; 1 << COUNTER_BITS];
+    for sample in samples.iter().filter(|s| s.len() >= GRAM) {
+        for at in 0..=sample.len() - GRAM {
+            let count = &mut counts[gram(sample, at)];
+            *count = count.saturating_add(1);
+        }
+    }
+    let total: usize = samples.iter().map(|s| s.len()).sum();
+    let wanted = size.div_ceil(SEGMENT).max(1);
+    let epoch_len = total.div_ceil(wanted).max(SEGMENT);
+
+    // Epochs never span two samples; a sample longer than an epoch is cut
+    // into several.
+    let mut segments: Vec<(u64, &[u8])> = Vec::new();
+    for sample in samples {
+        for epoch in sample.chunks(epoch_len) {
+            if let Some(segment) = best_segment(epoch, &mut counts) {
+                segments.push(segment);
+            }
+        }
+    }
+    segments.sort_by_key(|&(score, _)| Reverse(score));
+    let mut chosen = Vec::new();
+    let mut len = 0;
+    for (_, segment) in segments {
+        if len + segment.len() <= size {
+            len += segment.len();
+            chosen.push(segment);
+        }
+    }
+    // Back references are shortest to the end of the dictionary, so the
+    // best segments go there.
+    chosen
+        .iter()
+        .rev()
+        .flat_map(|s| s.iter().copied())
+        .collect()
+}
+
+/// The segment of `epoch` whose substrings are the most frequent, with its
+/// score, clearing their counters.
+fn best_segment<'a>(epoch: &'a [u8], counts: &mut [u16]) -> Option<(u64, &'a [u8])> {
+    if epoch.len() < GRAM {
+        return None;
+    }
+    let len = SEGMENT.min(epoch.len());
+    let grams = len - GRAM + 1;
+    let mut score: u64 = (0..grams)
+        .map(|at| u64::from(counts[gram(epoch, at)]))
+        .sum();
+    let mut best = (score, 0);
+    for start in 1..=epoch.len() - len {
+        score -= u64::from(counts[gram(epoch, start - 1)]);
+        score += u64::from(counts[gram(epoch, start + grams - 1)]);
+        if score > best.0 {
+            best = (score, start);
+        }
+    }
+    // Substrings seen only once cannot be repeated by anything.
+    if best.0 <= grams as u64 {
+        return None;
+    }
+    let segment = &epoch[best.1..best.1 + len];
+    for at in 0..grams {
+        counts[gram(segment, at)] = 0;
+    }
+    Some((best.0, segment))
+}
@@ -0,0 +1,154 @@
+//! The LZ4 block format.
+//!
+//! A block is a series of sequences, each a token, a run of literals and a
+//! back reference:
+//!
+//! ```text
+//! token:u8  [literal length:u8*]  literal*  offset:u16  [match length:u8*]
+//! ```
+//!
+//! The high nibble of the token is the number of literals and the low one the
+//! match length minus four; a nibble of 15 continues in extra bytes that are
+//! added up until one is below 255. The last sequence stops after its
+//! literals, and the last five bytes of a block are always literals. Offsets
+//! may reach back into the dictionary, which acts as if it preceded the
+//! input.
+
+use super::copy_match;
+use crate::coding::Reader;
+use crate::error::{Error, Result};
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 65_535;
+/// Bytes at the end of the input that are always literals.
+const LAST_LITERALS: usize = 5;
+/// A match may not start within this many bytes of the end of the input.
+const MATCH_FIND_LIMIT: usize = 12;
+const HASH_BITS: u32 = 12;
+
+/// Bytes [`compress`] allocates for `input_len` bytes with a dictionary of
+/// `dictionary_len` bytes.
+pub(super) fn working_memory(input_len: usize, dictionary_len: usize) -> usize {
+    (1 << HASH_BITS) * 4 + dictionary_len.min(MAX_OFFSET) + input_len
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+/// Appends the compressed form of `input` to `out`.
+pub(super) fn compress(input: &[u8], dictionary: &[u8], out: &mut Vec<u8>) {
+    let dictionary = &dictionary[dictionary.len().saturating_sub(MAX_OFFSET)..];
+    let mut buf = Vec::with_capacity(dictionary.len() + input.len());
+    buf.extend_from_slice(dictionary);
+    buf.extend_from_slice(input);
+    let start = dictionary.len();
+    let end = buf.len();
+    let mut anchor = start;
+    if input.len() > MATCH_FIND_LIMIT {
+        // Positions are stored plus one so that zero means empty.
+        let mut table = vec![0u32; 1 << HASH_BITS];
+        for pos in 0..start.saturating_sub(MIN_MATCH - 1) {
+            table[hash(read_u32(&buf, pos))] = pos as u32 + 1;
+        }
+        let match_limit = end - LAST_LITERALS;
+        let mut pos = start;
+        while pos + MATCH_FIND_LIMIT < end {
+            let sequence = read_u32(&buf, pos);
+            let slot = &mut table[hash(sequence)];
+            let candidate = *slot as usize;
+            *slot = pos as u32 + 1;
+            if candidate == 0
+                || pos - (candidate - 1) > MAX_OFFSET
+                || read_u32(&buf, candidate - 1) != sequence
+            {
+                // Skip ahead faster the longer nothing matched.
+                pos += 1 + ((pos - anchor) >> 6);
+                continue;
+            }
+            let mut from = candidate - 1;
+            let mut len = MIN_MATCH;
+            while pos + len < match_limit && buf[from + len] == buf[pos + len] {
+                len += 1;
+            }
+            while pos > anchor && from > 0 && buf[pos - 1] == buf[from - 1] {
+                pos -= 1;
+                from -= 1;
+                len += 1;
+            }
+            emit(out, &buf[anchor..pos], Some((pos - from, len)));
+            pos += len;
+            anchor = pos;
+        }
+    }
+    emit(out, &buf[anchor..end], None);
+}
+
+fn emit(out: &mut Vec<u8>, literals: &[u8], back_reference: Option<(usize, usize)>) {
+    let match_len = back_reference.map_or(0, |(_, len)| len - MIN_MATCH);
+    let token = (literals.len().min(15) << 4) as u8 | match_len.min(15) as u8;
+    out.push(token);
+    if literals.len() >= 15 {
+        put_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = back_reference {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            put_length(out, match_len - 15);
+        }
+    }
+}
+
+fn put_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn length(cursor: &mut Reader<'_>, nibble: u8) -> Result<usize> {
+    let mut len = usize::from(nibble);
+    if nibble == 15 {
+        loop {
+            let byte = cursor.u8()?;
+            len += usize::from(byte);
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decompresses `input` into `out`, which must end up `len` bytes long.
+pub(super) fn decompress(
+    input: &[u8],
+    dictionary: &[u8],
+    len: usize,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let mut cursor = Reader::new(input);
+    loop {
+        let token = cursor.u8()?;
+        let literals = length(&mut cursor, token >> 4)?;
+        if out.len() + literals > len {
+            return Err(Error::corruption("LZ4 block decompresses past its length"));
+        }
+        out.extend_from_slice(cursor.take(literals)?);
+        if cursor.is_empty() {
+            return Ok(());
+        }
+        let offset = usize::from(u16::from_le_bytes([cursor.u8()?, cursor.u8()?]));
+        let match_len = length(&mut cursor, token & 15)? + MIN_MATCH;
+        if out.len() + match_len > len {
+            return Err(Error::corruption("LZ4 block decompresses past its length"));
+        }
+        copy_match(out, dictionary, offset, match_len)?;
+    }
+}
@@ -0,0 +1,156 @@
+//! Block compression for table files.
+//!
+//! Two codecs are built in, so that the crate keeps its zero-dependency
+//! footprint:
+//!
+//! - [`Compression::Lz4`] writes the LZ4 block format: byte-aligned
+//!   sequences of literals and back references, very fast to decompress.
+//! - [`Compression::Zstd`] writes Zstandard frames: a deeper search for back
+//!   references, then Huffman coded literals and FSE coded sequences. It
+//!   trades speed for a better ratio.
+//!
+//! Both can be primed with a dictionary of content typical of the data, which
+//! lets them find back references even in blocks too small to repeat
+//! themselves; [`Compression::train`] builds one from sample blocks. Zstd
+//! dictionaries also carry entropy tables fitted to the samples, which spare
+//! small blocks the cost of describing their own.
+//!
+//! A compressed payload starts with the varint length of its uncompressed
+//! form, so readers can reserve the memory to decompress it before they do.
+
+mod dictionary;
+mod lz4;
+mod zstd;
+
+pub(crate) use self::dictionary::TRAINING_MEMORY;
+
+use crate::coding::{put_varint, Reader};
+use crate::error::{Error, Result};
+
+/// Codec id of a block stored as is.
+pub(crate) const RAW: u8 = 0;
+/// Flag added to a codec id when the block was compressed with the table's
+/// dictionary.
+pub(crate) const DICTIONARY: u8 = 0x80;
+
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// Codec used for the blocks of LSM table files, selected through
+/// [`Options::compression`](crate::Options::compression).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Blocks are stored as is.
+    None,
+    /// LZ4 block format; fast, with a moderate ratio.
+    #[default]
+    Lz4,
+    /// Zstandard; slower, with a better ratio.
+    Zstd {
+        /// Effort spent looking for back references, from 1 to 22.
+        level: u8,
+    },
+}
+
+impl Compression {
+    /// Codec id stored with every block written with this compression.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => RAW,
+            Compression::Lz4 => LZ4,
+            Compression::Zstd { .. } => ZSTD,
+        }
+    }
+
+    /// Bytes [`compress`](Self::compress) allocates besides its output to
+    /// compress `input_len` bytes with a dictionary of `dictionary_len`
+    /// bytes.
+    pub(crate) fn working_memory(self, input_len: usize, dictionary_len: usize) -> usize {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => lz4::working_memory(input_len, dictionary_len),
+            Compression::Zstd { .. } => zstd::working_memory(input_len, dictionary_len),
+        }
+    }
+
+    /// Appends the compressed form of `input` to `out`. Fails if
+    /// `dictionary` is a Zstd dictionary that cannot be read.
+    pub fn compress(self, input: &[u8], dictionary: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        put_varint(out, input.len() as u64);
+        match self {
+            Compression::None => out.extend_from_slice(input),
+            Compression::Lz4 => lz4::compress(input, dictionary, out),
+            Compression::Zstd { level } => zstd::compress(input, dictionary, level, out)?,
+        }
+        Ok(())
+    }
+
+    /// Decompresses `input`, written by [`compress`](Self::compress) with the
+    /// same compression and dictionary.
+    pub fn decompress(self, input: &[u8], dictionary: &[u8]) -> Result<Vec<u8>> {
+        decompress(self.id(), input, dictionary)
+    }
+
+    /// Builds a dictionary of at most `size` bytes from `samples`, blocks
+    /// typical of the data to compress.
+    pub fn train(self, samples: &[&[u8]], size: usize) -> Vec<u8> {
+        let content = dictionary::train(samples, size);
+        match self {
+            Compression::Zstd { level } => zstd::finalize(&content, samples, level, size),
+            _ => content,
+        }
+    }
+}
+
+/// Length the compressed payload `input` decompresses to.
+pub(crate) fn decompressed_len(input: &[u8]) -> Result<usize> {
+    Ok(Reader::new(input).varint()? as usize)
+}
+
+/// Decompresses `input`, written by the codec with id `codec`.
+pub(crate) fn decompress(codec: u8, input: &[u8], dictionary: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Reader::new(input);
+    let len = reader.varint()? as usize;
+    let payload = &input[reader.position()..];
+    let mut out = Vec::with_capacity(len);
+    match codec {
+        RAW => out.extend_from_slice(payload),
+        LZ4 => lz4::decompress(payload, dictionary, len, &mut out)?,
+        ZSTD => zstd::decompress(payload, dictionary, len, &mut out)?,
+        _ => return Err(Error::corruption(format!("unknown compression {codec}"))),
+    }
+    if out.len() != len {
+        return Err(Error::corruption("compressed block has the wrong length"));
+    }
+    Ok(out)
+}
+
+/// Appends `len` bytes starting `offset` bytes back from the end of `out`,
+/// where the dictionary is taken to precede `out`. The ranges may overlap.
+fn copy_match(out: &mut Vec<u8>, dictionary: &[u8], offset: usize, len: usize) -> Result<()> {
+    if offset == 0 || offset > out.len() + dictionary.len() {
+        return Err(Error::corruption(
+            "back reference before the start of the data",
+        ));
+    }
+    let mut len = len;
+    if offset > out.len() {
+        let back = offset - out.len();
+        let from = dictionary.len() - back;
+        let n = back.min(len);
+        out.extend_from_slice(&dictionary[from..from + n]);
+        len -= n;
+        if len == 0 {
+            return Ok(());
+        }
+    }
+    let start = out.len() - offset;
+    if offset >= len {
+        out.extend_from_within(start..start + len);
+    } else {
+        for i in start..start + len {
+            out.push(out[i]);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Bit streams.
+//!
+//! Entropy-coded streams are written least significant bit first and read
+//! back to front, starting with the bits written last. The writer closes
+//! such a stream with a set bit, so the reader can tell where the bits of the
+//! last byte start. Table descriptions are read front to back.
+
+use crate::error::{Error, Result};
+
+/// Writes bits least significant first.
+pub(super) struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    bits: u64,
+    len: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    pub(super) fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            bits: 0,
+            len: 0,
+        }
+    }
+
+    /// Writes the low `len` bits of `value`; `len` is at most 32.
+    pub(super) fn put(&mut self, value: u64, len: u32) {
+        debug_assert!(len <= 32);
+        self.bits |= (value & ((1 << len) - 1)) << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Writes out the last partial byte, padded with zeros.
+    pub(super) fn flush(self) {
+        if self.len > 0 {
+            self.out.push(self.bits as u8);
+        }
+    }
+
+    /// Ends a stream to be read back to front.
+    pub(super) fn close(mut self) {
+        self.put(1, 1);
+        self.flush();
+    }
+}
+
+/// Reads a stream closed by [`BitWriter::close`] back to front.
+pub(super) struct BackwardReader<'a> {
+    input: &'a [u8],
+    /// Bits left to read; negative once the reader went past the start.
+    left: isize,
+}
+
+impl<'a> BackwardReader<'a> {
+    pub(super) fn new(input: &'a [u8]) -> Result<Self> {
+        match input.last() {
+            Some(&last) if last != 0 => {
+                let mark = 7 - last.leading_zeros() as isize;
+                Ok(BackwardReader {
+                    input,
+                    left: 8 * (input.len() as isize - 1) + mark,
+                })
+            }
+            _ => Err(Error::corruption("Zstd bit stream has no end mark")),
+        }
+    }
+
+    /// The next `len` bits, at most 56, without consuming them. Bits before
+    /// the start of the stream read as zeros.
+    pub(super) fn peek(&self, len: u32) -> u64 {
+        if len == 0 {
+            return 0;
+        }
+        let start = self.left - len as isize;
+        if start >= 0 {
+            return self.bits_at(start as usize, len);
+        }
+        let missing = start.unsigned_abs() as u32;
+        if missing >= len {
+            0
+        } else {
+            self.bits_at(0, len - missing) << missing
+        }
+    }
+
+    fn bits_at(&self, start: usize, len: u32) -> u64 {
+        let byte = start / 8;
+        let end = (byte + 8).min(self.input.len());
+        let mut word = [0u8; 8];
+        word[..end - byte].copy_from_slice(&self.input[byte..end]);
+        (u64::from_le_bytes(word) >> (start % 8)) & ((1 << len) - 1)
+    }
+
+    pub(super) fn consume(&mut self, len: u32) {
+        self.left -= len as isize;
+    }
+
+    pub(super) fn read(&mut self, len: u32) -> u64 {
+        let value = self.peek(len);
+        self.consume(len);
+        value
+    }
+
+    /// Whether more bits were read than the stream has.
+    pub(super) fn overflowed(&self) -> bool {
+        self.left < 0
+    }
+
+    /// Whether every bit was read, and no more.
+    pub(super) fn is_finished(&self) -> bool {
+        self.left == 0
+    }
+}
+
+/// Reads bits least significant first, front to back.
+pub(super) struct ForwardReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ForwardReader<'a> {
+    pub(super) fn new(input: &'a [u8]) -> Self {
+        ForwardReader { input, pos: 0 }
+    }
+
+    /// The next `len` bits, at most 32, padded with zeros past the end.
+    pub(super) fn peek(&self, len: u32) -> u32 {
+        let mut value = 0u64;
+        for i in 0..(self.pos % 8 + len as usize).div_ceil(8) {
+            if let Some(&byte) = self.input.get(self.pos / 8 + i) {
+                value |= u64::from(byte) << (8 * i);
+            }
+        }
+        ((value >> (self.pos % 8)) & ((1 << len) - 1)) as u32
+    }
+
+    pub(super) fn consume(&mut self, len: u32) -> Result<()> {
+        self.pos += len as usize;
+        if self.pos > 8 * self.input.len() {
+            return Err(Error::corruption("Zstd table description is truncated"));
+        }
+        Ok(())
+    }
+
+    pub(super) fn read(&mut self, len: u32) -> Result<u32> {
+        let value = self.peek(len);
+        self.consume(len)?;
+        Ok(value)
+    }
+
+    /// Bytes the bits read so far take up.
+    pub(super) fn bytes_read(&self) -> usize {
+        self.pos.div_ceil(8)
+    }
+}
@@ -0,0 +1,156 @@
+//! Zstd dictionaries.
+//!
+//! Anything that does not start with the dictionary magic number is raw
+//! content. A formatted dictionary also gives the entropy tables and repeat
+//! offsets the first block of a frame starts from:
+//!
+//! ```text
+//! magic:u32  id:u32  huffman table  offsets  match lengths  literal lengths  repeat offsets:u32*3  content
+//! ```
+//!
+//! The tables are described as in a compressed block. [`finalize`] builds
+//! them from the statistics of the samples compressed with the content.
+
+use super::fse::Distribution;
+use super::huffman::HuffmanTable;
+use super::matcher::Matcher;
+use super::sequences::{
+    self, LITERAL_LENGTHS, MATCH_LENGTHS, MAX_ACCURACY, MAX_SYMBOL, OFFSETS, REPEAT_OFFSETS,
+};
+use super::{Tables, MAX_BLOCK};
+use crate::checksum::crc32c;
+use crate::error::{Error, Result};
+
+const MAGIC: u32 = 0xec30_a437;
+/// Smallest content of a formatted dictionary, which holds the repeat
+/// offsets.
+const MIN_CONTENT: usize = 8;
+/// Dictionary ids below this one are reserved.
+const MIN_ID: u32 = 32_768;
+
+/// A dictionary as the codec uses it.
+pub(super) struct Dictionary<'a> {
+    /// Id frames compressed with the dictionary refer to it by; zero for
+    /// raw content.
+    pub(super) id: u32,
+    pub(super) content: &'a [u8],
+    pub(super) tables: Tables,
+}
+
+impl<'a> Dictionary<'a> {
+    pub(super) fn load(bytes: &'a [u8]) -> Result<Self> {
+        if bytes.len() < 8 || bytes[..4] != MAGIC.to_le_bytes() {
+            return Ok(Dictionary {
+                id: 0,
+                content: bytes,
+                tables: Tables::default(),
+            });
+        }
+        let corrupt = || Error::corruption("invalid Zstd dictionary");
+        let id = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let mut tables = Tables::default();
+        let (huffman, len) = HuffmanTable::read(&bytes[8..])?;
+        tables.huffman = Some(huffman);
+        let mut pos = 8 + len;
+        for kind in [OFFSETS, MATCH_LENGTHS, LITERAL_LENGTHS] {
+            let rest = bytes.get(pos..).ok_or_else(corrupt)?;
+            let (distribution, len) =
+                Distribution::read(rest, MAX_SYMBOL[kind], MAX_ACCURACY[kind])?;
+            tables.sequences[kind] = Some(distribution);
+            pos += len;
+        }
+        let repeats = bytes.get(pos..pos + 12).ok_or_else(corrupt)?;
+        let content = &bytes[pos + 12..];
+        for (i, repeat) in repeats.chunks(4).enumerate() {
+            let offset = u32::from_le_bytes(repeat.try_into().unwrap());
+            if offset == 0 || offset as usize > content.len() {
+                return Err(corrupt());
+            }
+            tables.repeat_offsets[i] = offset;
+        }
+        Ok(Dictionary {
+            id,
+            content,
+            tables,
+        })
+    }
+}
+
+fn highbit(value: usize) -> usize {
+    (usize::BITS - 1 - value.max(1).leading_zeros()) as usize
+}
+
+/// Turns `content` into a formatted dictionary of at most `size` bytes,
+/// with entropy tables fitted to `samples` compressed at `level`. Content is
+/// dropped from the front to make room for the tables. Returns the content
+/// alone when there is too little of it.
+pub(in crate::compression) fn finalize(
+    content: &[u8],
+    samples: &[&[u8]],
+    level: u8,
+    size: usize,
+) -> Vec<u8> {
+    if content.len() < MIN_CONTENT || size < MIN_CONTENT {
+        return content.to_vec();
+    }
+    // Every code counts at least once, so that the tables can code blocks
+    // unlike the samples.
+    let mut literals = [1u32; 256];
+    let max_offset_code = highbit(content.len() + MAX_BLOCK).min(MAX_SYMBOL[OFFSETS]);
+    let mut counts = [
+        vec![1u32; MAX_SYMBOL[LITERAL_LENGTHS] + 1],
+        vec![1u32; max_offset_code + 1],
+        vec![1u32; MAX_SYMBOL[MATCH_LENGTHS] + 1],
+    ];
+    for sample in samples {
+        let mut matcher = Matcher::new(content, sample, level);
+        let mut repeats = REPEAT_OFFSETS;
+        for block in sample.chunks(MAX_BLOCK) {
+            let (block_literals, block_sequences) = matcher.block(block.len(), &mut repeats);
+            for &b in &block_literals {
+                literals[usize::from(b)] += 1;
+            }
+            for sequence in &block_sequences {
+                for (kind, &(code, _, _)) in sequences::codes(sequence).iter().enumerate() {
+                    if let Some(count) = counts[kind].get_mut(usize::from(code)) {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut header = MAGIC.to_le_bytes().to_vec();
+    header.extend_from_slice(&[0; 4]);
+    // Flatter counts give shallower codes, whose weights compress better.
+    let described = (0..8).any(|_| {
+        let table = HuffmanTable::build(&literals).unwrap();
+        if table.write(&mut header) {
+            return true;
+        }
+        for count in &mut literals {
+            *count = *count / 2 + 1;
+        }
+        false
+    });
+    if !described {
+        return content.to_vec();
+    }
+    for kind in [OFFSETS, MATCH_LENGTHS, LITERAL_LENGTHS] {
+        Distribution::normalize(&counts[kind], MAX_ACCURACY[kind]).write(&mut header);
+    }
+    for offset in REPEAT_OFFSETS {
+        header.extend_from_slice(&offset.to_le_bytes());
+    }
+    let keep = size.saturating_sub(header.len()).min(content.len());
+    if keep < MIN_CONTENT {
+        return content[content.len() - size.min(content.len())..].to_vec();
+    }
+    let content = &content[content.len() - keep..];
+    let mut id_source = header.clone();
+    id_source.extend_from_slice(content);
+    let id = MIN_ID + crc32c(&id_source) % ((1 << 31) - MIN_ID);
+    header[4..8].copy_from_slice(&id.to_le_bytes());
+    header.extend_from_slice(content);
+    header
+}
@@ -0,0 +1,375 @@
+//! Finite State Entropy, the tabled asymmetric numeral system Zstd codes
+//! sequences and Huffman weights with.
+//!
+//! A distribution gives every symbol a share of `1 << accuracy` states,
+//! spread over the table in a fixed order so that encoder and decoder build
+//! the same one. A state is a table position; decoding it yields a symbol
+//! and the number of bits to read to find the next state. A probability of
+//! -1 stands for "less than one" and takes a single state that resets the
+//! decoder with a full read.
+
+use super::bits::{BackwardReader, BitWriter, ForwardReader};
+use crate::error::{Error, Result};
+
+/// Smallest accuracy a described distribution can have.
+const MIN_ACCURACY: u32 = 5;
+
+/// Normalized probabilities of symbols, summing to `1 << accuracy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Distribution {
+    pub(super) probabilities: Vec<i16>,
+    pub(super) accuracy: u32,
+}
+
+impl Distribution {
+    pub(super) fn new(probabilities: &[i16], accuracy: u32) -> Self {
+        Distribution {
+            probabilities: probabilities.to_vec(),
+            accuracy,
+        }
+    }
+
+    /// The distribution of a stream that only holds `symbol`.
+    pub(super) fn single(symbol: u8) -> Self {
+        let mut probabilities = vec![0; usize::from(symbol) + 1];
+        probabilities[usize::from(symbol)] = 1;
+        Distribution {
+            probabilities,
+            accuracy: 0,
+        }
+    }
+
+    /// Normalizes `counts` to `1 << accuracy`, giving every symbol seen at
+    /// least one state. `accuracy` must leave a state for each of them.
+    pub(super) fn normalize(counts: &[u32], accuracy: u32) -> Self {
+        let size = 1i64 << accuracy;
+        let total: i64 = counts.iter().map(|&c| i64::from(c)).sum();
+        let last = counts.iter().rposition(|&c| c > 0).unwrap_or(0);
+        let mut probabilities: Vec<i16> = counts[..=last]
+            .iter()
+            .map(|&c| match c {
+                0 => 0,
+                c => (i64::from(c) * size / total).max(1) as i16,
+            })
+            .collect();
+        let mut sum: i64 = probabilities.iter().map(|&p| i64::from(p)).sum();
+        let largest = (0..probabilities.len())
+            .max_by_key(|&s| counts[s])
+            .unwrap_or(0);
+        if sum < size {
+            probabilities[largest] += (size - sum) as i16;
+        }
+        while sum > size {
+            // Rounding the rare symbols up overshot: take the excess from the
+            // most probable ones.
+            let s = (0..probabilities.len())
+                .max_by_key(|&s| probabilities[s])
+                .unwrap();
+            let cut = (sum - size).min(i64::from(probabilities[s]) - 1);
+            probabilities[s] -= cut as i16;
+            sum -= cut;
+        }
+        Distribution {
+            probabilities,
+            accuracy,
+        }
+    }
+
+    /// Accuracy to normalize `total` occurrences of symbols up to
+    /// `max_symbol` to, at most `max_accuracy`.
+    pub(super) fn accuracy(total: usize, max_symbol: usize, max_accuracy: u32) -> u32 {
+        let total = total.max(2) as u32;
+        let source_bits = highbit(total - 1).saturating_sub(2);
+        let min_bits = (highbit(total) + 1).min(highbit(max_symbol as u32) + 2);
+        source_bits
+            .min(max_accuracy)
+            .max(min_bits)
+            .clamp(MIN_ACCURACY, max_accuracy)
+    }
+
+    /// Approximate bits taken by coding `counts`, or `None` if some symbol
+    /// cannot be coded.
+    pub(super) fn cost(&self, counts: &[u32]) -> Option<f64> {
+        let mut bits = 0.0;
+        for (s, &c) in counts.iter().enumerate().filter(|(_, &c)| c > 0) {
+            let p = *self.probabilities.get(s).filter(|&&p| p != 0)?;
+            let p = f64::from(p.unsigned_abs());
+            bits += f64::from(c) * (f64::from(self.accuracy) - p.log2());
+        }
+        Some(bits)
+    }
+
+    /// Reads a distribution of symbols up to `max_symbol` described at the
+    /// start of `input`, returning it with the bytes its description took.
+    pub(super) fn read(
+        input: &[u8],
+        max_symbol: usize,
+        max_accuracy: u32,
+    ) -> Result<(Self, usize)> {
+        let corrupt = || Error::corruption("invalid Zstd FSE table description");
+        let mut reader = ForwardReader::new(input);
+        let accuracy = reader.read(4)? + MIN_ACCURACY;
+        if accuracy > max_accuracy {
+            return Err(corrupt());
+        }
+        let mut remaining = (1i32 << accuracy) + 1;
+        let mut threshold = 1i32 << accuracy;
+        let mut bits = accuracy + 1;
+        let mut probabilities = Vec::new();
+        let mut previous_zero = false;
+        while remaining > 1 {
+            if previous_zero {
+                loop {
+                    let repeat = reader.read(2)?;
+                    probabilities.extend(std::iter::repeat_n(0, repeat as usize));
+                    if repeat < 3 {
+                        break;
+                    }
+                }
+            }
+            if probabilities.len() > max_symbol {
+                return Err(corrupt());
+            }
+            let max = 2 * threshold - 1 - remaining;
+            let low = reader.peek(bits - 1) as i32;
+            let value = if low < max {
+                reader.consume(bits - 1)?;
+                low
+            } else {
+                let value = reader.read(bits)? as i32;
+                if value >= threshold {
+                    value - max
+                } else {
+                    value
+                }
+            };
+            let probability = value - 1;
+            remaining -= probability.abs();
+            if remaining < 1 {
+                return Err(corrupt());
+            }
+            probabilities.push(probability as i16);
+            previous_zero = probability == 0;
+            while remaining < threshold {
+                bits -= 1;
+                threshold >>= 1;
+            }
+        }
+        let distribution = Distribution {
+            probabilities,
+            accuracy,
+        };
+        Ok((distribution, reader.bytes_read()))
+    }
+
+    /// Appends the description of the distribution to `out`.
+    pub(super) fn write(&self, out: &mut Vec<u8>) {
+        let mut writer = BitWriter::new(out);
+        writer.put(u64::from(self.accuracy - MIN_ACCURACY), 4);
+        let mut remaining = (1i32 << self.accuracy) + 1;
+        let mut threshold = 1i32 << self.accuracy;
+        let mut bits = self.accuracy + 1;
+        let last = self.probabilities.iter().rposition(|&p| p != 0);
+        let probabilities = &self.probabilities[..last.map_or(0, |l| l + 1)];
+        let mut symbol = 0;
+        let mut previous_zero = false;
+        while symbol < probabilities.len() && remaining > 1 {
+            if previous_zero {
+                let mut start = symbol;
+                while probabilities[symbol] == 0 {
+                    symbol += 1;
+                }
+                while symbol >= start + 3 {
+                    writer.put(3, 2);
+                    start += 3;
+                }
+                writer.put((symbol - start) as u64, 2);
+            }
+            let probability = i32::from(probabilities[symbol]);
+            symbol += 1;
+            let max = 2 * threshold - 1 - remaining;
+            remaining -= probability.abs();
+            let mut value = probability + 1;
+            if value >= threshold {
+                value += max;
+            }
+            writer.put(value as u64, if value < max { bits - 1 } else { bits });
+            previous_zero = value == 1;
+            while remaining < threshold {
+                bits -= 1;
+                threshold >>= 1;
+            }
+        }
+        writer.flush();
+    }
+
+    /// The symbol of every state, in the order shared by both coders, with
+    /// the number of "less than one" symbols at the end of the table.
+    fn spread(&self) -> (Vec<u8>, usize) {
+        let size = 1usize << self.accuracy;
+        let mut symbols = vec![0u8; size];
+        let mut high = size;
+        for (s, _) in self
+            .probabilities
+            .iter()
+            .enumerate()
+            .filter(|(_, &p)| p == -1)
+        {
+            high -= 1;
+            symbols[high] = s as u8;
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mask = size - 1;
+        let mut position = 0;
+        for (s, &p) in self.probabilities.iter().enumerate() {
+            for _ in 0..p.max(0) {
+                symbols[position] = s as u8;
+                position = (position + step) & mask;
+                while position >= high {
+                    position = (position + step) & mask;
+                }
+            }
+        }
+        (symbols, size - high)
+    }
+}
+
+fn highbit(value: u32) -> u32 {
+    31 - value.max(1).leading_zeros()
+}
+
+#[derive(Clone, Copy)]
+struct DecodingEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// Turns states into symbols.
+pub(super) struct Decoder {
+    accuracy: u32,
+    entries: Vec<DecodingEntry>,
+}
+
+impl Decoder {
+    pub(super) fn new(distribution: &Distribution) -> Self {
+        let size = 1u32 << distribution.accuracy;
+        let (symbols, _) = distribution.spread();
+        let mut next: Vec<u32> = distribution
+            .probabilities
+            .iter()
+            .map(|&p| p.unsigned_abs().into())
+            .collect();
+        let entries = symbols
+            .iter()
+            .map(|&symbol| {
+                let state = next[usize::from(symbol)];
+                next[usize::from(symbol)] += 1;
+                let bits = distribution.accuracy - highbit(state);
+                DecodingEntry {
+                    symbol,
+                    bits: bits as u8,
+                    base: ((state << bits) - size) as u16,
+                }
+            })
+            .collect();
+        Decoder {
+            accuracy: distribution.accuracy,
+            entries,
+        }
+    }
+
+    /// Reads the first state.
+    pub(super) fn init(&self, reader: &mut BackwardReader<'_>) -> usize {
+        reader.read(self.accuracy) as usize
+    }
+
+    pub(super) fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    /// Reads the bits leading from `state` to the next state.
+    pub(super) fn update(&self, state: usize, reader: &mut BackwardReader<'_>) -> usize {
+        let entry = self.entries[state];
+        usize::from(entry.base) + reader.read(u32::from(entry.bits)) as usize
+    }
+}
+
+/// Turns symbols into states, in the reverse of the order they are decoded.
+pub(super) struct Encoder {
+    accuracy: u32,
+    states: Vec<u16>,
+    /// Per symbol, the offset of its states in `states` and the adjustment
+    /// that yields the bits written from a state.
+    transforms: Vec<(i32, u32)>,
+}
+
+impl Encoder {
+    pub(super) fn new(distribution: &Distribution) -> Self {
+        let size = 1u32 << distribution.accuracy;
+        let (symbols, _) = distribution.spread();
+        let mut cumulative = Vec::with_capacity(distribution.probabilities.len() + 1);
+        let mut total = 0u32;
+        for &p in &distribution.probabilities {
+            cumulative.push(total);
+            total += u32::from(p.unsigned_abs());
+        }
+        let mut states = vec![0u16; size as usize];
+        for (u, &symbol) in symbols.iter().enumerate() {
+            let slot = &mut cumulative[usize::from(symbol)];
+            states[*slot as usize] = (size + u as u32) as u16;
+            *slot += 1;
+        }
+        let accuracy = distribution.accuracy;
+        let mut total = 0i32;
+        let transforms = distribution
+            .probabilities
+            .iter()
+            .map(|&p| match p {
+                0 => (0, ((accuracy + 1) << 16).wrapping_sub(size)),
+                -1 | 1 => {
+                    total += 1;
+                    (total - 2, (accuracy << 16).wrapping_sub(size))
+                }
+                p => {
+                    let p = i32::from(p);
+                    let max_bits = accuracy - highbit(p as u32 - 1);
+                    let min_state = (p as u32) << max_bits;
+                    total += p;
+                    (total - 2 * p, (max_bits << 16).wrapping_sub(min_state))
+                }
+            })
+            .collect();
+        Encoder {
+            accuracy,
+            states,
+            transforms,
+        }
+    }
+
+    fn next(&self, state: u32, delta: i32) -> u32 {
+        u32::from(self.states[(state as i32 + delta) as usize])
+    }
+
+    /// The state of the last symbol to be encoded, the first decoded.
+    pub(super) fn init(&self, symbol: u8) -> u32 {
+        let (delta, bits_delta) = self.transforms[usize::from(symbol)];
+        let bits = bits_delta.wrapping_add(1 << 15) >> 16;
+        let value = (bits << 16).wrapping_sub(bits_delta);
+        self.next(value >> bits, delta)
+    }
+
+    /// Moves from `state` to a state of `symbol`, writing the bits the
+    /// decoder reads to come back.
+    pub(super) fn encode(&self, state: &mut u32, symbol: u8, writer: &mut BitWriter<'_>) {
+        let (delta, bits_delta) = self.transforms[usize::from(symbol)];
+        let bits = state.wrapping_add(bits_delta) >> 16;
+        writer.put(u64::from(*state), bits);
+        *state = self.next(*state >> bits, delta);
+    }
+
+    /// Writes the final state, the first one the decoder reads.
+    pub(super) fn flush(&self, state: u32, writer: &mut BitWriter<'_>) {
+        writer.put(u64::from(state), self.accuracy);
+    }
+}
@@ -0,0 +1,370 @@
+//! Huffman coding of literals.
+//!
+//! A code is described by the weight of every byte value: a byte of weight
+//! `w > 0` has a code of `max_bits + 1 - w` bits, and zero means unused.
+//! Codes are assigned in order of increasing weight, then byte value, which
+//! makes the weights a complete description. The weight of the last byte
+//! value used is left out, since the others determine it. The weights are
+//! written four bits each, or FSE coded with two interleaved states.
+
+use super::bits::{BackwardReader, BitWriter};
+use super::fse::{self, Distribution};
+use crate::error::{Error, Result};
+
+/// Longest code.
+pub(super) const MAX_BITS: u32 = 11;
+/// Most weights the description can list.
+const MAX_WEIGHTS: usize = 255;
+/// Most weights that can be written four bits each.
+const MAX_DIRECT_WEIGHTS: usize = 128;
+const WEIGHT_ACCURACY: u32 = 6;
+
+fn corrupt() -> Error {
+    Error::corruption("invalid Zstd Huffman table")
+}
+
+/// A Huffman code for byte values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct HuffmanTable {
+    /// Weight of every byte value up to the last one used.
+    weights: Vec<u8>,
+    max_bits: u32,
+}
+
+impl HuffmanTable {
+    /// The code for bytes seen `counts` times, or `None` if fewer than two
+    /// byte values were seen.
+    pub(super) fn build(counts: &[u32; 256]) -> Option<Self> {
+        if counts.iter().filter(|&&c| c > 0).count() < 2 {
+            return None;
+        }
+        let mut counts = counts.to_vec();
+        let lengths = loop {
+            let lengths = code_lengths(&counts);
+            if lengths.iter().all(|&len| u32::from(len) <= MAX_BITS) {
+                break lengths;
+            }
+            // Flatten the distribution until the tree is shallow enough.
+            for count in counts.iter_mut().filter(|c| **c > 0) {
+                *count = (*count >> 1).max(1);
+            }
+        };
+        let max_bits = u32::from(*lengths.iter().max().unwrap());
+        let last = lengths.iter().rposition(|&len| len > 0).unwrap();
+        let weights = lengths[..=last]
+            .iter()
+            .map(|&len| match len {
+                0 => 0,
+                len => (max_bits + 1 - u32::from(len)) as u8,
+            })
+            .collect();
+        Some(HuffmanTable { weights, max_bits })
+    }
+
+    /// Whether every byte counted in `counts` has a code.
+    pub(super) fn covers(&self, counts: &[u32; 256]) -> bool {
+        counts
+            .iter()
+            .enumerate()
+            .all(|(b, &c)| c == 0 || self.weights.get(b).is_some_and(|&w| w > 0))
+    }
+
+    /// Bits taken by coding `counts`, which the table must cover.
+    pub(super) fn cost(&self, counts: &[u32; 256]) -> usize {
+        self.weights
+            .iter()
+            .zip(counts)
+            .filter(|(&w, _)| w > 0)
+            .map(|(&w, &c)| c as usize * (self.max_bits + 1 - u32::from(w)) as usize)
+            .sum()
+    }
+
+    /// Reads the table described at the start of `input`, returning it with
+    /// the bytes the description took.
+    pub(super) fn read(input: &[u8]) -> Result<(Self, usize)> {
+        let header = usize::from(*input.first().ok_or_else(corrupt)?);
+        let (mut weights, len) = if header < 128 {
+            let data = input.get(1..1 + header).ok_or_else(corrupt)?;
+            (read_fse_weights(data)?, 1 + header)
+        } else {
+            let count = header - 127;
+            let bytes = input.get(1..1 + count.div_ceil(2)).ok_or_else(corrupt)?;
+            let weights = (0..count)
+                .map(|i| match i % 2 {
+                    0 => bytes[i / 2] >> 4,
+                    _ => bytes[i / 2] & 0xf,
+                })
+                .collect();
+            (weights, 1 + count.div_ceil(2))
+        };
+        if weights.iter().any(|&w| u32::from(w) > MAX_BITS) {
+            return Err(corrupt());
+        }
+        let total: u32 = weights
+            .iter()
+            .filter(|&&w| w > 0)
+            .map(|&w| 1 << (w - 1))
+            .sum();
+        if total == 0 {
+            return Err(corrupt());
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let rest = (1 << max_bits) - total;
+        if max_bits > MAX_BITS || !rest.is_power_of_two() {
+            return Err(corrupt());
+        }
+        weights.push(rest.trailing_zeros() as u8 + 1);
+        Ok((HuffmanTable { weights, max_bits }, len))
+    }
+
+    /// Appends the description of the table to `out`, or returns false if
+    /// its weights cannot be described.
+    pub(super) fn write(&self, out: &mut Vec<u8>) -> bool {
+        let weights = &self.weights[..self.weights.len() - 1];
+        let direct = weights.len() <= MAX_DIRECT_WEIGHTS;
+        let mut compressed = Vec::new();
+        let fse = write_fse_weights(weights, &mut compressed) && compressed.len() < 128;
+        if fse && (!direct || compressed.len() < weights.len().div_ceil(2)) {
+            out.push(compressed.len() as u8);
+            out.extend_from_slice(&compressed);
+        } else if direct {
+            out.push((127 + weights.len()) as u8);
+            for pair in weights.chunks(2) {
+                out.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// The code and its length in bits of every byte value.
+    pub(super) fn codes(&self) -> Vec<(u16, u8)> {
+        let mut codes = vec![(0, 0); 256];
+        let mut next = 0u32;
+        for weight in 1..=self.max_bits {
+            for (b, _) in self
+                .weights
+                .iter()
+                .enumerate()
+                .filter(|(_, &w)| u32::from(w) == weight)
+            {
+                let bits = self.max_bits + 1 - weight;
+                codes[b] = ((next >> (weight - 1)) as u16, bits as u8);
+                next += 1 << (weight - 1);
+            }
+        }
+        codes
+    }
+
+    /// Byte value and code length of every `max_bits` prefix of the stream.
+    fn decoding_table(&self) -> Vec<(u8, u8)> {
+        let mut table = Vec::with_capacity(1 << self.max_bits);
+        for weight in 1..=self.max_bits {
+            for (b, _) in self
+                .weights
+                .iter()
+                .enumerate()
+                .filter(|(_, &w)| u32::from(w) == weight)
+            {
+                let bits = (self.max_bits + 1 - weight) as u8;
+                table.extend(std::iter::repeat_n((b as u8, bits), 1 << (weight - 1)));
+            }
+        }
+        table
+    }
+
+    /// Appends `literals` coded in one stream, or in four if `four_streams`,
+    /// to `out`. Returns false if a stream is too long for the jump table.
+    pub(super) fn encode(&self, literals: &[u8], four_streams: bool, out: &mut Vec<u8>) -> bool {
+        let codes = self.codes();
+        if !four_streams {
+            encode_stream(&codes, literals, out);
+            return true;
+        }
+        let jump_table = out.len();
+        out.extend_from_slice(&[0; 6]);
+        let segment = literals.len().div_ceil(4);
+        for (i, chunk) in literals.chunks(segment).enumerate() {
+            let start = out.len();
+            encode_stream(&codes, chunk, out);
+            let len = out.len() - start;
+            if i < 3 {
+                let Ok(len) = u16::try_from(len) else {
+                    return false;
+                };
+                out[jump_table + 2 * i..jump_table + 2 * i + 2].copy_from_slice(&len.to_le_bytes());
+            }
+        }
+        true
+    }
+
+    /// Decodes `count` literals from `input`, coded in one stream or in four
+    /// if `four_streams`, appending them to `out`.
+    pub(super) fn decode(
+        &self,
+        input: &[u8],
+        count: usize,
+        four_streams: bool,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        let table = self.decoding_table();
+        if !four_streams {
+            return decode_stream(&table, self.max_bits, input, count, out);
+        }
+        if input.len() < 6 {
+            return Err(corrupt());
+        }
+        let mut sizes = [0usize; 4];
+        for (i, size) in sizes[..3].iter_mut().enumerate() {
+            *size = usize::from(u16::from_le_bytes([input[2 * i], input[2 * i + 1]]));
+        }
+        let streams = &input[6..];
+        let known: usize = sizes[..3].iter().sum();
+        sizes[3] = streams.len().checked_sub(known).ok_or_else(corrupt)?;
+        let segment = count.div_ceil(4);
+        let mut start = 0;
+        for (i, &size) in sizes.iter().enumerate() {
+            let n = segment.min(count - (segment * i).min(count));
+            decode_stream(&table, self.max_bits, &streams[start..start + size], n, out)?;
+            start += size;
+        }
+        Ok(())
+    }
+}
+
+fn encode_stream(codes: &[(u16, u8)], literals: &[u8], out: &mut Vec<u8>) {
+    let mut writer = BitWriter::new(out);
+    // The stream is read back to front, so the first literal goes last.
+    for &b in literals.iter().rev() {
+        let (code, bits) = codes[usize::from(b)];
+        writer.put(u64::from(code), u32::from(bits));
+    }
+    writer.close();
+}
+
+fn decode_stream(
+    table: &[(u8, u8)],
+    max_bits: u32,
+    input: &[u8],
+    count: usize,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let mut reader = BackwardReader::new(input)?;
+    for _ in 0..count {
+        let (b, bits) = table[reader.peek(max_bits) as usize];
+        reader.consume(u32::from(bits));
+        out.push(b);
+    }
+    if !reader.is_finished() {
+        return Err(Error::corruption(
+            "Zstd literal stream does not end with its literals",
+        ));
+    }
+    Ok(())
+}
+
+/// Code lengths of a Huffman code for symbols seen `counts` times; unseen
+/// symbols get no code.
+fn code_lengths(counts: &[u32]) -> Vec<u8> {
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    let mut lengths = vec![0u8; counts.len()];
+    let used: Vec<usize> = (0..counts.len()).filter(|&s| counts[s] > 0).collect();
+    // Nodes below `counts.len()` are symbols, the rest internal nodes.
+    let mut parent = vec![usize::MAX; counts.len()];
+    let mut heap: BinaryHeap<_> = used
+        .iter()
+        .map(|&s| Reverse((u64::from(counts[s]), s)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a, x)) = heap.pop().unwrap();
+        let Reverse((b, y)) = heap.pop().unwrap();
+        let node = parent.len();
+        parent.push(usize::MAX);
+        parent[x] = node;
+        parent[y] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+    for &s in &used {
+        let mut depth = 0;
+        let mut node = s;
+        while parent[node] != usize::MAX {
+            node = parent[node];
+            depth += 1;
+        }
+        lengths[s] = depth.min(usize::from(u8::MAX)) as u8;
+    }
+    lengths
+}
+
+/// FSE codes `weights` into `out`, returning false if they cannot be: the
+/// decoder counts the weights by where the stream runs out, which some
+/// streams leave ambiguous.
+fn write_fse_weights(weights: &[u8], out: &mut Vec<u8>) -> bool {
+    let mut counts = [0u32; MAX_BITS as usize + 1];
+    for &w in weights {
+        counts[usize::from(w)] += 1;
+    }
+    if weights.len() <= 2 || counts.iter().any(|&c| c as usize == weights.len()) {
+        return false;
+    }
+    let max_symbol = counts.iter().rposition(|&c| c > 0).unwrap();
+    let accuracy = Distribution::accuracy(weights.len(), max_symbol, WEIGHT_ACCURACY);
+    let distribution = Distribution::normalize(&counts, accuracy);
+    let start = out.len();
+    distribution.write(out);
+    let encoder = fse::Encoder::new(&distribution);
+    let mut writer = BitWriter::new(out);
+    let n = weights.len();
+    let (mut first, mut second);
+    let mut i;
+    if n % 2 == 1 {
+        first = encoder.init(weights[n - 1]);
+        second = encoder.init(weights[n - 2]);
+        encoder.encode(&mut first, weights[n - 3], &mut writer);
+        i = n - 3;
+    } else {
+        second = encoder.init(weights[n - 1]);
+        first = encoder.init(weights[n - 2]);
+        i = n - 2;
+    }
+    while i > 0 {
+        encoder.encode(&mut second, weights[i - 1], &mut writer);
+        encoder.encode(&mut first, weights[i - 2], &mut writer);
+        i -= 2;
+    }
+    encoder.flush(second, &mut writer);
+    encoder.flush(first, &mut writer);
+    writer.close();
+    read_fse_weights(&out[start..]).is_ok_and(|decoded| decoded == weights)
+}
+
+fn read_fse_weights(input: &[u8]) -> Result<Vec<u8>> {
+    let (distribution, len) = Distribution::read(input, MAX_BITS as usize, WEIGHT_ACCURACY)?;
+    let decoder = fse::Decoder::new(&distribution);
+    let mut reader = BackwardReader::new(&input[len..])?;
+    let mut states = [decoder.init(&mut reader), decoder.init(&mut reader)];
+    if reader.overflowed() {
+        return Err(corrupt());
+    }
+    let mut weights = Vec::new();
+    // Decoding alternates between the states until one needs more bits than
+    // are left; the other one then holds the last weight.
+    for i in (0..2).cycle() {
+        if weights.len() >= MAX_WEIGHTS {
+            return Err(corrupt());
+        }
+        weights.push(decoder.symbol(states[i]));
+        states[i] = decoder.update(states[i], &mut reader);
+        if reader.overflowed() {
+            weights.push(decoder.symbol(states[1 - i]));
+            break;
+        }
+    }
+    if weights.len() > MAX_WEIGHTS {
+        return Err(corrupt());
+    }
+    Ok(weights)
+}
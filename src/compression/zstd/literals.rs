@@ -0,0 +1,163 @@
+//! The literals section of a compressed block.
+//!
+//! Literals are stored as is, as a single repeated byte, or Huffman coded in
+//! one stream or four, with a new table or the one the previous block used.
+//! The header gives the kind and the sizes, in as few bytes as they fit in.
+
+use super::huffman::HuffmanTable;
+use super::MAX_BLOCK;
+use crate::error::{Error, Result};
+
+const RAW: u8 = 0;
+const RLE: u8 = 1;
+const COMPRESSED: u8 = 2;
+const TREELESS: u8 = 3;
+
+/// Literals coded in a single stream rather than four.
+const MAX_SINGLE_STREAM: usize = 255;
+
+fn corrupt() -> Error {
+    Error::corruption("invalid Zstd literals section")
+}
+
+/// Appends the section holding `literals` to `out`, recording a new Huffman
+/// table in `table`.
+pub(super) fn write(literals: &[u8], table: &mut Option<HuffmanTable>, out: &mut Vec<u8>) {
+    if literals.len() > 1 && literals.iter().all(|&b| b == literals[0]) {
+        write_size(RLE, literals.len(), out);
+        out.push(literals[0]);
+        return;
+    }
+    if let Some((section, new_table)) = compress(literals, table.as_ref()) {
+        if section.len() < literals.len() + size_len(literals.len()) {
+            out.extend_from_slice(&section);
+            if new_table.is_some() {
+                *table = new_table;
+            }
+            return;
+        }
+    }
+    write_size(RAW, literals.len(), out);
+    out.extend_from_slice(literals);
+}
+
+fn size_len(size: usize) -> usize {
+    match size {
+        0..=31 => 1,
+        32..=4095 => 2,
+        _ => 3,
+    }
+}
+
+/// Writes the header of raw or repeated literals.
+fn write_size(kind: u8, size: usize, out: &mut Vec<u8>) {
+    let header = match size_len(size) {
+        1 => u32::from(kind) | (size as u32) << 3,
+        2 => u32::from(kind) | 1 << 2 | (size as u32) << 4,
+        _ => u32::from(kind) | 3 << 2 | (size as u32) << 4,
+    };
+    out.extend_from_slice(&header.to_le_bytes()[..size_len(size)]);
+}
+
+/// The Huffman coded section of `literals`, with the new table it uses if it
+/// does not reuse `previous`.
+fn compress(
+    literals: &[u8],
+    previous: Option<&HuffmanTable>,
+) -> Option<(Vec<u8>, Option<HuffmanTable>)> {
+    let mut counts = [0u32; 256];
+    for &b in literals {
+        counts[usize::from(b)] += 1;
+    }
+    let table = HuffmanTable::build(&counts)?;
+    let mut description = Vec::new();
+    let new_cost = table
+        .write(&mut description)
+        .then(|| 8 * description.len() + table.cost(&counts));
+    let previous_cost = previous
+        .filter(|p| p.covers(&counts))
+        .map(|p| p.cost(&counts));
+    let (kind, table, mut data) = match (new_cost, previous_cost) {
+        (Some(new), Some(old)) if new < old => (COMPRESSED, Some(table), description),
+        (_, Some(_)) => (TREELESS, None, Vec::new()),
+        (Some(_), None) => (COMPRESSED, Some(table), description),
+        (None, None) => return None,
+    };
+    let four_streams = literals.len() > MAX_SINGLE_STREAM;
+    let coder = table.as_ref().or(previous).unwrap();
+    if !coder.encode(literals, four_streams, &mut data) {
+        return None;
+    }
+    let (regenerated, compressed) = (literals.len() as u64, data.len() as u64);
+    let (format, size_bits, len) = match regenerated.max(compressed) {
+        _ if !four_streams => (0, 10, 3),
+        0..=1023 => (1, 10, 3),
+        1024..=16383 => (2, 14, 4),
+        _ => (3, 18, 5),
+    };
+    if compressed >= 1 << size_bits {
+        return None;
+    }
+    let header = u64::from(kind) | format << 2 | regenerated << 4 | compressed << (4 + size_bits);
+    let mut section = header.to_le_bytes()[..len].to_vec();
+    section.extend_from_slice(&data);
+    Some((section, table))
+}
+
+/// Reads the section at the start of `input`, returning the literals and the
+/// bytes the section took. A new Huffman table is recorded in `table`.
+pub(super) fn read(input: &[u8], table: &mut Option<HuffmanTable>) -> Result<(Vec<u8>, usize)> {
+    let header = |len: usize| -> Result<u64> {
+        let bytes = input.get(..len).ok_or_else(corrupt)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, &b| acc << 8 | u64::from(b)))
+    };
+    let first = header(1)?;
+    let kind = (first & 3) as u8;
+    let format = (first >> 2) & 3;
+    if kind == RAW || kind == RLE {
+        let (size, len) = match format {
+            0 | 2 => (first >> 3, 1),
+            1 => (header(2)? >> 4, 2),
+            _ => (header(3)? >> 4, 3),
+        };
+        let size = size as usize;
+        if size > MAX_BLOCK {
+            return Err(corrupt());
+        }
+        return match kind {
+            RAW => {
+                let literals = input.get(len..len + size).ok_or_else(corrupt)?;
+                Ok((literals.to_vec(), len + size))
+            }
+            _ => {
+                let &b = input.get(len).ok_or_else(corrupt)?;
+                Ok((vec![b; size], len + 1))
+            }
+        };
+    }
+    let (size_bits, len) = match format {
+        0 | 1 => (10, 3),
+        2 => (14, 4),
+        _ => (18, 5),
+    };
+    let header = header(len)?;
+    let mask = (1 << size_bits) - 1;
+    let regenerated = (header >> 4 & mask) as usize;
+    let compressed = (header >> (4 + size_bits) & mask) as usize;
+    if regenerated > MAX_BLOCK {
+        return Err(corrupt());
+    }
+    let mut data = input.get(len..len + compressed).ok_or_else(corrupt)?;
+    if kind == COMPRESSED {
+        let (new_table, used) = HuffmanTable::read(data)?;
+        *table = Some(new_table);
+        data = &data[used..];
+    }
+    let coder = table.as_ref().ok_or_else(corrupt)?;
+    let mut literals = Vec::with_capacity(regenerated);
+    coder.decode(data, regenerated, format != 0, &mut literals)?;
+    Ok((literals, len + compressed))
+}
@@ -0,0 +1,185 @@
+//! Match finding.
+//!
+//! Positions are chained by a hash of their first four bytes, and the chain
+//! is searched deeper the higher the level. From [`LAZY_LEVEL`] on, a match
+//! is given up for one starting a byte later when that one is better. The
+//! repeat offsets are tried first at every position: they take no more than
+//! a couple of bits to code, so a repeat match beats a slightly longer match
+//! further back. Matches are weighed by their length against the bits of
+//! their offset.
+
+use super::sequences::{encode_offset, Sequence};
+
+const MIN_MATCH: usize = 4;
+const LAZY_LEVEL: u8 = 5;
+const NONE: u32 = u32::MAX;
+
+/// Bits of the hash of a window of `len` bytes.
+fn hash_bits(len: usize) -> u32 {
+    (usize::BITS - len.leading_zeros()).clamp(10, 17)
+}
+
+/// Bytes a [`Matcher`] allocates over a window of `len` bytes.
+pub(super) fn working_memory(len: usize) -> usize {
+    (4 << hash_bits(len)) + 5 * len
+}
+
+fn highbit(value: u32) -> u32 {
+    31 - value.max(1).leading_zeros()
+}
+
+/// A match: its length, offset and worth.
+#[derive(Clone, Copy)]
+struct Match {
+    len: usize,
+    offset: u32,
+    gain: i64,
+}
+
+/// Finds matches in an input, which may refer back into a dictionary.
+pub(super) struct Matcher {
+    /// The dictionary content followed by the input.
+    buf: Vec<u8>,
+    /// Start of the part of the input not turned into sequences yet.
+    pos: usize,
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    hash_bits: u32,
+    depth: usize,
+    lazy: bool,
+}
+
+impl Matcher {
+    pub(super) fn new(content: &[u8], input: &[u8], level: u8) -> Self {
+        let mut buf = Vec::with_capacity(content.len() + input.len());
+        buf.extend_from_slice(content);
+        buf.extend_from_slice(input);
+        let hash_bits = hash_bits(buf.len());
+        let mut matcher = Matcher {
+            head: vec![NONE; 1 << hash_bits],
+            prev: vec![NONE; buf.len()],
+            buf,
+            pos: content.len(),
+            hash_bits,
+            depth: 1 << (u32::from(level) + 1).div_ceil(2).min(11),
+            lazy: level >= LAZY_LEVEL,
+        };
+        for at in 0..content.len() {
+            matcher.insert(at);
+        }
+        matcher
+    }
+
+    fn hash(&self, at: usize) -> usize {
+        let word = u32::from_le_bytes(self.buf[at..at + 4].try_into().unwrap());
+        (word.wrapping_mul(2_654_435_761) >> (32 - self.hash_bits)) as usize
+    }
+
+    fn insert(&mut self, at: usize) {
+        if at + MIN_MATCH <= self.buf.len() {
+            let h = self.hash(at);
+            self.prev[at] = self.head[h];
+            self.head[h] = at as u32;
+        }
+    }
+
+    /// Length of the common prefix of `buf[from..]` and `buf[at..]`, up to
+    /// `limit` bytes.
+    fn common(&self, from: usize, at: usize, limit: usize) -> usize {
+        self.buf[from..from + limit]
+            .iter()
+            .zip(&self.buf[at..at + limit])
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
+    /// The best match at `at`, ending by `end`, after the literals since
+    /// `anchor`.
+    fn best(&self, at: usize, end: usize, anchor: usize, repeats: [u32; 3]) -> Option<Match> {
+        let limit = end - at;
+        if limit < MIN_MATCH {
+            return None;
+        }
+        let mut best: Option<Match> = None;
+        let consider = |best: &mut Option<Match>, offset: usize, len: usize| {
+            if len < MIN_MATCH {
+                return;
+            }
+            let offset = offset as u32;
+            let (value, _) = encode_offset(repeats, offset, at == anchor);
+            let gain = 4 * len as i64 - i64::from(highbit(value));
+            if best.is_none_or(|b| gain > b.gain) {
+                *best = Some(Match { len, offset, gain });
+            }
+        };
+        for (i, &offset) in repeats.iter().enumerate() {
+            let offset = offset as usize;
+            if offset == 0 || offset > at || repeats[..i].contains(&(offset as u32)) {
+                continue;
+            }
+            consider(&mut best, offset, self.common(at - offset, at, limit));
+        }
+        let mut longest = best.map_or(0, |b| b.len);
+        let mut candidate = self.head[self.hash(at)];
+        let mut chain = self.depth;
+        while candidate != NONE && chain > 0 {
+            let from = candidate as usize;
+            if longest < limit && self.buf[from + longest] == self.buf[at + longest] {
+                let len = self.common(from, at, limit);
+                if len > longest {
+                    longest = len;
+                    consider(&mut best, at - from, len);
+                }
+            }
+            candidate = self.prev[from];
+            chain -= 1;
+        }
+        best
+    }
+
+    /// Turns the next `len` bytes of the input into literals and sequences,
+    /// updating `repeats` as the decoder will.
+    pub(super) fn block(&mut self, len: usize, repeats: &mut [u32; 3]) -> (Vec<u8>, Vec<Sequence>) {
+        let end = self.pos + len;
+        let mut literals = Vec::new();
+        let mut sequences = Vec::new();
+        let mut anchor = self.pos;
+        let mut at = self.pos;
+        while at < end {
+            let Some(mut found) = self.best(at, end, anchor, *repeats) else {
+                self.insert(at);
+                at += 1;
+                continue;
+            };
+            self.insert(at);
+            while self.lazy && at + 1 < end {
+                match self.best(at + 1, end, anchor, *repeats) {
+                    // Deferring costs a literal.
+                    Some(next) if next.gain > found.gain + 4 => {
+                        at += 1;
+                        self.insert(at);
+                        found = next;
+                    }
+                    _ => break,
+                }
+            }
+            literals.extend_from_slice(&self.buf[anchor..at]);
+            let literal_length = at - anchor;
+            let (offset_value, next) = encode_offset(*repeats, found.offset, literal_length == 0);
+            *repeats = next;
+            sequences.push(Sequence {
+                literal_length: literal_length as u32,
+                match_length: found.len as u32,
+                offset_value,
+            });
+            for skipped in at + 1..at + found.len {
+                self.insert(skipped);
+            }
+            at += found.len;
+            anchor = at;
+        }
+        literals.extend_from_slice(&self.buf[anchor..end]);
+        self.pos = end;
+        (literals, sequences)
+    }
+}
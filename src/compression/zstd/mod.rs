@@ -0,0 +1,272 @@
+//! The Zstandard format (RFC 8878).
+//!
+//! A compressed payload is a single Zstd frame, which the reference `zstd`
+//! tool reads and writes:
+//!
+//! ```text
+//! magic:u32  descriptor:u8  [window:u8]  [dictionary id]  [content size]  block*  [checksum:u32]
+//! ```
+//!
+//! Frames written here always give their content size and leave out the
+//! window and the checksum; blocks already carry a CRC in table files. Every
+//! block is stored as is, as one repeated byte, or compressed: a literals
+//! section (see [`literals`]) and a sequences section (see [`sequences`]).
+//! The repeat offsets and the entropy tables of a block carry over to the
+//! next, which can reuse them instead of describing its own.
+//!
+//! Dictionaries are either raw content, or in the format [`train`] builds,
+//! which adds entropy tables and repeat offsets for the first block to start
+//! from (see [`dictionary`]).
+//!
+//! [`train`]: super::Compression::train
+
+mod bits;
+mod dictionary;
+mod fse;
+mod huffman;
+mod literals;
+mod matcher;
+mod sequences;
+
+pub(super) use self::dictionary::finalize;
+
+use self::dictionary::Dictionary;
+use self::fse::Distribution;
+use self::huffman::HuffmanTable;
+use self::matcher::Matcher;
+use self::sequences::REPEAT_OFFSETS;
+use super::copy_match;
+use crate::coding::Reader;
+use crate::error::{Error, Result};
+
+const MAGIC: u32 = 0xfd2f_b528;
+/// Largest content of a block.
+const MAX_BLOCK: usize = 128 << 10;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+const SINGLE_SEGMENT: u8 = 0x20;
+const CHECKSUM: u8 = 0x04;
+const RESERVED: u8 = 0x08;
+
+/// Entropy tables and repeat offsets, as left by the previous block.
+#[derive(Debug, Clone)]
+struct Tables {
+    huffman: Option<HuffmanTable>,
+    /// Distributions of literal length, offset and match length codes.
+    sequences: [Option<Distribution>; 3],
+    repeat_offsets: [u32; 3],
+}
+
+impl Default for Tables {
+    fn default() -> Self {
+        Tables {
+            huffman: None,
+            sequences: [None, None, None],
+            repeat_offsets: REPEAT_OFFSETS,
+        }
+    }
+}
+
+/// Bytes [`compress`] allocates for `input_len` bytes with a dictionary of
+/// `dictionary_len` bytes.
+pub(super) fn working_memory(input_len: usize, dictionary_len: usize) -> usize {
+    let block = input_len.min(MAX_BLOCK);
+    // Literals, sequences and the compressed block, and the entropy tables.
+    matcher::working_memory(dictionary_len + input_len) + 5 * block + (16 << 10)
+}
+
+/// Appends the frame compressing `input` to `out`.
+pub(super) fn compress(
+    input: &[u8],
+    dictionary: &[u8],
+    level: u8,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let dictionary = Dictionary::load(dictionary)?;
+    write_frame_header(input.len(), dictionary.id, out);
+    if input.is_empty() {
+        write_block_header(true, BLOCK_RAW, 0, out);
+        return Ok(());
+    }
+    let mut tables = dictionary.tables;
+    let mut matcher = Matcher::new(dictionary.content, input, level);
+    let mut start = 0;
+    for block in input.chunks(MAX_BLOCK) {
+        start += block.len();
+        let last = start == input.len();
+        let before = tables.clone();
+        let (literals, sequences) = matcher.block(block.len(), &mut tables.repeat_offsets);
+        if block.iter().all(|&b| b == block[0]) {
+            tables = before;
+            write_block_header(last, BLOCK_RLE, block.len(), out);
+            out.push(block[0]);
+            continue;
+        }
+        let mut body = Vec::new();
+        literals::write(&literals, &mut tables.huffman, &mut body);
+        sequences::write(&sequences, &mut tables.sequences, &mut body);
+        if body.len() < block.len() {
+            write_block_header(last, BLOCK_COMPRESSED, body.len(), out);
+            out.extend_from_slice(&body);
+        } else {
+            // The decoder does not see the tables of a block stored as is.
+            tables = before;
+            write_block_header(last, BLOCK_RAW, block.len(), out);
+            out.extend_from_slice(block);
+        }
+    }
+    Ok(())
+}
+
+fn write_frame_header(len: usize, dictionary_id: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&MAGIC.to_le_bytes());
+    let (size_flag, size_len, size) = match len as u64 {
+        len @ 0..=255 => (0, 1, len),
+        len @ 256..=65791 => (1, 2, len - 256),
+        len @ 65792..=0xffff_ffff => (2, 4, len),
+        len => (3, 8, len),
+    };
+    let (id_flag, id_len) = match dictionary_id {
+        0 => (0, 0),
+        1..=255 => (1, 1),
+        256..=65535 => (2, 2),
+        _ => (3, 4),
+    };
+    out.push(size_flag << 6 | SINGLE_SEGMENT | id_flag);
+    out.extend_from_slice(&dictionary_id.to_le_bytes()[..id_len]);
+    out.extend_from_slice(&size.to_le_bytes()[..size_len]);
+}
+
+fn write_block_header(last: bool, kind: u32, size: usize, out: &mut Vec<u8>) {
+    let header = u32::from(last) | kind << 1 | (size as u32) << 3;
+    out.extend_from_slice(&header.to_le_bytes()[..3]);
+}
+
+/// Reads a little-endian integer of `len` bytes.
+fn read_le(reader: &mut Reader<'_>, len: usize) -> Result<u64> {
+    let bytes = reader.take(len)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &b| acc << 8 | u64::from(b)))
+}
+
+/// Decompresses the frame `input` into `out`, which must end up `len` bytes
+/// long.
+pub(super) fn decompress(
+    input: &[u8],
+    dictionary: &[u8],
+    len: usize,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let mut reader = Reader::new(input);
+    if read_le(&mut reader, 4)? != u64::from(MAGIC) {
+        return Err(Error::corruption("not a Zstd frame"));
+    }
+    let descriptor = reader.u8()?;
+    if descriptor & RESERVED != 0 {
+        return Err(Error::corruption(
+            "Zstd frame header has a reserved bit set",
+        ));
+    }
+    let single_segment = descriptor & SINGLE_SEGMENT != 0;
+    if !single_segment {
+        reader.u8()?;
+    }
+    let dictionary_id = read_le(&mut reader, [0, 1, 2, 4][usize::from(descriptor & 3)])? as u32;
+    let size_len = match descriptor >> 6 {
+        0 => usize::from(single_segment),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let mut size = read_le(&mut reader, size_len)?;
+    if size_len == 2 {
+        size += 256;
+    }
+    if size_len > 0 && size != len as u64 {
+        return Err(Error::corruption("Zstd frame has the wrong content size"));
+    }
+    let dictionary = Dictionary::load(dictionary)?;
+    if dictionary_id != 0 && dictionary_id != dictionary.id {
+        return Err(Error::corruption(format!(
+            "Zstd frame needs dictionary {dictionary_id}"
+        )));
+    }
+    let mut tables = dictionary.tables;
+    loop {
+        let header = read_le(&mut reader, 3)? as u32;
+        let size = (header >> 3) as usize;
+        let room = len - out.len();
+        match (header >> 1) & 3 {
+            BLOCK_RAW if size <= room => out.extend_from_slice(reader.take(size)?),
+            BLOCK_RLE if size <= room => {
+                let b = reader.u8()?;
+                out.resize(out.len() + size, b);
+            }
+            BLOCK_COMPRESSED if size <= MAX_BLOCK => {
+                decompress_block(
+                    reader.take(size)?,
+                    dictionary.content,
+                    &mut tables,
+                    len,
+                    out,
+                )?;
+            }
+            BLOCK_RAW | BLOCK_RLE => {
+                return Err(Error::corruption("Zstd frame decompresses past its length"));
+            }
+            _ => return Err(Error::corruption("invalid Zstd block")),
+        }
+        if header & 1 == 1 {
+            break;
+        }
+    }
+    if descriptor & CHECKSUM != 0 {
+        // Blocks are checked by the table, so the checksum is not.
+        reader.take(4)?;
+    }
+    if !reader.is_empty() {
+        return Err(Error::corruption("Zstd frame is followed by more data"));
+    }
+    Ok(())
+}
+
+/// Decompresses a compressed block into `out`, which may not grow past
+/// `len` bytes.
+fn decompress_block(
+    input: &[u8],
+    content: &[u8],
+    tables: &mut Tables,
+    len: usize,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    let past_length = || Error::corruption("Zstd frame decompresses past its length");
+    let (literals, used) = literals::read(input, &mut tables.huffman)?;
+    let mut pending = &literals[..];
+    let repeats = &mut tables.repeat_offsets;
+    sequences::read(&input[used..], &mut tables.sequences, |sequence| {
+        let literal_length = sequence.literal_length as usize;
+        let match_length = sequence.match_length as usize;
+        if literal_length > pending.len() {
+            return Err(Error::corruption("Zstd sequence runs past its literals"));
+        }
+        if literal_length + match_length > len - out.len() {
+            return Err(past_length());
+        }
+        let (offset, next) =
+            sequences::decode_offset(*repeats, sequence.offset_value, literal_length == 0)?;
+        *repeats = next;
+        out.extend_from_slice(&pending[..literal_length]);
+        pending = &pending[literal_length..];
+        copy_match(out, content, offset as usize, match_length)
+    })?;
+    if pending.len() > len - out.len() {
+        return Err(past_length());
+    }
+    out.extend_from_slice(pending);
+    Ok(())
+}
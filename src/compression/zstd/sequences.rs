@@ -0,0 +1,366 @@
+//! The sequences section of a compressed block.
+//!
+//! A sequence copies a run of literals, then a match. Literal lengths, match
+//! lengths and offsets are each turned into a code and extra bits; the codes
+//! are FSE coded with one table per kind, the extra bits written as is:
+//!
+//! ```text
+//! count  modes:u8  [table]*3  bit stream
+//! ```
+//!
+//! The modes byte says for each kind whether its table is the predefined
+//! one, a single symbol, described in the section, or the one the previous
+//! block used.
+
+use super::bits::{BackwardReader, BitWriter};
+use super::fse::{self, Distribution};
+use crate::error::{Error, Result};
+
+/// A run of literals followed by a match.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Sequence {
+    pub(super) literal_length: u32,
+    pub(super) match_length: u32,
+    /// The offset plus 3, or 1 to 3 for one of the repeat offsets.
+    pub(super) offset_value: u32,
+}
+
+pub(super) const LITERAL_LENGTHS: usize = 0;
+pub(super) const OFFSETS: usize = 1;
+pub(super) const MATCH_LENGTHS: usize = 2;
+
+pub(super) const MAX_SYMBOL: [usize; 3] = [35, 31, 52];
+pub(super) const MAX_ACCURACY: [u32; 3] = [9, 8, 9];
+
+const LITERAL_LENGTH_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64,
+    128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536,
+];
+const LITERAL_LENGTH_BITS: [u8; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+const MATCH_LENGTH_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31, 32, 33, 34, 35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027,
+    2051, 4099, 8195, 16387, 32771, 65539,
+];
+const MATCH_LENGTH_BITS: [u8; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+];
+
+const PREDEFINED: [(&[i16], u32); 3] = [
+    (
+        &[
+            4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1,
+            1, 1, 1, -1, -1, -1, -1,
+        ],
+        6,
+    ),
+    (
+        &[
+            1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1,
+            -1,
+        ],
+        5,
+    ),
+    (
+        &[
+            1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+            1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+        ],
+        6,
+    ),
+];
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_COMPRESSED: u8 = 2;
+const MODE_REPEAT: u8 = 3;
+
+/// Offset values of the repeat offsets as they are before the first block.
+pub(super) const REPEAT_OFFSETS: [u32; 3] = [1, 4, 8];
+
+/// The offset value coding `offset` after a run of literals, empty if
+/// `no_literals`, and the repeat offsets that follow.
+pub(super) fn encode_offset(repeats: [u32; 3], offset: u32, no_literals: bool) -> (u32, [u32; 3]) {
+    for value in 1..=3 {
+        let index = value - 1 + usize::from(no_literals);
+        if repeat(repeats, index) == offset {
+            return (value as u32, promote(repeats, index));
+        }
+    }
+    (offset + 3, [offset, repeats[0], repeats[1]])
+}
+
+/// The offset `value` codes after a run of literals, empty if
+/// `no_literals`, and the repeat offsets that follow.
+pub(super) fn decode_offset(
+    repeats: [u32; 3],
+    value: u32,
+    no_literals: bool,
+) -> Result<(u32, [u32; 3])> {
+    if value > 3 {
+        let offset = value - 3;
+        return Ok((offset, [offset, repeats[0], repeats[1]]));
+    }
+    let index = value as usize - 1 + usize::from(no_literals);
+    match repeat(repeats, index) {
+        0 => Err(corrupt()),
+        offset => Ok((offset, promote(repeats, index))),
+    }
+}
+
+/// Repeat offset `index`, where 3 stands for the first one minus one.
+fn repeat(repeats: [u32; 3], index: usize) -> u32 {
+    match index {
+        3 => repeats[0].wrapping_sub(1),
+        i => repeats[i],
+    }
+}
+
+/// Moves repeat offset `index` to the front.
+fn promote(repeats: [u32; 3], index: usize) -> [u32; 3] {
+    match index {
+        0 => repeats,
+        1 => [repeats[1], repeats[0], repeats[2]],
+        _ => [repeat(repeats, index), repeats[0], repeats[1]],
+    }
+}
+
+fn corrupt() -> Error {
+    Error::corruption("invalid Zstd sequences section")
+}
+
+fn predefined(kind: usize) -> Distribution {
+    let (probabilities, accuracy) = PREDEFINED[kind];
+    Distribution::new(probabilities, accuracy)
+}
+
+fn highbit(value: u32) -> u32 {
+    31 - value.max(1).leading_zeros()
+}
+
+/// Code and extra bits, with their count, of every kind of `sequence`.
+pub(super) fn codes(sequence: &Sequence) -> [(u8, u32, u32); 3] {
+    let ll = sequence.literal_length;
+    let ll_code = match ll {
+        0..=15 => ll as usize,
+        _ => LITERAL_LENGTH_BASE.partition_point(|&base| base <= ll) - 1,
+    };
+    let ml = sequence.match_length;
+    let ml_code = match ml {
+        3..=34 => (ml - 3) as usize,
+        _ => MATCH_LENGTH_BASE.partition_point(|&base| base <= ml) - 1,
+    };
+    let of_code = highbit(sequence.offset_value);
+    [
+        (
+            ll_code as u8,
+            ll - LITERAL_LENGTH_BASE[ll_code],
+            u32::from(LITERAL_LENGTH_BITS[ll_code]),
+        ),
+        (
+            of_code as u8,
+            sequence.offset_value - (1 << of_code),
+            of_code,
+        ),
+        (
+            ml_code as u8,
+            ml - MATCH_LENGTH_BASE[ml_code],
+            u32::from(MATCH_LENGTH_BITS[ml_code]),
+        ),
+    ]
+}
+
+/// Picks the cheapest table for codes seen `counts` times out of
+/// `sequences`, returning its mode, the distribution and its description.
+fn choose(
+    kind: usize,
+    counts: &[u32],
+    sequences: usize,
+    previous: Option<&Distribution>,
+) -> (u8, Distribution, Vec<u8>) {
+    let used = counts.iter().filter(|&&c| c > 0).count();
+    let max_symbol = counts.iter().rposition(|&c| c > 0).unwrap_or(0);
+    if used == 1 && sequences > 2 {
+        return (
+            MODE_RLE,
+            Distribution::single(max_symbol as u8),
+            vec![max_symbol as u8],
+        );
+    }
+    let accuracy = Distribution::accuracy(sequences, max_symbol, MAX_ACCURACY[kind]);
+    let described = Distribution::normalize(counts, accuracy);
+    let mut description = Vec::new();
+    described.write(&mut description);
+    let described_cost = described.cost(counts).unwrap() + 8.0 * description.len() as f64;
+    let mut best = (MODE_COMPRESSED, described, description, described_cost);
+    let predefined = predefined(kind);
+    if let Some(cost) = predefined.cost(counts) {
+        if cost <= best.3 {
+            best = (MODE_PREDEFINED, predefined, Vec::new(), cost);
+        }
+    }
+    if let Some(previous) = previous {
+        if let Some(cost) = previous.cost(counts) {
+            if cost <= best.3 {
+                best = (MODE_REPEAT, previous.clone(), Vec::new(), cost);
+            }
+        }
+    }
+    (best.0, best.1, best.2)
+}
+
+/// Appends the section coding `sequences` to `out`, recording the
+/// distributions it used in `tables`.
+pub(super) fn write(
+    sequences: &[Sequence],
+    tables: &mut [Option<Distribution>; 3],
+    out: &mut Vec<u8>,
+) {
+    let n = sequences.len();
+    match n {
+        0..=127 => out.push(n as u8),
+        128..=0x7eff => out.extend_from_slice(&[(n >> 8) as u8 + 128, n as u8]),
+        _ => {
+            out.push(255);
+            out.extend_from_slice(&((n - 0x7f00) as u16).to_le_bytes());
+        }
+    }
+    if n == 0 {
+        return;
+    }
+    let codes: Vec<_> = sequences.iter().map(codes).collect();
+    let mut modes = 0u8;
+    let mut descriptions = Vec::new();
+    for kind in [LITERAL_LENGTHS, OFFSETS, MATCH_LENGTHS] {
+        let mut counts = vec![0u32; MAX_SYMBOL[kind] + 1];
+        for code in &codes {
+            counts[usize::from(code[kind].0)] += 1;
+        }
+        let previous = tables[kind].as_ref();
+        let (mode, distribution, description) = choose(kind, &counts, n, previous);
+        modes |= mode << (6 - 2 * kind);
+        descriptions.extend_from_slice(&description);
+        tables[kind] = Some(distribution);
+    }
+    out.push(modes);
+    out.extend_from_slice(&descriptions);
+
+    let encoders: Vec<_> = tables
+        .iter()
+        .map(|d| fse::Encoder::new(d.as_ref().unwrap()))
+        .collect();
+    let mut writer = BitWriter::new(out);
+    let last = &codes[n - 1];
+    let mut states = [0; 3];
+    for kind in [MATCH_LENGTHS, OFFSETS, LITERAL_LENGTHS] {
+        states[kind] = encoders[kind].init(last[kind].0);
+    }
+    // The decoder reads sequences first to last, so they are written last
+    // to first, and the parts of each one in the reverse order it reads
+    // them.
+    for kind in [LITERAL_LENGTHS, MATCH_LENGTHS, OFFSETS] {
+        writer.put(u64::from(last[kind].1), last[kind].2);
+    }
+    for code in codes[..n - 1].iter().rev() {
+        for kind in [OFFSETS, MATCH_LENGTHS, LITERAL_LENGTHS] {
+            encoders[kind].encode(&mut states[kind], code[kind].0, &mut writer);
+        }
+        for kind in [LITERAL_LENGTHS, MATCH_LENGTHS, OFFSETS] {
+            writer.put(u64::from(code[kind].1), code[kind].2);
+        }
+    }
+    for kind in [MATCH_LENGTHS, OFFSETS, LITERAL_LENGTHS] {
+        encoders[kind].flush(states[kind], &mut writer);
+    }
+    writer.close();
+}
+
+/// Reads the section in `input`, which runs to the end of the block,
+/// passing each sequence to `execute` in order. Records the distributions
+/// the section used in `tables`.
+pub(super) fn read(
+    input: &[u8],
+    tables: &mut [Option<Distribution>; 3],
+    mut execute: impl FnMut(Sequence) -> Result<()>,
+) -> Result<()> {
+    let byte = |i: usize| input.get(i).copied().map(usize::from).ok_or_else(corrupt);
+    let (n, mut pos) = match byte(0)? {
+        b @ 0..=127 => (b, 1),
+        b @ 128..=254 => (((b - 128) << 8) + byte(1)?, 2),
+        _ => (byte(1)? + (byte(2)? << 8) + 0x7f00, 3),
+    };
+    if n == 0 {
+        return match pos == input.len() {
+            true => Ok(()),
+            false => Err(corrupt()),
+        };
+    }
+    let modes = byte(pos)? as u8;
+    pos += 1;
+    if modes & 3 != 0 {
+        return Err(corrupt());
+    }
+    for kind in [LITERAL_LENGTHS, OFFSETS, MATCH_LENGTHS] {
+        let distribution = match (modes >> (6 - 2 * kind)) & 3 {
+            MODE_PREDEFINED => predefined(kind),
+            MODE_RLE => {
+                let symbol = byte(pos)?;
+                pos += 1;
+                if symbol > MAX_SYMBOL[kind] {
+                    return Err(corrupt());
+                }
+                Distribution::single(symbol as u8)
+            }
+            MODE_COMPRESSED => {
+                let (distribution, len) =
+                    Distribution::read(&input[pos..], MAX_SYMBOL[kind], MAX_ACCURACY[kind])?;
+                pos += len;
+                distribution
+            }
+            _ => tables[kind].clone().ok_or_else(corrupt)?,
+        };
+        tables[kind] = Some(distribution);
+    }
+    let decoders: Vec<_> = tables
+        .iter()
+        .map(|d| fse::Decoder::new(d.as_ref().unwrap()))
+        .collect();
+    let mut reader = BackwardReader::new(input.get(pos..).ok_or_else(corrupt)?)?;
+    let mut states = [0; 3];
+    for kind in [LITERAL_LENGTHS, OFFSETS, MATCH_LENGTHS] {
+        states[kind] = decoders[kind].init(&mut reader);
+    }
+    for i in 0..n {
+        let mut values = [0u32; 3];
+        for kind in [OFFSETS, MATCH_LENGTHS, LITERAL_LENGTHS] {
+            let code = usize::from(decoders[kind].symbol(states[kind]));
+            let (base, bits) = match kind {
+                LITERAL_LENGTHS => (LITERAL_LENGTH_BASE[code], LITERAL_LENGTH_BITS[code].into()),
+                MATCH_LENGTHS => (MATCH_LENGTH_BASE[code], MATCH_LENGTH_BITS[code].into()),
+                _ => (1 << code, code as u32),
+            };
+            values[kind] = base + reader.read(bits) as u32;
+        }
+        if reader.overflowed() {
+            return Err(corrupt());
+        }
+        execute(Sequence {
+            literal_length: values[LITERAL_LENGTHS],
+            match_length: values[MATCH_LENGTHS],
+            offset_value: values[OFFSETS],
+        })?;
+        if i + 1 < n {
+            for kind in [LITERAL_LENGTHS, MATCH_LENGTHS, OFFSETS] {
+                states[kind] = decoders[kind].update(states[kind], &mut reader);
+            }
+        }
+    }
+    if !reader.is_finished() {
+        return Err(corrupt());
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! Common interface of the storage engines.
+//!
+//! A database directory is bound to one engine when it is created; the choice
+//! is recorded in an `ENGINE` file next to the engine's own files and checked
+//! on every open.
+
+use std::path::Path;
+
+use crate::btree::BTreeEngine;
+use crate::error::{Error, Result};
+use crate::lsm::LsmEngine;
+use crate::memory::MemoryBudget;
+use crate::options::Options;
+use crate::range::KeyRange;
+use crate::wal::Durability;
+
+const IDENTITY_FILE: &str = "ENGINE";
+
+/// Iterator over key-value pairs returned by [`StorageEngine::scan`].
+pub type Scan<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send + 'a>;
+
+/// Change to a single key, applied with [`StorageEngine::write`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// Folds `operand` into the value of `key` with the
+    /// [`MergeOperator`](crate::merge::MergeOperator) named `operator`.
+    Merge {
+        key: Vec<u8>,
+        operator: String,
+        operand: Vec<u8>,
+    },
+}
+
+impl Mutation {
+    /// Key the mutation applies to.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Mutation::Put { key, .. } | Mutation::Delete { key } | Mutation::Merge { key, .. } => {
+                key
+            }
+        }
+    }
+
+    /// Bytes of key and value or operand.
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Mutation::Put { key, value } => key.len() + value.len(),
+            Mutation::Merge {
+                key,
+                operator,
+                operand,
+            } => key.len() + operator.len() + operand.len(),
+            Mutation::Delete { key } => key.len(),
+        }
+    }
+}
+
+/// Ordered key-value storage.
+pub trait StorageEngine: Send + Sync {
+    /// Returns the value stored under `key`.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Stores `value` under `key` with the default durability.
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Removes `key` with the default durability.
+    fn delete(&self, key: &[u8]) -> Result<()>;
+
+    /// Folds `operand` into the value of `key` with the
+    /// [`MergeOperator`](crate::merge::MergeOperator) named `operator`, with
+    /// the default durability.
+    fn merge(&self, key: &[u8], operator: &str, operand: &[u8]) -> Result<()>;
+
+    /// Applies `mutations` atomically, returning once they are as durable as
+    /// `durability` requires.
+    fn write(&self, mutations: &[Mutation], durability: Durability) -> Result<()>;
+
+    /// Iterates in key order over the pairs in `range`.
+    fn scan(&self, range: KeyRange) -> Scan<'_>;
+
+    /// Writes buffered changes to disk.
+    fn flush(&self) -> Result<()>;
+}
+
+/// Storage engine backing a database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EngineKind {
+    /// Log-structured merge tree: cheap writes, suited to write-heavy loads.
+    #[default]
+    Lsm,
+    /// In-place B+tree: cheap reads, suited to read-heavy loads. Keys are
+    /// limited to about a quarter of [`Options::page_size`], see
+    /// [`BTreeEngine::max_key_len`].
+    BTree,
+}
+
+impl EngineKind {
+    fn name(self) -> &'static str {
+        match self {
+            EngineKind::Lsm => "lsm",
+            EngineKind::BTree => "btree",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lsm" => Some(EngineKind::Lsm),
+            "btree" => Some(EngineKind::BTree),
+            _ => None,
+        }
+    }
+}
+
+/// Opens the database in `dir` with the engine chosen by
+/// [`Options::engine`], creating it if needed.
+///
+/// Opening an existing database with a different engine than the one it was
+/// created with fails.
+pub fn open_engine(
+    dir: impl AsRef<Path>,
+    options: &Options,
+    budget: &MemoryBudget,
+) -> Result<Box<dyn StorageEngine>> {
+    let dir = dir.as_ref();
+    let vfs = &*options.vfs;
+    vfs.create_dir_all(dir)?;
+    let identity = dir.join(IDENTITY_FILE);
+    match vfs.read(&identity) {
+        Ok(name) => {
+            let name = String::from_utf8_lossy(&name);
+            let stored = EngineKind::from_name(name.trim())
+                .ok_or_else(|| Error::corruption(format!("unknown engine {:?}", name.trim())))?;
+            if stored != options.engine {
+                return Err(Error::invalid(format!(
+                    "database was created with the {} engine",
+                    stored.name()
+                )));
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // Written aside and renamed so that a crash never leaves it empty.
+            let temp = dir.join(format!("{IDENTITY_FILE}.tmp"));
+            vfs.write(&temp, format!("{}\n", options.engine.name()).as_bytes())?;
+            vfs.rename(&temp, &identity)?;
+            vfs.sync_dir(dir)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(match options.engine {
+        EngineKind::Lsm => Box::new(LsmEngine::open(dir, options, budget)?),
+        EngineKind::BTree => Box::new(BTreeEngine::open(dir, options, budget)?),
+    })
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        LsmEngine::get(self, key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        LsmEngine::put(self, key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        LsmEngine::delete(self, key)
+    }
+
+    fn merge(&self, key: &[u8], operator: &str, operand: &[u8]) -> Result<()> {
+        LsmEngine::merge(self, key, operator, operand)
+    }
+
+    fn write(&self, mutations: &[Mutation], durability: Durability) -> Result<()> {
+        LsmEngine::write(self, mutations, durability)
+    }
+
+    fn scan(&self, range: KeyRange) -> Scan<'_> {
+        Box::new(LsmEngine::scan(self, range))
+    }
+
+    fn flush(&self) -> Result<()> {
+        LsmEngine::flush(self)
+    }
+}
+
+impl StorageEngine for BTreeEngine {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        BTreeEngine::get(self, key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        BTreeEngine::put(self, key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        BTreeEngine::delete(self, key)
+    }
+
+    fn merge(&self, key: &[u8], operator: &str, operand: &[u8]) -> Result<()> {
+        BTreeEngine::merge(self, key, operator, operand)
+    }
+
+    fn write(&self, mutations: &[Mutation], durability: Durability) -> Result<()> {
+        BTreeEngine::write(self, mutations, durability)
+    }
+
+    fn scan(&self, range: KeyRange) -> Scan<'_> {
+        Box::new(BTreeEngine::scan(self, range))
+    }
+
+    fn flush(&self) -> Result<()> {
+        BTreeEngine::flush(self)
+    }
+}
@@ -0,0 +1,115 @@
+//! Error type shared by every layer of the database.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Result alias used throughout the crate.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Returned when a memory reservation cannot be satisfied from the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBudget {
+    /// Number of bytes that were asked for.
+    pub requested: usize,
+    /// Number of bytes that were still free when the request failed.
+    pub available: usize,
+    /// Configured limit of the budget.
+    pub limit: usize,
+}
+
+impl fmt::Display for OutOfBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memory budget exhausted: requested {} bytes, {} of {} available",
+            self.requested, self.available, self.limit
+        )
+    }
+}
+
+impl std::error::Error for OutOfBudget {}
+
+/// Errors produced by the database.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A memory reservation could not be satisfied.
+    OutOfBudget(OutOfBudget),
+    /// An underlying I/O operation failed.
+    Io(io::Error),
+    /// Every frame of a buffer pool is pinned, so no page can be brought in.
+    BufferPoolFull {
+        /// Number of frames in the pool.
+        frames: usize,
+    },
+    /// A caller supplied an argument the database cannot accept.
+    InvalidArgument(String),
+    /// Persistent state failed validation.
+    Corruption(String),
+    /// A transaction conflicted with a concurrent one and was rolled back.
+    Conflict(String),
+    /// A transaction's snapshot was released to bound the memory held by old
+    /// versions; it can no longer read or commit.
+    SnapshotTooOld,
+    /// A SQL query gave up waiting to be admitted.
+    QueueTimeout {
+        /// How long it waited.
+        waited: Duration,
+    },
+}
+
+impl Error {
+    pub(crate) fn invalid(msg: impl Into<String>) -> Self {
+        Error::InvalidArgument(msg.into())
+    }
+
+    pub(crate) fn corruption(msg: impl Into<String>) -> Self {
+        Error::Corruption(msg.into())
+    }
+
+    pub(crate) fn conflict(msg: impl Into<String>) -> Self {
+        Error::Conflict(msg.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OutOfBudget(e) => e.fmt(f),
+            Error::Io(e) => write!(f, "i/o error: {e}"),
+            Error::BufferPoolFull { frames } => {
+                write!(f, "all {frames} buffer pool frames are pinned")
+            }
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {msg}"),
+            Error::Corruption(msg) => write!(f, "corruption: {msg}"),
+            Error::Conflict(msg) => write!(f, "transaction conflict: {msg}"),
+            Error::SnapshotTooOld => write!(f, "snapshot too old"),
+            Error::QueueTimeout { waited } => {
+                write!(f, "query was not admitted after waiting {waited:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::OutOfBudget(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<OutOfBudget> for Error {
+    fn from(e: OutOfBudget) -> Self {
+        Error::OutOfBudget(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
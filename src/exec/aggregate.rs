@@ -0,0 +1,434 @@
+//! Hash aggregation.
+//!
+//! Input rows are folded into the state of their group in a hash table. Once
+//! the table fills the operator's memory grant, rows of groups already in it
+//! keep being folded in place, while rows of new groups are partitioned by
+//! the hash of their key and written to disk. The groups in memory are
+//! returned first; each partition is then aggregated in turn with the whole
+//! grant, hashing with a new seed so that it splits again if it still holds
+//! too many groups. A partition that keeps overflowing after several rounds is
+//! sorted by key with an [`ExternalSort`] instead, and its groups folded one
+//! after the other.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::{self, HashMap};
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::hash;
+use super::sort::{Compare, ExternalSort, SortedRecords};
+use super::spill::{split_pair, SpillFile, SpillReader, SpillWriter, SPILL_OVERHEAD};
+use crate::coding::u64_at;
+use crate::error::{Error, Result};
+use crate::memory::Reservation;
+use crate::vfs::Vfs;
+
+/// Bounds of the buffer each spilled partition is written and read through.
+const MIN_IO: usize = 512;
+const MAX_IO: usize = 64 << 10;
+
+/// Most partitions rows of new groups are split into.
+const MAX_FANOUT: usize = 64;
+
+/// Rounds of re-partitioning before a partition is sorted instead.
+const MAX_DEPTH: u64 = 4;
+
+/// Smallest grant an aggregation accepts.
+pub const MIN_AGGREGATE_GRANT: usize = 32 << 10;
+
+/// Bytes a group held in memory costs beyond its key and state: its slot in
+/// the table, and room for the table to grow into.
+const GROUP_COST: usize = 3 * size_of::<(Box<[u8]>, Vec<u8>)>();
+
+/// Function folding the values of a group into a state.
+///
+/// States are byte strings so that they can be held in memory or passed on
+/// as they are; the state of a group is what the aggregation returns for it,
+/// after [`finish`](Self::finish).
+pub trait Aggregate {
+    /// State of a group before any value.
+    fn init(&self) -> Vec<u8>;
+
+    /// Folds `value` into `state`.
+    fn update(&self, state: &mut Vec<u8>, value: &[u8]) -> Result<()>;
+
+    /// Turns the state of a complete group into its result.
+    fn finish(&self, state: &mut Vec<u8>) {
+        let _ = state;
+    }
+}
+
+/// Number of values, as a little-endian `u64`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Count;
+
+impl Aggregate for Count {
+    fn init(&self) -> Vec<u8> {
+        0u64.to_le_bytes().to_vec()
+    }
+
+    fn update(&self, state: &mut Vec<u8>, _value: &[u8]) -> Result<()> {
+        let count = u64_at(state, 0) + 1;
+        state.copy_from_slice(&count.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Wrapping sum of values that are little-endian `i64`s.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sum;
+
+impl Aggregate for Sum {
+    fn init(&self) -> Vec<u8> {
+        0i64.to_le_bytes().to_vec()
+    }
+
+    fn update(&self, state: &mut Vec<u8>, value: &[u8]) -> Result<()> {
+        let value: [u8; 8] = value
+            .try_into()
+            .map_err(|_| Error::invalid("sum of a value that is not 8 bytes long"))?;
+        let sum = (u64_at(state, 0) as i64).wrapping_add(i64::from_le_bytes(value));
+        state.copy_from_slice(&sum.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Bytewise least value; empty for a group without values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Min;
+
+impl Aggregate for Min {
+    fn init(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn update(&self, state: &mut Vec<u8>, value: &[u8]) -> Result<()> {
+        // The first value is folded into an empty state.
+        if state.is_empty() || value < state.as_slice() {
+            state.clear();
+            state.extend_from_slice(value);
+        }
+        Ok(())
+    }
+}
+
+/// Bytewise greatest value; empty for a group without values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Max;
+
+impl Aggregate for Max {
+    fn init(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn update(&self, state: &mut Vec<u8>, value: &[u8]) -> Result<()> {
+        if value > state.as_slice() {
+            state.clear();
+            state.extend_from_slice(value);
+        }
+        Ok(())
+    }
+}
+
+/// Sizes shared by an aggregation and those of its spilled partitions.
+#[derive(Clone)]
+struct Layout {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    /// Bytes of memory the aggregation may use.
+    limit: usize,
+    /// Size of the buffer each spilled partition is written and read through.
+    io_size: usize,
+    fanout: usize,
+}
+
+impl Layout {
+    /// Longest key and value together the aggregation accepts.
+    fn max_row(&self) -> usize {
+        self.limit / 8
+    }
+
+    /// Bytes the groups held in memory may take, leaving room for the
+    /// partition writers, a reader and a row.
+    fn table_limit(&self) -> usize {
+        self.limit - (self.fanout + 1) * (self.io_size + SPILL_OVERHEAD) - self.max_row()
+    }
+}
+
+/// States of the groups held in memory, by key.
+type GroupStates = HashMap<Box<[u8]>, Vec<u8>>;
+
+/// One round of aggregation: the groups in memory, and the partitions the
+/// rows of the other groups are written to.
+struct Table {
+    layout: Layout,
+    seed: u64,
+    groups: GroupStates,
+    /// Bytes held by the groups.
+    used: usize,
+    /// Set once the table is full.
+    partitions: Vec<SpillWriter>,
+}
+
+impl Table {
+    fn new(layout: Layout, seed: u64) -> Self {
+        Table {
+            layout,
+            seed,
+            groups: HashMap::new(),
+            used: 0,
+            partitions: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, aggregate: &impl Aggregate, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(state) = self.groups.get_mut(key) {
+            let before = state.len();
+            aggregate.update(state, value)?;
+            self.used = self.used + state.len() - before;
+            // The room kept for a row absorbs states that grow in place.
+            if self.used > self.layout.table_limit() + self.layout.max_row() {
+                return Err(Error::invalid("aggregate states outgrew the memory grant"));
+            }
+            return Ok(());
+        }
+        if self.partitions.is_empty() {
+            let mut state = aggregate.init();
+            aggregate.update(&mut state, value)?;
+            let cost = GROUP_COST + key.len() + state.len();
+            if self.used + cost <= self.layout.table_limit() {
+                self.groups.insert(key.into(), state);
+                self.used += cost;
+                return Ok(());
+            }
+            for _ in 0..self.layout.fanout {
+                let file = SpillFile::create(&*self.layout.vfs, &self.layout.dir)?;
+                let writer = SpillWriter::new(file, self.layout.io_size);
+                self.partitions.push(writer);
+            }
+        }
+        let p = (hash(self.seed, key) >> 32) as usize % self.layout.fanout;
+        self.partitions[p].write_pair(key, value)
+    }
+
+    /// Ends the round's input, returning its groups and the partitions still
+    /// to aggregate.
+    fn finish(self) -> Result<(GroupStates, Vec<Pending>)> {
+        let mut pending = Vec::new();
+        for writer in self.partitions {
+            let file = writer.finish()?;
+            if file.len() > 0 {
+                pending.push(Pending {
+                    file,
+                    depth: self.seed + 1,
+                });
+            }
+        }
+        Ok((self.groups, pending))
+    }
+}
+
+/// Spilled partition left to aggregate.
+struct Pending {
+    file: SpillFile,
+    /// Seed to re-partition it with; past [`MAX_DEPTH`] it is sorted.
+    depth: u64,
+}
+
+/// Orders rows written as pairs by key.
+fn compare_keys(a: &[u8], b: &[u8]) -> Ordering {
+    fn key(row: &[u8]) -> &[u8] {
+        split_pair(row).map_or(row, |(key, _)| key)
+    }
+    key(a).cmp(key(b))
+}
+
+/// Hash aggregation of keyed rows bounded by a memory grant, spilling the
+/// rows of the groups that do not fit to disk.
+///
+/// Rows are added with [`push`](Self::push); [`finish`](Self::finish) then
+/// returns each group's key and result, in no particular order.
+pub struct HashAggregate<A> {
+    aggregate: A,
+    table: Table,
+    grant: Reservation,
+}
+
+impl<A: Aggregate> HashAggregate<A> {
+    /// Aggregates with `aggregate` within `grant`, spilling rows to files in
+    /// `dir`.
+    pub fn new(
+        vfs: Arc<dyn Vfs>,
+        dir: impl Into<PathBuf>,
+        grant: Reservation,
+        aggregate: A,
+    ) -> Result<Self> {
+        if grant.size() < MIN_AGGREGATE_GRANT {
+            return Err(Error::invalid(format!(
+                "an aggregation needs a memory grant of at least {MIN_AGGREGATE_GRANT} bytes"
+            )));
+        }
+        let limit = grant.size();
+        let io_size = (limit / 64).clamp(MIN_IO, MAX_IO);
+        let layout = Layout {
+            vfs,
+            dir: dir.into(),
+            limit,
+            io_size,
+            fanout: (limit / 4 / (io_size + SPILL_OVERHEAD)).clamp(2, MAX_FANOUT),
+        };
+        Ok(HashAggregate {
+            aggregate,
+            table: Table::new(layout, 0),
+            grant,
+        })
+    }
+
+    /// Longest key and value together the aggregation accepts.
+    pub fn max_row_size(&self) -> usize {
+        self.table.layout.max_row()
+    }
+
+    /// Whether rows of new groups are being written to disk.
+    pub fn is_spilling(&self) -> bool {
+        !self.table.partitions.is_empty()
+    }
+
+    /// Folds `value` into the group of `key`.
+    pub fn push(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if key.len() + value.len() > self.max_row_size() {
+            return Err(Error::invalid(format!(
+                "aggregation row of {} bytes exceeds the {} bytes the memory grant allows",
+                key.len() + value.len(),
+                self.max_row_size()
+            )));
+        }
+        self.table.insert(&self.aggregate, key, value)
+    }
+
+    /// Ends the input and returns the groups.
+    pub fn finish(self) -> Result<Groups<A>> {
+        let layout = self.table.layout.clone();
+        let (groups, pending) = self.table.finish()?;
+        Ok(Groups {
+            aggregate: self.aggregate,
+            layout,
+            pending,
+            stage: Stage::Memory(groups.into_iter()),
+            grant: self.grant,
+        })
+    }
+}
+
+enum Stage {
+    Memory(hash_map::IntoIter<Box<[u8]>, Vec<u8>>),
+    /// Rows of a partition sorted by key, and the group being folded.
+    Sorted {
+        rows: SortedRecords,
+        group: Option<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+/// Groups of a [`HashAggregate`] with their results. Holds the aggregation's
+/// memory grant until dropped.
+pub struct Groups<A> {
+    aggregate: A,
+    layout: Layout,
+    pending: Vec<Pending>,
+    stage: Stage,
+    /// Part of it is lent to the sort of a partition while one is sorted.
+    grant: Reservation,
+}
+
+impl<A: Aggregate> Groups<A> {
+    fn next_group(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            match &mut self.stage {
+                Stage::Memory(groups) => {
+                    if let Some((key, mut state)) = groups.next() {
+                        self.aggregate.finish(&mut state);
+                        return Ok(Some((key.into_vec(), state)));
+                    }
+                }
+                Stage::Sorted { rows, group } => {
+                    for row in rows.by_ref() {
+                        let row = row?;
+                        let (key, value) = split_pair(&row)?;
+                        match group {
+                            Some((current, state)) if current.as_slice() == key => {
+                                self.aggregate.update(state, value)?;
+                            }
+                            _ => {
+                                let mut state = self.aggregate.init();
+                                self.aggregate.update(&mut state, value)?;
+                                let done = group.replace((key.to_vec(), state));
+                                if let Some((key, mut state)) = done {
+                                    self.aggregate.finish(&mut state);
+                                    return Ok(Some((key, state)));
+                                }
+                            }
+                        }
+                    }
+                    if let Some((key, mut state)) = group.take() {
+                        self.aggregate.finish(&mut state);
+                        return Ok(Some((key, state)));
+                    }
+                }
+            }
+            let Some(pending) = self.pending.pop() else {
+                return Ok(None);
+            };
+            self.start(pending)?;
+        }
+    }
+
+    /// Moves on to aggregating a spilled partition.
+    fn start(&mut self, pending: Pending) -> Result<()> {
+        let stage = std::mem::replace(&mut self.stage, Stage::Memory(HashMap::new().into_iter()));
+        if let Stage::Sorted { rows, .. } = stage {
+            self.grant.merge(rows.into_grant());
+        }
+        let mut rows = SpillReader::new(pending.file, self.layout.io_size);
+        if pending.depth > MAX_DEPTH {
+            // The rest of the grant covers the reader.
+            let lent = self.grant.split(self.layout.table_limit());
+            let mut sort = ExternalSort::with_comparator(
+                Arc::clone(&self.layout.vfs),
+                self.layout.dir.clone(),
+                lent,
+                compare_keys as Compare,
+            )?;
+            while rows.advance()? {
+                sort.push(rows.record())?;
+            }
+            self.stage = Stage::Sorted {
+                rows: sort.finish()?,
+                group: None,
+            };
+            return Ok(());
+        }
+        let mut table = Table::new(self.layout.clone(), pending.depth);
+        while rows.advance()? {
+            let (key, value) = split_pair(rows.record())?;
+            table.insert(&self.aggregate, key, value)?;
+        }
+        let (groups, pending) = table.finish()?;
+        self.pending.extend(pending);
+        self.stage = Stage::Memory(groups.into_iter());
+        Ok(())
+    }
+}
+
+impl<A: Aggregate> Iterator for Groups<A> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let group = self.next_group();
+        if group.is_err() {
+            self.pending.clear();
+            self.stage = Stage::Memory(HashMap::new().into_iter());
+        }
+        group.transpose()
+    }
+}
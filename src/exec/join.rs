@@ -0,0 +1,609 @@
+//! Grace hash join.
+//!
+//! Build records are hashed on their key into partitions held in memory.
+//! When they outgrow the join's memory grant the largest partition is written
+//! to disk, and later build records for it follow it there. Probe records
+//! that hash to a partition in memory are matched at once; those that hash to
+//! a spilled partition are written next to it.
+//!
+//! Once the probe input is exhausted, each pair of spilled build and probe
+//! partitions is joined in turn with the whole grant, hashing with a new seed
+//! so that a partition too large for memory is split again. A partition that
+//! re-partitioning fails to split, because most of its records share a key,
+//! is joined by a block nested loop instead: the build side is loaded as
+//! many records at a time as fit, and the probe side is read once per block.
+
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::hash;
+use super::spill::{split_pair, SpillFile, SpillReader, SpillWriter, SPILL_OVERHEAD};
+use crate::error::{Error, Result};
+use crate::memory::Reservation;
+use crate::vfs::Vfs;
+
+/// Bounds of the buffer each spilled partition is written and read through.
+const MIN_IO: usize = 512;
+const MAX_IO: usize = 64 << 10;
+
+/// Most partitions the input is split into.
+const MAX_FANOUT: usize = 64;
+
+/// Times a partition is re-partitioned before falling back to a nested loop.
+const MAX_DEPTH: u64 = 6;
+
+/// Smallest grant a join accepts.
+pub const MIN_JOIN_GRANT: usize = 16 << 10;
+
+/// Bytes a build record held in memory costs beyond its key and contents:
+/// its slot, and the room for its partition to grow into.
+const ENTRY_COST: usize = 3 * size_of::<Entry>();
+
+/// Build record held in memory.
+struct Entry {
+    hash: u64,
+    key_len: usize,
+    /// Key followed by the record.
+    data: Box<[u8]>,
+}
+
+impl Entry {
+    fn new(hash: u64, key: &[u8], record: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(key.len() + record.len());
+        data.extend_from_slice(key);
+        data.extend_from_slice(record);
+        Entry {
+            hash,
+            key_len: key.len(),
+            data: data.into_boxed_slice(),
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.data[..self.key_len]
+    }
+
+    fn record(&self) -> &[u8] {
+        &self.data[self.key_len..]
+    }
+
+    fn cost(&self) -> usize {
+        ENTRY_COST + self.data.len()
+    }
+}
+
+/// Position in a table sorted by hash of the next entry to check against a
+/// probe key.
+#[derive(Clone, Copy)]
+struct Cursor {
+    hash: u64,
+    pos: usize,
+}
+
+impl Cursor {
+    fn new(table: &[Entry], hash: u64) -> Self {
+        let pos = table.partition_point(|e| e.hash < hash);
+        Cursor { hash, pos }
+    }
+
+    /// Next entry of `table` whose key is `key`.
+    fn next<'t>(&mut self, table: &'t [Entry], key: &[u8]) -> Option<&'t Entry> {
+        while let Some(entry) = table.get(self.pos).filter(|e| e.hash == self.hash) {
+            self.pos += 1;
+            if entry.key() == key {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+/// Sizes shared by a join and the joins of its spilled partitions.
+#[derive(Clone)]
+struct Layout {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    /// Bytes of memory the join may use.
+    limit: usize,
+    /// Size of the buffer each spilled partition is written and read through.
+    io_size: usize,
+    fanout: usize,
+}
+
+impl Layout {
+    /// Longest key and record together the join accepts.
+    fn max_pair(&self) -> usize {
+        self.limit / 8
+    }
+
+    /// Bytes the build records held in memory may take, leaving room for a
+    /// reader and a writer.
+    fn table_limit(&self) -> usize {
+        self.limit - 2 * (self.io_size + SPILL_OVERHEAD) - self.max_pair()
+    }
+}
+
+enum Partition {
+    Memory(Vec<Entry>),
+    Spilled(SpillWriter),
+}
+
+/// Build side of a join, partitioned by the hash of the key.
+struct Build {
+    layout: Layout,
+    seed: u64,
+    partitions: Vec<Partition>,
+    /// Bytes held in memory by records and writers.
+    used: usize,
+}
+
+impl Build {
+    fn new(layout: Layout, seed: u64) -> Self {
+        let partitions = (0..layout.fanout)
+            .map(|_| Partition::Memory(Vec::new()))
+            .collect();
+        Build {
+            layout,
+            seed,
+            partitions,
+            used: 0,
+        }
+    }
+
+    fn partition(&self, hash: u64) -> usize {
+        (hash >> 32) as usize % self.layout.fanout
+    }
+
+    fn insert(&mut self, key: &[u8], record: &[u8]) -> Result<()> {
+        let hash = hash(self.seed, key);
+        let p = self.partition(hash);
+        let cost = ENTRY_COST + key.len() + record.len();
+        while matches!(self.partitions[p], Partition::Memory(_))
+            && self.used + cost > self.layout.table_limit()
+        {
+            self.spill_largest(p)?;
+        }
+        match &mut self.partitions[p] {
+            Partition::Memory(entries) => {
+                entries.push(Entry::new(hash, key, record));
+                self.used += cost;
+            }
+            Partition::Spilled(writer) => writer.write_pair(key, record)?,
+        }
+        Ok(())
+    }
+
+    /// Writes the largest partition in memory to disk, or partition `target`
+    /// once the others are empty.
+    fn spill_largest(&mut self, target: usize) -> Result<()> {
+        let largest = self
+            .partitions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| match p {
+                Partition::Memory(entries) if !entries.is_empty() || i == target => {
+                    Some((i, entries.iter().map(Entry::cost).sum()))
+                }
+                _ => None,
+            })
+            .max_by_key(|&(_, bytes): &(usize, usize)| bytes);
+        let Some((p, bytes)) = largest else {
+            return Err(Error::invalid(
+                "hash join partitions exceed the memory grant",
+            ));
+        };
+        let file = SpillFile::create(&*self.layout.vfs, &self.layout.dir)?;
+        let mut writer = SpillWriter::new(file, self.layout.io_size);
+        self.used += self.layout.io_size + SPILL_OVERHEAD;
+        let memory = std::mem::replace(&mut self.partitions[p], Partition::Memory(Vec::new()));
+        if let Partition::Memory(entries) = memory {
+            for entry in entries {
+                writer.write_pair(entry.key(), entry.record())?;
+            }
+        }
+        self.partitions[p] = Partition::Spilled(writer);
+        self.used -= bytes;
+        Ok(())
+    }
+
+    /// Ends the build input, sorting each partition in memory by hash.
+    fn into_table(self) -> Result<Table> {
+        let mut partitions = Vec::with_capacity(self.partitions.len());
+        for partition in self.partitions {
+            partitions.push(match partition {
+                Partition::Memory(mut entries) => {
+                    entries.sort_unstable_by_key(|e| e.hash);
+                    TablePartition::Memory(entries)
+                }
+                Partition::Spilled(writer) => {
+                    let build = writer.finish()?;
+                    let file = SpillFile::create(&*self.layout.vfs, &self.layout.dir)?;
+                    let probe = SpillWriter::new(file, self.layout.io_size);
+                    TablePartition::Spilled { build, probe }
+                }
+            });
+        }
+        Ok(Table {
+            layout: self.layout,
+            seed: self.seed,
+            partitions,
+        })
+    }
+}
+
+enum TablePartition {
+    Memory(Vec<Entry>),
+    Spilled {
+        build: SpillFile,
+        probe: SpillWriter,
+    },
+}
+
+/// Probe side of a join: the build partitions held in memory, ready to be
+/// matched, and writers for probe records of the spilled ones.
+struct Table {
+    layout: Layout,
+    seed: u64,
+    partitions: Vec<TablePartition>,
+}
+
+impl Table {
+    /// Locates the build records matching `key`, or writes the probe record
+    /// out if its partition is on disk.
+    fn probe(&mut self, key: &[u8], record: &[u8]) -> Result<Option<(usize, Cursor)>> {
+        let hash = hash(self.seed, key);
+        let p = (hash >> 32) as usize % self.layout.fanout;
+        match &mut self.partitions[p] {
+            TablePartition::Memory(entries) if entries.is_empty() => Ok(None),
+            TablePartition::Memory(entries) => Ok(Some((p, Cursor::new(entries, hash)))),
+            TablePartition::Spilled { probe, .. } => {
+                probe.write_pair(key, record)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn next_match(&self, p: usize, cursor: &mut Cursor, key: &[u8]) -> Option<&Entry> {
+        match &self.partitions[p] {
+            TablePartition::Memory(entries) => cursor.next(entries, key),
+            TablePartition::Spilled { .. } => None,
+        }
+    }
+
+    /// Releases the partitions in memory and returns the spilled ones still
+    /// to join.
+    fn finish(self) -> Result<Vec<Pending>> {
+        let mut pending = Vec::new();
+        for partition in self.partitions {
+            if let TablePartition::Spilled { build, probe } = partition {
+                let probe = probe.finish()?;
+                if build.len() > 0 && probe.len() > 0 {
+                    pending.push(Pending {
+                        build,
+                        probe,
+                        depth: self.seed + 1,
+                    });
+                }
+            }
+        }
+        Ok(pending)
+    }
+}
+
+/// Spilled partitions left to join.
+struct Pending {
+    build: SpillFile,
+    probe: SpillFile,
+    /// Seed to re-partition them with; past [`MAX_DEPTH`] they are joined
+    /// by a nested loop.
+    depth: u64,
+}
+
+impl Pending {
+    /// Sets up the join of the pair.
+    fn start(self, layout: &Layout) -> Result<Step> {
+        let parent_len = self.build.len();
+        let probe = SpillReader::new(self.probe, layout.io_size);
+        let mut build = SpillReader::new(self.build, layout.io_size);
+        if self.depth > MAX_DEPTH {
+            return Ok(Step::NestedLoop {
+                build,
+                carried: false,
+                block: Vec::new(),
+                probe,
+                found: None,
+            });
+        }
+        let mut table = Build::new(layout.clone(), self.depth);
+        while build.advance()? {
+            let (key, record) = split_pair(build.record())?;
+            table.insert(key, record)?;
+        }
+        Ok(Step::Hash {
+            table: table.into_table()?,
+            probe,
+            found: None,
+            parent_len,
+        })
+    }
+}
+
+/// Hash join of two inputs on a byte key, bounded by a memory grant.
+///
+/// Build records are added with [`build`](Self::build), then probe records
+/// are matched through the [`HashJoinProbe`] returned by
+/// [`probe`](Self::probe). Matches found while probing are returned at once;
+/// the rest come from [`HashJoinProbe::finish`] once the probe input is done.
+pub struct HashJoin {
+    build: Build,
+    grant: Reservation,
+}
+
+impl HashJoin {
+    /// Joins within `grant`, spilling partitions to files in `dir`.
+    pub fn new(vfs: Arc<dyn Vfs>, dir: impl Into<PathBuf>, grant: Reservation) -> Result<Self> {
+        if grant.size() < MIN_JOIN_GRANT {
+            return Err(Error::invalid(format!(
+                "a hash join needs a memory grant of at least {MIN_JOIN_GRANT} bytes"
+            )));
+        }
+        let limit = grant.size();
+        let io_size = (limit / 64).clamp(MIN_IO, MAX_IO);
+        let layout = Layout {
+            vfs,
+            dir: dir.into(),
+            limit,
+            io_size,
+            fanout: (limit / 4 / (io_size + SPILL_OVERHEAD)).clamp(2, MAX_FANOUT),
+        };
+        Ok(HashJoin {
+            build: Build::new(layout, 0),
+            grant,
+        })
+    }
+
+    /// Longest key and record together the join accepts.
+    pub fn max_record_size(&self) -> usize {
+        self.build.layout.max_pair()
+    }
+
+    fn check_size(&self, key: &[u8], record: &[u8]) -> Result<()> {
+        match key.len() + record.len() > self.max_record_size() {
+            true => Err(Error::invalid(format!(
+                "join record of {} bytes exceeds the {} bytes the memory grant allows",
+                key.len() + record.len(),
+                self.max_record_size()
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// Adds `record` to the build side under `key`.
+    pub fn build(&mut self, key: &[u8], record: &[u8]) -> Result<()> {
+        self.check_size(key, record)?;
+        self.build.insert(key, record)
+    }
+
+    /// Number of build partitions written to disk.
+    pub fn spilled_partitions(&self) -> usize {
+        let partitions = &self.build.partitions;
+        partitions
+            .iter()
+            .filter(|p| matches!(p, Partition::Spilled(_)))
+            .count()
+    }
+
+    /// Ends the build input and starts probing.
+    pub fn probe(self) -> Result<HashJoinProbe> {
+        Ok(HashJoinProbe {
+            table: self.build.into_table()?,
+            grant: self.grant,
+        })
+    }
+}
+
+/// Probe phase of a [`HashJoin`].
+pub struct HashJoinProbe {
+    table: Table,
+    grant: Reservation,
+}
+
+impl HashJoinProbe {
+    /// Returns the build records matching `key` that are held in memory; if
+    /// the key's partition was spilled, `record` is kept for
+    /// [`finish`](Self::finish) to match instead.
+    pub fn probe<'a>(&'a mut self, key: &'a [u8], record: &[u8]) -> Result<Matches<'a>> {
+        let max = self.table.layout.max_pair();
+        if key.len() + record.len() > max {
+            return Err(Error::invalid(format!(
+                "join record of {} bytes exceeds the {max} bytes the memory grant allows",
+                key.len() + record.len()
+            )));
+        }
+        let found = self.table.probe(key, record)?;
+        Ok(Matches {
+            table: &self.table,
+            key,
+            found,
+        })
+    }
+
+    /// Ends the probe input and returns the pairs of build and probe records
+    /// of the spilled partitions that match.
+    pub fn finish(self) -> Result<SpilledMatches> {
+        let layout = self.table.layout.clone();
+        Ok(SpilledMatches {
+            pending: self.table.finish()?,
+            layout,
+            current: None,
+            _grant: self.grant,
+        })
+    }
+}
+
+/// Build records held in memory matching a probe key.
+pub struct Matches<'a> {
+    table: &'a Table,
+    key: &'a [u8],
+    found: Option<(usize, Cursor)>,
+}
+
+impl<'a> Iterator for Matches<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let (p, cursor) = self.found.as_mut()?;
+        self.table
+            .next_match(*p, cursor, self.key)
+            .map(Entry::record)
+    }
+}
+
+/// Join of one pair of spilled partitions.
+enum Step {
+    /// The build partition is hashed again, and the probe partition read
+    /// through it.
+    Hash {
+        table: Table,
+        probe: SpillReader,
+        found: Option<(usize, Cursor)>,
+        /// Size of the build partition being split.
+        parent_len: u64,
+    },
+    /// The build partition is loaded a block at a time, and the probe
+    /// partition read once per block.
+    NestedLoop {
+        build: SpillReader,
+        /// The build reader holds a record the last block had no room for.
+        carried: bool,
+        block: Vec<Entry>,
+        probe: SpillReader,
+        found: Option<Cursor>,
+    },
+}
+
+/// Matching pairs of build and probe records from the spilled partitions of
+/// a [`HashJoin`]. Holds the join's memory grant until dropped.
+pub struct SpilledMatches {
+    layout: Layout,
+    pending: Vec<Pending>,
+    current: Option<Step>,
+    _grant: Reservation,
+}
+
+impl SpilledMatches {
+    fn next_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if self.current.is_none() {
+                let Some(pending) = self.pending.pop() else {
+                    return Ok(None);
+                };
+                self.current = Some(pending.start(&self.layout)?);
+            }
+            let step = self.current.as_mut().unwrap();
+            if let Some(pair) = step.next_pair(&self.layout)? {
+                return Ok(Some(pair));
+            }
+            if let Some(Step::Hash {
+                table, parent_len, ..
+            }) = self.current.take()
+            {
+                // A partition that did not shrink holds records sharing a
+                // key, which no seed will separate.
+                for mut pending in table.finish()? {
+                    if pending.build.len() >= parent_len {
+                        pending.depth = MAX_DEPTH + 1;
+                    }
+                    self.pending.push(pending);
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for SpilledMatches {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self.next_pair();
+        if pair.is_err() {
+            self.pending.clear();
+            self.current = None;
+        }
+        pair.transpose()
+    }
+}
+
+impl Step {
+    fn next_pair(&mut self, layout: &Layout) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            Step::Hash {
+                table,
+                probe,
+                found,
+                ..
+            } => loop {
+                if let Some((p, cursor)) = found {
+                    let (key, record) = split_pair(probe.record())?;
+                    if let Some(entry) = table.next_match(*p, cursor, key) {
+                        return Ok(Some((entry.record().to_vec(), record.to_vec())));
+                    }
+                }
+                if !probe.advance()? {
+                    return Ok(None);
+                }
+                let (key, record) = split_pair(probe.record())?;
+                *found = table.probe(key, record)?;
+            },
+            Step::NestedLoop {
+                build,
+                carried,
+                block,
+                probe,
+                found,
+            } => loop {
+                if let Some(cursor) = found {
+                    let (key, record) = split_pair(probe.record())?;
+                    if let Some(entry) = cursor.next(block, key) {
+                        return Ok(Some((entry.record().to_vec(), record.to_vec())));
+                    }
+                }
+                if probe.advance()? {
+                    let (key, _) = split_pair(probe.record())?;
+                    *found = Some(Cursor::new(block, hash(0, key)));
+                    continue;
+                }
+                *found = None;
+                if !load_block(layout, build, carried, block)? {
+                    return Ok(None);
+                }
+                probe.rewind();
+            },
+        }
+    }
+}
+
+/// Loads the next block of build records that fits in memory, returning
+/// `false` once there are none left.
+fn load_block(
+    layout: &Layout,
+    build: &mut SpillReader,
+    carried: &mut bool,
+    block: &mut Vec<Entry>,
+) -> Result<bool> {
+    block.clear();
+    let mut used = 0;
+    while std::mem::take(carried) || build.advance()? {
+        let (key, record) = split_pair(build.record())?;
+        let cost = ENTRY_COST + key.len() + record.len();
+        if used + cost > layout.table_limit() && !block.is_empty() {
+            *carried = true;
+            break;
+        }
+        block.push(Entry::new(hash(0, key), key, record));
+        used += cost;
+    }
+    block.sort_unstable_by_key(|e| e.hash);
+    Ok(!block.is_empty())
+}
@@ -0,0 +1,25 @@
+//! Query execution operators.
+//!
+//! Operators work within a memory grant, a [`Reservation`](crate::Reservation)
+//! handed to them when they are built, and spill to temporary files in a
+//! directory they are given once their input outgrows it.
+
+use std::hash::{DefaultHasher, Hasher};
+
+mod aggregate;
+mod join;
+mod sort;
+pub(crate) mod spill;
+
+pub use aggregate::{Aggregate, Count, Groups, HashAggregate, Max, Min, Sum, MIN_AGGREGATE_GRANT};
+pub use join::{HashJoin, HashJoinProbe, Matches, SpilledMatches, MIN_JOIN_GRANT};
+pub use sort::{Compare, ExternalSort, SortedRecords, MIN_SORT_GRANT};
+
+/// Hash of `key` used to partition inputs; each `seed` splits them
+/// differently.
+fn hash(seed: u64, key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(seed);
+    hasher.write(key);
+    hasher.finish()
+}
@@ -0,0 +1,320 @@
+//! External merge sort.
+//!
+//! Records are buffered in memory until the next one would overflow the
+//! sort's memory grant; the buffer is then sorted and written out as a run.
+//! Once every record has been pushed, runs are merged k at a time, with the
+//! fan-in k as large as the grant allows each run a read buffer and room for
+//! its largest record, until a single merge can produce the output. Input
+//! that fits in the grant is sorted without touching disk.
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::spill::{SpillFile, SpillReader, SpillWriter, SPILL_OVERHEAD};
+use crate::error::{Error, Result};
+use crate::memory::Reservation;
+use crate::vfs::Vfs;
+
+/// Bytes a buffered record costs beyond its contents.
+const RECORD_OVERHEAD: usize = size_of::<Vec<u8>>();
+
+/// Bounds of the buffer each run is written and read through.
+const MIN_IO: usize = 1 << 10;
+const MAX_IO: usize = 256 << 10;
+
+/// Smallest grant a sort accepts.
+pub const MIN_SORT_GRANT: usize = 16 * MIN_IO;
+
+/// Order records are sorted in.
+pub type Compare = fn(&[u8], &[u8]) -> Ordering;
+
+/// Sort of byte records bounded by a memory grant, spilling sorted runs to
+/// disk.
+///
+/// Records are compared bytewise unless a comparator is given with
+/// [`with_comparator`](Self::with_comparator). Equal records come out in no
+/// particular order.
+pub struct ExternalSort<C = Compare> {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    grant: Reservation,
+    compare: C,
+    records: Vec<Vec<u8>>,
+    /// Bytes of the grant in use.
+    used: usize,
+    /// Size of the buffer runs are written and read through.
+    io_size: usize,
+    /// Longest record pushed.
+    max_record: usize,
+    runs: VecDeque<SpillFile>,
+}
+
+impl ExternalSort {
+    /// Sorts records bytewise within `grant`, spilling runs to files in
+    /// `dir`.
+    pub fn new(vfs: Arc<dyn Vfs>, dir: impl Into<PathBuf>, grant: Reservation) -> Result<Self> {
+        Self::with_comparator(vfs, dir, grant, |a: &[u8], b: &[u8]| a.cmp(b))
+    }
+}
+
+impl<C: Fn(&[u8], &[u8]) -> Ordering> ExternalSort<C> {
+    /// Sorts records in the order of `compare` within `grant`, spilling runs
+    /// to files in `dir`.
+    pub fn with_comparator(
+        vfs: Arc<dyn Vfs>,
+        dir: impl Into<PathBuf>,
+        grant: Reservation,
+        compare: C,
+    ) -> Result<Self> {
+        if grant.size() < MIN_SORT_GRANT {
+            return Err(Error::invalid(format!(
+                "a sort needs a memory grant of at least {MIN_SORT_GRANT} bytes"
+            )));
+        }
+        let io_size = (grant.size() / 32).clamp(MIN_IO, MAX_IO);
+        Ok(ExternalSort {
+            vfs,
+            dir: dir.into(),
+            grant,
+            compare,
+            records: Vec::new(),
+            used: 0,
+            io_size,
+            max_record: 0,
+            runs: VecDeque::new(),
+        })
+    }
+
+    /// Longest record the grant can merge two runs of.
+    pub fn max_record_size(&self) -> usize {
+        self.grant.size() / 4 - self.io_size - SPILL_OVERHEAD
+    }
+
+    /// Memory a run being merged holds: its read buffer, its current record
+    /// and its reader.
+    fn reader_size(&self) -> usize {
+        self.io_size + self.max_record + SPILL_OVERHEAD
+    }
+
+    /// Number of sorted runs written to disk so far.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Adds `record` to the sort.
+    pub fn push(&mut self, record: &[u8]) -> Result<()> {
+        if record.len() > self.max_record_size() {
+            return Err(Error::invalid(format!(
+                "record of {} bytes exceeds the {} bytes a sort with a grant of {} bytes accepts",
+                record.len(),
+                self.max_record_size(),
+                self.grant.size()
+            )));
+        }
+        // Room is always left to write a run out through its buffer.
+        let reserve = self.io_size + SPILL_OVERHEAD;
+        if self.records.len() == self.records.capacity() {
+            let capacity = (2 * self.records.capacity()).max(16);
+            // Growing copies the slots, so both allocations are briefly held.
+            let grown = capacity * RECORD_OVERHEAD;
+            if self.used + grown + record.len() + reserve > self.grant.size() {
+                self.spill()?;
+            }
+            if self.records.len() == self.records.capacity() {
+                let old = self.records.capacity() * RECORD_OVERHEAD;
+                self.records.reserve_exact(capacity - self.records.len());
+                self.used += grown - old;
+            }
+        }
+        if self.used + record.len() + reserve > self.grant.size() {
+            self.spill()?;
+        }
+        self.records.push(record.to_vec());
+        self.used += record.len();
+        self.max_record = self.max_record.max(record.len());
+        Ok(())
+    }
+
+    /// Sorts the buffered records and writes them out as a run.
+    fn spill(&mut self) -> Result<()> {
+        if self.records.is_empty() {
+            return Ok(());
+        }
+        let compare = &self.compare;
+        self.records.sort_unstable_by(|a, b| compare(a, b));
+        let file = SpillFile::create(&*self.vfs, &self.dir)?;
+        let mut writer = SpillWriter::new(file, self.io_size);
+        for record in self.records.drain(..) {
+            writer.write(&record)?;
+            self.used -= record.len();
+        }
+        self.runs.push_back(writer.finish()?);
+        self.used += SPILL_OVERHEAD;
+        // The runs themselves must not crowd out the records.
+        if self.runs.len() * SPILL_OVERHEAD > self.grant.size() / 8 {
+            self.release_buffer();
+            self.merge_runs(self.runs.len() / 2)?;
+        }
+        Ok(())
+    }
+
+    fn release_buffer(&mut self) {
+        self.used -= self.records.capacity() * RECORD_OVERHEAD;
+        self.records = Vec::new();
+    }
+
+    /// Merges runs, oldest first, into new ones until at most `target` are
+    /// left, with as large a fan-in as the free memory allows.
+    fn merge_runs(&mut self, target: usize) -> Result<()> {
+        let free = self.grant.size() - self.used;
+        let fan_in = (free - self.io_size - SPILL_OVERHEAD) / self.reader_size();
+        while self.runs.len() > target {
+            let count = fan_in.min(self.runs.len() - target + 1);
+            let inputs = self.runs.drain(..count);
+            let mut merger = Merger::new(inputs, self.io_size, &self.compare)?;
+            let file = SpillFile::create(&*self.vfs, &self.dir)?;
+            let mut writer = SpillWriter::new(file, self.io_size);
+            while let Some(&i) = merger.heap.first() {
+                writer.write(merger.readers[i].record())?;
+                merger.advance()?;
+            }
+            drop(merger);
+            self.runs.push_back(writer.finish()?);
+            self.used -= (count - 1) * SPILL_OVERHEAD;
+        }
+        Ok(())
+    }
+
+    /// Finishes the input and returns the records in order.
+    pub fn finish(mut self) -> Result<SortedRecords<C>> {
+        if self.runs.is_empty() {
+            let compare = &self.compare;
+            self.records.sort_unstable_by(|a, b| compare(a, b));
+            let records = std::mem::take(&mut self.records).into_iter();
+            return Ok(SortedRecords {
+                output: Output::Memory(records),
+                grant: self.grant,
+            });
+        }
+        self.spill()?;
+        self.release_buffer();
+        self.merge_runs((self.grant.size() - self.used) / self.reader_size())?;
+        let merger = Merger::new(self.runs.drain(..), self.io_size, self.compare)?;
+        Ok(SortedRecords {
+            output: Output::Merge(merger),
+            grant: self.grant,
+        })
+    }
+}
+
+/// K-way merge of sorted runs, keeping the readers in a binary heap ordered
+/// by their current record.
+struct Merger<C> {
+    readers: Vec<SpillReader>,
+    /// Indexes of the readers that have a current record.
+    heap: Vec<usize>,
+    compare: C,
+}
+
+impl<C: Fn(&[u8], &[u8]) -> Ordering> Merger<C> {
+    fn new(
+        runs: impl ExactSizeIterator<Item = SpillFile>,
+        io_size: usize,
+        compare: C,
+    ) -> Result<Self> {
+        let mut merger = Merger {
+            readers: Vec::with_capacity(runs.len()),
+            heap: Vec::with_capacity(runs.len()),
+            compare,
+        };
+        for run in runs {
+            let mut reader = SpillReader::new(run, io_size);
+            if reader.advance()? {
+                merger.heap.push(merger.readers.len());
+            }
+            merger.readers.push(reader);
+        }
+        for i in (0..merger.heap.len() / 2).rev() {
+            merger.sift_down(i);
+        }
+        Ok(merger)
+    }
+
+    /// Whether the reader at heap slot `a` comes before the one at `b`;
+    /// ties go to the earlier run.
+    fn less(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.heap[a], self.heap[b]);
+        match (self.compare)(self.readers[a].record(), self.readers[b].record()) {
+            Ordering::Equal => a < b,
+            ordering => ordering == Ordering::Less,
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let mut least = i;
+            for child in [2 * i + 1, 2 * i + 2] {
+                if child < self.heap.len() && self.less(child, least) {
+                    least = child;
+                }
+            }
+            if least == i {
+                return;
+            }
+            self.heap.swap(i, least);
+            i = least;
+        }
+    }
+
+    /// Moves the reader with the least record on to its next one.
+    fn advance(&mut self) -> Result<()> {
+        if !self.readers[self.heap[0]].advance()? {
+            self.heap.swap_remove(0);
+        }
+        self.sift_down(0);
+        Ok(())
+    }
+}
+
+enum Output<C> {
+    Memory(std::vec::IntoIter<Vec<u8>>),
+    Merge(Merger<C>),
+}
+
+/// Records of an [`ExternalSort`] in order. Holds the sort's memory grant
+/// until dropped.
+pub struct SortedRecords<C = Compare> {
+    output: Output<C>,
+    grant: Reservation,
+}
+
+impl<C> SortedRecords<C> {
+    /// Drops the records not yet returned and hands back the memory grant.
+    pub fn into_grant(self) -> Reservation {
+        self.grant
+    }
+}
+
+impl<C: Fn(&[u8], &[u8]) -> Ordering> Iterator for SortedRecords<C> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.output {
+            Output::Memory(records) => records.next().map(Ok),
+            Output::Merge(merger) => {
+                let i = *merger.heap.first()?;
+                let record = merger.readers[i].take_record();
+                match merger.advance() {
+                    Ok(()) => Some(Ok(record)),
+                    Err(e) => {
+                        merger.heap.clear();
+                        Some(Err(e))
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Temporary files operators spill records to.
+//!
+//! A spill file holds a sequence of records, each prefixed by its varint
+//! length. It is unlinked as soon as it is created, so it disappears with its
+//! last handle even if the process dies. Writers and readers go through a
+//! buffer of a fixed capacity, which together with the largest record is all
+//! the memory they hold.
+//!
+//! Operators that spill keyed records store them as pairs: the key prefixed
+//! by its varint length, followed by the rest of the record.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::coding::{put_varint, Reader};
+use crate::error::Result;
+use crate::vfs::{Vfs, VfsFile};
+
+/// Approximate bytes of bookkeeping per open spill file.
+pub(crate) const SPILL_OVERHEAD: usize = 256;
+
+/// Longest varint length prefix.
+const MAX_HEADER: usize = 10;
+
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) struct SpillFile {
+    file: Box<dyn VfsFile>,
+    len: u64,
+}
+
+impl SpillFile {
+    /// Creates an empty spill file in `dir`.
+    pub(crate) fn create(vfs: &dyn Vfs, dir: &Path) -> Result<Self> {
+        let n = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("spill-{}-{n}.tmp", std::process::id()));
+        let file = vfs.open(&path, true)?;
+        vfs.remove(&path)?;
+        Ok(SpillFile { file, len: 0 })
+    }
+
+    /// Bytes written to the file.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// Varint encoding of `v`, and its length.
+fn varint(mut v: u64) -> ([u8; MAX_HEADER], usize) {
+    let mut out = [0; MAX_HEADER];
+    let mut len = 0;
+    while v >= 0x80 {
+        out[len] = v as u8 | 0x80;
+        v >>= 7;
+        len += 1;
+    }
+    out[len] = v as u8;
+    (out, len + 1)
+}
+
+/// Splits a record written with [`SpillWriter::write_pair`].
+pub(crate) fn split_pair(record: &[u8]) -> Result<(&[u8], &[u8])> {
+    let mut reader = Reader::new(record);
+    let key = reader.bytes()?;
+    Ok((key, &record[reader.position()..]))
+}
+
+/// Appends records to a spill file.
+pub(crate) struct SpillWriter {
+    file: SpillFile,
+    buf: Vec<u8>,
+}
+
+impl SpillWriter {
+    /// Writes to `file` through a buffer of `capacity` bytes.
+    pub(crate) fn new(file: SpillFile, capacity: usize) -> Self {
+        SpillWriter {
+            file,
+            buf: Vec::with_capacity(capacity.max(MAX_HEADER)),
+        }
+    }
+
+    pub(crate) fn write(&mut self, record: &[u8]) -> Result<()> {
+        self.write_parts(&[record])
+    }
+
+    /// Writes the record made of `key` and `value`.
+    pub(crate) fn write_pair(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (header, len) = varint(key.len() as u64);
+        self.write_parts(&[&header[..len], key, value])
+    }
+
+    /// Writes the record made of `parts` put end to end.
+    fn write_parts(&mut self, parts: &[&[u8]]) -> Result<()> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if self.buf.len() + MAX_HEADER + len > self.buf.capacity() {
+            self.flush()?;
+        }
+        put_varint(&mut self.buf, len as u64);
+        for part in parts {
+            if self.buf.len() + part.len() > self.buf.capacity() {
+                self.flush()?;
+            }
+            if part.len() <= self.buf.capacity() {
+                self.buf.extend_from_slice(part);
+            } else {
+                self.file.file.write_at(part, self.file.len)?;
+                self.file.len += part.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.file.write_at(&self.buf, self.file.len)?;
+        self.file.len += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes out the buffer and returns the file, releasing the buffer.
+    pub(crate) fn finish(mut self) -> Result<SpillFile> {
+        self.flush()?;
+        Ok(self.file)
+    }
+}
+
+/// Reads back the records of a spill file in order.
+pub(crate) struct SpillReader {
+    file: SpillFile,
+    /// Offset in the file of the end of `buf`.
+    offset: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    record: Vec<u8>,
+}
+
+impl SpillReader {
+    /// Reads `file` through a buffer of `capacity` bytes.
+    pub(crate) fn new(file: SpillFile, capacity: usize) -> Self {
+        SpillReader {
+            file,
+            offset: 0,
+            buf: Vec::with_capacity(capacity.max(MAX_HEADER)),
+            pos: 0,
+            record: Vec::new(),
+        }
+    }
+
+    /// Moves back to the first record.
+    pub(crate) fn rewind(&mut self) {
+        self.offset = 0;
+        self.buf.clear();
+        self.pos = 0;
+    }
+
+    /// Moves to the next record, returning `false` at the end of the file.
+    pub(crate) fn advance(&mut self) -> Result<bool> {
+        self.fill(MAX_HEADER)?;
+        if self.pos == self.buf.len() {
+            return Ok(false);
+        }
+        let mut header = Reader::new(&self.buf[self.pos..]);
+        let len = header.varint()? as usize;
+        self.pos += header.position();
+        if self.record.capacity() < len {
+            // Dropped first so the old and new buffers are never both held.
+            self.record = Vec::new();
+            self.record.reserve_exact(len);
+        }
+        self.record.clear();
+        let buffered = (self.buf.len() - self.pos).min(len);
+        self.record
+            .extend_from_slice(&self.buf[self.pos..self.pos + buffered]);
+        self.pos += buffered;
+        if buffered < len {
+            self.record.resize(len, 0);
+            let rest = &mut self.record[buffered..];
+            self.file.file.read_exact_at(rest, self.offset)?;
+            self.offset += rest.len() as u64;
+        }
+        Ok(true)
+    }
+
+    /// Buffers at least `want` bytes, or what is left of the file.
+    fn fill(&mut self, want: usize) -> Result<()> {
+        if self.buf.len() - self.pos >= want || self.offset == self.file.len {
+            return Ok(());
+        }
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let start = self.buf.len();
+        let n = (self.buf.capacity() - start).min((self.file.len - self.offset) as usize);
+        self.buf.resize(start + n, 0);
+        self.file
+            .file
+            .read_exact_at(&mut self.buf[start..], self.offset)?;
+        self.offset += n as u64;
+        Ok(())
+    }
+
+    /// Current record.
+    pub(crate) fn record(&self) -> &[u8] {
+        &self.record
+    }
+
+    /// Takes the current record out of the reader.
+    pub(crate) fn take_record(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.record)
+    }
+}
@@ -0,0 +1,109 @@
+//! Groups of writes applied atomically.
+
+use std::time::Duration;
+
+use super::merge::Merge;
+use super::{Db, DEFAULT};
+use crate::error::Result;
+use crate::memory::Reservation;
+use crate::wal::Durability;
+
+/// Approximate bytes of bookkeeping per operation buffered in a batch.
+const OP_OVERHEAD: usize = 48;
+
+/// Operation on a key already prefixed with its key space, as buffered in a
+/// [`WriteBatch`].
+pub(super) enum Op {
+    /// Stores a value, expiring after the time-to-live if there is one.
+    Put(Vec<u8>, Option<Duration>),
+    Delete,
+    Merge(Merge),
+}
+
+/// Writes buffered to be applied at once by [`WriteBatch::commit`]: either
+/// all of them become visible and durable or none do.
+///
+/// A batch is created by [`Db::batch`] and holds its operations in memory,
+/// reserved from the budget as they are added.
+pub struct WriteBatch<'db> {
+    db: &'db Db,
+    ops: Vec<(Vec<u8>, Op)>,
+    durability: Durability,
+    reservation: Reservation,
+}
+
+impl<'db> WriteBatch<'db> {
+    pub(super) fn new(db: &'db Db) -> Self {
+        WriteBatch {
+            db,
+            ops: Vec::new(),
+            durability: db.durability,
+            reservation: db.budget.reservation(),
+        }
+    }
+
+    /// Stores `value` under `key`, for good.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.push([&[DEFAULT], key].concat(), Op::Put(value.to_vec(), None))
+    }
+
+    /// Stores `value` under `key` until `ttl` has elapsed from the commit.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let op = Op::Put(value.to_vec(), Some(ttl));
+        self.push([&[DEFAULT], key].concat(), op)
+    }
+
+    /// Removes `key`.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.push([&[DEFAULT], key].concat(), Op::Delete)
+    }
+
+    /// Folds `merge` into the value of `key` left by the writes before it.
+    pub fn merge(&mut self, key: &[u8], merge: Merge) -> Result<()> {
+        self.push([&[DEFAULT], key].concat(), Op::Merge(merge))
+    }
+
+    /// Durability the batch waits for when committed, instead of
+    /// [`Options::durability`](crate::Options::durability).
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch holds no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(super) fn db(&self) -> &'db Db {
+        self.db
+    }
+
+    pub(super) fn push(&mut self, key: Vec<u8>, op: Op) -> Result<()> {
+        let size = match &op {
+            Op::Put(value, _) => value.len(),
+            Op::Merge(merge) => merge.size(),
+            Op::Delete => 0,
+        };
+        self.reservation.grow(key.len() + size + OP_OVERHEAD)?;
+        self.ops.push((key, op));
+        Ok(())
+    }
+
+    /// Applies the operations in the order they were added, as a single
+    /// atomic write.
+    pub fn commit(self) -> Result<()> {
+        let WriteBatch {
+            db,
+            ops,
+            durability,
+            reservation: _reservation,
+        } = self;
+        let _locks = db.locks.lock(ops.iter().map(|(key, _)| key.as_slice()));
+        db.apply(ops, durability)
+    }
+}
@@ -0,0 +1,47 @@
+//! Conversions between typed keys and values and the bytes stored for them.
+
+use crate::error::{Error, Result};
+
+/// Turns values of type `T` into bytes and back.
+///
+/// Codecs are types without values, named as the last parameter of a
+/// [`Tree`](super::Tree). A tree scans its keys in the order of their
+/// encodings, which is the order of the keys themselves only for codecs that
+/// preserve it, like [`Raw`], [`Utf8`] and [`Ordered`](super::Ordered).
+pub trait Codec<T> {
+    /// Appends the encoding of `value` to `out`.
+    fn encode(value: &T, out: &mut Vec<u8>) -> Result<()>;
+
+    /// Decodes a value from all of `bytes`.
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// Stores byte strings as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(value: &Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Stores strings as their UTF-8 bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8;
+
+impl Codec<String> for Utf8 {
+    fn encode(value: &String, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::corruption("string is not UTF-8"))
+    }
+}
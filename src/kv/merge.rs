@@ -0,0 +1,52 @@
+//! Read-modify-write operations on stored values.
+
+use crate::error::Result;
+use crate::merge::{Append, Counter};
+
+/// Change folded into the current value of a key by
+/// [`Db::merge`](super::Db::merge) and
+/// [`WriteBatch::merge`](super::WriteBatch::merge), without reading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merge {
+    /// Adds to a counter stored as a big-endian `i64`; a missing key counts
+    /// as zero. Read the value back with [`Merge::counter`].
+    Add(i64),
+    /// Appends an item to a list stored as items prefixed by their varint
+    /// length; a missing key counts as the empty list. Read the value back
+    /// with [`Merge::items`].
+    Append(Vec<u8>),
+    /// Folds `operand` in with the operator of
+    /// [`Options::merge_operators`](crate::Options::merge_operators) named
+    /// `operator`.
+    Operator { operator: String, operand: Vec<u8> },
+}
+
+impl Merge {
+    /// Name of the operator folding the merge in, and its operand.
+    pub(super) fn into_parts(self) -> (String, Vec<u8>) {
+        match self {
+            Merge::Add(delta) => (Counter::NAME.to_string(), Counter::operand(delta).to_vec()),
+            Merge::Append(item) => (Append::NAME.to_string(), Append::operand(&item)),
+            Merge::Operator { operator, operand } => (operator, operand),
+        }
+    }
+
+    /// Bytes the merge takes up in a batch.
+    pub(super) fn size(&self) -> usize {
+        match self {
+            Merge::Add(_) => 8,
+            Merge::Append(item) => item.len(),
+            Merge::Operator { operator, operand } => operator.len() + operand.len(),
+        }
+    }
+
+    /// Count held by a value written with [`Merge::Add`].
+    pub fn counter(value: &[u8]) -> Result<i64> {
+        Counter::value(value)
+    }
+
+    /// Items of a list written with [`Merge::Append`], oldest first.
+    pub fn items(value: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Append::items(value)
+    }
+}
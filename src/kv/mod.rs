@@ -0,0 +1,307 @@
+//! Embedded key-value store.
+//!
+//! A [`Db`] stores byte keys and values in the storage engine chosen by
+//! [`Options::engine`]. Besides its own keys it holds any number of named
+//! [`Tree`]s, each a separate key space of typed keys and values converted
+//! to bytes by a [`Codec`]. With the `serde` feature, on by default, the
+//! [`Ordered`] codec stores every type implementing serde's traits, keeping
+//! keys in their natural order.
+//!
+//! Writes to several keys, of the `Db` and of its trees alike, are applied
+//! atomically by a [`WriteBatch`]; on the LSM engine the whole batch is a
+//! single record of the write-ahead log. [`Db::compare_and_swap`] updates a
+//! key based on its current value, and [`Db::merge`] has a
+//! [`MergeOperator`](crate::MergeOperator) fold a change into it without
+//! reading it.
+//! Every write holds a lock on the keys it touches, one of a fixed set of
+//! stripes the keys are hashed to, so that these updates cannot interleave
+//! with other writes to the same keys.
+//!
+//! A key written with a time-to-live, by [`Db::put_with_ttl`] or through a
+//! tree opened [`with_ttl`](Tree::with_ttl), expires once it has elapsed:
+//! reads no longer see it from then on, and [`Db::sweep_expired`] deletes it
+//! for good. Writing a key again without a time-to-live makes it permanent.
+//!
+//! Keys are stored behind a prefix naming their key space: a single byte for
+//! the keys of the `Db` itself, and a byte followed by the length and bytes
+//! of the name for the keys of a tree. Three more key spaces hold the
+//! deadlines of the keys that expire and the key spaces they belong to.
+//!
+//! On the B+tree engine a key, prefix included, may be at most
+//! [`BTreeEngine::max_key_len`](crate::btree::BTreeEngine::max_key_len)
+//! bytes long, about a quarter of [`Options::page_size`]; values of any size
+//! are stored, those too large for a page in overflow pages.
+
+mod batch;
+mod codec;
+mod merge;
+#[cfg(feature = "serde")]
+mod ordered;
+mod tree;
+mod ttl;
+
+use std::collections::HashSet;
+use std::hash::{BuildHasher, RandomState};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+
+pub use self::batch::WriteBatch;
+pub use self::codec::{Codec, Raw, Utf8};
+pub use self::merge::Merge;
+#[cfg(feature = "serde")]
+pub use self::ordered::Ordered;
+pub use self::tree::{DefaultCodec, Tree, TreeScan};
+pub use self::ttl::Sweeper;
+
+use self::batch::Op;
+use self::ttl::LiveScan;
+
+use crate::coding::put_bytes;
+use crate::engine::{open_engine, StorageEngine};
+use crate::error::Result;
+use crate::memory::MemoryBudget;
+use crate::options::Options;
+use crate::range::{prefix_successor, KeyRange};
+use crate::wal::Durability;
+
+/// Key space of the keys written directly through a [`Db`].
+const DEFAULT: u8 = 0;
+/// Key space of the keys of named trees.
+const TREE: u8 = 1;
+/// Key space of the deadlines of expiring keys, by key.
+const EXPIRY: u8 = 2;
+/// Key space of the deadlines of expiring keys, by deadline.
+const DEADLINES: u8 = 3;
+/// Key space of the prefixes of the key spaces that hold expiring keys.
+const EXPIRING: u8 = 4;
+
+/// Number of locks the keys are hashed to.
+const LOCK_STRIPES: usize = 64;
+
+/// Embedded key-value database.
+pub struct Db {
+    engine: Box<dyn StorageEngine>,
+    budget: MemoryBudget,
+    durability: Durability,
+    locks: KeyLocks,
+    /// Prefixes of the key spaces that hold expiring keys, as stored under
+    /// [`EXPIRING`]. Keys of other key spaces have no deadline to look up.
+    expiring: RwLock<HashSet<Vec<u8>>>,
+}
+
+impl Db {
+    /// Opens the database in `dir` with the engine chosen by
+    /// [`Options::engine`], creating it if needed.
+    pub fn open(dir: impl AsRef<Path>, options: &Options, budget: &MemoryBudget) -> Result<Self> {
+        let engine = open_engine(dir, options, budget)?;
+        let expiring = ttl::expiring(&*engine)?;
+        Ok(Db {
+            engine,
+            budget: budget.clone(),
+            durability: options.durability,
+            locks: KeyLocks::new(),
+            expiring: RwLock::new(expiring),
+        })
+    }
+
+    /// Returns the value stored under `key`.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read(&[&[DEFAULT], key].concat())
+    }
+
+    /// Stores `value` under `key`, for good.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.set([&[DEFAULT], key].concat(), Op::Put(value.to_vec(), None))
+    }
+
+    /// Stores `value` under `key` until `ttl` has elapsed.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.set(
+            [&[DEFAULT], key].concat(),
+            Op::Put(value.to_vec(), Some(ttl)),
+        )
+    }
+
+    /// Removes `key`.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.set([&[DEFAULT], key].concat(), Op::Delete)
+    }
+
+    /// Time left before `key` expires, or `None` if it does not exist or
+    /// never expires.
+    pub fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let key = [&[DEFAULT], key].concat();
+        let now = ttl::now();
+        match self.deadline(&key)? {
+            Some(deadline) if deadline > now => Ok(Some(Duration::from_millis(deadline - now))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Replaces the value of `key` with `new` if it is `expected`, where
+    /// `None` stands for no value: expecting `None` only creates the key, and
+    /// a `new` of `None` deletes it.
+    ///
+    /// Returns `Ok(Err(current))` with the value found instead when it is not
+    /// the expected one.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), Option<Vec<u8>>>> {
+        self.swap([&[DEFAULT], key].concat(), expected, new, None)
+    }
+
+    /// Folds `merge` into the value of `key`, keeping its time-to-live.
+    pub fn merge(&self, key: &[u8], merge: Merge) -> Result<()> {
+        self.set([&[DEFAULT], key].concat(), Op::Merge(merge))
+    }
+
+    /// Starts a batch of writes to apply atomically.
+    pub fn batch(&self) -> WriteBatch<'_> {
+        WriteBatch::new(self)
+    }
+
+    /// Iterates in key order over the pairs in `range`.
+    pub fn scan(&self, range: impl Into<KeyRange>) -> DbScan<'_> {
+        DbScan {
+            inner: LiveScan::new(self, within(&[DEFAULT], range.into())),
+        }
+    }
+
+    /// Iterates in key order over the pairs whose key starts with `prefix`.
+    pub fn prefix_scan(&self, prefix: &[u8]) -> DbScan<'_> {
+        self.scan(KeyRange::prefix(prefix))
+    }
+
+    /// Opens the tree called `name`, which exists as soon as something is
+    /// stored in it. The key, value and codec types are usually given by the
+    /// type the tree is assigned to, such as `Tree<u64, User>`.
+    pub fn tree<K, V, C>(&self, name: &str) -> Tree<'_, K, V, C>
+    where
+        C: Codec<K> + Codec<V>,
+    {
+        let mut prefix = vec![TREE];
+        put_bytes(&mut prefix, name.as_bytes());
+        Tree::new(self, prefix)
+    }
+
+    /// Writes buffered changes to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    /// Storage engine holding the data.
+    pub fn engine(&self) -> &dyn StorageEngine {
+        &*self.engine
+    }
+
+    /// Returns the value stored under the prefixed `key`, unless it expired.
+    fn read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(value) = self.engine.get(key)? else {
+            return Ok(None);
+        };
+        if !self.expires(key) {
+            return Ok(Some(value));
+        }
+        match self.deadline(key)? {
+            Some(deadline) if deadline <= ttl::now() => Ok(None),
+            _ => Ok(Some(value)),
+        }
+    }
+
+    /// Applies `op` to the prefixed `key`.
+    fn set(&self, key: Vec<u8>, op: Op) -> Result<()> {
+        let _lock = self.locks.lock([key.as_slice()]);
+        self.apply(vec![(key, op)], self.durability)
+    }
+
+    /// Swaps the value of the prefixed `key`, giving `new` the time-to-live
+    /// `ttl`.
+    fn swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<Result<(), Option<Vec<u8>>>> {
+        let _lock = self.locks.lock([key.as_slice()]);
+        let current = self.read(&key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(current));
+        }
+        let op = match new {
+            Some(value) => Op::Put(value.to_vec(), ttl),
+            None => Op::Delete,
+        };
+        self.apply(vec![(key, op)], self.durability)?;
+        Ok(Ok(()))
+    }
+}
+
+/// Locks serializing the writes to the same keys.
+struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+    hasher: RandomState,
+}
+
+impl KeyLocks {
+    fn new() -> Self {
+        KeyLocks {
+            stripes: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Locks the stripes of `keys`, in a fixed order so that writers locking
+    /// several cannot deadlock.
+    fn lock<'k>(&self, keys: impl IntoIterator<Item = &'k [u8]>) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys
+            .into_iter()
+            .map(|key| self.hasher.hash_one(key) as usize % LOCK_STRIPES)
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|i| self.stripes[i].lock().unwrap())
+            .collect()
+    }
+}
+
+/// `range` moved into the key space starting with `prefix`.
+fn within(prefix: &[u8], range: KeyRange) -> KeyRange {
+    let prefixed = |key: Vec<u8>| [prefix, &key].concat();
+    KeyRange {
+        start: match range.start {
+            Bound::Unbounded => Bound::Included(prefix.to_vec()),
+            bound => bound.map(prefixed),
+        },
+        end: match range.end {
+            Bound::Unbounded => match prefix_successor(prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+            bound => bound.map(prefixed),
+        },
+    }
+}
+
+/// Iterator returned by [`Db::scan`] and [`Db::prefix_scan`].
+pub struct DbScan<'a> {
+    inner: LiveScan<'a>,
+}
+
+impl Iterator for DbScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inner.next()?.map(|(mut key, value)| {
+            key.remove(0);
+            (key, value)
+        }))
+    }
+}
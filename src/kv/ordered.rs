@@ -0,0 +1,680 @@
+//! Serde format whose bytewise order is the order of the values.
+//!
+//! Values are written without any description of their type, so decoding
+//! needs the type they were encoded from:
+//!
+//! - `bool` is a byte, 0 or 1; unsigned integers are big-endian and signed
+//!   ones big-endian with the sign bit flipped; floats are their bits with
+//!   the sign bit flipped when positive and every bit flipped when negative;
+//!   a `char` is its code point as a `u32`.
+//! - Strings and byte strings have their zero bytes followed by `0xff` and
+//!   end with two zero bytes, as in the keys of the SQL layer.
+//! - `None` is a zero byte and `Some` a one byte followed by the value.
+//! - Sequences and maps put a one byte before every element or entry and a
+//!   zero byte after the last.
+//! - Tuples, structs and newtypes are their fields one after the other, and
+//!   an enum variant is its index as a `u32` followed by its fields.
+//!
+//! No encoding is a prefix of another encoding of the same type, so values
+//! compare like the first field that differs, which is the order derived
+//! `Ord` implementations follow. Strings are compared bytewise; floats order
+//! negative zero before zero and NaN past infinity.
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use super::codec::Codec;
+use crate::coding::Reader;
+use crate::error::{Error, Result};
+
+/// Codec of every type implementing serde's `Serialize` and `Deserialize`,
+/// preserving the order of the values; see the [module](self) for the
+/// format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ordered;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Ordered {
+    fn encode(value: &T, out: &mut Vec<u8>) -> Result<()> {
+        value.serialize(&mut Encoder { out })
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        let mut decoder = Decoder {
+            reader: Reader::new(bytes),
+        };
+        let value = T::deserialize(&mut decoder)?;
+        if !decoder.reader.is_empty() {
+            return Err(Error::corruption("trailing bytes after value"));
+        }
+        Ok(value)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::invalid(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::corruption(msg.to_string())
+    }
+}
+
+const NONE: u8 = 0;
+const SOME: u8 = 1;
+/// Marker ending a sequence or map.
+const END: u8 = 0;
+/// Marker preceding every element of a sequence or map.
+const MORE: u8 = 1;
+
+fn put_escaped(out: &mut Vec<u8>, bytes: &[u8]) {
+    for &b in bytes {
+        out.push(b);
+        if b == 0 {
+            out.push(0xff);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+struct Encoder<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.out.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Encoder<'b> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.put(&[v as u8])
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.put(&((v as u8) ^ 1 << 7).to_be_bytes())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.put(&((v as u16) ^ 1 << 15).to_be_bytes())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.put(&((v as u32) ^ 1 << 31).to_be_bytes())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.put(&((v as u64) ^ 1 << 63).to_be_bytes())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.put(&((v as u128) ^ 1 << 127).to_be_bytes())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.put(&[v])
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.put(&v.to_be_bytes())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.put(&v.to_be_bytes())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.put(&v.to_be_bytes())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.put(&v.to_be_bytes())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        let bits = v.to_bits();
+        let bits = if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits ^ 1 << 31
+        };
+        self.put(&bits.to_be_bytes())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        let bits = v.to_bits();
+        let bits = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ 1 << 63
+        };
+        self.put(&bits.to_be_bytes())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_u32(v.into())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        put_escaped(self.out, v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        put_escaped(self.out, v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.put(&[NONE])
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<()> {
+        self.out.push(SOME);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, index: u32, _: &'static str) -> Result<()> {
+        self.serialize_u32(index)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.serialize_u32(index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(index)?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl ser::SerializeSeq for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.out.push(MORE);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.put(&[END])
+    }
+}
+
+impl ser::SerializeMap for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.out.push(MORE);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.put(&[END])
+    }
+}
+
+// Tuples, structs and their variants are their fields one after the other.
+
+impl ser::SerializeTuple for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _: &'static str, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _: &'static str, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Decoder<'de> {
+    reader: Reader<'de>,
+}
+
+impl<'de> Decoder<'de> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.reader.take(N)?.try_into().unwrap())
+    }
+
+    fn escaped(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.reader.u8()? {
+                0 if self.reader.u8()? == 0 => return Ok(bytes),
+                0 => bytes.push(0),
+                b => bytes.push(b),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.escaped()?).map_err(|_| Error::corruption("string is not UTF-8"))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    /// Reads the marker before an element, returning whether there is one.
+    fn more(&mut self) -> Result<bool> {
+        match self.reader.u8()? {
+            END => Ok(false),
+            MORE => Ok(true),
+            b => Err(Error::corruption(format!("invalid element marker {b}"))),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Decoder<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value> {
+        Err(Error::invalid(
+            "the ordered format cannot decode values of unknown type",
+        ))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.reader.u8()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            b => Err(Error::corruption(format!("invalid boolean {b}"))),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8((u8::from_be_bytes(self.array()?) ^ 1 << 7) as i8)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16((u16::from_be_bytes(self.array()?) ^ 1 << 15) as i16)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32((u32::from_be_bytes(self.array()?) ^ 1 << 31) as i32)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64((u64::from_be_bytes(self.array()?) ^ 1 << 63) as i64)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i128((u128::from_be_bytes(self.array()?) ^ 1 << 127) as i128)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.reader.u8()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(u16::from_be_bytes(self.array()?))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(u64::from_be_bytes(self.array()?))
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u128(u128::from_be_bytes(self.array()?))
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = u32::from_be_bytes(self.array()?);
+        let bits = if bits >> 31 == 1 {
+            bits ^ 1 << 31
+        } else {
+            !bits
+        };
+        visitor.visit_f32(f32::from_bits(bits))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = u64::from_be_bytes(self.array()?);
+        let bits = if bits >> 63 == 1 {
+            bits ^ 1 << 63
+        } else {
+            !bits
+        };
+        visitor.visit_f64(f64::from_bits(bits))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let code = self.u32()?;
+        let c = char::from_u32(code)
+            .ok_or_else(|| Error::corruption(format!("invalid character {code:#x}")))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.string()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.string()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.escaped()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.escaped()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.reader.u8()? {
+            NONE => visitor.visit_none(),
+            SOME => visitor.visit_some(self),
+            b => Err(Error::corruption(format!("invalid option tag {b}"))),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Elements {
+            decoder: self,
+            remaining: None,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Elements {
+            decoder: self,
+            remaining: Some(len),
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(Elements {
+            decoder: self,
+            remaining: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value> {
+        Err(Error::invalid(
+            "the ordered format cannot skip values of unknown type",
+        ))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Elements of a sequence or map, which end at a marker, or of a tuple or
+/// struct, which have a known count.
+struct Elements<'a, 'de> {
+    decoder: &'a mut Decoder<'de>,
+    remaining: Option<usize>,
+}
+
+impl Elements<'_, '_> {
+    fn next(&mut self) -> Result<bool> {
+        match &mut self.remaining {
+            Some(0) => Ok(false),
+            Some(n) => {
+                *n -= 1;
+                Ok(true)
+            }
+            None => self.decoder.more(),
+        }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if !self.next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if !self.next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.decoder)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Decoder<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let index = self.u32()?;
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Decoder<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
@@ -0,0 +1,200 @@
+//! Named key spaces of typed keys and values.
+
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+use super::batch::{Op, WriteBatch};
+use super::codec::Codec;
+use super::ttl::LiveScan;
+use super::{within, Db};
+use crate::error::{Error, Result};
+use crate::range::KeyRange;
+
+/// Codec of a [`Tree`] whose type names none: [`Ordered`](super::Ordered)
+/// with the `serde` feature, [`Raw`](super::Raw) without it.
+#[cfg(feature = "serde")]
+pub type DefaultCodec = super::Ordered;
+/// Codec of a [`Tree`] whose type names none: [`Ordered`](super::Ordered)
+/// with the `serde` feature, [`Raw`](super::Raw) without it.
+#[cfg(not(feature = "serde"))]
+pub type DefaultCodec = super::Raw;
+
+/// Marks the types a tree stores without owning any of them.
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// Key space of a [`Db`] holding keys of type `K` and values of type `V`,
+/// both stored in the encoding of `C`. Opened with [`Db::tree`].
+pub struct Tree<'db, K, V, C = DefaultCodec> {
+    db: &'db Db,
+    prefix: Vec<u8>,
+    /// Time-to-live of the values stored through the tree.
+    ttl: Option<Duration>,
+    _types: Types<K, V, C>,
+}
+
+impl<'db, K, V, C: Codec<K> + Codec<V>> Tree<'db, K, V, C> {
+    pub(super) fn new(db: &'db Db, prefix: Vec<u8>) -> Self {
+        Tree {
+            db,
+            prefix,
+            ttl: None,
+            _types: PhantomData,
+        }
+    }
+
+    /// Makes the values stored through the tree, by [`put`](Self::put),
+    /// [`compare_and_swap`](Self::compare_and_swap) and
+    /// [`batch_put`](Self::batch_put), expire once `ttl` has elapsed.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn key(&self, key: &K) -> Result<Vec<u8>> {
+        let mut out = self.prefix.clone();
+        C::encode(key, &mut out)?;
+        Ok(out)
+    }
+
+    fn check_batch(&self, batch: &WriteBatch<'_>) -> Result<()> {
+        match std::ptr::eq(self.db, batch.db()) {
+            true => Ok(()),
+            false => Err(Error::invalid("the batch belongs to another database")),
+        }
+    }
+
+    fn value(value: &V) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        C::encode(value, &mut out)?;
+        Ok(out)
+    }
+
+    /// The bound of an encoded key, without the prefix of the tree.
+    fn bound(bound: Bound<&K>) -> Result<Bound<Vec<u8>>> {
+        let (key, included) = match bound {
+            Bound::Included(key) => (key, true),
+            Bound::Excluded(key) => (key, false),
+            Bound::Unbounded => return Ok(Bound::Unbounded),
+        };
+        let mut out = Vec::new();
+        C::encode(key, &mut out)?;
+        Ok(match included {
+            true => Bound::Included(out),
+            false => Bound::Excluded(out),
+        })
+    }
+
+    /// Returns the value stored under `key`.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.db.read(&self.key(key)?)? {
+            Some(value) => Ok(Some(<C as Codec<V>>::decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `value` under `key`, with the time-to-live of the tree.
+    pub fn put(&self, key: &K, value: &V) -> Result<()> {
+        let op = Op::Put(Self::value(value)?, self.ttl);
+        self.db.set(self.key(key)?, op)
+    }
+
+    /// Stores `value` under `key` until `ttl` has elapsed, whatever the
+    /// time-to-live of the tree.
+    pub fn put_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        let op = Op::Put(Self::value(value)?, Some(ttl));
+        self.db.set(self.key(key)?, op)
+    }
+
+    /// Removes `key`.
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.db.set(self.key(key)?, Op::Delete)
+    }
+
+    /// Replaces the value of `key` with `new` if it is `expected`, as
+    /// [`Db::compare_and_swap`] does, comparing the encoded values. `new` gets
+    /// the time-to-live of the tree.
+    pub fn compare_and_swap(
+        &self,
+        key: &K,
+        expected: Option<&V>,
+        new: Option<&V>,
+    ) -> Result<Result<(), Option<V>>> {
+        let expected = expected.map(Self::value).transpose()?;
+        let new = new.map(Self::value).transpose()?;
+        let key = self.key(key)?;
+        match self
+            .db
+            .swap(key, expected.as_deref(), new.as_deref(), self.ttl)?
+        {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current
+                .map(|v| <C as Codec<V>>::decode(&v))
+                .transpose()?)),
+        }
+    }
+
+    /// Adds storing `value` under `key`, with the time-to-live of the tree,
+    /// to `batch`.
+    pub fn batch_put(&self, batch: &mut WriteBatch<'_>, key: &K, value: &V) -> Result<()> {
+        self.check_batch(batch)?;
+        batch.push(self.key(key)?, Op::Put(Self::value(value)?, self.ttl))
+    }
+
+    /// Adds removing `key` to `batch`.
+    pub fn batch_delete(&self, batch: &mut WriteBatch<'_>, key: &K) -> Result<()> {
+        self.check_batch(batch)?;
+        batch.push(self.key(key)?, Op::Delete)
+    }
+
+    /// Iterates over the pairs whose key lies in `range`, in the order of the
+    /// encoded keys.
+    pub fn scan(&self, range: impl RangeBounds<K>) -> Result<TreeScan<'db, K, V, C>> {
+        let range = KeyRange {
+            start: Self::bound(range.start_bound())?,
+            end: Self::bound(range.end_bound())?,
+        };
+        let range = within(&self.prefix, range);
+        Ok(self.iter(range))
+    }
+
+    /// Iterates over the pairs whose encoded key starts with the encoding of
+    /// `prefix`. With [`Ordered`](super::Ordered), these are the keys whose
+    /// leading fields equal `prefix`, as for a tuple key and its first
+    /// element.
+    pub fn prefix_scan<P>(&self, prefix: &P) -> Result<TreeScan<'db, K, V, C>>
+    where
+        C: Codec<P>,
+    {
+        let mut encoded = self.prefix.clone();
+        C::encode(prefix, &mut encoded)?;
+        Ok(self.iter(KeyRange::prefix(&encoded)))
+    }
+
+    fn iter(&self, range: KeyRange) -> TreeScan<'db, K, V, C> {
+        TreeScan {
+            inner: LiveScan::new(self.db, range),
+            prefix_len: self.prefix.len(),
+            _types: PhantomData,
+        }
+    }
+}
+
+/// Iterator returned by [`Tree::scan`] and [`Tree::prefix_scan`].
+pub struct TreeScan<'db, K, V, C = DefaultCodec> {
+    inner: LiveScan<'db>,
+    prefix_len: usize,
+    _types: Types<K, V, C>,
+}
+
+impl<K, V, C: Codec<K> + Codec<V>> Iterator for TreeScan<'_, K, V, C> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self.inner.next()?.and_then(|(key, value)| {
+            let key = <C as Codec<K>>::decode(&key[self.prefix_len..])?;
+            Ok((key, <C as Codec<V>>::decode(&value)?))
+        });
+        Some(pair)
+    }
+}
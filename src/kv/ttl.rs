@@ -0,0 +1,344 @@
+//! Expiration of keys after a time-to-live.
+//!
+//! The deadline of a key with a time-to-live is stored twice, in key spaces
+//! of their own: under the key, for reads to check, and as an index entry
+//! holding the deadline followed by the key, for the sweeper to find expired
+//! keys in deadline order. Both are written in the same atomic write as the
+//! value. Writing the key again replaces its deadline without reading the
+//! old one, so the old index entry stays behind until the sweeper reaches it
+//! and finds that it no longer matches.
+//!
+//! The first key given a time-to-live in a key space, the keys of the `Db`
+//! or of a tree, marks the key space as expiring. Only keys of expiring key
+//! spaces have deadlines to look up or replace, so the others are read and
+//! written as if time-to-lives did not exist.
+//!
+//! Reads hide a key from its deadline on. Its value, deadline and index
+//! entry stay on disk until [`Db::sweep_expired`] deletes them, a bounded
+//! chunk of keys at a time, either when called or periodically from the
+//! thread started by [`Db::spawn_sweeper`].
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::batch::Op;
+use super::{Db, DEADLINES, EXPIRING, EXPIRY, TREE};
+use crate::coding::Reader;
+use crate::engine::{Mutation, Scan, StorageEngine};
+use crate::error::{Error, Result};
+use crate::range::KeyRange;
+use crate::wal::Durability;
+
+/// Most expired keys a sweep deletes in one write.
+const SWEEP_CHUNK: usize = 64;
+/// Approximate bytes of bookkeeping per key held by a sweep.
+const SWEEP_OVERHEAD: usize = 48;
+
+/// Current time in milliseconds since the Unix epoch.
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Deadline of a key written at `now` to live for `ttl`.
+pub(super) fn deadline(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+/// Key under which the deadline of the prefixed `key` is stored.
+pub(super) fn expiry_key(key: &[u8]) -> Vec<u8> {
+    [&[EXPIRY], key].concat()
+}
+
+/// Key of the index entry of the prefixed `key` expiring at `deadline`.
+fn index_key(deadline: u64, key: &[u8]) -> Vec<u8> {
+    [&[DEADLINES][..], &deadline.to_be_bytes(), key].concat()
+}
+
+/// Prefix of the key space of the prefixed `key`.
+fn space(key: &[u8]) -> &[u8] {
+    if key.first() != Some(&TREE) {
+        return &key[..key.len().min(1)];
+    }
+    let mut reader = Reader::new(&key[1..]);
+    match reader.bytes() {
+        Ok(_) => &key[..1 + reader.position()],
+        Err(_) => key,
+    }
+}
+
+/// Prefixes of the key spaces `engine` marks as expiring.
+pub(super) fn expiring(engine: &dyn StorageEngine) -> Result<HashSet<Vec<u8>>> {
+    engine
+        .scan(KeyRange::prefix(&[EXPIRING]))
+        .map(|entry| entry.map(|(key, _)| key[1..].to_vec()))
+        .collect()
+}
+
+fn decode_deadline(bytes: &[u8]) -> Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::corruption("malformed key deadline"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl Db {
+    /// Deadline of the prefixed `key`, if it has one.
+    pub(super) fn deadline(&self, key: &[u8]) -> Result<Option<u64>> {
+        match self.engine.get(&expiry_key(key))? {
+            Some(bytes) => Ok(Some(decode_deadline(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the prefixed `key` belongs to an expiring key space, and so
+    /// may have a deadline.
+    pub(super) fn expires(&self, key: &[u8]) -> bool {
+        self.expiring.read().unwrap().contains(space(key))
+    }
+
+    /// Writes `ops` atomically together with the changes they make to the
+    /// deadlines of their keys, in expiring key spaces: a put replaces the
+    /// deadline with the one of its time-to-live, or removes it, a delete
+    /// removes it, and a merge keeps it, applying to no value if the key has
+    /// expired. Only merges look the deadline up. The caller holds the locks
+    /// of the keys.
+    pub(super) fn apply(&self, ops: Vec<(Vec<u8>, Op)>, durability: Durability) -> Result<()> {
+        let now = now();
+        // Deadlines of the keys as left by the ops so far.
+        let mut deadlines: HashMap<Vec<u8>, Option<u64>> = HashMap::new();
+        // Key spaces the ops mark as expiring.
+        let mut marked: HashSet<Vec<u8>> = HashSet::new();
+        let mut mutations = Vec::with_capacity(ops.len());
+        for (key, op) in ops {
+            let expires = self.expires(&key) || marked.contains(space(&key));
+            match &op {
+                Op::Put(_, Some(ttl)) => {
+                    if !expires {
+                        mutations.push(Mutation::Put {
+                            key: [&[EXPIRING], space(&key)].concat(),
+                            value: Vec::new(),
+                        });
+                        marked.insert(space(&key).to_vec());
+                    }
+                    let deadline = deadline(now, *ttl);
+                    mutations.push(Mutation::Put {
+                        key: expiry_key(&key),
+                        value: deadline.to_be_bytes().to_vec(),
+                    });
+                    mutations.push(Mutation::Put {
+                        key: index_key(deadline, &key),
+                        value: Vec::new(),
+                    });
+                    deadlines.insert(key.clone(), Some(deadline));
+                }
+                Op::Put(_, None) | Op::Delete if expires => {
+                    mutations.push(Mutation::Delete {
+                        key: expiry_key(&key),
+                    });
+                    deadlines.insert(key.clone(), None);
+                }
+                Op::Merge(_) if expires => {
+                    let current = match deadlines.get(&key) {
+                        Some(deadline) => *deadline,
+                        None => self.deadline(&key)?,
+                    };
+                    if let Some(deadline) = current.filter(|&deadline| deadline <= now) {
+                        mutations.push(Mutation::Delete { key: key.clone() });
+                        mutations.push(Mutation::Delete {
+                            key: expiry_key(&key),
+                        });
+                        mutations.push(Mutation::Delete {
+                            key: index_key(deadline, &key),
+                        });
+                        deadlines.insert(key.clone(), None);
+                    }
+                }
+                _ => {}
+            }
+            mutations.push(match op {
+                Op::Put(value, _) => Mutation::Put { key, value },
+                Op::Delete => Mutation::Delete { key },
+                Op::Merge(merge) => {
+                    let (operator, operand) = merge.into_parts();
+                    Mutation::Merge {
+                        key,
+                        operator,
+                        operand,
+                    }
+                }
+            });
+        }
+        self.engine.write(&mutations, durability)?;
+        if !marked.is_empty() {
+            self.expiring.write().unwrap().extend(marked);
+        }
+        Ok(())
+    }
+
+    /// Deletes every key whose deadline has passed, returning how many.
+    ///
+    /// Expired keys are read from the index and deleted in chunks of a
+    /// fixed number of keys, so a sweep holds little memory however many
+    /// keys it deletes.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        let now = now();
+        let range = KeyRange {
+            start: Bound::Included(vec![DEADLINES]),
+            end: Bound::Excluded(index_key(now.saturating_add(1), &[])),
+        };
+        let mut swept = 0;
+        loop {
+            let mut reservation = self.budget.reservation();
+            let mut chunk = Vec::with_capacity(SWEEP_CHUNK);
+            for entry in self.engine.scan(range.clone()).take(SWEEP_CHUNK) {
+                let (index, _) = entry?;
+                if index.len() < 9 {
+                    return Err(Error::corruption("malformed expiry index entry"));
+                }
+                reservation.grow(index.len() + SWEEP_OVERHEAD)?;
+                chunk.push(index);
+            }
+            if chunk.is_empty() {
+                return Ok(swept);
+            }
+            let _locks = self.locks.lock(chunk.iter().map(|index| &index[9..]));
+            let mut mutations = Vec::with_capacity(chunk.len() * 3);
+            for index in chunk {
+                let key = &index[9..];
+                // The key may have been written again since the index was
+                // read, replacing its deadline and index entry.
+                if self.deadline(key)? == Some(decode_deadline(&index[1..9])?) {
+                    mutations.push(Mutation::Delete { key: key.to_vec() });
+                    mutations.push(Mutation::Delete {
+                        key: expiry_key(key),
+                    });
+                    swept += 1;
+                }
+                mutations.push(Mutation::Delete { key: index });
+            }
+            self.engine.write(&mutations, self.durability)?;
+        }
+    }
+
+    /// Starts a thread calling [`sweep_expired`](Self::sweep_expired) every
+    /// `interval`, until the returned [`Sweeper`] is stopped or dropped, the
+    /// database is dropped, or a sweep fails.
+    pub fn spawn_sweeper(self: &Arc<Self>, interval: Duration) -> Sweeper {
+        let db = Arc::downgrade(self);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return Ok(()),
+            }
+            let Some(db) = db.upgrade() else {
+                return Ok(());
+            };
+            db.sweep_expired()?;
+        });
+        Sweeper {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Handle of the thread started by [`Db::spawn_sweeper`]. Dropping it stops
+/// the thread, waiting for a running sweep to finish.
+pub struct Sweeper {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Sweeper {
+    /// Stops the thread, returning the error that ended it early, if any.
+    pub fn stop(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        drop(self.stop.take());
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+/// Iterator over the pairs of a scan whose keys have not expired, checking
+/// them against a scan of their deadlines over the same range.
+pub(super) struct LiveScan<'a> {
+    data: Scan<'a>,
+    deadlines: Scan<'a>,
+    /// Next deadline not yet passed by the data scan, with its key
+    /// prefixed by [`EXPIRY`].
+    next: Option<(Vec<u8>, u64)>,
+    started: bool,
+    now: u64,
+}
+
+impl<'a> LiveScan<'a> {
+    pub(super) fn new(db: &'a Db, range: KeyRange) -> Self {
+        LiveScan {
+            deadlines: db.engine.scan(super::within(&[EXPIRY], range.clone())),
+            data: db.engine.scan(range),
+            next: None,
+            started: false,
+            now: now(),
+        }
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        self.next = match self.deadlines.next().transpose()? {
+            Some((key, bytes)) => Some((key, decode_deadline(&bytes)?)),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Whether the deadline of `key`, if any, has passed.
+    fn expired(&mut self, key: &[u8]) -> Result<bool> {
+        if !self.started {
+            self.started = true;
+            self.advance()?;
+        }
+        loop {
+            match &self.next {
+                Some((next, _)) if &next[1..] < key => self.advance()?,
+                Some((next, deadline)) if &next[1..] == key => return Ok(*deadline <= self.now),
+                _ => return Ok(false),
+            }
+        }
+    }
+}
+
+impl Iterator for LiveScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.data.next()? {
+                Ok(pair) => pair,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.expired(&key) {
+                Ok(true) => continue,
+                Ok(false) => return Some(Ok((key, value))),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
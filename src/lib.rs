@@ -0,0 +1,39 @@
+//! Rust based database which will function under limited memory capacity.
+//!
+//! Every component that keeps data on the heap reserves it from a single
+//! [`MemoryBudget`] configured through [`Options::memory_limit`]. Exceeding the
+//! budget is reported as an [`OutOfBudget`] error, or triggers eviction or
+//! spilling, rather than growing the process.
+
+pub mod btree;
+pub mod buffer;
+mod checksum;
+mod coding;
+pub mod compression;
+pub mod engine;
+pub mod error;
+pub mod exec;
+pub mod kv;
+pub mod lsm;
+pub mod memory;
+pub mod merge;
+pub mod mvcc;
+pub mod options;
+pub mod range;
+pub mod sql;
+pub mod vfs;
+pub mod wal;
+
+pub use engine::{open_engine, EngineKind, Mutation, StorageEngine};
+pub use error::{Error, OutOfBudget, Result};
+pub use exec::{ExternalSort, HashAggregate, HashJoin};
+pub use kv::{Codec, Db, Tree, WriteBatch};
+pub use memory::{MemoryBudget, Reclaim, Reservation};
+pub use merge::MergeOperator;
+pub use mvcc::{Isolation, Transaction, TransactionDb, VersionOverflow};
+pub use options::Options;
+pub use range::KeyRange;
+#[cfg(target_os = "linux")]
+pub use vfs::DirectVfs;
+pub use vfs::{FaultyVfs, MemVfs, StdVfs, Vfs};
+pub use wal::Durability;
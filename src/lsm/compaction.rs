@@ -0,0 +1,382 @@
+//! Merging tables to bound the work of reads, or dropping them to bound the
+//! size of the tree, as chosen by [`Options::compaction`].
+//!
+//! With [`CompactionStyle::Leveled`], level 0 is compacted into level 1 once
+//! it holds [`Options::l0_compaction_trigger`] tables. Every deeper level `n`
+//! may hold `10^n` times [`Options::target_file_size`] bytes; above that, one
+//! of its tables is merged into level `n + 1`, rotating through the key space.
+//!
+//! With [`CompactionStyle::Tiered`], the tree is a list of sorted runs from
+//! newest to oldest: every level 0 table, then every non-empty deeper level.
+//! Once there are [`Options::l0_compaction_trigger`] runs, the newest runs
+//! whose sizes are within the size ratio of the runs newer than them are
+//! merged into one; if no two are, just enough of the newest runs are merged
+//! to get below the trigger. The merged run takes the place of the oldest
+//! input: its level, or the deepest free level above the next older run when
+//! the input ended with the oldest level 0 table, or else the place of the
+//! inputs in level 0.
+//!
+//! With [`CompactionStyle::Fifo`], tables are never merged. Whole tables are
+//! dropped, oldest first, while the tree is larger than its maximum size or
+//! the newest data of the table is older than the time to live.
+//!
+//! Compactions run one at a time, on the compaction thread of the tree or in
+//! [`LsmEngine::compact`](super::LsmEngine::compact), while flushes go on
+//! adding tables to the front of level 0. Those are newer than any input,
+//! so a merge is installed in place of its inputs whatever was flushed in
+//! the meantime. Compactions stream through their inputs with one block per
+//! input run in memory and write outputs split at the target file size,
+//! except in level 0 where an output is one table.
+//! [`Options::compaction_rate_limit`] caps the bytes they read and write per
+//! second.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::sstable::{table_path, Table, TableBuilder, TableMeta};
+use super::{Compaction, EntrySource, Inner, LevelIter, MergeIter, Value, Version, NUM_LEVELS};
+use crate::error::Result;
+use crate::range::KeyRange;
+
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// How the tables of an LSM tree are compacted, selected through
+/// [`Options::compaction`](crate::Options::compaction).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// Levels of non-overlapping tables, each ten times the size of the one
+    /// above. Reads check at most one table per level, at the cost of
+    /// rewriting data more often; suits read-heavy workloads.
+    #[default]
+    Leveled,
+    /// Sorted runs merged with runs of similar size. Data is rewritten less
+    /// often than with leveled compaction but reads check more runs; suits
+    /// write-heavy workloads.
+    Tiered {
+        /// How much larger, in percent, a run may be than the newer runs
+        /// merged so far for it to join the merge.
+        size_ratio: u32,
+    },
+    /// Tables are never merged; the oldest ones are deleted instead. Suits
+    /// time series whose old data may go.
+    Fifo {
+        /// Bytes of tables the tree keeps at most.
+        max_size: u64,
+        /// Age after which a table is deleted, measured from the time its
+        /// newest entry was written. `None` keeps tables regardless of age.
+        ttl: Option<Duration>,
+    },
+}
+
+/// Work chosen by a compaction strategy.
+enum Task {
+    /// Merges `runs`, newest first, into `output_level`.
+    Merge {
+        runs: Vec<Vec<Arc<Table>>>,
+        output_level: usize,
+        /// Whether no older table may hold the keys of the inputs, so that
+        /// tombstones can go.
+        bottommost: bool,
+        /// Level whose compaction pointer moves to the given key.
+        pointer: Option<(usize, Vec<u8>)>,
+    },
+    /// Deletes `tables`.
+    Drop { tables: Vec<Arc<Table>> },
+}
+
+fn max_bytes_for_level(level: usize, target_file_size: u64) -> u64 {
+    target_file_size.saturating_mul(LEVEL_SIZE_MULTIPLIER.saturating_pow(level as u32))
+}
+
+fn overlapping(level: &[Arc<Table>], smallest: &[u8], largest: &[u8]) -> Vec<Arc<Table>> {
+    level
+        .iter()
+        .filter(|t| t.meta().overlaps(smallest, largest))
+        .cloned()
+        .collect()
+}
+
+fn key_range(tables: &[&Arc<Table>]) -> (Vec<u8>, Vec<u8>) {
+    let smallest = tables.iter().map(|t| &t.meta().smallest).min().unwrap();
+    let largest = tables.iter().map(|t| &t.meta().largest).max().unwrap();
+    (smallest.clone(), largest.clone())
+}
+
+fn run_size(run: &[Arc<Table>]) -> u64 {
+    run.iter().map(|t| t.meta().size).sum()
+}
+
+/// Seconds since the Unix epoch, the clock table ages are measured with.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Inner {
+    /// Runs compactions until the tree is within the limits of its strategy.
+    pub(super) fn compact(&self) -> Result<()> {
+        let mut compaction = self.compaction.lock().unwrap();
+        loop {
+            let version = self.snapshot().1;
+            let task = match self.options.compaction {
+                CompactionStyle::Leveled => self.pick_leveled(&version, &compaction),
+                CompactionStyle::Tiered { size_ratio } => self.pick_tiered(&version, size_ratio),
+                CompactionStyle::Fifo { max_size, ttl } => pick_fifo(&version, max_size, ttl),
+            };
+            let Some(task) = task else {
+                return Ok(());
+            };
+            self.run(task, &mut compaction)?;
+        }
+    }
+
+    fn pick_leveled(&self, version: &Version, compaction: &Compaction) -> Option<Task> {
+        let l0 = &version.levels[0];
+        if !l0.is_empty() && l0.len() >= self.options.l0_compaction_trigger {
+            let (smallest, largest) = key_range(&l0.iter().collect::<Vec<_>>());
+            let mut runs: Vec<_> = l0.iter().map(|t| vec![Arc::clone(t)]).collect();
+            runs.push(overlapping(&version.levels[1], &smallest, &largest));
+            return Some(self.merge_into(version, runs, 1, None));
+        }
+        let target = self.options.target_file_size as u64;
+        for level in 1..NUM_LEVELS - 1 {
+            let tables = &version.levels[level];
+            if run_size(tables) <= max_bytes_for_level(level, target) {
+                continue;
+            }
+            let pointer = &compaction.compact_pointer[level];
+            let input = tables
+                .iter()
+                .find(|t| t.meta().largest > *pointer)
+                .unwrap_or(&tables[0]);
+            let meta = input.meta();
+            let runs = vec![
+                vec![Arc::clone(input)],
+                overlapping(&version.levels[level + 1], &meta.smallest, &meta.largest),
+            ];
+            let pointer = Some((level, meta.largest.clone()));
+            return Some(self.merge_into(version, runs, level + 1, pointer));
+        }
+        None
+    }
+
+    fn pick_tiered(&self, version: &Version, size_ratio: u32) -> Option<Task> {
+        // Sorted runs, newest first, with the level each comes from.
+        let mut runs: Vec<(usize, Vec<Arc<Table>>)> = version.levels[0]
+            .iter()
+            .map(|t| (0, vec![Arc::clone(t)]))
+            .collect();
+        for (level, tables) in version.levels.iter().enumerate().skip(1) {
+            if !tables.is_empty() {
+                runs.push((level, tables.clone()));
+            }
+        }
+        let trigger = self.options.l0_compaction_trigger.max(2);
+        if runs.len() < trigger {
+            return None;
+        }
+        let mut merged = run_size(&runs[0].1);
+        let mut count = 1;
+        while count < runs.len() {
+            let size = run_size(&runs[count].1);
+            if u128::from(size) * 100 > u128::from(merged) * u128::from(100 + size_ratio) {
+                break;
+            }
+            merged += size;
+            count += 1;
+        }
+        if count < 2 {
+            count = runs.len() + 2 - trigger;
+        }
+        let (last_level, _) = runs[count - 1];
+        let output_level = if last_level > 0 {
+            last_level
+        } else if count == version.levels[0].len() {
+            // Every level 0 table is merged: move down to just above the
+            // next older run, if there is room.
+            let next = runs.get(count).map_or(NUM_LEVELS, |(level, _)| *level);
+            next - 1
+        } else {
+            0
+        };
+        let runs = runs.drain(..count).map(|(_, run)| run).collect();
+        Some(self.merge_into(version, runs, output_level, None))
+    }
+
+    /// A merge of `runs` into `output_level`, dropping tombstones if no older
+    /// table may hold their keys.
+    fn merge_into(
+        &self,
+        version: &Version,
+        runs: Vec<Vec<Arc<Table>>>,
+        output_level: usize,
+        pointer: Option<(usize, Vec<u8>)>,
+    ) -> Task {
+        let inputs: Vec<&Arc<Table>> = runs.iter().flatten().collect();
+        let (smallest, largest) = key_range(&inputs);
+        let is_input = |t: &Arc<Table>| inputs.iter().any(|i| i.id() == t.id());
+        // Inputs are always the newest data in their key range, so the older
+        // tables are the level 0 tables after the last input in level 0, if
+        // any, and the tables below the output level.
+        let older_l0 = match version.levels[0].iter().rposition(is_input) {
+            Some(last) => &version.levels[0][last + 1..],
+            None => &[],
+        };
+        let older = older_l0
+            .iter()
+            .chain(version.levels[output_level + 1..].iter().flatten())
+            .filter(|t| !is_input(t));
+        let bottommost = older
+            .into_iter()
+            .all(|t| !t.meta().overlaps(&smallest, &largest));
+        Task::Merge {
+            runs,
+            output_level,
+            bottommost,
+            pointer,
+        }
+    }
+
+    fn run(&self, task: Task, compaction: &mut Compaction) -> Result<()> {
+        let (removed, outputs, output_level, pointer) = match task {
+            Task::Merge {
+                runs,
+                output_level,
+                bottommost,
+                pointer,
+            } => {
+                let removed: Vec<Arc<Table>> = runs.iter().flatten().cloned().collect();
+                let outputs = self.merge_runs(runs, output_level, bottommost)?;
+                (removed, outputs, output_level, pointer)
+            }
+            Task::Drop { tables } => (tables, Vec::new(), 0, None),
+        };
+
+        let removed_ids: Vec<u64> = removed.iter().map(|t| t.id()).collect();
+        let mut tables = Vec::with_capacity(outputs.len());
+        for meta in outputs {
+            let table = Arc::new(Table::open(
+                &*self.options.vfs,
+                &self.dir,
+                meta,
+                &self.budget,
+            )?);
+            self.cache.insert(&table);
+            tables.push(table);
+        }
+
+        {
+            let log_lsn = self.manifest.lock().unwrap();
+            // Tables flushed since the task was picked are newer than its
+            // inputs and stay in front of them.
+            let mut levels = self.snapshot().1.levels.clone();
+            let first_input = levels[0]
+                .iter()
+                .position(|t| removed_ids.contains(&t.id()))
+                .unwrap_or(levels[0].len());
+            for level in &mut levels {
+                level.retain(|t| !removed_ids.contains(&t.id()));
+            }
+            if output_level == 0 {
+                // Only merges of the newest runs write to level 0.
+                levels[0].splice(first_input..first_input, tables);
+            } else {
+                levels[output_level].extend(tables);
+                levels[output_level].sort_by(|a, b| a.meta().smallest.cmp(&b.meta().smallest));
+            }
+            let version = Version { levels };
+            version
+                .manifest(self.next_file.load(Ordering::SeqCst), *log_lsn)
+                .store(&*self.options.vfs, &self.dir)?;
+            self.state.write().unwrap().version = Arc::new(version);
+        }
+        if let Some((level, key)) = pointer {
+            compaction.compact_pointer[level] = key;
+        }
+        for id in removed_ids {
+            self.options.vfs.remove(&table_path(&self.dir, id))?;
+        }
+        Ok(())
+    }
+
+    /// Streams the entries of `runs` into new tables, returning them.
+    fn merge_runs(
+        &self,
+        runs: Vec<Vec<Arc<Table>>>,
+        output_level: usize,
+        bottommost: bool,
+    ) -> Result<Vec<TableMeta>> {
+        let created = runs.iter().flatten().map(|t| t.meta().created).max();
+        let sources: Vec<Box<dyn EntrySource>> = runs
+            .into_iter()
+            .filter(|run| !run.is_empty())
+            .map(|run| {
+                let iter = LevelIter::new(run, KeyRange::all())
+                    .with_rate_limiter(self.rate_limiter.clone());
+                Box::new(iter) as Box<dyn EntrySource>
+            })
+            .collect();
+        let mut merge = MergeIter::new(sources, Arc::clone(&self.operators));
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        while let Some((key, mut value)) = merge.next_entry()? {
+            if bottommost {
+                // Nothing older is left: tombstones have nothing to hide and
+                // merges apply to no value.
+                value = value.stack(&key, Value::Delete, &self.operators)?;
+                if value == Value::Delete {
+                    continue;
+                }
+            }
+            let current = match &mut builder {
+                Some(b) => b,
+                None => {
+                    let created = TableBuilder::create(
+                        &*self.options.vfs,
+                        &self.dir,
+                        self.next_file_id(),
+                        &self.options,
+                        &self.budget,
+                    )?;
+                    builder.insert(created.with_rate_limiter(self.rate_limiter.clone()))
+                }
+            };
+            current.add(&key, &value)?;
+            if output_level > 0 && current.estimated_size() >= self.options.target_file_size as u64
+            {
+                outputs.push(builder.take().unwrap().finish()?);
+            }
+        }
+        if let Some(b) = builder.filter(|b| !b.is_empty()) {
+            outputs.push(b.finish()?);
+        }
+        // The outputs hold nothing newer than the inputs did.
+        for meta in &mut outputs {
+            meta.created = created.unwrap_or(meta.created);
+        }
+        Ok(outputs)
+    }
+}
+
+fn pick_fifo(version: &Version, max_size: u64, ttl: Option<Duration>) -> Option<Task> {
+    // Deeper levels are left over from another strategy and older than any
+    // level 0 table, which are ordered newest first.
+    let oldest_first = version.levels[1..]
+        .iter()
+        .rev()
+        .flatten()
+        .chain(version.levels[0].iter().rev());
+    let mut size: u64 = version.levels.iter().flatten().map(|t| t.meta().size).sum();
+    let expired = ttl.map_or(0, |ttl| now().saturating_sub(ttl.as_secs()));
+    let mut tables = Vec::new();
+    for table in oldest_first {
+        if size <= max_size && table.meta().created >= expired {
+            break;
+        }
+        size -= table.meta().size;
+        tables.push(Arc::clone(table));
+    }
+    (!tables.is_empty()).then_some(Task::Drop { tables })
+}
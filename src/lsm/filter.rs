@@ -0,0 +1,317 @@
+//! Approximate membership filters stored in table files.
+//!
+//! A filter answers whether a table (or one of its data blocks) may contain a
+//! key; a negative answer lets a lookup skip the read altogether. Two kinds
+//! are available:
+//!
+//! - Bloom filters set `k` bits per key, with `k` derived from the bits per
+//!   key, and have a false positive rate of about `0.6185^bits`.
+//! - Ribbon filters solve a banded linear system over GF(2) so that each key
+//!   maps to an `r`-bit fingerprint. They need about 30% less space than a
+//!   Bloom filter for the same false positive rate of `2^-r`, at the cost of
+//!   a slower build.
+//!
+//! A serialized filter starts with its kind, so tables written with
+//! different settings can be read side by side:
+//!
+//! ```text
+//! bloom:  1  k:u8  bits*
+//! ribbon: 2  r:u8  seed:u32  (word:u64 * r)*
+//! ```
+//!
+//! Keys are hashed with a function defined here rather than the standard
+//! library's, whose output may change between releases.
+
+use crate::coding::{put_u32, u32_at, u64_at};
+use crate::options::Options;
+
+const KIND_BLOOM: u8 = 1;
+const KIND_RIBBON: u8 = 2;
+
+/// Width of the band of coefficients each ribbon row covers.
+const RIBBON_WIDTH: usize = 64;
+/// Slots a ribbon filter allocates per key; the slack makes construction
+/// very likely to succeed on the first seed.
+const RIBBON_OVERHEAD: f64 = 1.1;
+/// Seeds a ribbon build tries before it grows the filter.
+const RIBBON_ATTEMPTS: u32 = 4;
+
+/// Kind of filter written into every LSM table file, selected through
+/// [`Options::filter`](crate::Options::filter).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    /// No filter; every lookup reads a data block of each candidate table.
+    None,
+    /// Bloom filter; fast to build and query.
+    #[default]
+    Bloom,
+    /// Ribbon filter; smaller than a Bloom filter with the same accuracy but
+    /// slower to build.
+    Ribbon,
+}
+
+/// What filters a table builder writes.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterPolicy {
+    kind: FilterKind,
+    bits_per_key: u32,
+    per_block: bool,
+}
+
+impl FilterPolicy {
+    /// Filters `options` asks for.
+    pub fn new(options: &Options) -> Self {
+        FilterPolicy {
+            kind: options.filter,
+            bits_per_key: options.filter_bits_per_key,
+            per_block: options.block_filters,
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.kind != FilterKind::None
+    }
+
+    pub(crate) fn per_block(&self) -> bool {
+        self.is_enabled() && self.per_block
+    }
+}
+
+/// Collects the hashes of the keys of a table or block and turns them into a
+/// filter.
+pub struct FilterBuilder {
+    policy: FilterPolicy,
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    /// Builder of the filters `policy` asks for.
+    pub fn new(policy: FilterPolicy) -> Self {
+        FilterBuilder {
+            policy,
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Adds `key` to the next filter built.
+    pub fn add(&mut self, key: &[u8]) {
+        self.hashes.push(hash(key));
+    }
+
+    /// Bytes held by the collected hashes.
+    pub(crate) fn memory(&self) -> usize {
+        self.hashes.capacity() * std::mem::size_of::<u64>()
+    }
+
+    /// Bytes the next [`finish`](Self::finish) allocates on top of the
+    /// collected hashes, the filter itself included.
+    pub(crate) fn build_memory(&self) -> usize {
+        let keys = self.hashes.len();
+        let bits = keys * self.policy.bits_per_key as usize;
+        match self.policy.kind {
+            FilterKind::None => 0,
+            FilterKind::Bloom => bits.div_ceil(8) + 64,
+            FilterKind::Ribbon => {
+                // Coefficients, results and solution per slot, then the words.
+                let slots = (keys as f64 * RIBBON_OVERHEAD) as usize + 2 * RIBBON_WIDTH;
+                slots * 16 + bits.div_ceil(8) + 64
+            }
+        }
+    }
+
+    /// Builds the filter of the keys added since the last call and forgets
+    /// them; `None` if there are no keys or filters are disabled.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.hashes.is_empty() {
+            return None;
+        }
+        let filter = match self.policy.kind {
+            FilterKind::None => None,
+            FilterKind::Bloom => Some(build_bloom(&self.hashes, self.policy.bits_per_key)),
+            FilterKind::Ribbon => Some(build_ribbon(&self.hashes, self.policy.bits_per_key)),
+        };
+        self.hashes.clear();
+        filter
+    }
+}
+
+/// Whether the keys `filter` was built from may include `key`. Filters of an
+/// unknown kind answer yes, so they only cost a read.
+pub fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    match filter.first() {
+        Some(&KIND_BLOOM) if filter.len() > 2 => bloom_may_contain(filter, hash(key)),
+        Some(&KIND_RIBBON) if filter.len() >= 6 => ribbon_may_contain(filter, hash(key)),
+        _ => true,
+    }
+}
+
+fn build_bloom(hashes: &[u64], bits_per_key: u32) -> Vec<u8> {
+    let k = ((f64::from(bits_per_key) * std::f64::consts::LN_2).round() as u8).clamp(1, 30);
+    let bits = (hashes.len() * bits_per_key as usize).max(64);
+    let bytes = bits.div_ceil(8);
+    let bits = bytes * 8;
+    let mut filter = vec![0u8; 2 + bytes];
+    filter[0] = KIND_BLOOM;
+    filter[1] = k;
+    for &h in hashes {
+        for bit in bloom_probes(h, k, bits) {
+            filter[2 + bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    filter
+}
+
+fn bloom_may_contain(filter: &[u8], h: u64) -> bool {
+    let bits = (filter.len() - 2) * 8;
+    bloom_probes(h, filter[1], bits).all(|bit| filter[2 + bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+/// Bit positions of a key, by double hashing.
+fn bloom_probes(h: u64, k: u8, bits: usize) -> impl Iterator<Item = usize> {
+    let delta = h.rotate_left(31) | 1;
+    (0..u64::from(k)).map(move |i| (h.wrapping_add(i.wrapping_mul(delta)) % bits as u64) as usize)
+}
+
+/// Where a key lands in a ribbon filter of `slots` slots: the first slot,
+/// the coefficients of the slots from there on, and the fingerprint.
+fn ribbon_row(h: u64, seed: u32, slots: usize, r: u32) -> (usize, u64, u32) {
+    let h = mix(h ^ u64::from(seed).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    let starts = (slots - RIBBON_WIDTH + 1) as u64;
+    let start = (((h >> 32) * starts) >> 32) as usize;
+    let coefficients = mix(h) | 1;
+    let fingerprint = (mix(h ^ 0x5851_f42d_4c95_7f2d) as u32) & fingerprint_mask(r);
+    (start, coefficients, fingerprint)
+}
+
+fn fingerprint_mask(r: u32) -> u32 {
+    if r >= 32 {
+        u32::MAX
+    } else {
+        (1 << r) - 1
+    }
+}
+
+fn build_ribbon(hashes: &[u64], bits_per_key: u32) -> Vec<u8> {
+    let r = ((f64::from(bits_per_key) / RIBBON_OVERHEAD).round() as u32).clamp(1, 32);
+    let mut slots = (hashes.len() as f64 * RIBBON_OVERHEAD).ceil() as usize;
+    loop {
+        // Whole blocks of 64 slots, at least one band wide.
+        slots = slots.div_ceil(RIBBON_WIDTH).max(1) * RIBBON_WIDTH + RIBBON_WIDTH;
+        for seed in 0..RIBBON_ATTEMPTS {
+            if let Some(solution) = solve_ribbon(hashes, seed, slots, r) {
+                return encode_ribbon(&solution, seed, r);
+            }
+        }
+        slots += slots / 4;
+    }
+}
+
+/// Finds, by Gaussian elimination on the banded system, a fingerprint per
+/// slot such that the slots selected by each key's coefficients XOR to the
+/// key's fingerprint.
+fn solve_ribbon(hashes: &[u64], seed: u32, slots: usize, r: u32) -> Option<Vec<u32>> {
+    let mut coefficients = vec![0u64; slots];
+    let mut results = vec![0u32; slots];
+    for &h in hashes {
+        let (mut start, mut c, mut result) = ribbon_row(h, seed, slots, r);
+        loop {
+            if c == 0 {
+                // The row is a combination of earlier ones; it only fits if
+                // the fingerprints agree, as they do for duplicate keys.
+                if result != 0 {
+                    return None;
+                }
+                break;
+            }
+            let shift = c.trailing_zeros() as usize;
+            start += shift;
+            c >>= shift;
+            if coefficients[start] == 0 {
+                coefficients[start] = c;
+                results[start] = result;
+                break;
+            }
+            c ^= coefficients[start];
+            result ^= results[start];
+        }
+    }
+    let mut solution = vec![0u32; slots];
+    for i in (0..slots).rev() {
+        let mut value = results[i];
+        let mut c = coefficients[i] >> 1;
+        while c != 0 {
+            let j = c.trailing_zeros() as usize + 1;
+            value ^= solution[i + j];
+            c &= c - 1;
+        }
+        solution[i] = value;
+    }
+    Some(solution)
+}
+
+/// Stores the solution column-wise: for every block of 64 slots, one word
+/// per fingerprint bit, so a query touches at most two blocks.
+fn encode_ribbon(solution: &[u32], seed: u32, r: u32) -> Vec<u8> {
+    let blocks = solution.len() / RIBBON_WIDTH;
+    let mut filter = Vec::with_capacity(6 + blocks * r as usize * 8);
+    filter.push(KIND_RIBBON);
+    filter.push(r as u8);
+    put_u32(&mut filter, seed);
+    for block in solution.chunks(RIBBON_WIDTH) {
+        for bit in 0..r {
+            let word = block
+                .iter()
+                .enumerate()
+                .fold(0u64, |word, (i, v)| word | (u64::from(v >> bit & 1) << i));
+            filter.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    filter
+}
+
+fn ribbon_may_contain(filter: &[u8], h: u64) -> bool {
+    let r = u32::from(filter[1]);
+    let seed = u32_at(filter, 2);
+    let words = &filter[6..];
+    let block_bytes = r as usize * 8;
+    if r == 0 || r > 32 || words.is_empty() || !words.len().is_multiple_of(block_bytes) {
+        return true;
+    }
+    let slots = words.len() / block_bytes * RIBBON_WIDTH;
+    let (start, c, fingerprint) = ribbon_row(h, seed, slots, r);
+    let (block, offset) = (start / RIBBON_WIDTH, start % RIBBON_WIDTH);
+    let word = |block: usize, bit: u32| u64_at(words, block * block_bytes + bit as usize * 8);
+    let mut value = 0u32;
+    for bit in 0..r {
+        let mut band = word(block, bit) >> offset;
+        if offset > 0 {
+            band |= word(block + 1, bit) << (RIBBON_WIDTH - offset);
+        }
+        value |= ((band & c).count_ones() & 1) << bit;
+    }
+    value == fingerprint
+}
+
+/// 64-bit hash of a key, stable across platforms and releases.
+fn hash(key: &[u8]) -> u64 {
+    const MULTIPLIER: u64 = 0x9fb2_1c65_1e98_df25;
+    let mut h = 0x2545_f491_4f6c_dd1d ^ key.len() as u64;
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        h = (h ^ word).wrapping_mul(MULTIPLIER).rotate_left(29);
+    }
+    let mut tail = [0u8; 8];
+    tail[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    h = (h ^ u64::from_le_bytes(tail)).wrapping_mul(MULTIPLIER);
+    mix(h)
+}
+
+/// Finalizer of MurmurHash3: every input bit affects every output bit.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
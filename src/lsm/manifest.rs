@@ -0,0 +1,97 @@
+//! Persistent description of which tables make up each level.
+//!
+//! The manifest is rewritten in full on every change: written to a temporary
+//! file, synced and renamed over the previous one, so a crash leaves either
+//! the old or the new version in place.
+
+use std::path::Path;
+
+use super::sstable::TableMeta;
+use crate::checksum::crc32c;
+use crate::coding::{put_bytes, put_u32, put_u64, put_varint, u32_at, Reader};
+use crate::error::{Error, Result};
+use crate::vfs::Vfs;
+use crate::wal::Lsn;
+
+const MAGIC: u64 = 0x3273_6566_696e_616d;
+const FILE_NAME: &str = "MANIFEST";
+const TEMP_NAME: &str = "MANIFEST.tmp";
+
+/// Tables of every level plus the next file number to allocate.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Manifest {
+    pub(crate) next_file: u64,
+    /// Log position up to which the writes are stored in the tables.
+    pub(crate) log_lsn: Lsn,
+    pub(crate) levels: Vec<Vec<TableMeta>>,
+}
+
+impl Manifest {
+    /// Reads the manifest from `dir`, or `None` if the database is new.
+    pub(crate) fn load(vfs: &dyn Vfs, dir: &Path) -> Result<Option<Manifest>> {
+        let data = match vfs.read(&dir.join(FILE_NAME)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() < 4 || u32_at(&data, data.len() - 4) != crc32c(&data[..data.len() - 4]) {
+            return Err(Error::corruption("manifest checksum mismatch"));
+        }
+        let mut reader = Reader::new(&data[..data.len() - 4]);
+        if reader.u64()? != MAGIC {
+            return Err(Error::corruption("not a manifest file"));
+        }
+        let next_file = reader.u64()?;
+        let log_lsn = reader.u64()?;
+        let level_count = reader.varint()? as usize;
+        let mut levels = Vec::with_capacity(level_count);
+        for _ in 0..level_count {
+            let count = reader.varint()? as usize;
+            let mut tables = Vec::with_capacity(count);
+            for _ in 0..count {
+                tables.push(TableMeta {
+                    id: reader.varint()?,
+                    size: reader.varint()?,
+                    entries: reader.varint()?,
+                    smallest: reader.bytes()?.to_vec(),
+                    largest: reader.bytes()?.to_vec(),
+                    created: reader.varint()?,
+                });
+            }
+            levels.push(tables);
+        }
+        Ok(Some(Manifest {
+            next_file,
+            log_lsn,
+            levels,
+        }))
+    }
+
+    /// Atomically replaces the manifest in `dir`.
+    pub(crate) fn store(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let mut buf = Vec::new();
+        put_u64(&mut buf, MAGIC);
+        put_u64(&mut buf, self.next_file);
+        put_u64(&mut buf, self.log_lsn);
+        put_varint(&mut buf, self.levels.len() as u64);
+        for level in &self.levels {
+            put_varint(&mut buf, level.len() as u64);
+            for table in level {
+                put_varint(&mut buf, table.id);
+                put_varint(&mut buf, table.size);
+                put_varint(&mut buf, table.entries);
+                put_bytes(&mut buf, &table.smallest);
+                put_bytes(&mut buf, &table.largest);
+                put_varint(&mut buf, table.created);
+            }
+        }
+        let crc = crc32c(&buf);
+        put_u32(&mut buf, crc);
+
+        let temp = dir.join(TEMP_NAME);
+        vfs.write(&temp, &buf)?;
+        vfs.rename(&temp, &dir.join(FILE_NAME))?;
+        vfs.sync_dir(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! In-memory write buffer of the LSM engine.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::{EntrySource, Value};
+use crate::engine::Mutation;
+use crate::error::Result;
+use crate::memory::{MemoryBudget, Reservation};
+use crate::merge::{self, Operators};
+use crate::range::KeyRange;
+
+/// Approximate per-entry cost of the map node and allocations.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Entries ready to be applied to a memtable, in key order.
+pub(crate) type Entries = Vec<(Vec<u8>, Value)>;
+
+/// Sorted map of the most recent writes, charged against the budget entry by
+/// entry.
+pub(crate) struct MemTable {
+    map: RwLock<BTreeMap<Vec<u8>, Value>>,
+    reservation: Mutex<Reservation>,
+    size: AtomicUsize,
+}
+
+impl MemTable {
+    pub(crate) fn new(budget: &MemoryBudget) -> Self {
+        MemTable {
+            map: RwLock::new(BTreeMap::new()),
+            reservation: Mutex::new(budget.reservation()),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes charged for the entries currently held.
+    pub(crate) fn size(&self) -> usize {
+        self.size.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.map.read().unwrap().is_empty()
+    }
+
+    /// Bytes a write of `key` and `value` is charged.
+    fn entry_charge(key: &[u8], value: &Value) -> usize {
+        key.len() + value.len() + ENTRY_OVERHEAD
+    }
+
+    /// Bytes applying `mutations` is charged, merges aside: a merge is
+    /// charged for its operand, but the entry it is folded into may be
+    /// larger.
+    pub(crate) fn charge(mutations: &[Mutation]) -> usize {
+        mutations.iter().map(|m| m.size() + ENTRY_OVERHEAD).sum()
+    }
+
+    /// Bytes applying `entries` is charged at most.
+    pub(crate) fn entries_charge(entries: &Entries) -> usize {
+        entries
+            .iter()
+            .map(|(key, value)| Self::entry_charge(key, value))
+            .sum()
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Value> {
+        self.map.read().unwrap().get(key).cloned()
+    }
+
+    /// Entries storing `mutations`, one per key: merges are stacked onto
+    /// the entry an earlier mutation or the memtable holds for their key.
+    ///
+    /// The entries stay valid until the memtable is written to by anyone
+    /// else, which the caller must rule out until it applies them.
+    pub(crate) fn prepare(&self, mutations: &[Mutation], operators: &Operators) -> Result<Entries> {
+        let map = self.map.read().unwrap();
+        let mut entries: BTreeMap<Vec<u8>, Value> = BTreeMap::new();
+        for mutation in mutations {
+            let value = match mutation {
+                Mutation::Put { value, .. } => Value::Put(value.clone()),
+                Mutation::Delete { .. } => Value::Delete,
+                Mutation::Merge {
+                    key,
+                    operator,
+                    operand,
+                } => {
+                    let merge = Value::Merge(vec![merge::tag(operator, operand)]);
+                    match entries.remove(key).or_else(|| map.get(key).cloned()) {
+                        Some(older) => merge.stack(key, older, operators)?,
+                        None => merge,
+                    }
+                }
+            };
+            entries.insert(mutation.key().to_vec(), value);
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    /// Applies `entries` from [`prepare`](Self::prepare) with memory
+    /// reserved beforehand in `reserved`, which must cover
+    /// [`entries_charge`](Self::entries_charge). Taking the memory up front
+    /// keeps a batch from being applied halfway.
+    pub(crate) fn apply(&self, entries: Entries, reserved: Reservation) {
+        let mut reservation = self.reservation.lock().unwrap();
+        reservation.merge(reserved);
+        let mut map = self.map.write().unwrap();
+        for (key, value) in entries {
+            if let Some(old) = map.get(&key) {
+                reservation.shrink(Self::entry_charge(&key, old));
+            }
+            map.insert(key, value);
+        }
+        self.size.store(reservation.size(), Ordering::Release);
+    }
+
+    /// Iterates over the entries in `range`, tombstones included.
+    ///
+    /// The iterator re-seeks for every entry instead of borrowing the map, so
+    /// it holds no lock between calls and sees writes made meanwhile.
+    pub(crate) fn iter(self: &Arc<Self>, range: KeyRange) -> MemTableIter {
+        MemTableIter {
+            table: Arc::clone(self),
+            range,
+            last: None,
+        }
+    }
+}
+
+pub(crate) struct MemTableIter {
+    table: Arc<MemTable>,
+    range: KeyRange,
+    last: Option<Vec<u8>>,
+}
+
+impl EntrySource for MemTableIter {
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        let map = self.table.map.read().unwrap();
+        let (start, end) = self.range.as_bounds();
+        let start = match &self.last {
+            Some(last) => Bound::Excluded(last.as_slice()),
+            None => start,
+        };
+        let next = map
+            .range::<[u8], _>((start, end))
+            .next()
+            .map(|(k, v)| (k.clone(), v.clone()));
+        if let Some((key, _)) = &next {
+            self.last = Some(key.clone());
+        }
+        Ok(next)
+    }
+}
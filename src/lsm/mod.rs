@@ -0,0 +1,725 @@
+//! Log-structured merge tree storage engine.
+//!
+//! Writes go to an in-memory memtable that is flushed to an immutable table
+//! file in level 0 as soon as it reaches [`Options::memtable_bytes`]. Level 0
+//! tables may overlap and are searched newest first; every deeper level is a
+//! sorted run of non-overlapping tables, so it costs at most one table per
+//! lookup. A table lookup reads a single block, which keeps the memory needed
+//! by a point lookup at one block per level, and a table's filter (see
+//! [`Options::filter`]) lets most lookups of absent keys skip the read. How
+//! tables move between levels depends on [`Options::compaction`].
+//!
+//! A merge (see [`crate::merge`]) is stored as an operand under
+//! its key and stacked onto the entry it finds for the key in the memtable.
+//! Reads and compactions fold the operands into the first full value or
+//! tombstone found below them, and compactions into the bottommost level
+//! fold them into no value.
+//!
+//! Every write is logged to the write-ahead log before it reaches the
+//! memtable. The manifest records the log position covered by the tables, so
+//! opening the tree replays the records past it and segments before it can be
+//! deleted.
+//!
+//! Flushes run on the writing thread, under the write lock. Compactions run
+//! on a background thread each tree starts, woken by every flush, so that
+//! writes only wait for the memtable to be written out, never for tables to
+//! be merged or for the rate limiter. Both install the tables they write by
+//! storing a new manifest under a lock of their own, on top of whatever the
+//! other installed meanwhile.
+
+mod compaction;
+mod filter;
+mod manifest;
+mod memtable;
+mod rate_limiter;
+mod sstable;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+
+pub use self::compaction::CompactionStyle;
+pub use self::filter::{may_contain, FilterBuilder, FilterKind, FilterPolicy};
+
+use self::manifest::Manifest;
+use self::memtable::{Entries, MemTable};
+use self::rate_limiter::RateLimiter;
+use self::sstable::{Table, TableBuilder, TableCache, TableIter};
+use crate::engine::Mutation;
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reclaim, Reservation};
+use crate::merge::Operators;
+use crate::options::Options;
+use crate::range::KeyRange;
+use crate::vfs::Vfs;
+use crate::wal::{Durability, LogRecord, Lsn, Wal};
+
+/// Number of levels, level 0 included.
+const NUM_LEVELS: usize = 7;
+
+/// What the LSM tree stores for a key: a value, a deletion marker, or merge
+/// operands still to apply to whatever is stored below them, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Put(Vec<u8>),
+    Delete,
+    Merge(Vec<Vec<u8>>),
+}
+
+impl Value {
+    fn len(&self) -> usize {
+        match self {
+            Value::Put(v) => v.len(),
+            Value::Delete => 0,
+            Value::Merge(operands) => operands.iter().map(Vec::len).sum(),
+        }
+    }
+
+    /// Combines the value with `older`, the value of the same key it
+    /// supersedes. Only merges need `operators`: they fold into a full value
+    /// or tombstone, and join the operands of an older merge.
+    fn stack(self, key: &[u8], older: Value, operators: &Operators) -> Result<Value> {
+        let Value::Merge(operands) = self else {
+            return Ok(self);
+        };
+        let base = match older {
+            Value::Put(v) => Some(v),
+            Value::Delete => None,
+            Value::Merge(mut stacked) => {
+                for operand in operands {
+                    let combined = stacked
+                        .last()
+                        .and_then(|last| operators.partial_merge(key, last, &operand));
+                    match combined {
+                        Some(combined) => *stacked.last_mut().unwrap() = combined,
+                        None => stacked.push(operand),
+                    }
+                }
+                return Ok(Value::Merge(stacked));
+            }
+        };
+        let operands: Vec<&[u8]> = operands.iter().map(Vec::as_slice).collect();
+        let value = operators.full_merge(key, base, &operands)?;
+        Ok(Value::Put(value))
+    }
+
+    /// The value a read of `key` returns, once nothing older is left to
+    /// stack below it.
+    fn resolve(self, key: &[u8], operators: &Operators) -> Result<Option<Vec<u8>>> {
+        match self.stack(key, Value::Delete, operators)? {
+            Value::Put(v) => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Sorted stream of entries, tombstones included.
+pub(crate) trait EntrySource: Send {
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>>;
+}
+
+/// The set of tables making up the tree at one point in time.
+#[derive(Default)]
+struct Version {
+    /// `levels[0]` is ordered newest first; deeper levels by smallest key.
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    fn empty() -> Self {
+        Version {
+            levels: vec![Vec::new(); NUM_LEVELS],
+        }
+    }
+
+    fn manifest(&self, next_file: u64, log_lsn: Lsn) -> Manifest {
+        Manifest {
+            next_file,
+            log_lsn,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|t| t.meta().clone()).collect())
+                .collect(),
+        }
+    }
+
+    /// Value of `key`, stacking what the tables hold under `found`, the
+    /// value from the memtable, until a full value or tombstone is reached.
+    fn get(
+        &self,
+        key: &[u8],
+        mut found: Option<Value>,
+        operators: &Operators,
+    ) -> Result<Option<Value>> {
+        let deeper = self.levels[1..].iter().filter_map(|level| {
+            let idx = level.partition_point(|t| t.meta().largest.as_slice() < key);
+            level.get(idx)
+        });
+        for table in self.levels[0].iter().chain(deeper) {
+            if matches!(found, Some(Value::Put(_) | Value::Delete)) {
+                break;
+            }
+            if let Some(older) = table.get(key)? {
+                found = Some(match found {
+                    Some(newer) => newer.stack(key, older, operators)?,
+                    None => older,
+                });
+            }
+        }
+        Ok(found)
+    }
+
+    /// One source per level 0 table, newest first, then one per deeper level.
+    fn sources(&self, range: &KeyRange) -> Vec<Box<dyn EntrySource>> {
+        let mut sources: Vec<Box<dyn EntrySource>> = Vec::new();
+        for table in &self.levels[0] {
+            if table.meta().overlaps_range(range) {
+                sources.push(Box::new(table.iter(range.clone())));
+            }
+        }
+        for level in &self.levels[1..] {
+            let tables: Vec<_> = level
+                .iter()
+                .filter(|t| t.meta().overlaps_range(range))
+                .cloned()
+                .collect();
+            if !tables.is_empty() {
+                sources.push(Box::new(LevelIter::new(tables, range.clone())));
+            }
+        }
+        sources
+    }
+}
+
+/// Concatenation of the non-overlapping tables of a level, opening one table
+/// at a time.
+struct LevelIter {
+    tables: std::vec::IntoIter<Arc<Table>>,
+    current: Option<TableIter>,
+    range: KeyRange,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl LevelIter {
+    fn new(tables: Vec<Arc<Table>>, range: KeyRange) -> Self {
+        LevelIter {
+            tables: tables.into_iter(),
+            current: None,
+            range,
+            rate_limiter: None,
+        }
+    }
+
+    /// Paces the block reads of the iterator with `rate_limiter`.
+    fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+}
+
+impl EntrySource for LevelIter {
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        loop {
+            if let Some(current) = &mut self.current {
+                if let Some(entry) = current.next_entry()? {
+                    return Ok(Some(entry));
+                }
+                self.current = None;
+            }
+            match self.tables.next() {
+                Some(table) => {
+                    let iter = table.iter(self.range.clone());
+                    self.current = Some(iter.with_rate_limiter(self.rate_limiter.clone()));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Merges sorted sources; for equal keys the earliest source wins, with the
+/// entries of later sources stacked below it.
+pub(crate) struct MergeIter {
+    sources: Vec<Box<dyn EntrySource>>,
+    heads: Vec<Option<(Vec<u8>, Value)>>,
+    started: bool,
+    operators: Arc<Operators>,
+}
+
+impl MergeIter {
+    pub(crate) fn new(sources: Vec<Box<dyn EntrySource>>, operators: Arc<Operators>) -> Self {
+        MergeIter {
+            heads: Vec::with_capacity(sources.len()),
+            sources,
+            started: false,
+            operators,
+        }
+    }
+}
+
+impl EntrySource for MergeIter {
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        if !self.started {
+            self.started = true;
+            for source in &mut self.sources {
+                self.heads.push(source.next_entry()?);
+            }
+        }
+        let mut winner: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                match winner {
+                    Some(w) if self.heads[w].as_ref().unwrap().0 <= *key => {}
+                    _ => winner = Some(i),
+                }
+            }
+        }
+        let Some(winner) = winner else {
+            return Ok(None);
+        };
+        let (key, mut value) = self.heads[winner].take().unwrap();
+        self.heads[winner] = self.sources[winner].next_entry()?;
+        for i in winner + 1..self.heads.len() {
+            if matches!(&self.heads[i], Some((k, _)) if *k == key) {
+                let (_, older) = self.heads[i].take().unwrap();
+                value = value.stack(&key, older, &self.operators)?;
+                self.heads[i] = self.sources[i].next_entry()?;
+            }
+        }
+        Ok(Some((key, value)))
+    }
+}
+
+/// Iterator over the live key-value pairs of a range of an [`LsmEngine`].
+pub struct LsmScan {
+    merge: MergeIter,
+    operators: Arc<Operators>,
+}
+
+impl Iterator for LsmScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.merge.next_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            match value.resolve(&key, &self.operators) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+struct State {
+    memtable: Arc<MemTable>,
+    version: Arc<Version>,
+}
+
+/// State only touched by the thread holding the write lock.
+struct Writer {
+    /// Log position of the last write applied to the memtable.
+    applied_lsn: Lsn,
+}
+
+/// State only touched by the thread running compactions.
+struct Compaction {
+    /// Per level, the largest key of the last table compacted out of it.
+    compact_pointer: Vec<Vec<u8>>,
+}
+
+/// Requests to the compaction thread, and what it reports back.
+#[derive(Default)]
+struct Signal {
+    /// Whether the tree changed since the thread last compacted it.
+    pending: bool,
+    stopped: bool,
+    /// Error of the last background compaction, until a caller is told.
+    error: Option<Error>,
+}
+
+/// Storage engine based on a log-structured merge tree.
+pub struct LsmEngine {
+    inner: Arc<Inner>,
+    compactor: Option<JoinHandle<()>>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: Options,
+    operators: Arc<Operators>,
+    budget: MemoryBudget,
+    state: RwLock<State>,
+    writer: Mutex<Writer>,
+    compaction: Mutex<Compaction>,
+    /// Held while a new version is stored in the manifest and installed;
+    /// guards the log position covered by the tables in the manifest.
+    manifest: Mutex<Lsn>,
+    next_file: AtomicU64,
+    signal: Mutex<Signal>,
+    signalled: Condvar,
+    cache: Arc<TableCache>,
+    /// Paces compaction I/O, if [`Options::compaction_rate_limit`] is set.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    wal: Wal,
+}
+
+impl LsmEngine {
+    /// Opens the tree stored in directory `dir`, creating it if needed, and
+    /// starts its compaction thread.
+    pub fn open(dir: impl AsRef<Path>, options: &Options, budget: &MemoryBudget) -> Result<Self> {
+        options.validate()?;
+        let dir = dir.as_ref().to_path_buf();
+        let vfs = &*options.vfs;
+        vfs.create_dir_all(&dir)?;
+        let manifest = Manifest::load(vfs, &dir)?.unwrap_or_default();
+
+        let cache = Arc::new(TableCache::default());
+        let weak: Weak<dyn Reclaim> = Arc::downgrade(&cache) as Weak<dyn Reclaim>;
+        budget.register_reclaimer(weak);
+
+        let mut version = Version::empty();
+        for (level, tables) in manifest.levels.into_iter().enumerate() {
+            if level >= NUM_LEVELS {
+                return Err(Error::corruption("manifest has too many levels"));
+            }
+            for meta in tables {
+                let table = Arc::new(Table::open(vfs, &dir, meta, budget)?);
+                cache.insert(&table);
+                version.levels[level].push(table);
+            }
+        }
+        remove_orphans(vfs, &dir, &version)?;
+        let wal = Wal::open(dir.join("wal"), options, budget, manifest.log_lsn)?;
+
+        let inner = Arc::new(Inner {
+            state: RwLock::new(State {
+                memtable: Arc::new(MemTable::new(budget)),
+                version: Arc::new(version),
+            }),
+            writer: Mutex::new(Writer {
+                applied_lsn: manifest.log_lsn,
+            }),
+            compaction: Mutex::new(Compaction {
+                compact_pointer: vec![Vec::new(); NUM_LEVELS],
+            }),
+            manifest: Mutex::new(manifest.log_lsn),
+            next_file: AtomicU64::new(manifest.next_file.max(1)),
+            signal: Mutex::new(Signal {
+                // The tree may have been left over its limits.
+                pending: true,
+                ..Signal::default()
+            }),
+            signalled: Condvar::new(),
+            dir,
+            options: options.clone(),
+            operators: Arc::new(Operators::new(&options.merge_operators)),
+            budget: budget.clone(),
+            cache,
+            rate_limiter: options
+                .compaction_rate_limit
+                .map(|rate| Arc::new(RateLimiter::new(rate))),
+            wal,
+        });
+        inner.replay()?;
+        let compactor = {
+            let inner = Arc::clone(&inner);
+            thread::spawn(move || inner.run_compactions())
+        };
+        Ok(LsmEngine {
+            inner,
+            compactor: Some(compactor),
+        })
+    }
+
+    /// Returns the value stored under `key`.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    /// Stores `value` under `key` with the durability of
+    /// [`Options::durability`].
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.inner.put(key, value)
+    }
+
+    /// Removes `key` with the durability of [`Options::durability`].
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    /// Folds `operand` into the value of `key` with the merge operator named
+    /// `operator`, with the durability of [`Options::durability`].
+    pub fn merge(&self, key: &[u8], operator: &str, operand: &[u8]) -> Result<()> {
+        self.inner.merge(key, operator, operand)
+    }
+
+    /// Applies `mutations` atomically, returning once they are as durable as
+    /// `durability` requires.
+    pub fn write(&self, mutations: &[Mutation], durability: Durability) -> Result<()> {
+        self.inner.write(mutations, durability)
+    }
+
+    /// Iterates in key order over the pairs in `range`.
+    ///
+    /// The iterator sees a fixed set of table files but reads the memtable
+    /// live, so writes made while it is running may or may not be observed.
+    pub fn scan(&self, range: impl Into<KeyRange>) -> LsmScan {
+        self.inner.scan(range.into())
+    }
+
+    /// Writes the memtable to a level 0 table, whatever its size, and wakes
+    /// the compaction thread. Returns the error of a failed background
+    /// compaction, if one has not been returned yet.
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.inner.flush_locked(&mut writer)?;
+        drop(writer);
+        self.inner.take_error()
+    }
+
+    /// Runs compactions on the calling thread until the tree is within the
+    /// limits of its strategy, waiting for one the compaction thread is
+    /// running. Writes go on meanwhile.
+    pub fn compact(&self) -> Result<()> {
+        self.inner.take_error()?;
+        self.inner.compact()
+    }
+
+    /// Number of tables in each level, level 0 first.
+    pub fn level_sizes(&self) -> Vec<usize> {
+        let (_, version) = self.inner.snapshot();
+        version.levels.iter().map(Vec::len).collect()
+    }
+}
+
+impl Drop for LsmEngine {
+    /// Stops the compaction thread, waiting for a running compaction to
+    /// finish.
+    fn drop(&mut self) {
+        self.inner.signal.lock().unwrap().stopped = true;
+        self.inner.signalled.notify_all();
+        if let Some(Err(panic)) = self.compactor.take().map(JoinHandle::join) {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+impl Inner {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (memtable, version) = self.snapshot();
+        match version.get(key, memtable.get(key), &self.operators)? {
+            Some(value) => value.resolve(key, &self.operators),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mutation = Mutation::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        self.write(&[mutation], self.options.durability)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mutation = Mutation::Delete { key: key.to_vec() };
+        self.write(&[mutation], self.options.durability)
+    }
+
+    fn merge(&self, key: &[u8], operator: &str, operand: &[u8]) -> Result<()> {
+        let mutation = Mutation::Merge {
+            key: key.to_vec(),
+            operator: operator.to_string(),
+            operand: operand.to_vec(),
+        };
+        self.write(&[mutation], self.options.durability)
+    }
+
+    fn write(&self, mutations: &[Mutation], durability: Durability) -> Result<()> {
+        if mutations.is_empty() {
+            return Ok(());
+        }
+        for mutation in mutations {
+            if let Mutation::Merge { operator, .. } = mutation {
+                self.operators.check(operator)?;
+            }
+        }
+        let record = LogRecord::encode_batch(mutations);
+        let lsn = {
+            let mut writer = self.writer.lock().unwrap();
+            let (entries, reserved) = self.prepare(&mut writer, mutations)?;
+            let lsn = self.wal.append(&record)?;
+            self.snapshot().0.apply(entries, reserved);
+            writer.applied_lsn = lsn;
+            lsn
+        };
+        // Other writers may log and apply their own batches while this one
+        // waits, so that they share the sync.
+        self.wal.commit(lsn, durability)
+    }
+
+    fn scan(&self, range: KeyRange) -> LsmScan {
+        let (memtable, version) = self.snapshot();
+        let mut sources: Vec<Box<dyn EntrySource>> = vec![Box::new(memtable.iter(range.clone()))];
+        sources.extend(version.sources(&range));
+        LsmScan {
+            merge: MergeIter::new(sources, Arc::clone(&self.operators)),
+            operators: Arc::clone(&self.operators),
+        }
+    }
+
+    fn snapshot(&self) -> (Arc<MemTable>, Arc<Version>) {
+        let state = self.state.read().unwrap();
+        (Arc::clone(&state.memtable), Arc::clone(&state.version))
+    }
+
+    /// Turns `mutations` into the entries they store in the memtable, with
+    /// the memory those need reserved, flushing the memtable first if they
+    /// would not fit.
+    fn prepare(
+        &self,
+        writer: &mut Writer,
+        mutations: &[Mutation],
+    ) -> Result<(Entries, Reservation)> {
+        let mut reserved = self.make_room(writer, MemTable::charge(mutations))?;
+        // Merges are stacked onto the memtable only once it can no longer
+        // be flushed under them.
+        let entries = self.snapshot().0.prepare(mutations, &self.operators)?;
+        let charge = MemTable::entries_charge(&entries);
+        if charge > reserved.size() {
+            reserved.grow(charge - reserved.size())?;
+        } else {
+            reserved.shrink(reserved.size() - charge);
+        }
+        Ok((entries, reserved))
+    }
+
+    /// Reserves `charge` bytes for the memtable, flushing it first if they
+    /// would not fit.
+    fn make_room(&self, writer: &mut Writer, charge: usize) -> Result<Reservation> {
+        let memtable = self.snapshot().0;
+        if !memtable.is_empty() && memtable.size() + charge > self.options.memtable_bytes() {
+            self.flush_locked(writer)?;
+        }
+        match self.budget.reserve(charge) {
+            Ok(reservation) => Ok(reservation),
+            Err(_) if !self.snapshot().0.is_empty() => {
+                // Turn the memtable into a table to free its memory, then retry.
+                self.flush_locked(writer)?;
+                Ok(self.budget.reserve(charge)?)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Applies the logged writes the tables do not cover yet.
+    fn replay(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for record in self.wal.replay(writer.applied_lsn)? {
+            let (lsn, payload) = record?;
+            let LogRecord::Batch(mutations) = LogRecord::decode(&payload)? else {
+                return Err(Error::corruption("page record in an LSM log"));
+            };
+            let (entries, reserved) = self.prepare(&mut writer, &mutations)?;
+            self.snapshot().0.apply(entries, reserved);
+            writer.applied_lsn = lsn;
+        }
+        Ok(())
+    }
+
+    /// Writes the memtable to a level 0 table and wakes the compaction
+    /// thread.
+    fn flush_locked(&self, writer: &mut Writer) -> Result<()> {
+        let memtable = self.snapshot().0;
+        if memtable.is_empty() {
+            return Ok(());
+        }
+        let id = self.next_file_id();
+        let vfs = &*self.options.vfs;
+        let mut builder = TableBuilder::create(vfs, &self.dir, id, &self.options, &self.budget)?;
+        let mut entries = memtable.iter(KeyRange::all());
+        while let Some((key, value)) = entries.next_entry()? {
+            builder.add(&key, &value)?;
+        }
+        let table = Arc::new(Table::open(
+            vfs,
+            &self.dir,
+            builder.finish()?,
+            &self.budget,
+        )?);
+        self.cache.insert(&table);
+
+        {
+            let mut log_lsn = self.manifest.lock().unwrap();
+            let mut levels = self.snapshot().1.levels.clone();
+            levels[0].insert(0, table);
+            let version = Version { levels };
+            version
+                .manifest(self.next_file.load(Ordering::SeqCst), writer.applied_lsn)
+                .store(vfs, &self.dir)?;
+            *log_lsn = writer.applied_lsn;
+            let mut state = self.state.write().unwrap();
+            state.memtable = Arc::new(MemTable::new(&self.budget));
+            state.version = Arc::new(version);
+        }
+        self.wal.truncate_before(writer.applied_lsn)?;
+        self.signal.lock().unwrap().pending = true;
+        self.signalled.notify_all();
+        Ok(())
+    }
+
+    fn next_file_id(&self) -> u64 {
+        self.next_file.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Body of the compaction thread: compacts the tree whenever a flush
+    /// changed it, until the tree is dropped.
+    fn run_compactions(&self) {
+        loop {
+            {
+                let mut signal = self.signal.lock().unwrap();
+                while !signal.pending && !signal.stopped {
+                    signal = self.signalled.wait(signal).unwrap();
+                }
+                if signal.stopped {
+                    return;
+                }
+                signal.pending = false;
+            }
+            if let Err(e) = self.compact() {
+                self.signal.lock().unwrap().error = Some(e);
+            }
+        }
+    }
+
+    /// Takes the error of the last failed background compaction.
+    fn take_error(&self) -> Result<()> {
+        match self.signal.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Removes table files and temporary files the manifest does not reference.
+fn remove_orphans(vfs: &dyn Vfs, dir: &Path, version: &Version) -> Result<()> {
+    let live: std::collections::HashSet<u64> =
+        version.levels.iter().flatten().map(|t| t.id()).collect();
+    for name in vfs.list(dir)? {
+        let orphan = match name.strip_suffix(".sst") {
+            Some(stem) => stem.parse::<u64>().is_ok_and(|id| !live.contains(&id)),
+            None => name.ends_with(".tmp"),
+        };
+        if orphan {
+            vfs.remove(&dir.join(name))?;
+        }
+    }
+    Ok(())
+}
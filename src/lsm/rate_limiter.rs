@@ -0,0 +1,58 @@
+//! Token bucket limiting the bandwidth of compaction I/O.
+//!
+//! The bucket fills at the configured rate up to a tenth of a second's worth
+//! of tokens. Every read or write takes as many tokens as it moves bytes; one
+//! that finds too few takes them anyway, leaving the bucket in debt, and
+//! sleeps until the debt is paid back. Requests larger than the bucket are
+//! therefore served too, only spread out over time.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Fraction of a second of I/O the bucket can hold.
+const BURST_SECONDS: f64 = 0.1;
+
+/// Shared limit on the bytes per second compactions read and write.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_second: f64,
+    burst: f64,
+    state: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second as f64;
+        let burst = bytes_per_second * BURST_SECONDS;
+        RateLimiter {
+            bytes_per_second,
+            burst,
+            state: Mutex::new(Bucket {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` tokens, sleeping as long as it takes to earn them.
+    pub(crate) fn request(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.state.lock().unwrap();
+            let now = Instant::now();
+            let earned = now.duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_second;
+            bucket.tokens = (bucket.tokens + earned).min(self.burst) - bytes as f64;
+            bucket.refilled = now;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second)
+        };
+        std::thread::sleep(wait);
+    }
+}
@@ -0,0 +1,863 @@
+//! Immutable sorted-string-table files.
+//!
+//! A table is a sequence of data blocks, each optionally followed by its
+//! filter, then the filter of the whole table, the compression dictionary, an
+//! index block and a fixed footer:
+//!
+//! ```text
+//! (data block [block filter])*  [table filter]  [dictionary]  index block  footer
+//! ```
+//!
+//! A data block holds entries `kind:u8 key:bytes [value:bytes]`, where merge
+//! entries hold `count:varint operand:bytes*` as their value. Every block
+//! is followed by the id of the codec that compressed it, if any (see
+//! [`Options::compression`]), and the CRC-32C of its stored contents and
+//! codec id. Data blocks of a table with a dictionary are compressed with it;
+//! the dictionary is trained on the first data blocks of the table, which are
+//! held back until then. The index holds, for every data block, the last key
+//! of the block, the block's offset and length, and the offset and length of
+//! its filter (zero when it has none). The footer records where the index,
+//! the table filter and the dictionary live and ends with a magic number and
+//! its own checksum.
+//!
+//! Decompressed blocks are charged against the budget like the blocks read
+//! from disk, and the compressed copy stays charged until it is dropped.
+//!
+//! Lookups consult the table filter, then the index, and then read exactly
+//! one data block, so the memory needed to search a table is one block plus
+//! its index and filter. Indexes and filters are loaded on demand and dropped
+//! again when the budget runs short, filters first. A table filter that does
+//! not fit in the budget is not loaded; the lookup then checks the filter of
+//! the block it is about to read instead, when the table has block filters.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::compaction;
+use super::filter::{may_contain, FilterBuilder, FilterPolicy};
+use super::rate_limiter::RateLimiter;
+use super::{EntrySource, Value};
+use crate::checksum::crc32c;
+use crate::coding::{put_bytes, put_u32, put_u64, put_varint, u32_at, u64_at, Reader};
+use crate::compression::{self, Compression};
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reclaim, Reservation};
+use crate::options::Options;
+use crate::range::KeyRange;
+use crate::vfs::{Vfs, VfsFile};
+
+const MAGIC: u64 = 0x6267_7473_6567_6966;
+const FOOTER_SIZE: usize = 56;
+/// Codec id and checksum following the contents of every block.
+const BLOCK_TRAILER_SIZE: usize = 5;
+/// A block is stored compressed only if that saves at least this fraction
+/// (one over) of its size.
+const MIN_COMPRESSION_SAVING: usize = 8;
+
+const KIND_PUT: u8 = 0;
+const KIND_DELETE: u8 = 1;
+const KIND_MERGE: u8 = 2;
+
+/// Location of a block within a table file.
+#[derive(Debug, Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    len: u32,
+}
+
+impl BlockHandle {
+    /// Handle of a block that was not written.
+    const NONE: BlockHandle = BlockHandle { offset: 0, len: 0 };
+
+    fn is_none(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Description of a table recorded in the manifest.
+#[derive(Debug, Clone)]
+pub(crate) struct TableMeta {
+    pub(crate) id: u64,
+    pub(crate) size: u64,
+    pub(crate) entries: u64,
+    pub(crate) smallest: Vec<u8>,
+    pub(crate) largest: Vec<u8>,
+    /// When the newest entry of the table was written, in seconds since the
+    /// Unix epoch.
+    pub(crate) created: u64,
+}
+
+impl TableMeta {
+    pub(crate) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.smallest.as_slice() <= largest && self.largest.as_slice() >= smallest
+    }
+
+    pub(crate) fn overlaps_range(&self, range: &KeyRange) -> bool {
+        !range.is_after(&self.smallest) && !range.is_before(&self.largest)
+    }
+}
+
+/// Path of the table file with id `id` inside `dir`.
+pub(crate) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:06}.sst"))
+}
+
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], value: &Value) {
+    match value {
+        Value::Put(v) => {
+            buf.push(KIND_PUT);
+            put_bytes(buf, key);
+            put_bytes(buf, v);
+        }
+        Value::Delete => {
+            buf.push(KIND_DELETE);
+            put_bytes(buf, key);
+        }
+        Value::Merge(operands) => {
+            buf.push(KIND_MERGE);
+            put_bytes(buf, key);
+            put_varint(buf, operands.len() as u64);
+            for operand in operands {
+                put_bytes(buf, operand);
+            }
+        }
+    }
+}
+
+fn decode_entry<'a>(reader: &mut Reader<'a>) -> Result<(&'a [u8], Value)> {
+    let kind = reader.u8()?;
+    let key = reader.bytes()?;
+    let value = match kind {
+        KIND_PUT => Value::Put(reader.bytes()?.to_vec()),
+        KIND_DELETE => Value::Delete,
+        KIND_MERGE => {
+            let count = reader.varint()? as usize;
+            let mut operands = Vec::with_capacity(count.min(64));
+            for _ in 0..count {
+                operands.push(reader.bytes()?.to_vec());
+            }
+            Value::Merge(operands)
+        }
+        _ => return Err(Error::corruption(format!("unknown entry kind {kind}"))),
+    };
+    Ok((key, value))
+}
+
+/// Bytes of data blocks a table buffers, per byte of dictionary, to train its
+/// dictionary on.
+const DICTIONARY_SAMPLE_RATIO: usize = 32;
+
+/// A finished data block that has not been written yet.
+struct PendingBlock {
+    data: Vec<u8>,
+    last_key: Vec<u8>,
+    filter: Option<Vec<u8>>,
+}
+
+/// The first data blocks of a table, kept until there are enough of them to
+/// train the table's dictionary on.
+struct Samples {
+    blocks: Vec<PendingBlock>,
+    bytes: usize,
+    target: usize,
+    reservation: Reservation,
+}
+
+impl Samples {
+    /// Keeps `block`, or hands it back if the budget cannot hold it.
+    fn push(&mut self, block: PendingBlock) -> std::result::Result<(), PendingBlock> {
+        let size = block.data.capacity()
+            + block.last_key.len()
+            + block.filter.as_ref().map_or(0, Vec::len);
+        if self.reservation.try_grow(size).is_err() {
+            return Err(block);
+        }
+        self.bytes += block.data.len();
+        self.blocks.push(block);
+        Ok(())
+    }
+}
+
+/// Streams sorted entries into a new table file.
+pub(crate) struct TableBuilder {
+    id: u64,
+    file: Box<dyn VfsFile>,
+    block_size: usize,
+    offset: u64,
+    block: Vec<u8>,
+    index: Vec<u8>,
+    policy: FilterPolicy,
+    filter: FilterBuilder,
+    block_filter: FilterBuilder,
+    compression: Compression,
+    /// Dictionary the data blocks are compressed with; empty for none.
+    dictionary: Vec<u8>,
+    dictionary_size: usize,
+    samples: Option<Samples>,
+    reservation: Reservation,
+    entries: u64,
+    smallest: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl TableBuilder {
+    /// Creates table `id` in `dir` with the block size, filters and
+    /// compression of `options`. The block buffer, index, filter keys and
+    /// compression buffers are charged against `budget` as they grow.
+    pub(crate) fn create(
+        vfs: &dyn Vfs,
+        dir: &Path,
+        id: u64,
+        options: &Options,
+        budget: &MemoryBudget,
+    ) -> Result<Self> {
+        let block_size = options.page_size;
+        let compression = options.compression;
+        let dictionary_size = match compression {
+            Compression::None => 0,
+            _ => options.compression_dictionary_size,
+        };
+        let working_memory = compression.working_memory(block_size, dictionary_size);
+        let reservation = budget.reserve(2 * block_size + working_memory)?;
+        let file = vfs.open(&table_path(dir, id), true)?;
+        file.set_size(0)?;
+        let policy = FilterPolicy::new(options);
+        let samples = (dictionary_size > 0).then(|| Samples {
+            blocks: Vec::new(),
+            bytes: 0,
+            target: dictionary_size * DICTIONARY_SAMPLE_RATIO,
+            reservation: budget.reservation(),
+        });
+        Ok(TableBuilder {
+            id,
+            file,
+            block_size,
+            offset: 0,
+            block: Vec::with_capacity(block_size),
+            index: Vec::new(),
+            policy,
+            filter: FilterBuilder::new(policy),
+            block_filter: FilterBuilder::new(policy),
+            compression,
+            dictionary: Vec::new(),
+            dictionary_size,
+            samples,
+            reservation,
+            entries: 0,
+            smallest: None,
+            last_key: Vec::new(),
+            rate_limiter: None,
+        })
+    }
+
+    /// Paces the writes of the table with `rate_limiter`.
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Appends an entry; keys must be added in strictly increasing order.
+    pub(crate) fn add(&mut self, key: &[u8], value: &Value) -> Result<()> {
+        debug_assert!(self.smallest.is_none() || key > self.last_key.as_slice());
+        let before = self.memory();
+        encode_entry(&mut self.block, key, value);
+        if self.policy.is_enabled() {
+            self.filter.add(key);
+        }
+        if self.policy.per_block() {
+            self.block_filter.add(key);
+        }
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_vec());
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.entries += 1;
+        self.charge(before)?;
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, including the blocks not written yet.
+    pub(crate) fn estimated_size(&self) -> u64 {
+        let pending = self.samples.as_ref().map_or(0, |s| s.bytes);
+        self.offset + (pending + self.block.len()) as u64
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Writes the table filter, dictionary, index and footer, syncs the file
+    /// and describes the table.
+    pub(crate) fn finish(mut self) -> Result<TableMeta> {
+        self.finish_block()?;
+        self.train()?;
+        let filter = finish_filter(&mut self.filter, self.reservation.budget())?;
+        let filter_handle = match filter {
+            Some(filter) => self.write_block(&filter, false)?,
+            None => BlockHandle::NONE,
+        };
+        let dictionary_handle = match std::mem::take(&mut self.dictionary) {
+            dictionary if dictionary.is_empty() => BlockHandle::NONE,
+            dictionary => self.write_block(&dictionary, false)?,
+        };
+        let index = std::mem::take(&mut self.index);
+        let index_handle = self.write_block(&index, true)?;
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        for handle in [index_handle, filter_handle, dictionary_handle] {
+            put_u64(&mut footer, handle.offset);
+            put_u32(&mut footer, handle.len);
+        }
+        put_u64(&mut footer, self.entries);
+        put_u64(&mut footer, MAGIC);
+        let crc = crc32c(&footer);
+        put_u32(&mut footer, crc);
+        self.file.write_at(&footer, self.offset)?;
+        self.offset += footer.len() as u64;
+        self.file.sync()?;
+        Ok(TableMeta {
+            id: self.id,
+            size: self.offset,
+            entries: self.entries,
+            smallest: self.smallest.unwrap_or_default(),
+            largest: self.last_key,
+            created: compaction::now(),
+        })
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let filter = finish_filter(&mut self.block_filter, self.reservation.budget())?;
+        let data = std::mem::replace(&mut self.block, Vec::with_capacity(self.block_size));
+        let block = PendingBlock {
+            data,
+            last_key: self.last_key.clone(),
+            filter,
+        };
+        if let Some(samples) = &mut self.samples {
+            match samples.push(block) {
+                Ok(()) if samples.bytes < samples.target => return Ok(()),
+                Ok(()) => return self.train(),
+                Err(block) => {
+                    // Out of memory for samples: make do with those we have.
+                    self.train()?;
+                    return self.write_data_block(block);
+                }
+            }
+        }
+        self.write_data_block(block)
+    }
+
+    /// Trains the dictionary on the buffered blocks and writes them.
+    fn train(&mut self) -> Result<()> {
+        let Some(samples) = self.samples.take() else {
+            return Ok(());
+        };
+        let before = self.memory();
+        let budget = self.reservation.budget();
+        let memory = compression::TRAINING_MEMORY + self.dictionary_size;
+        // A table without a dictionary is only larger, so training gives way
+        // to everything else when memory is short.
+        if let Ok(_scratch) = budget.try_reserve(memory) {
+            let data: Vec<&[u8]> = samples.blocks.iter().map(|b| b.data.as_slice()).collect();
+            self.dictionary = self.compression.train(&data, self.dictionary_size);
+        }
+        self.charge(before)?;
+        for block in samples.blocks {
+            self.write_data_block(block)?;
+        }
+        Ok(())
+    }
+
+    fn write_data_block(&mut self, block: PendingBlock) -> Result<()> {
+        let before = self.memory();
+        let handle = self.write_block(&block.data, true)?;
+        let filter_handle = match block.filter {
+            Some(filter) => self.write_block(&filter, false)?,
+            None => BlockHandle::NONE,
+        };
+        put_bytes(&mut self.index, &block.last_key);
+        put_varint(&mut self.index, handle.offset);
+        put_varint(&mut self.index, u64::from(handle.len));
+        put_varint(&mut self.index, filter_handle.offset);
+        put_varint(&mut self.index, u64::from(filter_handle.len));
+        self.charge(before)
+    }
+
+    /// Writes `data` followed by its codec and checksum, compressed if
+    /// `compress` is set and compression saves enough to be worth it.
+    fn write_block(&mut self, data: &[u8], compress: bool) -> Result<BlockHandle> {
+        let mut framed = Vec::with_capacity(data.len() + BLOCK_TRAILER_SIZE);
+        let mut codec = compression::RAW;
+        if compress && self.compression != Compression::None {
+            self.compression
+                .compress(data, &self.dictionary, &mut framed)?;
+            if framed.len() <= data.len() - data.len() / MIN_COMPRESSION_SAVING {
+                codec = self.compression.id();
+                if !self.dictionary.is_empty() {
+                    codec |= compression::DICTIONARY;
+                }
+            } else {
+                framed.clear();
+            }
+        }
+        if codec == compression::RAW {
+            framed.extend_from_slice(data);
+        }
+        let handle = BlockHandle {
+            offset: self.offset,
+            len: framed.len() as u32,
+        };
+        framed.push(codec);
+        let crc = crc32c(&framed);
+        framed.extend_from_slice(&crc.to_le_bytes());
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.request(framed.len());
+        }
+        self.file.write_at(&framed, self.offset)?;
+        self.offset += framed.len() as u64;
+        Ok(handle)
+    }
+
+    /// Bytes held by the buffers of the table being built.
+    fn memory(&self) -> usize {
+        self.block.capacity()
+            + self.index.capacity()
+            + self.filter.memory()
+            + self.block_filter.memory()
+            + self.dictionary.capacity()
+    }
+
+    /// Grows the reservation to cover buffer growth since `before`.
+    fn charge(&mut self, before: usize) -> Result<()> {
+        let after = self.memory();
+        if after > before {
+            self.reservation.grow(after - before)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drops the block cached in `slot` if nobody is using it, returning the
+/// bytes freed.
+fn release_block(slot: &Mutex<Option<Arc<Block>>>) -> usize {
+    let Ok(mut slot) = slot.try_lock() else {
+        return 0;
+    };
+    match &*slot {
+        Some(block) if Arc::strong_count(block) == 1 => {
+            let freed = block._reservation.size();
+            *slot = None;
+            freed
+        }
+        _ => 0,
+    }
+}
+
+/// Builds the filter of `builder`, holding the memory the build needs from
+/// `budget` meanwhile.
+fn finish_filter(builder: &mut FilterBuilder, budget: &MemoryBudget) -> Result<Option<Vec<u8>>> {
+    let _scratch = budget.reserve(builder.build_memory())?;
+    Ok(builder.finish())
+}
+
+struct IndexEntry {
+    last_key: Vec<u8>,
+    handle: BlockHandle,
+    filter: BlockHandle,
+}
+
+struct Index {
+    entries: Vec<IndexEntry>,
+    _reservation: Reservation,
+}
+
+impl Index {
+    /// Position of the first block that may contain keys `>= key`.
+    fn seek(&self, key: &[u8]) -> usize {
+        self.entries
+            .partition_point(|e| e.last_key.as_slice() < key)
+    }
+}
+
+/// A data block read from disk, charged against the budget while alive.
+struct Block {
+    data: Vec<u8>,
+    _reservation: Reservation,
+}
+
+/// An open table file.
+pub(crate) struct Table {
+    meta: TableMeta,
+    file: Box<dyn VfsFile>,
+    index_handle: BlockHandle,
+    index: Mutex<Option<Arc<Index>>>,
+    filter_handle: BlockHandle,
+    filter: Mutex<Option<Arc<Block>>>,
+    dictionary_handle: BlockHandle,
+    dictionary: Mutex<Option<Arc<Block>>>,
+    budget: MemoryBudget,
+}
+
+impl Table {
+    /// Opens the table described by `meta`, validating its footer.
+    pub(crate) fn open(
+        vfs: &dyn Vfs,
+        dir: &Path,
+        meta: TableMeta,
+        budget: &MemoryBudget,
+    ) -> Result<Self> {
+        let file = vfs.open(&table_path(dir, meta.id), false)?;
+        if meta.size < FOOTER_SIZE as u64 {
+            return Err(Error::corruption(format!("table {} is truncated", meta.id)));
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, meta.size - FOOTER_SIZE as u64)?;
+        if u32_at(&footer, 52) != crc32c(&footer[..52]) || u64_at(&footer, 44) != MAGIC {
+            return Err(Error::corruption(format!(
+                "table {} has a bad footer",
+                meta.id
+            )));
+        }
+        let index_handle = BlockHandle {
+            offset: u64_at(&footer, 0),
+            len: u32_at(&footer, 8),
+        };
+        let filter_handle = BlockHandle {
+            offset: u64_at(&footer, 12),
+            len: u32_at(&footer, 20),
+        };
+        let dictionary_handle = BlockHandle {
+            offset: u64_at(&footer, 24),
+            len: u32_at(&footer, 32),
+        };
+        Ok(Table {
+            meta,
+            file,
+            index_handle,
+            index: Mutex::new(None),
+            filter_handle,
+            filter: Mutex::new(None),
+            dictionary_handle,
+            dictionary: Mutex::new(None),
+            budget: budget.clone(),
+        })
+    }
+
+    pub(crate) fn meta(&self) -> &TableMeta {
+        &self.meta
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.meta.id
+    }
+
+    /// Looks `key` up, reading at most one data block.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Value>> {
+        if key < self.meta.smallest.as_slice() || key > self.meta.largest.as_slice() {
+            return Ok(None);
+        }
+        let filter = self.filter()?;
+        if filter.as_ref().is_some_and(|f| !may_contain(&f.data, key)) {
+            return Ok(None);
+        }
+        let index = self.index()?;
+        let Some(entry) = index.entries.get(index.seek(key)) else {
+            return Ok(None);
+        };
+        if filter.is_none() && !entry.filter.is_none() {
+            let block_filter = self.read_block(entry.filter)?;
+            if !may_contain(&block_filter.data, key) {
+                return Ok(None);
+            }
+        }
+        let block = self.read_block(entry.handle)?;
+        let mut reader = Reader::new(&block.data);
+        while !reader.is_empty() {
+            let (k, value) = decode_entry(&mut reader)?;
+            if k == key {
+                return Ok(Some(value));
+            }
+            if k > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterates over the entries in `range`, holding one block at a time.
+    pub(crate) fn iter(self: &Arc<Self>, range: KeyRange) -> TableIter {
+        TableIter {
+            table: Arc::clone(self),
+            range,
+            index: None,
+            next_block: 0,
+            block: None,
+            pos: 0,
+            done: false,
+            rate_limiter: None,
+        }
+    }
+
+    fn index(&self) -> Result<Arc<Index>> {
+        let mut slot = self.index.lock().unwrap();
+        if let Some(index) = &*slot {
+            return Ok(Arc::clone(index));
+        }
+        let block = self.read_block(self.index_handle)?;
+        let mut reservation = self.budget.reserve(block.data.len())?;
+        let mut entries = Vec::new();
+        let mut reader = Reader::new(&block.data);
+        while !reader.is_empty() {
+            let last_key = reader.bytes()?.to_vec();
+            let offset = reader.varint()?;
+            let len = reader.varint()? as u32;
+            let filter_offset = reader.varint()?;
+            let filter_len = reader.varint()? as u32;
+            entries.push(IndexEntry {
+                last_key,
+                handle: BlockHandle { offset, len },
+                filter: BlockHandle {
+                    offset: filter_offset,
+                    len: filter_len,
+                },
+            });
+        }
+        let overhead = entries.capacity() * std::mem::size_of::<IndexEntry>();
+        reservation.grow(overhead)?;
+        let index = Arc::new(Index {
+            entries,
+            _reservation: reservation,
+        });
+        *slot = Some(Arc::clone(&index));
+        Ok(index)
+    }
+
+    /// Returns the table filter, loading it if it is not cached. `None` if
+    /// the table has no filter or it does not fit in the budget right now.
+    fn filter(&self) -> Result<Option<Arc<Block>>> {
+        if self.filter_handle.is_none() {
+            return Ok(None);
+        }
+        let mut slot = self.filter.lock().unwrap();
+        if let Some(filter) = &*slot {
+            return Ok(Some(Arc::clone(filter)));
+        }
+        let filter = match self.read_block(self.filter_handle) {
+            Ok(block) => Arc::new(block),
+            Err(Error::OutOfBudget(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        *slot = Some(Arc::clone(&filter));
+        Ok(Some(filter))
+    }
+
+    /// Returns the dictionary the blocks of the table are compressed with,
+    /// loading it if it is not cached.
+    fn dictionary(&self) -> Result<Arc<Block>> {
+        if self.dictionary_handle.is_none() {
+            return Err(Error::corruption(format!(
+                "table {} uses a dictionary it does not have",
+                self.meta.id
+            )));
+        }
+        let mut slot = self.dictionary.lock().unwrap();
+        if let Some(dictionary) = &*slot {
+            return Ok(Arc::clone(dictionary));
+        }
+        let dictionary = Arc::new(self.read_block(self.dictionary_handle)?);
+        *slot = Some(Arc::clone(&dictionary));
+        Ok(dictionary)
+    }
+
+    /// Drops the cached filter if nobody is using it, returning the bytes
+    /// freed.
+    fn release_filter(&self) -> usize {
+        release_block(&self.filter)
+    }
+
+    /// Drops the cached dictionary if nobody is using it, returning the bytes
+    /// freed.
+    fn release_dictionary(&self) -> usize {
+        release_block(&self.dictionary)
+    }
+
+    /// Drops the cached index if nobody is using it, returning the bytes freed.
+    fn release_index(&self) -> usize {
+        let Ok(mut slot) = self.index.try_lock() else {
+            return 0;
+        };
+        match &*slot {
+            Some(index) if Arc::strong_count(index) == 1 => {
+                let freed = index._reservation.size();
+                *slot = None;
+                freed
+            }
+            _ => 0,
+        }
+    }
+
+    /// Reads the block at `handle`, decompressing it if needed. The budget
+    /// is charged for the compressed and the decompressed copy while both
+    /// exist.
+    fn read_block(&self, handle: BlockHandle) -> Result<Block> {
+        let len = handle.len as usize;
+        let reservation = self.budget.reserve(len + BLOCK_TRAILER_SIZE)?;
+        let mut data = vec![0u8; len + BLOCK_TRAILER_SIZE];
+        self.file.read_exact_at(&mut data, handle.offset)?;
+        let crc = u32_at(&data, len + 1);
+        data.truncate(len + 1);
+        if crc32c(&data) != crc {
+            return Err(Error::corruption(format!(
+                "checksum mismatch in table {} at offset {}",
+                self.meta.id, handle.offset
+            )));
+        }
+        let codec = data.pop().unwrap();
+        if codec == compression::RAW {
+            return Ok(Block {
+                data,
+                _reservation: reservation,
+            });
+        }
+        let dictionary = match codec & compression::DICTIONARY {
+            0 => None,
+            _ => Some(self.dictionary()?),
+        };
+        let decompressed = self.budget.reserve(compression::decompressed_len(&data)?)?;
+        let dictionary = dictionary.as_ref().map_or(&[][..], |d| d.data.as_slice());
+        let data = compression::decompress(codec & !compression::DICTIONARY, &data, dictionary)?;
+        Ok(Block {
+            data,
+            _reservation: decompressed,
+        })
+    }
+}
+
+/// Iterator over one table, keeping a single block in memory.
+pub(crate) struct TableIter {
+    table: Arc<Table>,
+    range: KeyRange,
+    index: Option<Arc<Index>>,
+    next_block: usize,
+    block: Option<Block>,
+    pos: usize,
+    done: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl TableIter {
+    /// Paces the block reads of the iterator with `rate_limiter`.
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    fn load_next_block(&mut self) -> Result<bool> {
+        self.block = None;
+        let index = match &self.index {
+            Some(index) => Arc::clone(index),
+            None => {
+                let index = self.table.index()?;
+                self.next_block = match self.range.as_bounds().0 {
+                    std::ops::Bound::Included(k) | std::ops::Bound::Excluded(k) => index.seek(k),
+                    std::ops::Bound::Unbounded => 0,
+                };
+                self.index = Some(Arc::clone(&index));
+                index
+            }
+        };
+        let Some(entry) = index.entries.get(self.next_block) else {
+            return Ok(false);
+        };
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.request(entry.handle.len as usize + BLOCK_TRAILER_SIZE);
+        }
+        self.block = Some(self.table.read_block(entry.handle)?);
+        self.next_block += 1;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl EntrySource for TableIter {
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+        while !self.done {
+            let exhausted = match &self.block {
+                Some(block) => self.pos >= block.data.len(),
+                None => true,
+            };
+            if exhausted {
+                if !self.load_next_block()? {
+                    self.done = true;
+                    // Release the index and block as soon as we are done.
+                    self.index = None;
+                }
+                continue;
+            }
+            let block = self.block.as_ref().unwrap();
+            let mut reader = Reader::new(&block.data[self.pos..]);
+            let (key, value) = decode_entry(&mut reader)?;
+            self.pos += reader.position();
+            if self.range.is_before(key) {
+                continue;
+            }
+            if self.range.is_after(key) {
+                self.done = true;
+                self.block = None;
+                self.index = None;
+                break;
+            }
+            return Ok(Some((key.to_vec(), value)));
+        }
+        Ok(None)
+    }
+}
+
+/// Registry of open tables that gives their filters and indexes back to the
+/// budget under memory pressure.
+#[derive(Default)]
+pub(crate) struct TableCache {
+    tables: Mutex<HashMap<u64, Weak<Table>>>,
+}
+
+impl TableCache {
+    pub(crate) fn insert(&self, table: &Arc<Table>) {
+        let mut tables = self.tables.lock().unwrap();
+        tables.retain(|_, t| t.strong_count() > 0);
+        tables.insert(table.id(), Arc::downgrade(table));
+    }
+}
+
+impl Reclaim for TableCache {
+    fn reclaim(&self, bytes: usize) -> usize {
+        let Ok(tables) = self.tables.try_lock() else {
+            return 0;
+        };
+        let tables: Vec<_> = tables.values().filter_map(Weak::upgrade).collect();
+        // Filters only save reads, while every read needs the index and,
+        // if the table has one, the dictionary.
+        let releases: [fn(&Table) -> usize; 3] = [
+            Table::release_filter,
+            Table::release_dictionary,
+            Table::release_index,
+        ];
+        let mut freed = 0;
+        for release in releases {
+            for table in &tables {
+                freed += release(table);
+                if freed >= bytes {
+                    return freed;
+                }
+            }
+        }
+        freed
+    }
+}
@@ -0,0 +1,267 @@
+//! Global memory accounting.
+//!
+//! A [`MemoryBudget`] is created once when a database is opened and shared by
+//! every component that keeps data on the heap: caches, buffers, memtables and
+//! query operators. Nothing grows without first holding a [`Reservation`] for
+//! the bytes it intends to keep. When a reservation cannot be satisfied the
+//! caller receives an [`OutOfBudget`] error and has to evict, spill or fail
+//! instead of growing the heap.
+//!
+//! Components that hold memory they can give back register a [`Reclaim`] hook.
+//! A request that would otherwise fail asks those hooks to release memory
+//! before giving up.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+pub use crate::error::OutOfBudget;
+
+/// Memory holder that can release part of its reservation on demand.
+///
+/// Hooks are invoked from whatever thread is asking the budget for memory,
+/// possibly while that thread holds locks of its own. Implementations must
+/// therefore never block: use `try_lock` and report `0` when busy.
+pub trait Reclaim: Send + Sync {
+    /// Attempts to release at least `bytes` back to the budget and returns the
+    /// number of bytes actually released.
+    fn reclaim(&self, bytes: usize) -> usize;
+}
+
+/// Shared, hard upper bound on the memory the database may hold.
+///
+/// Cloning is cheap; all clones account against the same limit.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    reclaimers: Mutex<Vec<Weak<dyn Reclaim>>>,
+}
+
+impl MemoryBudget {
+    /// Creates a budget allowing at most `limit` bytes to be reserved at once.
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Inner {
+                limit,
+                used: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+                reclaimers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Configured limit in bytes.
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Acquire)
+    }
+
+    /// Bytes that can still be reserved without reclaiming anything.
+    pub fn available(&self) -> usize {
+        self.limit().saturating_sub(self.used())
+    }
+
+    /// Highest value [`used`](Self::used) has reached since creation.
+    pub fn peak(&self) -> usize {
+        self.inner.peak.load(Ordering::Acquire)
+    }
+
+    /// Returns an empty reservation that can later be grown.
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    /// Reserves `bytes` if they are free right now, without asking registered
+    /// [`Reclaim`] hooks for memory.
+    pub fn try_reserve(&self, bytes: usize) -> Result<Reservation, OutOfBudget> {
+        let mut reservation = self.reservation();
+        reservation.try_grow(bytes)?;
+        Ok(reservation)
+    }
+
+    /// Reserves `bytes`, asking registered [`Reclaim`] hooks to release memory
+    /// if the budget is currently exhausted.
+    pub fn reserve(&self, bytes: usize) -> Result<Reservation, OutOfBudget> {
+        let mut reservation = self.reservation();
+        reservation.grow(bytes)?;
+        Ok(reservation)
+    }
+
+    /// Registers a hook consulted when a reservation would otherwise fail.
+    ///
+    /// The budget only keeps a weak reference; hooks whose owner has been
+    /// dropped are pruned automatically.
+    pub fn register_reclaimer(&self, reclaimer: Weak<dyn Reclaim>) {
+        let mut reclaimers = self.inner.reclaimers.lock().unwrap();
+        reclaimers.retain(|r| r.strong_count() > 0);
+        reclaimers.push(reclaimer);
+    }
+
+    fn acquire(&self, bytes: usize) -> bool {
+        let inner = &*self.inner;
+        let mut used = inner.used.load(Ordering::Acquire);
+        loop {
+            let next = match used.checked_add(bytes) {
+                Some(next) if next <= inner.limit => next,
+                _ => return false,
+            };
+            match inner
+                .used
+                .compare_exchange_weak(used, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    inner.peak.fetch_max(next, Ordering::AcqRel);
+                    return true;
+                }
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    fn acquire_or_reclaim(&self, bytes: usize) -> Result<(), OutOfBudget> {
+        if self.acquire(bytes) {
+            return Ok(());
+        }
+        let reclaimers: Vec<Arc<dyn Reclaim>> = {
+            let mut reclaimers = self.inner.reclaimers.lock().unwrap();
+            reclaimers.retain(|r| r.strong_count() > 0);
+            reclaimers.iter().filter_map(Weak::upgrade).collect()
+        };
+        for reclaimer in reclaimers {
+            let shortfall = bytes.saturating_sub(self.available());
+            if shortfall > 0 {
+                reclaimer.reclaim(shortfall);
+            }
+            if self.acquire(bytes) {
+                return Ok(());
+            }
+        }
+        Err(self.out_of_budget(bytes))
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes > 0 {
+            self.inner.used.fetch_sub(bytes, Ordering::AcqRel);
+        }
+    }
+
+    fn out_of_budget(&self, requested: usize) -> OutOfBudget {
+        OutOfBudget {
+            requested,
+            available: self.available(),
+            limit: self.limit(),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("limit", &self.limit())
+            .field("used", &self.used())
+            .field("peak", &self.peak())
+            .finish()
+    }
+}
+
+/// Bytes held against a [`MemoryBudget`], returned when dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Number of bytes currently held.
+    pub fn size(&self) -> usize {
+        self.bytes
+    }
+
+    /// Budget this reservation draws from.
+    pub fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+
+    /// Grows the reservation by `bytes` only if they are free right now.
+    pub fn try_grow(&mut self, bytes: usize) -> Result<(), OutOfBudget> {
+        if !self.budget.acquire(bytes) {
+            return Err(self.budget.out_of_budget(bytes));
+        }
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Grows the reservation by `bytes`, reclaiming memory from registered
+    /// hooks if necessary.
+    pub fn grow(&mut self, bytes: usize) -> Result<(), OutOfBudget> {
+        self.budget.acquire_or_reclaim(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Returns up to `bytes` to the budget.
+    pub fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+        self.budget.release(bytes);
+    }
+
+    /// Grows or shrinks the reservation to exactly `size` bytes.
+    pub fn resize(&mut self, size: usize) -> Result<(), OutOfBudget> {
+        if size > self.bytes {
+            self.grow(size - self.bytes)
+        } else {
+            self.shrink(self.bytes - size);
+            Ok(())
+        }
+    }
+
+    /// Moves up to `bytes` of this reservation into a new one.
+    pub fn split(&mut self, bytes: usize) -> Reservation {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+        Reservation {
+            budget: self.budget.clone(),
+            bytes,
+        }
+    }
+
+    /// Absorbs `other` into this reservation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the two reservations belong to different budgets.
+    pub fn merge(&mut self, mut other: Reservation) {
+        assert!(
+            Arc::ptr_eq(&self.budget.inner, &other.budget.inner),
+            "cannot merge reservations of different budgets"
+        );
+        self.bytes += std::mem::take(&mut other.bytes);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+impl fmt::Debug for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reservation")
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
@@ -0,0 +1,268 @@
+//! Read-modify-write without the read.
+//!
+//! A merge stores an operand describing a change, such as "add 3", instead
+//! of the value that results from it. Operands name the [`MergeOperator`]
+//! that folds them into the value of their key when the key is read: one of
+//! the built-in [`Counter`] and [`Append`], or one registered in
+//! [`Options::merge_operators`](crate::Options::merge_operators). The LSM
+//! engine keeps operands as entries of their own until a read or a
+//! compaction meets the value they apply to, so a merge costs no more I/O or
+//! memory than a put; the B+tree engine folds each operand into the leaf it
+//! writes anyway.
+//!
+//! An operand is stored behind the name of its operator, so keys merged by
+//! different operators share a database, and even a key may be merged by
+//! one operator and then another: each run of operands is folded by its own
+//! operator, oldest first. An operator must stay registered, under the same
+//! name, for as long as operands naming it may be stored.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::coding::{put_bytes, Reader};
+use crate::error::{Error, Result};
+
+/// Function folding merge operands into the value of a key.
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+    /// Name the operator is registered under, stored with every operand it
+    /// folds.
+    fn name(&self) -> &str;
+
+    /// Value of `key` once `operands`, oldest first, are applied to
+    /// `existing`, the value before them or `None` if the key had none.
+    ///
+    /// An operand or value the operator cannot interpret is an error, which
+    /// fails the read of the key, or the write or compaction folding it.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Result<Vec<u8>>;
+
+    /// One operand with the effect of `older` followed by `newer`, if the
+    /// operator can combine them. Combined operands take the room of one
+    /// until they meet the value they apply to; operands that are not
+    /// combined are kept as they are, for [`full_merge`](Self::full_merge)
+    /// to fold or reject. The default combines none.
+    fn partial_merge(&self, key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        let _ = (key, older, newer);
+        None
+    }
+}
+
+/// Counters stored as big-endian `i64`s, with operands holding the amount
+/// to add in the same form. A missing key counts as zero. Values or operands
+/// that are not eight bytes long, and sums that overflow, are errors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counter;
+
+impl Counter {
+    /// Name the counter is registered under.
+    pub const NAME: &'static str = "counter";
+
+    /// Operand adding `delta` to a counter.
+    pub fn operand(delta: i64) -> [u8; 8] {
+        delta.to_be_bytes()
+    }
+
+    /// Count held by a counter value.
+    pub fn value(value: &[u8]) -> Result<i64> {
+        let bytes = value
+            .try_into()
+            .map_err(|_| Error::invalid("value is not a counter"))?;
+        Ok(i64::from_be_bytes(bytes))
+    }
+}
+
+impl MergeOperator for Counter {
+    fn name(&self) -> &str {
+        Counter::NAME
+    }
+
+    fn full_merge(&self, _: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+        let mut count = existing.map_or(Ok(0), Counter::value)?;
+        for operand in operands {
+            count = count
+                .checked_add(Counter::value(operand)?)
+                .ok_or_else(|| Error::invalid("counter overflows an i64"))?;
+        }
+        Ok(count.to_be_bytes().to_vec())
+    }
+
+    fn partial_merge(&self, _: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        let sum = Counter::value(older)
+            .ok()?
+            .checked_add(Counter::value(newer).ok()?)?;
+        Some(sum.to_be_bytes().to_vec())
+    }
+}
+
+/// Append-only lists stored as items prefixed by their varint length, with
+/// operands holding the items to append in the same form. A missing key
+/// counts as the empty list; values or operands that are not lists are
+/// errors.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Append;
+
+impl Append {
+    /// Name the list operator is registered under.
+    pub const NAME: &'static str = "append";
+
+    /// Operand appending `item` to a list.
+    pub fn operand(item: &[u8]) -> Vec<u8> {
+        let mut operand = Vec::with_capacity(item.len() + 5);
+        put_bytes(&mut operand, item);
+        operand
+    }
+
+    /// Items of a list value, oldest first.
+    pub fn items(value: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut reader = Reader::new(value);
+        let mut items = Vec::new();
+        while !reader.is_empty() {
+            let item = reader
+                .bytes()
+                .map_err(|_| Error::invalid("value is not a list"))?;
+            items.push(item.to_vec());
+        }
+        Ok(items)
+    }
+
+    fn check(list: &[u8]) -> Result<()> {
+        let mut reader = Reader::new(list);
+        while !reader.is_empty() {
+            reader
+                .bytes()
+                .map_err(|_| Error::invalid("value is not a list"))?;
+        }
+        Ok(())
+    }
+}
+
+impl MergeOperator for Append {
+    fn name(&self) -> &str {
+        Append::NAME
+    }
+
+    fn full_merge(&self, _: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+        let mut list = existing.map_or_else(Vec::new, <[u8]>::to_vec);
+        Append::check(&list)?;
+        for operand in operands {
+            Append::check(operand)?;
+            list.extend_from_slice(operand);
+        }
+        Ok(list)
+    }
+
+    fn partial_merge(&self, _: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        Some([older, newer].concat())
+    }
+}
+
+/// Operand as stored: the name of its operator followed by the operand.
+pub(crate) fn tag(operator: &str, operand: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(operator.len() + operand.len() + 1);
+    put_bytes(&mut tagged, operator.as_bytes());
+    tagged.extend_from_slice(operand);
+    tagged
+}
+
+/// Name of the operator and operand of a stored operand.
+fn untag(tagged: &[u8]) -> Result<(&[u8], &[u8])> {
+    let mut reader = Reader::new(tagged);
+    let name = reader
+        .bytes()
+        .map_err(|_| Error::corruption("malformed merge operand"))?;
+    Ok((name, &tagged[reader.position()..]))
+}
+
+/// The operators merges may name: the built-in ones and those of
+/// [`Options::merge_operators`](crate::Options::merge_operators).
+#[derive(Debug)]
+pub(crate) struct Operators {
+    operators: Vec<Arc<dyn MergeOperator>>,
+}
+
+impl Operators {
+    pub(crate) fn new(registered: &[Arc<dyn MergeOperator>]) -> Self {
+        let mut operators: Vec<Arc<dyn MergeOperator>> = vec![Arc::new(Counter), Arc::new(Append)];
+        operators.extend(registered.iter().cloned());
+        Operators { operators }
+    }
+
+    /// Fails unless no two of `registered` share a name, nor share one with
+    /// a built-in operator.
+    pub(crate) fn validate(registered: &[Arc<dyn MergeOperator>]) -> Result<()> {
+        let operators = Operators::new(registered);
+        for (i, operator) in operators.operators.iter().enumerate() {
+            if operators.operators[..i]
+                .iter()
+                .any(|o| o.name() == operator.name())
+            {
+                return Err(Error::invalid(format!(
+                    "two merge operators are named {}",
+                    operator.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Operator named `name`.
+    pub(crate) fn get(&self, name: &[u8]) -> Result<&dyn MergeOperator> {
+        self.operators
+            .iter()
+            .find(|o| o.name().as_bytes() == name)
+            .map(|o| &**o)
+            .ok_or_else(|| {
+                Error::invalid(format!(
+                    "no merge operator is named {}",
+                    String::from_utf8_lossy(name)
+                ))
+            })
+    }
+
+    /// Fails unless an operator is named `name`.
+    pub(crate) fn check(&self, name: &str) -> Result<()> {
+        self.get(name.as_bytes()).map(|_| ())
+    }
+
+    /// Value of `key` once the stored `operands`, oldest first, are applied
+    /// to `existing`, each run of operands by the operator it names.
+    pub(crate) fn full_merge(
+        &self,
+        key: &[u8],
+        mut existing: Option<Vec<u8>>,
+        operands: &[&[u8]],
+    ) -> Result<Vec<u8>> {
+        let mut operands = operands.iter().map(|o| untag(o)).peekable();
+        while let Some(first) = operands.next() {
+            let (name, operand) = first?;
+            let mut run = vec![operand];
+            while let Some(Ok((next, operand))) = operands.peek() {
+                if *next != name {
+                    break;
+                }
+                run.push(operand);
+                operands.next();
+            }
+            let operator = self.get(name)?;
+            existing = Some(operator.full_merge(key, existing.as_deref(), &run)?);
+        }
+        existing.ok_or_else(|| Error::invalid("merge without operands"))
+    }
+
+    /// One stored operand with the effect of `older` followed by `newer`, if
+    /// they name the same operator and it combines them.
+    pub(crate) fn partial_merge(&self, key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        let (name, older) = untag(older).ok()?;
+        let (newer_name, newer) = untag(newer).ok()?;
+        if name != newer_name {
+            return None;
+        }
+        let operator = self.get(name).ok()?;
+        let combined = operator.partial_merge(key, older, newer)?;
+        Some(tag(operator.name(), &combined))
+    }
+}
@@ -0,0 +1,615 @@
+//! Multi-version concurrency control over a storage engine.
+//!
+//! A [`TransactionDb`] gives every [`Transaction`] a snapshot of the data as
+//! of the moment it began. Reads never wait for writers: the storage engine
+//! holds the latest committed values, and the version store keeps the values
+//! keys had before each recent commit for the snapshots that are older.
+//! Writes are buffered in the transaction and applied at commit as a single
+//! atomic batch; a transaction that writes a key some other transaction
+//! committed after its snapshot was taken fails with [`Error::Conflict`]
+//! (first committer wins). [`Transaction::require_unchanged`] extends the
+//! check to keys a transaction relies on without writing them.
+//!
+//! Old versions are dropped as soon as no active snapshot needs them. While
+//! long-running snapshots hold them back, the version store is bounded by
+//! [`Options::version_store_size`]: past it the oldest values are spilled to
+//! disk or, with [`VersionOverflow::AbortOldest`] or once spilling no longer
+//! helps, the oldest snapshots are aborted with [`Error::SnapshotTooOld`].
+//!
+//! Snapshot isolation still allows write skew: two transactions may each read
+//! what the other writes and both commit. Transactions begun with
+//! [`Isolation::Serializable`] rule it out by also tracking what they read,
+//! and fail with [`Error::Conflict`] when the reads and writes of concurrent
+//! serializable transactions could not have happened one after the other.
+
+mod ssi;
+mod versions;
+
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard, Weak};
+
+use self::ssi::ConflictTracker;
+use self::versions::{VersionStore, SPILL_FILE};
+use crate::engine::{open_engine, Mutation, Scan, StorageEngine};
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reclaim, Reservation};
+use crate::options::Options;
+use crate::range::KeyRange;
+use crate::wal::Durability;
+
+/// Logical time at which a transaction committed or took its snapshot.
+pub type Timestamp = u64;
+
+/// Approximate bytes of bookkeeping per key buffered in a transaction.
+const WRITE_OVERHEAD: usize = 64;
+
+/// Buffered write of a key, `None` for a delete.
+type Write = Option<Vec<u8>>;
+
+/// Bytes a buffered write of `value` under `key` is charged.
+fn write_charge(key: &[u8], value: Option<&Vec<u8>>) -> usize {
+    key.len() + value.map_or(0, Vec::len) + WRITE_OVERHEAD
+}
+
+/// What the version store does when it reaches its size limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VersionOverflow {
+    /// Write the oldest values to a spill file, aborting the oldest snapshots
+    /// only once the remaining keys and timestamps alone fill the store.
+    #[default]
+    Spill,
+    /// Abort the oldest snapshots so that their versions can be dropped.
+    AbortOldest,
+}
+
+/// Guarantees a transaction gets about concurrent ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Isolation {
+    /// Reads see a snapshot and writes conflict with concurrent writes to the
+    /// same keys.
+    #[default]
+    Snapshot,
+    /// As `Snapshot`, and the transaction also aborts when it could otherwise
+    /// take part in an execution no serial order of the serializable
+    /// transactions would produce.
+    Serializable,
+}
+
+/// Snapshot held by an active transaction.
+pub(crate) struct Snapshot {
+    id: u64,
+    start: Timestamp,
+    aborted: AtomicBool,
+}
+
+impl Snapshot {
+    /// Fails if the snapshot has been aborted to free old versions.
+    fn check(&self) -> Result<()> {
+        match self.aborted.load(Ordering::Acquire) {
+            true => Err(Error::SnapshotTooOld),
+            false => Ok(()),
+        }
+    }
+}
+
+struct Snapshots {
+    /// Timestamp of the last commit visible to new snapshots.
+    clock: Timestamp,
+    next_id: u64,
+    /// Active snapshots by id, hence also by start timestamp.
+    active: BTreeMap<u64, Arc<Snapshot>>,
+}
+
+impl Snapshots {
+    /// Timestamp at or before which no active snapshot needs versions.
+    fn horizon(&self) -> Timestamp {
+        self.active.values().next().map_or(self.clock, |s| s.start)
+    }
+}
+
+/// Database offering snapshot-isolated transactions.
+pub struct TransactionDb {
+    engine: Box<dyn StorageEngine>,
+    versions: Arc<VersionStore>,
+    conflicts: Arc<ConflictTracker>,
+    snapshots: Mutex<Snapshots>,
+    /// Serializes commits.
+    commit_lock: Mutex<()>,
+    /// Taken exclusively by a commit that aborts snapshots to make room for
+    /// its versions, so that no snapshot begins meanwhile, nor while the
+    /// engine is half updated by a commit too large to record versions for.
+    gate: RwLock<()>,
+    budget: MemoryBudget,
+    durability: Durability,
+}
+
+impl TransactionDb {
+    /// Opens the database in `dir` with the engine chosen by
+    /// [`Options::engine`], creating it if needed.
+    pub fn open(dir: impl AsRef<Path>, options: &Options, budget: &MemoryBudget) -> Result<Self> {
+        let dir = dir.as_ref();
+        let engine = open_engine(dir, options, budget)?;
+        let versions = Arc::new(VersionStore::new(
+            Arc::clone(&options.vfs),
+            dir.join(SPILL_FILE),
+            options.version_store_bytes(),
+            options.version_overflow,
+            budget,
+        ));
+        let conflicts = Arc::new(ConflictTracker::new(
+            options.conflict_tracking_bytes(),
+            budget,
+        ));
+        let weak = Arc::downgrade(&versions);
+        budget.register_reclaimer(weak as Weak<dyn Reclaim>);
+        let weak = Arc::downgrade(&conflicts);
+        budget.register_reclaimer(weak as Weak<dyn Reclaim>);
+        Ok(TransactionDb {
+            engine,
+            versions,
+            conflicts,
+            snapshots: Mutex::new(Snapshots {
+                clock: 0,
+                next_id: 0,
+                active: BTreeMap::new(),
+            }),
+            commit_lock: Mutex::new(()),
+            gate: RwLock::new(()),
+            budget: budget.clone(),
+            durability: options.durability,
+        })
+    }
+
+    /// Starts a transaction reading a snapshot of the committed data.
+    pub fn begin(&self) -> Transaction<'_> {
+        let _gate = self.gate.read().unwrap();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let snapshot = Arc::new(Snapshot {
+            id: snapshots.next_id,
+            start: snapshots.clock,
+            aborted: AtomicBool::new(false),
+        });
+        snapshots.next_id += 1;
+        snapshots.active.insert(snapshot.id, Arc::clone(&snapshot));
+        Transaction {
+            db: self,
+            snapshot,
+            isolation: Isolation::Snapshot,
+            writes: BTreeMap::new(),
+            undo: Vec::new(),
+            savepoints: Vec::new(),
+            unchanged: Vec::new(),
+            reservation: self.budget.reservation(),
+            done: false,
+        }
+    }
+
+    /// Starts a transaction with the given isolation level.
+    pub fn begin_with(&self, isolation: Isolation) -> Result<Transaction<'_>> {
+        let mut txn = self.begin();
+        if isolation == Isolation::Serializable {
+            let snapshot = &txn.snapshot;
+            self.conflicts.begin(snapshot.id, snapshot.start)?;
+            txn.isolation = isolation;
+        }
+        Ok(txn)
+    }
+
+    /// Storage engine holding the latest committed values.
+    pub fn engine(&self) -> &dyn StorageEngine {
+        &*self.engine
+    }
+
+    /// Number of transactions currently active.
+    pub fn active_transactions(&self) -> usize {
+        self.lock_snapshots().active.len()
+    }
+
+    fn lock_snapshots(&self) -> MutexGuard<'_, Snapshots> {
+        self.snapshots.lock().unwrap()
+    }
+
+    /// Value of `key` as seen by `snapshot`.
+    fn read(&self, key: &[u8], snapshot: &Snapshot) -> Result<Option<Vec<u8>>> {
+        // The engine is read first: a commit records its versions before it
+        // updates the engine, so whatever it changed since shows up below.
+        let current = self.engine.get(key)?;
+        match self.versions.lookup(key, snapshot)? {
+            Some(value) => Ok(value),
+            None => Ok(current),
+        }
+    }
+
+    fn commit(
+        &self,
+        snapshot: &Snapshot,
+        isolation: Isolation,
+        writes: &BTreeMap<Vec<u8>, Write>,
+        unchanged: &[KeyRange],
+    ) -> Result<()> {
+        let _commit = self.commit_lock.lock().unwrap();
+        snapshot.check()?;
+        for key in writes.keys() {
+            if self.versions.last_commit(key) > Some(snapshot.start) {
+                return Err(Error::conflict(
+                    "a concurrent transaction committed a write to the same key",
+                ));
+            }
+        }
+        for range in unchanged {
+            if !self.versions.commits_in(range, snapshot.start).is_empty() {
+                return Err(Error::conflict(
+                    "a concurrent transaction committed a write to a key required unchanged",
+                ));
+            }
+        }
+        let mut befores = Vec::with_capacity(writes.len());
+        for key in writes.keys() {
+            befores.push((key.clone(), self.engine.get(key)?));
+        }
+        let commit_ts = self.lock_snapshots().clock + 1;
+        let charge = VersionStore::charge(&befores);
+        let (keep, gate) = self.make_room(snapshot, charge)?;
+        let record = || match keep {
+            true => self.versions.insert(commit_ts, befores),
+            false => Ok(()),
+        };
+        match isolation {
+            Isolation::Snapshot => record()?,
+            Isolation::Serializable => {
+                self.conflicts
+                    .commit(snapshot.id, commit_ts, writes.keys(), record)?
+            }
+        }
+        let mutations: Vec<Mutation> = writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => Mutation::Put {
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+                None => Mutation::Delete { key: key.clone() },
+            })
+            .collect();
+        if let Err(e) = self.engine.write(&mutations, self.durability) {
+            self.versions.remove_commit(commit_ts);
+            return Err(e);
+        }
+        self.lock_snapshots().clock = commit_ts;
+        drop(gate);
+        Ok(())
+    }
+
+    /// Makes room in the version store for `bytes` more, dropping, spilling
+    /// or aborting the oldest snapshots other than `committer` as needed.
+    /// Returns `false` if the versions cannot be kept at all.
+    ///
+    /// Before aborting snapshots it takes the gate, returned with the
+    /// result, so that none begins that would have needed the versions of the
+    /// commit once they turn out not to fit.
+    fn make_room(
+        &self,
+        committer: &Snapshot,
+        bytes: usize,
+    ) -> Result<(bool, Option<RwLockWriteGuard<'_, ()>>)> {
+        let mut gate = None;
+        loop {
+            let horizon = self.lock_snapshots().horizon();
+            self.versions.collect(horizon)?;
+            if self.versions.fits(bytes) {
+                return Ok((true, gate));
+            }
+            if self.versions.spill(bytes)? > 0 && self.versions.fits(bytes) {
+                return Ok((true, gate));
+            }
+            if gate.is_none() {
+                gate = Some(self.gate.write().unwrap());
+                continue;
+            }
+            let mut snapshots = self.lock_snapshots();
+            let victim = snapshots
+                .active
+                .values()
+                .find(|s| s.id != committer.id)
+                .cloned();
+            let Some(victim) = victim else {
+                return Ok((false, gate));
+            };
+            victim.aborted.store(true, Ordering::Release);
+            snapshots.active.remove(&victim.id);
+        }
+    }
+
+    /// Forgets `snapshot` once its transaction has ended.
+    fn end(&self, snapshot: &Snapshot) {
+        let horizon = {
+            let mut snapshots = self.lock_snapshots();
+            snapshots.active.remove(&snapshot.id);
+            snapshots.horizon()
+        };
+        self.conflicts.collect(horizon);
+        // Dropping versions only fails to truncate the spill file, which the
+        // next collection retries.
+        let _ = self.versions.collect(horizon);
+    }
+}
+
+/// Unit of work reading one snapshot and committing atomically.
+///
+/// A transaction that is dropped without being committed is rolled back.
+pub struct Transaction<'a> {
+    db: &'a TransactionDb,
+    snapshot: Arc<Snapshot>,
+    isolation: Isolation,
+    /// Buffered writes; `None` deletes the key.
+    writes: BTreeMap<Vec<u8>, Write>,
+    /// Buffered write each write made since the oldest savepoint replaced,
+    /// `None` if the key had none.
+    undo: Vec<(Vec<u8>, Option<Write>)>,
+    /// Length of `undo` at each savepoint still set, oldest first.
+    savepoints: Vec<usize>,
+    /// Ranges no transaction may commit a write to before this one does.
+    unchanged: Vec<KeyRange>,
+    reservation: Reservation,
+    done: bool,
+}
+
+impl Transaction<'_> {
+    /// Timestamp of the commits the snapshot includes.
+    pub fn snapshot_timestamp(&self) -> Timestamp {
+        self.snapshot.start
+    }
+
+    /// Isolation level the transaction was begun with.
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    /// Returns the value of `key` in the snapshot, or as last written by this
+    /// transaction.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let point = KeyRange::new(Bound::Included(key), Bound::Included(key));
+        self.track_read(point)?;
+        self.db.read(key, &self.snapshot)
+    }
+
+    /// Records a read of `range` by a serializable transaction.
+    fn track_read(&self, range: KeyRange) -> Result<()> {
+        match self.isolation {
+            Isolation::Snapshot => Ok(()),
+            Isolation::Serializable => {
+                self.db
+                    .conflicts
+                    .read(self.snapshot.id, range, &self.db.versions)
+            }
+        }
+    }
+
+    /// Fails if the transaction can no longer commit.
+    fn check(&self) -> Result<()> {
+        self.snapshot.check()?;
+        match self.isolation {
+            Isolation::Snapshot => Ok(()),
+            Isolation::Serializable => self.db.conflicts.check(self.snapshot.id),
+        }
+    }
+
+    /// Stores `value` under `key` when the transaction commits.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.buffer(key, Some(value.to_vec()))
+    }
+
+    /// Removes `key` when the transaction commits.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.buffer(key, None)
+    }
+
+    fn buffer(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        self.check()?;
+        let mut charge = write_charge(key, value.as_ref());
+        if !self.savepoints.is_empty() && !self.writes.contains_key(key) {
+            // Charge the undo entry recording that the key had no write.
+            charge += write_charge(key, None);
+        }
+        self.reservation.grow(charge)?;
+        let old = self.writes.insert(key.to_vec(), value);
+        match (self.savepoints.is_empty(), old) {
+            // The replaced write keeps its charge while the undo log holds
+            // it.
+            (false, old) => self.undo.push((key.to_vec(), old)),
+            (true, Some(old)) => self.reservation.shrink(write_charge(key, old.as_ref())),
+            (true, None) => {}
+        }
+        Ok(())
+    }
+
+    /// Makes the transaction fail to commit with [`Error::Conflict`] if
+    /// another one commits a write to a key of `range` after its snapshot
+    /// was taken, as if it had written every key of the range itself, but
+    /// without keeping other transactions from committing first.
+    pub fn require_unchanged(&mut self, range: impl Into<KeyRange>) -> Result<()> {
+        let range = range.into();
+        if !self.unchanged.contains(&range) {
+            let bound = |bound: &Bound<Vec<u8>>| match bound {
+                Bound::Included(key) | Bound::Excluded(key) => key.len(),
+                Bound::Unbounded => 0,
+            };
+            self.reservation
+                .grow(bound(&range.start) + bound(&range.end) + WRITE_OVERHEAD)?;
+            self.unchanged.push(range);
+        }
+        Ok(())
+    }
+
+    /// Sets a savepoint that [`rollback_to_savepoint`] can return the
+    /// transaction's writes to. Savepoints nest: each rollback or release
+    /// applies to the most recent one still set.
+    ///
+    /// [`rollback_to_savepoint`]: Self::rollback_to_savepoint
+    pub fn savepoint(&mut self) {
+        self.savepoints.push(self.undo.len());
+    }
+
+    /// Discards the writes made since the most recent savepoint, which is
+    /// released. Does nothing if no savepoint is set.
+    pub fn rollback_to_savepoint(&mut self) {
+        let Some(mark) = self.savepoints.pop() else {
+            return;
+        };
+        for (key, old) in self.undo.drain(mark..).rev() {
+            let current = match old {
+                Some(old) => self.writes.insert(key.clone(), old),
+                None => {
+                    self.reservation.shrink(write_charge(&key, None));
+                    self.writes.remove(&key)
+                }
+            };
+            let current = current.expect("undone writes are buffered");
+            self.reservation
+                .shrink(write_charge(&key, current.as_ref()));
+        }
+    }
+
+    /// Releases the most recent savepoint, keeping the writes made since.
+    pub fn release_savepoint(&mut self) {
+        self.savepoints.pop();
+        if self.savepoints.is_empty() {
+            for (key, old) in self.undo.drain(..) {
+                let old = old.unwrap_or_default();
+                self.reservation.shrink(write_charge(&key, old.as_ref()));
+            }
+        }
+    }
+
+    /// Iterates in key order over the pairs in `range` in the snapshot,
+    /// including this transaction's own writes.
+    pub fn scan(&self, range: impl Into<KeyRange>) -> TransactionScan<'_> {
+        let range = range.into();
+        TransactionScan {
+            txn: self,
+            engine: self.db.engine.scan(range.clone()).peekable(),
+            range,
+            tracked: false,
+            last: None,
+        }
+    }
+
+    /// Applies the transaction's writes atomically.
+    ///
+    /// Fails with [`Error::Conflict`] if another transaction committed a
+    /// write to one of the same keys after this one's snapshot was taken or,
+    /// for a serializable transaction, if committing could break
+    /// serializability; the transaction is rolled back either way.
+    pub fn commit(mut self) -> Result<()> {
+        let result = match self.writes.is_empty() && self.unchanged.is_empty() {
+            true => self.check(),
+            false => self.db.commit(
+                &self.snapshot,
+                self.isolation,
+                &self.writes,
+                &self.unchanged,
+            ),
+        };
+        self.finish(result.is_ok());
+        result
+    }
+
+    /// Discards the transaction's writes.
+    pub fn rollback(mut self) {
+        self.finish(false);
+    }
+
+    fn finish(&mut self, committed: bool) {
+        self.done = true;
+        if self.isolation == Isolation::Serializable && !committed {
+            self.db.conflicts.abort(self.snapshot.id);
+        }
+        self.db.end(&self.snapshot);
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.finish(false);
+        }
+    }
+}
+
+/// Iterator over a range of a [`Transaction`]'s view of the data.
+pub struct TransactionScan<'a> {
+    txn: &'a Transaction<'a>,
+    engine: Peekable<Scan<'a>>,
+    range: KeyRange,
+    /// Whether the read of `range` has been tracked.
+    tracked: bool,
+    last: Option<Vec<u8>>,
+}
+
+impl TransactionScan<'_> {
+    fn next_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let txn = self.txn;
+        if !self.tracked {
+            txn.track_read(self.range.clone())?;
+            self.tracked = true;
+        }
+        loop {
+            let head = match self.engine.peek() {
+                Some(Ok((key, _))) => Some(key.clone()),
+                Some(Err(_)) => return Err(self.engine.next().unwrap().unwrap_err()),
+                None => None,
+            };
+            // Keys changed since the snapshot are looked up after the engine
+            // has been read up to them, like single reads.
+            let versioned = txn.db.versions.next_key(
+                &self.range,
+                self.last.as_deref(),
+                head.as_deref(),
+                &txn.snapshot,
+            )?;
+            let start = match &self.last {
+                Some(last) => Bound::Excluded(last.as_slice()),
+                None => self.range.as_bounds().0,
+            };
+            let own = txn
+                .writes
+                .range::<[u8], _>((start, Bound::Unbounded))
+                .next()
+                .map(|(key, _)| key.clone())
+                .filter(|key| !self.range.is_after(key));
+            let Some(key) = [head.clone(), versioned, own].into_iter().flatten().min() else {
+                return Ok(None);
+            };
+            let current = match head == Some(key.clone()) {
+                true => self.engine.next().unwrap()?.1.into(),
+                false => None,
+            };
+            self.last = Some(key.clone());
+            let value = match txn.writes.get(&key) {
+                Some(value) => value.clone(),
+                None => match txn.db.versions.lookup(&key, &txn.snapshot)? {
+                    Some(value) => value,
+                    None => current,
+                },
+            };
+            if let Some(value) = value {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+}
+
+impl Iterator for TransactionScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair().transpose()
+    }
+}
@@ -0,0 +1,363 @@
+//! Conflict tracking for serializable transactions.
+//!
+//! Serializable snapshot isolation runs transactions on snapshots as usual and
+//! watches the read-write antidependencies between concurrent ones: `R -rw->
+//! W` when `R` read a key that `W`, running at the same time, wrote. Every
+//! execution that is not serializable has a transaction with both an incoming
+//! and an outgoing such edge, so a transaction found in that position (a
+//! pivot) is aborted. The test is conservative: a few serializable executions
+//! are aborted too.
+//!
+//! Only serializable transactions take part. Their reads are remembered as keys
+//! and ranges for as long as a concurrent transaction may still write what they
+//! cover. When the reads outgrow their share of the memory budget, those of the
+//! transactions that read the most are coarsened into fewer, wider ranges,
+//! which can only report more conflicts, never fewer.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::{Mutex, MutexGuard};
+
+use super::versions::VersionStore;
+use super::Timestamp;
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reclaim, Reservation};
+use crate::range::KeyRange;
+
+/// Approximate bytes of bookkeeping per transaction.
+const ENTRY_OVERHEAD: usize = 96;
+
+/// Approximate bytes of bookkeeping per key or range read.
+const READ_OVERHEAD: usize = 48;
+
+pub(crate) struct ConflictTracker {
+    state: Mutex<State>,
+    limit: usize,
+}
+
+struct State {
+    /// Serializable transactions, active or committed but still concurrent
+    /// with an active one, by snapshot id.
+    txns: HashMap<u64, Entry>,
+    /// Ids of the committed transactions in `txns`, by commit timestamp.
+    committed: BTreeMap<Timestamp, u64>,
+    reservation: Reservation,
+}
+
+struct Entry {
+    start: Timestamp,
+    commit: Option<Timestamp>,
+    reads: Reads,
+    /// Some concurrent transaction read a key this one wrote.
+    in_conflict: bool,
+    /// This transaction read a key some concurrent transaction wrote.
+    out_conflict: bool,
+    /// The transaction must abort.
+    doomed: bool,
+}
+
+#[derive(Default)]
+struct Reads {
+    keys: BTreeSet<Vec<u8>>,
+    ranges: Vec<KeyRange>,
+    bytes: usize,
+}
+
+fn bound_len(bound: &Bound<Vec<u8>>) -> usize {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => key.len(),
+        Bound::Unbounded => 0,
+    }
+}
+
+fn range_charge(range: &KeyRange) -> usize {
+    bound_len(&range.start) + bound_len(&range.end) + READ_OVERHEAD
+}
+
+/// Orders lower bounds by the first key they admit.
+fn cmp_start(a: &Bound<Vec<u8>>, b: &Bound<Vec<u8>>) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Less,
+        (_, Bound::Unbounded) => Ordering::Greater,
+        (Bound::Included(a), Bound::Excluded(b)) if a == b => Ordering::Less,
+        (Bound::Excluded(a), Bound::Included(b)) if a == b => Ordering::Greater,
+        (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b)) => {
+            a.cmp(b)
+        }
+    }
+}
+
+/// The one of two upper bounds that admits more keys.
+fn max_end(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (a, b) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => Bound::Unbounded,
+        (Bound::Included(a), Bound::Excluded(b)) | (Bound::Excluded(b), Bound::Included(a))
+            if a >= b =>
+        {
+            Bound::Included(a)
+        }
+        (Bound::Included(a), Bound::Included(b)) => Bound::Included(a.max(b)),
+        (Bound::Excluded(a), Bound::Excluded(b)) => Bound::Excluded(a.max(b)),
+        (_, Bound::Excluded(b)) | (Bound::Excluded(b), _) => Bound::Excluded(b),
+    }
+}
+
+impl Reads {
+    fn len(&self) -> usize {
+        self.keys.len() + self.ranges.len()
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains(key) || self.ranges.iter().any(|range| range.contains(key))
+    }
+
+    /// Bytes recording a read of `range` adds, none if it is recorded
+    /// already.
+    fn charge(&self, range: &KeyRange) -> usize {
+        match (&range.start, &range.end) {
+            (Bound::Included(start), Bound::Included(end)) if start == end => {
+                match self.contains(start) {
+                    true => 0,
+                    false => start.len() + READ_OVERHEAD,
+                }
+            }
+            _ => match self.ranges.contains(range) {
+                true => 0,
+                false => range_charge(range),
+            },
+        }
+    }
+
+    /// Records a read of `range`, adding its [`charge`](Self::charge).
+    fn insert(&mut self, range: KeyRange) {
+        let added = self.charge(&range);
+        if added == 0 {
+            return;
+        }
+        match (range.start, range.end) {
+            (Bound::Included(start), Bound::Included(end)) if start == end => {
+                self.keys.insert(start);
+            }
+            (start, end) => self.ranges.push(KeyRange { start, end }),
+        }
+        self.bytes += added;
+    }
+
+    /// Halves the number of keys and ranges by merging neighbours into
+    /// ranges covering both, returning the bytes released.
+    fn coarsen(&mut self) -> usize {
+        let keys = std::mem::take(&mut self.keys)
+            .into_iter()
+            .map(|key| KeyRange {
+                start: Bound::Included(key.clone()),
+                end: Bound::Included(key),
+            });
+        let mut reads: Vec<KeyRange> = keys.chain(self.ranges.drain(..)).collect();
+        reads.sort_by(|a, b| cmp_start(&a.start, &b.start));
+        let mut reads = reads.into_iter();
+        while let Some(first) = reads.next() {
+            let range = match reads.next() {
+                Some(second) => KeyRange {
+                    start: first.start,
+                    end: max_end(first.end, second.end),
+                },
+                None => first,
+            };
+            self.ranges.push(range);
+        }
+        let before = self.bytes;
+        self.bytes = self.ranges.iter().map(range_charge).sum();
+        before - self.bytes
+    }
+}
+
+fn serialization_failure() -> Error {
+    Error::conflict("reads and writes of concurrent transactions could not be serialized")
+}
+
+impl ConflictTracker {
+    pub(crate) fn new(limit: usize, budget: &MemoryBudget) -> Self {
+        ConflictTracker {
+            state: Mutex::new(State {
+                txns: HashMap::new(),
+                committed: BTreeMap::new(),
+                reservation: budget.reservation(),
+            }),
+            limit,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Starts tracking the transaction with snapshot `id` taken at `start`.
+    pub(crate) fn begin(&self, id: u64, start: Timestamp) -> Result<()> {
+        let mut state = self.lock();
+        state.reservation.grow(ENTRY_OVERHEAD)?;
+        let entry = Entry {
+            start,
+            commit: None,
+            reads: Reads::default(),
+            in_conflict: false,
+            out_conflict: false,
+            doomed: false,
+        };
+        state.txns.insert(id, entry);
+        Ok(())
+    }
+
+    /// Fails if transaction `id` has been chosen to abort.
+    pub(crate) fn check(&self, id: u64) -> Result<()> {
+        match self.lock().txns.get(&id).is_some_and(|e| e.doomed) {
+            true => Err(serialization_failure()),
+            false => Ok(()),
+        }
+    }
+
+    /// Records that transaction `id` reads the keys in `range`, and the
+    /// conflicts with the concurrent transactions that already committed
+    /// writes to them.
+    pub(crate) fn read(&self, id: u64, range: KeyRange, versions: &VersionStore) -> Result<()> {
+        let mut state = self.lock();
+        let start = state.entry(id).start;
+        for commit in versions.commits_in(&range, start) {
+            if let Some(&writer) = state.committed.get(&commit) {
+                state.conflict(id, writer, id);
+            }
+        }
+        // Reserved first, so that a read the budget cannot hold is not
+        // recorded.
+        let added = state.entry(id).reads.charge(&range);
+        state.reservation.grow(added)?;
+        state.entry_mut(id).reads.insert(range);
+        state.fit(self.limit);
+        match state.entry(id).doomed {
+            true => Err(serialization_failure()),
+            false => Ok(()),
+        }
+    }
+
+    /// Validates the commit of transaction `id` at `commit_ts`, writing
+    /// `keys`, against the reads of concurrent transactions, and runs
+    /// `record` while no read can be tracked.
+    ///
+    /// A read tracked later sees the versions `record` leaves behind, while
+    /// one tracked earlier is seen here.
+    pub(crate) fn commit<'k>(
+        &self,
+        id: u64,
+        commit_ts: Timestamp,
+        keys: impl Iterator<Item = &'k Vec<u8>> + Clone,
+        record: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        let start = state.entry(id).start;
+        let readers: Vec<u64> = state
+            .txns
+            .iter()
+            .filter(|(&other, e)| other != id && e.commit.is_none_or(|c| c > start))
+            .filter(|(_, e)| keys.clone().any(|key| e.reads.contains(key)))
+            .map(|(&other, _)| other)
+            .collect();
+        for reader in readers {
+            state.conflict(reader, id, id);
+        }
+        if state.entry(id).doomed {
+            return Err(serialization_failure());
+        }
+        record()?;
+        state.entry_mut(id).commit = Some(commit_ts);
+        state.committed.insert(commit_ts, id);
+        Ok(())
+    }
+
+    /// Forgets transaction `id`, which rolled back or failed to commit.
+    pub(crate) fn abort(&self, id: u64) {
+        let mut state = self.lock();
+        if let Some(entry) = state.txns.get(&id) {
+            if let Some(commit) = entry.commit {
+                state.committed.remove(&commit);
+            }
+            state.remove(id);
+        }
+    }
+
+    /// Forgets the committed transactions no snapshot started before
+    /// `horizon` is concurrent with.
+    pub(crate) fn collect(&self, horizon: Timestamp) {
+        let mut state = self.lock();
+        while let Some(entry) = state.committed.first_entry() {
+            if *entry.key() > horizon {
+                break;
+            }
+            let id = entry.remove();
+            state.remove(id);
+        }
+    }
+}
+
+impl State {
+    fn entry(&self, id: u64) -> &Entry {
+        &self.txns[&id]
+    }
+
+    fn entry_mut(&mut self, id: u64) -> &mut Entry {
+        self.txns.get_mut(&id).unwrap()
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(entry) = self.txns.remove(&id) {
+            self.reservation.shrink(entry.reads.bytes + ENTRY_OVERHEAD);
+        }
+    }
+
+    /// Records the edge `reader -rw-> writer`, dooming a transaction it turns
+    /// into a pivot, or `actor` if that one has already committed.
+    fn conflict(&mut self, reader: u64, writer: u64, actor: u64) {
+        self.entry_mut(reader).out_conflict = true;
+        self.entry_mut(writer).in_conflict = true;
+        for id in [reader, writer] {
+            let entry = self.entry(id);
+            if entry.in_conflict && entry.out_conflict {
+                let doomed = match entry.commit {
+                    None => id,
+                    Some(_) => actor,
+                };
+                self.entry_mut(doomed).doomed = true;
+            }
+        }
+    }
+
+    /// Coarsens the reads of the transactions that read the most until the
+    /// tracked reads fit in `limit` bytes, or cannot shrink further.
+    fn fit(&mut self, limit: usize) -> usize {
+        let mut released = 0;
+        while self.reservation.size() > limit {
+            let widest = self
+                .txns
+                .values_mut()
+                .filter(|e| e.reads.len() > 1)
+                .max_by_key(|e| e.reads.len());
+            let Some(entry) = widest else {
+                break;
+            };
+            let bytes = entry.reads.coarsen();
+            self.reservation.shrink(bytes);
+            released += bytes;
+        }
+        released
+    }
+}
+
+impl Reclaim for ConflictTracker {
+    fn reclaim(&self, bytes: usize) -> usize {
+        let Ok(mut state) = self.state.try_lock() else {
+            return 0;
+        };
+        let limit = state.reservation.size().saturating_sub(bytes);
+        state.fit(limit)
+    }
+}
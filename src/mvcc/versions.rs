@@ -0,0 +1,300 @@
+//! Before-images of recently overwritten keys, kept for older snapshots.
+//!
+//! Every commit records, for each key it writes, the value the key held just
+//! before it. A snapshot taken at timestamp `t` reads a key as the oldest
+//! before-image committed after `t`, or as the current value if there is none.
+//! Versions older than every active snapshot are dropped.
+//!
+//! Versions are charged to the memory budget. When they would outgrow their
+//! share the oldest values are written to a spill file, keeping only their
+//! keys and timestamps in memory; the caller aborts old snapshots when even
+//! that is not enough.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{Snapshot, Timestamp, VersionOverflow};
+use crate::error::Result;
+use crate::memory::{MemoryBudget, Reclaim, Reservation};
+use crate::range::KeyRange;
+use crate::vfs::{Vfs, VfsFile};
+
+/// Approximate bytes of bookkeeping per version.
+const VERSION_OVERHEAD: usize = 64;
+
+/// Name of the spill file in the database directory.
+pub(crate) const SPILL_FILE: &str = "versions.spill";
+
+enum Stored {
+    /// The key did not exist.
+    Absent,
+    Inline(Vec<u8>),
+    Spilled {
+        offset: u64,
+        len: usize,
+    },
+}
+
+struct Version {
+    commit_ts: Timestamp,
+    value: Stored,
+}
+
+pub(crate) struct VersionStore {
+    state: RwLock<State>,
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+    limit: usize,
+    overflow: VersionOverflow,
+}
+
+struct State {
+    chains: BTreeMap<Vec<u8>, VecDeque<Version>>,
+    /// Versions in commit order, the order they are dropped and spilled in.
+    order: VecDeque<(Timestamp, Vec<u8>)>,
+    /// Entries of `order` before this index have had their value spilled.
+    spilled: usize,
+    spill: Option<Box<dyn VfsFile>>,
+    spill_len: u64,
+    reservation: Reservation,
+}
+
+fn charge(key: &[u8], value: &Option<Vec<u8>>) -> usize {
+    2 * key.len() + value.as_ref().map_or(0, Vec::len) + VERSION_OVERHEAD
+}
+
+impl VersionStore {
+    pub(crate) fn new(
+        vfs: Arc<dyn Vfs>,
+        path: PathBuf,
+        limit: usize,
+        overflow: VersionOverflow,
+        budget: &MemoryBudget,
+    ) -> Self {
+        VersionStore {
+            state: RwLock::new(State {
+                chains: BTreeMap::new(),
+                order: VecDeque::new(),
+                spilled: 0,
+                spill: None,
+                spill_len: 0,
+                reservation: budget.reservation(),
+            }),
+            vfs,
+            path,
+            limit,
+            overflow,
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap()
+    }
+
+    /// Value of `key` as of `snapshot`, or `None` if it is the current one.
+    pub(crate) fn lookup(
+        &self,
+        key: &[u8],
+        snapshot: &Snapshot,
+    ) -> Result<Option<Option<Vec<u8>>>> {
+        let state = self.read();
+        // Checked under the lock: once a snapshot is aborted the versions it
+        // needs may be dropped, but not while this lock is held.
+        snapshot.check()?;
+        let Some(chain) = state.chains.get(key) else {
+            return Ok(None);
+        };
+        match chain.iter().find(|v| v.commit_ts > snapshot.start) {
+            Some(version) => Ok(Some(self.load(&state, &version.value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// First key in `range` after `after`, and not after `until`, that has a
+    /// version newer than `snapshot`.
+    pub(crate) fn next_key(
+        &self,
+        range: &KeyRange,
+        after: Option<&[u8]>,
+        until: Option<&[u8]>,
+        snapshot: &Snapshot,
+    ) -> Result<Option<Vec<u8>>> {
+        let state = self.read();
+        snapshot.check()?;
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => range.as_bounds().0,
+        };
+        let keys = state
+            .chains
+            .range::<[u8], _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| {
+                !range.is_after(key) && until.is_none_or(|u| key.as_slice() <= u)
+            })
+            .filter(|(_, chain)| chain.back().is_some_and(|v| v.commit_ts > snapshot.start));
+        Ok(keys.map(|(key, _)| key.clone()).next())
+    }
+
+    /// Timestamp of the last commit that wrote `key`, if still retained.
+    pub(crate) fn last_commit(&self, key: &[u8]) -> Option<Timestamp> {
+        let state = self.read();
+        state.chains.get(key)?.back().map(|v| v.commit_ts)
+    }
+
+    /// Timestamps of the commits after `after` that wrote a key in `range`.
+    pub(crate) fn commits_in(&self, range: &KeyRange, after: Timestamp) -> BTreeSet<Timestamp> {
+        let state = self.read();
+        state
+            .chains
+            .range::<[u8], _>(range.as_bounds())
+            .flat_map(|(_, chain)| chain.iter().rev())
+            .map(|version| version.commit_ts)
+            .filter(|&commit_ts| commit_ts > after)
+            .collect()
+    }
+
+    /// Whether versions charging `bytes` more fit the store's share.
+    pub(crate) fn fits(&self, bytes: usize) -> bool {
+        self.read().reservation.size() + bytes <= self.limit
+    }
+
+    /// Bytes the versions of a commit writing `befores` are charged.
+    pub(crate) fn charge(befores: &[(Vec<u8>, Option<Vec<u8>>)]) -> usize {
+        befores.iter().map(|(key, value)| charge(key, value)).sum()
+    }
+
+    /// Records the values `befores` held before the commit at `commit_ts`.
+    pub(crate) fn insert(
+        &self,
+        commit_ts: Timestamp,
+        befores: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<()> {
+        let mut state = self.write();
+        state.reservation.grow(Self::charge(&befores))?;
+        for (key, value) in befores {
+            let value = match value {
+                Some(value) => Stored::Inline(value),
+                None => Stored::Absent,
+            };
+            state.order.push_back((commit_ts, key.clone()));
+            let version = Version { commit_ts, value };
+            state.chains.entry(key).or_default().push_back(version);
+        }
+        Ok(())
+    }
+
+    /// Drops the versions recorded for a commit at `commit_ts` that did not
+    /// take place.
+    pub(crate) fn remove_commit(&self, commit_ts: Timestamp) {
+        let mut state = self.write();
+        while state.order.back().is_some_and(|(ts, _)| *ts == commit_ts) {
+            let (_, key) = state.order.pop_back().unwrap();
+            let chain = state.chains.get_mut(&key).unwrap();
+            let version = chain.pop_back().unwrap();
+            if chain.is_empty() {
+                state.chains.remove(&key);
+            }
+            state.release(&key, version);
+        }
+        state.spilled = state.spilled.min(state.order.len());
+    }
+
+    /// Drops the versions no snapshot started at or after `horizon` needs.
+    pub(crate) fn collect(&self, horizon: Timestamp) -> Result<()> {
+        let mut state = self.write();
+        while state.order.front().is_some_and(|(ts, _)| *ts <= horizon) {
+            let (_, key) = state.order.pop_front().unwrap();
+            let chain = state.chains.get_mut(&key).unwrap();
+            let version = chain.pop_front().unwrap();
+            if chain.is_empty() {
+                state.chains.remove(&key);
+            }
+            state.release(&key, version);
+            state.spilled = state.spilled.saturating_sub(1);
+        }
+        if state.order.is_empty() && state.spill_len > 0 {
+            state.spill.as_ref().unwrap().set_size(0)?;
+            state.spill_len = 0;
+        }
+        Ok(())
+    }
+
+    /// Moves the values of the oldest versions to the spill file until at
+    /// least `bytes` have been released, returning the bytes released.
+    pub(crate) fn spill(&self, bytes: usize) -> Result<usize> {
+        if self.overflow != VersionOverflow::Spill {
+            return Ok(0);
+        }
+        self.spill_locked(&mut self.write(), bytes)
+    }
+
+    fn spill_locked(&self, state: &mut State, bytes: usize) -> Result<usize> {
+        let mut released = 0;
+        while released < bytes && state.spilled < state.order.len() {
+            let (ts, key) = &state.order[state.spilled];
+            let version = state.chains.get_mut(key).unwrap();
+            let version = version.iter_mut().find(|v| v.commit_ts == *ts).unwrap();
+            if let Stored::Inline(value) = &version.value {
+                if state.spill.is_none() {
+                    let file = self.vfs.open(&self.path, true)?;
+                    file.set_size(0)?;
+                    state.spill = Some(file);
+                }
+                let file = state.spill.as_ref().unwrap();
+                file.write_at(value, state.spill_len)?;
+                let len = value.len();
+                version.value = Stored::Spilled {
+                    offset: state.spill_len,
+                    len,
+                };
+                state.spill_len += len as u64;
+                state.reservation.shrink(len);
+                released += len;
+            }
+            state.spilled += 1;
+        }
+        Ok(released)
+    }
+
+    fn load(&self, state: &State, value: &Stored) -> Result<Option<Vec<u8>>> {
+        match value {
+            Stored::Absent => Ok(None),
+            Stored::Inline(value) => Ok(Some(value.clone())),
+            Stored::Spilled { offset, len } => {
+                let mut value = vec![0; *len];
+                let file = state.spill.as_ref().unwrap();
+                file.read_exact_at(&mut value, *offset)?;
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+impl State {
+    fn release(&mut self, key: &[u8], version: Version) {
+        let value = match version.value {
+            Stored::Inline(value) => Some(value),
+            _ => None,
+        };
+        self.reservation.shrink(charge(key, &value));
+    }
+}
+
+impl Reclaim for VersionStore {
+    fn reclaim(&self, bytes: usize) -> usize {
+        if self.overflow != VersionOverflow::Spill {
+            return 0;
+        }
+        let Ok(mut state) = self.state.try_write() else {
+            return 0;
+        };
+        // An I/O error only means nothing more can be spilled right now.
+        self.spill_locked(&mut state, bytes).unwrap_or(0)
+    }
+}
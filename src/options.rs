@@ -0,0 +1,262 @@
+//! Configuration supplied when a database is opened.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::buffer::Eviction;
+use crate::compression::Compression;
+use crate::engine::EngineKind;
+use crate::error::{Error, Result};
+use crate::lsm::{CompactionStyle, FilterKind};
+use crate::memory::MemoryBudget;
+use crate::merge::{MergeOperator, Operators};
+use crate::mvcc::VersionOverflow;
+use crate::vfs::{StdVfs, Vfs};
+use crate::wal::Durability;
+
+/// Default memory limit: 16 MiB.
+pub const DEFAULT_MEMORY_LIMIT: usize = 16 << 20;
+
+/// Default size of a storage page: 4 KiB.
+pub const DEFAULT_PAGE_SIZE: usize = 4 << 10;
+
+/// Smallest memory limit the database accepts: 256 KiB.
+pub const MIN_MEMORY_LIMIT: usize = 256 << 10;
+
+/// Smallest memory the operators of SQL queries may share: 64 KiB.
+pub const MIN_QUERY_MEMORY: usize = 64 << 10;
+
+/// Settings fixed for the lifetime of an open database.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Hard upper bound, in bytes, on the memory held by the database.
+    pub memory_limit: usize,
+    /// Storage engine used when the database is created.
+    pub engine: EngineKind,
+    /// Size of a storage page in bytes; a power of two between 512 bytes and
+    /// 32 KiB.
+    pub page_size: usize,
+    /// Bytes of the memory limit handed to the buffer pool. Defaults to a
+    /// quarter of `memory_limit`.
+    pub buffer_pool_size: Option<usize>,
+    /// Policy the buffer pool uses to choose pages to evict.
+    pub eviction: Eviction,
+    /// Size at which the LSM memtable is flushed to disk. Defaults to an
+    /// eighth of `memory_limit`.
+    pub memtable_size: Option<usize>,
+    /// How the LSM tree merges or drops its tables.
+    pub compaction: CompactionStyle,
+    /// Number of level 0 tables that triggers a compaction into level 1, or
+    /// with tiered compaction the number of sorted runs that triggers a
+    /// merge.
+    pub l0_compaction_trigger: usize,
+    /// Size at which compaction output is split into a new table file.
+    pub target_file_size: usize,
+    /// Bytes per second compactions may read and write together, so that
+    /// they leave disk bandwidth to queries. Defaults to no limit; flushes of
+    /// the memtable are never limited.
+    pub compaction_rate_limit: Option<u64>,
+    /// Filter written into every LSM table file so that point lookups skip
+    /// the files that cannot hold the key.
+    pub filter: FilterKind,
+    /// Bits of filter per key, between 1 and 32. More bits make fewer lookups
+    /// read a table needlessly but take more memory once the filter is loaded.
+    pub filter_bits_per_key: u32,
+    /// Also writes a filter for every data block. Lookups check it before
+    /// reading the block when the filter of the whole table does not fit in
+    /// memory.
+    pub block_filters: bool,
+    /// Codec that compresses the blocks of LSM table files.
+    pub compression: Compression,
+    /// Size of the dictionary trained for every LSM table file and used to
+    /// compress its blocks, which helps most when values are small. Zero, the
+    /// default, trains none. Training holds back 32 times as many bytes of
+    /// blocks while a table is written.
+    pub compression_dictionary_size: usize,
+    /// Durability of writes that do not ask for one explicitly.
+    pub durability: Durability,
+    /// Merge operators that merges may name, besides the built-in
+    /// [`Counter`](crate::merge::Counter) and
+    /// [`Append`](crate::merge::Append). Names must be unique. A database
+    /// holding operands must be opened with the operators they name.
+    pub merge_operators: Vec<Arc<dyn MergeOperator>>,
+    /// Size of the write-ahead log buffer. Two buffers are reserved so that
+    /// writers can keep appending while one is written out. Defaults to a
+    /// thirty-second of `memory_limit`.
+    pub wal_buffer_size: Option<usize>,
+    /// Size at which the write-ahead log moves on to a new segment file.
+    pub wal_segment_size: usize,
+    /// File system the database files are stored in. Defaults to [`StdVfs`];
+    /// [`DirectVfs`](crate::DirectVfs) keeps the kernel from caching them.
+    pub vfs: Arc<dyn Vfs>,
+    /// Bytes the versions kept for transaction snapshots may hold in memory.
+    /// Defaults to a sixteenth of `memory_limit`.
+    pub version_store_size: Option<usize>,
+    /// What happens to old versions once they fill `version_store_size`.
+    pub version_overflow: VersionOverflow,
+    /// Bytes the reads tracked for serializable transactions may hold before
+    /// they are coarsened into wider ranges. Defaults to a thirty-second of
+    /// `memory_limit`.
+    pub conflict_tracking_size: Option<usize>,
+    /// Bytes the operators of running SQL queries may be granted together;
+    /// queries that would exceed it wait. Defaults to a quarter of
+    /// `memory_limit`.
+    pub query_memory_size: Option<usize>,
+    /// Number of SQL queries that may run at once; others wait. Defaults to
+    /// no limit.
+    pub max_concurrent_queries: Option<usize>,
+    /// How long a SQL query may wait to be admitted before it fails. Defaults
+    /// to waiting as long as it takes.
+    pub query_timeout: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            engine: EngineKind::default(),
+            page_size: DEFAULT_PAGE_SIZE,
+            buffer_pool_size: None,
+            eviction: Eviction::default(),
+            memtable_size: None,
+            compaction: CompactionStyle::default(),
+            l0_compaction_trigger: 4,
+            target_file_size: 2 << 20,
+            compaction_rate_limit: None,
+            filter: FilterKind::default(),
+            filter_bits_per_key: 10,
+            block_filters: false,
+            compression: Compression::default(),
+            compression_dictionary_size: 0,
+            durability: Durability::default(),
+            merge_operators: Vec::new(),
+            wal_buffer_size: None,
+            wal_segment_size: 4 << 20,
+            vfs: Arc::new(StdVfs),
+            version_store_size: None,
+            version_overflow: VersionOverflow::default(),
+            conflict_tracking_size: None,
+            query_memory_size: None,
+            max_concurrent_queries: None,
+            query_timeout: None,
+        }
+    }
+}
+
+impl Options {
+    /// Checks that the options describe a database that can be opened.
+    pub fn validate(&self) -> Result<()> {
+        if self.memory_limit < MIN_MEMORY_LIMIT {
+            return Err(Error::invalid(format!(
+                "memory_limit must be at least {MIN_MEMORY_LIMIT} bytes"
+            )));
+        }
+        if !self.page_size.is_power_of_two() || !(512..=1 << 15).contains(&self.page_size) {
+            return Err(Error::invalid(
+                "page_size must be a power of two between 512 bytes and 32 KiB",
+            ));
+        }
+        let pool = self.buffer_pool_bytes();
+        if pool < self.page_size || pool > self.memory_limit / 2 {
+            return Err(Error::invalid(
+                "buffer_pool_size must hold a page and leave half of memory_limit free",
+            ));
+        }
+        if matches!(self.eviction, Eviction::LruK { k: 0 }) {
+            return Err(Error::invalid("LRU-K needs k of at least 1"));
+        }
+        let memtable = self.memtable_bytes();
+        if memtable < self.page_size || memtable > self.memory_limit / 2 {
+            return Err(Error::invalid(
+                "memtable_size must hold a page and leave half of memory_limit free",
+            ));
+        }
+        if self.l0_compaction_trigger == 0 {
+            return Err(Error::invalid("l0_compaction_trigger must be at least 1"));
+        }
+        if self.target_file_size < self.page_size {
+            return Err(Error::invalid("target_file_size must hold at least a page"));
+        }
+        if self.compaction_rate_limit == Some(0) {
+            return Err(Error::invalid("compaction_rate_limit must be at least 1"));
+        }
+        if !(1..=32).contains(&self.filter_bits_per_key) {
+            return Err(Error::invalid(
+                "filter_bits_per_key must be between 1 and 32",
+            ));
+        }
+        if matches!(self.compression, Compression::Zstd { level } if !(1..=22).contains(&level)) {
+            return Err(Error::invalid(
+                "Zstd compression level must be between 1 and 22",
+            ));
+        }
+        if self.compression_dictionary_size > self.memory_limit / 64 {
+            return Err(Error::invalid(
+                "compression_dictionary_size must be at most a sixty-fourth of memory_limit",
+            ));
+        }
+        Operators::validate(&self.merge_operators)?;
+        let wal_buffer = self.wal_buffer_bytes();
+        if wal_buffer < self.page_size || wal_buffer > self.memory_limit / 8 {
+            return Err(Error::invalid(
+                "wal_buffer_size must hold a page and at most an eighth of memory_limit",
+            ));
+        }
+        if self.wal_segment_size < self.page_size {
+            return Err(Error::invalid("wal_segment_size must hold at least a page"));
+        }
+        if self.version_store_bytes() + self.conflict_tracking_bytes() > self.memory_limit / 2 {
+            return Err(Error::invalid(
+                "version_store_size and conflict_tracking_size must leave half of memory_limit free",
+            ));
+        }
+        let query_memory = self.query_memory_bytes();
+        if query_memory < MIN_QUERY_MEMORY || query_memory > self.memory_limit / 2 {
+            return Err(Error::invalid(format!(
+                "query_memory_size must be at least {MIN_QUERY_MEMORY} bytes and at most half of memory_limit"
+            )));
+        }
+        if self.max_concurrent_queries == Some(0) {
+            return Err(Error::invalid("max_concurrent_queries must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Bytes the LSM memtable may hold before it is flushed.
+    pub fn memtable_bytes(&self) -> usize {
+        self.memtable_size.unwrap_or(self.memory_limit / 8)
+    }
+
+    /// Bytes of each of the two write-ahead log buffers.
+    pub fn wal_buffer_bytes(&self) -> usize {
+        self.wal_buffer_size.unwrap_or(self.memory_limit / 32)
+    }
+
+    /// Bytes the version store may hold in memory.
+    pub fn version_store_bytes(&self) -> usize {
+        self.version_store_size.unwrap_or(self.memory_limit / 16)
+    }
+
+    /// Bytes the reads tracked for serializable transactions may hold.
+    pub fn conflict_tracking_bytes(&self) -> usize {
+        self.conflict_tracking_size
+            .unwrap_or(self.memory_limit / 32)
+    }
+
+    /// Bytes reserved for the buffer pool.
+    pub fn buffer_pool_bytes(&self) -> usize {
+        self.buffer_pool_size.unwrap_or(self.memory_limit / 4)
+    }
+
+    /// Bytes the operators of running SQL queries may be granted together.
+    pub fn query_memory_bytes(&self) -> usize {
+        self.query_memory_size.unwrap_or(self.memory_limit / 4)
+    }
+
+    /// Validates the options and creates the budget every component of the
+    /// database will reserve from.
+    pub fn memory_budget(&self) -> Result<MemoryBudget> {
+        self.validate()?;
+        Ok(MemoryBudget::new(self.memory_limit))
+    }
+}
@@ -0,0 +1,136 @@
+//! Key ranges accepted by scans.
+
+use std::ops::{Bound, Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+
+/// Owned range of byte keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+    /// Lower bound of the range.
+    pub start: Bound<Vec<u8>>,
+    /// Upper bound of the range.
+    pub end: Bound<Vec<u8>>,
+}
+
+impl KeyRange {
+    /// Range covering every key.
+    pub fn all() -> Self {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Range between two borrowed bounds.
+    pub fn new(start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self {
+        KeyRange {
+            start: start.map(<[u8]>::to_vec),
+            end: end.map(<[u8]>::to_vec),
+        }
+    }
+
+    /// Range covering every key starting with `prefix`.
+    pub fn prefix(prefix: &[u8]) -> Self {
+        let end = match prefix_successor(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        KeyRange {
+            start: Bound::Included(prefix.to_vec()),
+            end,
+        }
+    }
+
+    /// Whether `key` lies inside the range.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        !self.is_before(key) && !self.is_after(key)
+    }
+
+    /// Whether `key` sorts before the start of the range.
+    pub fn is_before(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key < start.as_slice(),
+            Bound::Excluded(start) => key <= start.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Whether `key` sorts after the end of the range.
+    pub fn is_after(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_slice(),
+            Bound::Excluded(end) => key >= end.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Borrowed view of the bounds, as accepted by `BTreeMap::range`.
+    pub fn as_bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (
+            self.start.as_ref().map(Vec::as_slice),
+            self.end.as_ref().map(Vec::as_slice),
+        )
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, if any.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl From<RangeFull> for KeyRange {
+    fn from(_: RangeFull) -> Self {
+        KeyRange::all()
+    }
+}
+
+impl From<Range<&[u8]>> for KeyRange {
+    fn from(r: Range<&[u8]>) -> Self {
+        KeyRange::new(Bound::Included(r.start), Bound::Excluded(r.end))
+    }
+}
+
+impl From<RangeInclusive<&[u8]>> for KeyRange {
+    fn from(r: RangeInclusive<&[u8]>) -> Self {
+        KeyRange::new(Bound::Included(r.start()), Bound::Included(r.end()))
+    }
+}
+
+impl From<RangeFrom<&[u8]>> for KeyRange {
+    fn from(r: RangeFrom<&[u8]>) -> Self {
+        KeyRange::new(Bound::Included(r.start), Bound::Unbounded)
+    }
+}
+
+impl From<RangeTo<&[u8]>> for KeyRange {
+    fn from(r: RangeTo<&[u8]>) -> Self {
+        KeyRange::new(Bound::Unbounded, Bound::Excluded(r.end))
+    }
+}
+
+impl From<RangeToInclusive<&[u8]>> for KeyRange {
+    fn from(r: RangeToInclusive<&[u8]>) -> Self {
+        KeyRange::new(Bound::Unbounded, Bound::Included(r.end))
+    }
+}
+
+impl From<Range<Vec<u8>>> for KeyRange {
+    fn from(r: Range<Vec<u8>>) -> Self {
+        KeyRange {
+            start: Bound::Included(r.start),
+            end: Bound::Excluded(r.end),
+        }
+    }
+}
+
+impl From<(Bound<Vec<u8>>, Bound<Vec<u8>>)> for KeyRange {
+    fn from((start, end): (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self {
+        KeyRange { start, end }
+    }
+}
@@ -0,0 +1,348 @@
+//! Admission of queries against the memory set aside for them.
+//!
+//! A query is planned to run within [`Options::query_memory_size`] and, before
+//! it produces a row, asks a [`QueryMemory`] for the sum of the grants of its
+//! operators: all of it ideally, and at least the minimums they can run with.
+//! The query is admitted at once if that minimum is free, fewer than
+//! [`Options::max_concurrent_queries`] run and no query waits ahead of it,
+//! with as much of the rest as is free; it is queued otherwise, until running
+//! queries hand back enough; and it is rejected outright if its minimum
+//! exceeds the whole of query memory. Admitted bytes are also reserved from
+//! the database's [`MemoryBudget`], so queries never hold more than the
+//! budget allows whatever else the database keeps.
+//!
+//! Queued queries are admitted by [`Priority`], and in arrival order within
+//! a priority: a query never overtakes one of its own class, so a large
+//! query is not starved by a stream of small ones, but any query of a higher
+//! class goes first. A query that waits longer than
+//! [`Options::query_timeout`] gives up with [`Error::QueueTimeout`].
+//!
+//! Each operator takes its grant out of the query's before it runs and, once
+//! it is done, reports how much of it it actually used and hands it back, so
+//! that queued queries need not wait for the whole of a long query to end.
+//!
+//! [`Options::query_memory_size`]: crate::Options::query_memory_size
+//! [`Options::max_concurrent_queries`]: crate::Options::max_concurrent_queries
+//! [`Options::query_timeout`]: crate::Options::query_timeout
+
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reservation};
+use crate::options::Options;
+
+/// Class of a query, deciding which of the queued queries is admitted first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Background work, admitted once no other query waits.
+    Low,
+    #[default]
+    Normal,
+    /// Interactive work, admitted ahead of every other queued query.
+    High,
+}
+
+/// Position of a queued query: higher priorities first, then by arrival.
+type Ticket = (Reverse<Priority>, u64);
+
+/// Memory shared by the operators of running queries.
+///
+/// Cloning is cheap; all clones admit against the same memory.
+#[derive(Clone)]
+pub struct QueryMemory {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    limit: usize,
+    max_running: usize,
+    timeout: Option<Duration>,
+    budget: MemoryBudget,
+    state: Mutex<State>,
+    /// Signalled when memory or a slot is handed back, or the head of the
+    /// queue moves.
+    freed: Condvar,
+}
+
+struct State {
+    granted: usize,
+    running: usize,
+    /// Tickets of the queued queries, in the order they are to be admitted.
+    queue: BTreeSet<Ticket>,
+    next_ticket: u64,
+    stats: QueryMemoryStats,
+}
+
+impl State {
+    /// Whether a query needing at least `min` bytes can run now.
+    fn fits(&self, inner: &Inner, min: usize) -> bool {
+        inner.limit - self.granted >= min && self.running < inner.max_running
+    }
+}
+
+/// Counters of a [`QueryMemory`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryMemoryStats {
+    /// Queries admitted, whether at once or after waiting.
+    pub admitted: u64,
+    /// Queries that had to wait to be admitted.
+    pub queued: u64,
+    /// Queries whose minimum exceeded the whole of query memory.
+    pub rejected: u64,
+    /// Queries that gave up waiting to be admitted.
+    pub timed_out: u64,
+    /// Queries currently running.
+    pub running: usize,
+    /// Bytes currently granted to running queries.
+    pub granted: usize,
+    /// Bytes granted to operators that have finished, and the bytes they
+    /// actually used at their peak.
+    pub operator_grants: u64,
+    pub operator_usage: u64,
+}
+
+/// Memory granted to, and used by, one operator of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperatorUsage {
+    /// Position of the operator among the lines of the `EXPLAIN` output.
+    pub step: usize,
+    pub granted: usize,
+    /// Most bytes the operator held at once, or an upper bound of them for
+    /// operators that do not track their memory exactly. It exceeds the
+    /// grant for an operator that had to take more from the budget because
+    /// the estimates it was planned with were off.
+    pub used: usize,
+}
+
+impl QueryMemory {
+    /// Admits queries against the query memory and limits of `options`,
+    /// reserving what they are granted from `budget`.
+    pub fn new(options: &Options, budget: &MemoryBudget) -> Self {
+        QueryMemory {
+            inner: Arc::new(Inner {
+                limit: options.query_memory_bytes(),
+                max_running: options.max_concurrent_queries.unwrap_or(usize::MAX),
+                timeout: options.query_timeout,
+                budget: budget.clone(),
+                state: Mutex::new(State {
+                    granted: 0,
+                    running: 0,
+                    queue: BTreeSet::new(),
+                    next_ticket: 0,
+                    stats: QueryMemoryStats::default(),
+                }),
+                freed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Bytes all running queries may be granted together.
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    pub fn stats(&self) -> QueryMemoryStats {
+        self.lock().stats
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap()
+    }
+
+    /// Admits a query of class `priority` needing at least `min` bytes and
+    /// at most `ideal`, waiting while less than `min` is free, all slots are
+    /// taken or other queries wait ahead.
+    pub fn admit(&self, min: usize, ideal: usize, priority: Priority) -> Result<QueryGrant> {
+        let inner = &*self.inner;
+        let limit = inner.limit;
+        let mut state = self.lock();
+        if min > limit {
+            state.stats.rejected += 1;
+            return Err(Error::invalid(format!(
+                "query needs at least {min} bytes of memory, but queries may only be granted {limit}"
+            )));
+        }
+        let ticket = (Reverse(priority), state.next_ticket);
+        state.next_ticket += 1;
+        let ahead = |state: &State| state.queue.first().is_some_and(|first| *first < ticket);
+        if ahead(&state) || !state.fits(inner, min) {
+            state.queue.insert(ticket);
+            state.stats.queued += 1;
+            let start = Instant::now();
+            while ahead(&state) || !state.fits(inner, min) {
+                state = match inner.timeout {
+                    None => inner.freed.wait(state).unwrap(),
+                    Some(timeout) => {
+                        let waited = start.elapsed();
+                        if waited >= timeout {
+                            state.queue.remove(&ticket);
+                            state.stats.timed_out += 1;
+                            drop(state);
+                            // The queries behind may now be at the head.
+                            inner.freed.notify_all();
+                            return Err(Error::QueueTimeout { waited });
+                        }
+                        inner.freed.wait_timeout(state, timeout - waited).unwrap().0
+                    }
+                };
+            }
+            state.queue.remove(&ticket);
+            // The next query in line may fit in what is left.
+            inner.freed.notify_all();
+        }
+        let bytes = ideal.clamp(min, limit - state.granted);
+        state.granted += bytes;
+        state.running += 1;
+        state.stats.granted = state.granted;
+        state.stats.running = state.running;
+        state.stats.admitted += 1;
+        drop(state);
+        let reservation = match inner.budget.reserve(bytes) {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                self.release(bytes);
+                self.end();
+                return Err(e.into());
+            }
+        };
+        Ok(QueryGrant {
+            memory: self.clone(),
+            reservation,
+            usage: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Hands back `bytes` of grants and wakes the queued queries.
+    fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let mut state = self.lock();
+        state.granted -= bytes;
+        state.stats.granted = state.granted;
+        drop(state);
+        self.inner.freed.notify_all();
+    }
+
+    /// Hands back the slot of a query that ended.
+    fn end(&self) {
+        let mut state = self.lock();
+        state.running -= 1;
+        state.stats.running = state.running;
+        drop(state);
+        self.inner.freed.notify_all();
+    }
+
+    fn report(&self, usage: &OperatorUsage) {
+        let mut state = self.lock();
+        state.stats.operator_grants += usage.granted as u64;
+        state.stats.operator_usage += usage.used as u64;
+    }
+}
+
+/// Memory admitted for a query, out of which its operators take their
+/// grants. What they have not taken, and the query's slot, are handed back
+/// when it is dropped.
+pub struct QueryGrant {
+    memory: QueryMemory,
+    reservation: Reservation,
+    usage: Arc<Mutex<Vec<OperatorUsage>>>,
+}
+
+impl QueryGrant {
+    /// Bytes not yet taken by operators.
+    pub fn size(&self) -> usize {
+        self.reservation.size()
+    }
+
+    /// Memory granted to and used by each operator so far.
+    pub fn usage(&self) -> Vec<OperatorUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Takes `bytes` for the operator at `step` of the plan.
+    pub(crate) fn operator(&mut self, step: usize, bytes: usize) -> OperatorGrant {
+        let reservation = self.reservation.split(bytes);
+        let mut usage = self.usage.lock().unwrap();
+        usage.push(OperatorUsage {
+            step,
+            granted: reservation.size(),
+            used: 0,
+        });
+        OperatorGrant {
+            memory: self.memory.clone(),
+            usage: Arc::clone(&self.usage),
+            index: usage.len() - 1,
+            granted: reservation.size(),
+            used: 0,
+            reservation: Some(reservation),
+            finished: false,
+        }
+    }
+}
+
+impl Drop for QueryGrant {
+    fn drop(&mut self) {
+        self.memory.release(self.reservation.size());
+        self.memory.end();
+    }
+}
+
+/// Grant of one operator of a query.
+pub(crate) struct OperatorGrant {
+    memory: QueryMemory,
+    usage: Arc<Mutex<Vec<OperatorUsage>>>,
+    index: usize,
+    granted: usize,
+    used: usize,
+    /// Reservation backing the grant, until it is handed to the operator.
+    reservation: Option<Reservation>,
+    finished: bool,
+}
+
+impl OperatorGrant {
+    /// Bytes granted.
+    pub(crate) fn size(&self) -> usize {
+        self.granted
+    }
+
+    /// Reservation backing the grant, for an operator that holds it itself.
+    pub(crate) fn take(&mut self) -> Reservation {
+        self.reservation.take().expect("grant already taken")
+    }
+
+    /// Reservation backing the grant, for an operator growing it past the
+    /// grant itself.
+    pub(crate) fn reservation(&mut self) -> &mut Reservation {
+        self.reservation.as_mut().expect("grant already taken")
+    }
+
+    /// Records that the operator holds `bytes`.
+    pub(crate) fn record(&mut self, bytes: usize) {
+        if bytes > self.used {
+            self.used = bytes;
+            self.usage.lock().unwrap()[self.index].used = bytes;
+        }
+    }
+
+    /// Ends the operator's use of the grant, reporting its usage and
+    /// handing the grant back.
+    pub(crate) fn finish(&mut self) {
+        if std::mem::replace(&mut self.finished, true) {
+            return;
+        }
+        self.reservation = None;
+        let usage = self.usage.lock().unwrap()[self.index];
+        self.memory.report(&usage);
+        self.memory.release(self.granted);
+    }
+}
+
+impl Drop for OperatorGrant {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
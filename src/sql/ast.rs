@@ -0,0 +1,266 @@
+//! Syntax tree of parsed SQL statements.
+//!
+//! Names are as written, lowercased unless quoted; nothing is checked against
+//! the catalog until the statement is planned.
+
+use std::fmt;
+
+use super::value::{DataType, Value};
+
+/// Statement accepted by [`parse`](super::parse).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    CreateTable(CreateTable),
+    CreateIndex(CreateIndex),
+    Insert(Insert),
+    Update(Update),
+    Delete(Delete),
+    Select(Box<Select>),
+    /// `ANALYZE` of a table, or of every table if none is named.
+    Analyze(Option<String>),
+    /// `EXPLAIN` of the physical plan chosen for a statement.
+    Explain(Box<Statement>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    /// Columns of a `PRIMARY KEY (...)` clause.
+    pub primary_key: Vec<String>,
+    pub if_not_exists: bool,
+}
+
+/// `CREATE [UNIQUE] INDEX` on columns or expressions of a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    /// Expressions the index is keyed by, in key order.
+    pub exprs: Vec<Expr>,
+    pub unique: bool,
+    pub if_not_exists: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: DataType,
+    pub not_null: bool,
+    /// Declared with `PRIMARY KEY` after its type.
+    pub primary_key: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insert {
+    pub table: String,
+    /// Columns the source fills, in order; every column when absent.
+    pub columns: Option<Vec<String>>,
+    pub source: InsertSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsertSource {
+    Values(Vec<Vec<Expr>>),
+    Select(Box<Select>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Update {
+    pub table: String,
+    pub assignments: Vec<(String, Expr)>,
+    pub filter: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delete {
+    pub table: String,
+    pub filter: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    pub distinct: bool,
+    pub items: Vec<SelectItem>,
+    /// Absent for a `SELECT` of expressions alone.
+    pub from: Option<TableExpr>,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderBy>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    /// `*`
+    Wildcard,
+    /// `table.*`
+    QualifiedWildcard(String),
+    Expr {
+        expr: Expr,
+        alias: Option<String>,
+    },
+}
+
+/// Table or join of tables in a `FROM` clause.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableExpr {
+    Table {
+        name: String,
+        alias: Option<String>,
+    },
+    /// Tables listed with commas are joined with [`JoinKind::Cross`].
+    Join {
+        left: Box<TableExpr>,
+        right: Box<TableExpr>,
+        kind: JoinKind,
+        on: Option<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    Inner,
+    Cross,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Column {
+        table: Option<String>,
+        name: String,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+    /// Call of a function; `star` for `COUNT(*)`.
+    Function {
+        name: String,
+        args: Vec<Expr>,
+        star: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    /// Binding strength; operators that bind tighter have higher ones.
+    pub(crate) fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq
+            | BinaryOp::NotEq
+            | BinaryOp::Lt
+            | BinaryOp::LtEq
+            | BinaryOp::Gt
+            | BinaryOp::GtEq => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 6,
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOp::Or => "OR",
+            BinaryOp::And => "AND",
+            BinaryOp::Eq => "=",
+            BinaryOp::NotEq => "<>",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+        })
+    }
+}
+
+/// Writes the expression back as SQL, parenthesizing every operation.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Literal(value) => value.fmt(f),
+            Expr::Column {
+                table: Some(table),
+                name,
+            } => write!(f, "{table}.{name}"),
+            Expr::Column { table: None, name } => f.write_str(name),
+            Expr::Unary {
+                op: UnaryOp::Not,
+                expr,
+            } => write!(f, "(NOT {expr})"),
+            Expr::Unary {
+                op: UnaryOp::Neg,
+                expr,
+            } => write!(f, "(-{expr})"),
+            Expr::Binary { op, left, right } => write!(f, "({left} {op} {right})"),
+            Expr::IsNull {
+                expr,
+                negated: false,
+            } => write!(f, "({expr} IS NULL)"),
+            Expr::IsNull {
+                expr,
+                negated: true,
+            } => write!(f, "({expr} IS NOT NULL)"),
+            Expr::Function {
+                name, star: true, ..
+            } => write!(f, "{name}(*)"),
+            Expr::Function { name, args, .. } => {
+                write!(f, "{name}(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    arg.fmt(f)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Schemas of the tables and indexes statements are planned against.
+
+use std::collections::BTreeMap;
+
+use super::ast::{BinaryOp, UnaryOp};
+use super::plan::Scalar;
+use super::row::{decode_row, row_bytes};
+use super::statistics::TableStatistics;
+use super::value::DataType;
+use crate::coding::{put_bytes, put_varint, Reader};
+use crate::error::{Error, Result};
+
+/// Operators in the order of their tags in stored expressions.
+const BINARY_OPS: [BinaryOp; 13] = [
+    BinaryOp::Or,
+    BinaryOp::And,
+    BinaryOp::Eq,
+    BinaryOp::NotEq,
+    BinaryOp::Lt,
+    BinaryOp::LtEq,
+    BinaryOp::Gt,
+    BinaryOp::GtEq,
+    BinaryOp::Add,
+    BinaryOp::Sub,
+    BinaryOp::Mul,
+    BinaryOp::Div,
+    BinaryOp::Rem,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub data_type: DataType,
+    /// Whether `NULL` is rejected; always true of primary key columns.
+    pub not_null: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSchema {
+    pub name: String,
+    pub columns: Vec<Column>,
+    /// Positions of the primary key columns, in key order. Empty for a table
+    /// without a primary key, whose rows are keyed by a hidden row id.
+    pub primary_key: Vec<usize>,
+}
+
+impl TableSchema {
+    /// Position of the column called `name`.
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    /// Encoding of the schema as it is stored.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_bytes(&mut out, self.name.as_bytes());
+        put_varint(&mut out, self.columns.len() as u64);
+        for column in &self.columns {
+            put_bytes(&mut out, column.name.as_bytes());
+            out.push(match column.data_type {
+                DataType::Integer => 0,
+                DataType::Text => 1,
+                DataType::Boolean => 2,
+            });
+            out.push(column.not_null as u8);
+        }
+        put_varint(&mut out, self.primary_key.len() as u64);
+        for &i in &self.primary_key {
+            put_varint(&mut out, i as u64);
+        }
+        out
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(buf);
+        let name = text(reader.bytes()?)?;
+        let count = reader.varint()? as usize;
+        let mut columns = Vec::with_capacity(count.min(buf.len()));
+        for _ in 0..count {
+            let name = text(reader.bytes()?)?;
+            let data_type = match reader.u8()? {
+                0 => DataType::Integer,
+                1 => DataType::Text,
+                2 => DataType::Boolean,
+                tag => return Err(Error::corruption(format!("unknown column type {tag}"))),
+            };
+            let not_null = reader.u8()? != 0;
+            columns.push(Column {
+                name,
+                data_type,
+                not_null,
+            });
+        }
+        let count = reader.varint()? as usize;
+        let mut primary_key = Vec::with_capacity(count.min(columns.len()));
+        for _ in 0..count {
+            let i = reader.varint()? as usize;
+            if i >= columns.len() {
+                return Err(Error::corruption("primary key column out of range"));
+            }
+            primary_key.push(i);
+        }
+        Ok(TableSchema {
+            name,
+            columns,
+            primary_key,
+        })
+    }
+}
+
+/// Secondary index of a table.
+///
+/// Each row of the table has an entry keyed by the values of `exprs` over
+/// it followed by its primary key, so that rows can be found in the order
+/// of those values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSchema {
+    pub name: String,
+    pub table: String,
+    /// Expressions over the columns of the table, in key order.
+    pub exprs: Vec<Scalar>,
+    /// Whether no two rows may have the same values, unless one of them is
+    /// `NULL`.
+    pub unique: bool,
+}
+
+impl IndexSchema {
+    /// Position of the column of the table that the key expression at `i`
+    /// is, if it is a plain column.
+    pub fn column(&self, i: usize) -> Option<usize> {
+        match self.exprs[i] {
+            Scalar::Column(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Encoding of the index as it is stored.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_bytes(&mut out, self.name.as_bytes());
+        put_bytes(&mut out, self.table.as_bytes());
+        out.push(self.unique as u8);
+        put_varint(&mut out, self.exprs.len() as u64);
+        for expr in &self.exprs {
+            encode_scalar(expr, &mut out);
+        }
+        out
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(buf);
+        let name = text(reader.bytes()?)?;
+        let table = text(reader.bytes()?)?;
+        let unique = reader.u8()? != 0;
+        let count = reader.varint()? as usize;
+        let mut exprs = Vec::with_capacity(count.min(buf.len()));
+        for _ in 0..count {
+            exprs.push(decode_scalar(&mut reader)?);
+        }
+        Ok(IndexSchema {
+            name,
+            table,
+            exprs,
+            unique,
+        })
+    }
+}
+
+fn encode_scalar(expr: &Scalar, out: &mut Vec<u8>) {
+    match expr {
+        Scalar::Literal(value) => {
+            out.push(0);
+            put_bytes(out, &row_bytes(std::slice::from_ref(value)));
+        }
+        Scalar::Column(i) => {
+            out.push(1);
+            put_varint(out, *i as u64);
+        }
+        Scalar::Unary { op, expr } => {
+            out.push(2);
+            out.push(match op {
+                UnaryOp::Not => 0,
+                UnaryOp::Neg => 1,
+            });
+            encode_scalar(expr, out);
+        }
+        Scalar::Binary { op, left, right } => {
+            out.push(3);
+            out.push(BINARY_OPS.iter().position(|o| o == op).unwrap() as u8);
+            encode_scalar(left, out);
+            encode_scalar(right, out);
+        }
+        Scalar::IsNull { expr, negated } => {
+            out.push(4);
+            out.push(*negated as u8);
+            encode_scalar(expr, out);
+        }
+    }
+}
+
+fn decode_scalar(reader: &mut Reader<'_>) -> Result<Scalar> {
+    Ok(match reader.u8()? {
+        0 => match &decode_row(reader.bytes()?)?[..] {
+            [value] => Scalar::Literal(value.clone()),
+            _ => return Err(Error::corruption("literal is not a single value")),
+        },
+        1 => Scalar::Column(reader.varint()? as usize),
+        2 => {
+            let op = match reader.u8()? {
+                0 => UnaryOp::Not,
+                1 => UnaryOp::Neg,
+                tag => return Err(Error::corruption(format!("unknown unary operator {tag}"))),
+            };
+            Scalar::Unary {
+                op,
+                expr: Box::new(decode_scalar(reader)?),
+            }
+        }
+        3 => {
+            let tag = reader.u8()?;
+            let Some(&op) = BINARY_OPS.get(tag as usize) else {
+                return Err(Error::corruption(format!("unknown binary operator {tag}")));
+            };
+            Scalar::Binary {
+                op,
+                left: Box::new(decode_scalar(reader)?),
+                right: Box::new(decode_scalar(reader)?),
+            }
+        }
+        4 => {
+            let negated = reader.u8()? != 0;
+            Scalar::IsNull {
+                expr: Box::new(decode_scalar(reader)?),
+                negated,
+            }
+        }
+        tag => return Err(Error::corruption(format!("unknown expression tag {tag}"))),
+    })
+}
+
+fn text(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::corruption("name is not UTF-8"))
+}
+
+/// Tables and indexes known to the planner, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    tables: BTreeMap<String, TableSchema>,
+    indexes: BTreeMap<String, IndexSchema>,
+    statistics: BTreeMap<String, TableStatistics>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableSchema> {
+        self.tables.get(name)
+    }
+
+    /// Schemas of every table, in name order.
+    pub fn tables(&self) -> impl Iterator<Item = &TableSchema> {
+        self.tables.values()
+    }
+
+    /// Adds a table, failing if one of the same name exists.
+    pub fn create(&mut self, schema: TableSchema) -> Result<()> {
+        if self.tables.contains_key(&schema.name) {
+            return Err(Error::invalid(format!(
+                "table {} already exists",
+                schema.name
+            )));
+        }
+        self.tables.insert(schema.name.clone(), schema);
+        Ok(())
+    }
+
+    pub fn index(&self, name: &str) -> Option<&IndexSchema> {
+        self.indexes.get(name)
+    }
+
+    /// Indexes of the table `table`, in name order.
+    pub fn indexes<'a>(&'a self, table: &'a str) -> impl Iterator<Item = &'a IndexSchema> {
+        self.indexes
+            .values()
+            .filter(move |index| index.table == table)
+    }
+
+    /// Adds an index, failing if one of the same name exists or its table
+    /// does not.
+    pub fn create_index(&mut self, index: IndexSchema) -> Result<()> {
+        if self.indexes.contains_key(&index.name) {
+            return Err(Error::invalid(format!(
+                "index {} already exists",
+                index.name
+            )));
+        }
+        if !self.tables.contains_key(&index.table) {
+            return Err(Error::invalid(format!("unknown table {}", index.table)));
+        }
+        self.indexes.insert(index.name.clone(), index);
+        Ok(())
+    }
+
+    /// Statistics of a table, or guesses if it was never analyzed.
+    pub fn statistics(&self, table: &str) -> TableStatistics {
+        self.statistics.get(table).cloned().unwrap_or_default()
+    }
+
+    pub fn set_statistics(&mut self, table: &str, statistics: TableStatistics) {
+        self.statistics.insert(table.to_owned(), statistics);
+    }
+}
@@ -0,0 +1,736 @@
+//! SQL database over a [`TransactionDb`].
+//!
+//! Statements run inside a transaction the caller begins and commits; a
+//! query's rows are read from its snapshot as they are pulled, and the
+//! writes of `INSERT`, `UPDATE` and `DELETE` are buffered in it like any
+//! other, together with the changes they make to the entries of the table's
+//! secondary indexes, so that indexes and rows commit or roll back as one. A
+//! statement that fails rolls back to a savepoint set before it, leaving the
+//! transaction as it was.
+//! `CREATE TABLE`, `CREATE INDEX` and `ANALYZE` change the catalog, which
+//! every transaction shares, and so commit on their own at once. An index is
+//! built from the rows committed when `CREATE INDEX` runs, while statements
+//! wait to be planned. It rewrites the schema of its table, which every
+//! transaction writing to the table requires unchanged: a transaction that
+//! wrote rows without the index's entries, and commits after it, fails with
+//! a conflict, as does `CREATE INDEX` if such a transaction commits while
+//! the index is being built.
+//!
+//! Before a statement runs, the memory its plan was given is admitted from
+//! the [`QueryMemory`] of the database, which may make it wait for running
+//! statements to hand theirs back; see [`admission`](super::admission).
+//!
+//! Everything is stored in the key space of the transactional store:
+//!
+//! | key                                    | value                 |
+//! |----------------------------------------|-----------------------|
+//! | `c` table name                         | schema                |
+//! | `s` table name                         | statistics            |
+//! | `r` table name                         | last row id allocated |
+//! | `i` index name                         | index                 |
+//! | `t` table name, then the primary key   | row                   |
+//! | `x` index name, then the key values    | primary key, or empty |
+//!
+//! The table name and primary key of a row's key use the order-preserving
+//! encoding of keys, so that rows are stored in primary key order; rows of a
+//! table without a primary key are keyed by a hidden row id. So do the index
+//! name and the values of its key expressions over a row in an index entry.
+//! The entry is keyed by those values alone in a unique index, holding the
+//! row's primary key, so that transactions adding the same values conflict;
+//! otherwise, and if a value is `NULL`, the primary key follows the values.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use super::admission::{OperatorUsage, Priority, QueryGrant, QueryMemory};
+use super::catalog::{Catalog, IndexSchema, TableSchema};
+use super::eval::eval;
+use super::executor::{buffered, BoxedNode, Builder};
+use super::optimizer::{fit_grants, optimize, total_min_grant};
+use super::physical::{Operator, PhysicalPlan};
+use super::plan::{plan, Field};
+use super::row::{decode_row, encode_key, encode_row, key_bytes, row_bytes, Row};
+use super::statistics::{Analyzer, TableStatistics};
+use super::value::Value;
+use crate::coding::{put_bytes, u64_at};
+use crate::error::{Error, Result};
+use crate::exec::spill::{split_pair, SpillFile, SpillReader, SpillWriter};
+use crate::memory::{MemoryBudget, Reservation};
+use crate::mvcc::{Isolation, Transaction, TransactionDb};
+use crate::options::Options;
+use crate::range::KeyRange;
+use crate::vfs::Vfs;
+
+const SCHEMA: u8 = b'c';
+const STATISTICS: u8 = b's';
+const ROW_ID: u8 = b'r';
+const INDEX: u8 = b'i';
+const TABLE: u8 = b't';
+const INDEX_ENTRY: u8 = b'x';
+
+/// Size of each of the buffers the rows a statement writes are spooled
+/// through.
+const SPOOL_IO: usize = 16 << 10;
+
+/// Prefix of the keys of the rows of `table`.
+pub(crate) fn table_prefix(table: &str) -> Vec<u8> {
+    let mut key = vec![TABLE];
+    encode_key(&Value::Text(table.to_owned()), &mut key);
+    key
+}
+
+/// Prefix of the keys of the entries of the index `index`.
+pub(crate) fn index_prefix(index: &str) -> Vec<u8> {
+    let mut key = vec![INDEX_ENTRY];
+    encode_key(&Value::Text(index.to_owned()), &mut key);
+    key
+}
+
+fn catalog_key(tag: u8, table: &str) -> Vec<u8> {
+    let mut key = vec![tag];
+    key.extend_from_slice(table.as_bytes());
+    key
+}
+
+/// Result of a statement.
+pub enum Output<'a> {
+    /// Rows of a query or of `EXPLAIN`.
+    Rows(Rows<'a>),
+    /// Number of rows inserted, updated or deleted.
+    Count(u64),
+    /// Completion of a statement that returns nothing.
+    Done,
+}
+
+/// Rows of a query, computed as they are pulled.
+///
+/// The memory admitted for the query is held until it is dropped.
+pub struct Rows<'a> {
+    root: BoxedNode<'a>,
+    grant: Option<QueryGrant>,
+    fields: Vec<Field>,
+    /// Set once a row failed; the query returns nothing after its error.
+    done: bool,
+}
+
+impl Rows<'_> {
+    /// Columns of the rows.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Memory granted to and used by each operator of the query so far.
+    pub fn usage(&self) -> Vec<OperatorUsage> {
+        self.grant.as_ref().map_or_else(Vec::new, QueryGrant::usage)
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let row = self.root.next().transpose();
+        self.done = matches!(row, Some(Err(_)));
+        row
+    }
+}
+
+/// Database of SQL tables.
+pub struct Database {
+    db: TransactionDb,
+    catalog: RwLock<Catalog>,
+    memory: QueryMemory,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    budget: MemoryBudget,
+}
+
+impl Database {
+    /// Opens the database in `dir`, creating it if needed.
+    pub fn open(dir: impl AsRef<Path>, options: &Options, budget: &MemoryBudget) -> Result<Self> {
+        let dir = dir.as_ref();
+        let db = TransactionDb::open(dir, options, budget)?;
+        let mut catalog = Catalog::new();
+        let txn = db.begin();
+        for pair in txn.scan(KeyRange::prefix(&[SCHEMA])) {
+            catalog.create(TableSchema::decode(&pair?.1)?)?;
+        }
+        for pair in txn.scan(KeyRange::prefix(&[INDEX])) {
+            catalog.create_index(IndexSchema::decode(&pair?.1)?)?;
+        }
+        for pair in txn.scan(KeyRange::prefix(&[STATISTICS])) {
+            let (key, value) = pair?;
+            let table = String::from_utf8(key[1..].to_vec())
+                .map_err(|_| Error::corruption("table name is not UTF-8"))?;
+            catalog.set_statistics(&table, TableStatistics::decode(&value)?);
+        }
+        txn.rollback();
+        Ok(Database {
+            catalog: RwLock::new(catalog),
+            memory: QueryMemory::new(options, budget),
+            vfs: Arc::clone(&options.vfs),
+            dir: dir.to_owned(),
+            budget: budget.clone(),
+            db,
+        })
+    }
+
+    /// Starts a transaction statements can be executed in.
+    pub fn begin(&self) -> Transaction<'_> {
+        self.db.begin()
+    }
+
+    /// Starts a transaction with the given isolation level.
+    pub fn begin_with(&self, isolation: Isolation) -> Result<Transaction<'_>> {
+        self.db.begin_with(isolation)
+    }
+
+    /// Memory the operators of running statements are granted from.
+    pub fn query_memory(&self) -> &QueryMemory {
+        &self.memory
+    }
+
+    /// Schema of the table `name`.
+    pub fn table(&self, name: &str) -> Option<TableSchema> {
+        self.catalog.read().unwrap().table(name).cloned()
+    }
+
+    /// Schema of the index `name`.
+    pub fn index(&self, name: &str) -> Option<IndexSchema> {
+        self.catalog.read().unwrap().index(name).cloned()
+    }
+
+    /// Statistics of the table `name` as of its last analysis.
+    pub fn statistics(&self, name: &str) -> TableStatistics {
+        self.catalog.read().unwrap().statistics(name)
+    }
+
+    /// Executes the statement `sql` in `txn`.
+    ///
+    /// The rows of a query are read as they are pulled from the result,
+    /// which borrows the transaction until it is dropped.
+    pub fn execute<'a>(&'a self, txn: &'a mut Transaction<'_>, sql: &str) -> Result<Output<'a>> {
+        self.execute_with(txn, sql, Priority::default())
+    }
+
+    /// Executes the statement `sql` in `txn`, queued behind the statements
+    /// of higher priority if it has to wait to be admitted.
+    pub fn execute_with<'a>(
+        &'a self,
+        txn: &'a mut Transaction<'_>,
+        sql: &str,
+        priority: Priority,
+    ) -> Result<Output<'a>> {
+        let statement = super::parse(sql)?;
+        let mut physical = {
+            let catalog = self.catalog.read().unwrap();
+            let plan = plan(&statement, &catalog)?;
+            optimize(&plan, &catalog, self.memory.limit())?
+        };
+        match &physical.operator {
+            Operator::Explain => {
+                let lines = physical.inputs[0]
+                    .explain()
+                    .into_iter()
+                    .map(|line| vec![Value::Text(line)])
+                    .collect();
+                return Ok(Output::Rows(Rows {
+                    root: buffered(lines),
+                    grant: None,
+                    fields: physical.fields,
+                    done: false,
+                }));
+            }
+            Operator::CreateTable {
+                schema,
+                if_not_exists,
+            } => {
+                self.create_table(schema, *if_not_exists)?;
+                return Ok(Output::Done);
+            }
+            _ => {}
+        }
+        let mut grant = self.admit(&mut physical, priority)?;
+        match &physical.operator {
+            Operator::CreateIndex {
+                index,
+                if_not_exists,
+            } => {
+                self.create_index(index, *if_not_exists, &physical, &mut grant)?;
+                Ok(Output::Done)
+            }
+            Operator::Insert { table, columns } => {
+                let (schema, indexes) = self.table_and_indexes(table)?;
+                let spool = self.spool(txn, &physical, &mut grant, |row, out| {
+                    let mut full = vec![Value::Null; schema.columns.len()];
+                    for (&i, value) in columns.iter().zip(row) {
+                        full[i] = value;
+                    }
+                    check_row(&schema, &full)?;
+                    encode_row(&full, out);
+                    Ok(())
+                })?;
+                drop(grant);
+                atomically(txn, &schema, |txn| {
+                    self.insert(txn, &schema, &indexes, spool)
+                })
+                .map(Output::Count)
+            }
+            Operator::Update { table, assignments } => {
+                let (schema, indexes) = self.table_and_indexes(table)?;
+                let spool = self.spool(txn, &physical, &mut grant, |row, out| {
+                    let mut new = row[..schema.columns.len()].to_vec();
+                    for (i, expr) in assignments {
+                        new[*i] = eval(expr, &row)?;
+                    }
+                    check_row(&schema, &new)?;
+                    put_bytes(out, &row_key(&schema, &row));
+                    put_bytes(out, &row_bytes(&row));
+                    encode_row(&new, out);
+                    Ok(())
+                })?;
+                drop(grant);
+                atomically(txn, &schema, |txn| {
+                    self.update(txn, &schema, &indexes, spool)
+                })
+                .map(Output::Count)
+            }
+            Operator::Delete { table } => {
+                let (schema, indexes) = self.table_and_indexes(table)?;
+                let spool = self.spool(txn, &physical, &mut grant, |row, out| {
+                    put_bytes(out, &row_key(&schema, &row));
+                    encode_row(&row, out);
+                    Ok(())
+                })?;
+                drop(grant);
+                atomically(txn, &schema, |txn| self.delete(txn, &indexes, spool)).map(Output::Count)
+            }
+            Operator::Analyze => {
+                let analyzed = self.analyze(txn, &physical, &mut grant)?;
+                drop(grant);
+                let mut catalog = self.catalog.write().unwrap();
+                let mut ddl = self.db.begin();
+                for (table, statistics) in &analyzed {
+                    ddl.put(&catalog_key(STATISTICS, table), &statistics.encode())?;
+                }
+                ddl.commit()?;
+                for (table, statistics) in analyzed {
+                    catalog.set_statistics(&table, statistics);
+                }
+                Ok(Output::Done)
+            }
+            _ => {
+                let txn: &'a Transaction<'a> = txn;
+                let catalog = self.catalog.read().unwrap();
+                let mut builder = Builder::new(
+                    txn,
+                    &catalog,
+                    Arc::clone(&self.vfs),
+                    self.dir.clone(),
+                    &mut grant,
+                );
+                let root = builder.build(&physical)?;
+                drop(catalog);
+                Ok(Output::Rows(Rows {
+                    root,
+                    grant: Some(grant),
+                    fields: physical.fields,
+                    done: false,
+                }))
+            }
+        }
+    }
+
+    /// Schema of `table` and of each of its indexes.
+    fn table_and_indexes(&self, table: &str) -> Result<(TableSchema, Vec<IndexSchema>)> {
+        let catalog = self.catalog.read().unwrap();
+        let schema = catalog
+            .table(table)
+            .ok_or_else(|| Error::invalid(format!("unknown table {table}")))?;
+        Ok((schema.clone(), catalog.indexes(table).cloned().collect()))
+    }
+
+    /// Admits the memory of `plan`, shrinking its grants to what was
+    /// admitted if that is less than they add up to.
+    fn admit(&self, plan: &mut PhysicalPlan, priority: Priority) -> Result<QueryGrant> {
+        let ideal = plan.total_grant();
+        let grant = self.memory.admit(total_min_grant(plan), ideal, priority)?;
+        if grant.size() < ideal {
+            fit_grants(plan, grant.size())?;
+        }
+        Ok(grant)
+    }
+
+    fn create_table(&self, schema: &TableSchema, if_not_exists: bool) -> Result<()> {
+        let mut catalog = self.catalog.write().unwrap();
+        if catalog.table(&schema.name).is_some() && if_not_exists {
+            return Ok(());
+        }
+        let mut ddl = self.db.begin();
+        ddl.put(&catalog_key(SCHEMA, &schema.name), &schema.encode())?;
+        ddl.commit()?;
+        catalog.create(schema.clone())
+    }
+
+    /// Creates `index` in a transaction of its own, from the rows of its
+    /// table that the input of `plan` scans.
+    fn create_index(
+        &self,
+        index: &IndexSchema,
+        if_not_exists: bool,
+        plan: &PhysicalPlan,
+        grant: &mut QueryGrant,
+    ) -> Result<()> {
+        let mut catalog = self.catalog.write().unwrap();
+        if catalog.index(&index.name).is_some() {
+            return match if_not_exists {
+                true => Ok(()),
+                false => Err(Error::invalid(format!(
+                    "index {} already exists",
+                    index.name
+                ))),
+            };
+        }
+        let schema = catalog
+            .table(&index.table)
+            .ok_or_else(|| Error::invalid(format!("unknown table {}", index.table)))?
+            .clone();
+        let mut ddl = self.db.begin();
+        let (mut reader, _buffers) = self.spool_from(&ddl, &catalog, plan, grant, |row, out| {
+            let (key, value) = index_entry(index, &row, &row_key(&schema, &row))?;
+            put_bytes(out, &key);
+            out.extend_from_slice(&value);
+            Ok(())
+        })?;
+        while reader.advance()? {
+            let (key, value) = split_pair(reader.record())?;
+            put_entry(&mut ddl, index, key, value)?;
+        }
+        ddl.put(&catalog_key(INDEX, &index.name), &index.encode())?;
+        // Transactions writing rows the index was built without conflict
+        // with it one way or the other.
+        ddl.put(&catalog_key(SCHEMA, &schema.name), &schema.encode())?;
+        ddl.require_unchanged(KeyRange::prefix(&table_prefix(&schema.name)))?;
+        ddl.commit()?;
+        catalog.create_index(index.clone())
+    }
+
+    /// Runs the input of `plan`, a statement writing to a table, and spools
+    /// what `record` makes of each of its rows to a file, so that the table
+    /// is not written while it is being read. Returns a reader of the file,
+    /// and the reservation of the buffers it is written and read through.
+    fn spool(
+        &self,
+        txn: &Transaction<'_>,
+        plan: &PhysicalPlan,
+        grant: &mut QueryGrant,
+        record: impl FnMut(Row, &mut Vec<u8>) -> Result<()>,
+    ) -> Result<(SpillReader, Reservation)> {
+        let catalog = self.catalog.read().unwrap();
+        self.spool_from(txn, &catalog, plan, grant, record)
+    }
+
+    /// As [`spool`](Self::spool), planning against `catalog`.
+    fn spool_from(
+        &self,
+        txn: &Transaction<'_>,
+        catalog: &Catalog,
+        plan: &PhysicalPlan,
+        grant: &mut QueryGrant,
+        mut record: impl FnMut(Row, &mut Vec<u8>) -> Result<()>,
+    ) -> Result<(SpillReader, Reservation)> {
+        let buffers = self.budget.reserve(2 * SPOOL_IO)?;
+        let mut builder =
+            Builder::new(txn, catalog, Arc::clone(&self.vfs), self.dir.clone(), grant)
+                .with_row_ids();
+        builder.next_step();
+        let mut source = builder.build(&plan.inputs[0])?;
+        let mut writer = SpillWriter::new(SpillFile::create(&*self.vfs, &self.dir)?, SPOOL_IO);
+        let mut out = Vec::new();
+        while let Some(row) = source.next()? {
+            out.clear();
+            record(row, &mut out)?;
+            writer.write(&out)?;
+        }
+        Ok((SpillReader::new(writer.finish()?, SPOOL_IO), buffers))
+    }
+
+    fn insert(
+        &self,
+        txn: &mut Transaction<'_>,
+        schema: &TableSchema,
+        indexes: &[IndexSchema],
+        (mut reader, _buffers): (SpillReader, Reservation),
+    ) -> Result<u64> {
+        let counter = catalog_key(ROW_ID, &schema.name);
+        let mut row_id = match txn.get(&counter)? {
+            Some(value) if value.len() == 8 => u64_at(&value, 0),
+            Some(_) => return Err(Error::corruption("row id counter is not 8 bytes")),
+            None => 0,
+        };
+        let mut count = 0;
+        while reader.advance()? {
+            let key = match schema.primary_key.is_empty() {
+                true => {
+                    row_id += 1;
+                    let mut key = table_prefix(&schema.name);
+                    encode_key(&Value::Integer(row_id as i64), &mut key);
+                    key
+                }
+                false => {
+                    let key = row_key(schema, &decode_row(reader.record())?);
+                    if txn.get(&key)?.is_some() {
+                        return Err(duplicate(schema));
+                    }
+                    key
+                }
+            };
+            txn.put(&key, reader.record())?;
+            if !indexes.is_empty() {
+                let row = decode_row(reader.record())?;
+                for index in indexes {
+                    let (entry, value) = index_entry(index, &row, &key)?;
+                    put_entry(txn, index, &entry, &value)?;
+                }
+            }
+            count += 1;
+        }
+        if schema.primary_key.is_empty() && count > 0 {
+            txn.put(&counter, &row_id.to_le_bytes())?;
+        }
+        Ok(count)
+    }
+
+    /// Writes updated rows, each spooled after the key and the values of its
+    /// old version. Rows whose primary key changes, and index entries that
+    /// change, are deleted before any is written anew, so that keys may
+    /// trade places.
+    fn update(
+        &self,
+        txn: &mut Transaction<'_>,
+        schema: &TableSchema,
+        indexes: &[IndexSchema],
+        (mut reader, _buffers): (SpillReader, Reservation),
+    ) -> Result<u64> {
+        // Row ids never change.
+        let new_key = |old: &[u8], row: &[Value]| match schema.primary_key.is_empty() {
+            true => old.to_vec(),
+            false => row_key(schema, row),
+        };
+        while reader.advance()? {
+            let (old_key, old, row) = split_update(reader.record())?;
+            let (old, new) = (decode_row(old)?, decode_row(row)?);
+            let key = new_key(old_key, &new);
+            if key != old_key {
+                txn.delete(old_key)?;
+            }
+            for (_, (entry, _), _) in changed_entries(indexes, (old_key, &old), (&key, &new))? {
+                txn.delete(&entry)?;
+            }
+        }
+        reader.rewind();
+        let mut count = 0;
+        while reader.advance()? {
+            let (old_key, old, row) = split_update(reader.record())?;
+            let (old, new) = (decode_row(old)?, decode_row(row)?);
+            let key = new_key(old_key, &new);
+            if key != old_key && txn.get(&key)?.is_some() {
+                return Err(duplicate(schema));
+            }
+            txn.put(&key, row)?;
+            for (index, _, (entry, value)) in
+                changed_entries(indexes, (old_key, &old), (&key, &new))?
+            {
+                put_entry(txn, index, &entry, &value)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Deletes rows, each spooled after its key.
+    fn delete(
+        &self,
+        txn: &mut Transaction<'_>,
+        indexes: &[IndexSchema],
+        (mut reader, _buffers): (SpillReader, Reservation),
+    ) -> Result<u64> {
+        let mut count = 0;
+        while reader.advance()? {
+            let (key, row) = split_pair(reader.record())?;
+            txn.delete(key)?;
+            let row = decode_row(row)?;
+            for index in indexes {
+                txn.delete(&index_entry(index, &row, key)?.0)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Gathers the statistics of each table `plan` scans.
+    fn analyze(
+        &self,
+        txn: &Transaction<'_>,
+        plan: &PhysicalPlan,
+        grant: &mut QueryGrant,
+    ) -> Result<Vec<(String, TableStatistics)>> {
+        let catalog = self.catalog.read().unwrap();
+        let mut builder = Builder::new(
+            txn,
+            &catalog,
+            Arc::clone(&self.vfs),
+            self.dir.clone(),
+            grant,
+        );
+        let step = builder.next_step();
+        let mut operator = builder.operator_grant(step, plan);
+        let mut reservation = operator.take();
+        let mut analyzed = Vec::new();
+        for scan in &plan.inputs {
+            let Operator::SeqScan { table } = &scan.operator else {
+                unreachable!("ANALYZE reads its tables with sequential scans")
+            };
+            let mut rows = builder.build(scan)?;
+            let mut analyzer = Analyzer::new(scan.fields.len(), reservation)?;
+            while let Some(row) = rows.next()? {
+                analyzer.push(&row);
+            }
+            let (statistics, grant) = analyzer.finish();
+            operator.record(grant.size());
+            reservation = grant;
+            analyzed.push((table.clone(), statistics));
+        }
+        drop(reservation);
+        operator.finish();
+        Ok(analyzed)
+    }
+}
+
+/// Runs `write`, the writes of a statement to the table of `schema` in
+/// `txn`, within a savepoint, so that a statement failing halfway leaves
+/// none of its writes behind.
+///
+/// The transaction requires the schema unchanged: the writes are made for
+/// the indexes the table has now.
+fn atomically<'t, T>(
+    txn: &mut Transaction<'t>,
+    schema: &TableSchema,
+    write: impl FnOnce(&mut Transaction<'t>) -> Result<T>,
+) -> Result<T> {
+    let key = catalog_key(SCHEMA, &schema.name);
+    txn.require_unchanged(key.as_slice()..=key.as_slice())?;
+    txn.savepoint();
+    let result = write(txn);
+    match result.is_ok() {
+        true => txn.release_savepoint(),
+        false => txn.rollback_to_savepoint(),
+    }
+    result
+}
+
+/// Key a row of `table` is stored under: its primary key, or the row id
+/// scans append to rows of tables without one.
+fn row_key(schema: &TableSchema, row: &[Value]) -> Vec<u8> {
+    let mut key = table_prefix(&schema.name);
+    match schema.primary_key.is_empty() {
+        true => encode_key(&row[schema.columns.len()], &mut key),
+        false => key.extend(key_bytes(schema.primary_key.iter().map(|&i| &row[i]))),
+    }
+    key
+}
+
+/// Fails unless each value of `row` fits its column of `schema`.
+fn check_row(schema: &TableSchema, row: &[Value]) -> Result<()> {
+    for (column, value) in schema.columns.iter().zip(row) {
+        match value.data_type() {
+            None if column.not_null => {
+                return Err(Error::invalid(format!(
+                    "column {} cannot be null",
+                    column.name
+                )));
+            }
+            Some(found) if found != column.data_type => {
+                return Err(Error::invalid(format!(
+                    "column {} of type {} cannot hold {found}",
+                    column.name, column.data_type
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Key and value of an index entry.
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// Key and value of the entry for `row`, stored under `key`, in `index`.
+fn index_entry(index: &IndexSchema, row: &[Value], key: &[u8]) -> Result<Entry> {
+    let mut entry = index_prefix(&index.name);
+    let mut null = false;
+    for expr in &index.exprs {
+        let value = eval(expr, row)?;
+        null |= value.is_null();
+        encode_key(&value, &mut entry);
+    }
+    let primary_key = &key[table_prefix(&index.table).len()..];
+    match index.unique && !null {
+        true => Ok((entry, primary_key.to_vec())),
+        false => {
+            entry.extend_from_slice(primary_key);
+            Ok((entry, Vec::new()))
+        }
+    }
+}
+
+/// Indexes whose entry for a row changes as it is updated from `old` to
+/// `new`, each row given with the key it is stored under, with the old and
+/// the new entry.
+fn changed_entries<'i>(
+    indexes: &'i [IndexSchema],
+    (old_key, old): (&[u8], &[Value]),
+    (key, new): (&[u8], &[Value]),
+) -> Result<Vec<(&'i IndexSchema, Entry, Entry)>> {
+    let mut changed = Vec::new();
+    for index in indexes {
+        let before = index_entry(index, old, old_key)?;
+        let after = index_entry(index, new, key)?;
+        if before != after {
+            changed.push((index, before, after));
+        }
+    }
+    Ok(changed)
+}
+
+/// Writes an entry of `index`, failing if it is unique and another row
+/// already has the entry's values.
+fn put_entry(
+    txn: &mut Transaction<'_>,
+    index: &IndexSchema,
+    key: &[u8],
+    value: &[u8],
+) -> Result<()> {
+    // Only entries keyed by their values alone have one.
+    if !value.is_empty() && txn.get(key)?.is_some() {
+        return Err(Error::invalid(format!(
+            "duplicate key in unique index {}",
+            index.name
+        )));
+    }
+    txn.put(key, value)
+}
+
+/// Old key, old row and new row of a spooled update.
+fn split_update(record: &[u8]) -> Result<(&[u8], &[u8], &[u8])> {
+    let (key, rest) = split_pair(record)?;
+    let (old, new) = split_pair(rest)?;
+    Ok((key, old, new))
+}
+
+fn duplicate(schema: &TableSchema) -> Error {
+    Error::invalid(format!("duplicate primary key in table {}", schema.name))
+}
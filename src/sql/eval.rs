@@ -0,0 +1,119 @@
+//! Evaluation of expressions over rows.
+//!
+//! `NULL` follows SQL's three-valued logic: an operation on it yields `NULL`,
+//! except that `FALSE AND NULL` is false and `TRUE OR NULL` is true. A row
+//! passes a condition only if it evaluates to true.
+
+use std::cmp::Ordering;
+
+use super::ast::{BinaryOp, UnaryOp};
+use super::plan::Scalar;
+use super::value::Value;
+use crate::error::{Error, Result};
+
+pub(crate) fn eval(expr: &Scalar, row: &[Value]) -> Result<Value> {
+    Ok(match expr {
+        Scalar::Literal(value) => value.clone(),
+        Scalar::Column(i) => row[*i].clone(),
+        Scalar::Unary { op, expr } => match (op, eval(expr, row)?) {
+            (_, Value::Null) => Value::Null,
+            (UnaryOp::Not, Value::Boolean(b)) => Value::Boolean(!b),
+            (UnaryOp::Neg, Value::Integer(n)) => Value::Integer(
+                n.checked_neg()
+                    .ok_or_else(|| Error::invalid("integer overflow"))?,
+            ),
+            (UnaryOp::Not, v) => return Err(mismatch("NOT", &v)),
+            (UnaryOp::Neg, v) => return Err(mismatch("-", &v)),
+        },
+        Scalar::Binary { op, left, right } => match op {
+            BinaryOp::And => match eval(left, row)? {
+                Value::Boolean(false) => Value::Boolean(false),
+                l => match (boolean(&l, op)?, boolean(&eval(right, row)?, op)?) {
+                    (_, Some(false)) => Value::Boolean(false),
+                    (Some(true), Some(true)) => Value::Boolean(true),
+                    _ => Value::Null,
+                },
+            },
+            BinaryOp::Or => match eval(left, row)? {
+                Value::Boolean(true) => Value::Boolean(true),
+                l => match (boolean(&l, op)?, boolean(&eval(right, row)?, op)?) {
+                    (_, Some(true)) => Value::Boolean(true),
+                    (Some(false), Some(false)) => Value::Boolean(false),
+                    _ => Value::Null,
+                },
+            },
+            op => binary(*op, eval(left, row)?, eval(right, row)?)?,
+        },
+        Scalar::IsNull { expr, negated } => Value::Boolean(eval(expr, row)?.is_null() != *negated),
+    })
+}
+
+/// Whether `row` passes the condition `predicate`.
+pub(crate) fn passes(predicate: &Scalar, row: &[Value]) -> Result<bool> {
+    Ok(eval(predicate, row)? == Value::Boolean(true))
+}
+
+fn boolean(value: &Value, op: &BinaryOp) -> Result<Option<bool>> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Boolean(b) => Ok(Some(*b)),
+        v => Err(mismatch(&op.to_string(), v)),
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+    let ordering = |allowed: fn(Ordering) -> bool| -> Result<Value> {
+        match left.data_type() == right.data_type() {
+            true => Ok(Value::Boolean(allowed(left.cmp(&right)))),
+            false => Err(Error::invalid(format!(
+                "cannot compare {} with {}",
+                left.data_type().unwrap(),
+                right.data_type().unwrap()
+            ))),
+        }
+    };
+    match op {
+        BinaryOp::Eq => ordering(Ordering::is_eq),
+        BinaryOp::NotEq => ordering(Ordering::is_ne),
+        BinaryOp::Lt => ordering(Ordering::is_lt),
+        BinaryOp::LtEq => ordering(Ordering::is_le),
+        BinaryOp::Gt => ordering(Ordering::is_gt),
+        BinaryOp::GtEq => ordering(Ordering::is_ge),
+        _ => {
+            let (Value::Integer(l), Value::Integer(r)) = (&left, &right) else {
+                let operand = if left.data_type() == right.data_type() {
+                    &left
+                } else {
+                    [&left, &right]
+                        .into_iter()
+                        .find(|v| !matches!(v, Value::Integer(_)))
+                        .unwrap()
+                };
+                return Err(mismatch(&op.to_string(), operand));
+            };
+            let result = match op {
+                BinaryOp::Add => l.checked_add(*r),
+                BinaryOp::Sub => l.checked_sub(*r),
+                BinaryOp::Mul => l.checked_mul(*r),
+                BinaryOp::Div | BinaryOp::Rem if *r == 0 => {
+                    return Err(Error::invalid("division by zero"))
+                }
+                BinaryOp::Div => l.checked_div(*r),
+                _ => l.checked_rem(*r),
+            };
+            result
+                .map(Value::Integer)
+                .ok_or_else(|| Error::invalid("integer overflow"))
+        }
+    }
+}
+
+fn mismatch(op: &str, value: &Value) -> Error {
+    Error::invalid(format!(
+        "operator {op} does not apply to {}",
+        value.data_type().unwrap()
+    ))
+}
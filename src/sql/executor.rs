@@ -0,0 +1,1362 @@
+//! Execution of physical plans.
+//!
+//! A plan runs as a tree of nodes, one per step, each producing its rows one
+//! at a time as its parent pulls them and pulling rows from its own inputs in
+//! turn. Steps that stream hold a row at most; steps that hold rows take the
+//! grant the optimizer gave them out of the memory admitted for the query
+//! when the tree is built, run within it, spilling to temporary files, and
+//! record the bytes they hold as they go. Each hands its grant back as soon
+//! as it has consumed its input and released what it held, so that the
+//! memory of a finished sort is free for other queries while its rows are
+//! still being read.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::mem::{self, size_of};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::admission::{OperatorGrant, QueryGrant};
+use super::catalog::{Catalog, IndexSchema, TableSchema};
+use super::database::{index_prefix, table_prefix};
+use super::eval::{eval, passes};
+use super::physical::{Operator, PhysicalPlan};
+use super::plan::{AggregateCall, AggregateFunction, Scalar, SortKey};
+use super::row::{
+    decode_key, decode_row, encode_key, encode_key_descending, encode_row, key_bytes, row_bytes,
+    Row,
+};
+use super::value::Value;
+use crate::coding::put_bytes;
+use crate::error::{Error, Result};
+use crate::exec::spill::{split_pair, SpillFile, SpillReader, SpillWriter, SPILL_OVERHEAD};
+use crate::exec::{
+    Aggregate, Compare, ExternalSort, Groups, HashAggregate, HashJoin, HashJoinProbe,
+    SortedRecords, SpilledMatches,
+};
+use crate::mvcc::{Transaction, TransactionScan};
+use crate::range::{prefix_successor, KeyRange};
+use crate::vfs::Vfs;
+
+/// Bounds of the buffer rows spilled by joins are written and read through.
+const MIN_IO: usize = 512;
+const MAX_IO: usize = 64 << 10;
+
+/// Step of a running plan.
+pub(crate) trait Node {
+    /// Next row the step produces, or `None` once it has produced them all.
+    fn next(&mut self) -> Result<Option<Row>>;
+}
+
+pub(crate) type BoxedNode<'a> = Box<dyn Node + 'a>;
+
+/// Bytes a row costs held in memory.
+fn row_memory(row: &[Value]) -> usize {
+    let text: usize = row
+        .iter()
+        .map(|v| match v {
+            Value::Text(s) => s.len(),
+            _ => 0,
+        })
+        .sum();
+    size_of::<Row>() + mem::size_of_val(row) + text
+}
+
+fn io_size(grant: usize) -> usize {
+    (grant / 16).clamp(MIN_IO, MAX_IO)
+}
+
+/// Order-preserving key of `exprs` over `row`; `None` if one of them is
+/// `NULL`, which equals nothing.
+fn join_key<'e>(exprs: impl Iterator<Item = &'e Scalar>, row: &[Value]) -> Result<Option<Vec<u8>>> {
+    let mut key = Vec::new();
+    for expr in exprs {
+        let value = eval(expr, row)?;
+        if value.is_null() {
+            return Ok(None);
+        }
+        encode_key(&value, &mut key);
+    }
+    Ok(Some(key))
+}
+
+/// Order-preserving key of `keys` over `row`.
+fn sort_key(keys: &[SortKey], row: &[Value]) -> Result<Vec<u8>> {
+    let mut key = Vec::new();
+    for k in keys {
+        let value = eval(&k.expr, row)?;
+        match k.descending {
+            true => encode_key_descending(&value, &mut key),
+            false => encode_key(&value, &mut key),
+        }
+    }
+    Ok(key)
+}
+
+/// Orders records written as a key with its length followed by a row.
+fn compare_keys(a: &[u8], b: &[u8]) -> Ordering {
+    fn key(record: &[u8]) -> &[u8] {
+        split_pair(record).map_or(record, |(key, _)| key)
+    }
+    key(a).cmp(key(b))
+}
+
+/// The columns of `left` followed by those of `right`, if they pass
+/// `filter`.
+fn joined(left: &[Value], right: &[Value], filter: Option<&Scalar>) -> Result<Option<Row>> {
+    let mut row = Vec::with_capacity(left.len() + right.len());
+    row.extend_from_slice(left);
+    row.extend_from_slice(right);
+    match filter {
+        Some(filter) if !passes(filter, &row)? => Ok(None),
+        _ => Ok(Some(row)),
+    }
+}
+
+/// Builds the nodes running a physical plan.
+pub(crate) struct Builder<'a, 'b> {
+    txn: &'a Transaction<'a>,
+    catalog: &'b Catalog,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    grant: &'b mut QueryGrant,
+    /// Position of the next step visited among the lines of the `EXPLAIN`
+    /// output.
+    step: usize,
+    /// Whether scans of tables without a primary key append the row id of
+    /// each row, for statements that write the rows back.
+    row_ids: bool,
+}
+
+impl<'a, 'b> Builder<'a, 'b> {
+    /// Builds nodes reading through `txn`, taking their grants out of
+    /// `grant` and spilling to files in `dir`.
+    pub(crate) fn new(
+        txn: &'a Transaction<'a>,
+        catalog: &'b Catalog,
+        vfs: Arc<dyn Vfs>,
+        dir: PathBuf,
+        grant: &'b mut QueryGrant,
+    ) -> Self {
+        Builder {
+            txn,
+            catalog,
+            vfs,
+            dir,
+            grant,
+            step: 0,
+            row_ids: false,
+        }
+    }
+
+    pub(crate) fn with_row_ids(mut self) -> Self {
+        self.row_ids = true;
+        self
+    }
+
+    /// Moves on to the next step, returning its position.
+    pub(crate) fn next_step(&mut self) -> usize {
+        self.step += 1;
+        self.step - 1
+    }
+
+    /// Takes the grant of `plan`, the step at `step`.
+    pub(crate) fn operator_grant(&mut self, step: usize, plan: &PhysicalPlan) -> OperatorGrant {
+        self.grant.operator(step, plan.estimate.grant)
+    }
+
+    fn schema(&self, table: &str) -> Result<&'b TableSchema> {
+        self.catalog
+            .table(table)
+            .ok_or_else(|| Error::invalid(format!("unknown table {table}")))
+    }
+
+    /// Builds the nodes of `plan` and of its inputs.
+    pub(crate) fn build(&mut self, plan: &PhysicalPlan) -> Result<BoxedNode<'a>> {
+        let step = self.next_step();
+        Ok(match &plan.operator {
+            Operator::SeqScan { table } => {
+                let prefix = table_prefix(table);
+                self.scan(table, KeyRange::prefix(&prefix), prefix.len())?
+            }
+            Operator::IndexScan {
+                table,
+                prefix,
+                lower,
+                upper,
+            } => {
+                let schema = self.schema(table)?;
+                let keys = &schema.primary_key;
+                for (&column, value) in keys.iter().zip(prefix) {
+                    check_type(schema, column, value)?;
+                }
+                for bound in [lower, upper] {
+                    if let Bound::Included(value) | Bound::Excluded(value) = bound {
+                        check_type(schema, keys[prefix.len()], value)?;
+                    }
+                }
+                let table_len = table_prefix(table).len();
+                let mut base = table_prefix(table);
+                for value in prefix {
+                    encode_key(value, &mut base);
+                }
+                self.scan(table, key_range(&base, lower, upper), table_len)?
+            }
+            Operator::SecondaryIndexScan {
+                table,
+                index,
+                prefix,
+                lower,
+                upper,
+                covering,
+            } => {
+                let schema = self.schema(table)?;
+                let index = self
+                    .catalog
+                    .index(index)
+                    .ok_or_else(|| Error::invalid(format!("unknown index {index}")))?;
+                let bounds = [lower, upper].into_iter().filter_map(|bound| match bound {
+                    Bound::Included(value) | Bound::Excluded(value) => Some((prefix.len(), value)),
+                    Bound::Unbounded => None,
+                });
+                for (i, value) in prefix.iter().enumerate().chain(bounds) {
+                    if let Some(column) = index.column(i) {
+                        check_type(schema, column, value)?;
+                    }
+                }
+                let prefix_len = index_prefix(&index.name).len();
+                let mut base = index_prefix(&index.name);
+                for value in prefix {
+                    encode_key(value, &mut base);
+                }
+                Box::new(IndexEntryNode {
+                    entries: self.txn.scan(key_range(&base, lower, upper)),
+                    txn: self.txn,
+                    schema: schema.clone(),
+                    index: index.clone(),
+                    table_prefix: table_prefix(table),
+                    prefix_len,
+                    covering: *covering,
+                    row_ids: self.row_ids && schema.primary_key.is_empty(),
+                })
+            }
+            Operator::Values { rows } => Box::new(ValuesNode {
+                rows: rows.clone().into_iter(),
+            }),
+            Operator::Filter { predicate } => Box::new(FilterNode {
+                input: self.build(&plan.inputs[0])?,
+                predicate: predicate.clone(),
+            }),
+            Operator::Project { exprs } => Box::new(ProjectNode {
+                input: self.build(&plan.inputs[0])?,
+                exprs: exprs.clone(),
+            }),
+            Operator::Limit { limit, offset } => Box::new(LimitNode {
+                input: self.build(&plan.inputs[0])?,
+                remaining: *limit,
+                skip: *offset,
+            }),
+            Operator::HashJoin {
+                keys,
+                filter,
+                build_left,
+            } => {
+                let grant = self.operator_grant(step, plan);
+                let left = self.build(&plan.inputs[0])?;
+                let right = self.build(&plan.inputs[1])?;
+                let left_keys = keys.iter().map(|(l, _)| l.clone()).collect();
+                let right_keys = keys.iter().map(|(_, r)| r.clone()).collect();
+                let (build, probe, build_keys, probe_keys) = match build_left {
+                    true => (left, right, left_keys, right_keys),
+                    false => (right, left, right_keys, left_keys),
+                };
+                Box::new(HashJoinNode {
+                    build,
+                    probe,
+                    build_keys,
+                    probe_keys,
+                    filter: filter.clone(),
+                    build_left: *build_left,
+                    vfs: Arc::clone(&self.vfs),
+                    dir: self.dir.clone(),
+                    pending: VecDeque::new(),
+                    state: JoinState::Build,
+                    grant,
+                })
+            }
+            Operator::MergeJoin { keys, filter } => {
+                let grant = self.operator_grant(step, plan);
+                Box::new(MergeJoinNode {
+                    left: self.build(&plan.inputs[0])?,
+                    right: self.build(&plan.inputs[1])?,
+                    left_keys: keys.iter().map(|k| k.0.clone()).collect(),
+                    right_keys: keys.iter().map(|k| k.1.clone()).collect(),
+                    filter: filter.clone(),
+                    vfs: Arc::clone(&self.vfs),
+                    dir: self.dir.clone(),
+                    head: None,
+                    right_done: false,
+                    group_key: None,
+                    group: Group::Memory(Vec::new()),
+                    current: None,
+                    position: 0,
+                    grant,
+                })
+            }
+            Operator::NestedLoopJoin { filter } => {
+                let grant = self.operator_grant(step, plan);
+                Box::new(NestedLoopNode {
+                    left: self.build(&plan.inputs[0])?,
+                    right: self.build(&plan.inputs[1])?,
+                    filter: filter.clone(),
+                    vfs: Arc::clone(&self.vfs),
+                    dir: self.dir.clone(),
+                    block: Vec::new(),
+                    started: false,
+                    left_done: false,
+                    writer: None,
+                    spooled: None,
+                    right_row: None,
+                    position: 0,
+                    grant,
+                })
+            }
+            Operator::HashAggregate {
+                group_by,
+                aggregates,
+            } => {
+                let grant = self.operator_grant(step, plan);
+                Box::new(HashAggregateNode {
+                    input: self.build(&plan.inputs[0])?,
+                    group_by: group_by.clone(),
+                    aggregates: aggregates.clone(),
+                    vfs: Arc::clone(&self.vfs),
+                    dir: self.dir.clone(),
+                    groups: None,
+                    done: false,
+                    grant,
+                })
+            }
+            Operator::StreamAggregate {
+                group_by,
+                aggregates,
+            } => Box::new(StreamAggregateNode {
+                input: self.build(&plan.inputs[0])?,
+                group_by: group_by.clone(),
+                aggregates: aggregates.clone(),
+                group: None,
+                started: false,
+                done: false,
+            }),
+            Operator::Sort { keys } => {
+                let grant = self.operator_grant(step, plan);
+                Box::new(SortNode {
+                    input: self.build(&plan.inputs[0])?,
+                    keys: keys.clone(),
+                    vfs: Arc::clone(&self.vfs),
+                    dir: self.dir.clone(),
+                    sorted: None,
+                    done: false,
+                    grant,
+                })
+            }
+            Operator::TopN { keys, limit } => {
+                let grant = self.operator_grant(step, plan);
+                Box::new(TopNNode {
+                    input: self.build(&plan.inputs[0])?,
+                    keys: keys.clone(),
+                    limit: *limit,
+                    output: None,
+                    grant,
+                })
+            }
+            Operator::CreateTable { .. }
+            | Operator::CreateIndex { .. }
+            | Operator::Insert { .. }
+            | Operator::Update { .. }
+            | Operator::Delete { .. }
+            | Operator::Analyze
+            | Operator::Explain => {
+                return Err(Error::invalid("statement does not produce rows"));
+            }
+        })
+    }
+
+    fn scan(&self, table: &str, range: KeyRange, prefix_len: usize) -> Result<BoxedNode<'a>> {
+        let schema = self.schema(table)?;
+        Ok(Box::new(ScanNode {
+            rows: self.txn.scan(range),
+            prefix_len,
+            row_ids: self.row_ids && schema.primary_key.is_empty(),
+        }))
+    }
+}
+
+/// First key after every key starting with `key`.
+fn after(key: &[u8]) -> Vec<u8> {
+    prefix_successor(key).expect("row keys start with a table tag")
+}
+
+/// Range of the keys starting with `base` and continuing with a value
+/// between `lower` and `upper`.
+fn key_range(base: &[u8], lower: &Bound<Value>, upper: &Bound<Value>) -> KeyRange {
+    let with = |value: &Value| {
+        let mut key = base.to_vec();
+        encode_key(value, &mut key);
+        key
+    };
+    // Keys continue past the bounding value with the rest of the key, so
+    // bounds that exclude a value skip every key starting with it.
+    let start = match lower {
+        Bound::Unbounded => Bound::Included(base.to_vec()),
+        Bound::Included(value) => Bound::Included(with(value)),
+        Bound::Excluded(value) => Bound::Included(after(&with(value))),
+    };
+    let end = match upper {
+        Bound::Unbounded => Bound::Excluded(after(base)),
+        Bound::Included(value) => Bound::Excluded(after(&with(value))),
+        Bound::Excluded(value) => Bound::Excluded(with(value)),
+    };
+    KeyRange { start, end }
+}
+
+/// Fails unless `value` can be compared with column `column` of `schema`.
+fn check_type(schema: &TableSchema, column: usize, value: &Value) -> Result<()> {
+    let expected = schema.columns[column].data_type;
+    match value.data_type() {
+        Some(found) if found != expected => Err(Error::invalid(format!(
+            "cannot compare {expected} with {found}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Rows of a table in primary key order.
+struct ScanNode<'a> {
+    rows: TransactionScan<'a>,
+    /// Bytes of the table's key prefix, which the rest of a key follows.
+    prefix_len: usize,
+    /// Whether the row id the row is keyed by is appended to it.
+    row_ids: bool,
+}
+
+impl Node for ScanNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        let Some((key, value)) = self.rows.next().transpose()? else {
+            return Ok(None);
+        };
+        let mut row = decode_row(&value)?;
+        if self.row_ids {
+            row.extend(decode_key(&key[self.prefix_len..])?);
+        }
+        Ok(Some(row))
+    }
+}
+
+/// Rows of a table in the order of the entries of one of its secondary
+/// indexes.
+struct IndexEntryNode<'a> {
+    entries: TransactionScan<'a>,
+    txn: &'a Transaction<'a>,
+    schema: TableSchema,
+    index: IndexSchema,
+    table_prefix: Vec<u8>,
+    /// Bytes of the index's key prefix, which the values of an entry follow.
+    prefix_len: usize,
+    /// Whether rows are made of the values of the entries alone.
+    covering: bool,
+    /// Whether the row id the row is keyed by is appended to it.
+    row_ids: bool,
+}
+
+impl Node for IndexEntryNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        let Some((key, value)) = self.entries.next().transpose()? else {
+            return Ok(None);
+        };
+        let mut values = decode_key(&key[self.prefix_len..])?;
+        // Entries of a unique index without a NULL hold the primary key in
+        // their value rather than at the end of their key.
+        let primary_key = match value.is_empty() {
+            true => values.split_off(self.index.exprs.len().min(values.len())),
+            false => decode_key(&value)?,
+        };
+        let mut row = match self.covering {
+            true => {
+                let mut row = vec![Value::Null; self.schema.columns.len()];
+                for (i, value) in values.into_iter().enumerate() {
+                    if let Some(column) = self.index.column(i) {
+                        row[column] = value;
+                    }
+                }
+                for (&column, value) in self.schema.primary_key.iter().zip(&primary_key) {
+                    row[column] = value.clone();
+                }
+                row
+            }
+            false => {
+                let mut key = self.table_prefix.clone();
+                key.extend(key_bytes(&primary_key));
+                let Some(row) = self.txn.get(&key)? else {
+                    return Err(Error::corruption(format!(
+                        "index {} has an entry for a missing row",
+                        self.index.name
+                    )));
+                };
+                decode_row(&row)?
+            }
+        };
+        if self.row_ids {
+            row.extend(primary_key);
+        }
+        Ok(Some(row))
+    }
+}
+
+/// Node returning rows already computed.
+pub(crate) fn buffered<'a>(rows: Vec<Row>) -> BoxedNode<'a> {
+    Box::new(BufferedNode(rows.into_iter()))
+}
+
+struct BufferedNode(std::vec::IntoIter<Row>);
+
+impl Node for BufferedNode {
+    fn next(&mut self) -> Result<Option<Row>> {
+        Ok(self.0.next())
+    }
+}
+
+struct ValuesNode {
+    rows: std::vec::IntoIter<Vec<Scalar>>,
+}
+
+impl Node for ValuesNode {
+    fn next(&mut self) -> Result<Option<Row>> {
+        match self.rows.next() {
+            Some(exprs) => Ok(Some(
+                exprs.iter().map(|e| eval(e, &[])).collect::<Result<_>>()?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+struct FilterNode<'a> {
+    input: BoxedNode<'a>,
+    predicate: Scalar,
+}
+
+impl Node for FilterNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        while let Some(row) = self.input.next()? {
+            if passes(&self.predicate, &row)? {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+}
+
+struct ProjectNode<'a> {
+    input: BoxedNode<'a>,
+    exprs: Vec<Scalar>,
+}
+
+impl Node for ProjectNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        let Some(row) = self.input.next()? else {
+            return Ok(None);
+        };
+        Ok(Some(
+            self.exprs
+                .iter()
+                .map(|e| eval(e, &row))
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+struct LimitNode<'a> {
+    input: BoxedNode<'a>,
+    remaining: Option<u64>,
+    skip: u64,
+}
+
+impl Node for LimitNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        if self.remaining == Some(0) {
+            return Ok(None);
+        }
+        while self.skip > 0 {
+            if self.input.next()?.is_none() {
+                return Ok(None);
+            }
+            self.skip -= 1;
+        }
+        let row = self.input.next()?;
+        if row.is_some() {
+            if let Some(remaining) = &mut self.remaining {
+                *remaining -= 1;
+            }
+        }
+        Ok(row)
+    }
+}
+
+enum JoinState {
+    Build,
+    Probe(HashJoinProbe),
+    Spilled(Box<SpilledMatches>),
+    Done,
+}
+
+/// Join through a [`HashJoin`] built from one input and probed with the
+/// other.
+struct HashJoinNode<'a> {
+    build: BoxedNode<'a>,
+    probe: BoxedNode<'a>,
+    build_keys: Vec<Scalar>,
+    probe_keys: Vec<Scalar>,
+    filter: Option<Scalar>,
+    build_left: bool,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    /// Joined rows of the last probe row not yet returned.
+    pending: VecDeque<Row>,
+    state: JoinState,
+    grant: OperatorGrant,
+}
+
+impl HashJoinNode<'_> {
+    /// Joins a build row with a probe row, in the order of the inputs.
+    fn join(&mut self, build: &[Value], probe: &[Value]) -> Result<()> {
+        let (left, right) = match self.build_left {
+            true => (build, probe),
+            false => (probe, build),
+        };
+        if let Some(row) = joined(left, right, self.filter.as_ref())? {
+            self.pending.push_back(row);
+        }
+        Ok(())
+    }
+}
+
+impl Node for HashJoinNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Ok(Some(row));
+            }
+            match &mut self.state {
+                JoinState::Build => {
+                    let mut join =
+                        HashJoin::new(Arc::clone(&self.vfs), &self.dir, self.grant.take())?;
+                    let mut held = 0;
+                    while let Some(row) = self.build.next()? {
+                        let Some(key) = join_key(self.build_keys.iter(), &row)? else {
+                            continue;
+                        };
+                        let record = row_bytes(&row);
+                        held += key.len() + record.len() + size_of::<Row>();
+                        self.grant.record(held.min(self.grant.size()));
+                        join.build(&key, &record)?;
+                    }
+                    self.state = JoinState::Probe(join.probe()?);
+                }
+                JoinState::Probe(join) => {
+                    let Some(row) = self.probe.next()? else {
+                        let JoinState::Probe(join) = mem::replace(&mut self.state, JoinState::Done)
+                        else {
+                            unreachable!()
+                        };
+                        self.state = JoinState::Spilled(Box::new(join.finish()?));
+                        continue;
+                    };
+                    let Some(key) = join_key(self.probe_keys.iter(), &row)? else {
+                        continue;
+                    };
+                    let record = row_bytes(&row);
+                    let matches = join
+                        .probe(&key, &record)?
+                        .map(decode_row)
+                        .collect::<Result<Vec<_>>>()?;
+                    for build in matches {
+                        self.join(&build, &row)?;
+                    }
+                }
+                JoinState::Spilled(matches) => match matches.next().transpose()? {
+                    Some((build, probe)) => {
+                        let (build, probe) = (decode_row(&build)?, decode_row(&probe)?);
+                        self.join(&build, &probe)?;
+                    }
+                    None => {
+                        self.state = JoinState::Done;
+                        self.grant.finish();
+                    }
+                },
+                JoinState::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Right rows sharing a key.
+enum Group {
+    Memory(Vec<Row>),
+    /// Rows too many for the grant, written to a file.
+    Spilled(SpillReader),
+}
+
+/// Join of two inputs sorted by their keys, holding the right rows of one
+/// key at a time.
+struct MergeJoinNode<'a> {
+    left: BoxedNode<'a>,
+    right: BoxedNode<'a>,
+    left_keys: Vec<Scalar>,
+    right_keys: Vec<Scalar>,
+    filter: Option<Scalar>,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    /// Next right row not in the group, with its key.
+    head: Option<(Vec<u8>, Row)>,
+    right_done: bool,
+    group_key: Option<Vec<u8>>,
+    group: Group,
+    /// Left row being joined with the group, and the position of the next
+    /// right row in it if held in memory.
+    current: Option<Row>,
+    position: usize,
+    grant: OperatorGrant,
+}
+
+/// Next row of `input` whose key is not `NULL`, with its key.
+fn next_keyed(input: &mut BoxedNode<'_>, keys: &[Scalar]) -> Result<Option<(Vec<u8>, Row)>> {
+    while let Some(row) = input.next()? {
+        if let Some(key) = join_key(keys.iter(), &row)? {
+            return Ok(Some((key, row)));
+        }
+    }
+    Ok(None)
+}
+
+impl MergeJoinNode<'_> {
+    fn next_right(&mut self) -> Result<()> {
+        if self.head.is_none() && !self.right_done {
+            self.head = next_keyed(&mut self.right, &self.right_keys)?;
+            self.right_done = self.head.is_none();
+        }
+        Ok(())
+    }
+
+    /// Gathers the right rows whose key is `key`, skipping those before it.
+    fn load_group(&mut self, key: Vec<u8>) -> Result<()> {
+        self.group = Group::Memory(Vec::new());
+        let limit = self.grant.size();
+        let io = io_size(limit);
+        let mut held = 0;
+        let mut rows = Vec::new();
+        let mut writer: Option<SpillWriter> = None;
+        loop {
+            self.next_right()?;
+            match &self.head {
+                Some((k, _)) if *k < key => {}
+                Some((k, _)) if *k == key => {
+                    let (_, row) = self.head.take().unwrap();
+                    match &mut writer {
+                        Some(writer) => writer.write(&row_bytes(&row))?,
+                        None => {
+                            held += row_memory(&row);
+                            rows.push(row);
+                            if held + 2 * (io + SPILL_OVERHEAD) > limit {
+                                let file = SpillFile::create(&*self.vfs, &self.dir)?;
+                                let mut spill = SpillWriter::new(file, io);
+                                for row in rows.drain(..) {
+                                    spill.write(&row_bytes(&row))?;
+                                }
+                                held = 0;
+                                writer = Some(spill);
+                            }
+                        }
+                    }
+                    self.grant.record(held.min(limit));
+                    continue;
+                }
+                _ => break,
+            }
+            self.head = None;
+        }
+        self.group = match writer {
+            Some(writer) => Group::Spilled(SpillReader::new(writer.finish()?, io)),
+            None => Group::Memory(rows),
+        };
+        self.group_key = Some(key);
+        Ok(())
+    }
+}
+
+impl Node for MergeJoinNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        loop {
+            if let Some(left) = &self.current {
+                let right = match &mut self.group {
+                    Group::Memory(rows) => {
+                        self.position += 1;
+                        rows.get(self.position - 1).cloned()
+                    }
+                    Group::Spilled(reader) => match reader.advance()? {
+                        true => Some(decode_row(reader.record())?),
+                        false => None,
+                    },
+                };
+                match right {
+                    Some(right) => match joined(left, &right, self.filter.as_ref())? {
+                        Some(row) => return Ok(Some(row)),
+                        None => continue,
+                    },
+                    None => self.current = None,
+                }
+            }
+            let Some((key, left)) = next_keyed(&mut self.left, &self.left_keys)? else {
+                self.group = Group::Memory(Vec::new());
+                self.grant.finish();
+                return Ok(None);
+            };
+            if self.group_key.as_ref() != Some(&key) {
+                self.load_group(key)?;
+            }
+            match &mut self.group {
+                Group::Memory(rows) if rows.is_empty() => continue,
+                Group::Memory(_) => self.position = 0,
+                Group::Spilled(reader) => reader.rewind(),
+            }
+            self.current = Some(left);
+        }
+    }
+}
+
+/// Join of each block of left rows that fits in the grant with every right
+/// row. The right rows are written to a file on the first pass if more than
+/// one block is needed, and read back from it on the others.
+struct NestedLoopNode<'a> {
+    left: BoxedNode<'a>,
+    right: BoxedNode<'a>,
+    filter: Option<Scalar>,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    block: Vec<Row>,
+    started: bool,
+    left_done: bool,
+    /// File the right rows are written to on the first pass.
+    writer: Option<SpillWriter>,
+    /// File the right rows are read from on the other passes.
+    spooled: Option<SpillReader>,
+    right_row: Option<Row>,
+    /// Position in the block of the next left row joined with `right_row`.
+    position: usize,
+    grant: OperatorGrant,
+}
+
+impl NestedLoopNode<'_> {
+    fn load_block(&mut self) -> Result<()> {
+        self.block.clear();
+        let limit = self
+            .grant
+            .size()
+            .saturating_sub(2 * (io_size(self.grant.size()) + SPILL_OVERHEAD));
+        let mut held = 0;
+        while held < limit || self.block.is_empty() {
+            match self.left.next()? {
+                Some(row) => {
+                    held += row_memory(&row);
+                    self.block.push(row);
+                }
+                None => {
+                    self.left_done = true;
+                    break;
+                }
+            }
+        }
+        self.grant.record(held);
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.block = Vec::new();
+        self.spooled = None;
+        self.grant.finish();
+    }
+}
+
+impl Node for NestedLoopNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        loop {
+            if let Some(right) = &self.right_row {
+                while let Some(left) = self.block.get(self.position) {
+                    self.position += 1;
+                    if let Some(row) = joined(left, right, self.filter.as_ref())? {
+                        return Ok(Some(row));
+                    }
+                }
+                self.right_row = None;
+            }
+            if !self.started {
+                self.started = true;
+                self.load_block()?;
+                if self.block.is_empty() {
+                    self.finish();
+                    return Ok(None);
+                }
+                if !self.left_done {
+                    let file = SpillFile::create(&*self.vfs, &self.dir)?;
+                    self.writer = Some(SpillWriter::new(file, io_size(self.grant.size())));
+                }
+            }
+            let right = match &mut self.spooled {
+                None => match self.right.next()? {
+                    Some(row) => {
+                        if let Some(writer) = &mut self.writer {
+                            writer.write(&row_bytes(&row))?;
+                        }
+                        Some(row)
+                    }
+                    None => None,
+                },
+                Some(reader) => match reader.advance()? {
+                    true => Some(decode_row(reader.record())?),
+                    false => None,
+                },
+            };
+            match right {
+                Some(row) => {
+                    self.right_row = Some(row);
+                    self.position = 0;
+                }
+                None => {
+                    if self.left_done {
+                        self.finish();
+                        return Ok(None);
+                    }
+                    match (self.writer.take(), &mut self.spooled) {
+                        (Some(writer), _) => {
+                            let io = io_size(self.grant.size());
+                            self.spooled = Some(SpillReader::new(writer.finish()?, io));
+                        }
+                        (None, Some(reader)) => reader.rewind(),
+                        (None, None) => unreachable!(),
+                    }
+                    self.load_block()?;
+                }
+            }
+        }
+    }
+}
+
+/// Results being folded of the aggregate calls of a group: one value per
+/// call, but two for `AVG`, its sum and count.
+fn initial_state(calls: &[AggregateCall]) -> Row {
+    let mut state = Vec::new();
+    for call in calls {
+        match call.function {
+            AggregateFunction::Count => state.push(Value::Integer(0)),
+            AggregateFunction::Avg => state.extend([Value::Integer(0), Value::Integer(0)]),
+            _ => state.push(Value::Null),
+        }
+    }
+    state
+}
+
+/// Arguments of the aggregate calls for `row`; `COUNT(*)` counts a value
+/// that is never `NULL`.
+fn arguments(calls: &[AggregateCall], row: &[Value]) -> Result<Row> {
+    calls
+        .iter()
+        .map(|call| match &call.arg {
+            Some(arg) => eval(arg, row),
+            None => Ok(Value::Boolean(true)),
+        })
+        .collect()
+}
+
+fn add(sum: &mut Value, value: &Value, function: &str) -> Result<()> {
+    *sum = match (&*sum, value) {
+        (Value::Null, Value::Integer(n)) => Value::Integer(*n),
+        (Value::Integer(s), Value::Integer(n)) => Value::Integer(
+            s.checked_add(*n)
+                .ok_or_else(|| Error::invalid("integer overflow"))?,
+        ),
+        (_, v) => {
+            return Err(Error::invalid(format!(
+                "aggregate {function} does not apply to {}",
+                v.data_type().unwrap()
+            )));
+        }
+    };
+    Ok(())
+}
+
+/// Folds the arguments of a row into `state`; `NULL`s are skipped.
+fn fold(calls: &[AggregateCall], state: &mut [Value], args: &[Value]) -> Result<()> {
+    let mut slot = 0;
+    for (call, value) in calls.iter().zip(args) {
+        let skip = value.is_null();
+        match call.function {
+            AggregateFunction::Count if !skip => add(&mut state[slot], &Value::Integer(1), "")?,
+            AggregateFunction::Sum if !skip => add(&mut state[slot], value, "sum")?,
+            AggregateFunction::Avg => {
+                if !skip {
+                    add(&mut state[slot], value, "avg")?;
+                    add(&mut state[slot + 1], &Value::Integer(1), "")?;
+                }
+                slot += 1;
+            }
+            AggregateFunction::Min if !skip && (state[slot].is_null() || *value < state[slot]) => {
+                state[slot] = value.clone();
+            }
+            AggregateFunction::Max if !skip && (state[slot].is_null() || *value > state[slot]) => {
+                state[slot] = value.clone();
+            }
+            _ => {}
+        }
+        slot += 1;
+    }
+    Ok(())
+}
+
+/// Results of the aggregate calls from their folded state. `AVG` truncates
+/// towards zero, as `/` does, there being no fractional values.
+fn results(calls: &[AggregateCall], mut state: Row) -> Row {
+    let mut out = Vec::with_capacity(calls.len());
+    let mut values = state.drain(..);
+    for call in calls {
+        let value = values.next().unwrap();
+        out.push(match call.function {
+            AggregateFunction::Avg => match (value, values.next().unwrap()) {
+                (Value::Integer(sum), Value::Integer(count)) if count > 0 => {
+                    Value::Integer(sum / count)
+                }
+                _ => Value::Null,
+            },
+            _ => value,
+        });
+    }
+    out
+}
+
+/// Aggregate calls folded over encoded states, for a [`HashAggregate`].
+struct Accumulators {
+    calls: Vec<AggregateCall>,
+}
+
+impl Aggregate for Accumulators {
+    fn init(&self) -> Vec<u8> {
+        row_bytes(&initial_state(&self.calls))
+    }
+
+    fn update(&self, state: &mut Vec<u8>, value: &[u8]) -> Result<()> {
+        let mut folded = decode_row(state)?;
+        fold(&self.calls, &mut folded, &decode_row(value)?)?;
+        state.clear();
+        encode_row(&folded, state);
+        Ok(())
+    }
+}
+
+/// Aggregation through a [`HashAggregate`].
+struct HashAggregateNode<'a> {
+    input: BoxedNode<'a>,
+    group_by: Vec<Scalar>,
+    aggregates: Vec<AggregateCall>,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    groups: Option<Groups<Accumulators>>,
+    done: bool,
+    grant: OperatorGrant,
+}
+
+impl Node for HashAggregateNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        if self.done {
+            return Ok(None);
+        }
+        let next = self.advance();
+        // Input left halfway through cannot give correct rows anymore.
+        if next.is_err() {
+            self.done = true;
+        }
+        next
+    }
+}
+
+impl HashAggregateNode<'_> {
+    fn advance(&mut self) -> Result<Option<Row>> {
+        if self.groups.is_none() {
+            let accumulators = Accumulators {
+                calls: self.aggregates.clone(),
+            };
+            let mut aggregate = HashAggregate::new(
+                Arc::clone(&self.vfs),
+                &self.dir,
+                self.grant.take(),
+                accumulators,
+            )?;
+            let mut held = 0;
+            let mut empty = true;
+            while let Some(row) = self.input.next()? {
+                empty = false;
+                let key = key_bytes(
+                    &self
+                        .group_by
+                        .iter()
+                        .map(|g| eval(g, &row))
+                        .collect::<Result<Vec<_>>>()?,
+                );
+                let value = row_bytes(&arguments(&self.aggregates, &row)?);
+                held += key.len() + value.len();
+                self.grant.record(held.min(self.grant.size()));
+                aggregate.push(&key, &value)?;
+            }
+            // Aggregates without groups have a result even for no rows.
+            if empty && self.group_by.is_empty() {
+                drop(aggregate);
+                self.done = true;
+                self.grant.finish();
+                let state = initial_state(&self.aggregates);
+                return Ok(Some(results(&self.aggregates, state)));
+            }
+            self.groups = Some(aggregate.finish()?);
+        }
+        match self.groups.as_mut().unwrap().next().transpose()? {
+            Some((key, state)) => {
+                let mut row = decode_key(&key)?;
+                row.extend(results(&self.aggregates, decode_row(&state)?));
+                Ok(Some(row))
+            }
+            None => {
+                self.done = true;
+                self.groups = None;
+                self.grant.finish();
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Aggregation of an input sorted by its group keys, one group at a time.
+struct StreamAggregateNode<'a> {
+    input: BoxedNode<'a>,
+    group_by: Vec<Scalar>,
+    aggregates: Vec<AggregateCall>,
+    /// Key values and state of the group being folded, with its key.
+    group: Option<(Vec<u8>, Row, Row)>,
+    started: bool,
+    done: bool,
+}
+
+impl Node for StreamAggregateNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        if self.done {
+            return Ok(None);
+        }
+        let next = self.advance();
+        // Input left halfway through cannot give correct rows anymore.
+        if next.is_err() {
+            self.done = true;
+        }
+        next
+    }
+}
+
+impl StreamAggregateNode<'_> {
+    fn advance(&mut self) -> Result<Option<Row>> {
+        loop {
+            let Some(row) = self.input.next()? else {
+                self.done = true;
+                let group = match self.group.take() {
+                    Some(group) => group,
+                    // Aggregates without groups have a result even for no
+                    // rows.
+                    None if !self.started && self.group_by.is_empty() => {
+                        (Vec::new(), Vec::new(), initial_state(&self.aggregates))
+                    }
+                    None => return Ok(None),
+                };
+                return Ok(Some(self.output(group)));
+            };
+            self.started = true;
+            let values = self
+                .group_by
+                .iter()
+                .map(|g| eval(g, &row))
+                .collect::<Result<Vec<_>>>()?;
+            let key = key_bytes(&values);
+            let args = arguments(&self.aggregates, &row)?;
+            let finished = match &mut self.group {
+                Some((k, _, state)) if *k == key => {
+                    fold(&self.aggregates, state, &args)?;
+                    continue;
+                }
+                group => group.take(),
+            };
+            let mut state = initial_state(&self.aggregates);
+            fold(&self.aggregates, &mut state, &args)?;
+            self.group = Some((key, values, state));
+            if let Some(group) = finished {
+                return Ok(Some(self.output(group)));
+            }
+        }
+    }
+
+    fn output(&self, (_, mut values, state): (Vec<u8>, Row, Row)) -> Row {
+        values.extend(results(&self.aggregates, state));
+        values
+    }
+}
+
+/// Sort through an [`ExternalSort`] of records made of the sort key and the
+/// row.
+struct SortNode<'a> {
+    input: BoxedNode<'a>,
+    keys: Vec<SortKey>,
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    sorted: Option<SortedRecords>,
+    done: bool,
+    grant: OperatorGrant,
+}
+
+impl Node for SortNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        if self.done {
+            return Ok(None);
+        }
+        let next = self.advance();
+        // Input left halfway through cannot give correct rows anymore.
+        if next.is_err() {
+            self.done = true;
+        }
+        next
+    }
+}
+
+impl SortNode<'_> {
+    fn advance(&mut self) -> Result<Option<Row>> {
+        if self.sorted.is_none() {
+            let mut sort = ExternalSort::with_comparator(
+                Arc::clone(&self.vfs),
+                &self.dir,
+                self.grant.take(),
+                compare_keys as Compare,
+            )?;
+            let mut held = 0;
+            let mut record = Vec::new();
+            while let Some(row) = self.input.next()? {
+                record.clear();
+                put_bytes(&mut record, &sort_key(&self.keys, &row)?);
+                encode_row(&row, &mut record);
+                held += record.len() + size_of::<Vec<u8>>();
+                self.grant.record(held.min(self.grant.size()));
+                sort.push(&record)?;
+            }
+            self.sorted = Some(sort.finish()?);
+        }
+        match self.sorted.as_mut().unwrap().next().transpose()? {
+            Some(record) => Ok(Some(decode_row(split_pair(&record)?.1)?)),
+            None => {
+                self.done = true;
+                self.sorted = None;
+                self.grant.finish();
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Row kept by a [`TopNNode`], ordered by its sort key and then by arrival
+/// so that equal rows keep their input order.
+struct Ranked {
+    key: Vec<u8>,
+    seq: u64,
+    row: Row,
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.key, self.seq).cmp(&(&other.key, other.seq))
+    }
+}
+
+/// First `limit` rows in the order of `keys`, kept in a heap whose largest
+/// row is dropped whenever it grows past `limit`. Its grant is what the
+/// optimizer expected the rows to take; it grows from the budget if they
+/// take more.
+struct TopNNode<'a> {
+    input: BoxedNode<'a>,
+    keys: Vec<SortKey>,
+    limit: u64,
+    output: Option<std::vec::IntoIter<Ranked>>,
+    grant: OperatorGrant,
+}
+
+impl Node for TopNNode<'_> {
+    fn next(&mut self) -> Result<Option<Row>> {
+        if self.output.is_none() {
+            let mut heap = BinaryHeap::new();
+            let mut held = 0;
+            let mut seq = 0;
+            while let Some(row) = self.input.next()? {
+                let ranked = Ranked {
+                    key: sort_key(&self.keys, &row)?,
+                    seq,
+                    row,
+                };
+                seq += 1;
+                if heap.len() as u64 == self.limit {
+                    match heap.peek_mut() {
+                        Some(mut top) if ranked < *top => {
+                            held -= ranked_memory(&top);
+                            held += ranked_memory(&ranked);
+                            *top = ranked;
+                        }
+                        _ => continue,
+                    }
+                } else {
+                    held += ranked_memory(&ranked);
+                    heap.push(ranked);
+                }
+                let reservation = self.grant.reservation();
+                if held > reservation.size() {
+                    reservation.grow(held - reservation.size())?;
+                }
+                self.grant.record(held);
+            }
+            self.output = Some(heap.into_sorted_vec().into_iter());
+        }
+        match self.output.as_mut().unwrap().next() {
+            Some(ranked) => Ok(Some(ranked.row)),
+            None => {
+                self.output = Some(Vec::new().into_iter());
+                self.grant.finish();
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn ranked_memory(ranked: &Ranked) -> usize {
+    size_of::<Ranked>() + ranked.key.len() + row_memory(&ranked.row)
+}
@@ -0,0 +1,167 @@
+//! Splitting of SQL text into tokens.
+
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    /// Identifier or keyword, lowercased unless it was quoted.
+    Word {
+        text: String,
+        quoted: bool,
+    },
+    Integer(i64),
+    String(String),
+    LeftParen,
+    RightParen,
+    Comma,
+    Semicolon,
+    Dot,
+    Star,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Token::Word { text, .. } => return f.write_str(text),
+            Token::Integer(n) => return write!(f, "{n}"),
+            Token::String(s) => return write!(f, "'{s}'"),
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::Comma => ",",
+            Token::Semicolon => ";",
+            Token::Dot => ".",
+            Token::Star => "*",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Eq => "=",
+            Token::NotEq => "<>",
+            Token::Lt => "<",
+            Token::LtEq => "<=",
+            Token::Gt => ">",
+            Token::GtEq => ">=",
+        };
+        f.write_str(symbol)
+    }
+}
+
+/// Token with the byte offset it starts at, for error messages.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Spanned {
+    pub(crate) token: Token,
+    pub(crate) offset: usize,
+}
+
+pub(crate) fn syntax_error(offset: usize, msg: impl fmt::Display) -> Error {
+    Error::invalid(format!("syntax error at offset {offset}: {msg}"))
+}
+
+pub(crate) fn tokenize(sql: &str) -> Result<Vec<Spanned>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let offset = i;
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == b'-' && bytes.get(i + 1) == Some(&b'-') {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+        let token = match c {
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                Token::Word {
+                    text: sql[offset..i].to_ascii_lowercase(),
+                    quoted: false,
+                }
+            }
+            b'0'..=b'9' => {
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let value = sql[offset..i]
+                    .parse()
+                    .map_err(|_| syntax_error(offset, "integer out of range"))?;
+                Token::Integer(value)
+            }
+            b'\'' | b'"' => {
+                let (text, end) = quoted(sql, i)?;
+                i = end;
+                match c {
+                    b'\'' => Token::String(text),
+                    _ => Token::Word { text, quoted: true },
+                }
+            }
+            _ => {
+                let next = bytes.get(i + 1).copied();
+                let (token, len) = match (c, next) {
+                    (b'<', Some(b'=')) => (Token::LtEq, 2),
+                    (b'>', Some(b'=')) => (Token::GtEq, 2),
+                    (b'<', Some(b'>')) | (b'!', Some(b'=')) => (Token::NotEq, 2),
+                    (b'(', _) => (Token::LeftParen, 1),
+                    (b')', _) => (Token::RightParen, 1),
+                    (b',', _) => (Token::Comma, 1),
+                    (b';', _) => (Token::Semicolon, 1),
+                    (b'.', _) => (Token::Dot, 1),
+                    (b'*', _) => (Token::Star, 1),
+                    (b'+', _) => (Token::Plus, 1),
+                    (b'-', _) => (Token::Minus, 1),
+                    (b'/', _) => (Token::Slash, 1),
+                    (b'%', _) => (Token::Percent, 1),
+                    (b'=', _) => (Token::Eq, 1),
+                    (b'<', _) => (Token::Lt, 1),
+                    (b'>', _) => (Token::Gt, 1),
+                    _ => {
+                        let c = sql[offset..].chars().next().unwrap_or_default();
+                        return Err(syntax_error(offset, format!("unexpected character {c:?}")));
+                    }
+                };
+                i += len;
+                token
+            }
+        };
+        tokens.push(Spanned { token, offset });
+    }
+    Ok(tokens)
+}
+
+/// Reads the quoted text starting at `start`, where a doubled quote stands
+/// for itself, returning it and the offset past the closing quote.
+fn quoted(sql: &str, start: usize) -> Result<(String, usize)> {
+    let quote = sql.as_bytes()[start];
+    let mut text = String::new();
+    let mut i = start + 1;
+    loop {
+        let Some(end) = sql[i..].find(quote as char) else {
+            return Err(syntax_error(start, "unterminated quoted text"));
+        };
+        text.push_str(&sql[i..i + end]);
+        i += end + 1;
+        if sql.as_bytes().get(i) != Some(&quote) {
+            return Ok((text, i));
+        }
+        text.push(quote as char);
+        i += 1;
+    }
+}
@@ -0,0 +1,56 @@
+//! SQL front end.
+//!
+//! [`parse`] turns the text of a statement into its [`ast`], and [`plan`]
+//! resolves it against a [`Catalog`] into a logical [`Plan`] of relational
+//! operators. [`optimize`] then picks the algorithm running each operator
+//! and the memory grant it gets, giving a [`PhysicalPlan`] whose
+//! [`explain`](PhysicalPlan::explain) output is what `EXPLAIN` returns.
+//! A [`Database`] runs the plans over a [`TransactionDb`](crate::TransactionDb)
+//! as trees of operators pulling rows from one another, each within its
+//! grant, after admitting the memory of the whole plan from the
+//! [`QueryMemory`] concurrent statements share.
+//!
+//! The dialect is a small subset of standard SQL: `CREATE TABLE` with
+//! `INTEGER`, `TEXT` and `BOOLEAN` columns and a primary key; `CREATE
+//! [UNIQUE] INDEX` on columns or expressions of a table; `INSERT` of
+//! values or of a query; `UPDATE` and `DELETE` with a `WHERE` clause; and
+//! `SELECT` with inner and cross joins, `WHERE`, `GROUP BY` with `COUNT`,
+//! `SUM`, `MIN`, `MAX` and `AVG`, `HAVING`, `DISTINCT`, `ORDER BY`, `LIMIT`
+//! and `OFFSET`; `ANALYZE` of a table or of every table, which gathers the
+//! statistics the optimizer estimates with; and `EXPLAIN` of any of them.
+//! Unquoted names are case-insensitive. There are no fractional numbers:
+//! `/` divides integers truncating towards zero, and so does `AVG`, whose
+//! result is an `INTEGER`.
+
+mod admission;
+pub mod ast;
+mod catalog;
+mod database;
+mod eval;
+mod executor;
+mod lexer;
+mod optimizer;
+mod parser;
+mod physical;
+mod plan;
+mod row;
+mod statistics;
+mod value;
+
+pub use admission::{OperatorUsage, Priority, QueryGrant, QueryMemory, QueryMemoryStats};
+pub use catalog::{Catalog, Column, IndexSchema, TableSchema};
+pub use database::{Database, Output, Rows};
+pub use optimizer::optimize;
+pub use physical::{Estimate, Operator, PhysicalPlan};
+pub use plan::{plan, AggregateCall, AggregateFunction, Field, Plan, Scalar, SortKey};
+pub use statistics::{
+    Analyzer, ColumnStatistics, Histogram, TableStatistics, ANALYZE_GRANT, MIN_ANALYZE_GRANT,
+};
+pub use value::{DataType, Value};
+
+use crate::error::Result;
+
+/// Parses a single SQL statement, optionally ending with a semicolon.
+pub fn parse(sql: &str) -> Result<ast::Statement> {
+    parser::Parser::new(sql)?.statement()
+}
@@ -0,0 +1,1247 @@
+//! Cost-based choice of physical plans.
+//!
+//! Each logical operator is turned into the cheapest of the physical ones
+//! that can run it, costed as the pages it reads and writes plus the rows it
+//! processes. Memory enters the cost: an operator that holds rows is given a
+//! share of the query's memory as its grant, and the pages it must spill once
+//! its input outgrows the grant are counted. A hash join whose build side
+//! does not fit costs the partitions it writes and reads back, so when memory
+//! is scarce a merge join over inputs already in key order, or cheaply
+//! sorted, wins; a sort whose input does not fit costs its merge passes; a
+//! filter on the leading keys of the primary key or of a secondary index of
+//! a table is run as an index scan when that reads fewer pages, counting a
+//! random read for each row a secondary index does not hold all the columns
+//! of. Which columns those are is worked out from the top of the plan down,
+//! so a scan of an index holding every column read above it never touches
+//! the table.
+//!
+//! Row counts come from the [`TableStatistics`](super::TableStatistics) of
+//! the catalog. For an analyzed table they include the distinct count, null
+//! fraction and histogram of each column, which give the fraction of rows a
+//! comparison with a constant keeps and the rows a join or grouping yields;
+//! otherwise conditions are assumed to keep fixed fractions of rows.
+
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::ast::BinaryOp;
+use super::catalog::Catalog;
+use super::physical::{ColumnEstimate, Estimate, Operator, PhysicalPlan};
+use super::plan::{AggregateCall, AggregateFunction, Field, Plan, Scalar, SortKey};
+use super::statistics::{ANALYZE_GRANT, MIN_ANALYZE_GRANT};
+use super::value::Value;
+use crate::error::{Error, Result};
+use crate::exec::{MIN_AGGREGATE_GRANT, MIN_JOIN_GRANT, MIN_SORT_GRANT};
+
+const PAGE_SIZE: f64 = 4096.0;
+
+/// Cost of reading or writing a page in sequence.
+const SEQ_PAGE_COST: f64 = 1.0;
+
+/// Cost of a page read that must first seek.
+const RANDOM_PAGE_COST: f64 = 4.0;
+
+/// Cost of handling a row.
+const CPU_ROW_COST: f64 = 0.01;
+
+/// Cost of evaluating an operator or comparison.
+const CPU_OPERATOR_COST: f64 = 0.0025;
+
+/// Bytes an operator holding a row in memory spends beyond its values.
+const ROW_OVERHEAD: f64 = 48.0;
+
+/// Bytes assumed for a value computed by an expression.
+const EXPR_WIDTH: f64 = 8.0;
+
+/// Fractions of rows assumed to pass conditions of each kind.
+const EQ_SELECTIVITY: f64 = 0.005;
+const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+const NULL_SELECTIVITY: f64 = 0.01;
+const DEFAULT_SELECTIVITY: f64 = 0.5;
+
+/// Fraction of its input rows assumed to be distinct groups.
+const GROUP_FRACTION: f64 = 0.1;
+
+/// Chooses a physical plan for `plan`, whose memory-holding steps are given
+/// grants adding up to at most `memory` bytes.
+pub fn optimize(plan: &Plan, catalog: &Catalog, memory: usize) -> Result<PhysicalPlan> {
+    let holders = memory_holders(plan).max(1);
+    let optimizer = Optimizer {
+        catalog,
+        share: memory / holders,
+    };
+    let mut physical = optimizer.build(plan, None, &all(plan))?;
+    fit_grants(&mut physical, memory)?;
+    Ok(physical)
+}
+
+/// Number of operators that may hold rows in memory, counting a join twice
+/// for the sort it may need.
+fn memory_holders(plan: &Plan) -> usize {
+    match plan {
+        Plan::CreateTable { .. }
+        | Plan::CreateIndex { .. }
+        | Plan::Scan { .. }
+        | Plan::Values { .. } => 0,
+        Plan::Insert { source, .. } | Plan::Update { source, .. } | Plan::Delete { source, .. } => {
+            memory_holders(source)
+        }
+        Plan::Filter { input, .. } | Plan::Project { input, .. } | Plan::Limit { input, .. } => {
+            memory_holders(input)
+        }
+        Plan::Explain(input) => memory_holders(input),
+        Plan::Analyze { .. } => 1,
+        Plan::Aggregate { input, .. } | Plan::Sort { input, .. } => 1 + memory_holders(input),
+        Plan::Join { left, right, .. } => 2 + memory_holders(left) + memory_holders(right),
+    }
+}
+
+/// Smallest grant the step can run with.
+fn min_grant(plan: &PhysicalPlan) -> usize {
+    match plan.operator {
+        Operator::Sort { .. } => MIN_SORT_GRANT,
+        Operator::HashJoin { .. }
+        | Operator::MergeJoin { .. }
+        | Operator::NestedLoopJoin { .. } => MIN_JOIN_GRANT,
+        Operator::HashAggregate { .. } => MIN_AGGREGATE_GRANT,
+        Operator::Analyze => MIN_ANALYZE_GRANT,
+        _ => plan.estimate.grant,
+    }
+}
+
+pub(crate) fn total_min_grant(plan: &PhysicalPlan) -> usize {
+    min_grant(plan) + plan.inputs.iter().map(total_min_grant).sum::<usize>()
+}
+
+/// Shrinks the grants of `plan` towards their minimums until they add up to
+/// at most `memory` bytes.
+pub(crate) fn fit_grants(plan: &mut PhysicalPlan, memory: usize) -> Result<()> {
+    let total = plan.total_grant();
+    if total <= memory {
+        return Ok(());
+    }
+    let min = total_min_grant(plan);
+    if min > memory {
+        return Err(Error::invalid(format!(
+            "query needs at least {min} bytes of memory, but only {memory} may be granted"
+        )));
+    }
+    let ratio = (memory - min) as f64 / (total - min) as f64;
+    scale_grants(plan, ratio);
+    Ok(())
+}
+
+fn scale_grants(plan: &mut PhysicalPlan, ratio: f64) {
+    let min = min_grant(plan);
+    let grant = &mut plan.estimate.grant;
+    *grant = min + ((*grant - min) as f64 * ratio) as usize;
+    for input in &mut plan.inputs {
+        scale_grants(input, ratio);
+    }
+}
+
+fn pages(bytes: f64) -> f64 {
+    (bytes / PAGE_SIZE).ceil()
+}
+
+fn row_size(columns: &[ColumnEstimate]) -> f64 {
+    columns.iter().map(|c| c.width).sum()
+}
+
+/// Estimates for the values of `expr` over rows with `columns`.
+fn estimate_expr(expr: &Scalar, columns: &[ColumnEstimate]) -> ColumnEstimate {
+    match expr {
+        Scalar::Column(i) => columns[*i].clone(),
+        Scalar::Literal(value) => ColumnEstimate {
+            width: match value {
+                Value::Text(s) => s.len() as f64,
+                _ => EXPR_WIDTH,
+            },
+            distinct: 1.0,
+            null_fraction: if value.is_null() { 1.0 } else { 0.0 },
+            histogram: None,
+        },
+        _ => {
+            let mut distinct: f64 = 1.0;
+            expr.visit_columns(&mut |i| distinct = distinct.max(columns[i].distinct));
+            ColumnEstimate {
+                width: EXPR_WIDTH,
+                distinct,
+                null_fraction: NULL_SELECTIVITY,
+                histogram: None,
+            }
+        }
+    }
+}
+
+/// `columns` of an input cut down to `rows` rows.
+fn capped(columns: &[ColumnEstimate], rows: f64) -> Vec<ColumnEstimate> {
+    columns
+        .iter()
+        .map(|c| ColumnEstimate {
+            distinct: c.distinct.min(rows).max(1.0),
+            ..c.clone()
+        })
+        .collect()
+}
+
+/// Fraction of rows with `columns` assumed to satisfy `predicate`.
+fn selectivity(predicate: &Scalar, columns: &[ColumnEstimate]) -> f64 {
+    match predicate {
+        Scalar::Binary { op, left, right } => {
+            let constant = [&**left, &**right].iter().find_map(|side| match side {
+                Scalar::Column(c) => compared(predicate, side).map(|(op, v)| (*c, op, v)),
+                _ => None,
+            });
+            if let Some((c, op, value)) = constant {
+                let column = &columns[c];
+                return match op {
+                    BinaryOp::Eq => equal_selectivity(column),
+                    BinaryOp::Lt => {
+                        range_selectivity(column, &Bound::Unbounded, &Bound::Excluded(value))
+                    }
+                    BinaryOp::LtEq => {
+                        range_selectivity(column, &Bound::Unbounded, &Bound::Included(value))
+                    }
+                    BinaryOp::Gt => {
+                        range_selectivity(column, &Bound::Excluded(value), &Bound::Unbounded)
+                    }
+                    _ => range_selectivity(column, &Bound::Included(value), &Bound::Unbounded),
+                };
+            }
+            match op {
+                BinaryOp::And => selectivity(left, columns) * selectivity(right, columns),
+                BinaryOp::Or => {
+                    let (a, b) = (selectivity(left, columns), selectivity(right, columns));
+                    a + b - a * b
+                }
+                BinaryOp::Eq => EQ_SELECTIVITY,
+                BinaryOp::NotEq => 1.0 - EQ_SELECTIVITY,
+                BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq => RANGE_SELECTIVITY,
+                _ => DEFAULT_SELECTIVITY,
+            }
+        }
+        Scalar::Unary { expr, .. } => 1.0 - selectivity(expr, columns),
+        Scalar::IsNull { expr, negated } => {
+            let nulls = estimate_expr(expr, columns).null_fraction;
+            match negated {
+                true => 1.0 - nulls,
+                false => nulls,
+            }
+        }
+        Scalar::Literal(Value::Boolean(true)) => 1.0,
+        Scalar::Literal(_) => 0.0,
+        Scalar::Column(_) => DEFAULT_SELECTIVITY,
+    }
+}
+
+/// Fraction of rows whose value of `column` equals a given constant.
+fn equal_selectivity(column: &ColumnEstimate) -> f64 {
+    match column.histogram {
+        Some(_) => (1.0 - column.null_fraction) / column.distinct,
+        None => EQ_SELECTIVITY,
+    }
+}
+
+/// Fraction of rows whose value of `column` lies between `lower` and
+/// `upper`.
+fn range_selectivity(column: &ColumnEstimate, lower: &Bound<Value>, upper: &Bound<Value>) -> f64 {
+    let Some(histogram) = &column.histogram else {
+        let bounded = [lower, upper]
+            .iter()
+            .filter(|b| ***b != Bound::Unbounded)
+            .count();
+        return RANGE_SELECTIVITY.powi(bounded as i32);
+    };
+    let from = match lower {
+        Bound::Included(v) => histogram.fraction_below(v, false),
+        Bound::Excluded(v) => histogram.fraction_below(v, true),
+        Bound::Unbounded => 0.0,
+    };
+    let to = match upper {
+        Bound::Included(v) => histogram.fraction_below(v, true),
+        Bound::Excluded(v) => histogram.fraction_below(v, false),
+        Bound::Unbounded => 1.0,
+    };
+    (1.0 - column.null_fraction) * (to - from).max(0.0)
+}
+
+/// Conditions of a filter bounding the keys of an index: equalities fixing
+/// its leading keys, then bounds of the next one.
+struct KeyBounds {
+    prefix: Vec<Value>,
+    lower: Bound<Value>,
+    upper: Bound<Value>,
+    /// Conditions left for a filter above the scan.
+    rest: Vec<Scalar>,
+}
+
+impl KeyBounds {
+    /// Takes the conditions on `keys` out of `rest`.
+    fn new(keys: &[Scalar], mut rest: Vec<Scalar>) -> Self {
+        let mut prefix = Vec::new();
+        let mut lower = Bound::Unbounded;
+        let mut upper = Bound::Unbounded;
+        for key in keys {
+            let equal = rest
+                .iter()
+                .position(|p| matches!(compared(p, key), Some((BinaryOp::Eq, _))));
+            if let Some(i) = equal {
+                let Some((_, value)) = compared(&rest.remove(i), key) else {
+                    unreachable!()
+                };
+                prefix.push(value);
+                continue;
+            }
+            let mut i = 0;
+            while i < rest.len() {
+                let bound = match compared(&rest[i], key) {
+                    Some((BinaryOp::Gt | BinaryOp::GtEq, _)) if lower == Bound::Unbounded => {
+                        &mut lower
+                    }
+                    Some((BinaryOp::Lt | BinaryOp::LtEq, _)) if upper == Bound::Unbounded => {
+                        &mut upper
+                    }
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let Some((op, value)) = compared(&rest.remove(i), key) else {
+                    unreachable!()
+                };
+                *bound = match op {
+                    BinaryOp::Gt | BinaryOp::Lt => Bound::Excluded(value),
+                    _ => Bound::Included(value),
+                };
+            }
+            break;
+        }
+        KeyBounds {
+            prefix,
+            lower,
+            upper,
+            rest,
+        }
+    }
+
+    fn is_bounded(&self) -> bool {
+        !self.prefix.is_empty() || self.lower != Bound::Unbounded || self.upper != Bound::Unbounded
+    }
+
+    /// Rows, of `rows` with `columns`, within the bounds of an index keyed by
+    /// `keys`.
+    fn matched(&self, keys: &[Scalar], unique: bool, rows: f64, columns: &[ColumnEstimate]) -> f64 {
+        if unique && self.prefix.len() == keys.len() {
+            return 1.0_f64.min(rows);
+        }
+        let equal: f64 = keys[..self.prefix.len()]
+            .iter()
+            .map(|key| equal_selectivity(&estimate_expr(key, columns)))
+            .product();
+        let mut matched = rows * equal;
+        if let Some(next) = keys.get(self.prefix.len()) {
+            if self.lower != Bound::Unbounded || self.upper != Bound::Unbounded {
+                let next = estimate_expr(next, columns);
+                matched *= range_selectivity(&next, &self.lower, &self.upper);
+            }
+        }
+        matched
+    }
+}
+
+fn split_conjuncts(predicate: &Scalar, out: &mut Vec<Scalar>) {
+    match predicate {
+        Scalar::Binary {
+            op: BinaryOp::And,
+            left,
+            right,
+        } => {
+            split_conjuncts(left, out);
+            split_conjuncts(right, out);
+        }
+        predicate => out.push(predicate.clone()),
+    }
+}
+
+fn conjunction(predicates: Vec<Scalar>) -> Option<Scalar> {
+    predicates.into_iter().reduce(|left, right| Scalar::Binary {
+        op: BinaryOp::And,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+/// Whether rows in `ordering` are also in the order of `keys`.
+fn satisfies(ordering: &[SortKey], keys: &[SortKey]) -> bool {
+    keys.len() <= ordering.len() && ordering.iter().zip(keys).all(|(o, k)| o == k)
+}
+
+/// Whether rows in `ordering` come grouped by the values of `group_by`.
+fn groups_in_order(ordering: &[SortKey], group_by: &[Scalar]) -> bool {
+    group_by.len() <= ordering.len()
+        && ordering[..group_by.len()]
+            .iter()
+            .all(|k| group_by.contains(&k.expr))
+}
+
+fn ascending(exprs: impl IntoIterator<Item = Scalar>) -> Vec<SortKey> {
+    exprs
+        .into_iter()
+        .map(|expr| SortKey {
+            expr,
+            descending: false,
+        })
+        .collect()
+}
+
+/// Cost of sorting `rows` rows of `row_size` bytes within `grant`, with the
+/// merge passes spilled runs need.
+fn sort_cost(rows: f64, row_size: f64, grant: usize) -> f64 {
+    let cpu = rows * rows.max(2.0).log2() * CPU_OPERATOR_COST;
+    let bytes = rows * (row_size + ROW_OVERHEAD);
+    let grant = grant as f64;
+    if bytes <= grant {
+        return cpu;
+    }
+    let runs = (bytes / grant).ceil();
+    let io_size = (grant / 32.0).clamp(1024.0, 256.0 * 1024.0);
+    let fan_in = (grant / 4.0 / io_size).max(2.0);
+    let passes = runs.log(fan_in).ceil().max(1.0);
+    cpu + 2.0 * passes * pages(rows * row_size) * SEQ_PAGE_COST
+}
+
+/// Cost of writing and reading back the `fraction` of `bytes` that spills.
+fn spill_cost(fraction: f64, bytes: f64) -> f64 {
+    2.0 * fraction * pages(bytes) * SEQ_PAGE_COST
+}
+
+/// Fraction of an input needing `ideal` bytes that spills within `grant`.
+fn spilled(ideal: f64, grant: usize) -> f64 {
+    (1.0 - grant as f64 / ideal).max(0.0)
+}
+
+struct Optimizer<'a> {
+    catalog: &'a Catalog,
+    /// Memory each operator holding rows may be granted.
+    share: usize,
+}
+
+impl Optimizer<'_> {
+    /// Grant for an operator that would like `ideal` bytes and needs at
+    /// least `min`.
+    fn grant(&self, ideal: f64, min: usize) -> usize {
+        (ideal.ceil() as usize).clamp(min, self.share.max(min))
+    }
+
+    /// Builds the physical plan of `plan`; `top`, if given, is the number of
+    /// its first rows that are used, and `read` tells which of its columns
+    /// are.
+    fn build(&self, plan: &Plan, top: Option<u64>, read: &[bool]) -> Result<PhysicalPlan> {
+        Ok(match plan {
+            Plan::CreateTable {
+                schema,
+                if_not_exists,
+            } => leaf(
+                Operator::CreateTable {
+                    schema: schema.clone(),
+                    if_not_exists: *if_not_exists,
+                },
+                0.0,
+                0.0,
+            ),
+            Plan::CreateIndex {
+                index,
+                if_not_exists,
+                scan,
+            } => {
+                let Plan::Scan { table, fields } = &**scan else {
+                    unreachable!("indexes are created from table scans")
+                };
+                let operator = Operator::CreateIndex {
+                    index: index.clone(),
+                    if_not_exists: *if_not_exists,
+                };
+                self.write(operator, self.seq_scan(table, fields))
+            }
+            Plan::Insert {
+                table,
+                columns,
+                source,
+            } => {
+                let operator = Operator::Insert {
+                    table: table.clone(),
+                    columns: columns.clone(),
+                };
+                self.write(operator, self.build(source, None, &all(source))?)
+            }
+            Plan::Update {
+                table,
+                assignments,
+                source,
+            } => {
+                let operator = Operator::Update {
+                    table: table.clone(),
+                    assignments: assignments.clone(),
+                };
+                self.write(operator, self.build(source, None, &all(source))?)
+            }
+            Plan::Delete { table, source } => {
+                let operator = Operator::Delete {
+                    table: table.clone(),
+                };
+                self.write(operator, self.build(source, None, &all(source))?)
+            }
+            Plan::Scan { table, fields } => {
+                let scans = self.index_scans(table, fields, None, read);
+                cheapest(self.seq_scan(table, fields), scans)
+            }
+            Plan::Values { rows, fields } => {
+                let count = rows.len() as f64;
+                let columns = match rows.first() {
+                    Some(row) => row
+                        .iter()
+                        .map(|e| ColumnEstimate {
+                            distinct: count,
+                            ..estimate_expr(e, &[])
+                        })
+                        .collect(),
+                    None => Vec::new(),
+                };
+                PhysicalPlan {
+                    operator: Operator::Values { rows: rows.clone() },
+                    inputs: Vec::new(),
+                    fields: fields.clone(),
+                    ordering: Vec::new(),
+                    estimate: estimate(count, &columns, count * CPU_ROW_COST, 0),
+                    columns,
+                }
+            }
+            Plan::Filter { input, predicate } => {
+                let mut read = read.to_vec();
+                mark(&mut read, predicate);
+                let best = self.filter(self.build(input, None, &read)?, predicate.clone());
+                match &**input {
+                    Plan::Scan { table, fields } => cheapest(
+                        best,
+                        self.index_scans(table, fields, Some(predicate), &read),
+                    ),
+                    _ => best,
+                }
+            }
+            Plan::Project {
+                input,
+                exprs,
+                fields,
+            } => {
+                let mut input_read = vec![false; input.fields().len()];
+                for (expr, _) in exprs.iter().zip(read).filter(|(_, &read)| read) {
+                    mark(&mut input_read, expr);
+                }
+                let input = self.build(input, top, &input_read)?;
+                let columns: Vec<ColumnEstimate> = exprs
+                    .iter()
+                    .map(|e| estimate_expr(e, &input.columns))
+                    .collect();
+                let mut ordering = Vec::new();
+                for key in &input.ordering {
+                    match exprs.iter().position(|e| *e == key.expr) {
+                        Some(i) => ordering.push(SortKey {
+                            expr: Scalar::Column(i),
+                            descending: key.descending,
+                        }),
+                        None => break,
+                    }
+                }
+                let rows = input.estimate.rows;
+                let cost = input.estimate.cost + rows * exprs.len() as f64 * CPU_OPERATOR_COST;
+                PhysicalPlan {
+                    operator: Operator::Project {
+                        exprs: exprs.clone(),
+                    },
+                    estimate: estimate(rows, &columns, cost, 0),
+                    inputs: vec![input],
+                    fields: fields.clone(),
+                    ordering,
+                    columns,
+                }
+            }
+            Plan::Join {
+                left,
+                right,
+                keys,
+                filter,
+            } => {
+                let mut read = read.to_vec();
+                let left_len = left.fields().len();
+                let (left_read, right_read) = read.split_at_mut(left_len);
+                for (l, r) in keys {
+                    mark(left_read, l);
+                    mark(right_read, r);
+                }
+                if let Some(filter) = filter {
+                    mark(&mut read, filter);
+                }
+                let (left_read, right_read) = read.split_at(left_len);
+                self.join(
+                    self.build(left, None, left_read)?,
+                    self.build(right, None, right_read)?,
+                    keys,
+                    filter.as_ref(),
+                )
+            }
+            Plan::Aggregate {
+                input,
+                group_by,
+                aggregates,
+                fields,
+            } => {
+                let mut read = vec![false; input.fields().len()];
+                for expr in group_by
+                    .iter()
+                    .chain(aggregates.iter().flat_map(|a| &a.arg))
+                {
+                    mark(&mut read, expr);
+                }
+                self.aggregate(
+                    self.build(input, None, &read)?,
+                    group_by,
+                    aggregates,
+                    fields,
+                )
+            }
+            Plan::Sort { input, keys } => {
+                let mut read = read.to_vec();
+                for key in keys {
+                    mark(&mut read, &key.expr);
+                }
+                self.sort(self.build(input, None, &read)?, keys, top)
+            }
+            Plan::Limit {
+                input,
+                limit,
+                offset,
+            } => {
+                let top = limit.map(|limit| limit.saturating_add(*offset));
+                let input = self.build(input, top, read)?;
+                let mut rows = (input.estimate.rows - *offset as f64).max(0.0);
+                if let Some(limit) = limit {
+                    rows = rows.min(*limit as f64);
+                }
+                let cost = input.estimate.cost + rows * CPU_ROW_COST;
+                let columns = capped(&input.columns, rows);
+                PhysicalPlan {
+                    operator: Operator::Limit {
+                        limit: *limit,
+                        offset: *offset,
+                    },
+                    estimate: estimate(rows, &columns, cost, 0),
+                    fields: input.fields.clone(),
+                    ordering: input.ordering.clone(),
+                    columns,
+                    inputs: vec![input],
+                }
+            }
+            Plan::Analyze { scans } => {
+                // Statistics describe whole rows, so no index scan will do.
+                let inputs = scans
+                    .iter()
+                    .map(|scan| match scan {
+                        Plan::Scan { table, fields } => self.seq_scan(table, fields),
+                        _ => unreachable!("ANALYZE plans only table scans"),
+                    })
+                    .collect::<Vec<_>>();
+                let cost = inputs
+                    .iter()
+                    .map(|input| {
+                        let e = input.estimate;
+                        e.cost + e.rows * input.columns.len() as f64 * CPU_OPERATOR_COST
+                    })
+                    .sum();
+                let grant = self.grant(ANALYZE_GRANT as f64, MIN_ANALYZE_GRANT);
+                PhysicalPlan {
+                    operator: Operator::Analyze,
+                    inputs,
+                    fields: Vec::new(),
+                    ordering: Vec::new(),
+                    estimate: estimate(0.0, &[], cost, grant),
+                    columns: Vec::new(),
+                }
+            }
+            Plan::Explain(statement) => {
+                let input = self.build(statement, None, &all(statement))?;
+                let columns = vec![ColumnEstimate {
+                    width: 80.0,
+                    distinct: 1.0,
+                    null_fraction: 0.0,
+                    histogram: None,
+                }];
+                let rows = input.explain().len() as f64;
+                PhysicalPlan {
+                    operator: Operator::Explain,
+                    inputs: vec![input],
+                    fields: vec![Field {
+                        table: None,
+                        name: "plan".to_owned(),
+                    }],
+                    ordering: Vec::new(),
+                    estimate: estimate(rows, &columns, 0.0, 0),
+                    columns,
+                }
+            }
+        })
+    }
+
+    /// Step writing the rows of `input` to a table.
+    fn write(&self, operator: Operator, input: PhysicalPlan) -> PhysicalPlan {
+        let e = input.estimate;
+        let cost = e.cost + e.rows * CPU_ROW_COST + pages(e.rows * e.row_size) * SEQ_PAGE_COST;
+        PhysicalPlan {
+            operator,
+            inputs: vec![input],
+            fields: Vec::new(),
+            ordering: Vec::new(),
+            estimate: Estimate {
+                rows: e.rows,
+                row_size: 0.0,
+                cost,
+                grant: 0,
+            },
+            columns: Vec::new(),
+        }
+    }
+
+    /// Rows of `table`, estimates for its columns and the order of its
+    /// primary key.
+    fn table_layout(
+        &self,
+        table: &str,
+        fields: &[Field],
+    ) -> (f64, Vec<ColumnEstimate>, Vec<SortKey>) {
+        let statistics = self.catalog.statistics(table);
+        let rows = statistics.rows as f64;
+        let mut columns = match statistics.columns.len() == fields.len() {
+            true => statistics
+                .columns
+                .into_iter()
+                .map(|c| ColumnEstimate {
+                    width: c.width,
+                    distinct: (c.distinct as f64).max(1.0),
+                    null_fraction: c.null_fraction,
+                    histogram: Some(Arc::new(c.histogram)),
+                })
+                .collect(),
+            false => vec![
+                ColumnEstimate {
+                    width: statistics.row_size as f64 / fields.len().max(1) as f64,
+                    distinct: (rows * GROUP_FRACTION).max(1.0),
+                    null_fraction: NULL_SELECTIVITY,
+                    histogram: None,
+                };
+                fields.len()
+            ],
+        };
+        let mut ordering = Vec::new();
+        if let Some(schema) = self.catalog.table(table) {
+            if let [key] = schema.primary_key[..] {
+                if columns[key].histogram.is_none() {
+                    columns[key].distinct = rows.max(1.0);
+                }
+            }
+            ordering = ascending(schema.primary_key.iter().map(|&i| Scalar::Column(i)));
+        }
+        (rows, columns, ordering)
+    }
+
+    fn seq_scan(&self, table: &str, fields: &[Field]) -> PhysicalPlan {
+        let (rows, columns, ordering) = self.table_layout(table, fields);
+        let cost = pages(rows * row_size(&columns)) * SEQ_PAGE_COST + rows * CPU_ROW_COST;
+        PhysicalPlan {
+            operator: Operator::SeqScan {
+                table: table.to_owned(),
+            },
+            inputs: Vec::new(),
+            fields: fields.to_vec(),
+            ordering,
+            estimate: estimate(rows, &columns, cost, 0),
+            columns,
+        }
+    }
+
+    fn filter(&self, input: PhysicalPlan, predicate: Scalar) -> PhysicalPlan {
+        let rows = input.estimate.rows * selectivity(&predicate, &input.columns);
+        let cost = input.estimate.cost + input.estimate.rows * CPU_OPERATOR_COST;
+        let columns = capped(&input.columns, rows);
+        PhysicalPlan {
+            operator: Operator::Filter { predicate },
+            estimate: estimate(rows, &columns, cost, 0),
+            fields: input.fields.clone(),
+            ordering: input.ordering.clone(),
+            columns,
+            inputs: vec![input],
+        }
+    }
+
+    /// Scans of `table` through its primary key and its secondary indexes
+    /// for the conditions of `predicate` on their leading keys, each under a
+    /// filter for the other conditions. Indexes holding every column in
+    /// `read`, the columns read above the scan, are scanned even if no
+    /// condition bounds them.
+    fn index_scans(
+        &self,
+        table: &str,
+        fields: &[Field],
+        predicate: Option<&Scalar>,
+        read: &[bool],
+    ) -> Vec<PhysicalPlan> {
+        let Some(schema) = self.catalog.table(table) else {
+            return Vec::new();
+        };
+        let mut conjuncts = Vec::new();
+        if let Some(predicate) = predicate {
+            split_conjuncts(predicate, &mut conjuncts);
+        }
+        let (rows, columns, ordering) = self.table_layout(table, fields);
+        let mut scans = Vec::new();
+        let primary_key: Vec<Scalar> = schema
+            .primary_key
+            .iter()
+            .map(|&i| Scalar::Column(i))
+            .collect();
+        let bounds = KeyBounds::new(&primary_key, conjuncts.clone());
+        if bounds.is_bounded() {
+            let matched = bounds.matched(&primary_key, true, rows, &columns);
+            let cost = RANDOM_PAGE_COST
+                + pages(matched * row_size(&columns)) * SEQ_PAGE_COST
+                + matched * CPU_ROW_COST;
+            let scan = Operator::IndexScan {
+                table: table.to_owned(),
+                prefix: bounds.prefix,
+                lower: bounds.lower,
+                upper: bounds.upper,
+            };
+            let scan = self.index_scan(scan, fields, ordering, &columns, matched, cost);
+            scans.push(self.filter_rest(scan, bounds.rest));
+        }
+        for index in self.catalog.indexes(table) {
+            let bounds = KeyBounds::new(&index.exprs, conjuncts.clone());
+            let covering = read.iter().enumerate().all(|(c, &read)| {
+                !read || schema.primary_key.contains(&c) || index.exprs.contains(&Scalar::Column(c))
+            });
+            if !bounds.is_bounded() && !covering {
+                continue;
+            }
+            let matched = bounds.matched(&index.exprs, index.unique, rows, &columns);
+            let entry_size: f64 = index
+                .exprs
+                .iter()
+                .map(|e| estimate_expr(e, &columns).width)
+                .chain(schema.primary_key.iter().map(|&i| columns[i].width))
+                .sum();
+            let mut cost = RANDOM_PAGE_COST
+                + pages(matched * entry_size) * SEQ_PAGE_COST
+                + matched * CPU_ROW_COST;
+            if !covering {
+                // Each row is fetched by its primary key.
+                cost += matched * RANDOM_PAGE_COST;
+            }
+            let ordering = ascending(
+                index
+                    .exprs
+                    .iter()
+                    .cloned()
+                    .chain(primary_key.iter().cloned()),
+            );
+            let scan = Operator::SecondaryIndexScan {
+                table: table.to_owned(),
+                index: index.name.clone(),
+                prefix: bounds.prefix,
+                lower: bounds.lower,
+                upper: bounds.upper,
+                covering,
+            };
+            let scan = self.index_scan(scan, fields, ordering, &columns, matched, cost);
+            scans.push(self.filter_rest(scan, bounds.rest));
+        }
+        scans
+    }
+
+    fn index_scan(
+        &self,
+        operator: Operator,
+        fields: &[Field],
+        ordering: Vec<SortKey>,
+        columns: &[ColumnEstimate],
+        rows: f64,
+        cost: f64,
+    ) -> PhysicalPlan {
+        let columns = capped(columns, rows);
+        PhysicalPlan {
+            operator,
+            inputs: Vec::new(),
+            fields: fields.to_vec(),
+            ordering,
+            estimate: estimate(rows, &columns, cost, 0),
+            columns,
+        }
+    }
+
+    /// `scan` under a filter for the conditions in `rest`, if any.
+    fn filter_rest(&self, scan: PhysicalPlan, rest: Vec<Scalar>) -> PhysicalPlan {
+        match conjunction(rest) {
+            Some(predicate) => self.filter(scan, predicate),
+            None => scan,
+        }
+    }
+
+    fn join(
+        &self,
+        left: PhysicalPlan,
+        right: PhysicalPlan,
+        keys: &[(Scalar, Scalar)],
+        filter: Option<&Scalar>,
+    ) -> PhysicalPlan {
+        let (l, r) = (left.estimate.rows, right.estimate.rows);
+        let mut rows = l * r;
+        // Keys are likely correlated, so only the most selective counts.
+        let distinct = keys
+            .iter()
+            .map(|(l, r)| {
+                let l = estimate_expr(l, &left.columns).distinct;
+                let r = estimate_expr(r, &right.columns).distinct;
+                l.max(r)
+            })
+            .fold(1.0, f64::max);
+        rows /= distinct;
+        let mut fields = left.fields.clone();
+        fields.extend(right.fields.iter().cloned());
+        let mut columns = left.columns.clone();
+        columns.extend(right.columns.iter().cloned());
+        if let Some(filter) = filter {
+            rows *= selectivity(filter, &columns);
+        }
+        let columns = capped(&columns, rows);
+        let inputs_cost = left.estimate.cost + right.estimate.cost;
+        let output_cost = rows * CPU_ROW_COST;
+        let mut candidates = Vec::new();
+
+        if !keys.is_empty() {
+            // Hash join, built from the smaller side.
+            let left_bytes = l * (left.estimate.row_size + ROW_OVERHEAD);
+            let right_bytes = r * (right.estimate.row_size + ROW_OVERHEAD);
+            let build_left = left_bytes < right_bytes;
+            let (build, probe) = match build_left {
+                true => (left_bytes, right_bytes),
+                false => (right_bytes, left_bytes),
+            };
+            let ideal = build * 1.25;
+            let grant = self.grant(ideal, MIN_JOIN_GRANT);
+            let cost = inputs_cost
+                + (l + r) * CPU_ROW_COST
+                + spill_cost(spilled(ideal, grant), build + probe)
+                + output_cost;
+            let operator = Operator::HashJoin {
+                keys: keys.to_vec(),
+                filter: filter.cloned(),
+                build_left,
+            };
+            candidates.push((operator, None, cost, grant));
+
+            // Merge join, sorting the inputs not already in key order.
+            let left_keys = ascending(keys.iter().map(|(l, _)| l.clone()));
+            let right_keys = ascending(keys.iter().map(|(_, r)| r.clone()));
+            let mut cost = inputs_cost + (l + r) * CPU_ROW_COST + output_cost;
+            for (input, keys) in [(&left, &left_keys), (&right, &right_keys)] {
+                if !satisfies(&input.ordering, keys) {
+                    let e = input.estimate;
+                    let grant = self.grant(e.rows * (e.row_size + ROW_OVERHEAD), MIN_SORT_GRANT);
+                    cost += sort_cost(e.rows, e.row_size, grant) + e.rows * CPU_ROW_COST;
+                }
+            }
+            let operator = Operator::MergeJoin {
+                keys: keys.to_vec(),
+                filter: filter.cloned(),
+            };
+            candidates.push((
+                operator,
+                Some((left_keys, right_keys)),
+                cost,
+                MIN_JOIN_GRANT,
+            ));
+        }
+
+        // Block nested loop join, re-reading the right input once per block.
+        let left_bytes = l * (left.estimate.row_size + ROW_OVERHEAD);
+        let grant = self.grant(left_bytes, MIN_JOIN_GRANT);
+        let blocks = (left_bytes / grant as f64).ceil().max(1.0);
+        let right_pages = pages(r * right.estimate.row_size);
+        let cost = inputs_cost
+            + right_pages * (1.0 + blocks) * SEQ_PAGE_COST
+            + l * r * CPU_OPERATOR_COST
+            + output_cost;
+        let mut conditions: Vec<Scalar> = keys
+            .iter()
+            .map(|(l, r)| {
+                let mut r = r.clone();
+                shift(&mut r, left.fields.len());
+                Scalar::Binary {
+                    op: BinaryOp::Eq,
+                    left: Box::new(l.clone()),
+                    right: Box::new(r),
+                }
+            })
+            .collect();
+        conditions.extend(filter.cloned());
+        let operator = Operator::NestedLoopJoin {
+            filter: conjunction(conditions),
+        };
+        candidates.push((operator, None, cost, grant));
+
+        let (operator, sorts, cost, grant) = candidates
+            .into_iter()
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .unwrap();
+        let mut ordering = Vec::new();
+        let inputs = match sorts {
+            Some((left_keys, right_keys)) => {
+                ordering = left_keys.clone();
+                vec![
+                    self.sorted(left, &left_keys),
+                    self.sorted(right, &right_keys),
+                ]
+            }
+            None => vec![left, right],
+        };
+        PhysicalPlan {
+            operator,
+            inputs,
+            fields,
+            ordering,
+            estimate: estimate(rows, &columns, cost, grant),
+            columns,
+        }
+    }
+
+    /// `input` in the order of `keys`, sorting it if it is not already.
+    fn sorted(&self, input: PhysicalPlan, keys: &[SortKey]) -> PhysicalPlan {
+        match satisfies(&input.ordering, keys) {
+            true => input,
+            false => self.sort_step(input, keys),
+        }
+    }
+
+    fn sort_step(&self, input: PhysicalPlan, keys: &[SortKey]) -> PhysicalPlan {
+        let e = input.estimate;
+        let grant = self.grant(e.rows * (e.row_size + ROW_OVERHEAD) * 1.1, MIN_SORT_GRANT);
+        let cost = e.cost + sort_cost(e.rows, e.row_size, grant) + e.rows * CPU_ROW_COST;
+        PhysicalPlan {
+            operator: Operator::Sort {
+                keys: keys.to_vec(),
+            },
+            estimate: estimate(e.rows, &input.columns, cost, grant),
+            fields: input.fields.clone(),
+            ordering: keys.to_vec(),
+            columns: input.columns.clone(),
+            inputs: vec![input],
+        }
+    }
+
+    /// Sort of `input`, keeping only its first `top` rows in memory if that
+    /// is cheaper.
+    fn sort(&self, input: PhysicalPlan, keys: &[SortKey], top: Option<u64>) -> PhysicalPlan {
+        if satisfies(&input.ordering, keys) {
+            return input;
+        }
+        let e = input.estimate;
+        if let Some(limit) = top {
+            let kept = (limit as f64).min(e.rows);
+            let grant = (kept * (e.row_size + ROW_OVERHEAD)).ceil() as usize;
+            if grant <= self.share {
+                let cost =
+                    e.cost + e.rows * (kept.max(2.0).log2() * CPU_OPERATOR_COST + CPU_ROW_COST);
+                let full = self.sort_step(input.clone(), keys);
+                if cost < full.estimate.cost {
+                    return PhysicalPlan {
+                        operator: Operator::TopN {
+                            keys: keys.to_vec(),
+                            limit,
+                        },
+                        estimate: estimate(kept, &input.columns, cost, grant.max(1)),
+                        fields: input.fields.clone(),
+                        ordering: keys.to_vec(),
+                        columns: input.columns.clone(),
+                        inputs: vec![input],
+                    };
+                }
+                return full;
+            }
+        }
+        self.sort_step(input, keys)
+    }
+
+    fn aggregate(
+        &self,
+        input: PhysicalPlan,
+        group_by: &[Scalar],
+        aggregates: &[AggregateCall],
+        fields: &[Field],
+    ) -> PhysicalPlan {
+        let e = input.estimate;
+        let keys: Vec<ColumnEstimate> = group_by
+            .iter()
+            .map(|g| estimate_expr(g, &input.columns))
+            .collect();
+        let groups = keys
+            .iter()
+            .map(|k| k.distinct)
+            .product::<f64>()
+            .min(e.rows)
+            .max(1.0);
+        let mut columns = capped(&keys, groups);
+        for call in aggregates {
+            let width = match (&call.function, &call.arg) {
+                (AggregateFunction::Min | AggregateFunction::Max, Some(arg)) => {
+                    estimate_expr(arg, &input.columns).width
+                }
+                _ => EXPR_WIDTH,
+            };
+            columns.push(ColumnEstimate {
+                width,
+                distinct: groups,
+                null_fraction: 0.0,
+                histogram: None,
+            });
+        }
+        let process = e.rows * (CPU_ROW_COST + aggregates.len() as f64 * CPU_OPERATOR_COST);
+        let stream = |input: PhysicalPlan, cost: f64| {
+            let mut ordering = Vec::new();
+            for key in &input.ordering {
+                match group_by.iter().position(|g| *g == key.expr) {
+                    Some(i) => ordering.push(SortKey {
+                        expr: Scalar::Column(i),
+                        descending: key.descending,
+                    }),
+                    None => break,
+                }
+            }
+            PhysicalPlan {
+                operator: Operator::StreamAggregate {
+                    group_by: group_by.to_vec(),
+                    aggregates: aggregates.to_vec(),
+                },
+                inputs: vec![input],
+                fields: fields.to_vec(),
+                ordering,
+                estimate: estimate(groups, &columns, cost, 0),
+                columns: columns.clone(),
+            }
+        };
+        if groups_in_order(&input.ordering, group_by) {
+            let cost = e.cost + process;
+            return stream(input, cost);
+        }
+
+        let state = row_size(&columns) + ROW_OVERHEAD;
+        let ideal = groups * state * 1.25;
+        let grant = self.grant(ideal, MIN_AGGREGATE_GRANT);
+        let hash_cost = e.cost + process + spill_cost(spilled(ideal, grant), e.rows * e.row_size);
+        let sorted = self.sort_step(input.clone(), &ascending(group_by.iter().cloned()));
+        let sort_cost = sorted.estimate.cost + process;
+        if sort_cost < hash_cost {
+            return stream(sorted, sort_cost);
+        }
+        PhysicalPlan {
+            operator: Operator::HashAggregate {
+                group_by: group_by.to_vec(),
+                aggregates: aggregates.to_vec(),
+            },
+            inputs: vec![input],
+            fields: fields.to_vec(),
+            ordering: Vec::new(),
+            estimate: estimate(groups, &columns, hash_cost, grant),
+            columns,
+        }
+    }
+}
+
+/// Marks every column of a plan's rows as read.
+fn all(plan: &Plan) -> Vec<bool> {
+    vec![true; plan.fields().len()]
+}
+
+/// Marks the columns `expr` reads.
+fn mark(read: &mut [bool], expr: &Scalar) {
+    expr.visit_columns(&mut |i| read[i] = true);
+}
+
+/// The cheapest of `best` and `others`.
+fn cheapest(best: PhysicalPlan, others: Vec<PhysicalPlan>) -> PhysicalPlan {
+    others.into_iter().fold(best, |best, plan| {
+        match plan.estimate.cost < best.estimate.cost {
+            true => plan,
+            false => best,
+        }
+    })
+}
+
+fn estimate(rows: f64, columns: &[ColumnEstimate], cost: f64, grant: usize) -> Estimate {
+    Estimate {
+        rows,
+        row_size: row_size(columns),
+        cost,
+        grant,
+    }
+}
+
+fn leaf(operator: Operator, rows: f64, cost: f64) -> PhysicalPlan {
+    PhysicalPlan {
+        operator,
+        inputs: Vec::new(),
+        fields: Vec::new(),
+        ordering: Vec::new(),
+        estimate: estimate(rows, &[], cost, 0),
+        columns: Vec::new(),
+    }
+}
+
+/// Moves every column reference `by` positions to the right.
+fn shift(expr: &mut Scalar, by: usize) {
+    match expr {
+        Scalar::Literal(_) => {}
+        Scalar::Column(i) => *i += by,
+        Scalar::Unary { expr, .. } | Scalar::IsNull { expr, .. } => shift(expr, by),
+        Scalar::Binary { left, right, .. } => {
+            shift(left, by);
+            shift(right, by);
+        }
+    }
+}
+
+/// The comparison `predicate` makes of `expr` with a constant, as the
+/// operator with the expression on its left and the constant.
+fn compared(predicate: &Scalar, expr: &Scalar) -> Option<(BinaryOp, Value)> {
+    let Scalar::Binary { op, left, right } = predicate else {
+        return None;
+    };
+    let (op, value) = match (&**left, &**right) {
+        (e, Scalar::Literal(v)) if e == expr => (*op, v),
+        (Scalar::Literal(v), e) if e == expr => {
+            let op = match op {
+                BinaryOp::Lt => BinaryOp::Gt,
+                BinaryOp::LtEq => BinaryOp::GtEq,
+                BinaryOp::Gt => BinaryOp::Lt,
+                BinaryOp::GtEq => BinaryOp::LtEq,
+                op => *op,
+            };
+            (op, v)
+        }
+        _ => return None,
+    };
+    match (op, value) {
+        (_, Value::Null) => None,
+        (BinaryOp::Eq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq, _) => {
+            Some((op, value.clone()))
+        }
+        _ => None,
+    }
+}
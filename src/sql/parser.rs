@@ -0,0 +1,590 @@
+//! Recursive descent parser producing the [`ast`](super::ast).
+
+use super::ast::{
+    BinaryOp, ColumnDef, CreateIndex, CreateTable, Delete, Expr, Insert, InsertSource, JoinKind,
+    OrderBy, Select, SelectItem, Statement, TableExpr, UnaryOp, Update,
+};
+use super::lexer::{syntax_error, tokenize, Spanned, Token};
+use super::value::{DataType, Value};
+use crate::error::{Error, Result};
+
+/// Words that cannot be used as an alias without `AS`.
+const RESERVED: &[&str] = &[
+    "select", "from", "where", "group", "having", "order", "limit", "offset", "join", "inner",
+    "cross", "left", "right", "full", "outer", "on", "and", "or", "not", "as", "by", "set",
+    "values", "union",
+];
+
+pub(crate) struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    /// Offset of the end of the text, reported for errors at its end.
+    end: usize,
+}
+
+impl Parser {
+    pub(crate) fn new(sql: &str) -> Result<Self> {
+        Ok(Parser {
+            tokens: tokenize(sql)?,
+            pos: 0,
+            end: sql.len(),
+        })
+    }
+
+    /// Parses a single statement, optionally followed by a semicolon.
+    pub(crate) fn statement(mut self) -> Result<Statement> {
+        let statement = self.inner_statement()?;
+        self.eat(&Token::Semicolon);
+        if self.pos < self.tokens.len() {
+            return Err(self.unexpected("the end of the statement"));
+        }
+        Ok(statement)
+    }
+
+    fn inner_statement(&mut self) -> Result<Statement> {
+        Ok(match self.peek_word() {
+            Some("explain") => {
+                self.pos += 1;
+                Statement::Explain(Box::new(self.inner_statement()?))
+            }
+            Some("create") => {
+                self.pos += 1;
+                match self.peek_word() {
+                    Some("index" | "unique") => Statement::CreateIndex(self.create_index()?),
+                    _ => Statement::CreateTable(self.create_table()?),
+                }
+            }
+            Some("insert") => Statement::Insert(self.insert()?),
+            Some("update") => Statement::Update(self.update()?),
+            Some("delete") => Statement::Delete(self.delete()?),
+            Some("select") => Statement::Select(Box::new(self.select()?)),
+            Some("analyze") => {
+                self.pos += 1;
+                let table = match self.peek() {
+                    Some(Token::Word { .. }) => Some(self.identifier()?),
+                    _ => None,
+                };
+                Statement::Analyze(table)
+            }
+            _ => return Err(self.unexpected("a statement")),
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    /// The next token if it is an unquoted word.
+    fn peek_word(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word {
+                text,
+                quoted: false,
+            }) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |t| t.offset)
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        match self.peek() {
+            Some(token) => syntax_error(
+                self.offset(),
+                format!("expected {expected}, found `{token}`"),
+            ),
+            None => syntax_error(self.offset(), format!("expected {expected}, found the end")),
+        }
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, token: Token) -> Result<()> {
+        match self.eat(&token) {
+            true => Ok(()),
+            false => Err(self.unexpected(&format!("`{token}`"))),
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_word() == Some(keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected(&keyword.to_ascii_uppercase())),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Token::Word { text, .. }) => {
+                let text = text.clone();
+                self.pos += 1;
+                Ok(text)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    /// Comma-separated list of at least one item.
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let mut items = vec![item(self)?];
+        while self.eat(&Token::Comma) {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn parenthesized<T>(&mut self, item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        self.expect(Token::LeftParen)?;
+        let items = self.list(item)?;
+        self.expect(Token::RightParen)?;
+        Ok(items)
+    }
+
+    /// Optional `IF NOT EXISTS`.
+    fn if_not_exists(&mut self) -> Result<bool> {
+        let if_not_exists = self.eat_keyword("if");
+        if if_not_exists {
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+        }
+        Ok(if_not_exists)
+    }
+
+    /// `CREATE TABLE`, after `CREATE`.
+    fn create_table(&mut self) -> Result<CreateTable> {
+        self.expect_keyword("table")?;
+        let if_not_exists = self.if_not_exists()?;
+        let name = self.identifier()?;
+        let mut columns = Vec::new();
+        let mut primary_key = Vec::new();
+        self.expect(Token::LeftParen)?;
+        loop {
+            if self.eat_keyword("primary") {
+                self.expect_keyword("key")?;
+                if !primary_key.is_empty() {
+                    return Err(syntax_error(self.offset(), "more than one primary key"));
+                }
+                primary_key = self.parenthesized(Self::identifier)?;
+            } else {
+                columns.push(self.column_def()?);
+            }
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(Token::RightParen)?;
+        Ok(CreateTable {
+            name,
+            columns,
+            primary_key,
+            if_not_exists,
+        })
+    }
+
+    /// `CREATE INDEX`, after `CREATE`.
+    fn create_index(&mut self) -> Result<CreateIndex> {
+        let unique = self.eat_keyword("unique");
+        self.expect_keyword("index")?;
+        let if_not_exists = self.if_not_exists()?;
+        let name = self.identifier()?;
+        self.expect_keyword("on")?;
+        let table = self.identifier()?;
+        let exprs = self.parenthesized(Self::expr)?;
+        Ok(CreateIndex {
+            name,
+            table,
+            exprs,
+            unique,
+            if_not_exists,
+        })
+    }
+
+    fn column_def(&mut self) -> Result<ColumnDef> {
+        let name = self.identifier()?;
+        let offset = self.offset();
+        let type_name = self.identifier()?;
+        let Some(data_type) = DataType::from_name(&type_name) else {
+            return Err(syntax_error(offset, format!("unknown type {type_name}")));
+        };
+        // Lengths such as VARCHAR(255) are accepted and ignored.
+        if self.eat(&Token::LeftParen) {
+            self.integer()?;
+            self.expect(Token::RightParen)?;
+        }
+        let mut column = ColumnDef {
+            name,
+            data_type,
+            not_null: false,
+            primary_key: false,
+        };
+        loop {
+            if self.eat_keyword("not") {
+                self.expect_keyword("null")?;
+                column.not_null = true;
+            } else if self.eat_keyword("null") {
+                column.not_null = false;
+            } else if self.eat_keyword("primary") {
+                self.expect_keyword("key")?;
+                column.primary_key = true;
+            } else {
+                return Ok(column);
+            }
+        }
+    }
+
+    fn insert(&mut self) -> Result<Insert> {
+        self.expect_keyword("insert")?;
+        self.expect_keyword("into")?;
+        let table = self.identifier()?;
+        let columns = match self.peek() {
+            Some(Token::LeftParen) => Some(self.parenthesized(Self::identifier)?),
+            _ => None,
+        };
+        let source = if self.eat_keyword("values") {
+            InsertSource::Values(self.list(|p| p.parenthesized(Self::expr))?)
+        } else if self.peek_word() == Some("select") {
+            InsertSource::Select(Box::new(self.select()?))
+        } else {
+            return Err(self.unexpected("VALUES or SELECT"));
+        };
+        Ok(Insert {
+            table,
+            columns,
+            source,
+        })
+    }
+
+    fn update(&mut self) -> Result<Update> {
+        self.expect_keyword("update")?;
+        let table = self.identifier()?;
+        self.expect_keyword("set")?;
+        let assignments = self.list(|p| {
+            let column = p.identifier()?;
+            p.expect(Token::Eq)?;
+            Ok((column, p.expr()?))
+        })?;
+        let filter = self.filter()?;
+        Ok(Update {
+            table,
+            assignments,
+            filter,
+        })
+    }
+
+    fn delete(&mut self) -> Result<Delete> {
+        self.expect_keyword("delete")?;
+        self.expect_keyword("from")?;
+        let table = self.identifier()?;
+        let filter = self.filter()?;
+        Ok(Delete { table, filter })
+    }
+
+    fn filter(&mut self) -> Result<Option<Expr>> {
+        match self.eat_keyword("where") {
+            true => self.expr().map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn select(&mut self) -> Result<Select> {
+        self.expect_keyword("select")?;
+        let distinct = self.eat_keyword("distinct");
+        if !distinct {
+            self.eat_keyword("all");
+        }
+        let items = self.list(Self::select_item)?;
+        let from = match self.eat_keyword("from") {
+            true => Some(self.from()?),
+            false => None,
+        };
+        let filter = self.filter()?;
+        let mut group_by = Vec::new();
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            group_by = self.list(Self::expr)?;
+        }
+        let having = match self.eat_keyword("having") {
+            true => Some(self.expr()?),
+            false => None,
+        };
+        let mut order_by = Vec::new();
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            order_by = self.list(|p| {
+                let expr = p.expr()?;
+                let descending = p.eat_keyword("desc");
+                if !descending {
+                    p.eat_keyword("asc");
+                }
+                Ok(OrderBy { expr, descending })
+            })?;
+        }
+        let mut limit = None;
+        let mut offset = None;
+        if self.eat_keyword("limit") {
+            limit = Some(self.count()?);
+        }
+        if self.eat_keyword("offset") {
+            offset = Some(self.count()?);
+        }
+        Ok(Select {
+            distinct,
+            items,
+            from,
+            filter,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        })
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem> {
+        if self.eat(&Token::Star) {
+            return Ok(SelectItem::Wildcard);
+        }
+        let is_qualified_wildcard = matches!(self.peek(), Some(Token::Word { .. }))
+            && matches!(self.tokens.get(self.pos + 1), Some(t) if t.token == Token::Dot)
+            && matches!(self.tokens.get(self.pos + 2), Some(t) if t.token == Token::Star);
+        if is_qualified_wildcard {
+            let table = self.identifier()?;
+            self.pos += 2;
+            return Ok(SelectItem::QualifiedWildcard(table));
+        }
+        let expr = self.expr()?;
+        let alias = self.alias()?;
+        Ok(SelectItem::Expr { expr, alias })
+    }
+
+    /// `AS name`, or a bare name that is not a keyword.
+    fn alias(&mut self) -> Result<Option<String>> {
+        if self.eat_keyword("as") {
+            return self.identifier().map(Some);
+        }
+        match self.peek() {
+            Some(Token::Word { text, quoted }) if *quoted || !RESERVED.contains(&text.as_str()) => {
+                self.identifier().map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn from(&mut self) -> Result<TableExpr> {
+        let mut from = self.table()?;
+        loop {
+            let kind = if self.eat(&Token::Comma) {
+                JoinKind::Cross
+            } else if self.eat_keyword("cross") {
+                self.expect_keyword("join")?;
+                JoinKind::Cross
+            } else if self.eat_keyword("inner") {
+                self.expect_keyword("join")?;
+                JoinKind::Inner
+            } else if self.eat_keyword("join") {
+                JoinKind::Inner
+            } else if matches!(self.peek_word(), Some("left" | "right" | "full")) {
+                return Err(syntax_error(self.offset(), "outer joins are not supported"));
+            } else {
+                return Ok(from);
+            };
+            let right = self.table()?;
+            let on = match kind {
+                JoinKind::Inner => {
+                    self.expect_keyword("on")?;
+                    Some(self.expr()?)
+                }
+                JoinKind::Cross => None,
+            };
+            from = TableExpr::Join {
+                left: Box::new(from),
+                right: Box::new(right),
+                kind,
+                on,
+            };
+        }
+    }
+
+    fn table(&mut self) -> Result<TableExpr> {
+        if self.eat(&Token::LeftParen) {
+            let from = self.from()?;
+            self.expect(Token::RightParen)?;
+            return Ok(from);
+        }
+        let name = self.identifier()?;
+        let alias = self.alias()?;
+        Ok(TableExpr::Table { name, alias })
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        match self.peek() {
+            Some(&Token::Integer(n)) => {
+                self.pos += 1;
+                Ok(n)
+            }
+            _ => Err(self.unexpected("an integer")),
+        }
+    }
+
+    /// Row count of `LIMIT` or `OFFSET`.
+    fn count(&mut self) -> Result<u64> {
+        self.integer().map(|n| n as u64)
+    }
+
+    pub(crate) fn expr(&mut self) -> Result<Expr> {
+        self.binary(0)
+    }
+
+    /// Operations whose operators bind tighter than `min`.
+    fn binary(&mut self, min: u8) -> Result<Expr> {
+        let mut left = self.prefix(min)?;
+        loop {
+            if self.peek_word() == Some("is") && min < 4 {
+                self.pos += 1;
+                let negated = self.eat_keyword("not");
+                self.expect_keyword("null")?;
+                left = Expr::IsNull {
+                    expr: Box::new(left),
+                    negated,
+                };
+                continue;
+            }
+            let op = match (self.peek(), self.peek_word()) {
+                (_, Some("or")) => BinaryOp::Or,
+                (_, Some("and")) => BinaryOp::And,
+                (Some(Token::Eq), _) => BinaryOp::Eq,
+                (Some(Token::NotEq), _) => BinaryOp::NotEq,
+                (Some(Token::Lt), _) => BinaryOp::Lt,
+                (Some(Token::LtEq), _) => BinaryOp::LtEq,
+                (Some(Token::Gt), _) => BinaryOp::Gt,
+                (Some(Token::GtEq), _) => BinaryOp::GtEq,
+                (Some(Token::Plus), _) => BinaryOp::Add,
+                (Some(Token::Minus), _) => BinaryOp::Sub,
+                (Some(Token::Star), _) => BinaryOp::Mul,
+                (Some(Token::Slash), _) => BinaryOp::Div,
+                (Some(Token::Percent), _) => BinaryOp::Rem,
+                _ => return Ok(left),
+            };
+            if op.precedence() <= min {
+                return Ok(left);
+            }
+            self.pos += 1;
+            let right = self.binary(op.precedence())?;
+            left = Expr::Binary {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+    }
+
+    /// `NOT` binds looser than comparisons, unary minus tighter than any
+    /// operator.
+    fn prefix(&mut self, min: u8) -> Result<Expr> {
+        if min < 3 && self.eat_keyword("not") {
+            let expr = self.binary(2)?;
+            return Ok(Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(expr),
+            });
+        }
+        if self.eat(&Token::Minus) {
+            let expr = self.prefix(u8::MAX)?;
+            return Ok(match expr {
+                Expr::Literal(Value::Integer(n)) => Expr::Literal(Value::Integer(-n)),
+                expr => Expr::Unary {
+                    op: UnaryOp::Neg,
+                    expr: Box::new(expr),
+                },
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let offset = self.offset();
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.unexpected("an expression"));
+        };
+        self.pos += 1;
+        match token {
+            Token::Integer(n) => Ok(Expr::Literal(Value::Integer(n))),
+            Token::String(s) => Ok(Expr::Literal(Value::Text(s))),
+            Token::LeftParen => {
+                let expr = self.expr()?;
+                self.expect(Token::RightParen)?;
+                Ok(expr)
+            }
+            Token::Word { text, quoted } => {
+                if !quoted {
+                    match text.as_str() {
+                        "null" => return Ok(Expr::Literal(Value::Null)),
+                        "true" => return Ok(Expr::Literal(Value::Boolean(true))),
+                        "false" => return Ok(Expr::Literal(Value::Boolean(false))),
+                        _ if RESERVED.contains(&text.as_str()) => {
+                            self.pos -= 1;
+                            return Err(self.unexpected("an expression"));
+                        }
+                        _ => {}
+                    }
+                }
+                if self.eat(&Token::LeftParen) {
+                    return self.call(text);
+                }
+                if self.eat(&Token::Dot) {
+                    let name = self.identifier()?;
+                    return Ok(Expr::Column {
+                        table: Some(text),
+                        name,
+                    });
+                }
+                Ok(Expr::Column {
+                    table: None,
+                    name: text,
+                })
+            }
+            _ => Err(syntax_error(
+                offset,
+                format!("expected an expression, found `{token}`"),
+            )),
+        }
+    }
+
+    /// Arguments of a call to `name`, after its opening parenthesis.
+    fn call(&mut self, name: String) -> Result<Expr> {
+        if self.eat(&Token::Star) {
+            self.expect(Token::RightParen)?;
+            return Ok(Expr::Function {
+                name,
+                args: Vec::new(),
+                star: true,
+            });
+        }
+        let mut args = Vec::new();
+        if !self.eat(&Token::RightParen) {
+            args = self.list(Self::expr)?;
+            self.expect(Token::RightParen)?;
+        }
+        Ok(Expr::Function {
+            name,
+            args,
+            star: false,
+        })
+    }
+}
@@ -0,0 +1,454 @@
+//! Physical plans chosen by the optimizer, and their `EXPLAIN` output.
+
+use std::fmt::{self, Write as _};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::ast::UnaryOp;
+use super::catalog::{IndexSchema, TableSchema};
+use super::plan::{AggregateCall, AggregateFunction, Field, Scalar, SortKey};
+use super::statistics::Histogram;
+use super::value::Value;
+
+/// Algorithm running one step of a physical plan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operator {
+    CreateTable {
+        schema: TableSchema,
+        if_not_exists: bool,
+    },
+    /// Creates an index from the rows of its input, a scan of its table.
+    CreateIndex {
+        index: IndexSchema,
+        if_not_exists: bool,
+    },
+    /// Inserts the rows of its input, as in [`Plan::Insert`](super::Plan::Insert).
+    Insert {
+        table: String,
+        columns: Vec<usize>,
+    },
+    /// Updates the rows of its input, as in [`Plan::Update`](super::Plan::Update).
+    Update {
+        table: String,
+        assignments: Vec<(usize, Scalar)>,
+    },
+    /// Deletes the rows of its input.
+    Delete {
+        table: String,
+    },
+    /// Reads every row of a table in primary key order.
+    SeqScan {
+        table: String,
+    },
+    /// Reads, in primary key order, the rows of a table whose primary key
+    /// starts with the values of `prefix`, and whose next key column lies
+    /// between `lower` and `upper`.
+    IndexScan {
+        table: String,
+        prefix: Vec<Value>,
+        lower: Bound<Value>,
+        upper: Bound<Value>,
+    },
+    /// Reads, in the order of the secondary index `index`, the rows of a
+    /// table whose values of its key expressions start with `prefix` and
+    /// continue with one between `lower` and `upper`. Each row is fetched by
+    /// its primary key, unless the index is `covering`: the columns read
+    /// above the scan are then taken from the index alone, and the others
+    /// are `NULL`.
+    SecondaryIndexScan {
+        table: String,
+        index: String,
+        prefix: Vec<Value>,
+        lower: Bound<Value>,
+        upper: Bound<Value>,
+        covering: bool,
+    },
+    Values {
+        rows: Vec<Vec<Scalar>>,
+    },
+    Filter {
+        predicate: Scalar,
+    },
+    Project {
+        exprs: Vec<Scalar>,
+    },
+    /// Joins its two inputs with a [`HashJoin`](crate::exec::HashJoin)
+    /// built from the left one if `build_left`, from the right one otherwise.
+    HashJoin {
+        keys: Vec<(Scalar, Scalar)>,
+        filter: Option<Scalar>,
+        build_left: bool,
+    },
+    /// Joins two inputs sorted by their keys in ascending order, holding only
+    /// the right rows of one key at a time.
+    MergeJoin {
+        keys: Vec<(Scalar, Scalar)>,
+        filter: Option<Scalar>,
+    },
+    /// Joins each block of left rows that fits in the grant with every right
+    /// row.
+    NestedLoopJoin {
+        filter: Option<Scalar>,
+    },
+    /// Aggregates with a [`HashAggregate`](crate::exec::HashAggregate).
+    HashAggregate {
+        group_by: Vec<Scalar>,
+        aggregates: Vec<AggregateCall>,
+    },
+    /// Aggregates an input sorted by its group keys one group at a time.
+    StreamAggregate {
+        group_by: Vec<Scalar>,
+        aggregates: Vec<AggregateCall>,
+    },
+    /// Sorts with an [`ExternalSort`](crate::exec::ExternalSort).
+    Sort {
+        keys: Vec<SortKey>,
+    },
+    /// Keeps the first `limit` rows in the order of `keys` in memory.
+    TopN {
+        keys: Vec<SortKey>,
+        limit: u64,
+    },
+    Limit {
+        limit: Option<u64>,
+        offset: u64,
+    },
+    /// Gathers the statistics of the tables its inputs, one scan of each,
+    /// read with an [`Analyzer`](super::Analyzer).
+    Analyze,
+    /// Returns the `EXPLAIN` output of its input, one row per line.
+    Explain,
+}
+
+/// Optimizer estimates for a step of a physical plan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// Rows the step produces.
+    pub rows: f64,
+    /// Average bytes of a row it produces.
+    pub row_size: f64,
+    /// Cost of the step and of its inputs, in units of a sequential page
+    /// read.
+    pub cost: f64,
+    /// Bytes of memory the step is granted; zero for steps that stream.
+    pub grant: usize,
+}
+
+/// Step of a physical plan with the steps producing its input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicalPlan {
+    pub operator: Operator,
+    pub inputs: Vec<PhysicalPlan>,
+    /// Columns of the rows the step produces.
+    pub fields: Vec<Field>,
+    /// Keys the rows it produces are sorted by, if any.
+    pub ordering: Vec<SortKey>,
+    pub estimate: Estimate,
+    /// Estimates for each of the columns it produces.
+    pub(crate) columns: Vec<ColumnEstimate>,
+}
+
+/// Optimizer estimates for a column.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ColumnEstimate {
+    /// Average bytes of a value.
+    pub(crate) width: f64,
+    /// Number of distinct values.
+    pub(crate) distinct: f64,
+    /// Fraction of values that are `NULL`.
+    pub(crate) null_fraction: f64,
+    /// Distribution of the values, if the column is one of an analyzed
+    /// table.
+    pub(crate) histogram: Option<Arc<Histogram>>,
+}
+
+impl PhysicalPlan {
+    /// Sum of the grants of every step.
+    pub fn total_grant(&self) -> usize {
+        self.estimate.grant + self.inputs.iter().map(Self::total_grant).sum::<usize>()
+    }
+
+    /// Lines of the `EXPLAIN` output, one per step.
+    pub fn explain(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.explain_into(0, &mut lines);
+        lines
+    }
+
+    fn explain_into(&self, depth: usize, lines: &mut Vec<String>) {
+        let mut line = "  ".repeat(depth);
+        self.describe(&mut line);
+        let e = &self.estimate;
+        let _ = write!(line, "  (rows={:.0} cost={:.2}", e.rows, e.cost);
+        if e.grant > 0 {
+            let _ = write!(line, " grant={}", Bytes(e.grant));
+        }
+        line.push(')');
+        lines.push(line);
+        for input in &self.inputs {
+            input.explain_into(depth + 1, lines);
+        }
+    }
+
+    /// Columns the expressions of the step refer to.
+    fn input_fields(&self) -> Vec<Field> {
+        let mut fields = Vec::new();
+        for input in &self.inputs {
+            fields.extend(input.fields.iter().cloned());
+        }
+        fields
+    }
+
+    fn describe(&self, out: &mut String) {
+        let input = self.input_fields();
+        let show = |expr: &Scalar| {
+            Show {
+                expr,
+                fields: &input,
+            }
+            .to_string()
+        };
+        let list = |exprs: &mut dyn Iterator<Item = String>| exprs.collect::<Vec<_>>().join(", ");
+        let keys = |keys: &[SortKey], fields: &[Field]| {
+            list(&mut keys.iter().map(|k| {
+                let expr = Show {
+                    expr: &k.expr,
+                    fields,
+                };
+                match k.descending {
+                    true => format!("{expr} DESC"),
+                    false => expr.to_string(),
+                }
+            }))
+        };
+        let join_keys = |keys: &[(Scalar, Scalar)]| {
+            let left = &self.inputs[0].fields;
+            let right = &self.inputs[1].fields;
+            list(&mut keys.iter().map(|(l, r)| {
+                let l = Show {
+                    expr: l,
+                    fields: left,
+                };
+                let r = Show {
+                    expr: r,
+                    fields: right,
+                };
+                format!("{l} = {r}")
+            }))
+        };
+        let _ = match &self.operator {
+            Operator::CreateTable { schema, .. } => write!(out, "CreateTable {}", schema.name),
+            Operator::CreateIndex { index, .. } => {
+                write!(out, "CreateIndex {} on {}", index.name, index.table)
+            }
+            Operator::Insert { table, .. } => write!(out, "Insert {table}"),
+            Operator::Update { table, assignments } => {
+                let set = list(
+                    &mut assignments
+                        .iter()
+                        .map(|(i, e)| format!("{} = {}", input[*i].name, show(e))),
+                );
+                write!(out, "Update {table} set=[{set}]")
+            }
+            Operator::Delete { table } => write!(out, "Delete {table}"),
+            Operator::SeqScan { table } => write!(out, "SeqScan {table}"),
+            Operator::IndexScan {
+                table,
+                prefix,
+                lower,
+                upper,
+            } => {
+                let _ = write!(out, "IndexScan {table}");
+                write_bounds(out, prefix, lower, upper)
+            }
+            Operator::SecondaryIndexScan {
+                table,
+                index,
+                prefix,
+                lower,
+                upper,
+                covering,
+            } => {
+                let name = match covering {
+                    true => "IndexOnlyScan",
+                    false => "IndexScan",
+                };
+                let _ = write!(out, "{name} {table} using {index}");
+                write_bounds(out, prefix, lower, upper)
+            }
+            Operator::Values { rows } => write!(out, "Values rows={}", rows.len()),
+            Operator::Filter { predicate } => write!(out, "Filter {}", show(predicate)),
+            Operator::Project { exprs } => {
+                write!(out, "Project [{}]", list(&mut exprs.iter().map(show)))
+            }
+            Operator::HashJoin {
+                keys,
+                filter,
+                build_left,
+            } => {
+                let side = if *build_left { "left" } else { "right" };
+                let _ = write!(out, "HashJoin keys=[{}] build={side}", join_keys(keys));
+                write_filter(out, filter.as_ref().map(show))
+            }
+            Operator::MergeJoin { keys, filter } => {
+                let _ = write!(out, "MergeJoin keys=[{}]", join_keys(keys));
+                write_filter(out, filter.as_ref().map(show))
+            }
+            Operator::NestedLoopJoin { filter } => {
+                let _ = write!(out, "NestedLoopJoin");
+                write_filter(out, filter.as_ref().map(show))
+            }
+            Operator::HashAggregate {
+                group_by,
+                aggregates,
+            }
+            | Operator::StreamAggregate {
+                group_by,
+                aggregates,
+            } => {
+                let name = match self.operator {
+                    Operator::HashAggregate { .. } => "HashAggregate",
+                    _ => "StreamAggregate",
+                };
+                let _ = write!(out, "{name}");
+                if !group_by.is_empty() {
+                    let _ = write!(out, " group=[{}]", list(&mut group_by.iter().map(show)));
+                }
+                if aggregates.is_empty() {
+                    return;
+                }
+                let calls = list(&mut aggregates.iter().map(|call| match &call.arg {
+                    None => "count(*)".to_owned(),
+                    Some(arg) => format!("{}({})", function_name(call.function), show(arg)),
+                }));
+                write!(out, " aggregates=[{calls}]")
+            }
+            Operator::Sort { keys: sort } => write!(out, "Sort [{}]", keys(sort, &input)),
+            Operator::TopN { keys: sort, limit } => {
+                write!(out, "TopN [{}] limit={limit}", keys(sort, &input))
+            }
+            Operator::Limit { limit, offset } => {
+                let _ = write!(out, "Limit");
+                if let Some(limit) = limit {
+                    let _ = write!(out, " limit={limit}");
+                }
+                match offset {
+                    0 => Ok(()),
+                    offset => write!(out, " offset={offset}"),
+                }
+            }
+            Operator::Analyze => write!(out, "Analyze"),
+            Operator::Explain => write!(out, "Explain"),
+        };
+    }
+}
+
+fn write_bounds(
+    out: &mut String,
+    prefix: &[Value],
+    lower: &Bound<Value>,
+    upper: &Bound<Value>,
+) -> fmt::Result {
+    if !prefix.is_empty() {
+        let prefix: Vec<String> = prefix.iter().map(Value::to_string).collect();
+        write!(out, " prefix=[{}]", prefix.join(", "))?;
+    }
+    match lower {
+        Bound::Included(v) => write!(out, " from={v}")?,
+        Bound::Excluded(v) => write!(out, " after={v}")?,
+        Bound::Unbounded => {}
+    }
+    match upper {
+        Bound::Included(v) => write!(out, " to={v}"),
+        Bound::Excluded(v) => write!(out, " before={v}"),
+        Bound::Unbounded => Ok(()),
+    }
+}
+
+fn write_filter(out: &mut String, filter: Option<String>) -> fmt::Result {
+    match filter {
+        Some(filter) => write!(out, " filter={filter}"),
+        None => Ok(()),
+    }
+}
+
+fn function_name(function: AggregateFunction) -> &'static str {
+    match function {
+        AggregateFunction::Count => "count",
+        AggregateFunction::Sum => "sum",
+        AggregateFunction::Min => "min",
+        AggregateFunction::Max => "max",
+        AggregateFunction::Avg => "avg",
+    }
+}
+
+/// Expression written with the names of the columns it reads.
+struct Show<'a> {
+    expr: &'a Scalar,
+    fields: &'a [Field],
+}
+
+impl Show<'_> {
+    fn nested<'a>(&'a self, expr: &'a Scalar) -> Show<'a> {
+        Show {
+            expr,
+            fields: self.fields,
+        }
+    }
+
+    /// Writes the expression, parenthesized if it is an operation.
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expr {
+            Scalar::Literal(_) | Scalar::Column(_) => fmt::Display::fmt(self, f),
+            _ => write!(f, "({self})"),
+        }
+    }
+}
+
+impl fmt::Display for Show<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expr {
+            Scalar::Literal(value) => value.fmt(f),
+            Scalar::Column(i) => match self.fields.get(*i) {
+                Some(Field {
+                    table: Some(table),
+                    name,
+                }) => write!(f, "{table}.{name}"),
+                Some(Field { table: None, name }) => f.write_str(name),
+                None => write!(f, "#{i}"),
+            },
+            Scalar::Unary { op, expr } => {
+                f.write_str(match op {
+                    UnaryOp::Not => "NOT ",
+                    UnaryOp::Neg => "-",
+                })?;
+                self.nested(expr).fmt_operand(f)
+            }
+            Scalar::Binary { op, left, right } => {
+                self.nested(left).fmt_operand(f)?;
+                write!(f, " {op} ")?;
+                self.nested(right).fmt_operand(f)
+            }
+            Scalar::IsNull { expr, negated } => {
+                self.nested(expr).fmt_operand(f)?;
+                f.write_str(match negated {
+                    true => " IS NOT NULL",
+                    false => " IS NULL",
+                })
+            }
+        }
+    }
+}
+
+/// Byte count written with a binary unit.
+struct Bytes(usize);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            n if n >= 1 << 20 => write!(f, "{:.1}MiB", n as f64 / (1 << 20) as f64),
+            n if n >= 1 << 10 => write!(f, "{:.1}KiB", n as f64 / (1 << 10) as f64),
+            n => write!(f, "{n}B"),
+        }
+    }
+}
@@ -0,0 +1,1064 @@
+//! Logical plans of statements.
+//!
+//! Planning resolves names against the [`Catalog`] and turns a statement into
+//! a tree of relational operators whose expressions refer to the columns of
+//! their input by position. Each operator maps onto a physical one that runs
+//! within a memory grant: [`Plan::Sort`] onto an
+//! [`ExternalSort`](crate::exec::ExternalSort), [`Plan::Aggregate`] onto a
+//! [`HashAggregate`](crate::exec::HashAggregate), and a [`Plan::Join`] with
+//! equality keys onto a [`HashJoin`](crate::exec::HashJoin). The rest stream
+//! rows through without holding them.
+//!
+//! Conditions of `WHERE` and `ON` clauses are split at `AND` and each placed
+//! on the lowest operator that sees every column it reads, so that tables are
+//! filtered before they are joined; equalities between the two sides of a
+//! join become its keys.
+
+use std::collections::HashSet;
+
+use super::ast::{
+    BinaryOp, CreateIndex, CreateTable, Delete, Expr, Insert, InsertSource, JoinKind, Select,
+    SelectItem, Statement, TableExpr, UnaryOp, Update,
+};
+use super::catalog::{Catalog, Column, IndexSchema, TableSchema};
+use super::value::Value;
+use crate::error::{Error, Result};
+
+/// Column of the rows an operator produces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    /// Table or alias the column can be qualified with.
+    pub table: Option<String>,
+    pub name: String,
+}
+
+impl Field {
+    fn new(table: Option<&str>, name: impl Into<String>) -> Self {
+        Field {
+            table: table.map(str::to_owned),
+            name: name.into(),
+        }
+    }
+}
+
+/// Expression over the columns of an operator's input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Literal(Value),
+    /// Column of the input, by position.
+    Column(usize),
+    Unary {
+        op: UnaryOp,
+        expr: Box<Scalar>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Scalar>,
+        right: Box<Scalar>,
+    },
+    IsNull {
+        expr: Box<Scalar>,
+        negated: bool,
+    },
+}
+
+impl Scalar {
+    /// Calls `f` with the position of every column the expression reads.
+    pub fn visit_columns(&self, f: &mut impl FnMut(usize)) {
+        match self {
+            Scalar::Literal(_) => {}
+            Scalar::Column(i) => f(*i),
+            Scalar::Unary { expr, .. } | Scalar::IsNull { expr, .. } => expr.visit_columns(f),
+            Scalar::Binary { left, right, .. } => {
+                left.visit_columns(f);
+                right.visit_columns(f);
+            }
+        }
+    }
+
+    /// Lowest and highest column the expression reads.
+    fn column_span(&self) -> Option<(usize, usize)> {
+        let mut span: Option<(usize, usize)> = None;
+        self.visit_columns(&mut |i| {
+            span = Some(span.map_or((i, i), |(lo, hi)| (lo.min(i), hi.max(i))));
+        });
+        span
+    }
+
+    /// Moves every column reference `by` positions to the left.
+    fn shift(&mut self, by: usize) {
+        match self {
+            Scalar::Literal(_) => {}
+            Scalar::Column(i) => *i -= by,
+            Scalar::Unary { expr, .. } | Scalar::IsNull { expr, .. } => expr.shift(by),
+            Scalar::Binary { left, right, .. } => {
+                left.shift(by);
+                right.shift(by);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl AggregateFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            "avg" => Some(AggregateFunction::Avg),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateCall {
+    pub function: AggregateFunction,
+    /// Argument; `None` for `COUNT(*)`.
+    pub arg: Option<Scalar>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub expr: Scalar,
+    pub descending: bool,
+}
+
+/// Logical plan of a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Plan {
+    CreateTable {
+        schema: TableSchema,
+        if_not_exists: bool,
+    },
+    /// Creates `index` from the rows `scan`, a [`Plan::Scan`] of its table,
+    /// reads.
+    CreateIndex {
+        index: IndexSchema,
+        if_not_exists: bool,
+        scan: Box<Plan>,
+    },
+    /// Inserts the rows of `source` into `table`, the value of each of its
+    /// columns going to the table column at the same position in `columns`.
+    Insert {
+        table: String,
+        columns: Vec<usize>,
+        source: Box<Plan>,
+    },
+    /// Sets columns of the rows of `table` that `source` produces, each to
+    /// an expression over the row's old values.
+    Update {
+        table: String,
+        assignments: Vec<(usize, Scalar)>,
+        source: Box<Plan>,
+    },
+    /// Deletes the rows of `table` that `source` produces.
+    Delete {
+        table: String,
+        source: Box<Plan>,
+    },
+    /// Every row of a table, in primary key order.
+    Scan {
+        table: String,
+        fields: Vec<Field>,
+    },
+    /// Rows of constant expressions.
+    Values {
+        rows: Vec<Vec<Scalar>>,
+        fields: Vec<Field>,
+    },
+    Filter {
+        input: Box<Plan>,
+        predicate: Scalar,
+    },
+    Project {
+        input: Box<Plan>,
+        exprs: Vec<Scalar>,
+        fields: Vec<Field>,
+    },
+    /// Pairs of rows of `left` and `right` whose `keys` are equal and that
+    /// pass `filter`, each the columns of the left row followed by those of
+    /// the right one. Each key pairs an expression over the left row with one
+    /// over the right row; without keys every pair is considered.
+    Join {
+        left: Box<Plan>,
+        right: Box<Plan>,
+        keys: Vec<(Scalar, Scalar)>,
+        filter: Option<Scalar>,
+    },
+    /// One row per group of input rows with equal `group_by` values: those
+    /// values followed by the result of each aggregate.
+    Aggregate {
+        input: Box<Plan>,
+        group_by: Vec<Scalar>,
+        aggregates: Vec<AggregateCall>,
+        fields: Vec<Field>,
+    },
+    Sort {
+        input: Box<Plan>,
+        keys: Vec<SortKey>,
+    },
+    Limit {
+        input: Box<Plan>,
+        limit: Option<u64>,
+        offset: u64,
+    },
+    /// Gathers the statistics of the table each of `scans`, a
+    /// [`Plan::Scan`], reads.
+    Analyze {
+        scans: Vec<Plan>,
+    },
+    /// Describes the physical plan of a statement instead of running it.
+    Explain(Box<Plan>),
+}
+
+impl Plan {
+    /// Columns of the rows the plan produces; none for statements other than
+    /// queries.
+    pub fn fields(&self) -> Vec<Field> {
+        match self {
+            Plan::CreateTable { .. }
+            | Plan::CreateIndex { .. }
+            | Plan::Insert { .. }
+            | Plan::Update { .. }
+            | Plan::Delete { .. }
+            | Plan::Analyze { .. } => Vec::new(),
+            Plan::Explain(_) => vec![Field::new(None, "plan")],
+            Plan::Scan { fields, .. }
+            | Plan::Values { fields, .. }
+            | Plan::Project { fields, .. }
+            | Plan::Aggregate { fields, .. } => fields.clone(),
+            Plan::Filter { input, .. } | Plan::Sort { input, .. } | Plan::Limit { input, .. } => {
+                input.fields()
+            }
+            Plan::Join { left, right, .. } => {
+                let mut fields = left.fields();
+                fields.extend(right.fields());
+                fields
+            }
+        }
+    }
+}
+
+/// Plans `statement` against the tables of `catalog`.
+pub fn plan(statement: &Statement, catalog: &Catalog) -> Result<Plan> {
+    let planner = Planner { catalog };
+    match statement {
+        Statement::CreateTable(create) => planner.create_table(create),
+        Statement::CreateIndex(create) => planner.create_index(create),
+        Statement::Insert(insert) => planner.insert(insert),
+        Statement::Update(update) => planner.update(update),
+        Statement::Delete(delete) => planner.delete(delete),
+        Statement::Select(select) => planner.select(select),
+        Statement::Analyze(table) => planner.analyze(table.as_deref()),
+        Statement::Explain(statement) => Ok(Plan::Explain(Box::new(plan(statement, catalog)?))),
+    }
+}
+
+struct Planner<'a> {
+    catalog: &'a Catalog,
+}
+
+/// Position of the column `name`, qualified by `table` if given, among
+/// `fields`.
+fn resolve(fields: &[Field], table: Option<&str>, name: &str) -> Result<usize> {
+    let mut found = fields.iter().enumerate().filter(|(_, f)| {
+        f.name == name && table.is_none_or(|table| f.table.as_deref() == Some(table))
+    });
+    let column = match table {
+        Some(table) => format!("{table}.{name}"),
+        None => name.to_owned(),
+    };
+    match (found.next(), found.next()) {
+        (Some((i, _)), None) => Ok(i),
+        (None, _) => Err(Error::invalid(format!("unknown column {column}"))),
+        (Some(_), Some(_)) => Err(Error::invalid(format!("column {column} is ambiguous"))),
+    }
+}
+
+fn is_aggregate(expr: &Expr) -> bool {
+    matches!(expr, Expr::Function { name, .. } if AggregateFunction::from_name(name).is_some())
+}
+
+fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function { .. } if is_aggregate(expr) => true,
+        Expr::Literal(_) | Expr::Column { .. } => false,
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => contains_aggregate(expr),
+        Expr::Binary { left, right, .. } => contains_aggregate(left) || contains_aggregate(right),
+        Expr::Function { args, .. } => args.iter().any(contains_aggregate),
+    }
+}
+
+/// Adds the aggregate calls in `expr` to `calls`, skipping repeats.
+fn collect_aggregates<'e>(expr: &'e Expr, calls: &mut Vec<&'e Expr>) -> Result<()> {
+    match expr {
+        Expr::Function { args, .. } if is_aggregate(expr) => {
+            if args.iter().any(contains_aggregate) {
+                return Err(Error::invalid("aggregate calls cannot be nested"));
+            }
+            if !calls.contains(&expr) {
+                calls.push(expr);
+            }
+        }
+        Expr::Literal(_) | Expr::Column { .. } => {}
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => collect_aggregates(expr, calls)?,
+        Expr::Binary { left, right, .. } => {
+            collect_aggregates(left, calls)?;
+            collect_aggregates(right, calls)?;
+        }
+        Expr::Function { args, .. } => {
+            for arg in args {
+                collect_aggregates(arg, calls)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Splits `expr` at its top-level `AND`s.
+fn conjuncts(expr: &Expr, out: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::And,
+            left,
+            right,
+        } => {
+            conjuncts(left, out);
+            conjuncts(right, out);
+        }
+        expr => out.push(expr.clone()),
+    }
+}
+
+/// Joins `predicates` with `AND`.
+fn conjunction(predicates: Vec<Scalar>) -> Option<Scalar> {
+    predicates.into_iter().reduce(|left, right| Scalar::Binary {
+        op: BinaryOp::And,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+fn filter(input: Plan, predicates: Vec<Scalar>) -> Plan {
+    match conjunction(predicates) {
+        Some(predicate) => Plan::Filter {
+            input: Box::new(input),
+            predicate,
+        },
+        None => input,
+    }
+}
+
+/// Binds expressions over the columns of one input.
+struct Scope<'a> {
+    fields: &'a [Field],
+}
+
+impl Scope<'_> {
+    fn bind(&self, expr: &Expr) -> Result<Scalar> {
+        Ok(match expr {
+            Expr::Literal(value) => Scalar::Literal(value.clone()),
+            Expr::Column { table, name } => {
+                Scalar::Column(resolve(self.fields, table.as_deref(), name)?)
+            }
+            Expr::Unary { op, expr } => Scalar::Unary {
+                op: *op,
+                expr: Box::new(self.bind(expr)?),
+            },
+            Expr::Binary { op, left, right } => Scalar::Binary {
+                op: *op,
+                left: Box::new(self.bind(left)?),
+                right: Box::new(self.bind(right)?),
+            },
+            Expr::IsNull { expr, negated } => Scalar::IsNull {
+                expr: Box::new(self.bind(expr)?),
+                negated: *negated,
+            },
+            Expr::Function { name, .. } if is_aggregate(expr) => {
+                return Err(Error::invalid(format!(
+                    "aggregate {name} is not allowed here"
+                )));
+            }
+            Expr::Function { name, .. } => {
+                return Err(Error::invalid(format!("unknown function {name}")));
+            }
+        })
+    }
+}
+
+/// Binds expressions over the rows of an aggregation: its group keys, then
+/// its aggregate results.
+struct GroupedScope<'a> {
+    /// Scope of the aggregation's input.
+    input: Scope<'a>,
+    group_by: &'a [Expr],
+    /// Bound group keys, to recognize columns grouped on.
+    group_keys: &'a [Scalar],
+    aggregates: &'a [&'a Expr],
+}
+
+impl GroupedScope<'_> {
+    fn bind(&self, expr: &Expr) -> Result<Scalar> {
+        if let Some(i) = self.group_by.iter().position(|g| g == expr) {
+            return Ok(Scalar::Column(i));
+        }
+        if let Some(i) = self.aggregates.iter().position(|a| *a == expr) {
+            return Ok(Scalar::Column(self.group_by.len() + i));
+        }
+        Ok(match expr {
+            Expr::Column { name, .. } => {
+                let column = self.input.bind(expr)?;
+                match self.group_keys.iter().position(|k| *k == column) {
+                    Some(i) => Scalar::Column(i),
+                    None => {
+                        return Err(Error::invalid(format!(
+                            "column {name} must appear in GROUP BY or be used in an aggregate"
+                        )));
+                    }
+                }
+            }
+            Expr::Literal(value) => Scalar::Literal(value.clone()),
+            Expr::Unary { op, expr } => Scalar::Unary {
+                op: *op,
+                expr: Box::new(self.bind(expr)?),
+            },
+            Expr::Binary { op, left, right } => Scalar::Binary {
+                op: *op,
+                left: Box::new(self.bind(left)?),
+                right: Box::new(self.bind(right)?),
+            },
+            Expr::IsNull { expr, negated } => Scalar::IsNull {
+                expr: Box::new(self.bind(expr)?),
+                negated: *negated,
+            },
+            Expr::Function { .. } => return self.input.bind(expr),
+        })
+    }
+}
+
+/// Operator of a `FROM` clause being built, with its number of columns.
+struct Placed {
+    plan: Plan,
+    len: usize,
+}
+
+/// Condition of a `WHERE` or `ON` clause over the columns of the whole
+/// `FROM` clause, waiting to be placed.
+struct Pending {
+    predicate: Scalar,
+    span: Option<(usize, usize)>,
+}
+
+impl Planner<'_> {
+    fn table(&self, name: &str) -> Result<&TableSchema> {
+        self.catalog
+            .table(name)
+            .ok_or_else(|| Error::invalid(format!("unknown table {name}")))
+    }
+
+    fn create_table(&self, create: &CreateTable) -> Result<Plan> {
+        if self.catalog.table(&create.name).is_some() && !create.if_not_exists {
+            return Err(Error::invalid(format!(
+                "table {} already exists",
+                create.name
+            )));
+        }
+        let mut columns: Vec<Column> = Vec::new();
+        for def in &create.columns {
+            if columns.iter().any(|c| c.name == def.name) {
+                return Err(Error::invalid(format!(
+                    "column {} is defined more than once",
+                    def.name
+                )));
+            }
+            columns.push(Column {
+                name: def.name.clone(),
+                data_type: def.data_type,
+                not_null: def.not_null || def.primary_key,
+            });
+        }
+        let inline: Vec<&str> = create
+            .columns
+            .iter()
+            .filter(|c| c.primary_key)
+            .map(|c| c.name.as_str())
+            .collect();
+        let key_names: Vec<&str> = match (inline.len(), create.primary_key.len()) {
+            (0, _) => create.primary_key.iter().map(String::as_str).collect(),
+            (1, 0) => inline,
+            _ => return Err(Error::invalid("a table can have only one primary key")),
+        };
+        let mut schema = TableSchema {
+            name: create.name.clone(),
+            columns,
+            primary_key: Vec::new(),
+        };
+        for name in key_names {
+            let Some(i) = schema.column(name) else {
+                return Err(Error::invalid(format!(
+                    "unknown column {name} in primary key"
+                )));
+            };
+            if schema.primary_key.contains(&i) {
+                return Err(Error::invalid(format!(
+                    "column {name} appears twice in the primary key"
+                )));
+            }
+            schema.columns[i].not_null = true;
+            schema.primary_key.push(i);
+        }
+        Ok(Plan::CreateTable {
+            schema,
+            if_not_exists: create.if_not_exists,
+        })
+    }
+
+    fn create_index(&self, create: &CreateIndex) -> Result<Plan> {
+        if self.catalog.index(&create.name).is_some() && !create.if_not_exists {
+            return Err(Error::invalid(format!(
+                "index {} already exists",
+                create.name
+            )));
+        }
+        let scan = self.scan(&create.table, None)?;
+        let scope = Scope {
+            fields: &scan.fields(),
+        };
+        let exprs = create
+            .exprs
+            .iter()
+            .map(|expr| scope.bind(expr))
+            .collect::<Result<_>>()?;
+        Ok(Plan::CreateIndex {
+            index: IndexSchema {
+                name: create.name.clone(),
+                table: create.table.clone(),
+                exprs,
+                unique: create.unique,
+            },
+            if_not_exists: create.if_not_exists,
+            scan: Box::new(scan),
+        })
+    }
+
+    fn scan(&self, name: &str, alias: Option<&str>) -> Result<Plan> {
+        let schema = self.table(name)?;
+        let qualifier = alias.unwrap_or(name);
+        let fields = schema
+            .columns
+            .iter()
+            .map(|c| Field::new(Some(qualifier), &c.name))
+            .collect();
+        Ok(Plan::Scan {
+            table: name.to_owned(),
+            fields,
+        })
+    }
+
+    /// Scan of `table` keeping the rows that pass `filter`.
+    fn filtered_scan(&self, table: &str, predicate: Option<&Expr>) -> Result<Plan> {
+        let scan = self.scan(table, None)?;
+        let Some(predicate) = predicate else {
+            return Ok(scan);
+        };
+        let predicate = Scope {
+            fields: &scan.fields(),
+        }
+        .bind(predicate)?;
+        Ok(filter(scan, vec![predicate]))
+    }
+
+    fn insert(&self, insert: &Insert) -> Result<Plan> {
+        let schema = self.table(&insert.table)?;
+        let columns: Vec<usize> = match &insert.columns {
+            None => (0..schema.columns.len()).collect(),
+            Some(names) => {
+                let mut columns = Vec::new();
+                for name in names {
+                    let Some(i) = schema.column(name) else {
+                        return Err(Error::invalid(format!(
+                            "table {} has no column {name}",
+                            schema.name
+                        )));
+                    };
+                    if columns.contains(&i) {
+                        return Err(Error::invalid(format!(
+                            "column {name} is inserted more than once"
+                        )));
+                    }
+                    columns.push(i);
+                }
+                columns
+            }
+        };
+        if let Some(missing) =
+            (0..schema.columns.len()).find(|i| schema.columns[*i].not_null && !columns.contains(i))
+        {
+            return Err(Error::invalid(format!(
+                "column {} cannot be null",
+                schema.columns[missing].name
+            )));
+        }
+        let fields: Vec<Field> = columns
+            .iter()
+            .map(|&i| Field::new(None, &schema.columns[i].name))
+            .collect();
+        let source = match &insert.source {
+            InsertSource::Values(rows) => {
+                let scope = Scope { fields: &[] };
+                let mut bound = Vec::with_capacity(rows.len());
+                for row in rows {
+                    if row.len() != columns.len() {
+                        return Err(Error::invalid(format!(
+                            "{} values given for {} columns",
+                            row.len(),
+                            columns.len()
+                        )));
+                    }
+                    bound.push(row.iter().map(|e| scope.bind(e)).collect::<Result<_>>()?);
+                }
+                Plan::Values {
+                    rows: bound,
+                    fields,
+                }
+            }
+            InsertSource::Select(select) => {
+                let source = self.select(select)?;
+                let width = source.fields().len();
+                if width != columns.len() {
+                    return Err(Error::invalid(format!(
+                        "query returns {width} columns for {} inserted columns",
+                        columns.len()
+                    )));
+                }
+                source
+            }
+        };
+        Ok(Plan::Insert {
+            table: schema.name.clone(),
+            columns,
+            source: Box::new(source),
+        })
+    }
+
+    fn update(&self, update: &Update) -> Result<Plan> {
+        let source = self.filtered_scan(&update.table, update.filter.as_ref())?;
+        let schema = self.table(&update.table)?;
+        let fields = source.fields();
+        let scope = Scope { fields: &fields };
+        let mut assignments: Vec<(usize, Scalar)> = Vec::new();
+        for (name, expr) in &update.assignments {
+            let Some(i) = schema.column(name) else {
+                return Err(Error::invalid(format!(
+                    "table {} has no column {name}",
+                    schema.name
+                )));
+            };
+            if assignments.iter().any(|(c, _)| *c == i) {
+                return Err(Error::invalid(format!(
+                    "column {name} is assigned more than once"
+                )));
+            }
+            assignments.push((i, scope.bind(expr)?));
+        }
+        Ok(Plan::Update {
+            table: schema.name.clone(),
+            assignments,
+            source: Box::new(source),
+        })
+    }
+
+    fn delete(&self, delete: &Delete) -> Result<Plan> {
+        let source = self.filtered_scan(&delete.table, delete.filter.as_ref())?;
+        Ok(Plan::Delete {
+            table: delete.table.clone(),
+            source: Box::new(source),
+        })
+    }
+
+    fn analyze(&self, table: Option<&str>) -> Result<Plan> {
+        let scans = match table {
+            Some(table) => vec![self.scan(table, None)?],
+            None => self
+                .catalog
+                .tables()
+                .map(|schema| self.scan(&schema.name, None))
+                .collect::<Result<_>>()?,
+        };
+        Ok(Plan::Analyze { scans })
+    }
+
+    /// Columns of the whole `FROM` clause, checking that no two tables go by
+    /// the same name.
+    fn clause_fields(&self, from: &TableExpr, names: &mut HashSet<String>) -> Result<Vec<Field>> {
+        match from {
+            TableExpr::Table { name, alias } => {
+                let qualifier = alias.as_ref().unwrap_or(name);
+                if !names.insert(qualifier.clone()) {
+                    return Err(Error::invalid(format!(
+                        "table name {qualifier} is used more than once"
+                    )));
+                }
+                Ok(self.scan(name, alias.as_deref())?.fields())
+            }
+            TableExpr::Join { left, right, .. } => {
+                let mut fields = self.clause_fields(left, names)?;
+                fields.extend(self.clause_fields(right, names)?);
+                Ok(fields)
+            }
+        }
+    }
+
+    fn collect_conditions(from: &TableExpr, out: &mut Vec<Expr>) {
+        if let TableExpr::Join {
+            left, right, on, ..
+        } = from
+        {
+            Self::collect_conditions(left, out);
+            Self::collect_conditions(right, out);
+            if let Some(on) = on {
+                conjuncts(on, out);
+            }
+        }
+    }
+
+    /// Builds the operators of a `FROM` clause starting at column `start`,
+    /// placing the conditions in `pending` that read only their columns.
+    fn join_tree(
+        &self,
+        from: &TableExpr,
+        start: usize,
+        pending: &mut Vec<Pending>,
+    ) -> Result<Placed> {
+        let (plan, len, split) = match from {
+            TableExpr::Table { name, alias } => {
+                let plan = self.scan(name, alias.as_deref())?;
+                let len = plan.fields().len();
+                (plan, len, None)
+            }
+            TableExpr::Join {
+                left, right, kind, ..
+            } => {
+                debug_assert!(*kind == JoinKind::Inner || *kind == JoinKind::Cross);
+                let left = self.join_tree(left, start, pending)?;
+                let right = self.join_tree(right, start + left.len, pending)?;
+                let len = left.len + right.len;
+                let join = Plan::Join {
+                    left: Box::new(left.plan),
+                    right: Box::new(right.plan),
+                    keys: Vec::new(),
+                    filter: None,
+                };
+                (join, len, Some(start + left.len))
+            }
+        };
+        let end = start + len;
+        let (placed, rest): (Vec<Pending>, Vec<Pending>) = std::mem::take(pending)
+            .into_iter()
+            .partition(|p| p.span.is_some_and(|(lo, hi)| lo >= start && hi < end));
+        *pending = rest;
+        let mut predicates = Vec::new();
+        let mut keys = Vec::new();
+        for Pending { mut predicate, .. } in placed {
+            if let Some(split) = split {
+                if let Some(key) = join_key(&predicate, start, split) {
+                    keys.push(key);
+                    continue;
+                }
+            }
+            predicate.shift(start);
+            predicates.push(predicate);
+        }
+        let plan = match plan {
+            Plan::Join { left, right, .. } => Plan::Join {
+                left,
+                right,
+                keys,
+                filter: conjunction(predicates),
+            },
+            plan => filter(plan, predicates),
+        };
+        Ok(Placed { plan, len })
+    }
+
+    fn select(&self, select: &Select) -> Result<Plan> {
+        // Operators producing the rows of the FROM clause, filtered.
+        let mut conditions = Vec::new();
+        if let Some(from) = &select.from {
+            Self::collect_conditions(from, &mut conditions);
+        }
+        if let Some(filter) = &select.filter {
+            conjuncts(filter, &mut conditions);
+        }
+        let (mut plan, fields) = match &select.from {
+            Some(from) => {
+                let fields = self.clause_fields(from, &mut HashSet::new())?;
+                let scope = Scope { fields: &fields };
+                let mut pending = Vec::new();
+                for condition in &conditions {
+                    let predicate = scope.bind(condition)?;
+                    let span = predicate.column_span();
+                    pending.push(Pending { predicate, span });
+                }
+                let placed = self.join_tree(from, 0, &mut pending)?;
+                let rest = pending.into_iter().map(|p| p.predicate).collect();
+                (filter(placed.plan, rest), fields)
+            }
+            None => {
+                let values = Plan::Values {
+                    rows: vec![Vec::new()],
+                    fields: Vec::new(),
+                };
+                let scope = Scope { fields: &[] };
+                let predicates = conditions
+                    .iter()
+                    .map(|c| scope.bind(c))
+                    .collect::<Result<_>>()?;
+                (filter(values, predicates), Vec::new())
+            }
+        };
+        let scope = Scope { fields: &fields };
+
+        // Select list, expanding wildcards.
+        let mut items: Vec<(Expr, Field)> = Vec::new();
+        for item in &select.items {
+            match item {
+                SelectItem::Wildcard | SelectItem::QualifiedWildcard(_) => {
+                    let table = match item {
+                        SelectItem::QualifiedWildcard(table) => Some(table.as_str()),
+                        _ => None,
+                    };
+                    let before = items.len();
+                    for field in &fields {
+                        if table.is_none_or(|t| field.table.as_deref() == Some(t)) {
+                            let expr = Expr::Column {
+                                table: field.table.clone(),
+                                name: field.name.clone(),
+                            };
+                            items.push((expr, field.clone()));
+                        }
+                    }
+                    if items.len() == before {
+                        return Err(match table {
+                            Some(table) => Error::invalid(format!("unknown table {table}")),
+                            None => Error::invalid("SELECT * without tables"),
+                        });
+                    }
+                }
+                SelectItem::Expr { expr, alias } => {
+                    let field = match (alias, expr) {
+                        (Some(alias), _) => Field::new(None, alias),
+                        (None, Expr::Column { name, .. }) => {
+                            let Scalar::Column(i) = scope.bind(expr)? else {
+                                unreachable!()
+                            };
+                            Field::new(fields[i].table.as_deref(), name)
+                        }
+                        (None, expr) => Field::new(None, expr.to_string()),
+                    };
+                    items.push((expr.clone(), field));
+                }
+            }
+        }
+
+        // Aggregation.
+        let mut aggregates = Vec::new();
+        for (expr, _) in &items {
+            collect_aggregates(expr, &mut aggregates)?;
+        }
+        if let Some(having) = &select.having {
+            collect_aggregates(having, &mut aggregates)?;
+        }
+        for key in &select.order_by {
+            collect_aggregates(&key.expr, &mut aggregates)?;
+        }
+        let grouped = !select.group_by.is_empty() || !aggregates.is_empty();
+        if select.having.is_some() && !grouped {
+            return Err(Error::invalid("HAVING without GROUP BY or aggregates"));
+        }
+        let group_keys: Vec<Scalar> = select
+            .group_by
+            .iter()
+            .map(|e| scope.bind(e))
+            .collect::<Result<_>>()?;
+        let grouped_scope = GroupedScope {
+            input: Scope { fields: &fields },
+            group_by: &select.group_by,
+            group_keys: &group_keys,
+            aggregates: &aggregates,
+        };
+        let bind = |expr: &Expr| match grouped {
+            true => grouped_scope.bind(expr),
+            false => scope.bind(expr),
+        };
+        if grouped {
+            let mut calls = Vec::new();
+            let mut agg_fields: Vec<Field> = select
+                .group_by
+                .iter()
+                .zip(&group_keys)
+                .map(|(expr, key)| match key {
+                    Scalar::Column(i) => fields[*i].clone(),
+                    _ => Field::new(None, expr.to_string()),
+                })
+                .collect();
+            for call in &aggregates {
+                let Expr::Function { name, args, star } = call else {
+                    unreachable!()
+                };
+                let function = AggregateFunction::from_name(name).unwrap();
+                let arg = match (function, *star, args.as_slice()) {
+                    (AggregateFunction::Count, true, []) => None,
+                    (_, false, [arg]) => Some(scope.bind(arg)?),
+                    _ => {
+                        return Err(Error::invalid(format!(
+                            "wrong arguments to aggregate {name}"
+                        )));
+                    }
+                };
+                calls.push(AggregateCall { function, arg });
+                agg_fields.push(Field::new(None, call.to_string()));
+            }
+            plan = Plan::Aggregate {
+                input: Box::new(plan),
+                group_by: group_keys.clone(),
+                aggregates: calls,
+                fields: agg_fields,
+            };
+            if let Some(having) = &select.having {
+                plan = filter(plan, vec![grouped_scope.bind(having)?]);
+            }
+        }
+
+        // Projection, with the sort keys not in the select list appended.
+        let mut exprs = items
+            .iter()
+            .map(|(expr, _)| bind(expr))
+            .collect::<Result<Vec<_>>>()?;
+        let mut out_fields: Vec<Field> = items.iter().map(|(_, f)| f.clone()).collect();
+        let width = exprs.len();
+        let mut sort_keys = Vec::new();
+        for key in &select.order_by {
+            let column = match &key.expr {
+                Expr::Literal(Value::Integer(n)) => match usize::try_from(*n) {
+                    Ok(n) if (1..=width).contains(&n) => n - 1,
+                    _ => {
+                        return Err(Error::invalid(format!(
+                            "ORDER BY position {n} is not in the select list"
+                        )));
+                    }
+                },
+                expr => {
+                    let named = match expr {
+                        Expr::Column { table: None, name } => {
+                            let mut matches = out_fields[..width]
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, f)| f.name == *name);
+                            match (matches.next(), matches.next()) {
+                                (Some((i, _)), None) => Some(i),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    match named {
+                        Some(i) => i,
+                        None => {
+                            let bound = bind(expr)?;
+                            match exprs.iter().position(|e| *e == bound) {
+                                Some(i) => i,
+                                None if select.distinct => {
+                                    return Err(Error::invalid(
+                                        "ORDER BY of a SELECT DISTINCT must use selected columns",
+                                    ));
+                                }
+                                None => {
+                                    exprs.push(bound);
+                                    out_fields.push(Field::new(None, expr.to_string()));
+                                    exprs.len() - 1
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            sort_keys.push(SortKey {
+                expr: Scalar::Column(column),
+                descending: key.descending,
+            });
+        }
+        plan = Plan::Project {
+            input: Box::new(plan),
+            exprs,
+            fields: out_fields.clone(),
+        };
+        if select.distinct {
+            plan = Plan::Aggregate {
+                input: Box::new(plan),
+                group_by: (0..width).map(Scalar::Column).collect(),
+                aggregates: Vec::new(),
+                fields: out_fields[..width].to_vec(),
+            };
+        }
+        if !sort_keys.is_empty() {
+            plan = Plan::Sort {
+                input: Box::new(plan),
+                keys: sort_keys,
+            };
+        }
+        if out_fields.len() > width {
+            plan = Plan::Project {
+                input: Box::new(plan),
+                exprs: (0..width).map(Scalar::Column).collect(),
+                fields: out_fields[..width].to_vec(),
+            };
+        }
+        if select.limit.is_some() || select.offset.is_some() {
+            plan = Plan::Limit {
+                input: Box::new(plan),
+                limit: select.limit,
+                offset: select.offset.unwrap_or(0),
+            };
+        }
+        Ok(plan)
+    }
+}
+
+/// The condition as a key of a join whose left input starts at column
+/// `start` and right input at `split`, if it equates an expression over one
+/// side with one over the other.
+fn join_key(predicate: &Scalar, start: usize, split: usize) -> Option<(Scalar, Scalar)> {
+    let Scalar::Binary {
+        op: BinaryOp::Eq,
+        left,
+        right,
+    } = predicate
+    else {
+        return None;
+    };
+    let side = |e: &Scalar| match e.column_span() {
+        Some((_, hi)) if hi < split => Some(true),
+        Some((lo, _)) if lo >= split => Some(false),
+        _ => None,
+    };
+    let (mut l, mut r) = match (side(left)?, side(right)?) {
+        (true, false) => ((**left).clone(), (**right).clone()),
+        (false, true) => ((**right).clone(), (**left).clone()),
+        _ => return None,
+    };
+    l.shift(start);
+    r.shift(split);
+    Some((l, r))
+}
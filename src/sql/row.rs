@@ -0,0 +1,141 @@
+//! Encodings of rows and keys.
+//!
+//! Rows are stored and passed between operators as a tag byte per value
+//! followed by its contents. Keys — primary keys in storage, and the sort,
+//! group and join keys of operators — use an encoding whose bytewise order is
+//! the order of the values: each value is a tag byte, ordered like the
+//! variants of [`Value`], then an integer as big-endian bytes with the sign
+//! bit flipped, or text with its zero bytes escaped and a terminator. A key
+//! is never a prefix of another, so inverting its bytes reverses the order,
+//! which is how descending sort keys are encoded.
+
+use super::value::Value;
+use crate::coding::{put_bytes, put_u64, put_varint, Reader};
+use crate::error::{Error, Result};
+
+const NULL: u8 = 0;
+const INTEGER: u8 = 1;
+const TEXT: u8 = 2;
+const BOOLEAN: u8 = 3;
+
+/// Row of values as operators pass them on.
+pub(crate) type Row = Vec<Value>;
+
+pub(crate) fn encode_row(row: &[Value], out: &mut Vec<u8>) {
+    put_varint(out, row.len() as u64);
+    for value in row {
+        match value {
+            Value::Null => out.push(NULL),
+            Value::Integer(n) => {
+                out.push(INTEGER);
+                put_u64(out, *n as u64);
+            }
+            Value::Text(s) => {
+                out.push(TEXT);
+                put_bytes(out, s.as_bytes());
+            }
+            Value::Boolean(b) => {
+                out.push(BOOLEAN);
+                out.push(*b as u8);
+            }
+        }
+    }
+}
+
+pub(crate) fn row_bytes(row: &[Value]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_row(row, &mut out);
+    out
+}
+
+pub(crate) fn decode_row(buf: &[u8]) -> Result<Row> {
+    let mut reader = Reader::new(buf);
+    let len = reader.varint()? as usize;
+    let mut row = Vec::with_capacity(len.min(buf.len()));
+    for _ in 0..len {
+        row.push(match reader.u8()? {
+            NULL => Value::Null,
+            INTEGER => Value::Integer(reader.u64()? as i64),
+            TEXT => Value::Text(text(reader.bytes()?)?),
+            BOOLEAN => Value::Boolean(reader.u8()? != 0),
+            tag => return Err(Error::corruption(format!("unknown value tag {tag}"))),
+        });
+    }
+    Ok(row)
+}
+
+/// Appends the order-preserving encoding of `value`.
+pub(crate) fn encode_key(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(NULL),
+        Value::Integer(n) => {
+            out.push(INTEGER);
+            out.extend_from_slice(&(*n as u64 ^ 1 << 63).to_be_bytes());
+        }
+        Value::Text(s) => {
+            out.push(TEXT);
+            for &b in s.as_bytes() {
+                out.push(b);
+                if b == 0 {
+                    out.push(0xff);
+                }
+            }
+            out.extend_from_slice(&[0, 0]);
+        }
+        Value::Boolean(b) => {
+            out.push(BOOLEAN);
+            out.push(*b as u8);
+        }
+    }
+}
+
+/// Appends the encoding of `value` ordering it in reverse.
+pub(crate) fn encode_key_descending(value: &Value, out: &mut Vec<u8>) {
+    let start = out.len();
+    encode_key(value, out);
+    for b in &mut out[start..] {
+        *b = !*b;
+    }
+}
+
+/// Order-preserving encoding of `values` one after the other.
+pub(crate) fn key_bytes<'v>(values: impl IntoIterator<Item = &'v Value>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        encode_key(value, &mut out);
+    }
+    out
+}
+
+/// Decodes the values of a key made of ascending values only.
+pub(crate) fn decode_key(buf: &[u8]) -> Result<Row> {
+    let mut reader = Reader::new(buf);
+    let mut values = Vec::new();
+    while !reader.is_empty() {
+        values.push(match reader.u8()? {
+            NULL => Value::Null,
+            INTEGER => {
+                let bytes = reader.take(8)?.try_into().unwrap();
+                Value::Integer((u64::from_be_bytes(bytes) ^ 1 << 63) as i64)
+            }
+            TEXT => {
+                let mut bytes = Vec::new();
+                loop {
+                    match reader.u8()? {
+                        0 if reader.u8()? == 0 => break,
+                        0 => bytes.push(0),
+                        b => bytes.push(b),
+                    }
+                }
+                Value::Text(text(&bytes)?)
+            }
+            BOOLEAN => Value::Boolean(reader.u8()? != 0),
+            tag => return Err(Error::corruption(format!("unknown key tag {tag}"))),
+        });
+    }
+    Ok(values)
+}
+
+fn text(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::corruption("text is not UTF-8"))
+}
@@ -0,0 +1,393 @@
+//! Statistics of the contents of tables, gathered by `ANALYZE`.
+//!
+//! An [`Analyzer`] sees every row of a table once and stays within a fixed
+//! memory grant however large the table is. Row, null and byte counts are
+//! kept exactly; distinct values of each column are counted with a
+//! HyperLogLog sketch; and a uniform sample of rows is kept by reservoir
+//! sampling, from which an equi-depth histogram of each column is built once
+//! every row has been seen. The sample holds as many rows as the part of the
+//! grant the sketches leave fits: once it is full, a sampled row is dropped
+//! at random for good whenever a new one would overflow it, which keeps the
+//! sample uniform.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::mem::{self, size_of};
+
+use super::row::{decode_row, row_bytes};
+use super::value::Value;
+use crate::coding::{put_bytes, put_u64, put_varint, Reader};
+use crate::error::{Error, Result};
+use crate::memory::Reservation;
+
+/// Grant the optimizer asks for to analyze a table.
+pub const ANALYZE_GRANT: usize = 256 << 10;
+
+/// Smallest grant an analyzer accepts.
+pub const MIN_ANALYZE_GRANT: usize = 16 << 10;
+
+/// Buckets of the histograms built.
+const HISTOGRAM_BUCKETS: usize = 100;
+
+/// Bounds of the number of bits of a hash that pick a register of a
+/// distinct count sketch; the sketch has 2^precision registers.
+const MIN_PRECISION: u32 = 4;
+const MAX_PRECISION: u32 = 14;
+
+/// Bytes a sampled row costs beyond its values.
+const ROW_OVERHEAD: usize = size_of::<Vec<Value>>();
+
+/// What the optimizer knows of the contents of a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableStatistics {
+    /// Number of rows.
+    pub rows: u64,
+    /// Average bytes of a row.
+    pub row_size: usize,
+    /// Statistics of each column, in table order; empty if the table was
+    /// never analyzed.
+    pub columns: Vec<ColumnStatistics>,
+}
+
+impl Default for TableStatistics {
+    /// Guesses used for tables never analyzed.
+    fn default() -> Self {
+        TableStatistics {
+            rows: 1000,
+            row_size: 100,
+            columns: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStatistics {
+    /// Fraction of rows whose value is `NULL`.
+    pub null_fraction: f64,
+    /// Estimated number of distinct values other than `NULL`.
+    pub distinct: u64,
+    /// Average bytes of a value, counting `NULL`s as empty.
+    pub width: f64,
+    pub histogram: Histogram,
+}
+
+impl TableStatistics {
+    /// Encoding of the statistics as they are stored.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u64(&mut out, self.rows);
+        put_varint(&mut out, self.row_size as u64);
+        put_varint(&mut out, self.columns.len() as u64);
+        for column in &self.columns {
+            put_u64(&mut out, column.null_fraction.to_bits());
+            put_u64(&mut out, column.distinct);
+            put_u64(&mut out, column.width.to_bits());
+            put_bytes(&mut out, &row_bytes(&column.histogram.bounds));
+        }
+        out
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(buf);
+        let rows = reader.u64()?;
+        let row_size = reader.varint()? as usize;
+        let count = reader.varint()? as usize;
+        let mut columns = Vec::with_capacity(count.min(buf.len()));
+        for _ in 0..count {
+            columns.push(ColumnStatistics {
+                null_fraction: f64::from_bits(reader.u64()?),
+                distinct: reader.u64()?,
+                width: f64::from_bits(reader.u64()?),
+                histogram: Histogram {
+                    bounds: decode_row(reader.bytes()?)?,
+                },
+            });
+        }
+        Ok(TableStatistics {
+            rows,
+            row_size,
+            columns,
+        })
+    }
+}
+
+/// Equi-depth histogram of the values of a column other than `NULL`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Smallest value followed by the largest value of each bucket, in
+    /// ascending order. Each bucket holds the same number of values, so a
+    /// value frequent enough to fill several buckets repeats as their bound.
+    /// Empty if the column holds nothing but `NULL`s.
+    pub bounds: Vec<Value>,
+}
+
+impl Histogram {
+    /// Histogram of `values`, which must be sorted.
+    fn new(values: &[Value], buckets: usize) -> Self {
+        if values.is_empty() {
+            return Histogram::default();
+        }
+        let last = values.len() - 1;
+        let buckets = buckets.min(last).max(1);
+        let bounds = (0..=buckets)
+            .map(|i| values[i * last / buckets].clone())
+            .collect();
+        Histogram { bounds }
+    }
+
+    /// Estimated fraction of the values that are less than `value`, or at
+    /// most `value` if `inclusive`.
+    pub fn fraction_below(&self, value: &Value, inclusive: bool) -> f64 {
+        let bounds = &self.bounds;
+        let Some(buckets) = bounds.len().checked_sub(1).filter(|&n| n > 0) else {
+            return match bounds.first() {
+                None => 0.5,
+                Some(only) if only < value || (inclusive && only == value) => 1.0,
+                Some(_) => 0.0,
+            };
+        };
+        let below = |bound: &Value| bound < value || (inclusive && bound == value);
+        if !below(&bounds[0]) {
+            return 0.0;
+        }
+        // Buckets whose largest value is below `value`.
+        let full = bounds[1..].partition_point(below);
+        if full == buckets {
+            return 1.0;
+        }
+        let partial = match (&bounds[full], &bounds[full + 1], value) {
+            (Value::Integer(lo), Value::Integer(hi), Value::Integer(v)) if hi > lo => {
+                (*v as f64 - *lo as f64) / (*hi as f64 - *lo as f64)
+            }
+            _ => 0.5,
+        };
+        (full as f64 + partial.clamp(0.0, 1.0)) / buckets as f64
+    }
+}
+
+/// Gathers the [`TableStatistics`] of a table from each of its rows, within
+/// a memory grant.
+pub struct Analyzer {
+    grant: Reservation,
+    rows: u64,
+    columns: Vec<ColumnSummary>,
+    sample: Vec<Vec<Value>>,
+    /// Bytes the sample takes, and the most it may take.
+    sample_bytes: usize,
+    sample_limit: usize,
+    /// Rows the reservoir holds once full; unbounded until the sample first
+    /// outgrows its limit.
+    capacity: usize,
+    random: Random,
+}
+
+/// Exact counts and distinct count sketch of a column.
+struct ColumnSummary {
+    nulls: u64,
+    bytes: u64,
+    sketch: HyperLogLog,
+}
+
+impl Analyzer {
+    /// Analyzes rows of `columns` values within `grant`, a quarter of which
+    /// goes to the distinct count sketches and the rest to the sample.
+    pub fn new(columns: usize, grant: Reservation) -> Result<Self> {
+        if grant.size() < MIN_ANALYZE_GRANT {
+            return Err(Error::invalid(format!(
+                "an analysis needs a memory grant of at least {MIN_ANALYZE_GRANT} bytes"
+            )));
+        }
+        let registers = grant.size() / 4 / columns.max(1);
+        let precision = registers.max(1).ilog2().clamp(MIN_PRECISION, MAX_PRECISION);
+        let columns: Vec<ColumnSummary> = (0..columns)
+            .map(|_| ColumnSummary {
+                nulls: 0,
+                bytes: 0,
+                sketch: HyperLogLog::new(precision),
+            })
+            .collect();
+        let sketches = columns.len() << precision;
+        Ok(Analyzer {
+            sample_limit: grant.size().saturating_sub(sketches),
+            grant,
+            rows: 0,
+            columns,
+            sample: Vec::new(),
+            sample_bytes: 0,
+            capacity: usize::MAX,
+            random: Random(0x5eed),
+        })
+    }
+
+    /// Adds a row of the table, one value per column.
+    pub fn push(&mut self, row: &[Value]) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows += 1;
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            if value.is_null() {
+                column.nulls += 1;
+                continue;
+            }
+            column.bytes += width(value) as u64;
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            column.sketch.insert(hasher.finish());
+        }
+
+        let size = sampled_size(row);
+        if self.sample.len() < self.capacity {
+            self.sample.push(row.to_vec());
+        } else {
+            let i = self.random.below(self.rows) as usize;
+            if i >= self.capacity {
+                return;
+            }
+            self.sample_bytes -= sampled_size(&self.sample[i]);
+            self.sample[i] = row.to_vec();
+        }
+        self.sample_bytes += size;
+        while self.sample_bytes > self.sample_limit && !self.sample.is_empty() {
+            let i = self.random.below(self.sample.len() as u64) as usize;
+            let dropped = self.sample.swap_remove(i);
+            self.sample_bytes -= sampled_size(&dropped);
+            self.capacity = self.sample.len();
+        }
+    }
+
+    /// Rows sampled so far.
+    pub fn sampled(&self) -> usize {
+        self.sample.len()
+    }
+
+    /// Statistics of the rows pushed, and the grant, now free.
+    pub fn finish(mut self) -> (TableStatistics, Reservation) {
+        let rows = self.rows;
+        let mut total_bytes = 0;
+        // Dropping the sketches makes room for the sampled values of a column
+        // to be sorted.
+        let estimates: Vec<f64> = self
+            .columns
+            .iter_mut()
+            .map(|column| mem::take(&mut column.sketch).estimate())
+            .collect();
+        let mut columns = Vec::with_capacity(self.columns.len());
+        for (i, column) in self.columns.iter().enumerate() {
+            let mut values: Vec<Value> = self
+                .sample
+                .iter_mut()
+                .map(|row| mem::replace(&mut row[i], Value::Null))
+                .filter(|v| !v.is_null())
+                .collect();
+            values.sort_unstable();
+            let non_null = rows - column.nulls;
+            let distinct = (estimates[i].round() as u64).clamp(non_null.min(1), non_null);
+            total_bytes += column.bytes;
+            columns.push(ColumnStatistics {
+                null_fraction: fraction(column.nulls, rows),
+                distinct,
+                width: fraction(column.bytes, rows),
+                histogram: Histogram::new(&values, HISTOGRAM_BUCKETS),
+            });
+        }
+        let statistics = TableStatistics {
+            rows,
+            row_size: fraction(total_bytes, rows).round() as usize,
+            columns,
+        };
+        (statistics, self.grant)
+    }
+}
+
+fn fraction(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 / whole as f64,
+    }
+}
+
+/// Bytes of a value as stored.
+fn width(value: &Value) -> usize {
+    match value {
+        Value::Null => 0,
+        Value::Integer(_) => 8,
+        Value::Text(s) => s.len(),
+        Value::Boolean(_) => 1,
+    }
+}
+
+/// Bytes a copy of `row` takes in the sample.
+fn sampled_size(row: &[Value]) -> usize {
+    let text: usize = row
+        .iter()
+        .map(|v| match v {
+            Value::Text(s) => s.len(),
+            _ => 0,
+        })
+        .sum();
+    ROW_OVERHEAD + mem::size_of_val(row) + text
+}
+
+/// HyperLogLog sketch of the number of distinct hashes inserted.
+///
+/// Each hash picks a register with its top `precision` bits, which keeps the
+/// longest run of leading zeros seen in the remaining bits; the relative
+/// error is about 1.04 / sqrt(2^precision).
+#[derive(Default)]
+struct HyperLogLog {
+    precision: u32,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new(precision: u32) -> Self {
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() + 1).min(64 - self.precision + 1) as u8;
+        let register = &mut self.registers[index];
+        *register = (*register).max(rank);
+    }
+
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| (-(r as f64)).exp2()).sum();
+        let raw = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        // Small counts leave registers empty; linear counting is then more
+        // accurate.
+        if raw <= 2.5 * m && empty > 0 {
+            return m * (m / empty as f64).ln();
+        }
+        raw
+    }
+}
+
+/// SplitMix64 generator; seeded the same every time so that analyzing the
+/// same rows gives the same statistics.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+}
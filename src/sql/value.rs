@@ -0,0 +1,76 @@
+//! Values and types of SQL columns.
+
+use std::fmt;
+
+/// Type of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataType {
+    /// 64-bit signed integer.
+    Integer,
+    /// UTF-8 string.
+    Text,
+    Boolean,
+}
+
+impl DataType {
+    /// Parses the name of a type as written in `CREATE TABLE`.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "integer" | "int" | "bigint" => Some(DataType::Integer),
+            "text" | "varchar" | "string" => Some(DataType::Text),
+            "boolean" | "bool" => Some(DataType::Boolean),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DataType::Integer => "INTEGER",
+            DataType::Text => "TEXT",
+            DataType::Boolean => "BOOLEAN",
+        })
+    }
+}
+
+/// Value of a column in a row.
+///
+/// Values are ordered with `NULL` first; values of different types, which a
+/// column never mixes, are ordered by type.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Text(String),
+    Boolean(bool),
+}
+
+impl Value {
+    /// Type of the value; `None` for `NULL`, which belongs to every type.
+    pub fn data_type(&self) -> Option<DataType> {
+        match self {
+            Value::Null => None,
+            Value::Integer(_) => Some(DataType::Integer),
+            Value::Text(_) => Some(DataType::Text),
+            Value::Boolean(_) => Some(DataType::Boolean),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+}
+
+/// Writes the value as a SQL literal.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("NULL"),
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Text(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Value::Boolean(true) => f.write_str("TRUE"),
+            Value::Boolean(false) => f.write_str("FALSE"),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Files opened with `O_DIRECT`, bypassing the kernel page cache.
+
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::RwLock;
+
+use super::{StdVfs, Vfs, VfsFile};
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+const O_DIRECT: i32 = 0o200_000;
+#[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
+const O_DIRECT: i32 = 0o400_000;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const O_DIRECT: i32 = 0o100_000;
+#[cfg(target_arch = "sparc64")]
+const O_DIRECT: i32 = 0x100_000;
+#[cfg(not(any(
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc64"
+)))]
+const O_DIRECT: i32 = 0o40_000;
+
+/// Alignment of buffers, offsets and lengths passed to the kernel. Covers the
+/// logical block size of every common device.
+const BLOCK: usize = 4096;
+
+/// Largest bounce buffer an operation uses; longer transfers are split.
+const CHUNK: usize = 64 * BLOCK;
+
+/// [`Vfs`] opening files with `O_DIRECT`, so that their data is not cached a
+/// second time by the kernel.
+///
+/// Accesses need not be aligned: they go through a bounce buffer of at most
+/// 256 KiB, and writes that cover a block partially read it back first.
+/// Directory operations are those of [`StdVfs`]. The file system must support
+/// `O_DIRECT`; tmpfs, for one, only does since Linux 6.6.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectVfs;
+
+impl Vfs for DirectVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .custom_flags(O_DIRECT)
+            .open(path)?;
+        Ok(Box::new(DirectFile {
+            file,
+            lock: RwLock::new(()),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        StdVfs.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdVfs.rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        StdVfs.create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        StdVfs.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        StdVfs.sync_dir(dir)
+    }
+}
+
+/// Zeroed heap buffer aligned to [`BLOCK`].
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, BLOCK).unwrap();
+        // SAFETY: `len` is a non-zero multiple of `BLOCK`.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuf { ptr, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the allocation holds `len` initialized bytes.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: the allocation holds `len` initialized bytes, borrowed
+        // uniquely through `self`.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.len, BLOCK).unwrap();
+        // SAFETY: allocated in `new` with this layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) }
+    }
+}
+
+fn align_down(offset: u64) -> u64 {
+    offset / BLOCK as u64 * BLOCK as u64
+}
+
+fn align_up(offset: u64) -> u64 {
+    offset.div_ceil(BLOCK as u64) * BLOCK as u64
+}
+
+struct DirectFile {
+    file: File,
+    /// Writes extend the file to a block boundary before trimming it back, so
+    /// they exclude every other access.
+    lock: RwLock<()>,
+}
+
+impl DirectFile {
+    /// Reads the blocks at `offset` into `buf`, returning the bytes read
+    /// before the end of the file.
+    fn read_blocks(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self
+                .file
+                .read_at(&mut buf[filled..], offset + filled as u64)?
+            {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl VfsFile for DirectFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let _guard = self.lock.read().unwrap();
+        let size = self.file.metadata()?.len();
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let end = size.min(offset + buf.len() as u64);
+        let start = align_down(offset);
+        let span = (align_up(end) - start).min(CHUNK as u64) as usize;
+        let mut bounce = AlignedBuf::new(span);
+        let read = self.read_blocks(bounce.as_mut_slice(), start)?;
+        let end = end.min(start + read as u64);
+        if end <= offset {
+            return Ok(0);
+        }
+        let n = (end - offset) as usize;
+        let from = (offset - start) as usize;
+        buf[..n].copy_from_slice(&bounce.as_slice()[from..from + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.lock.write().unwrap();
+        let size = self.file.metadata()?.len();
+        let write_end = offset + buf.len() as u64;
+        let span = (align_up(write_end) - align_down(offset)).min(CHUNK as u64);
+        let mut bounce = AlignedBuf::new(span as usize);
+        let mut at = offset;
+        while at < write_end {
+            let start = align_down(at);
+            let end = align_up(write_end).min(start + CHUNK as u64);
+            let block = bounce.as_mut_slice();
+            let block = &mut block[..(end - start) as usize];
+            block.fill(0);
+            // Keep the bytes around the write in blocks it covers partially.
+            if at > start && start < size {
+                self.read_blocks(&mut block[..BLOCK], start)?;
+            }
+            let tail = end - BLOCK as u64;
+            if write_end < end && tail < size && (tail > start || at == start) {
+                let from = (tail - start) as usize;
+                self.read_blocks(&mut block[from..], tail)?;
+            }
+            let n = (end.min(write_end) - at) as usize;
+            let from = (at - start) as usize;
+            let taken = (at - offset) as usize;
+            block[from..from + n].copy_from_slice(&buf[taken..taken + n]);
+            self.file.write_all_at(block, start)?;
+            at += n as u64;
+        }
+        // The last block was written whole; trim what lies past the data.
+        self.file.set_len(size.max(write_end))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let _guard = self.lock.read().unwrap();
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let _guard = self.lock.write().unwrap();
+        self.file.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
@@ -0,0 +1,396 @@
+//! In-memory file system that loses, tears and reorders unsynced writes.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{Vfs, VfsFile};
+
+/// Granularity at which a write can be torn, unless it is shorter.
+const SECTOR: usize = 512;
+
+/// In-memory [`Vfs`] simulating power failures, for testing recovery.
+///
+/// Until a file is synced its writes are only pending. [`crash`](Self::crash)
+/// keeps a random subset of the pending writes of every file, tears some of
+/// them at a sector boundary and forgets the others, so later writes can
+/// survive earlier ones exactly as if the disk had reordered them. Renames,
+/// creations and removals not yet made durable by [`Vfs::sync_dir`] survive
+/// up to a random point. Handles opened before the crash fail from then on,
+/// like those of a dead process.
+///
+/// [`fail_after`](Self::fail_after) makes every operation fail once a number
+/// of further writes have been made, so that a crash can be injected in the
+/// middle of whatever the database is doing. All randomness comes from the
+/// seed, so a failing run can be replayed exactly.
+#[derive(Debug, Clone)]
+pub struct FaultyVfs {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+enum Pending {
+    Write { offset: u64, data: Vec<u8> },
+    SetSize(u64),
+}
+
+#[derive(Debug, Default)]
+struct Inode {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+    pending: Vec<Pending>,
+    handles: usize,
+}
+
+#[derive(Debug)]
+enum DirOp {
+    Link(PathBuf, u64),
+    Unlink(PathBuf),
+    Rename(PathBuf, PathBuf),
+}
+
+impl DirOp {
+    fn dir(&self) -> Option<&Path> {
+        match self {
+            DirOp::Link(path, _) | DirOp::Unlink(path) | DirOp::Rename(_, path) => path.parent(),
+        }
+    }
+
+    fn apply(&self, names: &mut BTreeMap<PathBuf, u64>) {
+        match self {
+            DirOp::Link(path, inode) => {
+                names.insert(path.clone(), *inode);
+            }
+            DirOp::Unlink(path) => {
+                names.remove(path);
+            }
+            DirOp::Rename(from, to) => {
+                if let Some(inode) = names.remove(from) {
+                    names.insert(to.clone(), inode);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    rng: u64,
+    generation: u64,
+    next_inode: u64,
+    inodes: HashMap<u64, Inode>,
+    names: BTreeMap<PathBuf, u64>,
+    durable_names: BTreeMap<PathBuf, u64>,
+    pending_dir: Vec<DirOp>,
+    dirs: BTreeSet<PathBuf>,
+    writes_left: Option<u64>,
+}
+
+impl State {
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_random() % n.max(1)
+    }
+
+    /// Fails if a crash has been injected; counts one more write otherwise.
+    fn charge_write(&mut self) -> io::Result<()> {
+        match &mut self.writes_left {
+            Some(0) => Err(crashed()),
+            Some(n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Forgets the files no name, pending link or open handle refers to.
+    fn collect_garbage(&mut self) {
+        let mut live: BTreeSet<u64> = self.names.values().copied().collect();
+        live.extend(self.durable_names.values().copied());
+        live.extend(self.pending_dir.iter().filter_map(|op| match op {
+            DirOp::Link(_, inode) => Some(*inode),
+            _ => None,
+        }));
+        self.inodes
+            .retain(|id, inode| inode.handles > 0 || live.contains(id));
+    }
+
+    fn check_alive(&self) -> io::Result<()> {
+        match self.writes_left {
+            Some(0) => Err(crashed()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("simulated crash")
+}
+
+fn apply(data: &mut Vec<u8>, pending: &Pending) {
+    match pending {
+        Pending::Write {
+            offset,
+            data: bytes,
+        } => {
+            let offset = *offset as usize;
+            if data.len() < offset + bytes.len() {
+                data.resize(offset + bytes.len(), 0);
+            }
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        Pending::SetSize(size) => data.resize(*size as usize, 0),
+    }
+}
+
+impl FaultyVfs {
+    /// Creates an empty file system whose faults are drawn from `seed`.
+    pub fn new(seed: u64) -> Self {
+        FaultyVfs {
+            state: Arc::new(Mutex::new(State {
+                rng: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+                generation: 0,
+                next_inode: 1,
+                inodes: HashMap::new(),
+                names: BTreeMap::new(),
+                durable_names: BTreeMap::new(),
+                pending_dir: Vec::new(),
+                dirs: BTreeSet::new(),
+                writes_left: None,
+            })),
+        }
+    }
+
+    /// Makes every operation fail once `writes` more writes, size changes,
+    /// syncs or directory changes have been made.
+    pub fn fail_after(&self, writes: u64) {
+        self.lock().writes_left = Some(writes);
+    }
+
+    /// Simulates a power failure, leaving only what had reached the disk.
+    pub fn crash(&self) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let keep = state.below(state.pending_dir.len() as u64 + 1) as usize;
+        for op in state.pending_dir.drain(..).take(keep) {
+            op.apply(&mut state.durable_names);
+        }
+        state.names = state.durable_names.clone();
+
+        let mut ids: Vec<u64> = state.inodes.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let mut inode = state.inodes.remove(&id).unwrap();
+            for pending in std::mem::take(&mut inode.pending) {
+                if state.below(2) == 0 {
+                    continue;
+                }
+                let pending = match pending {
+                    Pending::Write { offset, mut data } if state.below(4) == 0 => {
+                        let cut = state.below(data.len() as u64) as usize;
+                        let cut = if data.len() > SECTOR {
+                            cut / SECTOR * SECTOR
+                        } else {
+                            cut
+                        };
+                        data.truncate(cut);
+                        Pending::Write { offset, data }
+                    }
+                    pending => pending,
+                };
+                apply(&mut inode.durable, &pending);
+            }
+            inode.data = inode.durable.clone();
+            inode.handles = 0;
+            state.inodes.insert(id, inode);
+        }
+        state.generation += 1;
+        state.collect_garbage();
+        state.writes_left = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+struct FaultyFile {
+    state: Arc<Mutex<State>>,
+    inode: u64,
+    generation: u64,
+}
+
+impl FaultyFile {
+    fn lock(&self) -> io::Result<MutexGuard<'_, State>> {
+        let state = self.state.lock().unwrap();
+        if state.generation != self.generation {
+            return Err(io::Error::other("file opened before a crash"));
+        }
+        state.check_alive()?;
+        Ok(state)
+    }
+}
+
+impl Drop for FaultyFile {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == self.generation {
+            state.inodes.get_mut(&self.inode).unwrap().handles -= 1;
+            state.collect_garbage();
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl Vfs for FaultyVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.lock();
+        state.check_alive()?;
+        let inode = match state.names.get(path) {
+            Some(&inode) => inode,
+            None if create => {
+                state.charge_write()?;
+                let inode = state.next_inode;
+                state.next_inode += 1;
+                state.inodes.insert(inode, Inode::default());
+                state.names.insert(path.to_path_buf(), inode);
+                state
+                    .pending_dir
+                    .push(DirOp::Link(path.to_path_buf(), inode));
+                inode
+            }
+            None => return Err(not_found(path)),
+        };
+        state.inodes.get_mut(&inode).unwrap().handles += 1;
+        Ok(Box::new(FaultyFile {
+            state: Arc::clone(&self.state),
+            inode,
+            generation: state.generation,
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.charge_write()?;
+        if state.names.remove(path).is_none() {
+            return Err(not_found(path));
+        }
+        state.pending_dir.push(DirOp::Unlink(path.to_path_buf()));
+        state.collect_garbage();
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.charge_write()?;
+        let inode = state.names.remove(from).ok_or_else(|| not_found(from))?;
+        state.names.insert(to.to_path_buf(), inode);
+        state
+            .pending_dir
+            .push(DirOp::Rename(from.to_path_buf(), to.to_path_buf()));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check_alive()?;
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let state = self.lock();
+        state.check_alive()?;
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .names
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.charge_write()?;
+        let state = &mut *state;
+        let (done, rest) = state
+            .pending_dir
+            .drain(..)
+            .partition(|op| op.dir() == Some(dir));
+        state.pending_dir = rest;
+        for op in done {
+            op.apply(&mut state.durable_names);
+        }
+        state.collect_garbage();
+        Ok(())
+    }
+}
+
+impl VfsFile for FaultyFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let state = self.lock()?;
+        let data = &state.inodes[&self.inode].data;
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.charge_write()?;
+        let inode = state.inodes.get_mut(&self.inode).unwrap();
+        let pending = Pending::Write {
+            offset,
+            data: buf.to_vec(),
+        };
+        apply(&mut inode.data, &pending);
+        inode.pending.push(pending);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let state = self.lock()?;
+        Ok(state.inodes[&self.inode].data.len() as u64)
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.charge_write()?;
+        let inode = state.inodes.get_mut(&self.inode).unwrap();
+        let pending = Pending::SetSize(size);
+        apply(&mut inode.data, &pending);
+        inode.pending.push(pending);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut state = self.lock()?;
+        state.charge_write()?;
+        let inode = state.inodes.get_mut(&self.inode).unwrap();
+        inode.durable = inode.data.clone();
+        inode.pending.clear();
+        Ok(())
+    }
+}
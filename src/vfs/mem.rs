@@ -0,0 +1,157 @@
+//! Files held in memory.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use super::{Vfs, VfsFile};
+
+type Data = Arc<RwLock<Vec<u8>>>;
+
+/// [`Vfs`] keeping every file in memory, for tests.
+///
+/// Clones share the same files, so a database can be closed and reopened on
+/// them. Nothing is lost short of dropping the last clone: syncs do nothing.
+/// The files are not charged to any [`MemoryBudget`](crate::MemoryBudget).
+#[derive(Clone, Default)]
+pub struct MemVfs {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    files: BTreeMap<PathBuf, Data>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemVfs {
+    /// Creates an empty file system.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl fmt::Debug for MemVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("MemVfs")
+            .field("files", &state.files.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl State {
+    fn check_dir(&self, dir: Option<&Path>) -> io::Result<()> {
+        match dir {
+            Some(dir) if !dir.as_os_str().is_empty() && !self.dirs.contains(dir) => {
+                Err(not_found(dir))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Vfs for MemVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.lock();
+        let data = match state.files.get(path) {
+            Some(data) => Arc::clone(data),
+            None if create => {
+                state.check_dir(path.parent())?;
+                let data = Data::default();
+                state.files.insert(path.to_path_buf(), Arc::clone(&data));
+                data
+            }
+            None => return Err(not_found(path)),
+        };
+        Ok(Box::new(MemFile(data)))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        // Open handles keep the data, as on Unix.
+        match self.lock().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check_dir(to.parent())?;
+        let data = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let state = self.lock();
+        state.check_dir(Some(dir))?;
+        Ok(state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.lock().check_dir(Some(dir))
+    }
+}
+
+struct MemFile(Data);
+
+impl VfsFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.0.read().unwrap();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.0.write().unwrap();
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.0.write().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
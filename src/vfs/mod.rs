@@ -0,0 +1,168 @@
+//! File system abstraction.
+//!
+//! Every file the database touches is opened through a [`Vfs`], chosen with
+//! [`Options::vfs`](crate::Options::vfs). Files are accessed positionally and
+//! nothing is durable until [`VfsFile::sync`] returns; creations, renames and
+//! removals are durable once the directory holding them has been synced with
+//! [`Vfs::sync_dir`].
+//!
+//! [`StdVfs`] goes through the kernel page cache, which keeps its own copy of
+//! whatever the database reads or writes: memory the [`MemoryBudget`] never
+//! sees. [`DirectVfs`] opens files with `O_DIRECT` instead, so the buffer pool
+//! and block cache are the only caches and the budget bounds the memory the
+//! database really uses. [`MemVfs`] and [`FaultyVfs`] keep files in memory for
+//! tests, the latter losing unsynced writes on a simulated crash.
+//!
+//! [`MemoryBudget`]: crate::MemoryBudget
+
+#[cfg(target_os = "linux")]
+mod direct;
+mod faulty;
+mod mem;
+
+#[cfg(target_os = "linux")]
+pub use direct::DirectVfs;
+pub use faulty::FaultyVfs;
+pub use mem::MemVfs;
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// File system the database stores its files in.
+pub trait Vfs: Send + Sync + fmt::Debug {
+    /// Opens the file at `path` for reading and writing, creating it empty if
+    /// it does not exist and `create` is set.
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Removes the file at `path`.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Atomically replaces `to` with the file at `from`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Creates directory `path` and its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Names of the files in directory `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+
+    /// Makes creations, renames and removals inside `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Reads the whole file at `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path, false)?;
+        let mut data = vec![0; file.size()? as usize];
+        file.read_exact_at(&mut data, 0)?;
+        Ok(data)
+    }
+
+    /// Replaces the contents of the file at `path` with `data` and syncs it.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let file = self.open(path, true)?;
+        file.set_size(0)?;
+        file.write_at(data, 0)?;
+        file.sync()
+    }
+}
+
+/// File opened through a [`Vfs`].
+pub trait VfsFile: Send + Sync {
+    /// Reads into `buf` from `offset`, returning the number of bytes read;
+    /// `0` means `offset` is at or past the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Writes all of `buf` at `offset`, extending the file if needed.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Current length of the file in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Truncates or zero-extends the file to `size` bytes.
+    fn set_size(&self, size: u64) -> io::Result<()>;
+
+    /// Makes every completed write durable.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Fills `buf` from `offset`, failing if the file ends first.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_at(&mut buf[filled..], offset + filled as u64)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// [`Vfs`] backed by the operating system's file system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdVfs;
+
+struct StdFile(File);
+
+impl Vfs for StdVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(StdFile(file)))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+impl VfsFile for StdFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.0.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.0.write_all_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.0.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+}
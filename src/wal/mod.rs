@@ -0,0 +1,522 @@
+//! Write-ahead log.
+//!
+//! Records are framed as `crc:u32 len:u32 payload`, the checksum covering the
+//! length and the payload, and appended to segment files named after the log
+//! position they start at. A segment is closed once it grows past
+//! [`Options::wal_segment_size`] and deleted once the engine no longer needs
+//! its records.
+//!
+//! Appended records go to an in-memory buffer whose size is reserved from the
+//! memory budget up front. When it is full, writers block until it has been
+//! written out instead of growing it. Commits waiting for the same flush are
+//! grouped: one thread writes and syncs everything buffered so far on behalf
+//! of all of them.
+//!
+//! Log positions ([`Lsn`]) are byte offsets in the logical stream of records.
+//! A record is identified by the position just past its end, so a record is
+//! durable once the log has been synced up to its LSN.
+
+mod record;
+
+pub(crate) use record::{LogRecord, PageChange};
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::checksum::{crc32c, extend};
+use crate::error::{Error, Result};
+use crate::memory::{MemoryBudget, Reservation};
+use crate::options::Options;
+use crate::vfs::{Vfs, VfsFile};
+
+/// Position in the log.
+pub type Lsn = u64;
+
+/// Bytes of framing in front of every record.
+const FRAME_HEADER: usize = 8;
+
+/// Largest payload a single record may carry.
+const MAX_RECORD: usize = u32::MAX as usize;
+
+/// How durable a write must be before the call making it returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// The log has been synced to stable storage.
+    #[default]
+    Sync,
+    /// The log has been handed to the operating system: the write survives a
+    /// crash of the process but not of the machine.
+    Async,
+    /// The write is only buffered in memory and reaches the log with the next
+    /// flush; a crash of the process may lose it.
+    None,
+}
+
+struct Segment {
+    file: Box<dyn VfsFile>,
+    start: Lsn,
+    len: u64,
+}
+
+struct State {
+    buffer: Vec<u8>,
+    spare: Vec<u8>,
+    capacity: usize,
+    /// Position just past the last appended record.
+    next_lsn: Lsn,
+    /// Everything before this position has been written to the segment.
+    written_lsn: Lsn,
+    /// Everything before this position has been synced.
+    synced_lsn: Lsn,
+    /// Highest position a committer asked to be synced.
+    sync_wanted: Lsn,
+    /// Whether a thread is currently writing on behalf of the others.
+    flushing: bool,
+    /// Taken by the flushing thread while it performs I/O.
+    segment: Option<Segment>,
+    /// Start positions of the segments on disk, oldest first.
+    segments: Vec<Lsn>,
+    failed: bool,
+    _reservation: Reservation,
+}
+
+/// Append-only, segmented, group-committed log.
+pub struct Wal {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    segment_size: u64,
+    budget: MemoryBudget,
+    state: Mutex<State>,
+    flushed: Condvar,
+}
+
+fn segment_path(dir: &Path, start: Lsn) -> PathBuf {
+    dir.join(format!("{start:020}.log"))
+}
+
+fn list_segments(vfs: &dyn Vfs, dir: &Path) -> Result<Vec<Lsn>> {
+    let mut segments: Vec<Lsn> = vfs
+        .list(dir)?
+        .iter()
+        .filter_map(|name| name.strip_suffix(".log")?.parse().ok())
+        .collect();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+impl Wal {
+    /// Opens the log in `dir`, discarding a torn record at its tail.
+    ///
+    /// `min_lsn` is the highest position the caller knows to be covered by
+    /// its own durable state; if the log ends before it, new records start
+    /// there so positions never go backwards.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        options: &Options,
+        budget: &MemoryBudget,
+        min_lsn: Lsn,
+    ) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let vfs = Arc::clone(&options.vfs);
+        vfs.create_dir_all(&dir)?;
+        let capacity = options.wal_buffer_bytes();
+        let reservation = budget.reserve(2 * capacity)?;
+
+        let mut segments = list_segments(&*vfs, &dir)?;
+        let mut next_lsn = min_lsn;
+        let mut segment = None;
+        if let Some(&start) = segments.last() {
+            let path = segment_path(&dir, start);
+            let file = vfs.open(&path, false)?;
+            let valid = Self::valid_length(&*file, budget)?;
+            let end = start + valid;
+            if end >= min_lsn {
+                file.set_size(valid)?;
+                file.sync()?;
+                segment = Some(Segment {
+                    file,
+                    start,
+                    len: valid,
+                });
+                next_lsn = end;
+            }
+        }
+        let segment = match segment {
+            Some(segment) => segment,
+            None => {
+                let file = vfs.open(&segment_path(&dir, next_lsn), true)?;
+                file.set_size(0)?;
+                vfs.sync_dir(&dir)?;
+                segments.push(next_lsn);
+                Segment {
+                    file,
+                    start: next_lsn,
+                    len: 0,
+                }
+            }
+        };
+
+        Ok(Wal {
+            segment_size: options.wal_segment_size as u64,
+            budget: budget.clone(),
+            state: Mutex::new(State {
+                buffer: Vec::with_capacity(capacity),
+                spare: Vec::with_capacity(capacity),
+                capacity,
+                next_lsn,
+                written_lsn: next_lsn,
+                synced_lsn: next_lsn,
+                sync_wanted: next_lsn,
+                flushing: false,
+                segment: Some(segment),
+                segments,
+                failed: false,
+                _reservation: reservation,
+            }),
+            flushed: Condvar::new(),
+            vfs,
+            dir,
+        })
+    }
+
+    /// Position just past the last appended record.
+    pub fn next_lsn(&self) -> Lsn {
+        self.state.lock().unwrap().next_lsn
+    }
+
+    /// Appends a record and returns its LSN.
+    ///
+    /// The record is only buffered; use [`commit`](Self::commit) to wait for
+    /// it to reach the log. Blocks while the buffer is full.
+    pub fn append(&self, payload: &[u8]) -> Result<Lsn> {
+        if payload.len() > MAX_RECORD {
+            return Err(Error::invalid("log record too large"));
+        }
+        let frame_len = FRAME_HEADER + payload.len();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.failed {
+                return Err(Error::Io(std::io::Error::other(
+                    "write-ahead log failed earlier",
+                )));
+            }
+            if state.buffer.len() + frame_len <= state.capacity {
+                break;
+            }
+            if state.buffer.is_empty() && !state.flushing {
+                // Larger than the whole buffer: write it directly.
+                let _reservation = self.budget.reserve(frame_len)?;
+                let mut frame = Vec::with_capacity(frame_len);
+                encode_frame(&mut frame, payload);
+                state.next_lsn += frame_len as u64;
+                let lsn = state.next_lsn;
+                self.lead(state, Some(&frame), false)?;
+                return Ok(lsn);
+            }
+            state = self.flush_step(state, false)?;
+        }
+        encode_frame(&mut state.buffer, payload);
+        state.next_lsn += frame_len as u64;
+        Ok(state.next_lsn)
+    }
+
+    /// Waits until the record with LSN `lsn` is as durable as `durability`
+    /// requires.
+    pub fn commit(&self, lsn: Lsn, durability: Durability) -> Result<()> {
+        let sync = match durability {
+            Durability::None => return Ok(()),
+            Durability::Async => false,
+            Durability::Sync => true,
+        };
+        let mut state = self.state.lock().unwrap();
+        if sync {
+            state.sync_wanted = state.sync_wanted.max(lsn);
+        }
+        loop {
+            let reached = if sync {
+                state.synced_lsn
+            } else {
+                state.written_lsn
+            };
+            if reached >= lsn {
+                return Ok(());
+            }
+            state = self.flush_step(state, sync)?;
+        }
+    }
+
+    /// Writes and syncs every appended record.
+    pub fn sync(&self) -> Result<()> {
+        let lsn = self.next_lsn();
+        self.commit(lsn, Durability::Sync)
+    }
+
+    /// Deletes the segments holding only records before `lsn`. The segment
+    /// being written to is always kept.
+    pub fn truncate_before(&self, lsn: Lsn) -> Result<()> {
+        let doomed: Vec<Lsn> = {
+            let mut state = self.state.lock().unwrap();
+            let keep_from = state
+                .segments
+                .windows(2)
+                .take_while(|w| w[1] <= lsn)
+                .count();
+            state.segments.drain(..keep_from).collect()
+        };
+        for start in &doomed {
+            self.vfs.remove(&segment_path(&self.dir, *start))?;
+        }
+        if !doomed.is_empty() {
+            self.vfs.sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates over the records whose LSN is greater than `after`.
+    pub fn replay(&self, after: Lsn) -> Result<WalIter> {
+        // Make sure everything appended so far can be read back.
+        self.commit(self.next_lsn(), Durability::Async)?;
+        let segments = self.state.lock().unwrap().segments.clone();
+        // Skip the segments that end before `after`.
+        let skip = segments.windows(2).take_while(|w| w[1] <= after).count();
+        let segments = segments[skip..].to_vec();
+        Ok(WalIter {
+            vfs: Arc::clone(&self.vfs),
+            dir: self.dir.clone(),
+            segments: segments.into_iter(),
+            current: None,
+            after,
+            budget: self.budget.clone(),
+        })
+    }
+
+    /// Either waits for the thread currently flushing, or flushes the buffer
+    /// itself. Returns with the lock held again.
+    fn flush_step<'a>(
+        &'a self,
+        state: MutexGuard<'a, State>,
+        sync: bool,
+    ) -> Result<MutexGuard<'a, State>> {
+        if state.flushing {
+            return Ok(self.flushed.wait(state).unwrap());
+        }
+        self.lead(state, None, sync)?;
+        Ok(self.state.lock().unwrap())
+    }
+
+    /// Writes the buffer, followed by `extra` if given, on behalf of every
+    /// waiting thread, then syncs if anybody asked for it.
+    fn lead(
+        &self,
+        mut state: MutexGuard<'_, State>,
+        extra: Option<&[u8]>,
+        sync: bool,
+    ) -> Result<()> {
+        state.flushing = true;
+        let spare = std::mem::take(&mut state.spare);
+        let data = std::mem::replace(&mut state.buffer, spare);
+        let start = state.written_lsn;
+        let end = state.next_lsn;
+        let need_sync = sync || state.sync_wanted > state.synced_lsn;
+        let mut segment = state
+            .segment
+            .take()
+            .expect("segment present when not flushing");
+        drop(state);
+
+        let result = self.write_out(&mut segment, start, &data, extra, need_sync);
+
+        let mut state = self.state.lock().unwrap();
+        let rotated = segment.start;
+        if state.segments.last() != Some(&rotated) {
+            state.segments.push(rotated);
+        }
+        state.segment = Some(segment);
+        let mut data = data;
+        data.clear();
+        state.spare = data;
+        state.flushing = false;
+        match &result {
+            Ok(()) => {
+                state.written_lsn = end;
+                if need_sync {
+                    state.synced_lsn = end;
+                }
+            }
+            Err(_) => state.failed = true,
+        }
+        drop(state);
+        self.flushed.notify_all();
+        result
+    }
+
+    fn write_out(
+        &self,
+        segment: &mut Segment,
+        start: Lsn,
+        data: &[u8],
+        extra: Option<&[u8]>,
+        sync: bool,
+    ) -> Result<()> {
+        if segment.len >= self.segment_size && segment.start != start {
+            segment.file.sync()?;
+            let file = self.vfs.open(&segment_path(&self.dir, start), true)?;
+            file.set_size(0)?;
+            self.vfs.sync_dir(&self.dir)?;
+            *segment = Segment {
+                file,
+                start,
+                len: 0,
+            };
+        }
+        segment.file.write_at(data, segment.len)?;
+        segment.len += data.len() as u64;
+        if let Some(extra) = extra {
+            segment.file.write_at(extra, segment.len)?;
+            segment.len += extra.len() as u64;
+        }
+        if sync {
+            segment.file.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Length of the prefix of a segment made of intact records.
+    fn valid_length(file: &dyn VfsFile, budget: &MemoryBudget) -> Result<u64> {
+        let mut reader = SegmentReader::new(0, budget)?;
+        while reader.next_record(file)?.is_some() {}
+        Ok(reader.offset)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+fn encode_frame(buf: &mut Vec<u8>, payload: &[u8]) {
+    let len = (payload.len() as u32).to_le_bytes();
+    let crc = extend(crc32c(&len), payload);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(payload);
+}
+
+/// Reads the records of one segment, stopping at the first torn record.
+struct SegmentReader {
+    buf: Vec<u8>,
+    /// `buf[pos..filled]` has been read from the file but not consumed.
+    pos: usize,
+    filled: usize,
+    /// File offset the next read of `buf` starts at.
+    file_pos: u64,
+    start: Lsn,
+    /// Bytes of intact records read so far.
+    offset: u64,
+    budget: MemoryBudget,
+    _reservation: Reservation,
+}
+
+const READ_BUFFER: usize = 32 << 10;
+
+impl SegmentReader {
+    fn new(start: Lsn, budget: &MemoryBudget) -> Result<Self> {
+        let reservation = budget.reserve(READ_BUFFER)?;
+        Ok(SegmentReader {
+            buf: vec![0; READ_BUFFER],
+            pos: 0,
+            filled: 0,
+            file_pos: 0,
+            start,
+            offset: 0,
+            budget: budget.clone(),
+            _reservation: reservation,
+        })
+    }
+
+    /// Next intact record as `(lsn, payload)`, or `None` at the end of the
+    /// segment or at the first damaged record.
+    fn next_record(&mut self, file: &dyn VfsFile) -> Result<Option<(Lsn, Vec<u8>, Reservation)>> {
+        let mut header = [0u8; FRAME_HEADER];
+        if !self.read_full(file, &mut header)? {
+            return Ok(None);
+        }
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let Ok(reservation) = self.budget.reserve(len) else {
+            // A torn length can claim more than the budget; a genuine record
+            // this large could never have been written.
+            return Ok(None);
+        };
+        let mut payload = vec![0u8; len];
+        if !self.read_full(file, &mut payload)? {
+            return Ok(None);
+        }
+        if extend(crc32c(&header[4..]), &payload) != crc {
+            return Ok(None);
+        }
+        self.offset += (FRAME_HEADER + len) as u64;
+        Ok(Some((self.start + self.offset, payload, reservation)))
+    }
+
+    /// Fills `out` completely, or returns `false` if the segment ends first.
+    fn read_full(&mut self, file: &dyn VfsFile, out: &mut [u8]) -> Result<bool> {
+        let mut copied = 0;
+        while copied < out.len() {
+            if self.pos == self.filled {
+                self.pos = 0;
+                self.filled = file.read_at(&mut self.buf, self.file_pos)?;
+                self.file_pos += self.filled as u64;
+                if self.filled == 0 {
+                    return Ok(false);
+                }
+            }
+            let n = (out.len() - copied).min(self.filled - self.pos);
+            out[copied..copied + n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            copied += n;
+        }
+        Ok(true)
+    }
+}
+
+/// Iterator over the records of a [`Wal`], oldest first.
+pub struct WalIter {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    segments: std::vec::IntoIter<Lsn>,
+    current: Option<(Box<dyn VfsFile>, SegmentReader)>,
+    after: Lsn,
+    budget: MemoryBudget,
+}
+
+impl WalIter {
+    fn open_segment(&self, start: Lsn) -> Result<(Box<dyn VfsFile>, SegmentReader)> {
+        let file = self.vfs.open(&segment_path(&self.dir, start), false)?;
+        Ok((file, SegmentReader::new(start, &self.budget)?))
+    }
+}
+
+impl Iterator for WalIter {
+    type Item = Result<(Lsn, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let start = self.segments.next()?;
+                match self.open_segment(start) {
+                    Ok(current) => self.current = Some(current),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let (file, reader) = self.current.as_mut().unwrap();
+            match reader.next_record(&**file) {
+                Ok(Some((lsn, _, _))) if lsn <= self.after => continue,
+                Ok(Some((lsn, payload, _))) => return Some(Ok((lsn, payload))),
+                Ok(None) => self.current = None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
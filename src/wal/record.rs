@@ -0,0 +1,243 @@
+//! Payload of write-ahead log records.
+//!
+//! ```text
+//! batch:        1 count:varint mutation*
+//! update:       2 txn:varint undo:mutation pages
+//! compensation: 3 txn:varint undo_next:varint pages
+//! commit:       4 txn:varint
+//! abort:        5 txn:varint
+//! pages:        6 pages
+//!
+//! mutation: op:u8 key:bytes [value:bytes | operand:bytes]
+//! pages:    count:varint (7 page:varint body:bytes | 8 page:varint offset:varint bytes:bytes)*
+//! ```
+
+use crate::buffer::PageId;
+use crate::coding::{put_bytes, put_varint, Reader};
+use crate::engine::Mutation;
+use crate::error::{Error, Result};
+
+use super::Lsn;
+
+const KIND_BATCH: u8 = 1;
+const KIND_UPDATE: u8 = 2;
+const KIND_COMPENSATION: u8 = 3;
+const KIND_COMMIT: u8 = 4;
+const KIND_ABORT: u8 = 5;
+const KIND_PAGES: u8 = 6;
+const KIND_IMAGE: u8 = 7;
+const KIND_DELTA: u8 = 8;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const OP_MERGE: u8 = 3;
+
+/// Physical change to one page, redone by copying bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PageChange {
+    /// The whole body of the page.
+    Image { page: PageId, body: Vec<u8> },
+    /// Bytes of the body starting at `offset`.
+    Delta {
+        page: PageId,
+        offset: usize,
+        bytes: Vec<u8>,
+    },
+}
+
+/// Change described by one log record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LogRecord {
+    /// Mutations applied atomically.
+    Batch(Vec<Mutation>),
+    /// One mutation of transaction `txn`, with the page changes redoing it
+    /// and the mutation undoing it.
+    Update {
+        txn: u64,
+        undo: Mutation,
+        pages: Vec<PageChange>,
+    },
+    /// Redo-only record of an undo step of `txn`; `undo_next` is the LSN of
+    /// the next update of `txn` left to undo, `0` if none.
+    Compensation {
+        txn: u64,
+        undo_next: Lsn,
+        pages: Vec<PageChange>,
+    },
+    /// Transaction `txn` completed; its updates must survive.
+    Commit { txn: u64 },
+    /// Transaction `txn` has been rolled back entirely.
+    Abort { txn: u64 },
+    /// Page changes belonging to no transaction.
+    Pages(Vec<PageChange>),
+}
+
+fn put_mutation(buf: &mut Vec<u8>, mutation: &Mutation) {
+    match mutation {
+        Mutation::Put { key, value } => {
+            buf.push(OP_PUT);
+            put_bytes(buf, key);
+            put_bytes(buf, value);
+        }
+        Mutation::Delete { key } => {
+            buf.push(OP_DELETE);
+            put_bytes(buf, key);
+        }
+        Mutation::Merge {
+            key,
+            operator,
+            operand,
+        } => {
+            buf.push(OP_MERGE);
+            put_bytes(buf, key);
+            put_bytes(buf, operator.as_bytes());
+            put_bytes(buf, operand);
+        }
+    }
+}
+
+fn put_pages(buf: &mut Vec<u8>, pages: &[PageChange]) {
+    put_varint(buf, pages.len() as u64);
+    for change in pages {
+        match change {
+            PageChange::Image { page, body } => {
+                buf.push(KIND_IMAGE);
+                put_varint(buf, *page);
+                put_bytes(buf, body);
+            }
+            PageChange::Delta {
+                page,
+                offset,
+                bytes,
+            } => {
+                buf.push(KIND_DELTA);
+                put_varint(buf, *page);
+                put_varint(buf, *offset as u64);
+                put_bytes(buf, bytes);
+            }
+        }
+    }
+}
+
+fn mutation(reader: &mut Reader<'_>) -> Result<Mutation> {
+    let op = reader.u8()?;
+    let key = reader.bytes()?.to_vec();
+    match op {
+        OP_PUT => Ok(Mutation::Put {
+            key,
+            value: reader.bytes()?.to_vec(),
+        }),
+        OP_DELETE => Ok(Mutation::Delete { key }),
+        OP_MERGE => Ok(Mutation::Merge {
+            key,
+            operator: String::from_utf8(reader.bytes()?.to_vec())
+                .map_err(|_| Error::corruption("merge operator name is not UTF-8"))?,
+            operand: reader.bytes()?.to_vec(),
+        }),
+        _ => Err(Error::corruption(format!("unknown log operation {op}"))),
+    }
+}
+
+fn pages(reader: &mut Reader<'_>) -> Result<Vec<PageChange>> {
+    let count = reader.varint()? as usize;
+    let mut pages = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let kind = reader.u8()?;
+        let page = reader.varint()?;
+        pages.push(match kind {
+            KIND_IMAGE => PageChange::Image {
+                page,
+                body: reader.bytes()?.to_vec(),
+            },
+            KIND_DELTA => PageChange::Delta {
+                page,
+                offset: reader.varint()? as usize,
+                bytes: reader.bytes()?.to_vec(),
+            },
+            _ => return Err(Error::corruption(format!("unknown page change {kind}"))),
+        });
+    }
+    Ok(pages)
+}
+
+impl LogRecord {
+    /// Encodes a batch without copying the mutations.
+    pub(crate) fn encode_batch(mutations: &[Mutation]) -> Vec<u8> {
+        let size: usize = mutations.iter().map(|m| m.size() + 12).sum();
+        let mut buf = Vec::with_capacity(size + 10);
+        buf.push(KIND_BATCH);
+        put_varint(&mut buf, mutations.len() as u64);
+        for mutation in mutations {
+            put_mutation(&mut buf, mutation);
+        }
+        buf
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            LogRecord::Batch(mutations) => return Self::encode_batch(mutations),
+            LogRecord::Update { txn, undo, pages } => {
+                buf.push(KIND_UPDATE);
+                put_varint(&mut buf, *txn);
+                put_mutation(&mut buf, undo);
+                put_pages(&mut buf, pages);
+            }
+            LogRecord::Compensation {
+                txn,
+                undo_next,
+                pages,
+            } => {
+                buf.push(KIND_COMPENSATION);
+                put_varint(&mut buf, *txn);
+                put_varint(&mut buf, *undo_next);
+                put_pages(&mut buf, pages);
+            }
+            LogRecord::Commit { txn } => {
+                buf.push(KIND_COMMIT);
+                put_varint(&mut buf, *txn);
+            }
+            LogRecord::Abort { txn } => {
+                buf.push(KIND_ABORT);
+                put_varint(&mut buf, *txn);
+            }
+            LogRecord::Pages(pages) => {
+                buf.push(KIND_PAGES);
+                put_pages(&mut buf, pages);
+            }
+        }
+        buf
+    }
+
+    pub(crate) fn decode(payload: &[u8]) -> Result<LogRecord> {
+        let mut reader = Reader::new(payload);
+        match reader.u8()? {
+            KIND_BATCH => {
+                let count = reader.varint()? as usize;
+                let mut mutations = Vec::with_capacity(count.min(payload.len()));
+                for _ in 0..count {
+                    mutations.push(mutation(&mut reader)?);
+                }
+                Ok(LogRecord::Batch(mutations))
+            }
+            KIND_UPDATE => Ok(LogRecord::Update {
+                txn: reader.varint()?,
+                undo: mutation(&mut reader)?,
+                pages: pages(&mut reader)?,
+            }),
+            KIND_COMPENSATION => Ok(LogRecord::Compensation {
+                txn: reader.varint()?,
+                undo_next: reader.varint()?,
+                pages: pages(&mut reader)?,
+            }),
+            KIND_COMMIT => Ok(LogRecord::Commit {
+                txn: reader.varint()?,
+            }),
+            KIND_ABORT => Ok(LogRecord::Abort {
+                txn: reader.varint()?,
+            }),
+            KIND_PAGES => Ok(LogRecord::Pages(pages(&mut reader)?)),
+            kind => Err(Error::corruption(format!("unknown log record kind {kind}"))),
+        }
+    }
+}
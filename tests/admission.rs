@@ -0,0 +1,121 @@
+//! Admission of queries against query memory: timeouts and the order queued
+//! queries are admitted in.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use digestive_database::sql::{Priority, QueryMemory};
+use digestive_database::{Error, Options};
+
+const QUERY_MEMORY: usize = 64 << 10;
+
+fn memory(max_concurrent_queries: Option<usize>, query_timeout: Option<Duration>) -> QueryMemory {
+    let options = Options {
+        query_memory_size: Some(QUERY_MEMORY),
+        max_concurrent_queries,
+        query_timeout,
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    QueryMemory::new(&options, &budget)
+}
+
+/// Waits until `n` queries in all have had to queue.
+fn wait_queued(memory: &QueryMemory, n: u64) {
+    while memory.stats().queued < n {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn queries_needing_more_than_query_memory_are_rejected() {
+    let memory = memory(None, None);
+    let result = memory.admit(QUERY_MEMORY + 1, QUERY_MEMORY + 1, Priority::Normal);
+    assert!(matches!(result, Err(Error::InvalidArgument(_))));
+    assert_eq!(memory.stats().rejected, 1);
+}
+
+#[test]
+fn grants_shrink_to_what_is_free() {
+    let memory = memory(None, None);
+    let first = memory.admit(1 << 10, 48 << 10, Priority::Normal).unwrap();
+    assert_eq!(first.size(), 48 << 10);
+    let second = memory.admit(1 << 10, 48 << 10, Priority::Normal).unwrap();
+    assert_eq!(second.size(), 16 << 10);
+    assert_eq!(memory.stats().granted, QUERY_MEMORY);
+}
+
+#[test]
+fn queued_queries_time_out() {
+    let timeout = Duration::from_millis(50);
+    let memory = memory(Some(1), Some(timeout));
+    let running = memory.admit(1 << 10, 1 << 10, Priority::Normal).unwrap();
+    match memory.admit(1 << 10, 1 << 10, Priority::High) {
+        Err(Error::QueueTimeout { waited }) => assert!(waited >= timeout),
+        other => panic!(
+            "expected a timeout, got {:?}",
+            other.map(|grant| grant.size())
+        ),
+    }
+    let stats = memory.stats();
+    assert_eq!((stats.queued, stats.timed_out, stats.running), (1, 1, 1));
+    drop(running);
+    memory.admit(1 << 10, 1 << 10, Priority::Normal).unwrap();
+}
+
+#[test]
+fn queued_queries_are_admitted_by_priority_then_arrival() {
+    let memory = memory(Some(1), None);
+    let running = memory.admit(1 << 10, 1 << 10, Priority::Normal).unwrap();
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let queries = [
+        ("low", Priority::Low),
+        ("normal 1", Priority::Normal),
+        ("high", Priority::High),
+        ("normal 2", Priority::Normal),
+    ];
+    let mut threads = Vec::new();
+    for (i, (name, priority)) in queries.into_iter().enumerate() {
+        let (queue, order) = (memory.clone(), Arc::clone(&order));
+        threads.push(thread::spawn(move || {
+            let grant = queue.admit(1 << 10, 1 << 10, priority).unwrap();
+            order.lock().unwrap().push(name);
+            drop(grant);
+        }));
+        wait_queued(&memory, i as u64 + 1);
+    }
+    drop(running);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let order = order.lock().unwrap();
+    assert_eq!(*order, ["high", "normal 1", "normal 2", "low"]);
+}
+
+#[test]
+fn small_queries_do_not_overtake_a_queued_large_one() {
+    let memory = memory(None, None);
+    let running = memory.admit(48 << 10, 48 << 10, Priority::Normal).unwrap();
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut threads = Vec::new();
+    for (i, (name, size)) in [("large", 32 << 10), ("small", 1 << 10)]
+        .into_iter()
+        .enumerate()
+    {
+        let (queue, order) = (memory.clone(), Arc::clone(&order));
+        threads.push(thread::spawn(move || {
+            let grant = queue.admit(size, size, Priority::Normal).unwrap();
+            order.lock().unwrap().push(name);
+            grant
+        }));
+        wait_queued(&memory, i as u64 + 1);
+    }
+    // A kilobyte is free, but the large query is ahead of the small one.
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(memory.stats().running, 1);
+    drop(running);
+    let grants: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    assert_eq!(grants.iter().map(|g| g.size()).sum::<usize>(), 33 << 10);
+    assert_eq!(*order.lock().unwrap(), ["large", "small"]);
+}
@@ -0,0 +1,142 @@
+//! Hash aggregation correctness under small memory grants.
+//!
+//! Every aggregation is checked against one into a `BTreeMap`. The spill
+//! directory is on a file system that counts the files created in it, so a
+//! test can tell whether rows went to disk.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use digestive_database::exec::{Count, Max, Sum, MIN_AGGREGATE_GRANT};
+use digestive_database::vfs::VfsFile;
+use digestive_database::{HashAggregate, MemVfs, MemoryBudget, Vfs};
+
+/// In-memory file system counting the files created in it.
+#[derive(Debug, Default)]
+struct CountingVfs {
+    inner: MemVfs,
+    created: AtomicUsize,
+}
+
+impl Vfs for CountingVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        if create {
+            self.created.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.open(path, create)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+fn spill_dir() -> Arc<CountingVfs> {
+    let vfs = Arc::new(CountingVfs::default());
+    vfs.create_dir_all(Path::new("/spill")).unwrap();
+    vfs
+}
+
+#[test]
+fn sums_many_more_groups_than_fit_in_its_grant() {
+    const GROUPS: u64 = 20_000;
+    let vfs = spill_dir();
+    let budget = MemoryBudget::new(1 << 20);
+    let grant = budget.reserve(MIN_AGGREGATE_GRANT).unwrap();
+    let mut aggregate = HashAggregate::new(vfs.clone(), "/spill", grant, Sum).unwrap();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut expected = BTreeMap::new();
+    for _ in 0..60_000 {
+        let key = format!("group-{:08}", rng.below(GROUPS)).into_bytes();
+        let value = rng.below(1000) as i64 - 500;
+        *expected.entry(key.clone()).or_insert(0i64) += value;
+        aggregate.push(&key, &value.to_le_bytes()).unwrap();
+    }
+    assert!(aggregate.is_spilling());
+    assert!(vfs.created.load(Ordering::Relaxed) > 0);
+    assert_eq!(budget.used(), MIN_AGGREGATE_GRANT);
+
+    let mut groups = BTreeMap::new();
+    for group in aggregate.finish().unwrap() {
+        let (key, sum) = group.unwrap();
+        let sum = i64::from_le_bytes(sum.try_into().unwrap());
+        assert!(groups.insert(key, sum).is_none(), "group returned twice");
+    }
+    assert_eq!(groups, expected);
+    // The grant goes back to the budget with the last group, and the spill
+    // files with their handles.
+    assert_eq!(budget.used(), 0);
+    assert!(vfs.list(Path::new("/spill")).unwrap().is_empty());
+}
+
+#[test]
+fn aggregates_groups_that_fit_in_memory() {
+    let vfs = spill_dir();
+    let budget = MemoryBudget::new(1 << 20);
+    let mut counts = HashAggregate::new(
+        vfs.clone(),
+        "/spill",
+        budget.reserve(256 << 10).unwrap(),
+        Count,
+    )
+    .unwrap();
+    let mut maxima = HashAggregate::new(
+        vfs.clone(),
+        "/spill",
+        budget.reserve(256 << 10).unwrap(),
+        Max,
+    )
+    .unwrap();
+    let mut rng = Rng(7);
+    let mut expected = BTreeMap::new();
+    for _ in 0..5_000 {
+        let key = [rng.below(100) as u8];
+        let value = rng.below(1 << 20).to_be_bytes();
+        let (count, max) = expected.entry(key.to_vec()).or_insert((0u64, [0; 8]));
+        *count += 1;
+        *max = value.max(*max);
+        counts.push(&key, &value).unwrap();
+        maxima.push(&key, &value).unwrap();
+    }
+    assert!(!counts.is_spilling());
+
+    let counts: BTreeMap<Vec<u8>, Vec<u8>> = counts.finish().unwrap().map(Result::unwrap).collect();
+    let maxima: BTreeMap<Vec<u8>, Vec<u8>> = maxima.finish().unwrap().map(Result::unwrap).collect();
+    assert_eq!(counts.len(), expected.len());
+    for (key, (count, max)) in expected {
+        assert_eq!(counts[&key], count.to_le_bytes());
+        assert_eq!(maxima[&key], max);
+    }
+    assert_eq!(vfs.created.load(Ordering::Relaxed), 0);
+    assert_eq!(budget.used(), 0);
+}
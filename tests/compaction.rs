@@ -0,0 +1,162 @@
+//! Compaction of LSM trees: the runs each strategy picks, what FIFO
+//! retention keeps, and the pacing of the rate limiter on the compaction
+//! thread.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use digestive_database::compression::Compression;
+use digestive_database::lsm::{CompactionStyle, LsmEngine};
+use digestive_database::{MemVfs, Options};
+
+/// Entries per flushed table.
+const ENTRIES: usize = 256;
+const VALUE_LEN: usize = 240;
+
+fn open(compaction: CompactionStyle, compaction_rate_limit: Option<u64>) -> LsmEngine {
+    let options = Options {
+        memory_limit: 64 << 20,
+        memtable_size: Some(4 << 20),
+        target_file_size: 4 << 20,
+        l0_compaction_trigger: 4,
+        compaction,
+        compaction_rate_limit,
+        compression: Compression::None,
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    LsmEngine::open("lsm", &options, &budget).unwrap()
+}
+
+fn key(table: usize, i: usize) -> Vec<u8> {
+    format!("table{table:04}-key{i:06}").into_bytes()
+}
+
+/// Writes a table of equally sized entries under keys of its own.
+fn flush_table(engine: &LsmEngine, table: usize) {
+    for i in 0..ENTRIES {
+        engine
+            .put(&key(table, i), &[table as u8; VALUE_LEN])
+            .unwrap();
+    }
+    engine.flush().unwrap();
+}
+
+fn holds_table(engine: &LsmEngine, table: usize) -> bool {
+    let found = (0..ENTRIES)
+        .filter(|&i| engine.get(&key(table, i)).unwrap().is_some())
+        .count();
+    assert!(
+        found == 0 || found == ENTRIES,
+        "table {table} is partly gone"
+    );
+    found == ENTRIES
+}
+
+#[test]
+fn tiered_merges_runs_of_similar_size() {
+    let engine = open(CompactionStyle::Tiered { size_ratio: 0 }, None);
+    for table in 0..3 {
+        flush_table(&engine, table);
+    }
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes(), [3, 0, 0, 0, 0, 0, 0]);
+
+    // Four runs of one size: all of them are merged, into the last level
+    // since nothing is older.
+    flush_table(&engine, 3);
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes(), [0, 0, 0, 0, 0, 0, 1]);
+
+    // Three new runs and the merged one, larger than the three together:
+    // only the three are merged, just above it.
+    for table in 4..7 {
+        flush_table(&engine, table);
+    }
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes(), [0, 0, 0, 0, 0, 1, 1]);
+
+    // Two new runs: they are merged together, as each older run is larger
+    // than the runs newer than it.
+    for table in 7..9 {
+        flush_table(&engine, table);
+    }
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes(), [0, 0, 0, 0, 1, 1, 1]);
+    assert!((0..9).all(|table| holds_table(&engine, table)));
+}
+
+#[test]
+fn fifo_drops_the_oldest_tables_over_the_size_limit() {
+    let engine = open(
+        CompactionStyle::Fifo {
+            max_size: (3 * ENTRIES * VALUE_LEN + ENTRIES * VALUE_LEN / 2) as u64,
+            ttl: None,
+        },
+        None,
+    );
+    for table in 0..6 {
+        flush_table(&engine, table);
+    }
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes(), [3, 0, 0, 0, 0, 0, 0]);
+    for table in 0..6 {
+        assert_eq!(holds_table(&engine, table), table >= 3, "table {table}");
+    }
+}
+
+#[test]
+fn fifo_drops_tables_past_their_time_to_live() {
+    let engine = open(
+        CompactionStyle::Fifo {
+            max_size: u64::MAX,
+            ttl: Some(Duration::from_secs(1)),
+        },
+        None,
+    );
+    for table in 0..2 {
+        flush_table(&engine, table);
+    }
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes()[0], 2);
+    // Table ages are counted in whole seconds.
+    std::thread::sleep(Duration::from_millis(2100));
+    flush_table(&engine, 2);
+    engine.compact().unwrap();
+    assert_eq!(engine.level_sizes(), [1, 0, 0, 0, 0, 0, 0]);
+    assert!(!holds_table(&engine, 0) && !holds_table(&engine, 1));
+    assert!(holds_table(&engine, 2));
+}
+
+#[test]
+fn rate_limited_compactions_leave_writes_alone() {
+    const RATE: u64 = 256 << 10;
+    let engine = open(CompactionStyle::Leveled, Some(RATE));
+    for table in 0..3 {
+        flush_table(&engine, table);
+    }
+    // The fourth table triggers a merge of level 0 into level 1 on the
+    // compaction thread; the flush does not wait for it.
+    let started = Instant::now();
+    flush_table(&engine, 3);
+    assert!(started.elapsed() < Duration::from_millis(500));
+    std::thread::sleep(Duration::from_millis(100));
+    let write = Instant::now();
+    engine.put(b"during", b"compaction").unwrap();
+    assert_eq!(engine.get(b"during").unwrap().unwrap(), b"compaction");
+    assert!(write.elapsed() < Duration::from_millis(200));
+
+    engine.compact().unwrap();
+    // The merge read and wrote the entries of four tables at least once
+    // each, less what the bucket held to begin with.
+    let moved = 2 * 4 * ENTRIES * VALUE_LEN;
+    let least = Duration::from_secs_f64((moved as f64 - RATE as f64 / 10.0) / RATE as f64);
+    assert!(
+        started.elapsed() >= least,
+        "compacted {moved} bytes in {:?}",
+        started.elapsed()
+    );
+    assert_eq!(engine.level_sizes(), [0, 1, 0, 0, 0, 0, 0]);
+    assert!((0..4).all(|table| holds_table(&engine, table)));
+}
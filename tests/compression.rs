@@ -0,0 +1,188 @@
+//! Block compression: round trips at every level, damaged input, and
+//! trained dictionaries.
+//!
+//! Compressed blocks are checksummed in table files, so decompression only
+//! has to fail cleanly on damaged input, never panic or read past it.
+
+use digestive_database::compression::Compression;
+
+/// Larger than a Zstd block, so that frames hold more than one.
+const LARGE: usize = 200 << 10;
+
+fn codecs() -> Vec<Compression> {
+    let mut codecs = vec![Compression::None, Compression::Lz4];
+    codecs.extend((1..=22).map(|level| Compression::Zstd { level }));
+    codecs
+}
+
+/// Bytes from xorshift, which no codec can shrink.
+fn incompressible(len: usize) -> Vec<u8> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn records(from: usize, count: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    for i in from..from + count {
+        out.extend_from_slice(
+            format!(
+                "{{\"id\":{i},\"name\":\"customer {}\",\"status\":\"{}\",\"balance\":{}}}\n",
+                i * 7919 % 1000,
+                ["active", "suspended", "closed"][i % 3],
+                i * 31 % 100_000,
+            )
+            .as_bytes(),
+        );
+    }
+    out
+}
+
+fn compress(compression: Compression, input: &[u8], dictionary: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    compression.compress(input, dictionary, &mut out).unwrap();
+    out
+}
+
+#[test]
+fn round_trips_at_every_level() {
+    let inputs = [
+        ("empty", Vec::new()),
+        ("one byte", vec![7]),
+        ("incompressible", incompressible(LARGE)),
+        ("one repeated byte", vec![b'x'; LARGE]),
+        ("repeated text", records(0, 100).repeat(LARGE / 6000)),
+        ("records", records(0, 3000)),
+    ];
+    for compression in codecs() {
+        for (name, input) in &inputs {
+            let compressed = compress(compression, input, &[]);
+            assert_eq!(
+                &compression.decompress(&compressed, &[]).unwrap(),
+                input,
+                "{compression:?} on {name}"
+            );
+            if *name == "one repeated byte" && compression != Compression::None {
+                assert!(compressed.len() < input.len() / 100, "{compression:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn truncated_input_is_an_error() {
+    let input = records(0, 200);
+    for compression in codecs() {
+        let compressed = compress(compression, &input, &[]);
+        for len in 0..compressed.len() {
+            assert!(
+                compression.decompress(&compressed[..len], &[]).is_err(),
+                "{compression:?} accepted {len} of {} bytes",
+                compressed.len()
+            );
+        }
+    }
+}
+
+#[test]
+fn corrupted_input_does_not_panic() {
+    let mut input = records(0, 50);
+    input.extend_from_slice(&incompressible(500));
+    for compression in [
+        Compression::Lz4,
+        Compression::Zstd { level: 1 },
+        Compression::Zstd { level: 19 },
+    ] {
+        let compressed = compress(compression, &input, &[]);
+        for i in 0..compressed.len() {
+            for flip in [0x01, 0x10, 0x80, 0xff] {
+                let mut damaged = compressed.clone();
+                damaged[i] ^= flip;
+                if let Ok(output) = compression.decompress(&damaged, &[]) {
+                    assert_eq!(output.len(), input.len(), "{compression:?}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn trailing_data_is_an_error() {
+    let input = records(0, 20);
+    for compression in [Compression::Lz4, Compression::Zstd { level: 3 }] {
+        let mut compressed = compress(compression, &input, &[]);
+        compressed.push(0);
+        assert!(compression.decompress(&compressed, &[]).is_err());
+    }
+}
+
+/// A frame the reference `zstd` tool wrote for input read from a pipe: it
+/// has a window descriptor and a checksum, and leaves out the content size.
+#[test]
+fn reads_frames_of_the_reference_tool() {
+    let mut payload = vec![47];
+    payload.extend_from_slice(&[
+        0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x68, 0xcd, 0x00, 0x00, 0x88, 0x64, 0x69, 0x67, 0x65, 0x73,
+        0x74, 0x69, 0x76, 0x65, 0x20, 0x61, 0x74, 0x61, 0x62, 0x61, 0x73, 0x65, 0x02, 0x00, 0x2c,
+        0x85, 0xe9, 0x57, 0x90, 0x82, 0xdc, 0xc0, 0x4e,
+    ]);
+    let output = Compression::Zstd { level: 3 }
+        .decompress(&payload, &[])
+        .unwrap();
+    assert_eq!(output, b"digestive digestive digestive database database");
+}
+
+#[test]
+fn trained_dictionaries_shrink_small_blocks() {
+    let samples: Vec<Vec<u8>> = (0..200).map(|i| records(i * 10, 10)).collect();
+    let samples: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+    let block = records(5000, 10);
+    for compression in [
+        Compression::Lz4,
+        Compression::Zstd { level: 1 },
+        Compression::Zstd { level: 9 },
+        Compression::Zstd { level: 22 },
+    ] {
+        let dictionary = compression.train(&samples, 4096);
+        assert!(!dictionary.is_empty() && dictionary.len() <= 4096);
+        let plain = compress(compression, &block, &[]);
+        let primed = compress(compression, &block, &dictionary);
+        assert!(
+            primed.len() < plain.len() * 4 / 5,
+            "{compression:?}: {} bytes with the dictionary, {} without",
+            primed.len(),
+            plain.len()
+        );
+        assert_eq!(compression.decompress(&primed, &dictionary).unwrap(), block);
+        if let Compression::Zstd { .. } = compression {
+            // The frame names the dictionary it needs.
+            assert!(compression.decompress(&primed, &[]).is_err());
+            let other = compression.train(&samples[..100], 2048);
+            assert!(compression.decompress(&primed, &other).is_err());
+        }
+    }
+}
+
+#[test]
+fn damaged_dictionaries_are_errors() {
+    let samples: Vec<Vec<u8>> = (0..100).map(|i| records(i * 10, 10)).collect();
+    let samples: Vec<&[u8]> = samples.iter().map(Vec::as_slice).collect();
+    let compression = Compression::Zstd { level: 3 };
+    let dictionary = compression.train(&samples, 2048);
+    let block = records(5000, 10);
+    // Cut into the entropy tables, which follow the magic number and id.
+    for len in 8..64 {
+        let mut out = Vec::new();
+        let _ = compression.compress(&block, &dictionary[..len], &mut out);
+        let _ = compression.decompress(&out, &dictionary[..len]);
+    }
+    assert!(compression
+        .compress(&block, &dictionary[..12], &mut Vec::new())
+        .is_err());
+}
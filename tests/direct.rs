@@ -0,0 +1,147 @@
+//! Unaligned reads and writes of files opened with `O_DIRECT`, checked
+//! against a model of the file in memory.
+//!
+//! The files live on tmpfs where there is one, which supports `O_DIRECT`
+//! since Linux 6.6; the tests are skipped where the file system does not.
+
+#![cfg(target_os = "linux")]
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use digestive_database::vfs::VfsFile;
+use digestive_database::{DirectVfs, Vfs};
+
+const BLOCK: u64 = 4096;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    let base = match shm.is_dir() {
+        true => shm.to_path_buf(),
+        false => std::env::temp_dir(),
+    };
+    let dir = base.join(format!("digestive-direct-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// File `name` opened with `O_DIRECT` in a directory of its own, or `None`
+/// if the file system does not support it.
+fn open(name: &str) -> Option<(Box<dyn VfsFile>, PathBuf)> {
+    let dir = temp_dir(name);
+    match DirectVfs.open(&dir.join("file"), true) {
+        Ok(file) => Some((file, dir)),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!("skipped: {} does not support O_DIRECT", dir.display());
+            std::fs::remove_dir_all(&dir).unwrap();
+            None
+        }
+        Err(e) => panic!("{e}"),
+    }
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// Writes `data` at `offset` both to `file` and to `model`.
+fn write(file: &dyn VfsFile, model: &mut Vec<u8>, data: &[u8], offset: u64) {
+    file.write_at(data, offset).unwrap();
+    let end = offset as usize + data.len();
+    if model.len() < end {
+        model.resize(end, 0);
+    }
+    model[offset as usize..end].copy_from_slice(data);
+}
+
+fn check(file: &dyn VfsFile, model: &[u8]) {
+    assert_eq!(file.size().unwrap(), model.len() as u64);
+    let mut contents = vec![0; model.len()];
+    file.read_exact_at(&mut contents, 0).unwrap();
+    assert!(contents == model, "the file differs from its model");
+}
+
+#[test]
+fn unaligned_writes_keep_the_bytes_around_them() {
+    let Some((file, dir)) = open("writes") else {
+        return;
+    };
+    let file = &*file;
+    let mut model = Vec::new();
+    // Within a block, ending on a boundary, and straddling one.
+    write(file, &mut model, &[1; 100], 10);
+    write(file, &mut model, &[2; 3000], BLOCK - 3000);
+    write(file, &mut model, &[3; 200], BLOCK - 100);
+    check(file, &model);
+    // Straddling several blocks, starting and ending inside them.
+    write(file, &mut model, &[4; 3 * BLOCK as usize], 1000);
+    // Past the end, leaving a hole that reads as zeros.
+    write(file, &mut model, &[5; 10], 10 * BLOCK + 5);
+    check(file, &model);
+    // Longer than the bounce buffer, so split into chunks.
+    let long: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+    write(file, &mut model, &long, 3 * BLOCK + 17);
+    check(file, &model);
+
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for round in 0..200u32 {
+        let len = 1 + rng.below(3 * BLOCK) as usize;
+        let offset = rng.below(model.len() as u64 + BLOCK);
+        write(file, &mut model, &vec![round as u8; len], offset);
+    }
+    check(file, &model);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unaligned_reads_stop_at_the_end_of_the_file() {
+    let Some((file, dir)) = open("reads") else {
+        return;
+    };
+    let file = &*file;
+    let data: Vec<u8> = (0..3 * BLOCK as u32 + 123)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    file.write_at(&data, 0).unwrap();
+    assert_eq!(file.size().unwrap(), data.len() as u64);
+
+    let mut rng = Rng(7);
+    for _ in 0..200 {
+        let offset = rng.below(data.len() as u64) as usize;
+        let len = 1 + rng.below(2 * BLOCK) as usize;
+        let mut buf = vec![0xee; len];
+        let read = file.read_at(&mut buf, offset as u64).unwrap();
+        // Reads shorter than the bounce buffer return all they ask for, or
+        // the rest of the file.
+        let expected = len.min(data.len() - offset);
+        assert_eq!(read, expected, "read of {len} bytes at {offset}");
+        assert_eq!(buf[..read], data[offset..offset + read]);
+        assert!(buf[read..].iter().all(|&b| b == 0xee));
+    }
+
+    // The short tail of the last block, and nothing past the end.
+    let mut tail = [0; 1000];
+    let read = file.read_at(&mut tail, data.len() as u64 - 23).unwrap();
+    assert_eq!(read, 23);
+    assert_eq!(tail[..read], data[data.len() - 23..]);
+    assert_eq!(file.read_at(&mut tail, data.len() as u64).unwrap(), 0);
+    assert_eq!(file.read_at(&mut tail, 10 * BLOCK).unwrap(), 0);
+    let mut exact = [0; 100];
+    assert!(file
+        .read_exact_at(&mut exact, data.len() as u64 - 50)
+        .is_err());
+
+    // Truncating to an unaligned size leaves a shorter tail.
+    file.set_size(BLOCK + 1).unwrap();
+    let read = file.read_at(&mut tail, BLOCK - 10).unwrap();
+    assert_eq!(read, 11);
+    assert_eq!(tail[..read], data[BLOCK as usize - 10..BLOCK as usize + 1]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
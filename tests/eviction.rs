@@ -0,0 +1,175 @@
+//! Buffer pool eviction order and hit counting.
+//!
+//! The policies are driven directly through [`EvictionPolicy`] to check the
+//! victims they pick, and through a [`BufferPool`] over an in-memory store to
+//! check which fetches they turn into hits.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use digestive_database::buffer::{
+    BufferPool, Clock, Eviction, EvictionPolicy, Lru, LruK, PageId, PageStore, TwoQ,
+};
+use digestive_database::{MemoryBudget, Result};
+
+const PAGE_SIZE: usize = 512;
+
+#[derive(Default)]
+struct MemStore {
+    pages: Mutex<HashMap<PageId, Vec<u8>>>,
+}
+
+impl PageStore for MemStore {
+    fn read_page(&self, id: PageId, buf: &mut [u8]) -> Result<()> {
+        match self.pages.lock().unwrap().get(&id) {
+            Some(page) => buf.copy_from_slice(page),
+            None => buf.fill(0),
+        }
+        Ok(())
+    }
+
+    fn write_page(&self, id: PageId, buf: &[u8]) -> Result<()> {
+        self.pages.lock().unwrap().insert(id, buf.to_vec());
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn pool(frames: usize, eviction: Eviction) -> BufferPool {
+    let budget = MemoryBudget::new(1 << 20);
+    let store = Box::new(MemStore::default());
+    BufferPool::with_policy(store, &budget, PAGE_SIZE, frames, eviction.build(frames)).unwrap()
+}
+
+/// Fetches `pages` in order, returning the ones that were hits.
+fn fetch_all(pool: &BufferPool, pages: &[PageId]) -> Vec<PageId> {
+    let mut hits = Vec::new();
+    for &page in pages {
+        let before = pool.stats().hits;
+        drop(pool.fetch(page).unwrap());
+        if pool.stats().hits > before {
+            hits.push(page);
+        }
+    }
+    hits
+}
+
+/// Records one access per frame, frame `i` holding page `i`, and makes them
+/// all evictable.
+fn fill(policy: &mut dyn EvictionPolicy, frames: usize) {
+    for frame in 0..frames {
+        policy.record_access(frame, frame as PageId);
+        policy.set_evictable(frame, true);
+    }
+}
+
+fn drain(policy: &mut dyn EvictionPolicy) -> Vec<usize> {
+    std::iter::from_fn(|| policy.evict()).collect()
+}
+
+#[test]
+fn lru_evicts_least_recently_used() {
+    let mut lru = Lru::new(4);
+    fill(&mut lru, 4);
+    lru.record_access(0, 0);
+    lru.record_access(2, 2);
+    assert_eq!(drain(&mut lru), vec![1, 3, 0, 2]);
+}
+
+#[test]
+fn pinned_frames_are_never_evicted() {
+    for eviction in [
+        Eviction::Lru,
+        Eviction::Clock,
+        Eviction::TwoQ,
+        Eviction::LruK { k: 2 },
+    ] {
+        let mut policy = eviction.build(3);
+        fill(&mut *policy, 3);
+        policy.set_evictable(1, false);
+        let mut victims = drain(&mut *policy);
+        victims.sort_unstable();
+        assert_eq!(victims, vec![0, 2], "{eviction:?}");
+        assert_eq!(policy.evict(), None, "{eviction:?}");
+    }
+}
+
+#[test]
+fn clock_gives_referenced_frames_a_second_chance() {
+    let mut clock = Clock::new(3);
+    fill(&mut clock, 3);
+    // The first sweep clears every reference bit, so the hand comes back to
+    // frame 0.
+    assert_eq!(clock.evict(), Some(0));
+    clock.record_access(1, 1);
+    // Frame 1 was referenced again and is skipped once.
+    assert_eq!(clock.evict(), Some(2));
+    assert_eq!(clock.evict(), Some(1));
+}
+
+#[test]
+fn two_q_promotes_pages_seen_again_after_eviction() {
+    let mut two_q = TwoQ::new(4);
+    fill(&mut two_q, 4);
+    // Re-references within the first-time queue do not promote a page.
+    two_q.record_access(0, 0);
+    assert_eq!(two_q.evict(), Some(0));
+    // Page 0 is remembered as a ghost: reading it again puts it with the
+    // frequently used pages.
+    two_q.record_access(0, 0);
+    two_q.set_evictable(0, true);
+    assert_eq!(two_q.evict(), Some(1));
+    assert_eq!(two_q.evict(), Some(2));
+    // Only one first-time page is left, within the quarter of the pool they
+    // are allowed, so the frequent page goes before it.
+    assert_eq!(two_q.evict(), Some(0));
+    assert_eq!(two_q.evict(), Some(3));
+}
+
+#[test]
+fn lru_k_evicts_by_backward_k_distance() {
+    let mut lru_k = LruK::new(3, 2);
+    fill(&mut lru_k, 3);
+    lru_k.record_access(0, 0);
+    lru_k.record_access(2, 2);
+    // Frame 1 has a single access: its K-distance is infinite.
+    assert_eq!(lru_k.evict(), Some(1));
+    // Frames 0 and 2 both have two accesses; frame 0's second most recent
+    // one is older.
+    assert_eq!(lru_k.evict(), Some(0));
+    assert_eq!(lru_k.evict(), Some(2));
+}
+
+#[test]
+fn lru_counts_hits() {
+    let pool = pool(2, Eviction::Lru);
+    let hits = fetch_all(&pool, &[1, 2, 1, 3, 1, 2]);
+    // Fetching 3 evicts 2, the least recently used page.
+    assert_eq!(hits, vec![1, 1]);
+    let stats = pool.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 4, 2));
+}
+
+#[test]
+fn lru_k_resists_scans() {
+    let hot = [0, 0];
+    let scan: Vec<PageId> = (100..120).collect();
+    for (eviction, survives) in [(Eviction::Lru, false), (Eviction::LruK { k: 2 }, true)] {
+        let pool = pool(4, eviction);
+        fetch_all(&pool, &hot);
+        fetch_all(&pool, &scan);
+        assert_eq!(fetch_all(&pool, &[0]) == [0], survives, "{eviction:?}");
+    }
+}
+
+#[test]
+fn lru_k_remembers_evicted_pages() {
+    let pool = pool(2, Eviction::LruK { k: 2 });
+    // Page 1 is evicted, then read again: with its earlier access it now has
+    // two, so the page read once after it is the one evicted next.
+    let hits = fetch_all(&pool, &[1, 2, 3, 1, 4, 5, 1]);
+    assert_eq!(hits, vec![1]);
+}
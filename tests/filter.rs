@@ -0,0 +1,93 @@
+//! Accuracy of the filters written into LSM table files.
+//!
+//! A filter must answer yes for every key it was built from, and yes for
+//! other keys about as rarely as its kind and bits per key promise.
+
+use digestive_database::lsm::{may_contain, FilterBuilder, FilterKind, FilterPolicy};
+use digestive_database::Options;
+
+const KEYS: usize = 10_000;
+const PROBES: usize = 200_000;
+
+fn build(kind: FilterKind, bits_per_key: u32, keys: usize) -> Option<Vec<u8>> {
+    let options = Options {
+        filter: kind,
+        filter_bits_per_key: bits_per_key,
+        ..Options::default()
+    };
+    let mut builder = FilterBuilder::new(FilterPolicy::new(&options));
+    for i in 0..keys {
+        builder.add(format!("key{i:08}").as_bytes());
+    }
+    builder.finish()
+}
+
+/// False positive rate the module documentation gives for `kind`.
+fn expected_rate(kind: FilterKind, bits_per_key: u32) -> f64 {
+    let bits = f64::from(bits_per_key);
+    match kind {
+        FilterKind::Bloom => 0.6185f64.powf(bits),
+        // Slots outnumber keys by a tenth, so fingerprints are a little
+        // shorter than the bits per key.
+        FilterKind::Ribbon => 0.5f64.powf((bits / 1.1).round().clamp(1.0, 32.0)),
+        FilterKind::None => 1.0,
+    }
+}
+
+fn check(kind: FilterKind) {
+    for bits_per_key in [2, 5, 10, 16] {
+        let filter = build(kind, bits_per_key, KEYS).unwrap();
+        for i in 0..KEYS {
+            let key = format!("key{i:08}");
+            assert!(
+                may_contain(&filter, key.as_bytes()),
+                "{kind:?} with {bits_per_key} bits lost {key}"
+            );
+        }
+        let positives = (0..PROBES)
+            .filter(|i| may_contain(&filter, format!("absent{i:08}").as_bytes()))
+            .count();
+        let rate = positives as f64 / PROBES as f64;
+        // A quarter over the estimate, plus four standard deviations of the
+        // sampling error.
+        let expected = expected_rate(kind, bits_per_key);
+        let bound = 1.25 * expected + 4.0 * (expected / PROBES as f64).sqrt();
+        assert!(
+            rate <= bound,
+            "{kind:?} with {bits_per_key} bits: false positive rate {rate}, bound {bound}"
+        );
+    }
+}
+
+#[test]
+fn bloom_has_no_false_negatives_and_bounded_false_positives() {
+    check(FilterKind::Bloom);
+}
+
+#[test]
+fn ribbon_has_no_false_negatives_and_bounded_false_positives() {
+    check(FilterKind::Ribbon);
+}
+
+#[test]
+fn ribbon_is_smaller_than_bloom_for_the_same_rate() {
+    // Ten bits of Bloom filter and eight of ribbon both give about 0.8%.
+    let bloom = build(FilterKind::Bloom, 10, KEYS).unwrap();
+    let ribbon = build(FilterKind::Ribbon, 8, KEYS).unwrap();
+    assert!(
+        ribbon.len() < bloom.len(),
+        "{} >= {}",
+        ribbon.len(),
+        bloom.len()
+    );
+}
+
+#[test]
+fn filters_of_small_and_empty_key_sets() {
+    for kind in [FilterKind::Bloom, FilterKind::Ribbon] {
+        assert_eq!(build(kind, 10, 0), None);
+        let filter = build(kind, 10, 1).unwrap();
+        assert!(may_contain(&filter, b"key00000000"));
+    }
+    assert_eq!(build(FilterKind::None, 10, KEYS), None);
+}
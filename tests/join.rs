@@ -0,0 +1,222 @@
+//! Grace hash join correctness under small memory grants.
+//!
+//! Every join is checked against a nested loop over the same inputs. The
+//! spill directory is on a file system that counts the files created in it,
+//! so a test can tell when a spilled partition was split again.
+
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use digestive_database::exec::MIN_JOIN_GRANT;
+use digestive_database::vfs::VfsFile;
+use digestive_database::{Error, HashJoin, MemVfs, MemoryBudget, Vfs};
+
+/// In-memory file system counting the files created in it.
+#[derive(Debug, Default)]
+struct CountingVfs {
+    inner: MemVfs,
+    created: AtomicUsize,
+}
+
+impl Vfs for CountingVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        if create {
+            self.created.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.open(path, create)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+type Rows = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// `count` rows with keys below `keys`, and records of up to `max` bytes
+/// numbering them.
+fn rows(rng: &mut Rng, count: usize, keys: u64, max: u64) -> Rows {
+    (0..count)
+        .map(|i| {
+            let key = rng.below(keys).to_be_bytes().to_vec();
+            let mut record = (i as u32).to_be_bytes().to_vec();
+            record.resize(4 + rng.below(max - 3) as usize, 0xab);
+            (key, record)
+        })
+        .collect()
+}
+
+/// Pairs of build and probe records with equal keys, sorted.
+fn nested_loop(build: &Rows, probe: &Rows) -> Rows {
+    let mut pairs = Vec::new();
+    for (probe_key, probe_record) in probe {
+        for (build_key, build_record) in build {
+            if build_key == probe_key {
+                pairs.push((build_record.clone(), probe_record.clone()));
+            }
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+struct Joined {
+    pairs: Rows,
+    /// Build partitions the join spilled before probing.
+    spilled: usize,
+    /// Spill files created, by the join and the joins of its partitions.
+    files: usize,
+}
+
+/// Joins `build` and `probe` within `grant` bytes, collecting the pairs
+/// matched while probing and after it, sorted.
+fn hash_join(build: &Rows, probe: &Rows, grant: usize) -> Joined {
+    let vfs = Arc::new(CountingVfs::default());
+    vfs.create_dir_all(Path::new("/spill")).unwrap();
+    let budget = MemoryBudget::new(1 << 20);
+    let mut join = HashJoin::new(vfs.clone(), "/spill", budget.reserve(grant).unwrap()).unwrap();
+    for (key, record) in build {
+        join.build(key, record).unwrap();
+    }
+    let spilled = join.spilled_partitions();
+    let mut probing = join.probe().unwrap();
+    let mut pairs = Vec::new();
+    for (key, record) in probe {
+        for matched in probing.probe(key, record).unwrap() {
+            pairs.push((matched.to_vec(), record.clone()));
+        }
+    }
+    for pair in probing.finish().unwrap() {
+        pairs.push(pair.unwrap());
+    }
+    pairs.sort();
+    // The grant goes back to the budget with the last matches, and the spill
+    // files with their handles.
+    assert_eq!(budget.used(), 0);
+    assert!(vfs.list(Path::new("/spill")).unwrap().is_empty());
+    Joined {
+        pairs,
+        spilled,
+        files: vfs.created.load(Ordering::Relaxed),
+    }
+}
+
+#[test]
+fn joins_in_memory_without_spilling() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let build = rows(&mut rng, 200, 100, 32);
+    let probe = rows(&mut rng, 300, 150, 32);
+    let joined = hash_join(&build, &probe, 256 << 10);
+    assert_eq!(joined.spilled, 0);
+    assert_eq!(joined.files, 0);
+    assert_eq!(joined.pairs, nested_loop(&build, &probe));
+}
+
+#[test]
+fn joins_inputs_many_times_its_grant() {
+    let mut rng = Rng(7);
+    let build = rows(&mut rng, 4_000, 3_000, 64);
+    let probe = rows(&mut rng, 4_000, 4_000, 64);
+    let joined = hash_join(&build, &probe, 64 << 10);
+    assert!(
+        joined.spilled > 0,
+        "the build input should have been spilled"
+    );
+    let expected = nested_loop(&build, &probe);
+    assert!(!expected.is_empty());
+    assert_eq!(joined.pairs, expected);
+}
+
+#[test]
+fn splits_partitions_too_large_for_the_grant_again() {
+    // Under the smallest grant some 13 KiB of build records fit in memory,
+    // and over 200 KiB of distinct keys go to five partitions, each too
+    // large to be joined without being split again.
+    let mut rng = Rng(11);
+    let build = rows(&mut rng, 3_000, 1 << 32, 128);
+    let probe = build
+        .iter()
+        .step_by(3)
+        .map(|(key, _)| (key.clone(), b"probe".to_vec()))
+        .collect();
+    let joined = hash_join(&build, &probe, MIN_JOIN_GRANT);
+    assert!(joined.spilled > 0);
+    // The top-level join creates a build and a probe file per spilled
+    // partition; more files were made by the joins of those partitions.
+    assert!(
+        joined.files > 2 * joined.spilled,
+        "{} files for {} spilled partitions",
+        joined.files,
+        joined.spilled
+    );
+    assert_eq!(joined.pairs, nested_loop(&build, &probe));
+}
+
+#[test]
+fn joins_inputs_sharing_a_single_key() {
+    // No seed splits a partition whose records share one key, so it is
+    // joined by a nested loop over blocks of the build side.
+    let key = b"same".to_vec();
+    let build: Rows = (0..2_000u32)
+        .map(|i| (key.clone(), [i.to_be_bytes().as_slice(), &[0; 28]].concat()))
+        .collect();
+    let probe: Rows = (0..50u32)
+        .map(|i| (key.clone(), i.to_be_bytes().to_vec()))
+        .chain([(b"other".to_vec(), b"unmatched".to_vec())])
+        .collect();
+    let joined = hash_join(&build, &probe, MIN_JOIN_GRANT);
+    assert!(joined.spilled > 0);
+    assert_eq!(joined.pairs.len(), 2_000 * 50);
+    assert_eq!(joined.pairs, nested_loop(&build, &probe));
+}
+
+#[test]
+fn rejects_records_and_grants_too_large_or_small() {
+    let budget = MemoryBudget::new(1 << 20);
+    let vfs = Arc::new(MemVfs::new());
+    let small = budget.reserve(MIN_JOIN_GRANT - 1).unwrap();
+    let result = HashJoin::new(vfs.clone(), "/spill", small);
+    assert!(matches!(result, Err(Error::InvalidArgument(_))));
+
+    let grant = budget.reserve(MIN_JOIN_GRANT).unwrap();
+    let mut join = HashJoin::new(vfs, "/spill", grant).unwrap();
+    let record = vec![0; join.max_record_size()];
+    assert!(matches!(
+        join.build(b"k", &record),
+        Err(Error::InvalidArgument(_))
+    ));
+    join.build(b"k", &record[1..]).unwrap();
+    let mut probe = join.probe().unwrap();
+    assert!(probe.probe(b"k", &record).is_err());
+    let matches: Vec<&[u8]> = probe.probe(b"k", b"").unwrap().collect();
+    assert_eq!(matches, [&record[1..]]);
+}
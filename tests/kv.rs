@@ -0,0 +1,340 @@
+//! The embedded key-value API: codecs, typed trees, write batches and
+//! compare-and-swap, on both storage engines.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use digestive_database::kv::{Merge, Raw, Utf8};
+use digestive_database::{Codec, Db, EngineKind, Error, FaultyVfs, MemVfs, Options, Tree};
+
+const ENGINES: [EngineKind; 2] = [EngineKind::Lsm, EngineKind::BTree];
+
+fn options(engine: EngineKind) -> Options {
+    Options {
+        memory_limit: 4 << 20,
+        engine,
+        page_size: 1024,
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    }
+}
+
+fn open(options: &Options) -> Db {
+    let budget = options.memory_budget().unwrap();
+    Db::open("/db", options, &budget).unwrap()
+}
+
+fn round_trip<T, C>(value: &T) -> Vec<u8>
+where
+    T: PartialEq + std::fmt::Debug,
+    C: Codec<T>,
+{
+    let mut encoded = Vec::new();
+    C::encode(value, &mut encoded).unwrap();
+    assert_eq!(&C::decode(&encoded).unwrap(), value);
+    encoded
+}
+
+#[test]
+fn byte_and_string_codecs_round_trip() {
+    for bytes in [Vec::new(), vec![0], vec![0, 0xff, 1, 0]] {
+        assert_eq!(round_trip::<_, Raw>(&bytes), bytes);
+    }
+    for string in ["", "key", "clé\0日本"] {
+        assert_eq!(
+            round_trip::<_, Utf8>(&string.to_string()),
+            string.as_bytes()
+        );
+    }
+    assert!(matches!(
+        <Utf8 as Codec<String>>::decode(&[0xc3, 0x28]),
+        Err(Error::Corruption(_))
+    ));
+}
+
+#[cfg(feature = "serde")]
+mod ordered {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use digestive_database::kv::Ordered;
+
+    /// Encodes `values`, given in ascending order, checking that they round
+    /// trip and that their encodings ascend too.
+    fn check_order<T>(values: &[T])
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let encoded: Vec<Vec<u8>> = values.iter().map(round_trip::<T, Ordered>).collect();
+        for (i, pair) in encoded.windows(2).enumerate() {
+            assert!(
+                pair[0] < pair[1],
+                "{:?} does not encode below {:?}",
+                values[i],
+                values[i + 1]
+            );
+        }
+    }
+
+    #[test]
+    fn encodings_follow_the_order_of_values() {
+        check_order(&[false, true]);
+        check_order(&[0u8, 1, 127, 128, 255]);
+        check_order(&[0u64, 1, 255, 256, u64::MAX]);
+        check_order(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        check_order(&[i8::MIN, -1, 0, i8::MAX]);
+        check_order(&[
+            f64::NEG_INFINITY,
+            -2.5,
+            -0.0,
+            0.0,
+            1e-300,
+            2.5,
+            f64::INFINITY,
+        ]);
+        check_order(&['\0', 'a', 'é', '日']);
+        check_order(&[
+            String::new(),
+            "\0".to_string(),
+            "\0\0".to_string(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+        check_order(&[vec![], vec![0u32], vec![0, 0], vec![0, 1], vec![1]]);
+        check_order(&[None, Some(0i32), Some(1)]);
+        check_order(&[
+            (1u32, "b".to_string()),
+            (2, "a".to_string()),
+            (2, "b".to_string()),
+        ]);
+        check_order(&[Ok::<u8, String>(200), Err("a".to_string())]);
+    }
+
+    #[test]
+    fn compound_values_round_trip() {
+        let mut map = BTreeMap::new();
+        map.insert("alice".to_string(), vec![(1u16, None), (2, Some(-3i64))]);
+        map.insert(String::new(), Vec::new());
+        round_trip::<_, Ordered>(&map);
+        round_trip::<_, Ordered>(&((), (0u128, -1i128), [1.5f32, -0.0], Some(Some(7u8))));
+        round_trip::<_, Ordered>(&f64::NAN.to_bits());
+        // Trailing or missing bytes are errors, not other values.
+        let mut encoded = Vec::new();
+        Ordered::encode(&(7u32, "x".to_string()), &mut encoded).unwrap();
+        for len in 0..encoded.len() {
+            assert!(<Ordered as Codec<(u32, String)>>::decode(&encoded[..len]).is_err());
+        }
+        encoded.push(0);
+        assert!(<Ordered as Codec<(u32, String)>>::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn typed_trees_scan_in_key_order() {
+        for engine in ENGINES {
+            let db = open(&options(engine));
+            let orders: Tree<(String, i64), Vec<String>> = db.tree("orders");
+            let customers = ["carol", "alice", "bob"];
+            for (i, customer) in customers.iter().enumerate() {
+                for day in [3i64, -1, 2] {
+                    let items = vec![format!("item{i}"); day.unsigned_abs() as usize];
+                    orders.put(&(customer.to_string(), day), &items).unwrap();
+                }
+            }
+            let key = ("bob".to_string(), -1);
+            assert_eq!(orders.get(&key).unwrap().unwrap(), ["item2"]);
+
+            let keys: Vec<(String, i64)> = orders
+                .scan(..)
+                .unwrap()
+                .map(|pair| pair.unwrap().0)
+                .collect();
+            let mut sorted = keys.clone();
+            sorted.sort();
+            assert_eq!(keys.len(), 9);
+            assert_eq!(keys, sorted, "{engine:?}");
+
+            let days: Vec<i64> = orders
+                .prefix_scan(&"alice".to_string())
+                .unwrap()
+                .map(|pair| pair.unwrap().0 .1)
+                .collect();
+            assert_eq!(days, [-1, 2, 3], "{engine:?}");
+
+            // Trees and the keys of the database do not see each other.
+            let other: Tree<(String, i64), Vec<String>> = db.tree("orders2");
+            assert!(other.get(&key).unwrap().is_none());
+            assert_eq!(db.scan(..).count(), 0);
+        }
+    }
+}
+
+#[test]
+fn write_batches_apply_entirely() {
+    for engine in ENGINES {
+        let db = open(&options(engine));
+        db.put(b"gone", b"x").unwrap();
+        let tree: Tree<Vec<u8>, Vec<u8>, Raw> = db.tree("t");
+        let mut batch = db.batch();
+        for i in 0..50u8 {
+            batch.put(&[b'k', i], &[i]).unwrap();
+            tree.batch_put(&mut batch, &vec![i], &vec![i, i]).unwrap();
+        }
+        batch.delete(b"gone").unwrap();
+        batch.put(b"k\x00", b"last").unwrap();
+        assert_eq!(batch.len(), 102);
+        batch.commit().unwrap();
+        assert_eq!(db.get(b"k\x00").unwrap().unwrap(), b"last");
+        assert!(db.get(b"gone").unwrap().is_none());
+        assert_eq!(db.prefix_scan(b"k").count(), 50);
+        assert_eq!(tree.scan(..).unwrap().count(), 50);
+    }
+}
+
+#[test]
+fn failed_write_batches_apply_nothing() {
+    for engine in ENGINES {
+        let db = open(&options(engine));
+        db.put(b"kept", b"old").unwrap();
+        let mut batch = db.batch();
+        batch.put(b"new", b"value").unwrap();
+        batch.delete(b"kept").unwrap();
+        // A merge naming no registered operator fails the whole batch.
+        let merge = Merge::Operator {
+            operator: "unknown".to_string(),
+            operand: b"1".to_vec(),
+        };
+        batch.merge(b"counter", merge).unwrap();
+        assert!(matches!(batch.commit(), Err(Error::InvalidArgument(_))));
+        assert!(db.get(b"new").unwrap().is_none(), "{engine:?}");
+        assert_eq!(db.get(b"kept").unwrap().unwrap(), b"old", "{engine:?}");
+    }
+
+    // A key too long for a B+tree page fails the whole batch as well.
+    let db = open(&options(EngineKind::BTree));
+    let mut batch = db.batch();
+    batch.put(b"short", b"value").unwrap();
+    batch.put(&[b'x'; 4096], b"value").unwrap();
+    assert!(batch.commit().is_err());
+    assert!(db.get(b"short").unwrap().is_none());
+
+    // Batches from another database are refused.
+    let other = open(&options(EngineKind::Lsm));
+    let tree: Tree<Vec<u8>, Vec<u8>, Raw> = other.tree("t");
+    let mut batch = db.batch();
+    assert!(tree.batch_put(&mut batch, &vec![1], &vec![1]).is_err());
+}
+
+/// Crashes the database at each of the first writes a batch makes, with
+/// the writes that were not synced torn in various ways: once reopened, it
+/// holds all of the batch or none of it.
+#[test]
+fn write_batches_survive_crashes_entirely_or_not_at_all() {
+    for engine in ENGINES {
+        for seed in 0..60 {
+            let fail_after = seed % 3;
+            let vfs = FaultyVfs::new(seed);
+            let options = Options {
+                vfs: Arc::new(vfs.clone()),
+                durability: digestive_database::Durability::Sync,
+                ..options(engine)
+            };
+            let db = open(&options);
+            db.put(b"account-a", b"100").unwrap();
+            db.put(b"account-b", b"0").unwrap();
+            vfs.fail_after(fail_after);
+            let mut batch = db.batch();
+            batch.put(b"account-a", b"40").unwrap();
+            batch.put(b"account-b", b"60").unwrap();
+            for i in 0..20u8 {
+                batch.put(&[b'l', b'o', b'g', i], &[i; 200]).unwrap();
+            }
+            let committed = batch.commit().is_ok();
+            vfs.crash();
+            drop(db);
+
+            let budget = options.memory_budget().unwrap();
+            let db = Db::open(Path::new("/db"), &options, &budget).unwrap();
+            let a = db.get(b"account-a").unwrap().unwrap();
+            let b = db.get(b"account-b").unwrap().unwrap();
+            let log = db.prefix_scan(b"log").count();
+            let applied = (a.as_slice(), b.as_slice(), log);
+            assert!(
+                applied == (b"40", b"60", 20) || applied == (b"100", b"0", 0),
+                "{engine:?} seed {seed} failing after {fail_after} writes: {applied:?}"
+            );
+            if committed {
+                assert_eq!(log, 20, "{engine:?}: a committed batch was lost");
+            }
+        }
+    }
+}
+
+#[test]
+fn compare_and_swap_fails_on_a_mismatch() {
+    for engine in ENGINES {
+        let db = open(&options(engine));
+        // Expecting no value only creates the key.
+        assert_eq!(db.compare_and_swap(b"k", None, Some(b"1")).unwrap(), Ok(()));
+        assert_eq!(
+            db.compare_and_swap(b"k", None, Some(b"2")).unwrap(),
+            Err(Some(b"1".to_vec()))
+        );
+        assert_eq!(
+            db.compare_and_swap(b"k", Some(b"0"), Some(b"2")).unwrap(),
+            Err(Some(b"1".to_vec()))
+        );
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"1", "{engine:?}");
+        assert_eq!(
+            db.compare_and_swap(b"k", Some(b"1"), Some(b"2")).unwrap(),
+            Ok(())
+        );
+        assert_eq!(db.get(b"k").unwrap().unwrap(), b"2");
+        // A new value of `None` deletes the key.
+        assert_eq!(db.compare_and_swap(b"k", Some(b"2"), None).unwrap(), Ok(()));
+        assert_eq!(
+            db.compare_and_swap(b"k", Some(b"2"), Some(b"3")).unwrap(),
+            Err(None)
+        );
+        assert!(db.get(b"k").unwrap().is_none());
+
+        let tree: Tree<String, String, Utf8> = db.tree("names");
+        let key = "id".to_string();
+        tree.put(&key, &"alice".to_string()).unwrap();
+        assert_eq!(
+            tree.compare_and_swap(&key, Some(&"bob".to_string()), None)
+                .unwrap(),
+            Err(Some("alice".to_string()))
+        );
+        assert_eq!(tree.get(&key).unwrap().unwrap(), "alice");
+    }
+}
+
+#[test]
+fn compare_and_swap_serializes_concurrent_updates() {
+    for engine in ENGINES {
+        let db = open(&options(engine));
+        db.put(b"counter", b"0").unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        loop {
+                            let current = db.get(b"counter").unwrap().unwrap();
+                            let n: u32 = std::str::from_utf8(&current).unwrap().parse().unwrap();
+                            let next = (n + 1).to_string();
+                            let swapped = db
+                                .compare_and_swap(b"counter", Some(&current), Some(next.as_bytes()))
+                                .unwrap();
+                            if swapped.is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(db.get(b"counter").unwrap().unwrap(), b"200", "{engine:?}");
+    }
+}
@@ -0,0 +1,264 @@
+//! The LSM engine on its own: flushes of a full memtable, deletes hiding
+//! the values of deeper levels, and point lookups reading at most one
+//! block per level, counted by a file system that counts table reads.
+
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use digestive_database::lsm::{FilterKind, LsmEngine};
+use digestive_database::vfs::VfsFile;
+use digestive_database::{KeyRange, MemVfs, Options, Vfs};
+
+/// In-memory file system counting the table files created in it and the
+/// reads of them.
+#[derive(Debug, Default)]
+struct CountingVfs {
+    inner: MemVfs,
+    tables: AtomicUsize,
+    reads: Arc<AtomicUsize>,
+}
+
+struct CountingFile {
+    inner: Box<dyn VfsFile>,
+    reads: Option<Arc<AtomicUsize>>,
+}
+
+fn is_table(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "sst")
+}
+
+impl Vfs for CountingVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        if create && is_table(path) {
+            self.tables.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(Box::new(CountingFile {
+            inner: self.inner.open(path, create)?,
+            reads: is_table(path).then(|| self.reads.clone()),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+}
+
+impl VfsFile for CountingFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if let Some(reads) = &self.reads {
+            reads.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.write_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.inner.set_size(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+fn options(vfs: Arc<CountingVfs>) -> Options {
+    Options {
+        memory_limit: 16 << 20,
+        page_size: 1024,
+        target_file_size: 4 << 10,
+        vfs,
+        ..Options::default()
+    }
+}
+
+fn open(options: &Options) -> LsmEngine {
+    let budget = options.memory_budget().unwrap();
+    LsmEngine::open("/lsm", options, &budget).unwrap()
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key{i:06}").into_bytes()
+}
+
+fn value(i: u64) -> Vec<u8> {
+    format!("value{i:06}").repeat(10).into_bytes()
+}
+
+#[test]
+fn flushes_the_memtable_once_it_reaches_its_size() {
+    const MEMTABLE: usize = 16 << 10;
+    let vfs = Arc::new(CountingVfs::default());
+    let options = Options {
+        memtable_size: Some(MEMTABLE),
+        // Keep every flushed table in level 0.
+        l0_compaction_trigger: 1000,
+        ..options(vfs.clone())
+    };
+    let engine = open(&options);
+    let mut written = 0;
+    for i in 0..1000 {
+        engine.put(&key(i), &value(i)).unwrap();
+        written += key(i).len() + value(i).len();
+        if written < MEMTABLE / 2 {
+            assert_eq!(vfs.tables.load(Ordering::Relaxed), 0, "flushed early");
+        }
+    }
+    // The memtable charges every entry more than its bytes, so it fills
+    // before it holds `MEMTABLE` of them, but not before half.
+    let tables = engine.level_sizes()[0];
+    assert_eq!(vfs.tables.load(Ordering::Relaxed), tables);
+    assert!(
+        (written / MEMTABLE..=2 * written / MEMTABLE).contains(&tables),
+        "{tables} tables for {written} bytes"
+    );
+    for i in 0..1000 {
+        assert_eq!(engine.get(&key(i)).unwrap(), Some(value(i)));
+    }
+
+    // The writes left in the memtable come back from the log.
+    drop(engine);
+    let engine = open(&options);
+    assert_eq!(engine.level_sizes()[0], tables);
+    assert_eq!(engine.scan(KeyRange::all()).count(), 1000);
+    assert_eq!(engine.get(&key(999)).unwrap(), Some(value(999)));
+}
+
+#[test]
+fn deletes_hide_the_values_of_deeper_levels() {
+    let vfs = Arc::new(CountingVfs::default());
+    let engine = open(&options(vfs));
+    for part in 0..4 {
+        for i in (part..2000).step_by(4) {
+            engine.put(&key(i), &value(i)).unwrap();
+        }
+        engine.flush().unwrap();
+    }
+    engine.compact().unwrap();
+    let sizes = engine.level_sizes();
+    assert_eq!(sizes[0], 0, "{sizes:?}");
+
+    // Tombstones in level 0, above the values they delete.
+    for i in (0..2000).step_by(3) {
+        engine.delete(&key(i)).unwrap();
+    }
+    engine.flush().unwrap();
+    assert_eq!(engine.level_sizes()[0], 1);
+    // And in the memtable: one key deleted, one written again.
+    engine.delete(&key(1)).unwrap();
+    engine.put(&key(3), b"again").unwrap();
+
+    let check = |engine: &LsmEngine| {
+        for i in 0..2000 {
+            let expected = match i {
+                3 => Some(b"again".to_vec()),
+                1 => None,
+                _ if i % 3 == 0 => None,
+                _ => Some(value(i)),
+            };
+            assert_eq!(engine.get(&key(i)).unwrap(), expected, "key {i}");
+        }
+        let scanned: Vec<Vec<u8>> = engine
+            .scan(KeyRange::all())
+            .map(|pair| pair.unwrap().0)
+            .collect();
+        let expected: Vec<Vec<u8>> = (0..2000)
+            .filter(|&i| i == 3 || (i != 1 && i % 3 != 0))
+            .map(key)
+            .collect();
+        assert_eq!(scanned, expected);
+    };
+    check(&engine);
+    // Compacted into the levels of the values, the tombstones still hide
+    // them.
+    engine.flush().unwrap();
+    for _ in 0..3 {
+        engine.put(b"other", b"").unwrap();
+        engine.delete(b"other").unwrap();
+        engine.flush().unwrap();
+    }
+    engine.compact().unwrap();
+    // Level 0 was compacted, its oldest table, the tombstones, included.
+    assert!(engine.level_sizes()[0] < 4);
+    check(&engine);
+}
+
+#[test]
+fn point_lookups_read_at_most_one_block_per_level() {
+    const KEYS: u64 = 4000;
+    let vfs = Arc::new(CountingVfs::default());
+    let options = Options {
+        // Without filters, every level whose range holds a key is read.
+        filter: FilterKind::None,
+        ..options(vfs.clone())
+    };
+    let engine = open(&options);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for round in 0..9 {
+        for _ in 0..KEYS / 4 {
+            let i = 2 * rng.below(KEYS);
+            engine.put(&key(i), &value(i + round)).unwrap();
+        }
+        engine.flush().unwrap();
+    }
+    engine.compact().unwrap();
+    let sizes = engine.level_sizes();
+    let levels = sizes[1..].iter().filter(|&&n| n > 0).count();
+    assert!(levels >= 2, "{sizes:?}");
+    // Every table a lookup may touch: each of level 0, one per deeper level.
+    let most = sizes[0] + levels;
+
+    // Load the indexes first, which stay in memory.
+    for i in 0..2 * KEYS {
+        engine.get(&key(i)).unwrap();
+    }
+    // Keys present, and absent ones between them.
+    let mut deepest = 0;
+    for i in 0..2 * KEYS {
+        let before = vfs.reads.load(Ordering::Relaxed);
+        engine.get(&key(i)).unwrap();
+        let reads = vfs.reads.load(Ordering::Relaxed) - before;
+        assert!(reads <= most, "{reads} reads for key {i} in {sizes:?}");
+        deepest = deepest.max(reads);
+    }
+    assert!(deepest >= 2, "no lookup went past one level");
+    // Keys past every table read nothing.
+    let before = vfs.reads.load(Ordering::Relaxed);
+    assert_eq!(engine.get(b"zzz").unwrap(), None);
+    assert_eq!(vfs.reads.load(Ordering::Relaxed), before);
+}
@@ -0,0 +1,146 @@
+//! Accounting of memory budgets: reservations failing at the limit, bytes
+//! going back when reservations shrink or drop, the peak, and reclaim hooks
+//! asked for memory before a reservation fails.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use digestive_database::{MemoryBudget, OutOfBudget, Reclaim, Reservation};
+
+#[test]
+fn grows_up_to_the_limit_and_no_further() {
+    let budget = MemoryBudget::new(1000);
+    let mut reservation = budget.reserve(600).unwrap();
+    reservation.grow(300).unwrap();
+    assert_eq!((reservation.size(), budget.used()), (900, 900));
+
+    let err = reservation.grow(101).unwrap_err();
+    assert_eq!(
+        err,
+        OutOfBudget {
+            requested: 101,
+            available: 100,
+            limit: 1000
+        }
+    );
+    // A failed request leaves everything as it was.
+    assert_eq!((reservation.size(), budget.used()), (900, 900));
+    assert!(budget.reserve(101).is_err());
+    assert!(reservation.try_grow(usize::MAX).is_err());
+    assert_eq!(budget.used(), 900);
+
+    reservation.grow(100).unwrap();
+    assert_eq!((budget.used(), budget.available()), (1000, 0));
+    assert!(budget.try_reserve(1).is_err());
+    assert!(budget.reserve(0).is_ok());
+}
+
+#[test]
+fn shrinking_and_dropping_return_bytes() {
+    let budget = MemoryBudget::new(1000);
+    let mut first = budget.reserve(400).unwrap();
+    let second = budget.reserve(500).unwrap();
+    first.shrink(150);
+    assert_eq!((first.size(), budget.used()), (250, 750));
+    // Shrinking by more than is held returns what is held.
+    first.shrink(1000);
+    assert_eq!((first.size(), budget.used()), (0, 500));
+
+    first.resize(300).unwrap();
+    let mut split = first.split(100);
+    assert_eq!((first.size(), split.size(), budget.used()), (200, 100, 800));
+    split.merge(second);
+    assert_eq!((split.size(), budget.used()), (600, 800));
+    drop(split);
+    assert_eq!(budget.used(), 200);
+    drop(first);
+    assert_eq!((budget.used(), budget.available()), (0, 1000));
+}
+
+#[test]
+fn peak_is_the_most_ever_reserved_at_once() {
+    let budget = MemoryBudget::new(1000);
+    assert_eq!(budget.peak(), 0);
+    let mut first = budget.reserve(300).unwrap();
+    let second = budget.reserve(400).unwrap();
+    assert_eq!(budget.peak(), 700);
+    drop(second);
+    first.grow(200).unwrap();
+    assert_eq!((budget.used(), budget.peak()), (500, 700));
+    // Failed requests do not count.
+    assert!(first.grow(600).is_err());
+    assert_eq!(budget.peak(), 700);
+    first.grow(350).unwrap();
+    drop(first);
+    assert_eq!((budget.used(), budget.peak()), (0, 850));
+}
+
+/// Cache of chunks it gives back to the budget, oldest first, when asked.
+struct Cache {
+    chunks: Mutex<Vec<Reservation>>,
+    asked: AtomicUsize,
+}
+
+impl Cache {
+    fn new(budget: &MemoryBudget, chunks: usize, size: usize) -> Arc<Cache> {
+        let chunks = (0..chunks).map(|_| budget.reserve(size).unwrap());
+        let cache = Arc::new(Cache {
+            chunks: Mutex::new(chunks.collect()),
+            asked: AtomicUsize::new(0),
+        });
+        budget.register_reclaimer(Arc::downgrade(&cache) as Weak<dyn Reclaim>);
+        cache
+    }
+
+    fn len(&self) -> usize {
+        self.chunks.lock().unwrap().len()
+    }
+}
+
+impl Reclaim for Cache {
+    fn reclaim(&self, bytes: usize) -> usize {
+        self.asked.fetch_add(1, Ordering::Relaxed);
+        let Ok(mut chunks) = self.chunks.try_lock() else {
+            return 0;
+        };
+        let mut freed = 0;
+        while freed < bytes && !chunks.is_empty() {
+            freed += chunks.remove(0).size();
+        }
+        freed
+    }
+}
+
+#[test]
+fn reclaim_hooks_make_room_for_reservations() {
+    let budget = MemoryBudget::new(1000);
+    let cache = Cache::new(&budget, 8, 100);
+    let _held = budget.reserve(100).unwrap();
+    assert_eq!(budget.available(), 100);
+
+    // Requests that fit do not ask.
+    let small = budget.reserve(100).unwrap();
+    assert_eq!(cache.asked.load(Ordering::Relaxed), 0);
+    drop(small);
+    // Others ask for the shortfall, and get the bytes freed.
+    let large = budget.reserve(350).unwrap();
+    assert_eq!(cache.asked.load(Ordering::Relaxed), 1);
+    assert_eq!(cache.len(), 5);
+    assert_eq!(budget.used(), 100 + 500 + 350);
+    // `try_reserve` does not ask.
+    assert!(budget.try_reserve(100).is_err());
+    assert_eq!(cache.asked.load(Ordering::Relaxed), 1);
+
+    // When the hooks cannot free enough, the request fails holding nothing
+    // more, though the hooks gave up all they had.
+    let err = budget.reserve(1000).unwrap_err();
+    assert_eq!(err.requested, 1000);
+    assert_eq!(cache.len(), 0);
+    assert_eq!(budget.used(), 100 + 350);
+
+    // Requests go on once the owner of a hook is gone.
+    drop(cache);
+    drop(large);
+    assert!(budget.reserve(900).is_ok());
+    assert!(budget.reserve(1000).is_err());
+}
@@ -0,0 +1,196 @@
+//! Merge operators: built-in and registered operators sharing a database,
+//! operands folded across flushes and reopens, and operands the operator
+//! rejects surfacing as errors, on both storage engines.
+
+use std::sync::Arc;
+
+use digestive_database::kv::Merge;
+use digestive_database::{Db, EngineKind, Error, MemVfs, MergeOperator, Options, Result};
+
+const ENGINES: [EngineKind; 2] = [EngineKind::Lsm, EngineKind::BTree];
+
+/// Keeps the largest of the big-endian `u64`s merged into a key.
+#[derive(Debug)]
+struct Max;
+
+fn number(bytes: &[u8]) -> Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::InvalidArgument("not a u64".into()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl MergeOperator for Max {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn full_merge(&self, _: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Result<Vec<u8>> {
+        let mut max = existing.map_or(Ok(0), number)?;
+        for operand in operands {
+            max = max.max(number(operand)?);
+        }
+        Ok(max.to_be_bytes().to_vec())
+    }
+}
+
+fn max(value: u64) -> Merge {
+    Merge::Operator {
+        operator: "max".to_string(),
+        operand: value.to_be_bytes().to_vec(),
+    }
+}
+
+fn options(engine: EngineKind) -> Options {
+    Options {
+        memory_limit: 4 << 20,
+        engine,
+        page_size: 1024,
+        vfs: Arc::new(MemVfs::new()),
+        merge_operators: vec![Arc::new(Max)],
+        ..Options::default()
+    }
+}
+
+fn open(options: &Options) -> Db {
+    let budget = options.memory_budget().unwrap();
+    Db::open("/db", options, &budget).unwrap()
+}
+
+#[test]
+fn operators_share_a_database() {
+    for engine in ENGINES {
+        let options = options(engine);
+        {
+            let db = open(&options);
+            for round in 0..4u64 {
+                for i in 0..50u64 {
+                    let key = format!("key{i:02}");
+                    db.merge(format!("count-{key}").as_bytes(), Merge::Add(1))
+                        .unwrap();
+                    db.merge(
+                        format!("list-{key}").as_bytes(),
+                        Merge::Append(vec![round as u8]),
+                    )
+                    .unwrap();
+                    db.merge(format!("max-{key}").as_bytes(), max(round * i % 7))
+                        .unwrap();
+                }
+                // Operands end up spread over several tables.
+                db.flush().unwrap();
+            }
+            let mut batch = db.batch();
+            batch.merge(b"count-key00", Merge::Add(-10)).unwrap();
+            batch.merge(b"count-key00", Merge::Add(3)).unwrap();
+            batch.commit().unwrap();
+        }
+
+        let db = open(&options);
+        let count = |key: &str| Merge::counter(&db.get(key.as_bytes()).unwrap().unwrap());
+        assert_eq!(count("count-key00").unwrap(), -3, "{engine:?}");
+        assert_eq!(count("count-key49").unwrap(), 4, "{engine:?}");
+        let list = db.get(b"list-key07").unwrap().unwrap();
+        assert_eq!(Merge::items(&list).unwrap(), [[0], [1], [2], [3]]);
+        let largest = db.get(b"max-key05").unwrap().unwrap();
+        assert_eq!(largest, 5u64.to_be_bytes(), "{engine:?}");
+        assert_eq!(db.scan(..).count(), 150, "{engine:?}");
+    }
+}
+
+#[test]
+fn merges_apply_to_the_value_they_find() {
+    for engine in ENGINES {
+        let db = open(&options(engine));
+        db.put(b"count", &40i64.to_be_bytes()).unwrap();
+        db.merge(b"count", Merge::Add(2)).unwrap();
+        db.delete(b"list").unwrap();
+        db.merge(b"list", Merge::Append(b"first".to_vec())).unwrap();
+        assert_eq!(
+            Merge::counter(&db.get(b"count").unwrap().unwrap()).unwrap(),
+            42
+        );
+        let list = db.get(b"list").unwrap().unwrap();
+        assert_eq!(Merge::items(&list).unwrap(), [b"first"]);
+    }
+}
+
+#[test]
+fn rejected_operands_are_errors() {
+    for engine in ENGINES {
+        let db = open(&options(engine));
+
+        // The value is at hand on both engines when the merge is written.
+        db.put(b"text", b"not a counter").unwrap();
+        let merged = db.merge(b"text", Merge::Add(1));
+        assert!(
+            matches!(merged, Err(Error::InvalidArgument(_))),
+            "{engine:?}"
+        );
+        assert_eq!(db.get(b"text").unwrap().unwrap(), b"not a counter");
+
+        db.put(b"full", &i64::MAX.to_be_bytes()).unwrap();
+        let merged = db.merge(b"full", Merge::Add(1));
+        assert!(
+            matches!(merged, Err(Error::InvalidArgument(_))),
+            "{engine:?}"
+        );
+
+        // Once the value is in a table, the LSM engine stores the operand
+        // without reading it and the read folding it fails instead.
+        db.put(b"flushed", &i64::MAX.to_be_bytes()).unwrap();
+        db.flush().unwrap();
+        match engine {
+            EngineKind::Lsm => {
+                db.merge(b"flushed", Merge::Add(1)).unwrap();
+                let read = db.get(b"flushed");
+                assert!(matches!(read, Err(Error::InvalidArgument(_))));
+            }
+            EngineKind::BTree => {
+                assert!(db.merge(b"flushed", Merge::Add(1)).is_err());
+            }
+        }
+        // A put replaces whatever the merges left.
+        db.put(b"flushed", &5i64.to_be_bytes()).unwrap();
+        assert_eq!(
+            Merge::counter(&db.get(b"flushed").unwrap().unwrap()).unwrap(),
+            5
+        );
+
+        let unknown = Merge::Operator {
+            operator: "min".to_string(),
+            operand: vec![0; 8],
+        };
+        assert!(matches!(
+            db.merge(b"other", unknown),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(db.get(b"other").unwrap().is_none());
+    }
+}
+
+#[test]
+fn operator_names_are_unique() {
+    #[derive(Debug)]
+    struct Named(&'static str);
+
+    impl MergeOperator for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn full_merge(&self, _: &[u8], _: Option<&[u8]>, _: &[&[u8]]) -> Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+    }
+
+    for names in [["counter", "max"], ["max", "max"]] {
+        let options = Options {
+            merge_operators: names
+                .iter()
+                .map(|&name| Arc::new(Named(name)) as _)
+                .collect(),
+            ..Options::default()
+        };
+        assert!(options.validate().is_err(), "{names:?}");
+    }
+}
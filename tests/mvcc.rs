@@ -0,0 +1,271 @@
+//! Snapshot-isolated transactions: what snapshots see, write-write
+//! conflicts, savepoints, and the version store under its size limit.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use digestive_database::{
+    EngineKind, Error, KeyRange, MemVfs, MemoryBudget, Options, Result, TransactionDb,
+    VersionOverflow,
+};
+
+const ENGINES: [EngineKind; 2] = [EngineKind::Lsm, EngineKind::BTree];
+
+fn options(version_store_size: usize, version_overflow: VersionOverflow) -> Options {
+    Options {
+        memory_limit: 8 << 20,
+        engine: EngineKind::Lsm,
+        vfs: Arc::new(MemVfs::new()),
+        version_store_size: Some(version_store_size),
+        version_overflow,
+        ..Options::default()
+    }
+}
+
+fn open(options: &Options) -> TransactionDb {
+    open_with_budget(options).0
+}
+
+fn open_with_budget(options: &Options) -> (TransactionDb, MemoryBudget) {
+    let budget = options.memory_budget().unwrap();
+    let db = TransactionDb::open("/db", options, &budget).unwrap();
+    (db, budget)
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{i:02}").into_bytes()
+}
+
+/// Commits `value` under `keys` keys.
+fn write_all(db: &TransactionDb, keys: usize, value: &[u8]) {
+    let mut txn = db.begin();
+    for i in 0..keys {
+        txn.put(&key(i), value).unwrap();
+    }
+    txn.commit().unwrap();
+}
+
+#[test]
+fn snapshots_do_not_see_later_commits() {
+    for engine in ENGINES {
+        let options = Options {
+            engine,
+            ..options(1 << 20, VersionOverflow::Spill)
+        };
+        let db = open(&options);
+        write_all(&db, 10, b"old");
+        let old = db.begin();
+
+        let mut txn = db.begin();
+        txn.put(&key(0), b"new").unwrap();
+        txn.delete(&key(1)).unwrap();
+        txn.put(b"added", b"new").unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(old.get(&key(0)).unwrap().unwrap(), b"old", "{engine:?}");
+        assert_eq!(old.get(&key(1)).unwrap().unwrap(), b"old");
+        assert!(old.get(b"added").unwrap().is_none());
+        let pairs = old
+            .scan(KeyRange::all())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 10);
+        assert!(pairs.iter().all(|(_, value)| value == b"old"));
+
+        let new = db.begin();
+        assert!(new.snapshot_timestamp() > old.snapshot_timestamp());
+        assert_eq!(new.get(&key(0)).unwrap().unwrap(), b"new");
+        assert!(new.get(&key(1)).unwrap().is_none());
+        assert_eq!(new.scan(KeyRange::all()).count(), 10);
+    }
+}
+
+#[test]
+fn the_first_committer_wins() {
+    for engine in ENGINES {
+        let options = Options {
+            engine,
+            ..options(1 << 20, VersionOverflow::Spill)
+        };
+        let db = open(&options);
+        write_all(&db, 2, b"0");
+        let mut first = db.begin();
+        let mut second = db.begin();
+        let mut disjoint = db.begin();
+        first.put(&key(0), b"first").unwrap();
+        second.delete(&key(0)).unwrap();
+        second.put(b"other", b"second").unwrap();
+        disjoint.put(&key(1), b"disjoint").unwrap();
+        first.commit().unwrap();
+        assert!(
+            matches!(second.commit(), Err(Error::Conflict(_))),
+            "{engine:?}"
+        );
+        disjoint.commit().unwrap();
+
+        // A transaction begun after the commit writes the key freely.
+        let mut later = db.begin();
+        later.put(&key(0), b"later").unwrap();
+        later.commit().unwrap();
+        let txn = db.begin();
+        assert_eq!(txn.get(&key(0)).unwrap().unwrap(), b"later");
+        assert_eq!(txn.get(&key(1)).unwrap().unwrap(), b"disjoint");
+        assert!(txn.get(b"other").unwrap().is_none());
+    }
+}
+
+#[test]
+fn versions_are_dropped_once_the_oldest_snapshot_ends() {
+    let (db, budget) = open_with_budget(&options(1 << 20, VersionOverflow::Spill));
+    write_all(&db, 10, &[0; 1024]);
+    let old = db.begin();
+    for round in 1..=20 {
+        write_all(&db, 10, &[round; 1024]);
+    }
+    assert_eq!(old.get(&key(3)).unwrap().unwrap(), [0; 1024]);
+    let held = budget.used();
+    old.rollback();
+    // No snapshot is left to read the 200 overwritten values.
+    let released = held - budget.used();
+    assert!(released >= 200 * 1024, "released {released} bytes");
+    assert_eq!(db.active_transactions(), 0);
+}
+
+#[test]
+fn spilled_versions_keep_old_snapshots_correct() {
+    // Room for the values of three commits, and for the keys of the spilled
+    // versions of fifteen.
+    let options = options(32 << 10, VersionOverflow::Spill);
+    let db = open(&options);
+    write_all(&db, 10, &[0; 1024]);
+    let old = db.begin();
+    let mut middle = None;
+    for round in 1..=15 {
+        write_all(&db, 10, &[round; 1024]);
+        if round == 7 {
+            middle = Some(db.begin());
+        }
+    }
+    let middle = middle.unwrap();
+    let spill = options.vfs.read(Path::new("/db/versions.spill")).unwrap();
+    assert!(spill.len() >= 100 * 1024, "spilled {} bytes", spill.len());
+
+    for i in 0..10 {
+        assert_eq!(old.get(&key(i)).unwrap().unwrap(), [0; 1024]);
+        assert_eq!(middle.get(&key(i)).unwrap().unwrap(), [7; 1024]);
+    }
+    let pairs = old
+        .scan(KeyRange::all())
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert!(pairs.iter().all(|(_, value)| value == &[0; 1024]));
+    old.commit().unwrap();
+    middle.commit().unwrap();
+}
+
+#[test]
+fn aborted_snapshots_fail_with_snapshot_too_old() {
+    let db = open(&options(16 << 10, VersionOverflow::AbortOldest));
+    write_all(&db, 10, &[0; 1024]);
+    let mut old = db.begin();
+    assert!(old.get(&key(0)).unwrap().is_some());
+    old.put(b"pending", b"write").unwrap();
+    // Two commits of ten overwritten values fill the store.
+    for round in 1..=2 {
+        write_all(&db, 10, &[round; 1024]);
+    }
+    assert!(matches!(old.get(&key(0)), Err(Error::SnapshotTooOld)));
+    assert!(matches!(
+        old.put(b"more", b"writes"),
+        Err(Error::SnapshotTooOld)
+    ));
+    assert!(matches!(old.commit(), Err(Error::SnapshotTooOld)));
+    let txn = db.begin();
+    assert_eq!(txn.get(&key(0)).unwrap().unwrap(), [2; 1024]);
+    assert!(txn.get(b"pending").unwrap().is_none());
+}
+
+#[test]
+fn rolling_back_to_a_savepoint_discards_later_writes() {
+    let db = open(&options(1 << 20, VersionOverflow::Spill));
+    write_all(&db, 1, b"stored");
+    let mut txn = db.begin();
+    txn.put(b"a", b"1").unwrap();
+    txn.savepoint();
+    txn.put(b"a", b"2").unwrap();
+    txn.put(b"b", b"2").unwrap();
+    txn.delete(&key(0)).unwrap();
+    txn.savepoint();
+    txn.put(b"c", b"3").unwrap();
+    txn.rollback_to_savepoint();
+    assert!(txn.get(b"c").unwrap().is_none());
+    assert_eq!(txn.get(b"a").unwrap().unwrap(), b"2");
+    txn.rollback_to_savepoint();
+    assert_eq!(txn.get(b"a").unwrap().unwrap(), b"1");
+    assert!(txn.get(b"b").unwrap().is_none());
+    assert_eq!(txn.get(&key(0)).unwrap().unwrap(), b"stored");
+
+    // Released savepoints keep their writes.
+    txn.savepoint();
+    txn.put(b"d", b"4").unwrap();
+    txn.release_savepoint();
+    txn.rollback_to_savepoint();
+    txn.commit().unwrap();
+
+    let txn = db.begin();
+    let pairs = txn
+        .scan(KeyRange::all())
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let expected = [
+        (b"a".to_vec(), b"1".to_vec()),
+        (b"d".to_vec(), b"4".to_vec()),
+        (key(0), b"stored".to_vec()),
+    ];
+    assert_eq!(pairs, expected);
+}
+
+/// Commits too large for the version store abort every other snapshot and
+/// write no versions; a snapshot beginning meanwhile must be aborted as
+/// well, or wait for the commit, rather than see it applied under it.
+#[test]
+fn snapshots_begun_during_an_overflowing_commit_stay_consistent() {
+    const KEYS: usize = 4;
+    let db = open(&options(16 << 10, VersionOverflow::AbortOldest));
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    let txn = db.begin();
+                    // The commit at timestamp `t` writes round `t - 1`.
+                    let expected = txn.snapshot_timestamp().checked_sub(1);
+                    for k in 0..KEYS {
+                        match txn.get(format!("key{k}").as_bytes()) {
+                            Ok(value) => assert_eq!(
+                                value.map(|v| v[0]),
+                                expected.map(|round| round as u8),
+                                "snapshot at {} saw a later commit",
+                                txn.snapshot_timestamp()
+                            ),
+                            Err(Error::SnapshotTooOld) => break,
+                            Err(e) => panic!("{e}"),
+                        }
+                    }
+                }
+            });
+        }
+        for round in 0..500u32 {
+            let mut txn = db.begin();
+            // Every commit overwrites more than the version store holds.
+            let value = vec![round as u8; 8 << 10];
+            for k in 0..KEYS {
+                txn.put(format!("key{k}").as_bytes(), &value).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+    });
+}
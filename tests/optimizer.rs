@@ -0,0 +1,99 @@
+//! Plans the optimizer picks, as `EXPLAIN` shows them: row estimates with and
+//! without statistics, and join algorithms that change with the memory a
+//! query may use.
+
+use std::sync::Arc;
+
+use digestive_database::sql::{Database, Output, Value};
+use digestive_database::{MemVfs, Options, Result};
+
+const JOIN: &str = "SELECT a.name, b.id FROM a JOIN b ON a.id = b.a_id";
+
+fn open(query_memory_size: usize) -> Database {
+    let options = Options {
+        memory_limit: 64 << 20,
+        query_memory_size: Some(query_memory_size),
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    Database::open("/db", &options, &budget).unwrap()
+}
+
+/// Runs `sql` in a transaction of its own, returning the rows of a query.
+fn run(db: &Database, sql: &str) -> Vec<Vec<Value>> {
+    let mut txn = db.begin();
+    let rows = match db.execute(&mut txn, sql).unwrap() {
+        Output::Rows(rows) => rows.collect::<Result<_>>().unwrap(),
+        Output::Count(_) | Output::Done => Vec::new(),
+    };
+    txn.commit().unwrap();
+    rows
+}
+
+fn explain(db: &Database, sql: &str) -> Vec<String> {
+    run(db, &format!("EXPLAIN {sql}"))
+        .into_iter()
+        .map(|line| match &line[..] {
+            [Value::Text(line)] => line.trim().to_owned(),
+            line => panic!("{line:?} is not a line of a plan"),
+        })
+        .collect()
+}
+
+/// Line of `plan` describing the step named `step`.
+fn step<'p>(plan: &'p [String], step: &str) -> &'p str {
+    plan.iter()
+        .find(|line| line.starts_with(step))
+        .unwrap_or_else(|| panic!("no {step} in {plan:#?}"))
+}
+
+/// Tables `a` of 2000 rows and `b` of 2000 rows referring to them, analyzed.
+fn analyzed(query_memory_size: usize) -> Database {
+    let db = open(query_memory_size);
+    run(&db, "CREATE TABLE a (id INTEGER PRIMARY KEY, name TEXT)");
+    run(&db, "CREATE TABLE b (id INTEGER PRIMARY KEY, a_id INTEGER)");
+    for chunk in (0..2000).collect::<Vec<_>>().chunks(500) {
+        let values =
+            |f: &dyn Fn(i32) -> String| chunk.iter().map(|&i| f(i)).collect::<Vec<_>>().join(", ");
+        let names = values(&|i| format!("({i}, 'name-{i:0>40}')"));
+        run(&db, &format!("INSERT INTO a VALUES {names}"));
+        let refs = values(&|i| format!("({i}, {})", i * 7 % 2000));
+        run(&db, &format!("INSERT INTO b VALUES {refs}"));
+    }
+    run(&db, "ANALYZE");
+    db
+}
+
+#[test]
+fn joins_hash_with_memory_to_spare_and_merge_without() {
+    let plan = explain(&analyzed(16 << 20), JOIN);
+    let join = step(&plan, "HashJoin");
+    assert!(join.contains("rows=2000 cost="), "{join}");
+    assert!(join.contains("grant="), "{join}");
+
+    // The 64 KiB a query may use leave the join 16 KiB, too little to hold
+    // the build side: sorting one input for a merge join costs less than
+    // spilling both.
+    let plan = explain(&analyzed(64 << 10), JOIN);
+    assert!(!plan.iter().any(|line| line.starts_with("HashJoin")));
+    let join = step(&plan, "MergeJoin");
+    assert!(join.contains("rows=2000 cost="), "{join}");
+    assert!(join.contains("grant=16.0KiB"), "{join}");
+    let sort = step(&plan, "Sort [b.a_id]");
+    assert!(sort.contains("grant="), "{sort}");
+    // Scans hold no rows, and so get no grant.
+    assert!(!step(&plan, "SeqScan a").contains("grant="));
+}
+
+#[test]
+fn tables_never_analyzed_are_assumed_to_hold_1000_rows() {
+    let db = open(16 << 20);
+    run(&db, "CREATE TABLE a (id INTEGER PRIMARY KEY, name TEXT)");
+    run(&db, "INSERT INTO a VALUES (1, 'x'), (2, 'y')");
+    let plan = explain(&db, "SELECT name FROM a");
+    assert!(step(&plan, "SeqScan a").contains("(rows=1000 cost="));
+    run(&db, "ANALYZE a");
+    let plan = explain(&db, "SELECT name FROM a");
+    assert!(step(&plan, "SeqScan a").contains("(rows=2 cost="));
+}
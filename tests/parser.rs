@@ -0,0 +1,274 @@
+//! Parsing and name resolution of SQL: operator precedence, quoting, the
+//! errors malformed statements give, and columns a query names that are
+//! unknown, ambiguous or not grouped.
+
+use std::sync::Arc;
+
+use digestive_database::sql::ast::{Expr, SelectItem, Statement};
+use digestive_database::sql::{parse, Database, Output, Value};
+use digestive_database::{Error, MemVfs, Options, Result};
+
+fn open() -> Database {
+    let options = Options {
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    Database::open("/db", &options, &budget).unwrap()
+}
+
+/// Rows of `sql`, run in a transaction of its own.
+fn run(db: &Database, sql: &str) -> Result<Vec<Vec<Value>>> {
+    let mut txn = db.begin();
+    let rows = match db.execute(&mut txn, sql)? {
+        Output::Rows(rows) => rows.collect::<Result<_>>()?,
+        Output::Count(_) | Output::Done => Vec::new(),
+    };
+    txn.commit()?;
+    Ok(rows)
+}
+
+fn message(result: Result<impl std::fmt::Debug>) -> String {
+    match result {
+        Err(Error::InvalidArgument(message)) => message,
+        other => panic!("expected an invalid argument, got {other:?}"),
+    }
+}
+
+/// Expression of `SELECT expr`.
+fn expr(sql: &str) -> Expr {
+    let Statement::Select(select) = parse(&format!("SELECT {sql}")).unwrap() else {
+        panic!("{sql} is not a query");
+    };
+    match &select.items[..] {
+        [SelectItem::Expr { expr, .. }] => expr.clone(),
+        items => panic!("{items:?}"),
+    }
+}
+
+/// Tables `a` and `b`, the second referring to the first.
+fn joined() -> Database {
+    let db = open();
+    run(&db, "CREATE TABLE a (id INTEGER PRIMARY KEY, name TEXT)").unwrap();
+    run(&db, "CREATE TABLE b (id INTEGER PRIMARY KEY, a_id INTEGER)").unwrap();
+    run(&db, "INSERT INTO a VALUES (1, 'x'), (2, 'y'), (3, 'y')").unwrap();
+    run(&db, "INSERT INTO b VALUES (10, 1), (20, 2), (30, 2)").unwrap();
+    db
+}
+
+#[test]
+fn operators_bind_by_precedence_and_from_the_left() {
+    for (sql, parsed) in [
+        ("1 + 2 * 3", "(1 + (2 * 3))"),
+        ("(1 + 2) * 3", "((1 + 2) * 3)"),
+        ("1 - 2 - 3", "((1 - 2) - 3)"),
+        ("x % 3 / 2 * 4", "(((x % 3) / 2) * 4)"),
+        ("a + b < c * 2", "((a + b) < (c * 2))"),
+        ("-a * b", "((-a) * b)"),
+        ("NOT a = b", "(NOT (a = b))"),
+        ("a OR b AND NOT c", "(a OR (b AND (NOT c)))"),
+        (
+            "a = 1 OR a IS NOT NULL AND b < 2",
+            "((a = 1) OR ((a IS NOT NULL) AND (b < 2)))",
+        ),
+    ] {
+        assert_eq!(expr(sql).to_string(), parsed, "{sql}");
+    }
+    let db = open();
+    let rows = run(
+        &db,
+        "SELECT 2 + 3 * 4, 10 - 4 - 3, 7 / 2 * 2, 1 = 2 OR 2 = 2",
+    )
+    .unwrap();
+    let expected = [
+        Value::Integer(14),
+        Value::Integer(3),
+        Value::Integer(6),
+        Value::Boolean(true),
+    ];
+    assert_eq!(rows, [expected]);
+}
+
+#[test]
+fn quotes_escape_themselves() {
+    assert_eq!(expr("'it''s'"), Expr::Literal(Value::Text("it's".into())));
+    assert_eq!(
+        expr("\"Mixed \"\"Case\"\"\".Name"),
+        Expr::Column {
+            table: Some("Mixed \"Case\"".into()),
+            name: "name".into(),
+        }
+    );
+
+    // Quoted names keep their case and may be keywords; unquoted ones are
+    // folded to lower case.
+    let db = open();
+    run(
+        &db,
+        "CREATE TABLE \"Mixed Case\" (\"select\" INTEGER PRIMARY KEY, Name TEXT)",
+    )
+    .unwrap();
+    run(
+        &db,
+        "INSERT INTO \"Mixed Case\" VALUES (1, 'it''s'), (2, '\"')",
+    )
+    .unwrap();
+    let rows = run(
+        &db,
+        "SELECT \"select\", NAME FROM \"Mixed Case\" ORDER BY 1",
+    )
+    .unwrap();
+    let text = |s: &str| Value::Text(s.into());
+    assert_eq!(
+        rows,
+        [
+            [Value::Integer(1), text("it's")],
+            [Value::Integer(2), text("\"")]
+        ]
+    );
+    let unquoted = run(&db, "SELECT name FROM Mixed_Case");
+    assert_eq!(message(unquoted), "unknown table mixed_case");
+}
+
+#[test]
+fn malformed_statements_say_where_and_what() {
+    for (sql, error) in [
+        (
+            "SELEC 1",
+            "at offset 0: expected a statement, found `selec`",
+        ),
+        (
+            "SELECT",
+            "at offset 6: expected an expression, found the end",
+        ),
+        (
+            "SELECT FROM t",
+            "at offset 7: expected an expression, found `from`",
+        ),
+        (
+            "SELECT 1 +",
+            "at offset 10: expected an expression, found the end",
+        ),
+        (
+            "SELECT 1 2",
+            "at offset 9: expected the end of the statement, found `2`",
+        ),
+        (
+            "SELECT 1; SELECT 2",
+            "at offset 10: expected the end of the statement, found `select`",
+        ),
+        ("SELECT 'abc", "at offset 7: unterminated quoted text"),
+        ("SELECT #", "at offset 7: unexpected character '#'"),
+        (
+            "SELECT 99999999999999999999",
+            "at offset 7: integer out of range",
+        ),
+        (
+            "INSERT INTO t VALUES (1",
+            "at offset 23: expected `)`, found the end",
+        ),
+        (
+            "CREATE TABLE t (id BLOB)",
+            "at offset 19: unknown type blob",
+        ),
+        (
+            "SELECT * FROM a LEFT JOIN b ON a.id = b.id",
+            "at offset 16: outer joins are not supported",
+        ),
+    ] {
+        assert_eq!(
+            message(parse(sql)),
+            format!("syntax error {error}"),
+            "{sql}"
+        );
+    }
+}
+
+#[test]
+fn columns_of_joins_resolve_to_one_table() {
+    let db = joined();
+    for (sql, error) in [
+        (
+            "SELECT id FROM a JOIN b ON a.id = b.a_id",
+            "column id is ambiguous",
+        ),
+        (
+            "SELECT name FROM a JOIN b ON id = a_id",
+            "column id is ambiguous",
+        ),
+        (
+            "SELECT a.missing FROM a JOIN b ON a.id = b.a_id",
+            "unknown column a.missing",
+        ),
+        (
+            "SELECT c.id FROM a JOIN b ON a.id = b.a_id",
+            "unknown column c.id",
+        ),
+        // An alias hides the name of its table.
+        (
+            "SELECT a.id FROM a AS x JOIN b ON x.id = b.a_id",
+            "unknown column a.id",
+        ),
+        (
+            "SELECT * FROM a JOIN a ON a.id = a.id",
+            "table name a is used more than once",
+        ),
+    ] {
+        assert_eq!(message(run(&db, sql)), error, "{sql}");
+    }
+    let rows = run(
+        &db,
+        "SELECT x.id, b.id, name FROM a AS x JOIN b ON x.id = a_id ORDER BY b.id",
+    )
+    .unwrap();
+    let row = |a, b, name: &str| {
+        vec![
+            Value::Integer(a),
+            Value::Integer(b),
+            Value::Text(name.into()),
+        ]
+    };
+    assert_eq!(rows, [row(1, 10, "x"), row(2, 20, "y"), row(2, 30, "y")]);
+}
+
+#[test]
+fn selected_columns_must_be_grouped_or_aggregated() {
+    let db = joined();
+    for (sql, error) in [
+        (
+            "SELECT name, COUNT(*) FROM a GROUP BY id",
+            "column name must appear in GROUP BY or be used in an aggregate",
+        ),
+        (
+            "SELECT name, COUNT(*) FROM a",
+            "column name must appear in GROUP BY or be used in an aggregate",
+        ),
+        (
+            "SELECT id FROM a GROUP BY id + 1",
+            "column id must appear in GROUP BY or be used in an aggregate",
+        ),
+        (
+            "SELECT name FROM a HAVING id > 1",
+            "HAVING without GROUP BY or aggregates",
+        ),
+        (
+            "SELECT SUM(COUNT(id)) FROM a",
+            "aggregate calls cannot be nested",
+        ),
+    ] {
+        assert_eq!(message(run(&db, sql)), error, "{sql}");
+    }
+    let rows = run(
+        &db,
+        "SELECT name, COUNT(*), id % 2 + 1 FROM a GROUP BY name, id % 2 + 1 ORDER BY 1, 3",
+    )
+    .unwrap();
+    let row = |name: &str, count, parity| {
+        vec![
+            Value::Text(name.into()),
+            Value::Integer(count),
+            Value::Integer(parity),
+        ]
+    };
+    assert_eq!(rows, [row("x", 1, 2), row("y", 1, 1), row("y", 1, 2)]);
+}
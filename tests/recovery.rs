@@ -0,0 +1,200 @@
+//! Property test of crash recovery.
+//!
+//! Each case runs a random workload against a database on a [`FaultyVfs`],
+//! crashes it at a random write and reopens it, over and over. Every write
+//! acknowledged with [`Durability::Sync`] must survive, and the write that
+//! was interrupted must be applied entirely or not at all.
+//!
+//! Set `RECOVERY_SEEDS` to run more cases; a failure names its seed so it can
+//! be replayed.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use digestive_database::merge::Counter;
+use digestive_database::{
+    open_engine, Durability, EngineKind, Error, FaultyVfs, KeyRange, MergeOperator, Mutation,
+    Options, StorageEngine,
+};
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Crashes per case.
+const ROUNDS: usize = 8;
+/// Operations attempted between crashes.
+const OPERATIONS: usize = 300;
+/// Keys below this index hold counters, which merges add to.
+const COUNTERS: u64 = 50;
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+fn options(engine: EngineKind, vfs: &FaultyVfs) -> Options {
+    Options {
+        memory_limit: 1 << 20,
+        engine,
+        page_size: 1024,
+        memtable_size: Some(16 << 10),
+        target_file_size: 16 << 10,
+        wal_buffer_size: Some(8 << 10),
+        wal_segment_size: 16 << 10,
+        durability: Durability::Sync,
+        vfs: std::sync::Arc::new(vfs.clone()),
+        ..Options::default()
+    }
+}
+
+fn random_batch(rng: &mut Rng) -> Vec<Mutation> {
+    (0..1 + rng.below(4))
+        .map(|_| {
+            let index = rng.below(200);
+            let key = format!("key{index:03}").into_bytes();
+            match rng.below(8) {
+                0 | 1 => Mutation::Delete { key },
+                2 | 3 if index < COUNTERS => Mutation::Merge {
+                    key,
+                    operator: Counter::NAME.to_string(),
+                    operand: Counter::operand(rng.below(100) as i64).to_vec(),
+                },
+                _ if index < COUNTERS => Mutation::Put {
+                    key,
+                    value: Counter::operand(rng.below(100) as i64).to_vec(),
+                },
+                _ => {
+                    // Now and then a value spanning several pages.
+                    let len = match rng.below(8) {
+                        0 => rng.below(3000),
+                        _ => rng.below(150),
+                    } as usize;
+                    let value = vec![rng.below(256) as u8; len];
+                    Mutation::Put { key, value }
+                }
+            }
+        })
+        .collect()
+}
+
+fn apply(model: &mut Model, batch: &[Mutation]) {
+    for mutation in batch {
+        match mutation {
+            Mutation::Put { key, value } => {
+                model.insert(key.clone(), value.clone());
+            }
+            Mutation::Delete { key } => {
+                model.remove(key);
+            }
+            Mutation::Merge { key, operand, .. } => {
+                let existing = model.get(key).map(Vec::as_slice);
+                let value = Counter.full_merge(key, existing, &[operand]).unwrap();
+                model.insert(key.clone(), value);
+            }
+        }
+    }
+}
+
+/// Only the injected failures may surface as errors.
+fn expect_crash(error: Error, engine: EngineKind, seed: u64, round: usize) {
+    if !matches!(error, Error::Io(_)) {
+        panic!("{engine:?} seed {seed} round {round}: {error}");
+    }
+}
+
+fn contents(db: &dyn StorageEngine) -> digestive_database::Result<Model> {
+    db.scan(KeyRange::all()).collect()
+}
+
+fn run_case(engine: EngineKind, seed: u64) {
+    let dir = Path::new("/db");
+    let vfs = FaultyVfs::new(seed);
+    let options = options(engine, &vfs);
+    let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+    let mut model = Model::new();
+    // Batch that failed before the crash and may or may not have survived it.
+    let mut pending: Option<Vec<Mutation>> = None;
+
+    for round in 0..ROUNDS {
+        // Now and then crash recovery itself.
+        if rng.below(4) == 0 {
+            vfs.fail_after(rng.below(50));
+        }
+        let budget = options.memory_budget().unwrap();
+        let db = match open_engine(dir, &options, &budget) {
+            Ok(db) => db,
+            Err(e) => {
+                expect_crash(e, engine, seed, round);
+                vfs.crash();
+                continue;
+            }
+        };
+        let found = match contents(&*db) {
+            Ok(found) => found,
+            Err(e) => {
+                expect_crash(e, engine, seed, round);
+                vfs.crash();
+                continue;
+            }
+        };
+        if found != model {
+            let batch = pending.as_deref().unwrap_or_else(|| {
+                panic!("{engine:?} seed {seed} round {round}: lost acknowledged writes")
+            });
+            apply(&mut model, batch);
+            assert!(
+                found == model,
+                "{engine:?} seed {seed} round {round}: interrupted batch applied partially"
+            );
+        }
+        pending = None;
+
+        vfs.fail_after(rng.below(400));
+        for _ in 0..OPERATIONS {
+            if rng.below(40) == 0 {
+                if let Err(e) = db.flush() {
+                    expect_crash(e, engine, seed, round);
+                    break;
+                }
+                continue;
+            }
+            let batch = random_batch(&mut rng);
+            match db.write(&batch, Durability::Sync) {
+                Ok(()) => apply(&mut model, &batch),
+                Err(e) => {
+                    expect_crash(e, engine, seed, round);
+                    pending = Some(batch);
+                    break;
+                }
+            }
+        }
+        vfs.crash();
+        drop(db);
+    }
+}
+
+fn seeds() -> u64 {
+    std::env::var("RECOVERY_SEEDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(24)
+}
+
+#[test]
+fn lsm_recovers_from_crashes() {
+    for seed in 1..=seeds() {
+        run_case(EngineKind::Lsm, seed);
+    }
+}
+
+#[test]
+fn btree_recovers_from_crashes() {
+    for seed in 1..=seeds() {
+        run_case(EngineKind::BTree, seed);
+    }
+}
@@ -0,0 +1,184 @@
+//! Anomalies serializable transactions rule out and snapshot isolation
+//! allows.
+//!
+//! Each test interleaves two transactions by hand so that both would commit
+//! under snapshot isolation, and checks that with
+//! [`Isolation::Serializable`] one of them fails with [`Error::Conflict`].
+
+use std::sync::Arc;
+
+use digestive_database::{
+    EngineKind, Error, Isolation, KeyRange, MemVfs, Options, Result, Transaction, TransactionDb,
+};
+
+const ENGINES: [EngineKind; 2] = [EngineKind::Lsm, EngineKind::BTree];
+
+fn open(engine: EngineKind) -> TransactionDb {
+    let options = Options {
+        engine,
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    TransactionDb::open("/db", &options, &budget).unwrap()
+}
+
+fn load(db: &TransactionDb, pairs: &[(&str, &str)]) {
+    let mut txn = db.begin();
+    for (key, value) in pairs {
+        txn.put(key.as_bytes(), value.as_bytes()).unwrap();
+    }
+    txn.commit().unwrap();
+}
+
+fn count(txn: &Transaction<'_>, prefix: &str) -> usize {
+    let range = KeyRange::prefix(prefix.as_bytes());
+    txn.scan(range).collect::<Result<Vec<_>>>().unwrap().len()
+}
+
+fn on_call(txn: &Transaction<'_>, doctor: &str) -> bool {
+    txn.get(doctor.as_bytes()).unwrap().as_deref() == Some(b"on")
+}
+
+/// Commits `first` then `second`, returning whether each one committed.
+fn commit_both(first: Transaction<'_>, second: Transaction<'_>) -> (bool, bool) {
+    let committed = |result: Result<()>| match result {
+        Ok(()) => true,
+        Err(Error::Conflict(_)) => false,
+        Err(e) => panic!("unexpected error: {e}"),
+    };
+    (committed(first.commit()), committed(second.commit()))
+}
+
+/// Two doctors are on call and each goes off call after checking that the
+/// other one still is.
+fn write_skew(db: &TransactionDb, isolation: Isolation) -> (bool, bool) {
+    load(db, &[("alice", "on"), ("bob", "on")]);
+    let mut t1 = db.begin_with(isolation).unwrap();
+    let mut t2 = db.begin_with(isolation).unwrap();
+    assert!(on_call(&t1, "alice") && on_call(&t1, "bob"));
+    assert!(on_call(&t2, "alice") && on_call(&t2, "bob"));
+    t1.put(b"alice", b"off").unwrap();
+    t2.put(b"bob", b"off").unwrap();
+    commit_both(t1, t2)
+}
+
+#[test]
+fn snapshot_isolation_allows_write_skew() {
+    for engine in ENGINES {
+        let db = open(engine);
+        assert_eq!(write_skew(&db, Isolation::Snapshot), (true, true));
+    }
+}
+
+#[test]
+fn serializable_aborts_write_skew() {
+    for engine in ENGINES {
+        let db = open(engine);
+        assert_eq!(write_skew(&db, Isolation::Serializable), (true, false));
+        let txn = db.begin();
+        assert!(on_call(&txn, "bob"), "{engine:?}");
+    }
+}
+
+/// Each transaction counts the rows of one table and inserts a row into the
+/// other, which the other transaction's count misses.
+fn phantom(db: &TransactionDb, isolation: Isolation) -> (bool, bool) {
+    load(db, &[("a/1", ""), ("b/1", "")]);
+    let mut t1 = db.begin_with(isolation).unwrap();
+    let mut t2 = db.begin_with(isolation).unwrap();
+    assert_eq!(count(&t1, "a/"), 1);
+    assert_eq!(count(&t2, "b/"), 1);
+    t1.put(b"b/2", b"").unwrap();
+    t2.put(b"a/2", b"").unwrap();
+    commit_both(t1, t2)
+}
+
+#[test]
+fn snapshot_isolation_allows_phantoms() {
+    for engine in ENGINES {
+        let db = open(engine);
+        assert_eq!(phantom(&db, Isolation::Snapshot), (true, true));
+    }
+}
+
+#[test]
+fn serializable_aborts_phantoms() {
+    for engine in ENGINES {
+        let db = open(engine);
+        assert_eq!(phantom(&db, Isolation::Serializable), (true, false));
+        let txn = db.begin();
+        assert_eq!(count(&txn, "a/"), 1, "{engine:?}");
+        assert_eq!(count(&txn, "b/"), 2, "{engine:?}");
+    }
+}
+
+#[test]
+fn serializable_commits_disjoint_transactions() {
+    for engine in ENGINES {
+        let db = open(engine);
+        load(&db, &[("a/1", ""), ("b/1", "")]);
+        let mut t1 = db.begin_with(Isolation::Serializable).unwrap();
+        let mut t2 = db.begin_with(Isolation::Serializable).unwrap();
+        assert_eq!(count(&t1, "a/"), 1);
+        assert_eq!(count(&t2, "b/"), 1);
+        t1.put(b"a/2", b"").unwrap();
+        t2.put(b"b/2", b"").unwrap();
+        assert_eq!(commit_both(t1, t2), (true, true), "{engine:?}");
+    }
+}
+
+#[test]
+fn serializable_reader_sees_writer_commit_after_it() {
+    // The reader only depends on the writer not having run first, which a
+    // serial order with the reader first satisfies.
+    for engine in ENGINES {
+        let db = open(engine);
+        load(&db, &[("x", "1")]);
+        let reader = db.begin_with(Isolation::Serializable).unwrap();
+        let mut writer = db.begin_with(Isolation::Serializable).unwrap();
+        writer.put(b"x", b"2").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(reader.get(b"x").unwrap().as_deref(), Some(&b"1"[..]));
+        reader.commit().unwrap();
+    }
+}
+
+#[test]
+fn reads_the_budget_cannot_track_are_not_recorded() {
+    for engine in ENGINES {
+        let options = Options {
+            engine,
+            vfs: Arc::new(MemVfs::new()),
+            ..Options::default()
+        };
+        let budget = options.memory_budget().unwrap();
+        let db = TransactionDb::open("/db", &options, &budget).unwrap();
+        load(&db, &[("a", "1"), ("b", "2")]);
+        let reader = db.begin_with(Isolation::Serializable).unwrap();
+        reader.get(b"a").unwrap();
+
+        // What a transaction without reads gives back when it ends.
+        let idle = db.begin_with(Isolation::Serializable).unwrap();
+        let before = budget.used();
+        idle.rollback();
+        let released = before - budget.used();
+
+        let txn = db.begin_with(Isolation::Serializable).unwrap();
+        // Take every byte left, down to those caches would give back.
+        let mut hog = Vec::new();
+        for size in [1 << 20, 1 << 10, 1] {
+            while let Ok(reservation) = budget.reserve(size) {
+                hog.push(reservation);
+            }
+        }
+        assert!(matches!(txn.get(b"b"), Err(Error::OutOfBudget(_))));
+        drop(hog);
+        // The failed read holds nothing, so ending the transaction gives
+        // back no more than an idle one, and none of the reader's bytes.
+        let before = budget.used();
+        txn.rollback();
+        assert_eq!(before - budget.used(), released, "{engine:?}");
+        reader.commit().unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+//! External sort correctness and memory use.
+//!
+//! The test binary counts the heap allocations of each thread, so a test can
+//! check that a sort never holds more than its memory grant, whatever the
+//! size of its input.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use digestive_database::exec::MIN_SORT_GRANT;
+use digestive_database::{Error, ExternalSort, MemoryBudget, StdVfs};
+
+struct CountingAlloc;
+
+thread_local! {
+    static LIVE: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+fn allocated(bytes: usize) {
+    let _ = LIVE.try_with(|live| {
+        live.set(live.get().wrapping_add(bytes));
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+    });
+}
+
+fn freed(bytes: usize) {
+    let _ = LIVE.try_with(|live| live.set(live.get().wrapping_sub(bytes)));
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            // Counted as a copy: both blocks may be live at once.
+            allocated(new_size);
+            freed(layout.size());
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Peak bytes the current thread held while running `f`, beyond what it held
+/// before.
+fn peak_during<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let base = LIVE.with(Cell::get);
+    PEAK.with(|peak| peak.set(base));
+    let out = f();
+    (out, PEAK.with(Cell::get) - base)
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// Fills `record` with a record of up to `max` bytes.
+    fn record(&mut self, record: &mut Vec<u8>, max: u64) {
+        record.clear();
+        for _ in 0..1 + self.below(max) {
+            record.push(self.below(256) as u8);
+        }
+    }
+}
+
+/// Order-independent digest of a multiset of records.
+#[derive(Default, PartialEq, Debug)]
+struct Digest {
+    count: u64,
+    sum: u64,
+}
+
+impl Digest {
+    fn add(&mut self, record: &[u8]) {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for &byte in record {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+        self.count += 1;
+        self.sum = self.sum.wrapping_add(hash);
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("digestive-sort-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn sorts_input_many_times_its_grant_within_the_grant() {
+    const GRANT: usize = 64 << 10;
+    const MAX_RECORD: u64 = 256;
+    const RECORDS: usize = 40_000;
+    let dir = temp_dir("large");
+    let budget = MemoryBudget::new(1 << 20);
+    let grant = budget.reserve(GRANT).unwrap();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut record = Vec::with_capacity(MAX_RECORD as usize);
+    let mut previous = Vec::with_capacity(MAX_RECORD as usize);
+    let (mut input, mut output) = (Digest::default(), Digest::default());
+
+    let (runs, peak) = peak_during(|| {
+        let mut sort = ExternalSort::new(Arc::new(StdVfs), &dir, grant).unwrap();
+        for _ in 0..RECORDS {
+            rng.record(&mut record, MAX_RECORD);
+            input.add(&record);
+            sort.push(&record).unwrap();
+        }
+        let runs = sort.runs();
+        for record in sort.finish().unwrap() {
+            let record = record.unwrap();
+            assert!(previous <= record, "records out of order");
+            output.add(&record);
+            previous.clear();
+            previous.extend_from_slice(&record);
+        }
+        runs
+    });
+
+    assert!(runs > 1, "the input should have been spilled");
+    assert_eq!(input, output);
+    // The caller holds one output record on top of the grant.
+    assert!(
+        peak <= GRANT + MAX_RECORD as usize,
+        "peak of {peak} bytes exceeds the grant of {GRANT}"
+    );
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sorts_in_memory_with_a_comparator() {
+    let dir = temp_dir("memory");
+    let budget = MemoryBudget::new(1 << 20);
+    let grant = budget.reserve(256 << 10).unwrap();
+    let descending = |a: &[u8], b: &[u8]| b.cmp(a);
+    let mut sort =
+        ExternalSort::with_comparator(Arc::new(StdVfs), &dir, grant, descending).unwrap();
+    let mut rng = Rng(7);
+    let mut expected = Vec::new();
+    for _ in 0..1000 {
+        let mut record = Vec::new();
+        rng.record(&mut record, 32);
+        sort.push(&record).unwrap();
+        expected.push(record);
+    }
+    assert_eq!(sort.runs(), 0);
+    let sorted: Vec<Vec<u8>> = sort.finish().unwrap().map(Result::unwrap).collect();
+    expected.sort_by(|a, b| descending(a, b));
+    assert_eq!(sorted, expected);
+    assert_eq!(budget.used(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rejects_records_and_grants_too_large_or_small() {
+    let dir = temp_dir("limits");
+    let budget = MemoryBudget::new(1 << 20);
+    let small = budget.reserve(MIN_SORT_GRANT - 1).unwrap();
+    let result = ExternalSort::new(Arc::new(StdVfs), &dir, small);
+    assert!(matches!(result, Err(Error::InvalidArgument(_))));
+
+    let grant = budget.reserve(MIN_SORT_GRANT).unwrap();
+    let mut sort = ExternalSort::new(Arc::new(StdVfs), &dir, grant).unwrap();
+    let record = vec![0; sort.max_record_size() + 1];
+    assert!(matches!(sort.push(&record), Err(Error::InvalidArgument(_))));
+    sort.push(&record[1..]).unwrap();
+    let sorted: Vec<_> = sort.finish().unwrap().map(Result::unwrap).collect();
+    assert_eq!(sorted, [&record[1..]]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
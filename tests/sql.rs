@@ -0,0 +1,190 @@
+//! SQL statements and queries that fail halfway through, indexes created
+//! while transactions write to their table, and averages of integers.
+
+use std::sync::Arc;
+
+use digestive_database::sql::{Database, Output, Value};
+use digestive_database::{Error, MemVfs, Options, Result, Transaction};
+
+fn open() -> Database {
+    let options = Options {
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    Database::open("/db", &options, &budget).unwrap()
+}
+
+fn execute(db: &Database, txn: &mut Transaction<'_>, sql: &str) -> Result<()> {
+    match db.execute(txn, sql)? {
+        Output::Rows(rows) => rows.collect::<Result<Vec<_>>>().map(drop),
+        Output::Count(_) | Output::Done => Ok(()),
+    }
+}
+
+/// Runs `sql` in a transaction of its own.
+fn run(db: &Database, sql: &str) {
+    let mut txn = db.begin();
+    execute(db, &mut txn, sql).unwrap();
+    txn.commit().unwrap();
+}
+
+fn query(db: &Database, txn: &mut Transaction<'_>, sql: &str) -> Vec<Vec<Value>> {
+    match db.execute(txn, sql).unwrap() {
+        Output::Rows(rows) => rows.collect::<Result<_>>().unwrap(),
+        _ => panic!("{sql} is not a query"),
+    }
+}
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_owned())
+}
+
+#[test]
+fn failed_insert_leaves_no_rows() {
+    let db = open();
+    run(&db, "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)");
+    let mut txn = db.begin();
+    execute(&db, &mut txn, "INSERT INTO t VALUES (1, 'p')").unwrap();
+    assert!(execute(&db, &mut txn, "INSERT INTO t VALUES (7, 'q'), (7, 'r')").is_err());
+    let rows = query(&db, &mut txn, "SELECT id, name FROM t");
+    assert_eq!(rows, vec![vec![Value::Integer(1), text("p")]]);
+    execute(&db, &mut txn, "INSERT INTO t VALUES (7, 'r')").unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn failed_update_leaves_rows_and_indexes_unchanged() {
+    let db = open();
+    run(&db, "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)");
+    run(&db, "CREATE UNIQUE INDEX by_name ON t (name)");
+    run(&db, "INSERT INTO t VALUES (1, 'a'), (2, 'b')");
+    let mut txn = db.begin();
+    // The second row updated collides with the first in the index.
+    assert!(execute(&db, &mut txn, "UPDATE t SET name = 'c'").is_err());
+    let rows = query(&db, &mut txn, "SELECT name FROM t WHERE name = 'a'");
+    assert_eq!(rows, vec![vec![text("a")]]);
+    let rows = query(&db, &mut txn, "SELECT id FROM t WHERE name = 'c'");
+    assert!(rows.is_empty());
+    execute(&db, &mut txn, "UPDATE t SET name = 'c' WHERE id = 2").unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn aggregates_stop_after_an_error() {
+    let db = open();
+    run(
+        &db,
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, g INTEGER, v INTEGER)",
+    );
+    run(
+        &db,
+        "INSERT INTO t VALUES (1, 1, 9223372036854775807), (2, 1, 1), (3, 2, 5)",
+    );
+    for sql in ["SELECT SUM(v) FROM t", "SELECT g, SUM(v) FROM t GROUP BY g"] {
+        let mut txn = db.begin();
+        let Output::Rows(mut rows) = db.execute(&mut txn, sql).unwrap() else {
+            panic!("{sql} is not a query");
+        };
+        let mut results = Vec::new();
+        for row in rows.by_ref() {
+            match row {
+                Ok(row) => results.push(row),
+                Err(_) => break,
+            }
+        }
+        assert!(rows.next().is_none(), "{sql}");
+        // Only group 2 has a sum, whether it comes before the error or not.
+        let expected = vec![Value::Integer(2), Value::Integer(5)];
+        assert!(results.iter().all(|row| *row == expected), "{sql}");
+    }
+}
+
+/// Rows of `sql`, a query by name on `users`, checking that it reads the
+/// unique index alone.
+fn by_name(db: &Database, sql: &str) -> Vec<Vec<Value>> {
+    let mut txn = db.begin();
+    let plan = query(db, &mut txn, &format!("EXPLAIN {sql}"));
+    assert!(plan
+        .iter()
+        .any(|line| matches!(&line[0], Value::Text(l) if l.contains("IndexOnlyScan"))));
+    query(db, &mut txn, sql)
+}
+
+#[test]
+fn writers_concurrent_with_create_index_conflict() {
+    let db = open();
+    run(
+        &db,
+        "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT)",
+    );
+    run(&db, "INSERT INTO users VALUES (2, 'bob')");
+    let mut writer = db.begin();
+    execute(&db, &mut writer, "INSERT INTO users VALUES (1, 'ann')").unwrap();
+    run(&db, "CREATE UNIQUE INDEX un ON users (name)");
+    // The row was written without an entry in the new index.
+    assert!(matches!(writer.commit(), Err(Error::Conflict(_))));
+
+    let select = "SELECT name FROM users WHERE name = 'ann'";
+    assert!(by_name(&db, select).is_empty());
+    run(&db, "INSERT INTO users VALUES (1, 'ann')");
+    assert_eq!(by_name(&db, select), vec![vec![text("ann")]]);
+    let mut txn = db.begin();
+    assert!(execute(&db, &mut txn, "INSERT INTO users VALUES (3, 'ann')").is_err());
+}
+
+#[test]
+fn writers_begun_after_create_index_commit() {
+    let db = open();
+    run(
+        &db,
+        "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT)",
+    );
+    run(&db, "CREATE UNIQUE INDEX un ON users (name)");
+    // Writers of a table do not conflict with one another through its
+    // schema.
+    let mut first = db.begin();
+    let mut second = db.begin();
+    execute(&db, &mut first, "INSERT INTO users VALUES (1, 'ann')").unwrap();
+    execute(&db, &mut second, "INSERT INTO users VALUES (2, 'bob')").unwrap();
+    first.commit().unwrap();
+    second.commit().unwrap();
+    let select = "SELECT name FROM users WHERE name = 'bob'";
+    assert_eq!(by_name(&db, select), vec![vec![text("bob")]]);
+}
+
+#[test]
+fn averages_truncate_like_division() {
+    let db = open();
+    run(&db, "CREATE TABLE a (id INTEGER PRIMARY KEY, g INTEGER)");
+    run(
+        &db,
+        "INSERT INTO a VALUES (1, 1), (2, 1), (-7, 2), (4, 2), (5, 3)",
+    );
+    let mut txn = db.begin();
+    let rows = query(&db, &mut txn, "SELECT AVG(id), SUM(id) / COUNT(id) FROM a");
+    assert_eq!(rows, vec![vec![Value::Integer(1), Value::Integer(1)]]);
+    let rows = query(
+        &db,
+        &mut txn,
+        "SELECT g, AVG(id) FROM a WHERE id > 0 OR g = 2 GROUP BY g ORDER BY g",
+    );
+    let row = |g, avg| vec![Value::Integer(g), Value::Integer(avg)];
+    assert_eq!(rows, vec![row(1, 1), row(2, -1), row(3, 5)]);
+    let rows = query(&db, &mut txn, "SELECT AVG(id) FROM a WHERE id > 10");
+    assert_eq!(rows, vec![vec![Value::Null]]);
+}
+
+#[test]
+fn queries_end_at_the_first_failed_row() {
+    let db = open();
+    run(&db, "CREATE TABLE a (id INTEGER PRIMARY KEY)");
+    run(&db, "INSERT INTO a VALUES (1), (2), (3)");
+    let mut txn = db.begin();
+    let Output::Rows(rows) = db.execute(&mut txn, "SELECT id / 0 FROM a").unwrap() else {
+        panic!("not a query");
+    };
+    let rows: Vec<_> = rows.collect();
+    assert_eq!(rows.len(), 1);
+    assert!(matches!(rows[0], Err(Error::InvalidArgument(_))));
+}
@@ -0,0 +1,199 @@
+//! Statistics `ANALYZE` gathers: histograms, distinct counts and null
+//! fractions of known distributions, and the memory the sample of a table
+//! larger than the grant holds.
+//!
+//! The test binary counts the heap allocations of each thread, so a test can
+//! check that an analysis never holds more than its memory grant.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use digestive_database::sql::{Analyzer, Value, MIN_ANALYZE_GRANT};
+use digestive_database::MemoryBudget;
+
+struct CountingAlloc;
+
+thread_local! {
+    static LIVE: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+fn allocated(bytes: usize) {
+    let _ = LIVE.try_with(|live| {
+        live.set(live.get().wrapping_add(bytes));
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+    });
+}
+
+fn freed(bytes: usize) {
+    let _ = LIVE.try_with(|live| live.set(live.get().wrapping_sub(bytes)));
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            // Counted as a copy: both blocks may be live at once.
+            allocated(new_size);
+            freed(layout.size());
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Peak bytes the current thread held while running `f`, beyond what it held
+/// before.
+fn peak_during<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let base = LIVE.with(Cell::get);
+    PEAK.with(|peak| peak.set(base));
+    let out = f();
+    (out, PEAK.with(Cell::get) - base)
+}
+
+#[test]
+fn measures_a_table_it_samples_whole() {
+    const ROWS: i64 = 2000;
+    let budget = MemoryBudget::new(1 << 20);
+    let mut analyzer = Analyzer::new(3, budget.reserve(256 << 10).unwrap()).unwrap();
+    for i in 0..ROWS {
+        let id = Value::Integer(i);
+        // Every fourth row has no name, and names repeat every 50 rows.
+        let name = match i % 4 {
+            0 => Value::Null,
+            _ => Value::Text(format!("name{:02}", i % 50)),
+        };
+        analyzer.push(&[id, name, Value::Boolean(i % 2 == 0)]);
+    }
+    assert_eq!(analyzer.sampled(), ROWS as usize);
+    let (statistics, grant) = analyzer.finish();
+    assert_eq!(grant.size(), 256 << 10);
+    drop(grant);
+    assert_eq!(budget.used(), 0);
+
+    assert_eq!(statistics.rows, ROWS as u64);
+    let [id, name, flag] = &statistics.columns[..] else {
+        panic!("{statistics:?}");
+    };
+    // With every row sampled the bounds of the 100 buckets are exact.
+    let bounds: Vec<Value> = (0..=100)
+        .map(|i| Value::Integer(i * (ROWS - 1) / 100))
+        .collect();
+    assert_eq!(id.histogram.bounds, bounds);
+    assert_eq!(id.null_fraction, 0.0);
+    assert_eq!(id.width, 8.0);
+    assert!(
+        id.distinct.abs_diff(ROWS as u64) <= ROWS as u64 / 50,
+        "{}",
+        id.distinct
+    );
+    assert!((id.histogram.fraction_below(&Value::Integer(500), false) - 0.25).abs() < 0.01);
+
+    assert_eq!(name.null_fraction, 0.25);
+    assert_eq!(name.width, 0.75 * 6.0);
+    // The rows left with names still cover every remainder by 50.
+    assert_eq!(name.distinct, 50);
+    assert_eq!(
+        name.histogram.bounds.first(),
+        Some(&Value::Text("name00".into()))
+    );
+    assert_eq!(
+        name.histogram.bounds.last(),
+        Some(&Value::Text("name49".into()))
+    );
+
+    assert_eq!(flag.distinct, 2);
+    assert_eq!(flag.histogram.bounds.first(), Some(&Value::Boolean(false)));
+    assert_eq!(statistics.row_size, 8 + 5 + 1);
+}
+
+#[test]
+fn distinct_counts_are_within_the_error_of_the_sketch() {
+    // The smallest grant leaves a quarter for a sketch of 2^12 registers,
+    // whose standard error is 1.04 / 64.
+    const ERROR: f64 = 1.04 / 64.0;
+    let budget = MemoryBudget::new(1 << 20);
+    let mut analyzer = Analyzer::new(2, budget.reserve(MIN_ANALYZE_GRANT).unwrap()).unwrap();
+    for i in 0..300_000i64 {
+        analyzer.push(&[Value::Integer(i), Value::Integer(i % 30_000)]);
+    }
+    let (statistics, _) = analyzer.finish();
+    // Two columns share the quarter, halving the registers of each.
+    let error = ERROR * 2f64.sqrt();
+    for (column, distinct) in statistics.columns.iter().zip([300_000.0, 30_000.0]) {
+        let relative = (column.distinct as f64 - distinct).abs() / distinct;
+        assert!(
+            relative < 3.0 * error,
+            "{} distinct values estimated as {}",
+            distinct,
+            column.distinct
+        );
+    }
+}
+
+#[test]
+fn samples_tables_larger_than_the_grant_within_it() {
+    const GRANT: usize = 64 << 10;
+    const ROWS: usize = 50_000;
+    let budget = MemoryBudget::new(1 << 20);
+    let grant = budget.reserve(GRANT).unwrap();
+    let mut row = vec![Value::Integer(0), Value::Text(String::new())];
+    let ((sampled, statistics), peak) = peak_during(|| {
+        let mut analyzer = Analyzer::new(2, grant).unwrap();
+        for i in 0..ROWS {
+            row[0] = Value::Integer(i as i64);
+            if let Value::Text(text) = &mut row[1] {
+                text.clear();
+                text.push_str(&format!("{i:0>40}"));
+            }
+            analyzer.push(&row);
+        }
+        let sampled = analyzer.sampled();
+        (sampled, analyzer.finish().0)
+    });
+    // The sample holds what the grant has room for, a fraction of the table.
+    assert!(
+        sampled > 100 && sampled < ROWS / 10,
+        "sampled {sampled} rows"
+    );
+    // The caller holds the statistics and the row it pushes on top of it.
+    assert!(
+        peak <= GRANT + (12 << 10),
+        "peak of {peak} bytes exceeds the grant of {GRANT}"
+    );
+    assert_eq!(statistics.rows, ROWS as u64);
+    // A uniform sample spreads over the whole table.
+    let bounds = &statistics.columns[0].histogram.bounds;
+    let (Value::Integer(first), Value::Integer(last)) = (&bounds[0], &bounds[bounds.len() - 1])
+    else {
+        panic!("{bounds:?}");
+    };
+    assert!(*first < ROWS as i64 / 50 && *last > ROWS as i64 * 49 / 50);
+    let median = statistics.columns[0]
+        .histogram
+        .fraction_below(&Value::Integer(ROWS as i64 / 2), false);
+    assert!((median - 0.5).abs() < 0.1, "{median}");
+}
@@ -0,0 +1,185 @@
+//! Per-key time-to-live: expired keys hidden from reads, merges and puts
+//! after a deadline, and the sweeper deleting expired keys with their
+//! deadline records, on both storage engines.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use digestive_database::kv::{Merge, Raw};
+use digestive_database::{Db, EngineKind, KeyRange, MemVfs, Options, Tree};
+
+const ENGINES: [EngineKind; 2] = [EngineKind::Lsm, EngineKind::BTree];
+
+const SHORT: Duration = Duration::from_millis(50);
+const LONG: Duration = Duration::from_secs(3600);
+
+/// Key spaces of the deadlines, by key and by deadline, and of the marks of
+/// the key spaces holding expiring keys.
+const EXPIRY: u8 = 2;
+const DEADLINES: u8 = 3;
+const EXPIRING: u8 = 4;
+
+fn open(engine: EngineKind) -> Db {
+    let options = Options {
+        memory_limit: 4 << 20,
+        engine,
+        page_size: 1024,
+        vfs: Arc::new(MemVfs::new()),
+        ..Options::default()
+    };
+    let budget = options.memory_budget().unwrap();
+    Db::open("/db", &options, &budget).unwrap()
+}
+
+fn wait_past(ttl: Duration) {
+    thread::sleep(ttl * 2);
+}
+
+/// Number of entries the engine stores in the key space `space`.
+fn stored(db: &Db, space: u8) -> usize {
+    db.engine()
+        .scan(KeyRange::prefix(&[space]))
+        .map(Result::unwrap)
+        .count()
+}
+
+#[test]
+fn expired_keys_are_invisible_to_reads() {
+    for engine in ENGINES {
+        let db = open(engine);
+        db.put_with_ttl(b"short", b"1", SHORT).unwrap();
+        db.put_with_ttl(b"long", b"2", LONG).unwrap();
+        db.put(b"permanent", b"3").unwrap();
+        let tree: Tree<Vec<u8>, Vec<u8>, Raw> = db.tree("sessions");
+        let sessions = tree.with_ttl(SHORT);
+        sessions.put(&b"a".to_vec(), &b"session".to_vec()).unwrap();
+        assert_eq!(db.get(b"short").unwrap().unwrap(), b"1", "{engine:?}");
+        assert!(db.ttl(b"short").unwrap().unwrap() <= SHORT);
+        assert_eq!(db.scan(..).count(), 3);
+        assert!(sessions.get(&b"a".to_vec()).unwrap().is_some());
+
+        wait_past(SHORT);
+        assert!(db.get(b"short").unwrap().is_none(), "{engine:?}");
+        assert!(db.ttl(b"short").unwrap().is_none());
+        let keys: Vec<Vec<u8>> = db.scan(..).map(|pair| pair.unwrap().0).collect();
+        assert_eq!(keys, [b"long".to_vec(), b"permanent".to_vec()]);
+        assert!(db.ttl(b"long").unwrap().unwrap() > SHORT);
+        assert!(sessions.get(&b"a".to_vec()).unwrap().is_none());
+        assert_eq!(sessions.scan(..).unwrap().count(), 0);
+        // Compare-and-swap sees no value either.
+        assert!(db
+            .compare_and_swap(b"short", None, Some(b"again"))
+            .unwrap()
+            .is_ok());
+        assert_eq!(db.get(b"short").unwrap().unwrap(), b"again");
+        assert!(db.ttl(b"short").unwrap().is_none());
+    }
+}
+
+#[test]
+fn merges_apply_to_no_value_once_the_key_expired() {
+    for engine in ENGINES {
+        let db = open(engine);
+        db.put_with_ttl(b"count", &40i64.to_be_bytes(), SHORT)
+            .unwrap();
+        // Before the deadline the merge keeps the time-to-live.
+        db.merge(b"count", Merge::Add(2)).unwrap();
+        let count = db.get(b"count").unwrap().unwrap();
+        assert_eq!(Merge::counter(&count).unwrap(), 42, "{engine:?}");
+        assert!(db.ttl(b"count").unwrap().is_some());
+
+        wait_past(SHORT);
+        assert!(db.get(b"count").unwrap().is_none());
+        db.merge(b"count", Merge::Add(1)).unwrap();
+        let count = db.get(b"count").unwrap().unwrap();
+        assert_eq!(Merge::counter(&count).unwrap(), 1, "{engine:?}");
+        assert!(db.ttl(b"count").unwrap().is_none());
+        assert_eq!(stored(&db, EXPIRY), 0);
+        assert_eq!(stored(&db, DEADLINES), 0);
+    }
+}
+
+#[test]
+fn puts_clear_time_to_lives() {
+    for engine in ENGINES {
+        let db = open(engine);
+        db.put_with_ttl(b"kept", b"old", SHORT).unwrap();
+        db.put(b"kept", b"new").unwrap();
+        db.put_with_ttl(b"deleted", b"old", SHORT).unwrap();
+        db.delete(b"deleted").unwrap();
+        db.put(b"deleted", b"new").unwrap();
+        let mut batch = db.batch();
+        batch.put_with_ttl(b"batched", b"old", SHORT).unwrap();
+        batch.put(b"batched", b"new").unwrap();
+        batch.commit().unwrap();
+        assert!(db.ttl(b"kept").unwrap().is_none(), "{engine:?}");
+
+        wait_past(SHORT);
+        for key in [&b"kept"[..], b"deleted", b"batched"] {
+            assert_eq!(db.get(key).unwrap().unwrap(), b"new", "{engine:?}");
+            assert!(db.ttl(key).unwrap().is_none());
+        }
+        // The index entries the puts left behind no longer match their keys.
+        assert_eq!(db.sweep_expired().unwrap(), 0);
+        assert_eq!(db.scan(..).count(), 3);
+        assert_eq!(stored(&db, EXPIRY), 0);
+        assert_eq!(stored(&db, DEADLINES), 0);
+    }
+}
+
+#[test]
+fn key_spaces_without_time_to_lives_store_no_deadlines() {
+    for engine in ENGINES {
+        let db = open(engine);
+        db.put(b"a", b"1").unwrap();
+        db.merge(b"b", Merge::Append(b"x".to_vec())).unwrap();
+        db.delete(b"a").unwrap();
+        assert_eq!(stored(&db, EXPIRING), 0, "{engine:?}");
+        assert_eq!(stored(&db, EXPIRY), 0);
+
+        // A tree with a time-to-live marks its own key space only.
+        let tree: Tree<Vec<u8>, Vec<u8>, Raw> = db.tree("cache");
+        let tree = tree.with_ttl(LONG);
+        tree.put(&vec![1], &vec![1]).unwrap();
+        tree.put(&vec![2], &vec![2]).unwrap();
+        db.put(b"c", b"3").unwrap();
+        assert_eq!(stored(&db, EXPIRING), 1, "{engine:?}");
+        assert_eq!(stored(&db, EXPIRY), 2);
+    }
+}
+
+#[test]
+fn sweeps_delete_expired_keys_and_their_index_entries() {
+    for engine in ENGINES {
+        let db = Arc::new(open(engine));
+        for i in 0..200u32 {
+            db.put_with_ttl(&i.to_be_bytes(), b"expiring", SHORT)
+                .unwrap();
+        }
+        for i in 200..210u32 {
+            db.put_with_ttl(&i.to_be_bytes(), b"lasting", LONG).unwrap();
+        }
+        let tree: Tree<Vec<u8>, Vec<u8>, Raw> = db.tree("sessions");
+        for i in 0..100u8 {
+            tree.put_with_ttl(&vec![i], &vec![i], SHORT).unwrap();
+        }
+        db.put(b"permanent", b"value").unwrap();
+        assert_eq!(stored(&db, DEADLINES), 310, "{engine:?}");
+
+        let sweeper = db.spawn_sweeper(Duration::from_millis(20));
+        wait_past(SHORT);
+        thread::sleep(Duration::from_millis(200));
+        sweeper.stop().unwrap();
+
+        assert_eq!(db.scan(..).count(), 11, "{engine:?}");
+        assert_eq!(tree.scan(..).unwrap().count(), 0);
+        // Only the keys that have not expired are left, with their
+        // deadlines and index entries.
+        assert_eq!(stored(&db, 0), 11, "{engine:?}");
+        assert_eq!(stored(&db, 1), 0);
+        assert_eq!(stored(&db, EXPIRY), 10);
+        assert_eq!(stored(&db, DEADLINES), 10);
+        assert_eq!(db.sweep_expired().unwrap(), 0);
+    }
+}
@@ -0,0 +1,298 @@
+//! Write-ahead log: segment rotation, torn and corrupt tails cut off when
+//! the log is opened again, commits of concurrent writers grouped into
+//! shared syncs, and what each durability level loses in a crash.
+
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use digestive_database::vfs::VfsFile;
+use digestive_database::wal::{Lsn, Wal};
+use digestive_database::{Durability, FaultyVfs, MemVfs, Options, Vfs};
+
+fn options(vfs: Arc<dyn Vfs>) -> Options {
+    Options {
+        memory_limit: 4 << 20,
+        page_size: 1024,
+        wal_buffer_size: Some(64 << 10),
+        wal_segment_size: 1024,
+        vfs,
+        ..Options::default()
+    }
+}
+
+fn open(options: &Options) -> Wal {
+    let budget = options.memory_budget().unwrap();
+    Wal::open("/wal", options, &budget, 0).unwrap()
+}
+
+fn record(i: usize) -> Vec<u8> {
+    format!("record {i:04} ").repeat(1 + i % 7).into_bytes()
+}
+
+fn replay(wal: &Wal) -> Vec<(Lsn, Vec<u8>)> {
+    wal.replay(0)
+        .unwrap()
+        .collect::<digestive_database::Result<_>>()
+        .unwrap()
+}
+
+/// Segment files of the log, oldest first, with their sizes.
+fn segments(vfs: &dyn Vfs) -> Vec<(Lsn, usize)> {
+    let mut segments: Vec<(Lsn, usize)> = vfs
+        .list(Path::new("/wal"))
+        .unwrap()
+        .iter()
+        .map(|name| {
+            let start = name.strip_suffix(".log").unwrap().parse().unwrap();
+            let size = vfs.read(&Path::new("/wal").join(name)).unwrap().len();
+            (start, size)
+        })
+        .collect();
+    segments.sort_unstable();
+    segments
+}
+
+#[test]
+fn segments_rotate_past_their_size() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemVfs::new());
+    let options = options(vfs.clone());
+    let wal = open(&options);
+    let mut lsns = Vec::new();
+    for i in 0..100 {
+        let lsn = wal.append(&record(i)).unwrap();
+        wal.commit(lsn, Durability::Sync).unwrap();
+        lsns.push(lsn);
+    }
+
+    let segments = segments(&*vfs);
+    assert!(segments.len() > 5, "{segments:?}");
+    for pair in segments.windows(2) {
+        let ((start, size), (next, _)) = (pair[0], pair[1]);
+        // A segment is closed by the first flush that finds it full, and
+        // the next one starts where it ends, at a record boundary.
+        assert!((1024..1024 + 200).contains(&size), "{segments:?}");
+        assert_eq!(start + size as u64, next);
+        assert!(lsns.contains(&next));
+    }
+    let records = replay(&wal);
+    let expected: Vec<(Lsn, Vec<u8>)> = lsns
+        .iter()
+        .enumerate()
+        .map(|(i, &lsn)| (lsn, record(i)))
+        .collect();
+    assert_eq!(records, expected);
+    // Replay after a position skips the segments and records before it.
+    let tail: Vec<Lsn> = wal
+        .replay(lsns[49])
+        .unwrap()
+        .map(|r| r.unwrap().0)
+        .collect();
+    assert_eq!(tail, lsns[50..]);
+
+    wal.truncate_before(lsns[49]).unwrap();
+    let left = self::segments(&*vfs);
+    assert!(left.len() < segments.len());
+    assert!(left[0].0 <= lsns[48]);
+    drop(wal);
+    let wal = open(&options);
+    assert_eq!(wal.next_lsn(), lsns[99]);
+    assert_eq!(replay(&wal).last(), expected.last());
+}
+
+#[test]
+fn torn_and_corrupt_tails_are_cut_off() {
+    // Cut inside the header of the last record, inside its payload, and a
+    // byte of its payload flipped.
+    for damage in 0..3 {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemVfs::new());
+        let options = Options {
+            wal_segment_size: 1 << 20,
+            ..options(vfs.clone())
+        };
+        let wal = open(&options);
+        let lsns: Vec<Lsn> = (0..10).map(|i| wal.append(&record(i)).unwrap()).collect();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let path = Path::new("/wal").join(format!("{:020}.log", 0));
+        let file = vfs.open(&path, false).unwrap();
+        let last = lsns[8];
+        match damage {
+            0 => file.set_size(last + 5).unwrap(),
+            1 => file.set_size(lsns[9] - 1).unwrap(),
+            _ => file.write_at(b"X", last + 12).unwrap(),
+        }
+
+        let wal = open(&options);
+        assert_eq!(wal.next_lsn(), last, "damage {damage}");
+        assert_eq!(file.size().unwrap(), last);
+        let records = replay(&wal);
+        assert_eq!(records.len(), 9);
+        // New records follow the intact ones.
+        let lsn = wal.append(b"after").unwrap();
+        wal.commit(lsn, Durability::Sync).unwrap();
+        drop(wal);
+        let records = replay(&open(&options));
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[9], (lsn, b"after".to_vec()));
+    }
+}
+
+/// In-memory file system whose syncs take a while, and are counted.
+#[derive(Debug, Default)]
+struct SlowSyncVfs {
+    inner: MemVfs,
+    syncs: Arc<AtomicUsize>,
+}
+
+struct SlowSyncFile {
+    inner: Box<dyn VfsFile>,
+    syncs: Arc<AtomicUsize>,
+}
+
+impl Vfs for SlowSyncVfs {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(SlowSyncFile {
+            inner: self.inner.open(path, create)?,
+            syncs: self.syncs.clone(),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+}
+
+impl VfsFile for SlowSyncFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.write_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.inner.set_size(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(2));
+        self.inner.sync()
+    }
+}
+
+#[test]
+fn concurrent_commits_share_syncs() {
+    const WRITERS: usize = 8;
+    const COMMITS: usize = 50;
+    let vfs = Arc::new(SlowSyncVfs::default());
+    let options = Options {
+        wal_segment_size: 1 << 20,
+        ..options(vfs.clone())
+    };
+    let wal = open(&options);
+    let before = vfs.syncs.load(Ordering::Relaxed);
+    thread::scope(|scope| {
+        for writer in 0..WRITERS {
+            let wal = &wal;
+            scope.spawn(move || {
+                for i in 0..COMMITS {
+                    let lsn = wal.append(&record(writer * COMMITS + i)).unwrap();
+                    wal.commit(lsn, Durability::Sync).unwrap();
+                }
+            });
+        }
+    });
+    let syncs = vfs.syncs.load(Ordering::Relaxed) - before;
+    // Writers that commit while a sync is under way wait for the next one,
+    // which covers all of them.
+    assert!(
+        syncs <= WRITERS * COMMITS / 2,
+        "{syncs} syncs for {} commits",
+        WRITERS * COMMITS
+    );
+    let mut records: Vec<Vec<u8>> = replay(&wal).into_iter().map(|(_, r)| r).collect();
+    records.sort();
+    let mut expected: Vec<Vec<u8>> = (0..WRITERS * COMMITS).map(record).collect();
+    expected.sort();
+    assert_eq!(records, expected);
+}
+
+/// Appends records 0 to 29, committing the first ten with `Sync`, the next
+/// ten with `Async` and the last ten with `None`.
+fn append_with_each_durability(wal: &Wal) {
+    for i in 0..30 {
+        let durability = match i / 10 {
+            0 => Durability::Sync,
+            1 => Durability::Async,
+            _ => Durability::None,
+        };
+        let lsn = wal.append(&record(i)).unwrap();
+        wal.commit(lsn, durability).unwrap();
+    }
+}
+
+#[test]
+fn process_crashes_lose_records_committed_without_durability() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemVfs::new());
+    let options = options(vfs.clone());
+    let wal = open(&options);
+    append_with_each_durability(&wal);
+    // The process dies: the buffer in memory is never written out.
+    std::mem::forget(wal);
+
+    let records: Vec<Vec<u8>> = replay(&open(&options))
+        .into_iter()
+        .map(|(_, r)| r)
+        .collect();
+    let expected: Vec<Vec<u8>> = (0..20).map(record).collect();
+    assert_eq!(records, expected);
+}
+
+#[test]
+fn machine_crashes_lose_only_records_never_synced() {
+    for seed in 0..20 {
+        let vfs = FaultyVfs::new(seed);
+        let options = options(Arc::new(vfs.clone()));
+        let wal = open(&options);
+        append_with_each_durability(&wal);
+        // Power fails: of the writes not yet synced, some reach the disk,
+        // in whole or in part, and others do not.
+        vfs.crash();
+        drop(wal);
+
+        let records: Vec<Vec<u8>> = replay(&open(&options))
+            .into_iter()
+            .map(|(_, r)| r)
+            .collect();
+        assert!((10..=20).contains(&records.len()), "seed {seed}");
+        let expected: Vec<Vec<u8>> = (0..records.len()).map(record).collect();
+        assert_eq!(records, expected, "seed {seed}");
+    }
+}